@@ -0,0 +1,150 @@
+//! Per-client permissions for the wishbone server, so that a lab can hand
+//! out access to a shared board without giving everyone reset and flash
+//! rights.  Given --client-tokens, a client has to send one of the tokens,
+//! followed by a newline, before its first packet, and can then only do
+//! what that token allows.  The file has a token and a permission on each
+//! line, with `#` starting a comment:
+//!
+//! ```text
+//! # token            permission
+//! 3f9c2a71d4e8b605   read-only
+//! 8e1b7d0c5a2f9463   read-write
+//! c07a45e9f1d3b82e   admin
+//! ```
+//!
+//! Without --client-tokens, anyone who can connect can do anything.
+//!
+//! The token goes ahead of the usual litex_server packets, so it's a
+//! change to the protocol that clients have to know about.  This tool
+//! sends one with `--ethernet-tcp --ethernet-token TOKEN`.  Other clients,
+//! such as LiteX's `RemoteClient`, don't, and need to write the token and
+//! a newline to the socket themselves as soon as it's connected:
+//!
+//! ```text
+//! sock = socket.create_connection((host, 1234))
+//! sock.sendall(b"8e1b7d0c5a2f9463\n")
+//! ```
+//!
+//! A write the token doesn't allow can't be refused in the protocol, which
+//! has no reply to writes, so the server closes the connection instead.
+//!
+//! Only the wishbone server takes tokens.  The GDB server can't be served
+//! alongside it, since it could still reset and flash the board.
+
+use std::io::{self, Read};
+
+/// The longest token a client may send, so one that never sends a
+/// newline can't take up memory
+const MAX_TOKEN: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Permission {
+    /// Reads only
+    ReadOnly,
+
+    /// Reads and writes, but not to the reset CSR or the SPI flash
+    ReadWrite,
+
+    /// Anything
+    Admin,
+}
+
+impl Permission {
+    fn from_string(item: &str) -> Option<Permission> {
+        match item {
+            "read-only" => Some(Permission::ReadOnly),
+            "read-write" => Some(Permission::ReadWrite),
+            "admin" => Some(Permission::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::ReadOnly => "read-only",
+            Permission::ReadWrite => "read-write",
+            Permission::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessList {
+    tokens: Vec<(String, Permission)>,
+
+    /// Addresses that only admin clients can write: the SoC reset and the
+    /// SPI flash CSRs
+    admin_only: Vec<u32>,
+}
+
+/// Compare without stopping at the first difference, so that how long a
+/// wrong token takes to turn away says nothing about the right one
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl AccessList {
+    pub fn parse(text: &str, admin_only: Vec<u32>) -> Result<AccessList, String> {
+        let mut tokens = vec![];
+        for (idx, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => continue,
+                [token, permission] => match Permission::from_string(permission) {
+                    Some(permission) => tokens.push((token.to_string(), permission)),
+                    None => {
+                        return Err(format!(
+                            "line {}: {} is not a permission -- must be read-only, read-write, or admin",
+                            idx + 1,
+                            permission
+                        ))
+                    }
+                },
+                _ => return Err(format!("line {}: expected a token and a permission", idx + 1)),
+            }
+        }
+        if tokens.is_empty() {
+            return Err("there are no tokens in it".to_owned());
+        }
+        Ok(AccessList { tokens, admin_only })
+    }
+
+    /// What the client that sent `token` may do, if it's one of ours
+    pub fn permission(&self, token: &str) -> Option<Permission> {
+        let mut found = None;
+        for (known, permission) in &self.tokens {
+            if same(known.as_bytes(), token.as_bytes()) {
+                found = Some(*permission);
+            }
+        }
+        found
+    }
+
+    pub fn allows_write(&self, permission: Permission, addr: u32) -> bool {
+        match permission {
+            Permission::ReadOnly => false,
+            Permission::ReadWrite => !self.admin_only.contains(&addr),
+            Permission::Admin => true,
+        }
+    }
+}
+
+/// Read the token a client starts with, up to the newline after it
+pub fn read_token<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut token = vec![];
+    let mut byte = [0; 1];
+    while token.len() < MAX_TOKEN {
+        if reader.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        if byte[0] == b'\n' {
+            return Ok(String::from_utf8_lossy(&token).trim_end_matches('\r').to_owned());
+        }
+        token.push(byte[0]);
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "token is too long"))
+}
@@ -0,0 +1,143 @@
+//! Telling boards apart by a unique ID burned into the silicon, rather
+//! than by which USB port they happen to be plugged into.
+
+use crate::bridge::usb::UsbBridge;
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::{Config, CsrRegister};
+use crate::spiflash::{FlashError, SpiFlash};
+
+use std::fs::File;
+use std::io;
+use std::time::Duration;
+
+/// A list of known boards, mapping unique IDs to labels.  It's read from
+/// a CSV file of `ID,LABEL` lines.
+#[derive(Clone, Default)]
+pub struct BoardRegistry {
+    boards: Vec<(String /* id */, String /* label */)>,
+}
+
+impl BoardRegistry {
+    /// Load the registry from `filename`, or from
+    /// `~/.config/wishbone-tool/boards.csv` if that exists.
+    pub fn load(filename: Option<&str>) -> Result<BoardRegistry, io::Error> {
+        let file = match filename {
+            Some(f) => File::open(f)?,
+            None => {
+                let default = match std::env::var_os("HOME") {
+                    Some(home) => {
+                        std::path::Path::new(&home).join(".config/wishbone-tool/boards.csv")
+                    }
+                    None => return Ok(BoardRegistry::default()),
+                };
+                match File::open(default) {
+                    Ok(f) => f,
+                    Err(_) => return Ok(BoardRegistry::default()),
+                }
+            }
+        };
+        let mut boards = vec![];
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(file);
+        for result in rdr.records() {
+            let r = result?;
+            if r.len() >= 2 {
+                boards.push((normalize_id(&r[0]), r[1].to_owned()));
+            }
+        }
+        Ok(BoardRegistry { boards })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boards.is_empty()
+    }
+
+    pub fn label(&self, id: &str) -> Option<&str> {
+        let id = normalize_id(id);
+        self.boards
+            .iter()
+            .find(|(i, _)| *i == id)
+            .map(|(_, l)| l.as_str())
+    }
+
+    pub fn id(&self, label: &str) -> Option<&str> {
+        self.boards
+            .iter()
+            .find(|(_, l)| l == label)
+            .map(|(i, _)| i.as_str())
+    }
+}
+
+fn normalize_id(id: &str) -> String {
+    id.trim_start_matches("0x").to_lowercase()
+}
+
+/// The FPGA DNA register, if the gateware has one.
+pub fn dna_register(registers: &[CsrRegister]) -> Option<&CsrRegister> {
+    registers.iter().find(|r| r.name == "dna_id")
+}
+
+/// Read a register that spans several words as one long hex string.
+fn read_id<F: FnMut(u32) -> Result<u32, BridgeError>>(
+    reg: &CsrRegister,
+    mut peek: F,
+) -> Result<String, BridgeError> {
+    let mut id = String::new();
+    // Only the low bits of each word are used on a narrow CSR bus
+    let digits = (reg.data_width / 4) as usize;
+    let mut words: Vec<u32> = (0..reg.words).collect();
+    words.sort_by_key(|word| std::cmp::Reverse(reg.significance(*word)));
+    for word in words {
+        let value = peek(reg.address + word * 4)? as u64 & ((1u64 << reg.data_width) - 1);
+        id.push_str(&format!("{:0width$x}", value, width = digits));
+    }
+    Ok(id)
+}
+
+/// Read the board's unique ID: the FPGA DNA if there is one, or else the
+/// unique ID of its SPI flash.
+pub fn board_id(cfg: &Config, bridge: &Bridge) -> Result<Option<String>, FlashError> {
+    if let Some(dna) = dna_register(&cfg.csr_registers) {
+        return Ok(Some(read_id(dna, |addr| bridge.peek(addr))?));
+    }
+    if let Some(spiflash) = SpiFlash::new(cfg) {
+        let uid = spiflash.unique_id(bridge)?;
+        return Ok(Some(uid.iter().map(|b| format!("{:02x}", b)).collect()));
+    }
+    Ok(None)
+}
+
+/// Read the DNA of a USB device that hasn't been connected to yet.
+pub fn usb_board_id(usb: &libusb::DeviceHandle, dna: &CsrRegister) -> Option<String> {
+    read_id(dna, |addr| UsbBridge::peek_handle(usb, addr)).ok()
+}
+
+/// Find the bus and address of the USB device whose DNA is `id`.  Only
+/// devices with the --pid, and the --vid if there is one, are opened, so
+/// that nothing else on the host is sent vendor requests.
+pub fn find_usb_board(cfg: &Config, id: &str) -> Option<(u8, u8)> {
+    let dna = dna_register(&cfg.csr_registers)?;
+    let pid = cfg.usb_pid?;
+    let usb_ctx = libusb::Context::new().ok()?;
+    for device in usb_ctx.devices().ok()?.iter() {
+        let desc = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        if desc.product_id() != pid || cfg.usb_vid.is_some_and(|vid| vid != desc.vendor_id()) {
+            continue;
+        }
+        if let Ok(usb) = device.open() {
+            if usb_board_id(&usb, dna).as_deref() == Some(id) {
+                return Some((device.bus_number(), device.address()));
+            }
+        }
+        // Leave the device alone for a moment before the next one is opened.
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
@@ -0,0 +1,83 @@
+//! Reading through a glitchy bridge, for bring-up on hardware where the
+//! odd read comes back wrong without any error.  In the areas it's asked
+//! for, a read is made more than once and the value most of them agree on
+//! is the one used.  If there's a mask to tell a good value from a bad
+//! one, such as status bits that are always zero, only a read that fails
+//! it is gone over again.
+//!
+//! Reading a FIFO or anything else that changes when it's read more than
+//! once would lose data, so areas holding those should be left out.
+
+use super::BridgeError;
+
+use log::debug;
+
+/// How many reads are voted on if it isn't said
+pub const DEFAULT_READS: u32 = 3;
+
+#[derive(Clone, Debug)]
+pub struct DeglitchPolicy {
+    pub base: u32,
+    pub size: u64,
+
+    /// How many reads are voted on
+    pub reads: u32,
+
+    /// A good value has these bits the same as in `expect`
+    pub mask: Option<u32>,
+    pub expect: u32,
+}
+
+impl DeglitchPolicy {
+    pub fn covers(&self, addr: u32) -> bool {
+        addr >= self.base && ((addr - self.base) as u64) < self.size
+    }
+
+    fn valid(&self, value: u32) -> bool {
+        match self.mask {
+            Some(mask) => value & mask == self.expect & mask,
+            None => true,
+        }
+    }
+
+    /// Settle on a value for `addr`, given the `first` read of it and a
+    /// way to read it again.  This gives how many of the reads were
+    /// thrown away, along with the value.
+    pub fn settle<F>(&self, addr: u32, first: u32, mut read: F) -> Result<(u32, u32), BridgeError>
+    where
+        F: FnMut() -> Result<u32, BridgeError>,
+    {
+        let mut values = vec![];
+        if self.mask.is_some() {
+            // With a mask, the first read stands unless it's plainly wrong
+            if self.valid(first) {
+                return Ok((first, 0));
+            }
+        } else {
+            values.push(first);
+        }
+        while values.len() < self.reads as usize {
+            values.push(read()?);
+        }
+
+        let mut best = None;
+        let mut best_count = 0;
+        for value in values.iter().filter(|v| self.valid(**v)) {
+            let count = values.iter().filter(|v| *v == value).count();
+            if count > best_count {
+                best = Some(*value);
+                best_count = count;
+            }
+        }
+        match best {
+            Some(value) if best_count * 2 > values.len() => {
+                let discarded = (values.len() - best_count) as u32 + if self.mask.is_some() { 1 } else { 0 };
+                if discarded > 0 {
+                    debug!("read of {:08x} glitched, settled on {:08x} from {:08x?}", addr, value, values);
+                }
+                Ok((value, discarded))
+            }
+            _ => Err(BridgeError::Unsettled(addr, values)),
+        }
+    }
+}
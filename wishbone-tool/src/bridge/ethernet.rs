@@ -13,6 +13,7 @@ use byteorder::{BigEndian, ByteOrder};
 
 use super::BridgeError;
 use crate::config::Config;
+use crate::trace;
 
 enum EthernetConnection {
     UDP(UdpSocket),
@@ -40,6 +41,7 @@ struct EthernetConfig {
     host: String,
     port: u16,
     tcp: bool,
+    token: Option<String>,
 }
 
 pub struct EthernetBridge {
@@ -55,6 +57,8 @@ enum ConnectThreadRequests {
     Exit,
     Poke(u32 /* addr */, u32 /* val */),
     Peek(u32 /* addr */),
+    BurstPoke(u32 /* addr */, Vec<u32> /* values */),
+    BurstPeek(u32 /* addr */, usize /* count */),
 }
 
 #[derive(Debug)]
@@ -63,6 +67,17 @@ enum ConnectThreadResponses {
     OpenedDevice,
     PeekResult(Result<u32, BridgeError>),
     PokeResult(Result<(), BridgeError>),
+    BurstPeekResult(Result<Vec<u32>, BridgeError>),
+}
+
+/// The most words a single Etherbone record can read or write, since its
+/// counts are a byte each
+pub const MAX_BURST_WORDS: usize = 255;
+
+/// An Etherbone packet header, followed by the start of a record with no
+/// Wishbone flags and every byte enabled
+fn record_header(writes: usize, reads: usize) -> Vec<u8> {
+    vec![0x4e, 0x6f, 0x10, 0x44, 0, 0, 0, 0, 0, 0x0f, writes as u8, reads as u8]
 }
 
 impl Clone for EthernetBridge {
@@ -88,7 +103,8 @@ impl EthernetBridge {
         };
         let port = cfg.ethernet_port;
         let tcp = cfg.ethernet_tcp;
-        let cfg = EthernetConfig { host, port, tcp, };
+        let token = cfg.ethernet_token.clone();
+        let cfg = EthernetConfig { host, port, tcp, token, };
 
         let thr_cv = cv.clone();
         let thr_cfg = cfg.clone();
@@ -118,8 +134,16 @@ impl EthernetBridge {
         loop {
             let mut connection = match cfg.tcp {
                 true => match TcpStream::connect(format!("{}:{}", host, port)) {
-                    Ok(conn) => {
+                    Ok(mut conn) => {
                         info!("Re-opened ethernet host {}:{}", host, port);
+                        // A server with --client-tokens wants a token first
+                        if let Some(token) = &cfg.token {
+                            if let Err(e) = conn.write_all(format!("{}\n", token).as_bytes()) {
+                                error!("unable to send the token to {}:{}: {}", host, port, e);
+                                thread::park_timeout(Duration::from_millis(500));
+                                continue;
+                            }
+                        }
                         EthernetConnection::TCP(conn)
                     },
                     Err(e) => {
@@ -199,6 +223,24 @@ impl EthernetBridge {
                             *response.lock().unwrap() = Some(ConnectThreadResponses::PokeResult(result));
                             cvar.notify_one();
                         }
+                        ConnectThreadRequests::BurstPeek(addr, count) => {
+                            let result = Self::do_burst_peek(&mut connection, &host, port, addr, count);
+                            if let Err(err) = &result {
+                                result_error = format!("burst peek {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::BurstPeekResult(result));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstPoke(addr, values) => {
+                            let result = Self::do_burst_poke(&mut connection, &host, port, addr, &values);
+                            if let Err(err) = &result {
+                                result_error = format!("burst poke {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::PokeResult(result));
+                            cvar.notify_one();
+                        }
                     },
                 }
             }
@@ -224,12 +266,19 @@ impl EthernetBridge {
                             )));
                             cvar.notify_one();
                         },
-                        ConnectThreadRequests::Poke(_addr, _val) => {
+                        ConnectThreadRequests::Poke(..)
+                        | ConnectThreadRequests::BurstPoke(..) => {
                             *response.lock().unwrap() = Some(ConnectThreadResponses::PokeResult(Err(
                                 BridgeError::NotConnected,
                             )));
                             cvar.notify_one();
                         },
+                        ConnectThreadRequests::BurstPeek(_addr, _count) => {
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::BurstPeekResult(Err(
+                                BridgeError::NotConnected,
+                            )));
+                            cvar.notify_one();
+                        },
                         ConnectThreadRequests::StartPolling(h, p) => {
                             host = h.clone();
                             port = p;
@@ -272,6 +321,7 @@ impl EthernetBridge {
         addr: u32,
         value: u32,
     ) -> Result<(), BridgeError> {
+        let _span = trace::span("ethernet", "poke");
         debug!("POKE @ {:08x} -> {:08x}", addr, value);
         let mut buffer: [u8;20] = [
 
@@ -315,6 +365,7 @@ impl EthernetBridge {
     }
 
     fn do_peek(connection: &mut EthernetConnection, host: &String, port: u16, addr: u32) -> Result<u32, BridgeError> {
+        let _span = trace::span("ethernet", "peek");
         let mut buffer: [u8;20] = [
 
             // 0
@@ -367,6 +418,68 @@ impl EthernetBridge {
         Ok(val)
     }
 
+    /// Write `values` to consecutive words from `addr` as one record, which
+    /// gives the base address and then each value in turn
+    fn do_burst_poke(
+        connection: &mut EthernetConnection,
+        host: &String,
+        port: u16,
+        addr: u32,
+        values: &[u32],
+    ) -> Result<(), BridgeError> {
+        let _span = trace::span("ethernet", "burst poke");
+        debug!("POKE {} words @ {:08x}", values.len(), addr);
+        let mut buffer = record_header(values.len(), 0);
+        buffer.extend_from_slice(&addr.to_be_bytes());
+        for value in values {
+            buffer.extend_from_slice(&value.to_be_bytes());
+        }
+        match connection {
+            EthernetConnection::UDP(u) => u.send_to(&buffer, format!("{}:{}", host, port))?,
+            EthernetConnection::TCP(t) => {
+                t.write_all(&buffer)?;
+                buffer.len()
+            }
+        };
+        Ok(())
+    }
+
+    /// Read `count` consecutive words from `addr` as one record.  Reads
+    /// each give their own address, and come back as a write record with
+    /// the values in the same order.
+    fn do_burst_peek(
+        connection: &mut EthernetConnection,
+        host: &String,
+        port: u16,
+        addr: u32,
+        count: usize,
+    ) -> Result<Vec<u32>, BridgeError> {
+        let _span = trace::span("ethernet", "burst peek");
+        let mut buffer = record_header(0, count);
+        buffer.extend_from_slice(&0u32.to_be_bytes());
+        for word in 0..count as u32 {
+            buffer.extend_from_slice(&addr.wrapping_add(word * 4).to_be_bytes());
+        }
+        let amt = match connection {
+            EthernetConnection::UDP(u) => {
+                u.send_to(&buffer, format!("{}:{}", host, port))?;
+                let (amt, _src) = u.recv_from(&mut buffer)?;
+                amt
+            },
+            EthernetConnection::TCP(t) => {
+                t.write_all(&buffer)?;
+                t.read_exact(&mut buffer)?;
+                buffer.len()
+            }
+        };
+        if amt != buffer.len() {
+            return Err(BridgeError::LengthError(amt, buffer.len()));
+        }
+        let values: Vec<u32> = buffer[16..].chunks(4).map(BigEndian::read_u32).collect();
+        debug!("PEEK {} words @ {:08x}", count, addr);
+        Ok(values)
+    }
+
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
         let &(ref lock, ref cvar) = &*self.main_rx;
         let mut _mtx = lock.lock().unwrap();
@@ -404,6 +517,47 @@ impl EthernetBridge {
             }
         }
     }
+
+    /// Write up to `MAX_BURST_WORDS` of `values` to consecutive words
+    /// starting at `addr`.
+    pub fn burst_poke(&self, addr: u32, values: &[u32]) -> Result<(), BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstPoke(addr, values.to_vec()))
+            .expect("Unable to send poke to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::PokeResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    /// Read up to `MAX_BURST_WORDS` consecutive words starting at `addr`.
+    pub fn burst_peek(&self, addr: u32, count: usize) -> Result<Vec<u32>, BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstPeek(addr, count))
+            .expect("Unable to send peek to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstPeekResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
 }
 
 impl Drop for EthernetBridge {
@@ -2,15 +2,27 @@ pub mod uart;
 pub mod usb;
 pub mod spi;
 pub mod ethernet;
+pub mod sim;
+pub mod deglitch;
+mod posted;
 
 use crate::config::Config;
+use crate::trace;
 use usb::UsbBridge;
 use uart::UartBridge;
 use spi::SpiBridge;
 use ethernet::EthernetBridge;
+use sim::SimBridge;
+use posted::WritePoster;
 
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
 
 #[derive(Clone)]
 pub enum BridgeKind {
@@ -18,6 +30,20 @@ pub enum BridgeKind {
     UartBridge,
     SpiBridge,
     EthernetBridge,
+    SimBridge,
+}
+
+impl BridgeKind {
+    pub fn from_string(name: &str) -> Option<BridgeKind> {
+        match name {
+            "usb" => Some(BridgeKind::UsbBridge),
+            "uart" => Some(BridgeKind::UartBridge),
+            "spi" => Some(BridgeKind::SpiBridge),
+            "ethernet" => Some(BridgeKind::EthernetBridge),
+            "sim" => Some(BridgeKind::SimBridge),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -26,12 +52,62 @@ pub enum BridgeCore {
     UartBridge(UartBridge),
     SpiBridge(SpiBridge),
     EthernetBridge(EthernetBridge),
+    SimBridge(SimBridge),
 }
 
 #[derive(Clone)]
 pub struct Bridge {
-    core: BridgeCore,
+    /// The connection to the device, which can be swapped for another
+    /// kind while the bridge is in use
+    core: Arc<RwLock<BridgeCore>>,
+    bus_mutex: Arc<Mutex<()>>,
+    stats: Arc<BridgeStats>,
+
+    /// Held by the CPU for the length of a debug operation
     mutex: Arc<Mutex<()>>,
+
+    /// Largest number of bytes to move in a single burst
+    burst_size: Arc<AtomicU32>,
+
+    /// What's needed to open a different kind of bridge to the same device
+    cfg: Arc<Config>,
+
+    /// Where pokes go when they're posted rather than waited for
+    poster: Option<Arc<WritePoster>>,
+
+    /// Nanoseconds a single access takes, or zero if it hasn't been timed
+    latency: Arc<AtomicU64>,
+}
+
+/// Running totals of the traffic that has gone over a bridge.
+#[derive(Default)]
+pub struct BridgeStats {
+    reads: AtomicU64,
+    writes: AtomicU64,
+
+    /// Number of failed transactions that had to be tried again
+    retries: AtomicU64,
+
+    /// Number of reads that were outvoted by `--deglitch`
+    glitches: AtomicU64,
+}
+
+impl BridgeStats {
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn glitches(&self) -> u64 {
+        self.glitches.load(Ordering::Relaxed)
+    }
 }
 
 #[derive(Debug)]
@@ -54,6 +130,15 @@ pub enum BridgeError {
     /// We got nothing back from the bridge
     #[allow(dead_code)]
     Timeout,
+
+    /// Reads of an address never agreed on a good value
+    Unsettled(u32, Vec<u32>),
+
+    /// A write was attempted in a --read-only session
+    ReadOnly(u32),
+
+    /// One of the threads sharing out a parallel read died
+    WorkerFailed,
 }
 
 impl ::std::fmt::Display for BridgeError {
@@ -66,10 +151,60 @@ impl ::std::fmt::Display for BridgeError {
             NotConnected => write!(f, "bridge not connected"),
             WrongResponse => write!(f, "wrong response received"),
             Timeout => write!(f, "connection timed out"),
+            Unsettled(addr, values) => write!(f, "reads of 0x{:08x} never agreed: got {:08x?}", addr, values),
+            ReadOnly(addr) => write!(f, "refusing to write to 0x{:08x} with --read-only", addr),
+            WorkerFailed => write!(f, "a parallel read thread died before finishing"),
         }
     }
 }
 
+/// The reason a freshly-connected bridge failed its health check
+#[derive(Debug)]
+pub enum ProbeError {
+    /// The device is there, but nothing on the other side answered
+    GatewareNotLoaded(u32),
+
+    /// Something answered, but it doesn't behave like a scratch register
+    WrongCsrBase(u32),
+
+    /// The bridge never completed a wishbone transaction
+    WishboneTimeout(u32, BridgeError),
+}
+
+impl ::std::fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        use ProbeError::*;
+        match self {
+            GatewareNotLoaded(addr) => write!(f, "the device was found, but nothing answered at 0x{:08x} -- is the gateware loaded?", addr),
+            WrongCsrBase(addr) => write!(f, "0x{:08x} doesn't look like a scratch register -- is the csr base (or --csr-csv) correct?", addr),
+            WishboneTimeout(addr, e) => write!(f, "wishbone access to 0x{:08x} never completed ({})", addr, e),
+        }
+    }
+}
+
+/// Number of times the health check tries each access before giving up
+const PROBE_ATTEMPTS: u32 = 3;
+
+/// Number of accesses in a row that must fail before falling over from
+/// USB to Ethernet
+const FAILOVER_ATTEMPTS: u32 = 10;
+
+/// Where the LiteX scratch register lives when there's no csr.csv
+const DEFAULT_SCRATCH_ADDRESS: u32 = 0xe000_0004;
+
+/// Number of reads timed to work out how slow a bridge is
+const LATENCY_SAMPLES: u32 = 8;
+
+/// Bridges that take longer than this per access, such as bit-banged SPI,
+/// are slow enough that the GDB server changes how it works
+const SLOW_LATENCY: Duration = Duration::from_millis(5);
+
+/// Transfers expected to take longer than this get a warning first
+const LENGTHY_OPERATION: Duration = Duration::from_secs(60);
+
+/// Transfers smaller than this aren't worth timing the bridge for
+const LENGTHY_CHECK_BYTES: u32 = 4096;
+
 impl std::convert::From<libusb::Error> for BridgeError {
     fn from(e: libusb::Error) -> BridgeError {
         BridgeError::USBError(e)
@@ -84,61 +219,716 @@ impl std::convert::From<io::Error> for BridgeError {
 
 impl Bridge {
     pub fn new(cfg: &Config) -> Result<Bridge, BridgeError> {
-        let mutex = Arc::new(Mutex::new(()));
-        match cfg.bridge_kind {
-            BridgeKind::UartBridge => Ok(Bridge { mutex, core: BridgeCore::UartBridge(UartBridge::new(cfg)?) } ),
-            BridgeKind::UsbBridge => Ok(Bridge { mutex, core: BridgeCore::UsbBridge(UsbBridge::new(cfg)?) } ),
-            BridgeKind::SpiBridge => Ok(Bridge { mutex, core: BridgeCore::SpiBridge(SpiBridge::new(cfg)?) } ),
-            BridgeKind::EthernetBridge => Ok(Bridge { mutex, core: BridgeCore::EthernetBridge(EthernetBridge::new(cfg)?) } ),
+        let bus_mutex = Arc::new(Mutex::new(()));
+        let stats = Arc::new(BridgeStats::default());
+        let core = match cfg.bridge_kind {
+            BridgeKind::UartBridge => BridgeCore::UartBridge(UartBridge::new(cfg)?),
+            BridgeKind::UsbBridge => BridgeCore::UsbBridge(UsbBridge::new(cfg)?),
+            BridgeKind::SpiBridge => BridgeCore::SpiBridge(SpiBridge::new(cfg)?),
+            BridgeKind::EthernetBridge => BridgeCore::EthernetBridge(EthernetBridge::new(cfg)?),
+            BridgeKind::SimBridge => BridgeCore::SimBridge(SimBridge::new(cfg)?),
+        };
+        let burst_size = Arc::new(AtomicU32::new(cfg.burst_size.unwrap_or_else(|| core.max_burst())));
+        let mutex = core.mutex().clone();
+        let core = Arc::new(RwLock::new(core));
+        let poster = if cfg.posted_writes { Some(Arc::new(WritePoster::new())) } else { None };
+        let cfg = Arc::new(cfg.clone());
+        let latency = Arc::new(AtomicU64::new(0));
+        Ok(Bridge { bus_mutex, mutex, stats, core, burst_size, cfg, poster, latency })
+    }
+
+    /// Return a copy of this bridge that keeps its own statistics, so the
+    /// traffic from one operation can be told apart from another's.
+    pub fn with_new_stats(&self) -> Bridge {
+        Bridge {
+            stats: Arc::new(BridgeStats::default()),
+            ..self.clone()
         }
     }
 
-    pub fn connect(&self) -> Result<(), BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
-        match &self.core{
-            BridgeCore::UsbBridge(b) => b.connect(),
-            BridgeCore::UartBridge(b) => b.connect(),
-            BridgeCore::SpiBridge(b) => b.connect(),
-            BridgeCore::EthernetBridge(b) => b.connect(),
+    pub fn burst_size(&self) -> u32 {
+        self.burst_size.load(Ordering::Relaxed)
+    }
+
+    /// Time a few reads of the scratch register, and remember how long a
+    /// single access takes.
+    pub fn measure_latency(&self) -> Result<Duration, BridgeError> {
+        let scratch = *self
+            .cfg
+            .register_mapping
+            .get("ctrl_scratch")
+            .unwrap_or(&DEFAULT_SCRATCH_ADDRESS);
+        let start = Instant::now();
+        for _ in 0..LATENCY_SAMPLES {
+            self.peek(scratch)?;
         }
+        let latency = start.elapsed() / LATENCY_SAMPLES;
+        self.latency.store(latency.as_nanos().max(1) as u64, Ordering::Relaxed);
+        Ok(latency)
     }
 
-    pub fn mutex(&self) -> &Arc<Mutex<()>> {
-        match &self.core {
-            BridgeCore::UsbBridge(b) => b.mutex(),
-            BridgeCore::UartBridge(b) => b.mutex(),
-            BridgeCore::SpiBridge(b) => b.mutex(),
-            BridgeCore::EthernetBridge(b) => b.mutex(),
+    /// How long an access took when the bridge was last timed
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
         }
     }
 
+    /// Whether the bridge has been timed and found to be very slow
+    pub fn is_slow(&self) -> bool {
+        self.latency().is_some_and(|l| l >= SLOW_LATENCY)
+    }
+
+    /// Warn if moving `bytes` a word at a time is going to take minutes,
+    /// timing the bridge first if that hasn't been done yet.
+    pub fn warn_if_lengthy(&self, what: &str, bytes: u32) {
+        if bytes < LENGTHY_CHECK_BYTES {
+            return;
+        }
+        let latency = match self.latency() {
+            Some(l) => l,
+            None => match self.measure_latency() {
+                Ok(l) => l,
+                Err(_) => return,
+            },
+        };
+        let estimate = latency * bytes.div_ceil(4);
+        if estimate >= LENGTHY_OPERATION {
+            warn!(
+                "{} {} bytes will take about {} minutes at {:?} per access",
+                what,
+                bytes,
+                estimate.as_secs().div_ceil(60),
+                latency
+            );
+        }
+    }
+
+    /// The short name of the kind of bridge currently in use.
+    pub fn kind_name(&self) -> &'static str {
+        match &*self.core() {
+            BridgeCore::UsbBridge(_) => "usb",
+            BridgeCore::UartBridge(_) => "uart",
+            BridgeCore::SpiBridge(_) => "spi",
+            BridgeCore::EthernetBridge(_) => "ethernet",
+            BridgeCore::SimBridge(_) => "sim",
+        }
+    }
+
+    /// Carry on over a different kind of bridge to the same device.  Every
+    /// copy of this bridge, and so every server using it, moves over once
+    /// whatever it's in the middle of has finished.
+    pub fn switch(&self, kind: BridgeKind) -> Result<(), BridgeError> {
+        let _mtx = self.lock();
+        self.switch_locked(kind)
+    }
+
+    fn switch_locked(&self, kind: BridgeKind) -> Result<(), BridgeError> {
+        let mut cfg = (*self.cfg).clone();
+        cfg.bridge_kind = kind.clone();
+        if let BridgeKind::EthernetBridge = kind {
+            if cfg.ethernet_host.is_none() {
+                return Err(BridgeError::NotConnected);
+            }
+        }
+        let core = match kind {
+            BridgeKind::UartBridge => BridgeCore::UartBridge(UartBridge::new(&cfg)?),
+            BridgeKind::UsbBridge => BridgeCore::UsbBridge(UsbBridge::new(&cfg)?),
+            BridgeKind::SpiBridge => BridgeCore::SpiBridge(SpiBridge::new(&cfg)?),
+            BridgeKind::EthernetBridge => BridgeCore::EthernetBridge(EthernetBridge::new(&cfg)?),
+            BridgeKind::SimBridge => BridgeCore::SimBridge(SimBridge::new(&cfg)?),
+        };
+        core.connect()?;
+        if cfg.burst_size.is_none() {
+            self.burst_size.store(core.max_burst(), Ordering::Relaxed);
+        }
+        *self.core.write().unwrap() = core;
+        info!("switched to the {} bridge", self.kind_name());
+        Ok(())
+    }
+
+    fn core(&self) -> RwLockReadGuard<'_, BridgeCore> {
+        self.core.read().unwrap()
+    }
+
+    pub fn stats(&self) -> &Arc<BridgeStats> {
+        &self.stats
+    }
+
+    /// Open up to `count` independent connections to the same device,
+    /// including this one, that can all be used at once.  Bridges that
+    /// only allow a single connection just return themselves.
+    pub fn connections(&self, cfg: &Config, count: u32) -> Result<Vec<Bridge>, BridgeError> {
+        let mut connections = vec![self.clone()];
+        if count <= 1 {
+            return Ok(connections);
+        }
+        match &*self.core() {
+            // Each TCP connection gets its own socket and polling thread.
+            // UDP ones all bind the same local port, so they can't.
+            BridgeCore::EthernetBridge(_) if cfg.ethernet_tcp => {
+                for _ in 1..count {
+                    let bridge = Bridge::new(cfg)?;
+                    bridge.connect()?;
+                    connections.push(bridge);
+                }
+            }
+            // Simulated memory can be shared between as many bridges as
+            // there are threads.
+            BridgeCore::SimBridge(_) => {
+                for _ in 1..count {
+                    connections.push(Bridge {
+                        bus_mutex: Arc::new(Mutex::new(())),
+                        ..self.clone()
+                    });
+                }
+            }
+            _ => warn!("this bridge only supports one connection, ignoring --connections"),
+        }
+        Ok(connections)
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        let _mtx = self.bus_mutex.lock().unwrap();
+        self.core().connect()
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
     pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
+        self.flush()?;
+        let _span = trace::span("bridge", "peek");
+        let _mtx = self.lock();
+        self.peek_locked(addr)
+    }
+
+    /// Write `value` to `addr`.  With posted writes this returns as soon as
+    /// the write is queued, and a failure is reported by a later access.
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        if let Some(poster) = &self.poster {
+            let direct = Bridge { poster: None, ..self.clone() };
+            return poster.post(direct, addr, value);
+        }
+        let _span = trace::span("bridge", "poke");
+        let _mtx = self.lock();
+        self.poke_locked(addr, value)
+    }
+
+    /// Wait for every posted write to be committed, returning the error
+    /// from the first one to fail.  Every other kind of access does this
+    /// first, so writes are never reordered with reads.
+    pub fn flush(&self) -> Result<(), BridgeError> {
+        match &self.poster {
+            Some(poster) => poster.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait until every write so far has actually reached the device: get
+    /// any posted writes committed, then read back `readback`, or the
+    /// scratch register if that's not given.  The bus can't answer the
+    /// read until the writes ahead of it are done, so anything after this
+    /// happens after them, such as waiting for a PLL to lock.
+    pub fn fence(&self, readback: Option<u32>) -> Result<(), BridgeError> {
+        self.flush()?;
+        let addr = readback.unwrap_or_else(|| {
+            *self
+                .cfg
+                .register_mapping
+                .get("ctrl_scratch")
+                .unwrap_or(&DEFAULT_SCRATCH_ADDRESS)
+        });
+        let _span = trace::span("bridge", "fence");
+        let _mtx = self.lock();
+        self.peek_locked(addr).map(|_| ())
+    }
+
+    /// The end of a request from a client: with `--sync`, make sure all
+    /// of its writes have landed before answering.
+    pub fn barrier(&self) -> Result<(), BridgeError> {
+        if self.cfg.posted_sync {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take the bridge, noting how long another thread held it up.
+    fn lock(&self) -> std::sync::MutexGuard<'_, ()> {
+        let _span = trace::span("bridge", "wait for bridge");
+        self.bus_mutex.lock().unwrap()
+    }
+
+    /// Write each of `values` to the same address in turn, such as a FIFO,
+    /// holding the bridge for the whole batch.
+    pub fn poke_all(&self, addr: u32, values: &[u32]) -> Result<(), BridgeError> {
+        self.flush()?;
+        let _span = trace::span("bridge", "poke all");
+        let _mtx = self.lock();
+        for value in values {
+            self.poke_locked(addr, *value)?;
+        }
+        Ok(())
+    }
+
+    /// Read `len` bytes starting at the word-aligned address `addr`.
+    /// The transfer is split into bursts that never cross a multiple of
+    /// the burst size, and the bridge is held for the length of each burst.
+    /// Bridges whose protocol can move several words at once send each
+    /// burst as one request, and the rest go a word at a time.
+    pub fn burst_read(&self, addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+        self.flush()?;
+        self.warn_if_lengthy("reading", len);
+        let mut data = Vec::with_capacity(len as usize);
+        for (start, count) in bursts(addr, len, self.burst_size()) {
+            let _span = trace::span("bridge", "burst read");
+            let _mtx = self.lock();
+            for value in self.burst_peek_locked(start, count as usize / 4)? {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        data.truncate(len as usize);
+        Ok(data)
+    }
+
+    /// Write `data` starting at the word-aligned address `addr`, padding
+    /// the final word with zeroes.  See `burst_read()` for how the
+    /// transfer is split up.
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        self.flush()?;
+        self.warn_if_lengthy("writing", data.len() as u32);
+        for (start, count) in bursts(addr, data.len() as u32, self.burst_size()) {
+            let _span = trace::span("bridge", "burst write");
+            let _mtx = self.lock();
+            let first = (start - addr) as usize;
+            let last = data.len().min(first + count as usize);
+            let values: Vec<u32> = data[first..last]
+                .chunks(4)
+                .map(|word| {
+                    let mut buf = [0; 4];
+                    buf[..word.len()].copy_from_slice(word);
+                    u32::from_le_bytes(buf)
+                })
+                .collect();
+            self.burst_poke_locked(start, &values)?;
+        }
+        Ok(())
+    }
+
+    /// Make sure there is working gateware on the other end of the bridge
+    /// by writing to the scratch register and reading it back, and log the
+    /// SoC identifier if there is one.  With --read-only, the scratch
+    /// register only has to be read.
+    ///
+    /// Only the bits the first CSR word carries are compared, since on a
+    /// SoC with 8-bit CSRs the rest don't stick.  Without a csr.csv the
+    /// width isn't known, so only the low byte is.
+    pub fn health_check(&self, cfg: &Config) -> Result<(), ProbeError> {
+        let scratch = *cfg
+            .register_mapping
+            .get("ctrl_scratch")
+            .unwrap_or(&DEFAULT_SCRATCH_ADDRESS);
+        let mask = match cfg.csr_registers.iter().find(|r| r.name == "ctrl_scratch") {
+            Some(reg) if reg.data_width < 32 => (1 << reg.data_width) - 1,
+            Some(_) => 0xffff_ffff,
+            None => 0xff,
+        };
+        let _mtx = self.bus_mutex.lock().unwrap();
+
+        let original = self.try_peek(scratch)? & mask;
+        if !cfg.read_only {
+            let pattern = !original & mask;
+            self.try_poke(scratch, pattern)?;
+            let readback = self.try_peek(scratch)? & mask;
+            self.try_poke(scratch, original)?;
+            if readback != pattern {
+                if (original == 0 || original == mask) && readback == original {
+                    return Err(ProbeError::GatewareNotLoaded(scratch));
+                }
+                return Err(ProbeError::WrongCsrBase(scratch));
+            }
+        }
+
+        // The identifier is a NUL-terminated string, one byte per word.
+        if let Some(&ident) = cfg.register_mapping.get("identifier_mem") {
+            let mut name = String::new();
+            for offset in 0..256 {
+                match self.try_peek(ident + offset * 4)? as u8 {
+                    0 => break,
+                    c => name.push(c as char),
+                }
+            }
+            info!("connected to {}", name);
+        }
+        Ok(())
+    }
+
+    /// Read the SoC identifier, if the gateware has one.
+    pub fn identifier(&self, cfg: &Config) -> Result<Option<String>, BridgeError> {
+        let ident = match cfg.register_mapping.get("identifier_mem") {
+            Some(&ident) => ident,
+            None => return Ok(None),
+        };
+        let mut name = String::new();
+        for offset in 0..256 {
+            match self.peek(ident + offset * 4)? as u8 {
+                0 => break,
+                c => name.push(c as char),
+            }
+        }
+        Ok(Some(name))
+    }
+
+    fn try_peek(&self, addr: u32) -> Result<u32, ProbeError> {
+        let mut attempt = 0;
+        loop {
+            let result = match &*self.core() {
+                BridgeCore::UsbBridge(b) => b.peek(addr),
+                BridgeCore::UartBridge(b) => b.peek(addr),
+                BridgeCore::SpiBridge(b) => b.peek(addr),
+                BridgeCore::EthernetBridge(b) => b.peek(addr),
+                BridgeCore::SimBridge(b) => b.peek(addr),
+            };
+            attempt += 1;
+            match result {
+                Ok(value) => {
+                    self.stats.reads.fetch_add(1, Ordering::Relaxed);
+                    return Ok(value);
+                }
+                Err(e) if attempt >= PROBE_ATTEMPTS => return Err(ProbeError::WishboneTimeout(addr, e)),
+                Err(_) => self.stats.retries.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+    }
+
+    fn try_poke(&self, addr: u32, value: u32) -> Result<(), ProbeError> {
+        let mut attempt = 0;
+        loop {
+            let result = match &*self.core() {
+                BridgeCore::UsbBridge(b) => b.poke(addr, value),
+                BridgeCore::UartBridge(b) => b.poke(addr, value),
+                BridgeCore::SpiBridge(b) => b.poke(addr, value),
+                BridgeCore::EthernetBridge(b) => b.poke(addr, value),
+                BridgeCore::SimBridge(b) => b.poke(addr, value),
+            };
+            attempt += 1;
+            match result {
+                Ok(()) => {
+                    self.stats.writes.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(e) if attempt >= PROBE_ATTEMPTS => return Err(ProbeError::WishboneTimeout(addr, e)),
+                Err(_) => self.stats.retries.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+    }
+
+    /// Read `addr`, voting on it if `--deglitch` covers it
+    fn peek_locked(&self, addr: u32) -> Result<u32, BridgeError> {
+        let first = self.peek_once_locked(addr)?;
+        let policy = match self.cfg.deglitch.iter().find(|p| p.covers(addr)) {
+            Some(policy) => policy,
+            None => return Ok(first),
+        };
+        let (value, discarded) = policy.settle(addr, first, || self.peek_once_locked(addr))?;
+        self.stats.glitches.fetch_add(discarded as u64, Ordering::Relaxed);
+        Ok(value)
+    }
+
+    fn peek_once_locked(&self, addr: u32) -> Result<u32, BridgeError> {
+        let mut failures = 0;
         loop {
-            let result = match &self.core {
+            let result = match &*self.core() {
                 BridgeCore::UsbBridge(b) => b.peek(addr),
                 BridgeCore::UartBridge(b) => b.peek(addr),
                 BridgeCore::SpiBridge(b) => b.peek(addr),
                 BridgeCore::EthernetBridge(b) => b.peek(addr),
+                BridgeCore::SimBridge(b) => b.peek(addr),
             };
             if result.is_ok() {
+                self.stats.reads.fetch_add(1, Ordering::Relaxed);
                 return result;
             }
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            failures += 1;
+            self.maybe_fail_over(failures);
         }
     }
 
-    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
+    /// Read `count` consecutive words from `addr`.  Words that --deglitch
+    /// covers have to be voted on one at a time, so a burst that touches
+    /// them goes a word at a time as well.
+    fn burst_peek_locked(&self, addr: u32, count: usize) -> Result<Vec<u32>, BridgeError> {
+        let deglitched = (0..count as u32)
+            .any(|word| self.cfg.deglitch.iter().any(|p| p.covers(addr.wrapping_add(word * 4))));
+        if deglitched || !self.core().bursts() {
+            return (0..count as u32).map(|word| self.peek_locked(addr + word * 4)).collect();
+        }
+        let mut failures = 0;
         loop {
-            let result = match &self.core {
+            let result = self.core().burst_peek(addr, count);
+            if result.is_ok() {
+                self.stats.reads.fetch_add(count as u64, Ordering::Relaxed);
+                return result;
+            }
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            failures += 1;
+            self.maybe_fail_over(failures);
+        }
+    }
+
+    /// Write `values` to consecutive words from `addr`
+    fn burst_poke_locked(&self, addr: u32, values: &[u32]) -> Result<(), BridgeError> {
+        if !self.core().bursts() {
+            for (word, value) in values.iter().enumerate() {
+                self.poke_locked(addr + word as u32 * 4, *value)?;
+            }
+            return Ok(());
+        }
+        if self.cfg.read_only {
+            return Err(BridgeError::ReadOnly(addr));
+        }
+        let mut failures = 0;
+        loop {
+            let result = self.core().burst_poke(addr, values);
+            if result.is_ok() {
+                self.stats.writes.fetch_add(values.len() as u64, Ordering::Relaxed);
+                return result;
+            }
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            failures += 1;
+            self.maybe_fail_over(failures);
+        }
+    }
+
+    fn poke_locked(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        // Everything that writes ends up here, whatever it didn't check
+        if self.cfg.read_only {
+            return Err(BridgeError::ReadOnly(addr));
+        }
+        let mut failures = 0;
+        loop {
+            let result = match &*self.core() {
                 BridgeCore::UsbBridge(b) => b.poke(addr, value),
                 BridgeCore::UartBridge(b) => b.poke(addr, value),
                 BridgeCore::SpiBridge(b) => b.poke(addr, value),
                 BridgeCore::EthernetBridge(b) => b.poke(addr, value),
+                BridgeCore::SimBridge(b) => b.poke(addr, value),
             };
             if result.is_ok() {
+                self.stats.writes.fetch_add(1, Ordering::Relaxed);
                 return result;
             }
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            failures += 1;
+            self.maybe_fail_over(failures);
+        }
+    }
+
+    /// If USB has stopped answering and there's an Ethernet link to the
+    /// same board, move over to that.
+    fn maybe_fail_over(&self, failures: u32) {
+        if !self.cfg.bridge_failover || !failures.is_multiple_of(FAILOVER_ATTEMPTS) {
+            return;
+        }
+        if !matches!(&*self.core(), BridgeCore::UsbBridge(_)) {
+            return;
+        }
+        warn!("usb bridge stopped responding, falling over to ethernet");
+        if let Err(e) = self.switch_locked(BridgeKind::EthernetBridge) {
+            warn!("unable to fall over to ethernet: {}", e);
+        }
+    }
+}
+
+impl BridgeCore {
+    fn connect(&self) -> Result<(), BridgeError> {
+        match self {
+            BridgeCore::UsbBridge(b) => b.connect(),
+            BridgeCore::UartBridge(b) => b.connect(),
+            BridgeCore::SpiBridge(b) => b.connect(),
+            BridgeCore::EthernetBridge(b) => b.connect(),
+            BridgeCore::SimBridge(b) => b.connect(),
+        }
+    }
+
+    fn mutex(&self) -> &Arc<Mutex<()>> {
+        match self {
+            BridgeCore::UsbBridge(b) => b.mutex(),
+            BridgeCore::UartBridge(b) => b.mutex(),
+            BridgeCore::SpiBridge(b) => b.mutex(),
+            BridgeCore::EthernetBridge(b) => b.mutex(),
+            BridgeCore::SimBridge(b) => b.mutex(),
+        }
+    }
+
+    /// Whether this bridge's protocol can read or write several
+    /// consecutive words in one request
+    fn bursts(&self) -> bool {
+        matches!(self, BridgeCore::UartBridge(_) | BridgeCore::EthernetBridge(_))
+    }
+
+    /// The default burst size, in bytes.  UART and Etherbone requests
+    /// carry up to 255 words, so a burst is the largest power of two that
+    /// fits.  The USB and SPI bridges only move a word at a time, and
+    /// simulated memory is local, so its bursts are only how long the
+    /// bridge is held for.
+    fn max_burst(&self) -> u32 {
+        match self {
+            BridgeCore::UsbBridge(_) => 4,
+            BridgeCore::UartBridge(_) => 512,
+            BridgeCore::SpiBridge(_) => 4,
+            BridgeCore::EthernetBridge(_) => 512,
+            BridgeCore::SimBridge(_) => 4096,
+        }
+    }
+
+    /// The most words one request can carry
+    fn burst_words(&self) -> usize {
+        match self {
+            BridgeCore::UartBridge(_) => uart::MAX_BURST_WORDS,
+            BridgeCore::EthernetBridge(_) => ethernet::MAX_BURST_WORDS,
+            _ => 1,
+        }
+    }
+
+    /// Read `count` consecutive words from `addr`, in as few requests as
+    /// will hold them.  Only for bridges that `bursts()`.
+    fn burst_peek(&self, addr: u32, count: usize) -> Result<Vec<u32>, BridgeError> {
+        let mut values = Vec::with_capacity(count);
+        while values.len() < count {
+            let start = addr + values.len() as u32 * 4;
+            let words = (count - values.len()).min(self.burst_words());
+            values.extend(match self {
+                BridgeCore::UartBridge(b) => b.burst_peek(start, words)?,
+                BridgeCore::EthernetBridge(b) => b.burst_peek(start, words)?,
+                _ => unreachable!("this bridge can't burst"),
+            });
+        }
+        Ok(values)
+    }
+
+    /// Write `values` to consecutive words from `addr`.  Only for bridges
+    /// that `bursts()`.
+    fn burst_poke(&self, addr: u32, values: &[u32]) -> Result<(), BridgeError> {
+        let per_request = self.burst_words();
+        for (idx, chunk) in values.chunks(per_request).enumerate() {
+            let start = addr + (idx * per_request) as u32 * 4;
+            match self {
+                BridgeCore::UartBridge(b) => b.burst_poke(start, chunk)?,
+                BridgeCore::EthernetBridge(b) => b.burst_poke(start, chunk)?,
+                _ => unreachable!("this bridge can't burst"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read `len` bytes starting at `addr`, sharing the bursts out between
+/// `connections` and putting them back in order as they arrive.
+pub fn parallel_read(connections: &[Bridge], addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+    if connections.len() <= 1 {
+        return connections[0].burst_read(addr, len);
+    }
+    let bursts = Arc::new(bursts(addr, len, connections[0].burst_size()));
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = channel();
+    let mut threads = vec![];
+    for bridge in connections {
+        let bridge = bridge.clone();
+        let bursts = bursts.clone();
+        let next = next.clone();
+        let tx = tx.clone();
+        threads.push(thread::spawn(move || loop {
+            let idx = next.fetch_add(1, Ordering::Relaxed);
+            let (start, count) = match bursts.get(idx) {
+                Some(b) => *b,
+                None => return,
+            };
+            let result = bridge.burst_read(start, count);
+            let failed = result.is_err();
+            if tx.send((idx, result)).is_err() || failed {
+                return;
+            }
+        }));
+    }
+    drop(tx);
+
+    let mut pieces: Vec<Option<Vec<u8>>> = vec![None; bursts.len()];
+    let mut error = None;
+    for (idx, result) in rx {
+        match result {
+            Ok(data) => pieces[idx] = Some(data),
+            Err(e) => {
+                // Stop the other threads from starting any more bursts
+                next.store(bursts.len(), Ordering::Relaxed);
+                error.get_or_insert(e);
+            }
         }
     }
+    for thr in threads {
+        if thr.join().is_err() {
+            error.get_or_insert(BridgeError::WorkerFailed);
+        }
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    let mut data = Vec::with_capacity(len as usize);
+    for piece in pieces {
+        // A thread that died without saying so leaves its burst missing
+        data.extend_from_slice(&piece.ok_or(BridgeError::WorkerFailed)?);
+    }
+    data.truncate(len as usize);
+    Ok(data)
+}
+
+/// Split the transfer of `len` bytes at `addr` into `(address, length)`
+/// bursts of at most `max` bytes.  Each burst stops at the next multiple
+/// of `max`, so no burst straddles an alignment (or cache line) boundary.
+/// `max` must be a power of two, and lengths are rounded up to whole words.
+/// Nothing runs past the top of the address space.
+pub fn bursts(addr: u32, len: u32, max: u32) -> Vec<(u32, u32)> {
+    let mut bursts = vec![];
+    let end = ((addr as u64 + len as u64 + 3) & !3).min(1 << 32);
+    let mut start = addr as u64;
+    while start < end {
+        let boundary = (start & !(max as u64 - 1)) + max as u64;
+        let stop = boundary.min(end);
+        bursts.push((start as u32, (stop - start) as u32));
+        start = stop;
+    }
+    bursts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_split_at_boundaries() {
+        assert_eq!(bursts(0x1000, 0, 512), vec![]);
+        assert_eq!(bursts(0x1000, 1, 512), vec![(0x1000, 4)]);
+        assert_eq!(bursts(0x11f0, 0x20, 512), vec![(0x11f0, 0x10), (0x1200, 0x10)]);
+        assert_eq!(
+            bursts(0x1000, 0x500, 512),
+            vec![(0x1000, 0x200), (0x1200, 0x200), (0x1400, 0x100)]
+        );
+    }
+
+    #[test]
+    fn bursts_stop_at_the_top_of_memory() {
+        assert_eq!(bursts(0xffff_ff00, 0x100, 512), vec![(0xffff_ff00, 0x100)]);
+        assert_eq!(
+            bursts(0xffff_fe00, 0x200, 256),
+            vec![(0xffff_fe00, 0x100), (0xffff_ff00, 0x100)]
+        );
+        assert_eq!(bursts(0xffff_fffc, 4, 4), vec![(0xffff_fffc, 4)]);
+        assert_eq!(bursts(0xffff_fffc, 0xffff_ffff, 4), vec![(0xffff_fffc, 4)]);
+        assert_eq!(bursts(0, 0xffff_fffd, 0x8000_0000).len(), 2);
+    }
 }
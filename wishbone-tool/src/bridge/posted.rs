@@ -0,0 +1,84 @@
+//! Posted writes: a poke is handed to a writer thread and returns straight
+//! away, and anything that went wrong is reported by the next access.
+
+use super::{Bridge, BridgeError};
+
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+struct PostedWrite {
+    /// The bridge to commit the write through, which doesn't post
+    bridge: Bridge,
+    addr: u32,
+    value: u32,
+}
+
+pub struct WritePoster {
+    queue: Mutex<Sender<PostedWrite>>,
+
+    /// Number of writes that haven't been committed yet
+    pending: Arc<(Mutex<usize>, Condvar)>,
+
+    /// The first write to fail since it was last reported
+    error: Arc<Mutex<Option<BridgeError>>>,
+}
+
+impl WritePoster {
+    pub fn new() -> WritePoster {
+        let (tx, rx) = channel::<PostedWrite>();
+        let pending = Arc::new((Mutex::new(0), Condvar::new()));
+        let error = Arc::new(Mutex::new(None));
+
+        let thr_pending = pending.clone();
+        let thr_error = error.clone();
+        thread::spawn(move || {
+            for write in rx {
+                if let Err(e) = write.bridge.poke(write.addr, write.value) {
+                    thr_error.lock().unwrap().get_or_insert(e);
+                }
+                let (count, cv) = &*thr_pending;
+                *count.lock().unwrap() -= 1;
+                cv.notify_all();
+            }
+        });
+
+        WritePoster {
+            queue: Mutex::new(tx),
+            pending,
+            error,
+        }
+    }
+
+    /// Queue up a write, unless an earlier one has failed.
+    pub fn post(&self, bridge: Bridge, addr: u32, value: u32) -> Result<(), BridgeError> {
+        if let Some(e) = self.error.lock().unwrap().take() {
+            return Err(e);
+        }
+        *self.pending.0.lock().unwrap() += 1;
+        self.queue
+            .lock()
+            .unwrap()
+            .send(PostedWrite {
+                bridge,
+                addr,
+                value,
+            })
+            .map_err(|_| BridgeError::NotConnected)
+    }
+
+    /// Wait for every write queued so far to be committed, and report the
+    /// first of them to fail.
+    pub fn flush(&self) -> Result<(), BridgeError> {
+        let (count, cv) = &*self.pending;
+        let mut count = count.lock().unwrap();
+        while *count > 0 {
+            count = cv.wait(count).unwrap();
+        }
+        drop(count);
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::info;
+
+use super::BridgeError;
+use crate::config::{parse_u32, Config, ConfigError};
+
+/// Behavioral models that can be attached to the simulated bus.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimPeripheralKind {
+    /// A LiteX UART.  Anything written to `rxtx` is printed to stdout, and
+    /// the receive FIFO is always empty.
+    Uart,
+
+    /// A free-running counter that increments once every microsecond.
+    Timer,
+
+    /// A read/write register that starts out as 0x12345678, like the one
+    /// in the LiteX `ctrl` block.
+    Scratch,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimPeripheral {
+    pub kind: SimPeripheralKind,
+    pub base: u32,
+}
+
+impl SimPeripheral {
+    /// Parse a peripheral spec of the form `kind@address`, e.g. `uart@0xe0001800`.
+    pub fn from_string(spec: &str) -> Result<Self, ConfigError> {
+        let fields: Vec<&str> = spec.split('@').collect();
+        if fields.len() != 2 {
+            return Err(ConfigError::InvalidConfig(format!(
+                "{} is not a valid peripheral -- must be KIND@ADDRESS (e.g. \"uart@0xe0001800\")",
+                spec
+            )));
+        }
+        let kind = match fields[0] {
+            "uart" => SimPeripheralKind::Uart,
+            "timer" => SimPeripheralKind::Timer,
+            "scratch" => SimPeripheralKind::Scratch,
+            other => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "unknown peripheral \"{}\" -- must be one of uart, timer, or scratch",
+                    other
+                )))
+            }
+        };
+        Ok(SimPeripheral {
+            kind,
+            base: parse_u32(fields[1])?,
+        })
+    }
+
+    fn size(&self) -> u32 {
+        match self.kind {
+            SimPeripheralKind::Uart => 0x20,
+            SimPeripheralKind::Timer => 4,
+            SimPeripheralKind::Scratch => 4,
+        }
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        addr >= self.base && addr - self.base < self.size()
+    }
+}
+
+/// A bridge that isn't connected to anything.  Memory reads back whatever
+/// was last written to it, and any peripherals are modeled in software.
+#[derive(Clone)]
+pub struct SimBridge {
+    peripherals: Vec<SimPeripheral>,
+    memory: Arc<Mutex<HashMap<u32, u32>>>,
+    start: Instant,
+    mutex: Arc<Mutex<()>>,
+}
+
+impl SimBridge {
+    pub fn new(cfg: &Config) -> Result<Self, BridgeError> {
+        let mut memory = HashMap::new();
+        for peripheral in &cfg.sim_peripherals {
+            info!(
+                "simulating {:?} at 0x{:08x}",
+                peripheral.kind, peripheral.base
+            );
+            // The scratch register behaves just like memory, aside from
+            // its reset value.
+            if peripheral.kind == SimPeripheralKind::Scratch {
+                memory.insert(peripheral.base, 0x12345678);
+            }
+        }
+        Ok(SimBridge {
+            peripherals: cfg.sim_peripherals.clone(),
+            memory: Arc::new(Mutex::new(memory)),
+            start: Instant::now(),
+            mutex: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    fn peripheral_at(&self, addr: u32) -> Option<&SimPeripheral> {
+        self.peripherals.iter().find(|p| p.contains(addr))
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let addr = addr & !3;
+        match self.peripheral_at(addr) {
+            Some(p) if p.kind == SimPeripheralKind::Uart => match addr - p.base {
+                // rxempty
+                0x08 => Ok(1),
+                // txempty
+                0x18 => Ok(1),
+                _ => Ok(0),
+            },
+            Some(p) if p.kind == SimPeripheralKind::Timer => {
+                Ok(self.start.elapsed().as_micros() as u32)
+            }
+            _ => Ok(*self.memory.lock().unwrap().get(&addr).unwrap_or(&0)),
+        }
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        let addr = addr & !3;
+        match self.peripheral_at(addr) {
+            Some(p) if p.kind == SimPeripheralKind::Uart => {
+                // rxtx
+                if addr == p.base {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&[value as u8])?;
+                    stdout.flush()?;
+                }
+            }
+            // The timer can't be written to
+            Some(p) if p.kind == SimPeripheralKind::Timer => (),
+            _ => {
+                self.memory.lock().unwrap().insert(addr, value);
+            }
+        }
+        Ok(())
+    }
+}
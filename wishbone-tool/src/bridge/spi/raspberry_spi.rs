@@ -15,6 +15,7 @@ use rppal::gpio::Mode::{Input, Output};
 
 use crate::bridge::BridgeError;
 use crate::config::Config;
+use crate::trace;
 
 const TIMEOUT_COUNT: u32 = 20000;
 
@@ -289,6 +290,7 @@ impl SpiBridge {
         addr: u32,
         value: u32,
     ) -> Result<(), BridgeError> {
+        let _span = trace::span("spi", "poke");
         debug!("poke: writing 0x{:08x} to 0x{:08x}", value, addr);
         let write_cmd = 0;
 
@@ -330,6 +332,7 @@ impl SpiBridge {
     }
 
     fn do_peek(pins: &mut SpiPins, addr: u32) -> Result<u32, BridgeError> {
+        let _span = trace::span("spi", "peek");
         let read_cmd = 1;
         Self::do_start(pins);
 
@@ -13,6 +13,7 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use super::BridgeError;
 use crate::config::Config;
+use crate::trace;
 
 pub struct UartBridge {
     path: String,
@@ -41,6 +42,8 @@ enum ConnectThreadRequests {
     Exit,
     Poke(u32 /* addr */, u32 /* val */),
     Peek(u32 /* addr */),
+    BurstPoke(u32 /* addr */, Vec<u32> /* values */),
+    BurstPeek(u32 /* addr */, u8 /* count */),
 }
 
 #[derive(Debug)]
@@ -49,8 +52,13 @@ enum ConnectThreadResponses {
     OpenedDevice,
     PeekResult(Result<u32, BridgeError>),
     PokeResult(Result<(), BridgeError>),
+    BurstPeekResult(Result<Vec<u32>, BridgeError>),
 }
 
+/// The most words a single read or write can move, since the count is a
+/// byte
+pub const MAX_BURST_WORDS: usize = 255;
+
 impl UartBridge {
     pub fn new(cfg: &Config) -> Result<Self, BridgeError> {
         let (main_tx, thread_rx) = channel();
@@ -162,6 +170,24 @@ impl UartBridge {
                             *response.lock().unwrap() = Some(ConnectThreadResponses::PokeResult(result));
                             cvar.notify_one();
                         }
+                        ConnectThreadRequests::BurstPeek(addr, count) => {
+                            let result = Self::do_burst_peek(&mut port, addr, count);
+                            if let Err(err) = &result {
+                                result_error = format!("burst peek {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::BurstPeekResult(result));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstPoke(addr, values) => {
+                            let result = Self::do_burst_poke(&mut port, addr, &values);
+                            if let Err(err) = &result {
+                                result_error = format!("burst poke {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::PokeResult(result));
+                            cvar.notify_one();
+                        }
                     },
                 }
             }
@@ -187,12 +213,19 @@ impl UartBridge {
                             )));
                             cvar.notify_one();
                         },
-                        ConnectThreadRequests::Poke(_addr, _val) => {
+                        ConnectThreadRequests::Poke(..)
+                        | ConnectThreadRequests::BurstPoke(..) => {
                             *response.lock().unwrap() = Some(ConnectThreadResponses::PokeResult(Err(
                                 BridgeError::NotConnected,
                             )));
                             cvar.notify_one();
                         },
+                        ConnectThreadRequests::BurstPeek(_addr, _count) => {
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::BurstPeekResult(Err(
+                                BridgeError::NotConnected,
+                            )));
+                            cvar.notify_one();
+                        },
                         ConnectThreadRequests::StartPolling(p, v) => {
                             path = p.clone();
                             baud = v;
@@ -233,6 +266,7 @@ impl UartBridge {
         addr: u32,
         value: u32,
     ) -> Result<(), BridgeError> {
+        let _span = trace::span("uart", "poke");
         debug!("POKE @ {:08x} -> {:08x}", addr, value);
         // WRITE, 1 word
         serial.write(&[0x01, 0x01])?;
@@ -244,6 +278,7 @@ impl UartBridge {
     }
 
     fn do_peek<T: SerialPort>(serial: &mut T, addr: u32) -> Result<u32, BridgeError> {
+        let _span = trace::span("uart", "peek");
         // READ, 1 word
         debug!("Peeking @ {:08x}", addr);
         serial.write(&[0x02, 0x01])?;
@@ -257,6 +292,42 @@ impl UartBridge {
         Ok(val)
     }
 
+    /// Write `values` to consecutive words from `addr`, which the gateware
+    /// takes as a single command with a word count
+    fn do_burst_poke<T: SerialPort>(
+        serial: &mut T,
+        addr: u32,
+        values: &[u32],
+    ) -> Result<(), BridgeError> {
+        let _span = trace::span("uart", "burst poke");
+        debug!("POKE {} words @ {:08x}", values.len(), addr);
+        let mut buffer = Vec::with_capacity(6 + values.len() * 4);
+        buffer.push(0x01);
+        buffer.push(values.len() as u8);
+        buffer.write_u32::<BigEndian>(addr >> 2)?;
+        for value in values {
+            buffer.write_u32::<BigEndian>(*value)?;
+        }
+        Ok(serial.write_all(&buffer)?)
+    }
+
+    /// Read `count` consecutive words from `addr`
+    fn do_burst_peek<T: SerialPort>(
+        serial: &mut T,
+        addr: u32,
+        count: u8,
+    ) -> Result<Vec<u32>, BridgeError> {
+        let _span = trace::span("uart", "burst peek");
+        debug!("Peeking {} words @ {:08x}", count, addr);
+        serial.write_all(&[0x02, count])?;
+        serial.write_u32::<BigEndian>(addr >> 2)?;
+        let mut values = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            values.push(serial.read_u32::<BigEndian>()?);
+        }
+        Ok(values)
+    }
+
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
         let &(ref lock, ref cvar) = &*self.main_rx;
         let mut _mtx = lock.lock().unwrap();
@@ -294,6 +365,47 @@ impl UartBridge {
             }
         }
     }
+
+    /// Write up to `MAX_BURST_WORDS` of `values` to consecutive words
+    /// starting at `addr`.
+    pub fn burst_poke(&self, addr: u32, values: &[u32]) -> Result<(), BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstPoke(addr, values.to_vec()))
+            .expect("Unable to send poke to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::PokeResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    /// Read up to `MAX_BURST_WORDS` consecutive words starting at `addr`.
+    pub fn burst_peek(&self, addr: u32, count: usize) -> Result<Vec<u32>, BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstPeek(addr, count as u8))
+            .expect("Unable to send peek to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstPeekResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
 }
 
 impl Drop for UartBridge {
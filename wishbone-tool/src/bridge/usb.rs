@@ -9,6 +9,7 @@ use log::{debug, error, info};
 
 use super::BridgeError;
 use crate::config::Config;
+use crate::trace;
 
 pub struct UsbBridge {
     usb_pid: Option<u16>,
@@ -258,6 +259,7 @@ impl UsbBridge {
         value: u32,
         debug_byte: u8,
     ) -> Result<(), BridgeError> {
+        let _span = trace::span("usb", "poke");
         let mut data_val = [0; 4];
         data_val[0] = ((value >> 0) & 0xff) as u8;
         data_val[1] = ((value >> 8) & 0xff) as u8;
@@ -291,6 +293,7 @@ impl UsbBridge {
     }
 
     fn do_peek(usb: &libusb::DeviceHandle, addr: u32, debug_byte: u8) -> Result<u32, BridgeError> {
+        let _span = trace::span("usb", "peek");
         let mut data_val = [0; 512];
         match usb.read_control(
             0x80 | debug_byte,
@@ -323,6 +326,12 @@ impl UsbBridge {
         }
     }
 
+    /// Read from a device that was opened directly, rather than through a
+    /// `UsbBridge`.
+    pub fn peek_handle(usb: &libusb::DeviceHandle, addr: u32) -> Result<u32, BridgeError> {
+        Self::do_peek(usb, addr, 0x43)
+    }
+
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
         let &(ref lock, ref cvar) = &*self.main_rx;
         let mut _mtx = lock.lock().unwrap();
@@ -0,0 +1,91 @@
+//! Progress checkpoints for long transfers, so one that gets interrupted
+//! partway through can pick up where it left off with `--resume`.
+//!
+//! A checkpoint lives next to the file being loaded or dumped, and records
+//! the region being copied, how far the copy got, and a CRC of the file
+//! contents up to that point.  The CRC catches a file that has been
+//! modified or swapped out in the meantime.
+
+use std::fs;
+use std::io;
+
+/// How much to copy between checkpoints
+pub const CHECKPOINT_INTERVAL: u32 = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub addr: u32,
+    pub len: u32,
+
+    /// Number of bytes copied so far
+    pub offset: u32,
+
+    /// CRC-32 of the first `offset` bytes of the file
+    pub crc: u32,
+}
+
+impl Checkpoint {
+    fn path(file: &str) -> String {
+        format!("{}.checkpoint", file)
+    }
+
+    /// The checkpoint left behind by an earlier transfer of `file`, if any
+    pub fn load(file: &str) -> Option<Checkpoint> {
+        let text = fs::read_to_string(Self::path(file)).ok()?;
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 4 {
+            return None;
+        }
+        Some(Checkpoint {
+            addr: u32::from_str_radix(fields[0].trim_start_matches("0x"), 16).ok()?,
+            len: fields[1].parse().ok()?,
+            offset: fields[2].parse().ok()?,
+            crc: u32::from_str_radix(fields[3], 16).ok()?,
+        })
+    }
+
+    pub fn save(&self, file: &str) -> io::Result<()> {
+        // Write it out under a temporary name first, so an interruption
+        // can't leave a half-written checkpoint behind.
+        let tmp = format!("{}.tmp", Self::path(file));
+        fs::write(
+            &tmp,
+            format!(
+                "0x{:08x} {} {} {:08x}\n",
+                self.addr, self.len, self.offset, self.crc
+            ),
+        )?;
+        fs::rename(&tmp, Self::path(file))
+    }
+
+    /// Remove the checkpoint once the transfer has finished
+    pub fn remove(file: &str) {
+        let _ = fs::remove_file(Self::path(file));
+    }
+
+    /// Whether this checkpoint can be resumed into the given transfer,
+    /// whose file begins with `data`.
+    pub fn matches(&self, addr: u32, len: u32, data: &[u8]) -> bool {
+        self.addr == addr
+            && self.len == len
+            && self.offset <= len
+            && data.len() >= self.offset as usize
+            && crc32(0, &data[..self.offset as usize]) == self.crc
+    }
+}
+
+/// CRC-32 (the zlib one), continuing on from `crc`.  Pass 0 to start.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -1,10 +1,38 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
+use std::time::Duration;
 
+use crate::access::AccessList;
+use crate::boards::BoardRegistry;
 use crate::bridge::spi::SpiPins;
+use crate::bridge::sim::SimPeripheral;
+use crate::bridge::deglitch::{self, DeglitchPolicy};
 use crate::bridge::BridgeKind;
+use crate::consolelog::{self, Rotation};
+use crate::coverage::CoverageMode;
+use crate::dma::{DmaEngine, Segment};
+use crate::ecc::EccController;
+use crate::endurance::EnduranceTarget;
+use crate::flash::{self, Partition};
+use crate::spiflash;
+use crate::footgun::{FootgunGuard, FootgunPolicy};
+use crate::journal::Journal;
+use crate::phy::{PrbsPattern, PrbsPhy};
+use crate::linux::LinuxOffsets;
+use crate::regions::{AccessPolicy, MemoryKind, MemoryRegion};
+use crate::report::ReportFormat;
+use crate::riscv::custom::{self, CustomCsr};
+use crate::riscv::pseudo::PseudoRegister;
+use crate::riscv::RiscvBackendKind;
+use crate::sequence::{SequenceOp, SequenceStep, TARGET_NAMES};
 use crate::server::ServerKind;
+use crate::soc::SocDescription;
+use crate::syslog::SyslogFormat;
+use crate::litescope::LiteScope;
+use crate::watch::{ScopeSync, WatchThreshold};
+use crate::xmodem::ModemProtocol;
+use crate::xover::ConsoleTap;
 use clap::ArgMatches;
 use csv;
 
@@ -75,6 +103,357 @@ pub fn parse_u32(value: &str) -> Result<u32, ConfigError> {
     }
 }
 
+pub fn parse_u64(value: &str) -> Result<u64, ConfigError> {
+    let (value, base) = get_base(value);
+    match u64::from_str_radix(value, base) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(ConfigError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+/// Parse a line rate in bits per second, such as "5G", "1250M" or
+/// "115200", with the suffixes counting in thousands.
+pub fn parse_rate(value: &str) -> Result<u64, ConfigError> {
+    let (digits, scale) = match value.char_indices().last() {
+        Some((idx, 'k')) | Some((idx, 'K')) => (&value[..idx], 1_000),
+        Some((idx, 'M')) => (&value[..idx], 1_000_000),
+        Some((idx, 'G')) => (&value[..idx], 1_000_000_000),
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) => n.checked_mul(scale).ok_or_else(|| {
+            ConfigError::InvalidConfig(format!("{} is too big", value))
+        }),
+        Err(e) => Err(ConfigError::NumberParseError(digits.to_owned(), e)),
+    }
+}
+
+/// Parse a size in bytes such as "64M", "512k" or "4096", with the
+/// suffixes counting in 1024s.
+pub fn parse_size(value: &str) -> Result<u64, ConfigError> {
+    let (digits, scale) = match value.char_indices().last() {
+        Some((idx, 'k')) | Some((idx, 'K')) => (&value[..idx], 1 << 10),
+        Some((idx, 'M')) => (&value[..idx], 1 << 20),
+        Some((idx, 'G')) => (&value[..idx], 1 << 30),
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) => n.checked_mul(scale).ok_or_else(|| {
+            ConfigError::InvalidConfig(format!("{} is too big", value))
+        }),
+        Err(e) => Err(ConfigError::NumberParseError(digits.to_owned(), e)),
+    }
+}
+
+/// Parse a duration such as "10s", "500ms", or "2m".  A bare number is
+/// taken to be in seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, ConfigError> {
+    if let Some(ms) = value.strip_suffix("ms") {
+        Ok(Duration::from_millis(parse_u32(ms)? as u64))
+    } else if let Some(s) = value.strip_suffix('s') {
+        Ok(Duration::from_secs(parse_u32(s)? as u64))
+    } else if let Some(m) = value.strip_suffix('m') {
+        Ok(Duration::from_secs(parse_u32(m)? as u64 * 60))
+    } else if let Some(h) = value.strip_suffix('h') {
+        Ok(Duration::from_secs(parse_u32(h)? as u64 * 3600))
+    } else {
+        Ok(Duration::from_secs(parse_u32(value)? as u64))
+    }
+}
+
+/// Parse a --deglitch `AREA[,READS[,MASK:VALUE]]`, where the area is
+/// `ADDRESS:LENGTH`, the name of a memory region, or `all`
+fn parse_deglitch(spec: &str, regions: &[MemoryRegion]) -> Result<DeglitchPolicy, ConfigError> {
+    let mut parts = spec.split(',');
+    let area = parts.next().unwrap_or("");
+    let (base, size) = match area.split_once(':') {
+        _ if area == "all" => (0, 1 << 32),
+        Some((addr, len)) => (parse_u32(addr)?, parse_u32(len)? as u64),
+        None => match regions.iter().find(|r| r.name == area) {
+            Some(region) => (region.base, region.size as u64),
+            None => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--deglitch {} isn't ADDRESS:LENGTH, all, or the name of a memory region",
+                    area
+                )))
+            }
+        },
+    };
+    let reads = match parts.next() {
+        Some(reads) => parse_u32(reads)?,
+        None => deglitch::DEFAULT_READS,
+    };
+    let (mask, expect) = match parts.next() {
+        Some(check) => match check.split_once(':') {
+            Some((mask, value)) => (Some(parse_u32(mask)?), parse_u32(value)?),
+            None => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--deglitch {} should give the check as MASK:VALUE",
+                    spec
+                )))
+            }
+        },
+        None => (None, 0),
+    };
+    if parts.next().is_some() {
+        return Err(ConfigError::InvalidConfig(format!(
+            "--deglitch {} has too many fields -- it's AREA[,READS[,MASK:VALUE]]",
+            spec
+        )));
+    }
+    // Without a mask, one read has nothing to be checked against
+    if reads < 1 || (mask.is_none() && reads < 2) {
+        return Err(ConfigError::InvalidConfig(format!(
+            "--deglitch {} needs more reads to vote on",
+            spec
+        )));
+    }
+    Ok(DeglitchPolicy {
+        base,
+        size,
+        reads,
+        mask,
+        expect,
+    })
+}
+
+/// A row of a CSV file, or an error saying which line of the `what` file
+/// couldn't be read
+pub fn csv_record(
+    result: Result<csv::StringRecord, csv::Error>,
+    what: &str,
+) -> Result<csv::StringRecord, ConfigError> {
+    result.map_err(|e| match e.position() {
+        Some(pos) => {
+            ConfigError::InvalidConfig(format!("line {} of the {} is bad: {}", pos.line(), what, e))
+        }
+        None => ConfigError::InvalidConfig(format!("the {} is bad: {}", what, e)),
+    })
+}
+
+/// A second board to open alongside the first, given to --peer as
+/// `usb:PID`, `uart:PORT[:BAUD]`, `ethernet:HOST[:PORT]`, or `sim`.  A USB
+/// peer needs its PID, since without one it would be the first board again.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerSpec {
+    Usb(u16),
+    Uart(String, Option<usize>),
+    Ethernet(String, Option<u16>),
+    Sim,
+}
+
+impl PeerSpec {
+    pub fn from_string(spec: &str) -> Result<PeerSpec, ConfigError> {
+        let mut parts = spec.splitn(3, ':');
+        let kind = parts.next().unwrap_or("");
+        let first = parts.next();
+        let second = parts.next();
+        match (kind, first, second) {
+            ("usb", Some(pid), None) => Ok(PeerSpec::Usb(parse_u16(pid)?)),
+            ("uart", Some(port), baud) => Ok(PeerSpec::Uart(
+                port.to_owned(),
+                match baud {
+                    Some(b) => Some(parse_u32(b)? as usize),
+                    None => None,
+                },
+            )),
+            ("ethernet", Some(host), port) => Ok(PeerSpec::Ethernet(
+                host.to_owned(),
+                match port {
+                    Some(p) => Some(parse_u16(p)?),
+                    None => None,
+                },
+            )),
+            ("sim", None, None) => Ok(PeerSpec::Sim),
+            _ => Err(ConfigError::InvalidConfig(format!(
+                "unrecognized peer {} -- must be usb:PID, uart:PORT[:BAUD], ethernet:HOST[:PORT], or sim",
+                spec
+            ))),
+        }
+    }
+}
+
+/// How loads treat words of fill (all zeroes or all ones), which padded
+/// images are often full of
+#[derive(Clone, Debug, PartialEq)]
+pub enum SparseMode {
+    /// Write every word
+    Off,
+
+    /// Don't write fill at all, assuming the target already holds it
+    Skip,
+
+    /// Read the target first, and only write fill if it isn't there already
+    Verify,
+}
+
+/// Which way a `Transfer` moves data
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferKind {
+    /// Copy a file into memory
+    Load,
+
+    /// Copy memory out to a file
+    Dump,
+}
+
+/// One region<->file copy, from --load-file, --dump-file, or a manifest
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub kind: TransferKind,
+    pub file: String,
+    pub addr: u32,
+
+    /// Number of bytes to dump.  Loads always copy the whole file.
+    pub len: u32,
+}
+
+impl Transfer {
+    /// Parse a `FILE@ADDRESS` load spec.
+    pub fn load_from_string(spec: &str) -> Result<Transfer, ConfigError> {
+        let (file, addr) = Self::split_spec(spec, "FILE@ADDRESS")?;
+        Ok(Transfer {
+            kind: TransferKind::Load,
+            file,
+            addr: parse_u32(addr)?,
+            len: 0,
+        })
+    }
+
+    /// Parse a `FILE@ADDRESS:LENGTH` dump spec.
+    pub fn dump_from_string(spec: &str) -> Result<Transfer, ConfigError> {
+        let (file, region) = Self::split_spec(spec, "FILE@ADDRESS:LENGTH")?;
+        let fields: Vec<&str> = region.split(':').collect();
+        if fields.len() != 2 {
+            return Err(ConfigError::InvalidConfig(format!(
+                "{} is not a valid dump -- must be FILE@ADDRESS:LENGTH",
+                spec
+            )));
+        }
+        Ok(Transfer {
+            kind: TransferKind::Dump,
+            file,
+            addr: parse_u32(fields[0])?,
+            len: parse_u32(fields[1])?,
+        })
+    }
+
+    /// Split on the last `@`, since that can't appear in an address but may
+    /// appear in a file name.
+    fn split_spec<'a>(spec: &'a str, format: &str) -> Result<(String, &'a str), ConfigError> {
+        match spec.rfind('@') {
+            Some(idx) if idx > 0 => Ok((spec[..idx].to_owned(), &spec[idx + 1..])),
+            _ => Err(ConfigError::InvalidConfig(format!(
+                "{} is not a valid transfer -- must be {}",
+                spec, format
+            ))),
+        }
+    }
+}
+
+/// Whether a CSR may be written, as described by the `mode` column of csr.csv
+#[derive(Clone, Debug, PartialEq)]
+pub enum CsrMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Which end of a multi-word CSR comes first, as described by the
+/// `config_csr_ordering` constant in csr.csv
+#[derive(Clone, Debug, PartialEq)]
+pub enum CsrOrdering {
+    /// Most significant word at the lowest address, which is what LiteX
+    /// has always done
+    Big,
+
+    /// Least significant word at the lowest address
+    Little,
+}
+
+impl CsrOrdering {
+    pub fn from_string(item: &str) -> Option<CsrOrdering> {
+        match item {
+            "big" => Some(CsrOrdering::Big),
+            "little" => Some(CsrOrdering::Little),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CsrRegister {
+    /// The name of the register, as it appears in csr.csv
+    pub name: String,
+
+    /// Address of the first CSR word
+    pub address: u32,
+
+    /// Number of 32-bit CSR words this register spans
+    pub words: u32,
+
+    /// Whether this register can be written
+    pub mode: CsrMode,
+
+    /// How many bits of the value each word carries, which is the CSR data
+    /// width the SoC was built with: 8 or 32
+    pub data_width: u32,
+
+    /// Which word carries the most significant bits
+    pub ordering: CsrOrdering,
+}
+
+impl CsrRegister {
+    /// The bits of each word that carry part of the value
+    pub fn word_mask(&self) -> u64 {
+        if self.data_width >= 32 {
+            0xffff_ffff
+        } else {
+            (1 << self.data_width) - 1
+        }
+    }
+
+    /// How significant the word at `word` is, counting up from 0 for the
+    /// least significant
+    pub fn significance(&self, word: u32) -> u32 {
+        match self.ordering {
+            CsrOrdering::Big => self.words - word - 1,
+            CsrOrdering::Little => word,
+        }
+    }
+
+    /// Put the words of a register back together, given in address order.
+    /// Anything wider than 64 bits loses its top end.
+    pub fn assemble(&self, words: &[u32]) -> u64 {
+        words.iter().enumerate().fold(0, |value, (word, w)| {
+            let shift = self.significance(word as u32) * self.data_width;
+            value | (*w as u64 & self.word_mask()).checked_shl(shift).unwrap_or(0)
+        })
+    }
+
+    /// Split `value` into the words to write, in address order
+    pub fn split(&self, value: u64) -> Vec<u32> {
+        (0..self.words)
+            .map(|word| {
+                let shift = self.significance(word) * self.data_width;
+                (value.checked_shr(shift).unwrap_or(0) & self.word_mask()) as u32
+            })
+            .collect()
+    }
+}
+
+/// The CSR data width a SoC was built with.  Newer csr.csv files say so in
+/// a constant; otherwise the 32-bit scratch register gives it away, since
+/// it takes four words on an 8-bit bus.
+pub fn csr_data_width(constant: Option<&str>, registers: &[CsrRegister]) -> u32 {
+    if let Some(width) = constant.and_then(|w| parse_u32(w).ok()) {
+        return width;
+    }
+    match registers.iter().find(|r| r.name == "ctrl_scratch") {
+        Some(scratch) if scratch.words == 4 => 8,
+        _ => 32,
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub usb_pid: Option<u16>,
@@ -82,26 +461,300 @@ pub struct Config {
     pub usb_bus: Option<u8>,
     pub usb_device: Option<u8>,
     pub memory_address: Option<u32>,
-    pub memory_value: Option<u32>,
+    pub memory_register: Option<CsrRegister>,
+    /// What to write: up to 64 bits for a register, or a word otherwise
+    pub memory_value: Option<u64>,
+
+    /// Go through this hart's debug interface to read and write, so that
+    /// its own CSRs and hart-local memory can be reached
+    pub hart: Option<u32>,
+
+    /// One of the CPU's registers to read or write with `hart`, or `regs`
+    /// for all of the integer ones
+    pub cpu_register: Option<String>,
     pub server_kind: Vec<ServerKind>,
     pub bridge_kind: BridgeKind,
     pub serial_port: Option<String>,
     pub serial_baud: Option<usize>,
     pub spi_pins: Option<SpiPins>,
+    pub sim_peripherals: Vec<SimPeripheral>,
     pub bind_addr: String,
     pub bind_port: u16,
     pub gdb_port: u16,
     pub ethernet_host: Option<String>,
     pub ethernet_port: u16,
     pub ethernet_tcp: bool,
+
+    /// Sent, with a newline, when connecting to a wishbone server that was
+    /// started with --client-tokens
+    pub ethernet_token: Option<String>,
+
+    /// Start out on USB, and move over to Ethernet if USB stops answering
+    pub bridge_failover: bool,
+
+    /// Let pokes return before they've been committed
+    pub posted_writes: bool,
+
+    /// Commit posted writes before answering each request from a client
+    pub posted_sync: bool,
+
+    /// Areas where reads are made more than once and voted on
+    pub deglitch: Vec<DeglitchPolicy>,
+
+    /// Refuse every write to the board, whichever server makes it
+    pub read_only: bool,
+
+    /// Read back what poke, load and restore operations write
+    pub verify: bool,
+
+    /// A second board to run sequences against, and its csr map
+    pub peer: Option<PeerSpec>,
+    pub peer_register_mapping: HashMap<String, u32>,
+
+    /// Steps to run across this board and the peer
+    pub sequence: Vec<SequenceStep>,
+
+    /// How many connections to open to the device for large reads, on
+    /// bridges that allow more than one
+    pub connections: u32,
     pub random_loops: Option<u32>,
     pub random_address: Option<u32>,
     pub random_range: Option<u32>,
     pub messible_address: Option<u32>,
     pub register_mapping: HashMap<String, u32>,
+    pub csr_registers: Vec<CsrRegister>,
     pub debug_offset: u32,
+    pub debug_backend: RiscvBackendKind,
+    pub reset_csr: Option<u32>,
+
+    /// Guard against writes that would upset the other servers
+    pub footguns: FootgunGuard,
+
+    /// Tokens the wishbone server's clients have to give, and what each
+    /// may do.  None lets anyone do anything.
+    pub client_tokens: Option<AccessList>,
+    pub reset_vector: Option<u32>,
+    pub reset_settle: u32,
+    pub linux_offsets: Option<LinuxOffsets>,
+    pub pseudo_registers: Vec<PseudoRegister>,
+
+    /// The CPU clock in Hz, from --clock-frequency or csr.csv
+    pub clock_frequency: Option<u64>,
+
+    /// CSRs outside the spec, from --custom-csrs
+    pub custom_csrs: Vec<CustomCsr>,
+
+    /// Copy the console into GDB with timestamps, through `console_tap`
+    /// if the terminal is what's reading the UART
+    pub gdb_console: bool,
+    pub console_tap: Option<ConsoleTap>,
+
+    /// Service semihosting calls while GDB has the CPU running
+    pub semihosting: bool,
+
+    /// Send semihosting calls on files and the console to GDB as File-I/O
+    /// requests.  This turns semihosting on.
+    pub gdb_fileio: bool,
+
+    /// How long the terminal may hold keystrokes back to send them together
+    pub write_combine: Option<Duration>,
+    pub send_file: Option<String>,
+    pub receive_file: Option<String>,
+    pub file_protocol: ModemProtocol,
     pub load_name: Option<String>,
     pub load_addr: Option<u32>,
+
+    /// The family a UF2 --load-name must be for
+    pub uf2_family: Option<u32>,
+    pub state_file: Option<String>,
+
+    /// A JSON dump that state-save compares the CSRs against
+    pub golden_file: Option<String>,
+    pub bench_iterations: u32,
+    pub coverage_file: Option<String>,
+    pub coverage_mode: CoverageMode,
+    pub coverage_size: u32,
+    pub coverage_samples: u32,
+    pub expect: Vec<String>,
+    pub report_format: Option<ReportFormat>,
+    pub report_file: Option<String>,
+    pub timeout: Duration,
+    pub summary_json: bool,
+
+    /// Chrome trace file to write timing spans to
+    pub trace_out: Option<String>,
+
+    /// Log host to send the log and the firmware's console to, as
+    /// HOST[:PORT], and how to put each line
+    pub syslog: Option<String>,
+    pub syslog_format: SyslogFormat,
+
+    /// Directory to capture the firmware's console to, and when the file
+    /// it's going to is set aside for a new one
+    pub log_dir: Option<String>,
+    pub log_rotation: Rotation,
+
+    /// Session journal to append this run to
+    pub journal: Option<Journal>,
+
+    /// Read ahead of GDB's memory reads in ordinary memory regions
+    pub gdb_prefetch: bool,
+
+    /// How many single steps to record so GDB can go back over them
+    pub gdb_record: Option<usize>,
+
+    /// Show the instructions around the pc each time the CPU stops
+    pub gdb_disasm: bool,
+
+    /// Reuse what was probed about the CPU the last time this SoC was seen
+    pub probe_cache: bool,
+    pub transfers: Vec<Transfer>,
+    pub sparse: SparseMode,
+
+    /// Pick interrupted transfers up from their last checkpoint
+    pub resume: bool,
+    pub burst_size: Option<u32>,
+    pub probe: bool,
+    pub ecc_clear: bool,
+    pub ecc_scrub: bool,
+
+    /// The scatter-gather engine "dma" runs, where its chain goes, and
+    /// the segments that make it up
+    pub dma_engine: Option<DmaEngine>,
+    pub dma_descriptors: Option<u32>,
+    pub dma_segments: Vec<Segment>,
+
+    /// What "phy-test" runs, on which PHY, and how fast the line is
+    pub phy: Option<String>,
+    pub prbs_pattern: PrbsPattern,
+    pub phy_loopback: Option<u32>,
+    pub phy_duration: Duration,
+    pub phy_rate: Option<u64>,
+
+    /// What "endurance" cycles, for how long, and the sensors it logs on
+    /// the way.  For flash, the region is an offset into the flash.
+    pub endurance_target: EnduranceTarget,
+    pub endurance_region: Option<(u32, u32)>,
+    pub endurance_duration: Duration,
+    pub endurance_interval: Duration,
+    pub endurance_sensors: Vec<CsrRegister>,
+
+    /// The RAM "stress" makes its accesses to, as base and length, the
+    /// seed it makes them from, and how many
+    pub stress_regions: Vec<(u32, u32)>,
+    pub stress_seed: Option<u64>,
+    pub stress_ops: Option<u64>,
+
+    /// The SVD file for "tui" to show the registers of
+    pub svd_file: Option<String>,
+
+    /// Expressions for "calc", each with the name to print it under
+    pub calc: Vec<(String, PseudoRegister)>,
+
+    /// The values of the csr.csv constants that the expressions use
+    pub calc_constants: HashMap<String, u64>,
+    pub watch: Vec<CsrRegister>,
+    pub watch_ecc: bool,
+    pub watch_interval: Duration,
+    pub watch_thresholds: Vec<WatchThreshold>,
+
+    /// Shell command to run when a threshold is crossed
+    pub watch_alert: Option<String>,
+
+    /// Weight of each new sample in the moving average, from 0 to 1
+    pub watch_ema_alpha: f64,
+
+    /// Number of times to poll, or 0 to keep going forever
+    pub watch_count: u32,
+
+    pub watch_scope: ScopeSync,
+
+    /// MQTT broker that watched values are published to, as HOST[:PORT]
+    pub mqtt_broker: Option<String>,
+
+    /// Topic to publish each register on, where `{register}` is replaced
+    /// with the register's name
+    pub mqtt_topic: String,
+    pub mqtt_client_id: String,
+
+    pub scope_offset: u32,
+    pub scope_length: u32,
+
+    /// CSV file that LiteScope captures are appended to
+    pub scope_file: Option<String>,
+
+    /// Address and bit number for --pulse
+    pub pulse: Option<(u32, u32)>,
+    pub pulse_duration: Duration,
+
+    /// Values to write to `waveform_register`, and when to write them
+    pub waveform: Vec<(Duration, u32)>,
+    pub waveform_register: Option<u32>,
+
+    /// Number of times to play the waveform, or 0 to repeat it forever
+    pub waveform_loops: u32,
+
+    /// Regions that only tolerate aligned 32-bit accesses, and so on
+    pub memory_regions: Vec<MemoryRegion>,
+
+    /// The whole of the csr.csv, for the generators
+    pub soc: SocDescription,
+
+    /// Where gen-dtb writes its tree, or stdout if not given
+    pub dtb_file: Option<String>,
+
+    /// Where gen-pac writes its code, or stdout if not given
+    pub pac_file: Option<String>,
+
+    /// Where gen-docs writes the register map, or stdout if not given
+    pub docs_file: Option<String>,
+
+    /// The hex file of bytes for etherbone-send to send or etherbone-decode
+    /// to decode
+    pub etherbone_packet: Option<String>,
+
+    pub flash_offset: u32,
+    pub flash_layout: Vec<Partition>,
+
+    /// The partition --flash-offset came from, if it was given by name
+    pub flash_partition: Option<Partition>,
+    pub signature_manifest: Option<String>,
+    pub signing_key: Option<String>,
+
+    pub otp_base: Option<u32>,
+    pub otp_words: u32,
+
+    /// Word offset and value to burn into the OTP block
+    pub otp_write: Option<(u32, u32)>,
+    pub otp_audit_log: String,
+
+    /// Where to write the host's clock, as microseconds since the epoch
+    pub time_sync_addr: Option<u32>,
+
+    /// How many round trips to time before writing the clock
+    pub time_sync_samples: u32,
+
+    /// Where the firmware keeps its packet rings, for the tap server
+    pub tap_base: Option<u32>,
+
+    /// The host interface to pass packets through
+    pub tap_name: String,
+
+    /// Pass IP packets through a TUN interface, rather than Ethernet
+    /// frames through a TAP interface
+    pub tap_tun: bool,
+
+    pub boards: BoardRegistry,
+
+    /// Label of the board to connect to, from the board registry
+    pub target: Option<String>,
+
+    /// Read the board's unique ID after connecting, to label the log and
+    /// --syslog with.  For the flash UID that means taking over the SPI
+    /// flash, so it's only done when asked for.
+    pub read_board_id: bool,
+
+    pub wait_for_lock: bool,
 }
 
 impl Config {
@@ -166,8 +819,83 @@ impl Config {
             None
         };
 
+        let uf2_family = match matches.value_of("uf2-family") {
+            Some(id) => Some(parse_u32(id)?),
+            None => None,
+        };
+
+        let state_file = matches.value_of("state-file").map(|s| s.to_owned());
+        let golden_file = matches.value_of("golden").map(|s| s.to_owned());
+
+        let bench_iterations = if let Some(n) = matches.value_of("bench-iterations") {
+            parse_u32(n)?
+        } else {
+            10
+        };
+
+        let summary_json = matches.value_of("summary") == Some("json");
+
+        let sparse = match matches.value_of("sparse") {
+            Some("skip") => SparseMode::Skip,
+            Some("verify") => SparseMode::Verify,
+            _ => SparseMode::Off,
+        };
+
+        let burst_size = if let Some(n) = matches.value_of("burst-size") {
+            Some(parse_u32(n)?)
+        } else {
+            None
+        };
+
+        let mut transfers = vec![];
+        if let Some(specs) = matches.values_of("load-file") {
+            for spec in specs {
+                transfers.push(Transfer::load_from_string(spec)?);
+            }
+        }
+        if let Some(specs) = matches.values_of("dump-file") {
+            for spec in specs {
+                transfers.push(Transfer::dump_from_string(spec)?);
+            }
+        }
+        transfers.extend(Self::parse_manifest(matches.value_of("manifest"))?);
+        if !transfers.is_empty() && !server_kind.contains(&ServerKind::Transfer) {
+            server_kind.push(ServerKind::Transfer);
+        }
+
+        let expect: Vec<String> = match matches.values_of("expect") {
+            Some(v) => v.map(|s| s.to_owned()).collect(),
+            None => vec![],
+        };
+        if expect.iter().any(|e| e.is_empty()) {
+            return Err(ConfigError::InvalidConfig(
+                "an --expect pattern can't be empty".to_owned(),
+            ));
+        }
+        let timeout = if let Some(t) = matches.value_of("timeout") {
+            parse_duration(t)?
+        } else {
+            Duration::from_secs(10)
+        };
+
+        let coverage_file = matches.value_of("coverage-file").map(|s| s.to_owned());
+        let coverage_mode = matches
+            .value_of("coverage-mode")
+            .and_then(CoverageMode::from_string)
+            .unwrap_or(CoverageMode::Step);
+        let coverage_size = if let Some(n) = matches.value_of("coverage-size") {
+            parse_u32(n)?
+        } else {
+            0x10000
+        };
+        let coverage_samples = if let Some(n) = matches.value_of("coverage-samples") {
+            parse_u32(n)?
+        } else {
+            100000
+        };
+
         let memory_value = if let Some(v) = matches.value_of("value") {
-            Some(parse_u32(v)?)
+            Some(parse_u64(v)?)
         } else {
             None
         };
@@ -191,6 +919,34 @@ impl Config {
         };
 
         let ethernet_tcp = matches.is_present("ethernet-tcp");
+        let ethernet_token = matches.value_of("ethernet-token").map(|s| s.to_owned());
+        if ethernet_token.is_some() && !ethernet_tcp {
+            return Err(ConfigError::InvalidConfig(
+                "--ethernet-token is only sent over --ethernet-tcp".to_owned(),
+            ));
+        }
+
+        // The Ethernet link is only the fallback, so start out on USB
+        let bridge_failover = matches.is_present("bridge-failover");
+        if bridge_failover {
+            if ethernet_host.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "--bridge-failover needs an --ethernet-host to fall over to".to_owned(),
+                ));
+            }
+            bridge_kind = BridgeKind::UsbBridge;
+        }
+        let connections = match matches.value_of("connections") {
+            Some(n) => match parse_u32(n)? {
+                0 => {
+                    return Err(ConfigError::InvalidConfig(
+                        "--connections must be at least 1".to_owned(),
+                    ))
+                }
+                n => n,
+            },
+            None => 1,
+        };
 
         let spi_pins = if let Some(pins) = matches.value_of("spi-pins") {
             bridge_kind = BridgeKind::SpiBridge;
@@ -199,6 +955,16 @@ impl Config {
             None
         };
 
+        let mut sim_peripherals = vec![];
+        if let Some(specs) = matches.values_of("sim-peripheral") {
+            for spec in specs {
+                sim_peripherals.push(SimPeripheral::from_string(spec)?);
+            }
+        }
+        if matches.is_present("sim") || !sim_peripherals.is_empty() {
+            bridge_kind = BridgeKind::SimBridge;
+        }
+
         if let Some(server_kinds) = matches.values_of("server-kind") {
             for sk in server_kinds {
                 server_kind.push(ServerKind::from_string(sk)?);
@@ -223,7 +989,36 @@ impl Config {
             None
         };
 
-        let register_mapping = Self::parse_csr_csv(matches.value_of("csr-csv"))?;
+        let (register_mapping, mut csr_registers) =
+            Self::parse_csr_csv(matches.value_of("csr-csv"))?;
+        let forced_data_width = match matches.value_of("csr-data-width") {
+            Some(w) => match parse_u32(w)? {
+                w @ 8 | w @ 16 | w @ 32 => Some(w),
+                w => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "CSR data width must be 8, 16, or 32, not {}",
+                        w
+                    )))
+                }
+            },
+            None => None,
+        };
+        if let Some(width) = forced_data_width {
+            for reg in &mut csr_registers {
+                reg.data_width = width;
+            }
+        }
+        let forced_ordering = match matches.value_of("csr-ordering") {
+            Some(o) => Some(CsrOrdering::from_string(o).ok_or_else(|| {
+                ConfigError::InvalidConfig(format!("CSR ordering must be big or little, not {}", o))
+            })?),
+            None => None,
+        };
+        if let Some(ordering) = &forced_ordering {
+            for reg in &mut csr_registers {
+                reg.ordering = ordering.clone();
+            }
+        }
 
         let messible_address = if let Some(messible_address) = matches.value_of("messible-address")
         {
@@ -244,58 +1039,1042 @@ impl Config {
             }
         };
 
+        let debug_backend = match matches.value_of("debug-backend") {
+            Some("dmi") => RiscvBackendKind::Dmi,
+            _ => RiscvBackendKind::VexRiscv,
+        };
+
+        let read_only = matches.is_present("read-only");
+        if read_only {
+            if let Some(kind) = server_kind.iter().find(|k| k.writes_to_board()) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "the {} server has to write to the board, which --read-only won't allow",
+                    kind.name()
+                )));
+            }
+            if memory_value.is_some() {
+                return Err(ConfigError::InvalidConfig(
+                    "--read-only won't write a value to the board".to_owned(),
+                ));
+            }
+        }
+
+        // The gateware reset CSR lets "reset halt" reset the whole SoC
+        // rather than just the CPU.
+        let reset_csr = register_mapping.get("ctrl_reset").cloned();
+
+        let client_tokens = if let Some(file_name) = matches.value_of("client-tokens") {
+            if !server_kind.contains(&ServerKind::Wishbone) {
+                return Err(ConfigError::InvalidConfig(
+                    "--client-tokens only applies to the wishbone server".to_owned(),
+                ));
+            }
+            // The GDB server can reset and flash the board, and has no way
+            // of taking a token, so it would leave the board wide open
+            if server_kind.contains(&ServerKind::GDB) {
+                return Err(ConfigError::InvalidConfig(
+                    "--client-tokens can't protect the gdb server, so it can't be served alongside it".to_owned(),
+                ));
+            }
+            let admin_only = reset_csr
+                .into_iter()
+                .chain(
+                    register_mapping
+                        .iter()
+                        .filter(|(name, _)| name.starts_with("spiflash_"))
+                        .map(|(_, addr)| *addr),
+                )
+                .collect();
+            Some(
+                AccessList::parse(&std::fs::read_to_string(file_name)?, admin_only).map_err(|e| {
+                    ConfigError::InvalidConfig(format!("bad client tokens {}: {}", file_name, e))
+                })?,
+            )
+        } else {
+            None
+        };
+        let footgun_policy =
+            FootgunPolicy::from_string(matches.value_of("footguns").unwrap_or("warn"))?;
+        let footguns = FootgunGuard::new(
+            if matches.is_present("force") && footgun_policy != FootgunPolicy::Off {
+                FootgunPolicy::Warn
+            } else {
+                footgun_policy
+            },
+            reset_csr,
+            if server_kind.contains(&ServerKind::GDB) {
+                Some(debug_offset)
+            } else {
+                None
+            },
+            read_only,
+        );
+
+        let linux_offsets = Self::parse_linux_offsets(matches.value_of("linux-offsets"))?;
+
+        let reset_vector = if let Some(addr) = matches.value_of("reset-vector") {
+            Some(parse_u32(addr)?)
+        } else {
+            None
+        };
+
+        let reset_settle = if let Some(ms) = matches.value_of("reset-settle") {
+            parse_u32(ms)?
+        } else {
+            10
+        };
+
+        // A register that spans several words can be given by name, and is
+        // then read and written as a whole.
+        let memory_register = matches.value_of("address").and_then(|name| {
+            let name = name.to_lowercase();
+            csr_registers
+                .iter()
+                .find(|r| r.words > 1 && r.name == name)
+                .cloned()
+        });
+        let hart = if let Some(h) = matches.value_of("hart") {
+            Some(parse_u32(h)?)
+        } else {
+            None
+        };
 
-        let memory_address = if let Some(addr) = matches.value_of("address") {
+        // Going through a hart, a name that isn't in the csr.csv is taken
+        // to be one of the CPU's own registers.
+        let mut cpu_register = None;
+        let memory_address = if let Some(reg) = &memory_register {
+            Some(reg.address)
+        } else if let Some(addr) = matches.value_of("address") {
             if let Some(addr) = register_mapping.get(&addr.to_lowercase()) {
                 Some(*addr)
+            } else if hart.is_some() && parse_u32(addr).is_err() {
+                cpu_register = Some(addr.to_lowercase());
+                None
             } else {
                 Some(parse_u32(addr)?)
             }
         } else {
             None
         };
-
-        if server_kind.len() == 0 {
-            if memory_address.is_none() {
-                return Err(ConfigError::NoOperationSpecified);
+        if let Some(value) = memory_value {
+            if value > u32::MAX as u64 && memory_register.is_none() && cpu_register.is_none() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "0x{:x} doesn't fit in the word at that address",
+                    value
+                )));
             }
-            server_kind.push(ServerKind::MemoryAccess);
         }
 
-        // Validate the configuration is correct
-        if matches.value_of("csr-csv").is_some() {
-            if server_kind.contains(&ServerKind::GDB) {
-                // You asked for --server gdb but no vexriscv jtag interfaces is found in the csr.csv file it should complain.
-                if !register_mapping.contains_key("vexriscv_debug") {
-                    return Err(ConfigError::InvalidConfig(
-                        "GDB specified but no vexriscv address present in csv file".to_owned(),
-                    ));
-                }
-                // You asked for --server terminal but no uart is found in the csr.csv file it should complain.
-                if !(register_mapping.contains_key("uart_xover_rxtx")
-                    && register_mapping.contains_key("uart_xover_rxempty")
-                    && register_mapping.contains_key("uart_xover_ev_pending"))
-                {
-                    return Err(ConfigError::InvalidConfig(
-                        "Terminal specified, but no xover uart addresses present in csv file"
-                            .to_owned(),
-                    ));
+        // Watched registers may be given by name, in which case they can
+        // span several words, or by address.
+        let mut watch = vec![];
+        if let Some(names) = matches.values_of("watch") {
+            for name in names {
+                let lower = name.to_lowercase();
+                if let Some(reg) = csr_registers.iter().find(|r| r.name == lower) {
+                    watch.push(reg.clone());
+                    continue;
                 }
+                let address = Self::lookup_address(&register_mapping, name)?;
+                watch.push(CsrRegister {
+                    name: name.to_owned(),
+                    address,
+                    words: 1,
+                    mode: CsrMode::ReadOnly,
+                    data_width: 32,
+                    ordering: CsrOrdering::Big,
+                });
             }
         }
+        let watch_ecc = matches.is_present("watch-ecc");
 
-        Ok(Config {
-            usb_pid,
-            usb_vid,
-            usb_bus,
-            usb_device,
-            serial_port,
+        let watch_interval = if let Some(t) = matches.value_of("watch-interval") {
+            parse_duration(t)?
+        } else {
+            Duration::from_secs(1)
+        };
+
+        let pulse = if let Some(spec) = matches.value_of("pulse") {
+            let fields: Vec<&str> = spec.rsplitn(2, ':').collect();
+            if fields.len() != 2 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} is not a valid pulse -- must be REGISTER:BIT",
+                    spec
+                )));
+            }
+            let addr = Self::lookup_address(&register_mapping, fields[1])?;
+            let bit = parse_u32(fields[0])?;
+            if bit > 31 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "bit {} is out of range -- must be 0-31",
+                    bit
+                )));
+            }
+            if !server_kind.contains(&ServerKind::Pulse) {
+                server_kind.push(ServerKind::Pulse);
+            }
+            Some((addr, bit))
+        } else {
+            None
+        };
+
+        let peer = match matches.value_of("peer") {
+            Some(spec) => Some(PeerSpec::from_string(spec)?),
+            None => None,
+        };
+        if let Some(PeerSpec::Usb(pid)) = peer {
+            if matches!(bridge_kind, BridgeKind::UsbBridge) && usb_pid == Some(pid) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--peer usb:{:04x} is the same board as the first one",
+                    pid
+                )));
+            }
+        }
+        let (peer_register_mapping, _) = Self::parse_csr_csv(matches.value_of("peer-csr-csv"))?;
+        let sequence = Self::parse_sequence(
+            matches.value_of("sequence"),
+            &[&register_mapping, &peer_register_mapping],
+            peer.is_some(),
+        )?;
+        if !sequence.is_empty() && !server_kind.contains(&ServerKind::Sequence) {
+            server_kind.push(ServerKind::Sequence);
+        }
+        if server_kind.contains(&ServerKind::Sequence) && sequence.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "sequence needs a list of steps with --sequence".to_owned(),
+            ));
+        }
+
+        let waveform = Self::parse_waveform(matches.value_of("waveform"))?;
+        let waveform_register = if let Some(name) = matches.value_of("waveform-register") {
+            Some(Self::lookup_address(&register_mapping, name)?)
+        } else {
+            None
+        };
+        let waveform_loops = if let Some(n) = matches.value_of("waveform-loops") {
+            parse_u32(n)?
+        } else {
+            1
+        };
+        if !waveform.is_empty() && !server_kind.contains(&ServerKind::Waveform) {
+            server_kind.push(ServerKind::Waveform);
+        }
+
+        let pulse_duration = if let Some(t) = matches.value_of("duration") {
+            parse_duration(t)?
+        } else {
+            Duration::from_millis(1)
+        };
+
+        let memory_regions = Self::parse_memory_regions(
+            matches.value_of("csr-csv"),
+            matches.value_of("memory-map"),
+        )?;
+        if matches.is_present("gdb-prefetch") && memory_regions.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "--gdb-prefetch needs to know where memory is, from --csr-csv or --memory-map"
+                    .to_owned(),
+            ));
+        }
+        let mut soc = match matches.value_of("csr-csv") {
+            Some(file_name) => SocDescription::load(file_name)?,
+            None => SocDescription::default(),
+        };
+        for reg in soc.blocks.iter_mut().flat_map(|b| b.registers.iter_mut()) {
+            if let Some(width) = forced_data_width {
+                reg.data_width = width;
+            }
+            if let Some(ordering) = &forced_ordering {
+                reg.ordering = ordering.clone();
+            }
+        }
+
+        let clock = match matches.value_of("clock-frequency") {
+            Some(hz) => Some(parse_u32(hz)? as u64),
+            None => soc
+                .constant("config_clock_frequency")
+                .and_then(|hz| parse_u32(hz).ok())
+                .map(|hz| hz as u64),
+        };
+        let mut pseudo_registers = vec![];
+        if let Some(specs) = matches.values_of("pseudo-register") {
+            for spec in specs {
+                pseudo_registers.push(
+                    PseudoRegister::from_string(spec, clock).map_err(ConfigError::InvalidConfig)?,
+                );
+            }
+        }
+
+        // A calculation without a name is printed as it was written
+        let mut calc = vec![];
+        let mut calc_constants = HashMap::new();
+        if let Some(specs) = matches.values_of("calc") {
+            for spec in specs {
+                let (label, pseudo) = if spec.contains('=') {
+                    let pseudo = PseudoRegister::from_string(spec, clock)
+                        .map_err(ConfigError::InvalidConfig)?;
+                    (pseudo.name.clone(), pseudo)
+                } else {
+                    let pseudo = PseudoRegister::from_string(&format!("value={}", spec), clock)
+                        .map_err(ConfigError::InvalidConfig)?;
+                    (spec.trim().to_owned(), pseudo)
+                };
+                for source in pseudo.sources() {
+                    if csr_registers.iter().any(|r| r.name == source) {
+                        continue;
+                    }
+                    match soc.constant(source).map(parse_u64) {
+                        Some(Ok(value)) => {
+                            calc_constants.insert(source.to_owned(), value);
+                        }
+                        _ => {
+                            return Err(ConfigError::InvalidConfig(format!(
+                                "{} uses {}, which isn't a CSR or a constant in csr.csv",
+                                label, source
+                            )))
+                        }
+                    }
+                }
+                calc.push((label, pseudo));
+            }
+            if !server_kind.contains(&ServerKind::Calc) {
+                server_kind.push(ServerKind::Calc);
+            }
+        }
+
+        let custom_csrs = if let Some(file_name) = matches.value_of("custom-csrs") {
+            let (csrs, aliases) = custom::parse(&std::fs::read_to_string(file_name)?).map_err(|e| {
+                ConfigError::InvalidConfig(format!("bad custom CSRs {}: {}", file_name, e))
+            })?;
+            pseudo_registers.extend(aliases);
+            csrs
+        } else {
+            vec![]
+        };
+
+        let flash_layout = if let Some(file_name) = matches.value_of("flash-layout") {
+            flash::parse_layout(&std::fs::read_to_string(file_name)?).map_err(|e| {
+                ConfigError::InvalidConfig(format!("bad flash layout {}: {}", file_name, e))
+            })?
+        } else {
+            vec![]
+        };
+        let flash_partition = if let Some(name) = matches.value_of("partition") {
+            match flash_layout.iter().find(|p| p.name == name) {
+                Some(p) => Some(p.clone()),
+                None => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "no partition named {} -- must be one of: {}",
+                        name,
+                        flash_layout
+                            .iter()
+                            .map(|p| p.name.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    )))
+                }
+            }
+        } else {
+            None
+        };
+        let flash_offset = if let Some(p) = &flash_partition {
+            p.offset
+        } else if let Some(offset) = matches.value_of("flash-offset") {
+            parse_u32(offset)?
+        } else {
+            0
+        };
+        let signature_manifest = matches.value_of("signature-manifest").map(|s| s.to_owned());
+
+        let otp_base = if let Some(addr) = matches.value_of("otp-base") {
+            Some(Self::lookup_address(&register_mapping, addr)?)
+        } else {
+            register_mapping
+                .get("otp")
+                .or_else(|| register_mapping.get("efuse"))
+                .cloned()
+        };
+        let otp_words = if let Some(n) = matches.value_of("otp-words") {
+            parse_u32(n)?
+        } else {
+            16
+        };
+
+        // Burning fuses is forever, so the write must be given twice.
+        let otp_write = if let Some(spec) = matches.value_of("otp-write") {
+            if matches.value_of("confirm-otp-write") != Some(spec) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "OTP writes are permanent -- repeat the write with --confirm-otp-write {} to really do it",
+                    spec
+                )));
+            }
+            let fields: Vec<&str> = spec.split(':').collect();
+            if fields.len() != 2 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} is not a valid OTP write -- must be WORD:VALUE",
+                    spec
+                )));
+            }
+            let word = parse_u32(fields[0])?;
+            if word >= otp_words {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "word {} is past the end of the {}-word OTP block",
+                    word, otp_words
+                )));
+            }
+            Some((word, parse_u32(fields[1])?))
+        } else {
+            None
+        };
+
+        let mut watch_thresholds = vec![];
+        if let Some(specs) = matches.values_of("watch-threshold") {
+            for spec in specs {
+                let threshold = WatchThreshold::from_string(spec)?;
+                if !watch.iter().any(|r| r.name.to_lowercase() == threshold.register) {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "threshold {} isn't on a --watch register",
+                        spec
+                    )));
+                }
+                watch_thresholds.push(threshold);
+            }
+        }
+        let watch_ema_alpha = match matches.value_of("watch-ema-alpha").map(|a| a.parse::<f64>()) {
+            None => 0.1,
+            Some(Ok(a)) if a > 0.0 && a <= 1.0 => a,
+            Some(_) => {
+                return Err(ConfigError::InvalidConfig(
+                    "--watch-ema-alpha must be a number above 0, and at most 1".to_owned(),
+                ))
+            }
+        };
+        let watch_count = if let Some(n) = matches.value_of("watch-count") {
+            parse_u32(n)?
+        } else {
+            0
+        };
+
+        let watch_scope = match matches.value_of("watch-scope") {
+            Some("arm") => ScopeSync::Arm,
+            Some("follow") => ScopeSync::Follow,
+            _ => ScopeSync::Off,
+        };
+        let scope_offset = if let Some(n) = matches.value_of("scope-offset") {
+            parse_u32(n)?
+        } else {
+            16
+        };
+        let scope_length = if let Some(n) = matches.value_of("scope-length") {
+            parse_u32(n)?
+        } else {
+            128
+        };
+
+        if server_kind.len() == 0 {
+            if memory_address.is_none() && cpu_register.is_none() {
+                return Err(ConfigError::NoOperationSpecified);
+            }
+            server_kind.push(ServerKind::MemoryAccess);
+        }
+
+        // Validate the configuration is correct
+        if matches.value_of("csr-csv").is_some() {
+            if server_kind.contains(&ServerKind::GDB) {
+                // You asked for --server gdb but no vexriscv jtag interfaces is found in the csr.csv file it should complain.
+                if debug_backend == RiscvBackendKind::VexRiscv
+                    && !register_mapping.contains_key("vexriscv_debug")
+                {
+                    return Err(ConfigError::InvalidConfig(
+                        "GDB specified but no vexriscv address present in csv file".to_owned(),
+                    ));
+                }
+                // You asked for --server terminal but no uart is found in the csr.csv file it should complain.
+                if !(register_mapping.contains_key("uart_xover_rxtx")
+                    && register_mapping.contains_key("uart_xover_rxempty")
+                    && register_mapping.contains_key("uart_xover_ev_pending"))
+                {
+                    return Err(ConfigError::InvalidConfig(
+                        "Terminal specified, but no xover uart addresses present in csv file"
+                            .to_owned(),
+                    ));
+                }
+            }
+        }
+
+        // Getting at a hart means halting the CPU
+        if hart.is_some() && read_only {
+            return Err(ConfigError::InvalidConfig(
+                "--hart halts the CPU, which --read-only won't allow".to_owned(),
+            ));
+        }
+        if hart.is_some() && !server_kind.contains(&ServerKind::MemoryAccess) {
+            return Err(ConfigError::InvalidConfig(
+                "--hart only applies to reading and writing an address or register".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::Coverage) {
+            if memory_address.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "coverage needs an --address to start recording from".to_owned(),
+                ));
+            }
+            if coverage_file.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "coverage needs a --coverage-file to write to".to_owned(),
+                ));
+            }
+        }
+
+        if server_kind.contains(&ServerKind::Waveform) {
+            if waveform.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "waveform needs a table of values with --waveform".to_owned(),
+                ));
+            }
+            if waveform_register.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "waveform needs a register to update with --waveform-register".to_owned(),
+                ));
+            }
+        }
+
+        if server_kind.contains(&ServerKind::Flash) {
+            if load_name.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "flash needs a file to write with --load-name".to_owned(),
+                ));
+            }
+            if !spiflash::present(&register_mapping) {
+                return Err(ConfigError::InvalidConfig(
+                    "flash needs a --csr-csv with the spiflash_bitbang or spiflash_core_master registers"
+                        .to_owned(),
+                ));
+            }
+        }
+
+        if server_kind.contains(&ServerKind::FlashVerifySignature)
+            && (load_name.is_none() || signature_manifest.is_none())
+        {
+            return Err(ConfigError::InvalidConfig(
+                "flash-verify-signature needs a --load-name and a --signature-manifest".to_owned(),
+            ));
+        }
+
+        let boards = BoardRegistry::load(matches.value_of("boards"))?;
+        let target = matches.value_of("target").map(|s| s.to_owned());
+        if let Some(label) = &target {
+            if boards.id(label).is_none() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "no board labelled {} in the board registry",
+                    label
+                )));
+            }
+            if crate::boards::dna_register(&csr_registers).is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "--target needs a --csr-csv with a dna_id register to tell boards apart".to_owned(),
+                ));
+            }
+        }
+
+        for generator in &[ServerKind::GenDtb, ServerKind::GenPac, ServerKind::GenDocs] {
+            if server_kind.contains(generator) && matches.value_of("csr-csv").is_none() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} needs a --csr-csv to describe the SoC",
+                    generator.name()
+                )));
+            }
+        }
+
+        for tool in &[ServerKind::EtherboneSend, ServerKind::EtherboneDecode] {
+            if server_kind.contains(tool) && matches.value_of("etherbone-packet").is_none() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} needs an --etherbone-packet file of hex bytes",
+                    tool.name()
+                )));
+            }
+        }
+        if server_kind.contains(&ServerKind::EtherboneSend) && ethernet_host.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "etherbone-send needs an --ethernet-host to send to".to_owned(),
+            ));
+        }
+
+        let write_combine = match matches.value_of("write-combine") {
+            Some(t) => Some(parse_duration(t)?),
+            None => None,
+        };
+
+        let file_protocol = match matches.value_of("file-protocol") {
+            Some(p) => ModemProtocol::from_string(p).ok_or_else(|| {
+                ConfigError::InvalidConfig(format!("unknown file protocol {}", p))
+            })?,
+            None => ModemProtocol::Xmodem,
+        };
+
+        if matches.is_present("mqtt-broker") && !server_kind.contains(&ServerKind::Watch) {
+            return Err(ConfigError::InvalidConfig(
+                "--mqtt-broker only makes sense with the watch server".to_owned(),
+            ));
+        }
+
+        let gdb_console = matches.is_present("gdb-console");
+        if gdb_console && !server_kind.contains(&ServerKind::GDB) {
+            return Err(ConfigError::InvalidConfig(
+                "--gdb-console only makes sense with the gdb server".to_owned(),
+            ));
+        }
+        let console_tap = if gdb_console {
+            Some(ConsoleTap::new())
+        } else {
+            None
+        };
+
+        let log_dir = matches.value_of("log-dir").map(|s| s.to_owned());
+        let reads_console = server_kind
+            .iter()
+            .any(|k| matches!(k, ServerKind::Terminal | ServerKind::Messible | ServerKind::Run))
+            || gdb_console;
+        if log_dir.is_some() && !reads_console {
+            return Err(ConfigError::InvalidConfig(
+                "--log-dir needs a server that reads the console: terminal, messible, run, or gdb with --gdb-console"
+                    .to_owned(),
+            ));
+        }
+        let log_rotation = Rotation {
+            max_size: match matches.value_of("log-max-size") {
+                Some(size) => match parse_size(size)? {
+                    0 => return Err(ConfigError::InvalidConfig("--log-max-size must be more than 0".to_owned())),
+                    size => size,
+                },
+                None => consolelog::DEFAULT_MAX_SIZE,
+            },
+            max_age: matches.value_of("log-max-age").map(parse_duration).transpose()?,
+        };
+        if log_dir.is_none() && (matches.is_present("log-max-size") || matches.is_present("log-max-age")) {
+            return Err(ConfigError::InvalidConfig(
+                "--log-max-size and --log-max-age only make sense with --log-dir".to_owned(),
+            ));
+        }
+
+        let gdb_record = match matches.value_of("gdb-record") {
+            Some(steps) => match parse_u32(steps)? {
+                0 => {
+                    return Err(ConfigError::InvalidConfig(
+                        "--gdb-record needs to keep at least one step".to_owned(),
+                    ))
+                }
+                steps => Some(steps as usize),
+            },
+            None => None,
+        };
+        if gdb_record.is_some() && !server_kind.contains(&ServerKind::GDB) {
+            return Err(ConfigError::InvalidConfig(
+                "--gdb-record only makes sense with the gdb server".to_owned(),
+            ));
+        }
+        let gdb_disasm = matches.is_present("gdb-disasm");
+        if gdb_disasm && !server_kind.contains(&ServerKind::GDB) {
+            return Err(ConfigError::InvalidConfig(
+                "--gdb-disasm only makes sense with the gdb server".to_owned(),
+            ));
+        }
+        let gdb_fileio = matches.is_present("gdb-fileio");
+        if gdb_fileio && !server_kind.contains(&ServerKind::GDB) {
+            return Err(ConfigError::InvalidConfig(
+                "--gdb-fileio only makes sense with the gdb server".to_owned(),
+            ));
+        }
+        let semihosting = matches.is_present("semihosting") || gdb_fileio;
+        if semihosting && !server_kind.contains(&ServerKind::GDB) {
+            return Err(ConfigError::InvalidConfig(
+                "--semihosting only makes sense with the gdb server".to_owned(),
+            ));
+        }
+
+        let time_sync_addr = if let Some(addr) = matches.value_of("time-sync-addr") {
+            Some(Self::lookup_address(&register_mapping, addr)?)
+        } else {
+            None
+        };
+        let time_sync_samples = if let Some(n) = matches.value_of("time-sync-samples") {
+            parse_u32(n)?
+        } else {
+            16
+        };
+        if server_kind.contains(&ServerKind::TimeSync) {
+            if time_sync_addr.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "time-sync needs a --time-sync-addr to write the time to".to_owned(),
+                ));
+            }
+            if time_sync_samples == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "--time-sync-samples must be at least 1".to_owned(),
+                ));
+            }
+        }
+
+        let tap_base = if let Some(addr) = matches.value_of("tap-base") {
+            Some(Self::lookup_address(&register_mapping, addr)?)
+        } else {
+            register_mapping.get("tap").cloned()
+        };
+        if server_kind.contains(&ServerKind::Tap) && tap_base.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "tap needs a --tap-base, or a --csr-csv with a tap region".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::Otp) && otp_base.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "otp needs an --otp-base, or a --csr-csv with an otp or efuse region".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::Pulse) && pulse.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "pulse needs a bit to pulse with --pulse".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::SerialBoot) && load_name.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "serial-boot needs a firmware file to send with --load-name".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::Run) {
+            if load_name.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "run needs a firmware file to load with --load-name".to_owned(),
+                ));
+            }
+            if expect.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "run needs at least one --expect pattern".to_owned(),
+                ));
+            }
+        }
+
+        if let Some(size) = burst_size {
+            if size < 4 || !size.is_power_of_two() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "burst size {} must be a power of two, and at least 4",
+                    size
+                )));
+            }
+        }
+
+        let ecc_controllers = EccController::discover(&csr_registers);
+        if server_kind.contains(&ServerKind::Ecc) && ecc_controllers.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "no ECC controllers found -- specify a --csr-csv with *_sec_errors and *_ded_errors registers".to_owned(),
+            ));
+        }
+
+        let phy = matches.value_of("phy").map(|s| s.to_owned());
+        // possible_values() makes sure this is a pattern
+        let prbs_pattern = PrbsPattern::from_string(matches.value_of("prbs").unwrap_or("7"))
+            .unwrap_or(PrbsPattern::Prbs7);
+        let phy_loopback = match matches.value_of("phy-loopback") {
+            Some(mode) => Some(parse_u32(mode)?),
+            None => None,
+        };
+        let phy_duration = match matches.value_of("phy-duration") {
+            Some(t) => parse_duration(t)?,
+            None => Duration::from_secs(10),
+        };
+        let phy_rate = match matches.value_of("phy-rate") {
+            Some(rate) => Some(parse_rate(rate)?),
+            None => None,
+        };
+        if server_kind.contains(&ServerKind::PhyTest) {
+            let phys = PrbsPhy::discover(&csr_registers);
+            if phys.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "no PHYs found -- specify a --csr-csv with *_tx_prbs_config, *_rx_prbs_config and *_rx_prbs_errors registers".to_owned(),
+                ));
+            }
+            if let Some(name) = &phy {
+                if !phys.iter().any(|p| p.name == *name) {
+                    let names: Vec<&str> = phys.iter().map(|p| p.name.as_str()).collect();
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "there's no PHY called {} -- there's {}",
+                        name,
+                        names.join(", ")
+                    )));
+                }
+            }
+        }
+
+        let mut deglitch = vec![];
+        for spec in matches.values_of("deglitch").unwrap_or_default() {
+            deglitch.push(parse_deglitch(spec, &memory_regions)?);
+        }
+
+        // possible_values() makes sure this is a target
+        let endurance_target =
+            EnduranceTarget::from_string(matches.value_of("endurance-target").unwrap_or("ram"))
+                .unwrap_or(EnduranceTarget::Ram);
+        let endurance_region = match matches.value_of("endurance-region") {
+            Some(spec) => Some(match spec.split_once(':') {
+                Some((addr, len)) => (parse_u32(addr)?, parse_u32(len)?),
+                None => match memory_regions.iter().find(|r| r.name == spec) {
+                    Some(region) if endurance_target == EnduranceTarget::Ram => {
+                        (region.base, region.size)
+                    }
+                    _ => {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "--endurance-region {} isn't ADDRESS:LENGTH or the name of a RAM region",
+                            spec
+                        )))
+                    }
+                },
+            }),
+            None => None,
+        };
+        let endurance_duration = match matches.value_of("endurance-duration") {
+            Some(t) => parse_duration(t)?,
+            None => Duration::from_secs(3600),
+        };
+        let endurance_interval = match matches.value_of("endurance-interval") {
+            Some(t) => parse_duration(t)?,
+            None => Duration::from_secs(60),
+        };
+        let endurance_sensors = match matches.values_of("endurance-sensor") {
+            Some(names) => {
+                let mut sensors = vec![];
+                for name in names {
+                    match csr_registers.iter().find(|r| r.name == name.to_lowercase()) {
+                        Some(reg) => sensors.push(reg.clone()),
+                        None => {
+                            return Err(ConfigError::InvalidConfig(format!(
+                                "--endurance-sensor {} isn't in the --csr-csv",
+                                name
+                            )))
+                        }
+                    }
+                }
+                sensors
+            }
+            None => csr_registers
+                .iter()
+                .filter(|r| r.name.ends_with("_temperature"))
+                .cloned()
+                .collect(),
+        };
+        let mut stress_regions = vec![];
+        for spec in matches.values_of("stress-region").unwrap_or_default() {
+            let (base, len) = match spec.split_once(':') {
+                Some((addr, len)) => (parse_u32(addr)?, parse_u32(len)?),
+                None => match memory_regions.iter().find(|r| r.name == spec) {
+                    Some(region) if region.kind == MemoryKind::Ram => (region.base, region.size),
+                    _ => {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "--stress-region {} isn't ADDRESS:LENGTH or the name of a RAM region",
+                            spec
+                        )))
+                    }
+                },
+            };
+            if len == 0 || !len.is_multiple_of(4) || !base.is_multiple_of(4) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--stress-region {} must be word aligned and a non-zero number of words",
+                    spec
+                )));
+            }
+            if base.checked_add(len).is_none() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--stress-region {} runs off the end of the address space",
+                    spec
+                )));
+            }
+            stress_regions.push((base, len));
+        }
+        if server_kind.contains(&ServerKind::Stress) && stress_regions.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "stress needs RAM it can write to with --stress-region".to_owned(),
+            ));
+        }
+        let stress_seed = matches.value_of("stress-seed").map(parse_u64).transpose()?;
+        let stress_ops = matches.value_of("stress-ops").map(parse_u64).transpose()?;
+
+        let svd_file = matches.value_of("svd").map(|s| s.to_owned());
+        if server_kind.contains(&ServerKind::Tui) && svd_file.is_none() && soc.blocks.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "tui needs registers to show, from an --svd file or a --csr-csv".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::Endurance) {
+            let (base, len) = match endurance_region {
+                Some(region) => region,
+                None => {
+                    return Err(ConfigError::InvalidConfig(
+                        "endurance needs something to cycle with --endurance-region".to_owned(),
+                    ))
+                }
+            };
+            if len == 0 || !len.is_multiple_of(4) {
+                return Err(ConfigError::InvalidConfig(
+                    "the --endurance-region length must be a non-zero multiple of 4".to_owned(),
+                ));
+            }
+            let end = match base.checked_add(len) {
+                Some(end) => end,
+                None => {
+                    return Err(ConfigError::InvalidConfig(
+                        "the --endurance-region runs off the end of the address space".to_owned(),
+                    ))
+                }
+            };
+            if endurance_interval.as_millis() == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "--endurance-interval can't be zero".to_owned(),
+                ));
+            }
+            if endurance_target == EnduranceTarget::Flash {
+                if !spiflash::present(&register_mapping) {
+                    return Err(ConfigError::InvalidConfig(
+                        "endurance needs a --csr-csv with the spiflash registers to cycle flash"
+                            .to_owned(),
+                    ));
+                }
+                if !base.is_multiple_of(spiflash::SECTOR_SIZE) || !len.is_multiple_of(spiflash::SECTOR_SIZE) {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "a flash --endurance-region must start and end on a {}-byte sector",
+                        spiflash::SECTOR_SIZE
+                    )));
+                }
+                // Every cycle erases the whole run, so it has to stay inside
+                // the flash, and inside one partition if there's a layout
+                if let Some(region) = memory_regions.iter().find(|r| r.kind == MemoryKind::Flash) {
+                    if end > region.size {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "the --endurance-region runs past the end of the {}-byte flash",
+                            region.size
+                        )));
+                    }
+                }
+                if !flash_layout.is_empty()
+                    && !flash_layout.iter().any(|p| p.contains(base) && end - p.offset <= p.size)
+                {
+                    return Err(ConfigError::InvalidConfig(
+                        "a flash --endurance-region must fit inside one partition of the --flash-layout"
+                            .to_owned(),
+                    ));
+                }
+            }
+        }
+
+        let dma_engines = DmaEngine::discover(&csr_registers);
+        let dma_engine = match matches.value_of("dma-engine") {
+            Some(name) => Some(
+                dma_engines
+                    .iter()
+                    .find(|e| e.name == name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "there's no DMA engine called {} in the csr map",
+                            name
+                        ))
+                    })?,
+            ),
+            None if dma_engines.len() == 1 => Some(dma_engines[0].clone()),
+            None => None,
+        };
+        let dma_descriptors = match matches.value_of("dma-descriptors") {
+            Some(addr) => Some(parse_u32(addr)?),
+            None => None,
+        };
+        let mut dma_segments = vec![];
+        if let Some(specs) = matches.values_of("dma-segment") {
+            for spec in specs {
+                let fields: Vec<&str> = spec.split(':').collect();
+                if fields.len() != 2 {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "{} is not a valid segment -- must be ADDRESS:LENGTH",
+                        spec
+                    )));
+                }
+                dma_segments.push(Segment {
+                    address: parse_u32(fields[0])?,
+                    length: parse_u32(fields[1])?,
+                });
+            }
+        }
+        if server_kind.contains(&ServerKind::Dma) {
+            if dma_engine.is_none() {
+                let names: Vec<&str> = dma_engines.iter().map(|e| e.name.as_str()).collect();
+                return Err(ConfigError::InvalidConfig(match names.len() {
+                    0 => "no DMA engines found -- specify a --csr-csv with *_descriptor, *_start and *_done registers".to_owned(),
+                    _ => format!("pick a DMA engine with --dma-engine: {}", names.join(", ")),
+                }));
+            }
+            if dma_descriptors.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "dma needs --dma-descriptors to say where the chain goes".to_owned(),
+                ));
+            }
+            if dma_segments.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "dma needs at least one --dma-segment".to_owned(),
+                ));
+            }
+        }
+
+        if server_kind.contains(&ServerKind::Watch)
+            && watch.is_empty()
+            && (!watch_ecc || ecc_controllers.is_empty())
+        {
+            return Err(ConfigError::InvalidConfig(
+                "nothing to watch -- specify registers with --watch, or --watch-ecc".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::GdbBench) && bench_iterations == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "gdb-bench needs at least one iteration".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::StateSave)
+            || server_kind.contains(&ServerKind::StateRestore)
+        {
+            if csr_registers.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "saving or restoring state requires a --csr-csv file".to_owned(),
+                ));
+            }
+            // A golden dump is enough for state-save on its own
+            let needs_state_file =
+                server_kind.contains(&ServerKind::StateRestore) || golden_file.is_none();
+            if state_file.is_none() && needs_state_file {
+                return Err(ConfigError::InvalidConfig(
+                    "saving or restoring state requires a --state-file".to_owned(),
+                ));
+            }
+        }
+
+        let cfg = Config {
+            usb_pid,
+            usb_vid,
+            usb_bus,
+            usb_device,
+            serial_port,
             serial_baud,
             spi_pins,
             memory_address,
+            memory_register,
             memory_value,
+            hart,
+            cpu_register,
             server_kind,
             bridge_kind,
+            sim_peripherals,
             bind_port,
             bind_addr,
             gdb_port,
@@ -304,50 +2083,438 @@ impl Config {
             random_range,
             messible_address,
             register_mapping,
+            csr_registers,
             debug_offset,
+            debug_backend,
+            reset_csr,
+            footguns,
+            client_tokens,
+            reset_vector,
+            reset_settle,
+            pseudo_registers,
+            clock_frequency: clock,
+            custom_csrs,
+            gdb_console,
+            semihosting,
+            gdb_fileio,
+            console_tap,
+            write_combine,
+            send_file: matches.value_of("send-file").map(|s| s.to_owned()),
+            receive_file: matches.value_of("receive-file").map(|s| s.to_owned()),
+            file_protocol,
+            linux_offsets,
             load_name,
             load_addr,
+            uf2_family,
+            state_file,
+            golden_file,
+            bench_iterations,
+            coverage_file,
+            coverage_mode,
+            coverage_size,
+            coverage_samples,
+            expect,
+            report_format: matches.value_of("report").and_then(ReportFormat::from_string),
+            report_file: matches.value_of("report-file").map(|s| s.to_owned()),
+            timeout,
+            summary_json,
+            gdb_prefetch: matches.is_present("gdb-prefetch"),
+            gdb_record,
+            gdb_disasm,
+            probe_cache: !matches.is_present("no-cache"),
+            journal: matches.value_of("journal").map(Journal::new),
+            trace_out: matches.value_of("trace-out").map(|s| s.to_owned()),
+            syslog: matches.value_of("syslog").map(|s| s.to_owned()),
+            // possible_values() makes sure this is a format
+            syslog_format: SyslogFormat::from_string(matches.value_of("syslog-format").unwrap_or("syslog"))
+                .unwrap_or(SyslogFormat::Rfc5424),
+            log_dir,
+            log_rotation,
+            transfers,
+            sparse,
+            resume: matches.is_present("resume"),
+            burst_size,
+            probe: !matches.is_present("no-probe"),
+            ecc_clear: matches.is_present("ecc-clear"),
+            ecc_scrub: matches.is_present("ecc-scrub"),
+            dma_engine,
+            dma_descriptors,
+            dma_segments,
+            phy,
+            prbs_pattern,
+            phy_loopback,
+            phy_duration,
+            phy_rate,
+            endurance_target,
+            endurance_region,
+            endurance_duration,
+            endurance_interval,
+            endurance_sensors,
+            stress_regions,
+            stress_seed,
+            stress_ops,
+            svd_file,
+            calc,
+            calc_constants,
+            watch,
+            watch_ecc,
+            watch_interval,
+            watch_thresholds,
+            watch_alert: matches.value_of("watch-alert").map(|s| s.to_owned()),
+            watch_ema_alpha,
+            watch_count,
+            watch_scope,
+            mqtt_broker: matches.value_of("mqtt-broker").map(|s| s.to_owned()),
+            mqtt_topic: matches
+                .value_of("mqtt-topic")
+                .unwrap_or("wishbone/{register}")
+                .to_owned(),
+            mqtt_client_id: matches
+                .value_of("mqtt-client-id")
+                .map(|s| s.to_owned())
+                .unwrap_or_else(|| format!("wishbone-tool-{}", std::process::id())),
+            scope_offset,
+            scope_length,
+            scope_file: matches.value_of("scope-file").map(|s| s.to_owned()),
+            pulse,
+            pulse_duration,
+            waveform,
+            waveform_register,
+            waveform_loops,
+            flash_offset,
+            memory_regions,
+            soc,
+            dtb_file: matches.value_of("dtb-file").map(|s| s.to_owned()),
+            pac_file: matches.value_of("pac-file").map(|s| s.to_owned()),
+            docs_file: matches.value_of("docs-file").map(|s| s.to_owned()),
+            etherbone_packet: matches.value_of("etherbone-packet").map(|s| s.to_owned()),
+            flash_layout,
+            flash_partition,
+            signature_manifest,
+            signing_key: matches.value_of("signing-key").map(|s| s.to_owned()),
+            otp_base,
+            otp_words,
+            otp_write,
+            boards,
+            target,
+            read_board_id: matches.is_present("board-id"),
+            wait_for_lock: matches.is_present("wait-for-lock"),
+            otp_audit_log: matches
+                .value_of("otp-audit-log")
+                .unwrap_or("otp-audit.log")
+                .to_owned(),
+            time_sync_addr,
+            time_sync_samples,
+            tap_base,
+            tap_name: matches.value_of("tap-name").unwrap_or("wbtap%d").to_owned(),
+            tap_tun: matches.is_present("tun"),
             ethernet_host,
+            bridge_failover,
+            posted_writes: matches.is_present("posted-writes"),
+            posted_sync: matches.is_present("sync"),
+            deglitch,
+            read_only,
+            verify: matches.is_present("verify"),
+            peer,
+            peer_register_mapping,
+            sequence,
             ethernet_port,
             ethernet_tcp,
-        })
+            ethernet_token,
+            connections,
+        };
+
+        if cfg.watch_scope != ScopeSync::Off && LiteScope::new(&cfg).is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "--watch-scope needs a --csr-csv with the LiteScope analyzer registers".to_owned(),
+            ));
+        }
+        Ok(cfg)
+    }
+
+    /// Read a manifest of transfers to perform, one per line, as either
+    /// `load,FILE,ADDRESS` or `dump,FILE,ADDRESS,LENGTH`.  Relative file
+    /// names are relative to the manifest itself.
+    fn parse_manifest(filename: Option<&str>) -> Result<Vec<Transfer>, ConfigError> {
+        let mut transfers = vec![];
+        let filename = match filename {
+            None => return Ok(transfers),
+            Some(s) => s,
+        };
+        let base_dir = std::path::Path::new(filename)
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new(""));
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(File::open(filename)?);
+        for result in rdr.records() {
+            let r = csv_record(result, "manifest")?;
+            let kind = match &r[0] {
+                "load" if r.len() == 3 => TransferKind::Load,
+                "dump" if r.len() == 4 => TransferKind::Dump,
+                _ => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "invalid manifest line: {}",
+                        r.iter().collect::<Vec<&str>>().join(",")
+                    )))
+                }
+            };
+            transfers.push(Transfer {
+                file: base_dir.join(&r[1]).to_string_lossy().to_string(),
+                addr: parse_u32(&r[2])?,
+                len: if kind == TransferKind::Dump {
+                    parse_u32(&r[3])?
+                } else {
+                    0
+                },
+                kind,
+            });
+        }
+        Ok(transfers)
+    }
+
+    /// Look up a register by name, falling back to parsing it as an address.
+    fn lookup_address(
+        register_mapping: &HashMap<String, u32>,
+        name: &str,
+    ) -> Result<u32, ConfigError> {
+        match register_mapping.get(&name.to_lowercase()) {
+            Some(addr) => Ok(*addr),
+            None => parse_u32(name),
+        }
+    }
+
+    /// Read a sequence of steps to run across two boards.  Each line is
+    /// `TARGET,STEP,ARGS...`, where `TARGET` is `a` for this board or `b`
+    /// for the peer, and `STEP` is one of:
+    ///
+    ///   poke,ADDR,VALUE
+    ///   peek,ADDR
+    ///   wait,ADDR,MASK,VALUE[,TIMEOUT]
+    ///   dump,ADDR,LEN,FILE
+    ///   sleep,TIME
+    ///   fence[,ADDR]
+    ///
+    /// A fence waits for the board's writes to land, which matters with
+    /// --posted-writes, by reading ADDR back or else the scratch register.
+    /// A line that just says `barrier` holds every board until all of them
+    /// have got that far, with their writes landed.  Addresses may be
+    /// register names from the csr map of the board they're on.
+    fn parse_sequence(
+        filename: Option<&str>,
+        register_mappings: &[&HashMap<String, u32>; 2],
+        have_peer: bool,
+    ) -> Result<Vec<SequenceStep>, ConfigError> {
+        let mut steps = vec![];
+        let file = match filename {
+            None => return Ok(steps),
+            Some(s) => File::open(s)?,
+        };
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(file);
+        for result in rdr.records() {
+            let r = csv_record(result, "sequence")?;
+            let line = r.position().map(|p| p.line() as usize).unwrap_or(0);
+            let fields: Vec<&str> = r.iter().collect();
+            let invalid = || {
+                ConfigError::InvalidConfig(format!(
+                    "invalid sequence step on line {}: {}",
+                    line,
+                    fields.join(",")
+                ))
+            };
+            if fields == ["barrier"] {
+                steps.push(SequenceStep {
+                    target: 0,
+                    op: SequenceOp::Barrier,
+                    line,
+                });
+                continue;
+            }
+            let target = TARGET_NAMES
+                .iter()
+                .position(|&name| Some(&name) == fields.first())
+                .ok_or_else(invalid)?;
+            if target > 0 && !have_peer {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "line {} of the sequence is for board b, but there's no --peer",
+                    line
+                )));
+            }
+            let addr = |s: &str| Self::lookup_address(register_mappings[target], s);
+            let op = match &fields[1..] {
+                ["poke", a, v] => SequenceOp::Poke(addr(a)?, parse_u32(v)?),
+                ["peek", a] => SequenceOp::Peek(addr(a)?),
+                ["wait", a, m, v] => SequenceOp::Wait {
+                    addr: addr(a)?,
+                    mask: parse_u32(m)?,
+                    value: parse_u32(v)?,
+                    timeout: Duration::from_secs(1),
+                },
+                ["wait", a, m, v, t] => SequenceOp::Wait {
+                    addr: addr(a)?,
+                    mask: parse_u32(m)?,
+                    value: parse_u32(v)?,
+                    timeout: parse_duration(t)?,
+                },
+                ["dump", a, len, file] => SequenceOp::Dump(addr(a)?, parse_u32(len)?, file.to_string()),
+                ["sleep", t] => SequenceOp::Sleep(parse_duration(t)?),
+                ["fence"] => SequenceOp::Fence(None),
+                ["fence", a] => SequenceOp::Fence(Some(addr(a)?)),
+                _ => return Err(invalid()),
+            };
+            steps.push(SequenceStep { target, op, line });
+        }
+        Ok(steps)
+    }
+
+    /// The configuration for opening the --peer board, if there is one.
+    pub fn peer_config(&self) -> Option<Config> {
+        let mut cfg = self.clone();
+        cfg.register_mapping = self.peer_register_mapping.clone();
+        cfg.bridge_failover = false;
+        match self.peer.as_ref()? {
+            PeerSpec::Usb(pid) => {
+                cfg.bridge_kind = BridgeKind::UsbBridge;
+                cfg.usb_pid = Some(*pid);
+                cfg.usb_bus = None;
+                cfg.usb_device = None;
+            }
+            PeerSpec::Uart(port, baud) => {
+                cfg.bridge_kind = BridgeKind::UartBridge;
+                cfg.serial_port = Some(port.clone());
+                cfg.serial_baud = baud.or(self.serial_baud);
+            }
+            PeerSpec::Ethernet(host, port) => {
+                cfg.bridge_kind = BridgeKind::EthernetBridge;
+                cfg.ethernet_host = Some(host.clone());
+                cfg.ethernet_port = port.unwrap_or(self.ethernet_port);
+            }
+            PeerSpec::Sim => cfg.bridge_kind = BridgeKind::SimBridge,
+        }
+        Some(cfg)
+    }
+
+    /// Read a waveform, with one `TIME,VALUE` pair per line.  Times are
+    /// measured from the start of the waveform, and must not go backwards.
+    fn parse_waveform(filename: Option<&str>) -> Result<Vec<(Duration, u32)>, ConfigError> {
+        let mut points = vec![];
+        let file = match filename {
+            None => return Ok(points),
+            Some(s) => File::open(s)?,
+        };
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(file);
+        let mut last = Duration::from_secs(0);
+        for result in rdr.records() {
+            let r = csv_record(result, "waveform")?;
+            if r.len() != 2 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "invalid waveform line: {}",
+                    r.iter().collect::<Vec<&str>>().join(",")
+                )));
+            }
+            let time = parse_duration(&r[0])?;
+            if time < last {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "waveform goes back in time at {}",
+                    &r[0]
+                )));
+            }
+            last = time;
+            points.push((time, parse_u32(&r[1])?));
+        }
+        Ok(points)
     }
 
-    fn parse_csr_csv(filename: Option<&str>) -> Result<HashMap<String, u32>, ConfigError> {
+    /// Read the kernel structure offsets needed to walk the Linux task list.
+    /// The file has one `name,value` pair per line, for each of the fields
+    /// in `LinuxOffsets`.
+    fn parse_linux_offsets(filename: Option<&str>) -> Result<Option<LinuxOffsets>, ConfigError> {
+        let file = match filename {
+            None => return Ok(None),
+            Some(s) => File::open(s)?,
+        };
+        let mut values = HashMap::new();
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(file);
+        for result in rdr.records() {
+            let r = csv_record(result, "linux offsets file")?;
+            if r.len() >= 2 {
+                values.insert(r[0].to_lowercase(), parse_u32(&r[1])?);
+            }
+        }
+        let get = |name: &str| match values.get(name) {
+            Some(v) => Ok(*v),
+            None => Err(ConfigError::InvalidConfig(format!(
+                "linux offsets file is missing \"{}\"",
+                name
+            ))),
+        };
+        Ok(Some(LinuxOffsets {
+            init_task: get("init_task")?,
+            tasks: get("tasks")?,
+            pid: get("pid")?,
+            comm: get("comm")?,
+            thread: get("thread")?,
+        }))
+    }
+
+    pub fn parse_csr_csv(
+        filename: Option<&str>,
+    ) -> Result<(HashMap<String, u32>, Vec<CsrRegister>), ConfigError> {
         let mut map = HashMap::new();
+        let mut registers = vec![];
         let file = match filename {
-            None => return Ok(map),
+            None => return Ok((map, registers)),
             Some(s) => File::open(s)?,
         };
+        let mut data_width = None;
+        let mut ordering = CsrOrdering::Big;
         let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(file);
         for result in rdr.records() {
             if let Ok(r) = result {
                 match &r[0] {
+                    "constant" if &r[1] == "config_csr_data_width" => {
+                        data_width = Some(r[2].to_owned());
+                    }
+                    "constant" if &r[1] == "config_csr_ordering" => {
+                        ordering = CsrOrdering::from_string(&r[2]).unwrap_or(CsrOrdering::Big);
+                    }
                     "csr_register" => {
                         let reg_name = &r[1];
                         let base_addr = parse_u32(&r[2])?;
                         let num_regs = parse_u32(&r[3])?;
 
-                        // If there's only one register, add it to the map.
-                        // However, CSRs can span multiple registers, and do so in reverse.
-                        // If this is the case, create indexed offsets for those registers.
-                        match num_regs {
-                            1 => {
-                                map.insert(reg_name.to_string().to_lowercase(), base_addr);
-                            }
-                            n => {
-                                for offset in 0..n {
-                                    map.insert(
-                                        format!(
-                                            "{}{}",
-                                            reg_name.to_string().to_lowercase(),
-                                            n - offset - 1
-                                        ),
-                                        base_addr + (offset * 4),
-                                    );
-                                }
-                            }
-                        }
+                        // Older csr.csv files lack the "mode" column, so assume
+                        // those registers are writable.
+                        let mode = match r.get(4) {
+                            Some("ro") => CsrMode::ReadOnly,
+                            _ => CsrMode::ReadWrite,
+                        };
+                        registers.push(CsrRegister {
+                            name: reg_name.to_lowercase(),
+                            address: base_addr,
+                            words: num_regs,
+                            mode,
+                            data_width: 32,
+                            ordering: CsrOrdering::Big,
+                        });
                     },
                     "memory_region" => {
                         let region = &r[1];
@@ -358,6 +2525,112 @@ impl Config {
                 };
             }
         }
-        Ok(map)
+        let data_width = csr_data_width(data_width.as_deref(), &registers);
+        for reg in &mut registers {
+            reg.data_width = data_width;
+            reg.ordering = ordering.clone();
+
+            // If there's only one register, add it to the map.  However,
+            // CSRs can span multiple registers, normally in reverse.  If
+            // this is the case, create indexed offsets for those registers.
+            match reg.words {
+                1 => {
+                    map.insert(reg.name.clone(), reg.address);
+                }
+                n => {
+                    for offset in 0..n {
+                        map.insert(
+                            format!("{}{}", reg.name, reg.significance(offset)),
+                            reg.address + (offset * 4),
+                        );
+                    }
+                }
+            }
+        }
+        Ok((map, registers))
+    }
+
+    /// Collect the regions of the address space that need special access
+    /// handling.  LiteX marks its CSR and peripheral regions as "io" in
+    /// csr.csv, and a `--memory-map` file of `name,base,size[,policy[,type]]`
+    /// lines can add regions or override the policy of those by name.  The
+    /// type is ram, rom or flash, and is guessed from the name if it's left
+    /// out.
+    fn parse_memory_regions(
+        csr_csv: Option<&str>,
+        memory_map: Option<&str>,
+    ) -> Result<Vec<MemoryRegion>, ConfigError> {
+        let mut regions: Vec<MemoryRegion> = vec![];
+        if let Some(file_name) = csr_csv {
+            let file = File::open(file_name)?;
+            let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(file);
+            for result in rdr.records() {
+                let r = csv_record(result, "csr csv")?;
+                if &r[0] != "memory_region" || r.len() < 4 {
+                    continue;
+                }
+                let name = r[1].to_lowercase();
+                regions.push(MemoryRegion {
+                    kind: MemoryKind::from_name(&name),
+                    name,
+                    base: parse_u32(&r[2])?,
+                    size: parse_u32(&r[3])?,
+                    policy: match r.get(4) {
+                        Some("io") => AccessPolicy::WordOnly,
+                        _ => AccessPolicy::Any,
+                    },
+                });
+            }
+        }
+
+        if let Some(file_name) = memory_map {
+            let file = File::open(file_name)?;
+            let mut rdr = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .comment(Some(b'#'))
+                .trim(csv::Trim::All)
+                .from_reader(file);
+            for result in rdr.records() {
+                let r = result.map_err(|e| {
+                    ConfigError::InvalidConfig(format!("bad memory map {}: {}", file_name, e))
+                })?;
+                if r.len() < 3 {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "memory map {} has a line with fewer than three fields",
+                        file_name
+                    )));
+                }
+                let policy = match r.get(3) {
+                    None | Some("") => AccessPolicy::Any,
+                    Some(p) => AccessPolicy::from_string(p).ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "unknown access policy {} -- must be 'any' or 'word'",
+                            p
+                        ))
+                    })?,
+                };
+                let name = r[0].to_lowercase();
+                let kind = match r.get(4) {
+                    None | Some("") => MemoryKind::from_name(&name),
+                    Some(k) => MemoryKind::from_string(k).ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "unknown memory type {} -- must be 'ram', 'rom' or 'flash'",
+                            k
+                        ))
+                    })?,
+                };
+                let region = MemoryRegion {
+                    name,
+                    base: parse_u32(&r[1])?,
+                    size: parse_u32(&r[2])?,
+                    policy,
+                    kind,
+                };
+                regions.retain(|existing| existing.name != region.name);
+                regions.push(region);
+            }
+        }
+        Ok(regions)
     }
 }
@@ -0,0 +1,232 @@
+//! Capturing what the firmware prints to files in a --log-dir, for soak
+//! tests that run for days.  The console goes to `console.log`, which is
+//! set aside as `console-<time>.log` once it reaches --log-max-size or
+//! has been open for --log-max-age, and then gzipped in the background by
+//! the system's `gzip`.  Starting again with the same directory carries
+//! on appending to `console.log`, and gzips anything that was set aside
+//! but not yet compressed when the last capture stopped.
+//!
+//! When the disk fills, the oldest logs that were set aside are deleted
+//! to make room.  If that's not enough, output is held in memory, up to a
+//! point, until there's room for it.
+
+use crate::syslog;
+
+use log::{error, warn};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The file being written to
+const CURRENT: &str = "console.log";
+
+/// How the files that have been set aside start
+const ARCHIVE_PREFIX: &str = "console-";
+
+/// The most output that's held while it can't be written
+const MAX_HELD: usize = 4 * 1024 * 1024;
+
+/// How long to leave it after `console.log` couldn't be set aside before
+/// trying again
+const ROTATE_RETRY: Duration = Duration::from_secs(60);
+
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// When `console.log` is set aside for a new one
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_age: Option<Duration>,
+}
+
+struct Capture {
+    dir: PathBuf,
+    rotation: Rotation,
+    file: File,
+
+    /// How much is in `console.log`, and when it was started
+    size: u64,
+    opened: SystemTime,
+
+    /// Output that couldn't be written yet
+    held: Vec<u8>,
+
+    /// How much output was thrown away while it couldn't be written
+    lost: u64,
+
+    /// Whether the last write failed, so that it's only said once
+    failing: bool,
+
+    /// When setting `console.log` aside last failed, if it did, so that
+    /// it's not tried on every write or said more than once
+    rotate_failed: Option<Instant>,
+}
+
+static CAPTURE: OnceLock<Mutex<Capture>> = OnceLock::new();
+
+/// Start writing the console to `dir`, which is made if need be
+pub fn install(dir: &Path, rotation: Rotation) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new().create(true).append(true).open(dir.join(CURRENT))?;
+    let metadata = file.metadata()?;
+    let capture = Capture {
+        dir: dir.to_owned(),
+        rotation,
+        file,
+        size: metadata.len(),
+        opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+        held: vec![],
+        lost: 0,
+        failing: false,
+        rotate_failed: None,
+    };
+    for leftover in capture.archives()? {
+        if leftover.extension().map(|e| e == "log").unwrap_or(false) {
+            compress(leftover);
+        }
+    }
+    if CAPTURE.set(Mutex::new(capture)).is_err() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the console is already being captured"));
+    }
+    Ok(())
+}
+
+/// Add some of what the firmware printed
+pub fn console(data: &[u8]) {
+    if let Some(capture) = CAPTURE.get() {
+        capture.lock().unwrap().write(data);
+    }
+}
+
+impl Capture {
+    fn write(&mut self, data: &[u8]) {
+        let retry = self.rotate_failed.is_none_or(|t| t.elapsed() >= ROTATE_RETRY);
+        if retry && self.due() {
+            match self.rotate() {
+                Ok(()) => self.rotate_failed = None,
+                Err(e) => {
+                    if self.rotate_failed.is_none() {
+                        error!("couldn't set aside {}: {}", self.dir.join(CURRENT).display(), e);
+                    }
+                    self.rotate_failed = Some(Instant::now());
+                }
+            }
+        }
+        self.held.extend_from_slice(data);
+        if self.held.len() > MAX_HELD {
+            let excess = self.held.len() - MAX_HELD;
+            self.held.drain(..excess);
+            self.lost += excess as u64;
+        }
+        while !self.held.is_empty() {
+            match self.file.write(&self.held) {
+                Ok(0) => return,
+                Ok(count) => {
+                    self.held.drain(..count);
+                    self.size += count as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::StorageFull && self.remove_oldest() => (),
+                Err(e) => {
+                    if !self.failing {
+                        error!("couldn't write the console to {}: {}", self.dir.display(), e);
+                        self.failing = true;
+                    }
+                    return;
+                }
+            }
+        }
+        self.failing = false;
+        if self.lost > 0 {
+            warn!("{} bytes of console output were lost while they couldn't be written", self.lost);
+            self.lost = 0;
+        }
+    }
+
+    fn due(&self) -> bool {
+        if self.size >= self.rotation.max_size {
+            return true;
+        }
+        match self.rotation.max_age {
+            Some(age) => self.opened.elapsed().map(|e| e >= age).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Set `console.log` aside to be gzipped, and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (year, month, day) = syslog::civil_date(secs);
+        let stamp = format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            year,
+            month,
+            day,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60
+        );
+        // Two in the same second get told apart by a number
+        let mut archive = self.dir.join(format!("{}{}.log", ARCHIVE_PREFIX, stamp));
+        let mut n = 1;
+        while archive.exists() || archive.with_extension("log.gz").exists() {
+            archive = self.dir.join(format!("{}{}.{}.log", ARCHIVE_PREFIX, stamp, n));
+            n += 1;
+        }
+        fs::rename(self.dir.join(CURRENT), &archive)?;
+        self.file = OpenOptions::new().create(true).append(true).open(self.dir.join(CURRENT))?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        compress(archive);
+        Ok(())
+    }
+
+    /// The logs that have been set aside, oldest first
+    fn archives(&self) -> io::Result<Vec<PathBuf>> {
+        let mut archives: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.starts_with(ARCHIVE_PREFIX))
+                    .unwrap_or(false)
+            })
+            .collect();
+        archives.sort();
+        Ok(archives)
+    }
+
+    /// Make room by deleting the oldest log that was set aside, returning
+    /// whether there was one
+    fn remove_oldest(&self) -> bool {
+        let oldest = match self.archives() {
+            Ok(archives) => archives.into_iter().next(),
+            Err(_) => None,
+        };
+        match oldest {
+            Some(oldest) => {
+                warn!("the disk is full, so {} is being deleted to make room", oldest.display());
+                fs::remove_file(oldest).is_ok()
+            }
+            None => false,
+        }
+    }
+}
+
+/// Gzip `path` in the background, which gzip replaces with `path.gz`.
+/// Without gzip, it's left as it is.
+fn compress(path: PathBuf) {
+    thread::spawn(move || match Command::new("gzip").arg("-f").arg(&path).status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => warn!("gzip {} failed: {}", path.display(), status),
+        Err(e) => warn!("couldn't run gzip on {}: {}", path.display(), e),
+    });
+}
@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// How the coverage server finds out where the CPU has been.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CoverageMode {
+    /// Single-step the CPU and record every instruction.  This is
+    /// complete, but slow.
+    Step,
+
+    /// Let the CPU run, and periodically halt it to see where it is.
+    Sample,
+}
+
+impl CoverageMode {
+    pub fn from_string(item: &str) -> Option<CoverageMode> {
+        match item {
+            "step" => Some(CoverageMode::Step),
+            "sample" => Some(CoverageMode::Sample),
+            _ => None,
+        }
+    }
+}
+
+/// A bitmap of executed addresses within a region of memory.  Instructions
+/// may be compressed, so there is one bit for every halfword.
+pub struct CoverageBitmap {
+    base: u32,
+    size: u32,
+    bits: Vec<u64>,
+
+    /// Number of times the CPU was seen outside of the region
+    misses: u32,
+}
+
+impl CoverageBitmap {
+    pub fn new(base: u32, size: u32) -> CoverageBitmap {
+        let halfwords = (size as usize).div_ceil(2);
+        CoverageBitmap {
+            base,
+            size,
+            bits: vec![0; halfwords.div_ceil(64)],
+            misses: 0,
+        }
+    }
+
+    /// Mark `addr` as having been executed.
+    pub fn record(&mut self, addr: u32) {
+        let offset = addr.wrapping_sub(self.base);
+        if offset >= self.size {
+            self.misses += 1;
+            return;
+        }
+        let bit = (offset / 2) as usize;
+        self.bits[bit / 64] |= 1 << (bit % 64);
+    }
+
+    /// Iterate over every address that has been executed.
+    pub fn addresses(&self) -> impl Iterator<Item = u32> + '_ {
+        let base = self.base;
+        self.bits.iter().enumerate().flat_map(move |(word_idx, word)| {
+            let word = *word;
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| base + ((word_idx as u32 * 64 + bit) * 2))
+        })
+    }
+
+    pub fn covered(&self) -> usize {
+        self.bits.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn misses(&self) -> u32 {
+        self.misses
+    }
+
+    /// Write out each executed address as a hex number on its own line.
+    /// This is the format `addr2line -e firmware.elf` accepts, so it can be
+    /// turned into per-line coverage for gcov or LLVM tooling.
+    pub fn export(&self, file_name: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(file_name)?);
+        for addr in self.addresses() {
+            writeln!(file, "0x{:08x}", addr)?;
+        }
+        file.flush()
+    }
+}
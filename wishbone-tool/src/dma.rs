@@ -0,0 +1,207 @@
+//! Scatter-gather DMA driven from the host: lay a chain of descriptors out
+//! in target memory, one per (address, length) segment, point the engine at
+//! it, and wait for it to finish.
+//!
+//! An engine is found by looking for `<name>_descriptor`, `<name>_start`
+//! and `<name>_done` registers in csr.csv.  The address of the first
+//! descriptor goes in `_descriptor`, writing 1 to `_start` sets it off, and
+//! `_done` reads as 1 once it has worked through the chain.
+//!
+//! Descriptors are four little-endian words, and sit one after another:
+//!
+//! ```text
+//!   +0x0  next     address of the next descriptor, or 0 at the end
+//!   +0x4  address  where the segment starts
+//!   +0x8  length   bytes in the segment
+//!   +0xc  status   cleared by the host, and written by the engine
+//! ```
+//!
+//! The engine sets `STATUS_DONE` in each descriptor as it finishes with
+//! it, along with `STATUS_ERROR` if the bus faulted, so that a chain that
+//! stalls can be traced to the segment it stalled on.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::CsrRegister;
+
+use log::debug;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DESCRIPTOR_LEN: u32 = 16;
+const STATUS_OFFSET: usize = 12;
+
+const STATUS_DONE: u32 = 1 << 0;
+const STATUS_ERROR: u32 = 1 << 1;
+
+/// How often `_done` is polled
+const DONE_POLL: Duration = Duration::from_millis(1);
+
+#[derive(Debug)]
+pub enum DmaError {
+    BridgeError(BridgeError),
+
+    /// The engine didn't finish in time
+    Timeout(
+        usize, /* segments finished */
+        usize, /* segments */
+    ),
+
+    /// The engine hit a bus error on this segment
+    SegmentFailed(usize /* index */, u32 /* address */),
+
+    /// A segment or the descriptors can't be used as given
+    BadChain(String),
+}
+
+impl std::fmt::Display for DmaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use DmaError::*;
+        match self {
+            BridgeError(e) => write!(f, "bridge error: {}", e),
+            Timeout(finished, total) => write!(
+                f,
+                "timed out with {} of {} segments finished",
+                finished, total
+            ),
+            SegmentFailed(index, addr) => {
+                write!(f, "bus error on segment {}, at {:08x}", index, addr)
+            }
+            BadChain(s) => write!(f, "descriptor chain: {}", s),
+        }
+    }
+}
+
+impl std::convert::From<BridgeError> for DmaError {
+    fn from(e: BridgeError) -> DmaError {
+        DmaError::BridgeError(e)
+    }
+}
+
+/// A run of memory for the engine to move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub length: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct DmaEngine {
+    pub name: String,
+    descriptor: u32,
+    start: u32,
+    done: u32,
+}
+
+impl DmaEngine {
+    pub fn discover(registers: &[CsrRegister]) -> Vec<DmaEngine> {
+        let find = |name: &str| registers.iter().find(|r| r.name == name);
+        let mut engines = vec![];
+        for reg in registers {
+            let name = match reg.name.strip_suffix("_descriptor") {
+                Some(n) => n,
+                None => continue,
+            };
+            let (start, done) = match (
+                find(&format!("{}_start", name)),
+                find(&format!("{}_done", name)),
+            ) {
+                (Some(start), Some(done)) => (start.address, done.address),
+                _ => continue,
+            };
+            engines.push(DmaEngine {
+                name: name.to_owned(),
+                descriptor: reg.address,
+                start,
+                done,
+            });
+        }
+        engines
+    }
+
+    /// Build the chain for `segments` at `base` and run it, returning how
+    /// long the engine took once it had been started.
+    pub fn run(
+        &self,
+        bridge: &Bridge,
+        base: u32,
+        segments: &[Segment],
+        timeout: Duration,
+    ) -> Result<Duration, DmaError> {
+        build_chain(bridge, base, segments)?;
+        bridge.poke(self.descriptor, base)?;
+        bridge.poke(self.start, 1)?;
+        let started = Instant::now();
+        let deadline = started + timeout;
+        while bridge.peek(self.done)? & 1 == 0 {
+            if Instant::now() >= deadline {
+                let finished = check_chain(bridge, base, segments)?;
+                return Err(DmaError::Timeout(finished, segments.len()));
+            }
+            thread::sleep(DONE_POLL);
+        }
+        let elapsed = started.elapsed();
+        check_chain(bridge, base, segments)?;
+        debug!("{} finished {} segments in {:?}", self.name, segments.len(), elapsed);
+        Ok(elapsed)
+    }
+}
+
+/// Write out one descriptor per segment, starting at `base`.  The whole
+/// chain goes in a single burst, and the bridge is flushed so that it's
+/// all there before the engine is started.
+pub fn build_chain(bridge: &Bridge, base: u32, segments: &[Segment]) -> Result<(), DmaError> {
+    if segments.is_empty() {
+        return Err(DmaError::BadChain("there are no segments".to_owned()));
+    }
+    if base == 0 || base & 3 != 0 {
+        return Err(DmaError::BadChain(format!(
+            "descriptors can't go at {:08x}, which must be word-aligned and not 0",
+            base
+        )));
+    }
+    let mut data = vec![];
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.length == 0 {
+            return Err(DmaError::BadChain(format!("segment {} is empty", index)));
+        }
+        let chain_end = base as u64 + segments.len() as u64 * DESCRIPTOR_LEN as u64;
+        let segment_end = segment.address as u64 + segment.length as u64;
+        if (segment.address as u64) < chain_end && segment_end > base as u64 {
+            return Err(DmaError::BadChain(format!(
+                "segment {} overlaps the descriptors at {:08x}",
+                index, base
+            )));
+        }
+        let next = if index + 1 < segments.len() {
+            base + (index as u32 + 1) * DESCRIPTOR_LEN
+        } else {
+            0
+        };
+        for word in &[next, segment.address, segment.length, 0] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    bridge.burst_write(base, &data)?;
+    bridge.flush()?;
+    Ok(())
+}
+
+/// Go through the status of each descriptor, returning how many the
+/// engine finished, or the first one that faulted.
+fn check_chain(bridge: &Bridge, base: u32, segments: &[Segment]) -> Result<usize, DmaError> {
+    let data = bridge.burst_read(base, segments.len() as u32 * DESCRIPTOR_LEN)?;
+    let mut finished = 0;
+    for (index, descriptor) in data.chunks(DESCRIPTOR_LEN as usize).enumerate() {
+        let mut status = [0; 4];
+        status.copy_from_slice(&descriptor[STATUS_OFFSET..STATUS_OFFSET + 4]);
+        let status = u32::from_le_bytes(status);
+        if status & STATUS_ERROR != 0 {
+            return Err(DmaError::SegmentFailed(index, segments[index].address));
+        }
+        if status & STATUS_DONE != 0 {
+            finished += 1;
+        }
+    }
+    Ok(finished)
+}
@@ -0,0 +1,197 @@
+//! Write the register map out as a document, from the same csr.csv that
+//! wishbone-tool resolves names with, so the map a user is poking is the
+//! one that's written down.  csr.csv doesn't describe bitfields or reset
+//! values, so the most that can be said about a register's bits is which
+//! of them each of its words carries.
+
+use crate::config::{CsrMode, CsrRegister};
+use crate::soc::SocDescription;
+
+/// One row of a block's register table, already formatted
+struct RegisterRow {
+    name: String,
+    address: String,
+    offset: String,
+    access: &'static str,
+    width: String,
+    words: String,
+}
+
+fn register_row(base: u32, reg: &CsrRegister) -> RegisterRow {
+    // Which bits of the value each word carries, in address order
+    let words = (0..reg.words)
+        .map(|word| {
+            let low = reg.significance(word) * reg.data_width;
+            format!(
+                "0x{:08x}: [{}:{}]",
+                reg.address + word * 4,
+                low + reg.data_width - 1,
+                low
+            )
+        })
+        .collect::<Vec<String>>();
+    RegisterRow {
+        name: reg.name.clone(),
+        address: format!("0x{:08x}", reg.address),
+        offset: format!("0x{:03x}", reg.address.wrapping_sub(base)),
+        access: match reg.mode {
+            CsrMode::ReadOnly => "ro",
+            CsrMode::ReadWrite => "rw",
+        },
+        width: format!("{}", reg.words * reg.data_width),
+        words: words.join(", "),
+    }
+}
+
+pub fn to_markdown(soc: &SocDescription) -> String {
+    let mut out = String::new();
+    out.push_str("# Register map\n\n");
+    out.push_str("Generated by wishbone-tool from csr.csv.\n");
+
+    for block in &soc.blocks {
+        out.push_str(&format!(
+            "\n## {}\n\nBase address `0x{:08x}`, {} bytes.\n\n",
+            block.name,
+            block.base,
+            block.size()
+        ));
+        if block.registers.is_empty() {
+            out.push_str("No registers.\n");
+            continue;
+        }
+        out.push_str("| Register | Address | Offset | Access | Bits | Words |\n");
+        out.push_str("|---|---|---|---|---|---|\n");
+        for reg in &block.registers {
+            let row = register_row(block.base, reg);
+            out.push_str(&format!(
+                "| `{}` | `{}` | `{}` | {} | {} | {} |\n",
+                row.name, row.address, row.offset, row.access, row.width, row.words
+            ));
+        }
+    }
+
+    if !soc.regions.is_empty() {
+        out.push_str("\n## Memory regions\n\n");
+        out.push_str("| Region | Base | Size |\n|---|---|---|\n");
+        for region in &soc.regions {
+            out.push_str(&format!(
+                "| `{}` | `0x{:08x}` | `0x{:x}` |\n",
+                region.name, region.base, region.size
+            ));
+        }
+    }
+
+    if !soc.constants.is_empty() {
+        out.push_str("\n## Constants\n\n");
+        out.push_str("| Constant | Value |\n|---|---|\n");
+        for (name, value) in &soc.constants {
+            out.push_str(&format!(
+                "| `{}` | `{}` |\n",
+                name,
+                value.replace('|', "\\|")
+            ));
+        }
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html_row(out: &mut String, cells: &[&str]) {
+    out.push_str("<tr>");
+    for cell in cells {
+        out.push_str(&format!("<td>{}</td>", escape(cell)));
+    }
+    out.push_str("</tr>\n");
+}
+
+fn html_header(out: &mut String, cells: &[&str]) {
+    out.push_str("<table>\n<tr>");
+    for cell in cells {
+        out.push_str(&format!("<th>{}</th>", cell));
+    }
+    out.push_str("</tr>\n");
+}
+
+/// A standalone HTML page with the same tables as `to_markdown`.  Each
+/// block gets an anchor named after it.
+pub fn to_html(soc: &SocDescription) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str("<title>Register map</title>\n");
+    out.push_str("<style>\ntable { border-collapse: collapse; }\n");
+    out.push_str("td, th { border: 1px solid #999; padding: 2px 8px; font-family: monospace; }\n");
+    out.push_str("</style>\n</head>\n<body>\n<h1>Register map</h1>\n");
+    out.push_str("<p>Generated by wishbone-tool from csr.csv.</p>\n<ul>\n");
+    for block in &soc.blocks {
+        let name = escape(&block.name);
+        out.push_str(&format!("<li><a href=\"#{}\">{}</a></li>\n", name, name));
+    }
+    out.push_str("</ul>\n");
+
+    for block in &soc.blocks {
+        let name = escape(&block.name);
+        out.push_str(&format!(
+            "<h2 id=\"{}\">{}</h2>\n<p>Base address 0x{:08x}, {} bytes.</p>\n",
+            name,
+            name,
+            block.base,
+            block.size()
+        ));
+        if block.registers.is_empty() {
+            out.push_str("<p>No registers.</p>\n");
+            continue;
+        }
+        html_header(
+            &mut out,
+            &["Register", "Address", "Offset", "Access", "Bits", "Words"],
+        );
+        for reg in &block.registers {
+            let row = register_row(block.base, reg);
+            html_row(
+                &mut out,
+                &[
+                    &row.name,
+                    &row.address,
+                    &row.offset,
+                    row.access,
+                    &row.width,
+                    &row.words,
+                ],
+            );
+        }
+        out.push_str("</table>\n");
+    }
+
+    if !soc.regions.is_empty() {
+        out.push_str("<h2>Memory regions</h2>\n");
+        html_header(&mut out, &["Region", "Base", "Size"]);
+        for region in &soc.regions {
+            html_row(
+                &mut out,
+                &[
+                    &region.name,
+                    &format!("0x{:08x}", region.base),
+                    &format!("0x{:x}", region.size),
+                ],
+            );
+        }
+        out.push_str("</table>\n");
+    }
+
+    if !soc.constants.is_empty() {
+        out.push_str("<h2>Constants</h2>\n");
+        html_header(&mut out, &["Constant", "Value"]);
+        for (name, value) in &soc.constants {
+            html_row(&mut out, &[name, value]);
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
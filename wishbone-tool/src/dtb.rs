@@ -0,0 +1,252 @@
+//! Turn a csr.csv into a device tree, so Linux sees the same SoC that
+//! wishbone-tool does.  The tree can come out as source or as a
+//! flattened blob.
+
+use crate::soc::SocDescription;
+
+#[derive(Clone, Debug)]
+pub enum PropValue {
+    Empty,
+    Cells(Vec<u32>),
+    Str(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<(String, PropValue)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Node {
+        Node {
+            name: name.to_owned(),
+            properties: vec![],
+            children: vec![],
+        }
+    }
+
+    fn cells(mut self, name: &str, cells: &[u32]) -> Node {
+        self.properties
+            .push((name.to_owned(), PropValue::Cells(cells.to_vec())));
+        self
+    }
+
+    fn string(mut self, name: &str, value: &str) -> Node {
+        self.properties
+            .push((name.to_owned(), PropValue::Str(value.to_owned())));
+        self
+    }
+
+    fn empty(mut self, name: &str) -> Node {
+        self.properties.push((name.to_owned(), PropValue::Empty));
+        self
+    }
+
+    fn child(mut self, node: Node) -> Node {
+        self.children.push(node);
+        self
+    }
+}
+
+/// The compatible string for a LiteX peripheral, as the upstream kernel
+/// drivers know it
+fn compatible(block: &str) -> String {
+    match block {
+        "ctrl" => "litex,soc-controller".to_owned(),
+        "uart" => "litex,liteuart".to_owned(),
+        "ethmac" => "litex,liteeth".to_owned(),
+        "spiflash" => "litex,spiflash".to_owned(),
+        "sdcore" => "litex,mmc".to_owned(),
+        other => format!(
+            "litex,{}",
+            other.trim_end_matches(|c: char| c.is_ascii_digit())
+        ),
+    }
+}
+
+/// Build the tree: RAM, the system clock, and a node for each CSR block
+/// on a simple bus.
+pub fn from_soc(soc: &SocDescription) -> Node {
+    let mut root = Node::new("")
+        .cells("#address-cells", &[1])
+        .cells("#size-cells", &[1]);
+    if let Some(id) = soc.constant("identifier") {
+        root = root.string("model", id);
+    }
+
+    if let Some(ram) = soc.region("main_ram") {
+        root = root.child(
+            Node::new(&format!("memory@{:x}", ram.base))
+                .string("device_type", "memory")
+                .cells("reg", &[ram.base, ram.size]),
+        );
+    }
+
+    if let Some(freq) = soc
+        .constant("config_clock_frequency")
+        .and_then(|f| f.parse::<u32>().ok())
+    {
+        root = root.child(
+            Node::new("clocks").child(
+                Node::new("sys_clk")
+                    .string("compatible", "fixed-clock")
+                    .cells("#clock-cells", &[0])
+                    .cells("clock-frequency", &[freq]),
+            ),
+        );
+    }
+
+    let mut bus = Node::new("soc")
+        .string("compatible", "simple-bus")
+        .cells("#address-cells", &[1])
+        .cells("#size-cells", &[1])
+        .empty("ranges");
+    for block in &soc.blocks {
+        let mut node = Node::new(&format!("{}@{:x}", block.name, block.base))
+            .string("compatible", &compatible(&block.name))
+            .cells("reg", &[block.base, block.size()]);
+        if let Some(irq) = soc
+            .constant(&format!("{}_interrupt", block.name))
+            .and_then(|i| i.parse::<u32>().ok())
+        {
+            node = node.cells("interrupts", &[irq]);
+        }
+        bus = bus.child(node.string("status", "okay"));
+    }
+    root.child(bus)
+}
+
+fn dts_node(node: &Node, depth: usize, out: &mut String) {
+    let indent = "\t".repeat(depth);
+    let name = if node.name.is_empty() {
+        "/"
+    } else {
+        &node.name
+    };
+    out.push_str(&format!("{}{} {{\n", indent, name));
+    for (name, value) in &node.properties {
+        match value {
+            PropValue::Empty => out.push_str(&format!("{}\t{};\n", indent, name)),
+            PropValue::Cells(cells) => {
+                let cells: Vec<String> = cells
+                    .iter()
+                    .map(|c| {
+                        if *c < 10 {
+                            c.to_string()
+                        } else {
+                            format!("0x{:x}", c)
+                        }
+                    })
+                    .collect();
+                out.push_str(&format!("{}\t{} = <{}>;\n", indent, name, cells.join(" ")))
+            }
+            PropValue::Str(s) => out.push_str(&format!("{}\t{} = \"{}\";\n", indent, name, s)),
+        }
+    }
+    for (idx, child) in node.children.iter().enumerate() {
+        if idx > 0 || !node.properties.is_empty() {
+            out.push('\n');
+        }
+        dts_node(child, depth + 1, out);
+    }
+    out.push_str(&format!("{}}};\n", indent));
+}
+
+/// Device tree source, suitable for dtc or for including in a board's .dts
+pub fn to_dts(root: &Node) -> String {
+    let mut out = "/dts-v1/;\n\n".to_owned();
+    dts_node(root, 0, &mut out);
+    out
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}
+
+fn fdt_node(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    push_u32(structure, FDT_BEGIN_NODE);
+    structure.extend_from_slice(node.name.as_bytes());
+    structure.push(0);
+    pad(structure);
+    for (name, value) in &node.properties {
+        let data = match value {
+            PropValue::Empty => vec![],
+            PropValue::Cells(cells) => cells.iter().flat_map(|c| c.to_be_bytes()).collect(),
+            PropValue::Str(s) => {
+                let mut data = s.as_bytes().to_vec();
+                data.push(0);
+                data
+            }
+        };
+
+        // Property names are shared through the strings block
+        let mut key = name.as_bytes().to_vec();
+        key.push(0);
+        let name_offset = match strings.windows(key.len()).position(|w| w == &key[..]) {
+            Some(offset) if offset == 0 || strings[offset - 1] == 0 => offset,
+            _ => {
+                strings.extend_from_slice(&key);
+                strings.len() - key.len()
+            }
+        };
+
+        push_u32(structure, FDT_PROP);
+        push_u32(structure, data.len() as u32);
+        push_u32(structure, name_offset as u32);
+        structure.extend_from_slice(&data);
+        pad(structure);
+    }
+    for child in &node.children {
+        fdt_node(child, structure, strings);
+    }
+    push_u32(structure, FDT_END_NODE);
+}
+
+/// A flattened device tree blob (version 17), as loaded by the kernel
+pub fn to_dtb(root: &Node) -> Vec<u8> {
+    let mut structure = vec![];
+    let mut strings = vec![];
+    fdt_node(root, &mut structure, &mut strings);
+    push_u32(&mut structure, FDT_END);
+
+    const HEADER_SIZE: u32 = 40;
+    const RESERVE_MAP_SIZE: u32 = 16;
+    let off_struct = HEADER_SIZE + RESERVE_MAP_SIZE;
+    let off_strings = off_struct + structure.len() as u32;
+    let total = off_strings + strings.len() as u32;
+
+    let mut blob = vec![];
+    for value in &[
+        FDT_MAGIC,
+        total,
+        off_struct,
+        off_strings,
+        HEADER_SIZE, // memory reservation map
+        17,          // version
+        16,          // last compatible version
+        0,           // boot cpu
+        strings.len() as u32,
+        structure.len() as u32,
+    ] {
+        push_u32(&mut blob, *value);
+    }
+    // An empty memory reservation map is just its terminating entry
+    blob.extend_from_slice(&[0; RESERVE_MAP_SIZE as usize]);
+    blob.extend_from_slice(&structure);
+    blob.extend_from_slice(&strings);
+    blob
+}
@@ -0,0 +1,125 @@
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::CsrRegister;
+
+use log::warn;
+
+/// An ECC memory controller, found by looking for a pair of
+/// `<name>_sec_errors` and `<name>_ded_errors` registers in csr.csv.
+#[derive(Clone, Debug)]
+pub struct EccController {
+    pub name: String,
+
+    /// Single-bit errors that were found and corrected
+    sec_errors: CsrRegister,
+
+    /// Double-bit errors that could only be detected
+    ded_errors: CsrRegister,
+
+    /// Writing 1 here resets both counters
+    clear: Option<u32>,
+
+    /// Writing 1 here starts a pass over memory to correct latent errors
+    scrub: Option<u32>,
+}
+
+/// A snapshot of an `EccController`'s counters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EccCounts {
+    pub correctable: u64,
+    pub uncorrectable: u64,
+}
+
+impl EccController {
+    pub fn discover(registers: &[CsrRegister]) -> Vec<EccController> {
+        let find = |name: &str| registers.iter().find(|r| r.name == name);
+        let mut controllers = vec![];
+        for reg in registers {
+            let name = match reg.name.strip_suffix("_sec_errors") {
+                Some(n) => n,
+                None => continue,
+            };
+            let ded_errors = match find(&format!("{}_ded_errors", name)) {
+                Some(r) => r.clone(),
+                None => continue,
+            };
+            controllers.push(EccController {
+                name: name.to_owned(),
+                sec_errors: reg.clone(),
+                ded_errors,
+                clear: find(&format!("{}_clear", name)).map(|r| r.address),
+                scrub: find(&format!("{}_scrub", name)).map(|r| r.address),
+            });
+        }
+        controllers
+    }
+
+    pub fn read(&self, bridge: &Bridge) -> Result<EccCounts, BridgeError> {
+        Ok(EccCounts {
+            correctable: read_counter(bridge, &self.sec_errors)?,
+            uncorrectable: read_counter(bridge, &self.ded_errors)?,
+        })
+    }
+
+    /// Reset the counters, returning `false` if the controller can't.
+    pub fn clear(&self, bridge: &Bridge) -> Result<bool, BridgeError> {
+        match self.clear {
+            Some(addr) => bridge.poke(addr, 1).and(Ok(true)),
+            None => Ok(false),
+        }
+    }
+
+    /// Start a scrub, returning `false` if the controller doesn't scrub.
+    pub fn scrub(&self, bridge: &Bridge) -> Result<bool, BridgeError> {
+        match self.scrub {
+            Some(addr) => bridge.poke(addr, 1).and(Ok(true)),
+            None => Ok(false),
+        }
+    }
+
+    /// The names and addresses of the counters, for watching them.
+    pub fn counters(&self) -> Vec<(String, CsrRegister)> {
+        vec![
+            (self.sec_errors.name.clone(), self.sec_errors.clone()),
+            (self.ded_errors.name.clone(), self.ded_errors.clone()),
+        ]
+    }
+}
+
+/// How many times to re-read a multi-word register that keeps changing
+/// under us before settling for what we have
+const TEAR_RETRIES: u32 = 8;
+
+/// Read a register that may be split across several CSR words.  All of
+/// the words are read in one burst, and then read again: if anything but
+/// the least significant word moved in between, a carry may have landed
+/// halfway through and the value is torn, so it's read again.
+pub fn read_counter(bridge: &Bridge, reg: &CsrRegister) -> Result<u64, BridgeError> {
+    let read_words = || -> Result<Vec<u32>, BridgeError> {
+        let data = bridge.burst_read(reg.address, reg.words * 4)?;
+        Ok(data
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect())
+    };
+    let mut words = read_words()?;
+    if reg.words == 1 {
+        return Ok(reg.assemble(&words));
+    }
+    let high_words = |words: &[u32]| -> Vec<u64> {
+        words
+            .iter()
+            .enumerate()
+            .filter(|(word, _)| reg.significance(*word as u32) != 0)
+            .map(|(_, w)| *w as u64 & reg.word_mask())
+            .collect()
+    };
+    for _ in 0..TEAR_RETRIES {
+        let again = read_words()?;
+        if high_words(&words) == high_words(&again) {
+            return Ok(reg.assemble(&words));
+        }
+        words = again;
+    }
+    warn!("{} kept changing while it was read, so it may be torn", reg.name);
+    Ok(reg.assemble(&words))
+}
@@ -0,0 +1,91 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+const PT_LOAD: u32 = 1;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// The file doesn't start with the ELF magic number
+    NotElf,
+
+    /// This is an ELF file, but not a 32-bit little-endian one
+    Unsupported,
+
+    /// A header or segment points past the end of the file
+    Truncated,
+}
+
+impl ::std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        use ElfError::*;
+        match self {
+            NotElf => write!(f, "not an ELF file"),
+            Unsupported => write!(f, "only 32-bit little-endian ELF files are supported"),
+            Truncated => write!(f, "ELF file is truncated"),
+        }
+    }
+}
+
+/// A chunk of memory to be loaded, taken from a `PT_LOAD` program header.
+pub struct ElfSegment {
+    /// Physical (load) address of the segment
+    pub addr: u32,
+
+    /// Contents of the segment, including any zero-filled `.bss`
+    pub data: Vec<u8>,
+}
+
+pub struct ElfImage {
+    pub entry: u32,
+    pub segments: Vec<ElfSegment>,
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.len() >= 4 && &data[0..4] == b"\x7fELF"
+}
+
+impl ElfImage {
+    pub fn parse(data: &[u8]) -> Result<ElfImage, ElfError> {
+        if !is_elf(data) {
+            return Err(ElfError::NotElf);
+        }
+        if data.len() < 0x34 {
+            return Err(ElfError::Truncated);
+        }
+        // EI_CLASS must be ELFCLASS32, and EI_DATA must be ELFDATA2LSB
+        if data[4] != 1 || data[5] != 1 {
+            return Err(ElfError::Unsupported);
+        }
+
+        let entry = LittleEndian::read_u32(&data[0x18..]);
+        let phoff = LittleEndian::read_u32(&data[0x1c..]) as usize;
+        let phentsize = LittleEndian::read_u16(&data[0x2a..]) as usize;
+        let phnum = LittleEndian::read_u16(&data[0x2c..]) as usize;
+
+        let mut segments = vec![];
+        for idx in 0..phnum {
+            let start = phoff + idx * phentsize;
+            let ph = data.get(start..start + 0x20).ok_or(ElfError::Truncated)?;
+            if LittleEndian::read_u32(&ph[0x00..]) != PT_LOAD {
+                continue;
+            }
+            let offset = LittleEndian::read_u32(&ph[0x04..]) as usize;
+            let paddr = LittleEndian::read_u32(&ph[0x0c..]);
+            let filesz = LittleEndian::read_u32(&ph[0x10..]) as usize;
+            let memsz = LittleEndian::read_u32(&ph[0x14..]) as usize;
+            if memsz == 0 {
+                continue;
+            }
+
+            let mut contents = data
+                .get(offset..offset + filesz)
+                .ok_or(ElfError::Truncated)?
+                .to_vec();
+            contents.resize(memsz.max(filesz), 0);
+            segments.push(ElfSegment {
+                addr: paddr,
+                data: contents,
+            });
+        }
+        Ok(ElfImage { entry, segments })
+    }
+}
@@ -0,0 +1,111 @@
+//! Endurance runs for hardware qualification: write a run of RAM or SPI
+//! flash over and over with a rotating set of patterns, reading it back
+//! each time and counting the words that didn't stick.
+//!
+//! The patterns go all-zeroes, all-ones, the two checkerboards, each
+//! word's own address, and then a fresh random fill, so that stuck bits,
+//! shorted neighbours and address lines that alias all turn up.  For
+//! flash, every cycle is also an erase of each sector in the run.
+
+use crate::bridge::Bridge;
+use crate::spiflash::{FlashError, SpiFlash};
+
+use rand::prelude::*;
+
+/// What an endurance run cycles
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnduranceTarget {
+    Ram,
+    Flash,
+}
+
+impl EnduranceTarget {
+    pub fn from_string(item: &str) -> Option<EnduranceTarget> {
+        match item {
+            "ram" => Some(EnduranceTarget::Ram),
+            "flash" => Some(EnduranceTarget::Flash),
+            _ => None,
+        }
+    }
+}
+
+const PATTERNS: usize = 6;
+
+/// How much is written and read back at once, which is a whole number of
+/// flash sectors
+const CHUNK_SIZE: u32 = 64 * 1024;
+
+/// The name of the pattern that cycle `cycle` writes
+pub fn pattern_name(cycle: u64) -> &'static str {
+    match cycle as usize % PATTERNS {
+        0 => "zeroes",
+        1 => "ones",
+        2 => "checkerboard",
+        3 => "inverse checkerboard",
+        4 => "address",
+        _ => "random",
+    }
+}
+
+/// The words cycle `cycle` writes to the `len` bytes at `base`
+fn pattern(cycle: u64, base: u32, len: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(len as usize);
+    let mut rng = thread_rng();
+    for offset in (0..len).step_by(4) {
+        let word = match cycle as usize % PATTERNS {
+            0 => 0x0000_0000,
+            1 => 0xffff_ffff,
+            2 => 0xaaaa_aaaa,
+            3 => 0x5555_5555,
+            4 => base.wrapping_add(offset),
+            _ => rng.gen::<u32>(),
+        };
+        data.extend_from_slice(&word.to_le_bytes());
+    }
+    data.truncate(len as usize);
+    data
+}
+
+/// How many words of `observed` aren't what was `written`
+fn count_errors(written: &[u8], observed: &[u8]) -> u64 {
+    written
+        .chunks(4)
+        .zip(observed.chunks(4))
+        .filter(|(w, o)| w != o)
+        .count() as u64
+}
+
+/// Run cycle `cycle` over the `len` bytes at `base`, returning how many
+/// words came back wrong.  For flash, `base` is an offset into the flash.
+/// It goes a chunk at a time, so that a big RAM doesn't need as much
+/// memory on the host.
+pub fn run_cycle(
+    bridge: &Bridge,
+    target: EnduranceTarget,
+    spiflash: Option<&SpiFlash>,
+    cycle: u64,
+    base: u32,
+    len: u32,
+) -> Result<u64, FlashError> {
+    let mut errors = 0;
+    let mut offset = 0;
+    while offset < len {
+        let addr = base + offset;
+        let chunk_len = (len - offset).min(CHUNK_SIZE);
+        let data = pattern(cycle, addr, chunk_len);
+        let observed = match (target, spiflash) {
+            (EnduranceTarget::Flash, Some(spiflash)) => {
+                spiflash.write_unverified(bridge, addr, &data)?;
+                spiflash.read(bridge, addr, chunk_len)?
+            }
+            _ => {
+                bridge.burst_write(addr, &data)?;
+                bridge.flush()?;
+                bridge.burst_read(addr, chunk_len)?
+            }
+        };
+        errors += count_errors(&data, &observed);
+        offset += chunk_len;
+    }
+    Ok(errors)
+}
@@ -0,0 +1,326 @@
+//! Hand-made Etherbone packets, for bringing up an Etherbone core: send
+//! exactly the bytes in a file, and pick apart whatever comes back.
+//!
+//! A packet is a header followed by records.  LiteX's Etherbone core, which
+//! is what the Ethernet bridge talks to, pads the header out to eight bytes:
+//!
+//! ```text
+//!   +0  magic         0x4e 0x6f
+//!   +2  version/flags version in the top nibble, then NR, PR and PF
+//!   +3  sizes         address size in the top nibble, port size in the bottom
+//!   +4  padding
+//! ```
+//!
+//! Each record is a four-byte header (flags, byte enable, write count and
+//! read count), then a base address and that many values to write, then a
+//! base address for the replies and that many addresses to read.  The
+//! reply to a read is a record writing the values back to the reply
+//! address.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::time::Duration;
+
+const MAGIC: u16 = 0x4e6f;
+const HEADER_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 4;
+
+/// The size bits that mean 32-bit addresses or ports
+const SIZE_32: u8 = 0x4;
+
+/// How long to wait for the device to answer
+const REPLY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// Header flags
+const FLAG_NR: u8 = 1 << 2;
+const FLAG_PR: u8 = 1 << 1;
+const FLAG_PF: u8 = 1 << 0;
+
+/// A record whose writes all go to the same address
+const RECORD_WFF: u8 = 1 << 1;
+
+/// Record flags, by name
+const RECORD_FLAGS: [(u8, &str); 6] = [
+    (1 << 7, "bca"),
+    (1 << 6, "rca"),
+    (1 << 5, "rff"),
+    (1 << 3, "cyc"),
+    (1 << 2, "wca"),
+    (RECORD_WFF, "wff"),
+];
+
+#[derive(Debug)]
+pub enum EtherboneError {
+    IoError(io::Error),
+
+    /// Something in the hex file that isn't a byte
+    BadHex(usize /* line */, String),
+
+    /// There isn't enough of a packet to decode
+    BadPacket(String),
+}
+
+impl std::fmt::Display for EtherboneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use EtherboneError::*;
+        match self {
+            IoError(e) => write!(f, "io error: {}", e),
+            BadHex(line, s) => write!(f, "line {}: {:?} isn't hex bytes", line, s),
+            BadPacket(s) => write!(f, "not an etherbone packet: {}", s),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for EtherboneError {
+    fn from(e: io::Error) -> EtherboneError {
+        EtherboneError::IoError(e)
+    }
+}
+
+pub struct Record {
+    flags: u8,
+    byte_enable: u8,
+
+    /// The address the writes start at, and the values written
+    writes: Option<(u32, Vec<u32>)>,
+
+    /// The address the replies go to, and the addresses read
+    reads: Option<(u32, Vec<u32>)>,
+}
+
+pub struct Packet {
+    version: u8,
+    flags: u8,
+    addr_size: u8,
+    port_size: u8,
+    records: Vec<Record>,
+
+    /// Why decoding stopped short, if it did
+    problem: Option<String>,
+}
+
+/// Read a packet from a file of hex bytes.  Whitespace between bytes is
+/// optional, and a `#` starts a comment that runs to the end of the line.
+pub fn read_hex_file(file_name: &str) -> Result<Vec<u8>, EtherboneError> {
+    parse_hex(&fs::read_to_string(file_name)?)
+}
+
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, EtherboneError> {
+    let mut data = vec![];
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        for token in line.split_whitespace() {
+            let digits = token.trim_start_matches("0x");
+            let bad = || EtherboneError::BadHex(line_number + 1, token.to_owned());
+            if digits.len() % 2 != 0 {
+                return Err(bad());
+            }
+            for pair in digits.as_bytes().chunks(2) {
+                let pair = std::str::from_utf8(pair).map_err(|_| bad())?;
+                data.push(u8::from_str_radix(pair, 16).map_err(|_| bad())?);
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// The widths a size field allows, one bit each from 8 bits up
+fn widths(size: u8) -> String {
+    let widths: Vec<String> = (0..4)
+        .filter(|bit| size & (1 << bit) != 0)
+        .map(|bit| format!("{}", 8 << bit))
+        .collect();
+    match widths.len() {
+        0 => "no".to_owned(),
+        _ => format!("{}-bit", widths.join("/")),
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    let bytes: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+    bytes.join(" ")
+}
+
+impl Packet {
+    /// Decode as much of `data` as makes sense.  Only a packet without a
+    /// whole header is an error; anything wrong after that is kept as the
+    /// `problem`, so that the records before it still get shown.
+    pub fn parse(data: &[u8]) -> Result<Packet, EtherboneError> {
+        if data.len() < 4 {
+            return Err(EtherboneError::BadPacket(format!(
+                "{} bytes is too short for a header",
+                data.len()
+            )));
+        }
+        let magic = BigEndian::read_u16(&data[0..2]);
+        if magic != MAGIC {
+            return Err(EtherboneError::BadPacket(format!(
+                "the magic is {:04x} rather than {:04x}",
+                magic, MAGIC
+            )));
+        }
+        let mut packet = Packet {
+            version: data[2] >> 4,
+            flags: data[2] & 0xf,
+            addr_size: data[3] >> 4,
+            port_size: data[3] & 0xf,
+            records: vec![],
+            problem: None,
+        };
+        if packet.addr_size != SIZE_32 || packet.port_size != SIZE_32 {
+            packet.problem =
+                Some("records are only decoded with 32-bit addresses and ports".to_owned());
+            return Ok(packet);
+        }
+
+        let mut offset = HEADER_LEN.min(data.len());
+        while offset < data.len() {
+            match Self::parse_record(&data[offset..]) {
+                Ok((record, len)) => {
+                    packet.records.push(record);
+                    offset += len;
+                }
+                Err(problem) => {
+                    packet.problem =
+                        Some(format!("record {}: {}", packet.records.len(), problem));
+                    break;
+                }
+            }
+        }
+        Ok(packet)
+    }
+
+    /// Decode the record at the start of `data`, returning it and how many
+    /// bytes it took up.
+    fn parse_record(data: &[u8]) -> Result<(Record, usize), String> {
+        if data.len() < RECORD_HEADER_LEN {
+            return Err(format!("{} bytes left over after the last record", data.len()));
+        }
+        let wcount = data[2] as usize;
+        let rcount = data[3] as usize;
+        let words_needed = |count| if count > 0 { count + 1 } else { 0 };
+        let len = RECORD_HEADER_LEN + 4 * (words_needed(wcount) + words_needed(rcount));
+        if data.len() < len {
+            return Err(format!(
+                "{} writes and {} reads need {} bytes, but there are only {}",
+                wcount,
+                rcount,
+                len,
+                data.len()
+            ));
+        }
+        let words: Vec<u32> = data[RECORD_HEADER_LEN..len]
+            .chunks(4)
+            .map(BigEndian::read_u32)
+            .collect();
+        let (write_words, read_words) = words.split_at(words_needed(wcount));
+        let split = |words: &[u32]| {
+            words
+                .split_first()
+                .map(|(base, rest)| (*base, rest.to_vec()))
+        };
+        let record = Record {
+            flags: data[0],
+            byte_enable: data[1],
+            writes: split(write_words),
+            reads: split(read_words),
+        };
+        Ok((record, len))
+    }
+
+    /// Whether the device should answer this packet: it's a probe, or it
+    /// reads something.
+    pub fn expects_reply(&self) -> bool {
+        self.flags & FLAG_PF != 0 || self.records.iter().any(|r| r.reads.is_some())
+    }
+}
+
+impl std::fmt::Display for Packet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut flags = vec![];
+        for (bit, name) in &[(FLAG_NR, "no-reads"), (FLAG_PR, "probe-reply"), (FLAG_PF, "probe")] {
+            if self.flags & bit != 0 {
+                flags.push(*name);
+            }
+        }
+        writeln!(
+            f,
+            "header: version {}, flags [{}], {} addresses, {} ports",
+            self.version,
+            flags.join(" "),
+            widths(self.addr_size),
+            widths(self.port_size)
+        )?;
+        for (index, record) in self.records.iter().enumerate() {
+            let flags: Vec<&str> = RECORD_FLAGS
+                .iter()
+                .filter(|(bit, _)| record.flags & bit != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            writeln!(
+                f,
+                "record {}: flags [{}], byte enable {:02x}",
+                index,
+                flags.join(" "),
+                record.byte_enable
+            )?;
+            if let Some((base, values)) = &record.writes {
+                let stride = if record.flags & RECORD_WFF != 0 { 0 } else { 4 };
+                for (i, value) in values.iter().enumerate() {
+                    let addr = base.wrapping_add(stride * i as u32);
+                    writeln!(f, "  write {:08x} = {:08x}", addr, value)?;
+                }
+            }
+            if let Some((base, addrs)) = &record.reads {
+                for addr in addrs {
+                    writeln!(f, "  read  {:08x}", addr)?;
+                }
+                writeln!(f, "  replies go to {:08x}", base)?;
+            }
+        }
+        if let Some(problem) = &self.problem {
+            writeln!(f, "stopped decoding: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send `packet` to the Etherbone core at `host`, and wait for an answer
+/// if `wait` is set.  Like the Ethernet bridge, a UDP socket listens on
+/// the same port it sends to.
+pub fn exchange(
+    host: &str,
+    port: u16,
+    tcp: bool,
+    packet: &[u8],
+    wait: bool,
+) -> Result<Option<Vec<u8>>, EtherboneError> {
+    let mut buffer = [0; 65536];
+    let result = if tcp {
+        let mut stream = TcpStream::connect(format!("{}:{}", host, port))?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        stream.write_all(packet)?;
+        if !wait {
+            return Ok(None);
+        }
+        stream.read(&mut buffer)
+    } else {
+        let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))?;
+        socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        socket.send_to(packet, format!("{}:{}", host, port))?;
+        if !wait {
+            return Ok(None);
+        }
+        socket.recv_from(&mut buffer).map(|(len, _src)| len)
+    };
+    match result {
+        Ok(len) => Ok(Some(buffer[..len].to_vec())),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,139 @@
+//! Flash layouts, and the programming GDB's `load` asks for, on top of
+//! the flash controller in `spiflash`.
+
+use crate::bridge::Bridge;
+use crate::spiflash::{FlashError, SpiFlash};
+
+use log::info;
+
+/// A named region of flash, from a --flash-layout file
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Partition {
+    pub fn contains(&self, offset: u32) -> bool {
+        offset >= self.offset && offset - self.offset < self.size
+    }
+}
+
+/// Read a flash layout, which is a TOML file with one table per partition:
+///
+/// ```toml
+/// [partitions.bitstream]
+/// offset = 0x000000
+/// size = 0x040000
+/// ```
+///
+/// Only as much TOML as is needed for this is understood.
+pub fn parse_layout(text: &str) -> Result<Vec<Partition>, String> {
+    let mut partitions: Vec<Partition> = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let table = line[1..line.len() - 1].trim();
+            match table.strip_prefix("partitions.") {
+                Some(name) => partitions.push(Partition {
+                    name: name.trim().trim_matches('"').to_owned(),
+                    offset: 0,
+                    size: 0,
+                }),
+                None => return Err(format!("line {}: unknown table [{}]", idx + 1, table)),
+            }
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), line[pos + 1..].trim().replace('_', "")),
+            None => return Err(format!("line {}: expected key = value", idx + 1)),
+        };
+        let value = if let Some(hex) = value.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16)
+        } else {
+            value.parse::<u32>()
+        }
+        .map_err(|_| format!("line {}: {} is not a number", idx + 1, value))?;
+        let partition = match partitions.last_mut() {
+            Some(p) => p,
+            None => return Err(format!("line {}: {} is outside of a partition", idx + 1, key)),
+        };
+        match key {
+            "offset" => partition.offset = value,
+            "size" => partition.size = value,
+            _ => return Err(format!("line {}: unknown key {}", idx + 1, key)),
+        }
+    }
+
+    for p in &partitions {
+        if p.size == 0 {
+            return Err(format!("partition {} has no size", p.name));
+        }
+        if let Some(other) = partitions
+            .iter()
+            .find(|o| o.name != p.name && o.contains(p.offset))
+        {
+            return Err(format!("partition {} overlaps {}", p.name, other.name));
+        }
+    }
+    Ok(partitions)
+}
+
+/// Flash programming for GDB's `load`, which comes as `vFlashErase` for
+/// each run of sectors, then `vFlashWrite`s of a packet's worth each, then
+/// `vFlashDone`.  The writes are gathered up until then, so that pages are
+/// programmed whole rather than a piece at a time.
+pub struct FlashLoader {
+    spiflash: SpiFlash,
+
+    /// What's still to be programmed, as runs of bytes by flash offset
+    pending: Vec<(u32, Vec<u8>)>,
+}
+
+impl FlashLoader {
+    pub fn new(spiflash: SpiFlash) -> FlashLoader {
+        FlashLoader {
+            spiflash,
+            pending: vec![],
+        }
+    }
+
+    pub fn erase(&mut self, bridge: &Bridge, offset: u32, len: u32) -> Result<(), FlashError> {
+        self.spiflash.check_id(bridge)?;
+        info!("erasing flash from 0x{:06x} to 0x{:06x}", offset, offset + len);
+        self.spiflash.erase(bridge, offset, len)
+    }
+
+    /// Hold on to `data` for programming at `offset`, adding it to the end
+    /// of the last write if it follows on from it
+    pub fn write(&mut self, offset: u32, data: &[u8]) {
+        if let Some((start, run)) = self.pending.last_mut() {
+            if *start + run.len() as u32 == offset {
+                run.extend_from_slice(data);
+                return;
+            }
+        }
+        self.pending.push((offset, data.to_vec()));
+    }
+
+    /// Program everything that's been written, and read it back
+    pub fn done(&mut self, bridge: &Bridge) -> Result<(), FlashError> {
+        for (offset, data) in self.pending.drain(..) {
+            info!("programming flash from 0x{:06x} to 0x{:06x}", offset, offset + data.len() as u32);
+            self.spiflash.program(bridge, offset, &data)?;
+            let readback = self.spiflash.read(bridge, offset, data.len() as u32)?;
+            if let Some(idx) = readback.iter().zip(&data).position(|(a, b)| a != b) {
+                return Err(FlashError::VerifyFailed(offset + idx as u32));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Warnings for writes that are almost certainly a mistake, given what
+//! else is going on: resetting the SoC out from under a halted GDB
+//! session, or scribbling over the debug interface the GDB server is
+//! driving.  With --footguns block they're refused instead, and with
+//! --footguns queue they're held until GDB lets the CPU run again.
+//! --force lets them through regardless.
+//!
+//! The wishbone server has no way to tell a client that a write failed,
+//! so when one is refused it closes the connection rather than letting
+//! the client carry on as if the write had landed.
+//!
+//! Only writes made by another server in the same process as the GDB
+//! server can be queued, since that's the only place GDB's halting the
+//! CPU is known about.  A poke given on the command line runs in its own
+//! process, which never sees a halted CPU, so queue does nothing for it
+//! and it's only checked against the debug interface.
+//!
+//! With --read-only every write is refused, and --force doesn't change
+//! that.
+
+use crate::config::ConfigError;
+
+use log::{info, warn};
+
+use std::sync::{Arc, Condvar, Mutex};
+
+/// How much of the address space the CPU debug interface takes up, which
+/// is enough for the VexRiscv registers and breakpoints, or the Debug
+/// Module's registers
+const DEBUG_WINDOW: u32 = 0x200;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FootgunPolicy {
+    Off,
+    Warn,
+    Block,
+    Queue,
+}
+
+impl FootgunPolicy {
+    pub fn from_string(item: &str) -> Result<FootgunPolicy, ConfigError> {
+        match item {
+            "off" => Ok(FootgunPolicy::Off),
+            "warn" => Ok(FootgunPolicy::Warn),
+            "block" => Ok(FootgunPolicy::Block),
+            "queue" => Ok(FootgunPolicy::Queue),
+            _ => Err(ConfigError::InvalidConfig(format!(
+                "{} is not a footgun policy -- must be off, warn, block, or queue",
+                item
+            ))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FootgunGuard {
+    policy: FootgunPolicy,
+    reset_csr: Option<u32>,
+
+    /// Base of the CPU debug interface, if the GDB server is running
+    debug_base: Option<u32>,
+
+    /// Whether a GDB session currently has the CPU halted, which queued
+    /// writes wait on
+    gdb_halted: Arc<(Mutex<bool>, Condvar)>,
+
+    /// Refuse every write
+    read_only: bool,
+}
+
+impl FootgunGuard {
+    pub fn new(
+        policy: FootgunPolicy,
+        reset_csr: Option<u32>,
+        debug_base: Option<u32>,
+        read_only: bool,
+    ) -> FootgunGuard {
+        FootgunGuard {
+            policy,
+            reset_csr,
+            debug_base,
+            gdb_halted: Arc::new((Mutex::new(false), Condvar::new())),
+            read_only,
+        }
+    }
+
+    pub fn set_gdb_halted(&self, halted: bool) {
+        let (lock, resumed) = &*self.gdb_halted;
+        *lock.lock().unwrap() = halted;
+        if !halted {
+            resumed.notify_all();
+        }
+    }
+
+    fn gdb_halted(&self) -> bool {
+        *self.gdb_halted.0.lock().unwrap()
+    }
+
+    fn problem(&self, addr: u32) -> Option<&'static str> {
+        if Some(addr) == self.reset_csr && self.gdb_halted() {
+            return Some("resets the SoC while GDB has the CPU halted");
+        }
+        if let Some(base) = self.debug_base {
+            if addr >= base && addr - base < DEBUG_WINDOW {
+                return Some("is part of the CPU debug interface the GDB server is using");
+            }
+        }
+        None
+    }
+
+    /// Check a write to `addr` made on behalf of `who`, and say whether it
+    /// should go ahead.  Under --footguns queue this waits for GDB to let
+    /// the CPU run, so it mustn't be called from the GDB server itself.
+    pub fn allow_write(&self, who: &str, addr: u32) -> bool {
+        if self.policy == FootgunPolicy::Queue && !self.read_only && self.problem(addr).is_some() {
+            let (lock, resumed) = &*self.gdb_halted;
+            let mut halted = lock.lock().unwrap();
+            if *halted {
+                info!(
+                    "holding a write from {} to {:08x} until GDB resumes the CPU",
+                    who, addr
+                );
+                while *halted {
+                    halted = resumed.wait(halted).unwrap();
+                }
+            }
+        }
+        self.check(who, addr)
+    }
+
+    /// Like `allow_write`, but never waits, so queued writes are refused.
+    /// This is for the GDB server, which would otherwise wait on itself.
+    pub fn allow_write_now(&self, who: &str, addr: u32) -> bool {
+        self.check(who, addr)
+    }
+
+    fn check(&self, who: &str, addr: u32) -> bool {
+        if self.read_only {
+            warn!("refusing a write from {} to {:08x}, as this session is --read-only", who, addr);
+            return false;
+        }
+        if self.policy == FootgunPolicy::Off {
+            return true;
+        }
+        let problem = match self.problem(addr) {
+            Some(p) => p,
+            None => return true,
+        };
+        let refuse = match self.policy {
+            FootgunPolicy::Block => true,
+            // Writes that got here without waiting are refused while the CPU
+            // is halted, and only warned about once it's running
+            FootgunPolicy::Queue => self.gdb_halted(),
+            _ => false,
+        };
+        if refuse {
+            warn!(
+                "refusing a write from {} to {:08x}, which {} (see --footguns, or use --force)",
+                who, addr, problem
+            );
+            false
+        } else {
+            warn!("{} is writing to {:08x}, which {}", who, addr, problem);
+            true
+        }
+    }
+}
@@ -1,20 +1,80 @@
 extern crate byteorder;
+use std::collections::VecDeque;
 use std::io;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
 
 use super::bridge::{Bridge, BridgeError};
-use super::riscv::{RiscvCpu, RiscvCpuError};
-
-use log::{debug, error, info};
+use super::flash::FlashLoader;
+use super::spiflash::SpiFlash;
+use super::footgun::{FootgunGuard, FootgunPolicy};
+use super::config::CsrRegister;
+use super::linux::{self, LinuxOffsets, LinuxTask};
+use super::monitor::{MonitorCommand, MonitorContext, MonitorRegistry};
+use super::prefetch::Prefetcher;
+use super::regions::{self, MemoryRegion};
+use super::rsp::{Frame, Packet, PacketReader};
+use super::riscv::record::Recorder;
+use super::riscv::trigger::TriggerMatch;
+use super::riscv::{CpuState, RiscvSystem, RiscvCpuError, StopCause, Xlen};
+use super::trace;
+
+use log::{debug, error, info, warn};
 
 use crate::gdb::byteorder::ByteOrder;
 use byteorder::{BigEndian, NativeEndian};
 
-const SUPPORTED_QUERIES: &[u8] = b"PacketSize=3fff;qXfer:features:read+;qXfer:threads:read+;qXfer:memory-map:read-;QStartNoAckMode+;vContSupported+";
+/// GDB's register number for the PC on RISC-V
+const PC_REGNUM: u32 = 32;
+
+/// The largest packet GDB is told it may send, unless told otherwise
+const DEFAULT_PACKET_SIZE: usize = 0x3fff;
+
+const SUPPORTED_QUERIES: &str =
+    "qXfer:features:read+;qXfer:threads:read+;QStartNoAckMode+;vContSupported+;QNonStop+";
+
+/// Stop replies waiting for GDB in non-stop mode, where they go out as
+/// `%Stop` notifications.  Only the first has been sent: each `vStopped`
+/// from GDB takes it off and is answered with the next one, or with `OK`
+/// once there are none left.
+#[derive(Default)]
+struct StopQueue {
+    non_stop: bool,
+    pending: VecDeque<String>,
+}
+
+impl StopQueue {
+    /// Queue a stop reply, sending it straight away if GDB isn't already
+    /// working through the queue
+    fn report(&mut self, connection: &mut TcpStream, reply: &str) -> io::Result<()> {
+        self.pending.push_back(reply.to_owned());
+        if self.pending.len() > 1 {
+            return Ok(());
+        }
+        debug!(" > Notifying GDB of a stop: {}", reply);
+        let notification = Packet::new(format!("Stop:{}", reply).as_bytes()).encode_notification();
+        connection.write_all(&notification)
+    }
+
+    /// The reply to `vStopped`
+    fn next(&mut self) -> String {
+        self.pending.pop_front();
+        self.pending.front().cloned().unwrap_or_else(|| "OK".to_owned())
+    }
+
+    /// Start the queue again with `replies`, the first of which is sent
+    /// as the reply to `?`
+    fn restart(&mut self, replies: Vec<String>) -> String {
+        self.pending = replies.into();
+        self.pending.front().cloned().unwrap_or_else(|| "OK".to_owned())
+    }
+}
 
 pub struct GdbController {
     connection: TcpStream,
+    stops: Arc<Mutex<StopQueue>>,
+    last_sent: Arc<Mutex<Vec<u8>>>,
 }
 
 impl Write for GdbController {
@@ -28,30 +88,36 @@ impl Write for GdbController {
 
 impl GdbController {
     pub fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
-        let mut buffer = [0; 16388];
-        let mut checksum: u8 = 0;
-        buffer[0] = '$' as u8;
-        for i in 0..inp.len() {
-            buffer[i + 1] = inp[i];
-            checksum = checksum.wrapping_add(inp[i]);
-        }
-        let checksum_str = &format!("{:02x}", checksum);
-        let checksum_bytes = checksum_str.as_bytes();
-        buffer[inp.len() + 1] = '#' as u8;
-        buffer[inp.len() + 2] = checksum_bytes[0];
-        buffer[inp.len() + 3] = checksum_bytes[1];
-        let (to_write, _rest) = buffer.split_at(inp.len() + 4);
+        let to_write = Packet::new(inp).encode();
         debug!(
             " > Writing {} bytes: {}",
             to_write.len(),
             String::from_utf8_lossy(&to_write)
         );
-        self.connection.write(&to_write)?;
-        Ok(())
+        let mut last_sent = self.last_sent.lock().unwrap();
+        *last_sent = to_write;
+        self.connection.write_all(&last_sent)
     }
 
+    /// Tell GDB the CPU has stopped, which in non-stop mode is a
+    /// notification
+    pub fn report_stop(&mut self, reply: &str) -> io::Result<()> {
+        let mut stops = self.stops.lock().unwrap();
+        if stops.non_stop {
+            return stops.report(&mut self.connection, reply);
+        }
+        drop(stops);
+        self.gdb_send(reply.as_bytes())
+    }
 
+    /// Print `msg` on GDB's console.  `O` packets aren't allowed in
+    /// non-stop mode, where one could be taken for the reply to whatever
+    /// GDB asked last, so there it's only logged.
     pub fn print_string(&mut self, msg: &str) -> io::Result<()> {
+        if self.stops.lock().unwrap().non_stop {
+            info!("{}", msg.trim_end());
+            return Ok(());
+        }
         debug!("Printing string {} to GDB", msg);
         let mut strs: Vec<String> = msg
             .as_bytes()
@@ -66,9 +132,83 @@ impl GdbController {
 
 pub struct GdbServer {
     connection: TcpStream,
+    reader: PacketReader<TcpStream>,
     no_ack_mode: bool,
+
+    /// The last packet sent, framed, in case GDB asks for it again.  Stop
+    /// replies from the poll thread go through its controller, so it's
+    /// shared with that.
+    last_sent: Arc<Mutex<Vec<u8>>>,
     is_alive: bool,
     last_signal: u8,
+
+    /// If set, present the Linux kernel's tasks as threads
+    linux: Option<LinuxOffsets>,
+
+    /// Tasks found the last time GDB asked for the thread list
+    tasks: Vec<LinuxTask>,
+
+    /// The thread the CPU is actually running
+    current_thread: u64,
+
+    /// The thread GDB has selected with `Hg`
+    selected_thread: u64,
+
+    /// Regions that must only see aligned word accesses
+    regions: Vec<MemoryRegion>,
+
+    /// Read-ahead for memory reads, if enabled
+    prefetch: Option<Prefetcher>,
+
+    /// Checks for writes that would pull the rug out from under us
+    footguns: FootgunGuard,
+
+    /// How wide the registers in `g` and `G` packets are
+    xlen: Xlen,
+
+    /// The `PacketSize` offered in `qSupported`
+    packet_size: usize,
+
+    /// The CPU clock in Hz, for `clock` in `monitor calc`
+    clock: Option<u64>,
+
+    /// The steps taken so far, if they're being recorded so that GDB can
+    /// go back over them
+    recorder: Option<Recorder>,
+
+    /// Where the breakpoints are, which have to be checked for by hand
+    /// when continuing backwards
+    breakpoint_addresses: Vec<u32>,
+
+    /// Stops to report in non-stop mode, shared with the controller
+    stops: Arc<Mutex<StopQueue>>,
+
+    /// Programs the flash regions for `load`, if there's a flash core
+    flash: Option<FlashLoader>,
+
+    /// The `monitor` commands
+    monitor: MonitorRegistry,
+
+    /// The CSRs from csr.csv, for `monitor csr`
+    csrs: Vec<CsrRegister>,
+}
+
+/// The CRC that GDB uses for `qCRC`: CRC-32 with the usual polynomial,
+/// but shifted out most significant bit first and without the final
+/// inversion, to match libiberty's `xcrc32()`.
+fn gdb_crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xffff_ffff;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
 }
 
 fn swab(src: u32) -> u32 {
@@ -78,6 +218,28 @@ fn swab(src: u32) -> u32 {
         | (src >> 24) & 0x000000ff
 }
 
+/// Registers go over the wire as target-endian hex, which is little endian
+fn encode_register(value: u64, bytes: usize) -> String {
+    (0..bytes)
+        .map(|i| format!("{:02x}", (value >> (8 * i)) as u8))
+        .collect()
+}
+
+/// No register is wider than a u64, so anything longer is refused
+fn decode_register(value: &str) -> Result<u64, GdbServerError> {
+    if value.len() > 16 {
+        return Err(GdbServerError::ProtocolError);
+    }
+    let mut result = 0;
+    for (i, byte) in value.as_bytes().chunks(2).enumerate() {
+        let byte = String::from_utf8_lossy(byte);
+        let byte = u8::from_str_radix(&byte, 16)
+            .map_err(|e| GdbServerError::NumberParseError(byte.to_string(), e))?;
+        result |= (byte as u64) << (8 * i);
+    }
+    Ok(result)
+}
+
 pub fn parse_u32(value: &str) -> Result<u32, GdbServerError> {
     match u32::from_str_radix(value, 16) {
         Ok(o) => Ok(o),
@@ -92,18 +254,11 @@ pub fn parse_i32(value: &str) -> Result<i32, GdbServerError> {
     }
 }
 
-fn gdb_unescape(input: &[u8]) -> Vec<u8> {
-    let mut out = input.to_vec();
-    out.iter_mut().fold(&mut Vec::new(), |vec_acc, this_u8| {
-        if vec_acc.last() == Some(&('}' as u8)) {
-            let len = vec_acc.len();
-            vec_acc[len - 1] = *this_u8 ^ 0x20;
-        } else {
-            vec_acc.push(*this_u8);
-        }
-        vec_acc
-    });
-    out
+pub fn parse_i64(value: &str) -> Result<i64, GdbServerError> {
+    match i64::from_str_radix(value, 16) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(GdbServerError::NumberParseError(value.to_owned(), e)),
+    }
 }
 
 pub fn parse_u64(value: &str) -> Result<u64, GdbServerError> {
@@ -175,6 +330,65 @@ impl BreakPointType {
             c => Err(GdbServerError::UnknownBreakpointType(c.to_string())),
         }
     }
+
+    /// What a watchpoint's trigger has to match, or `None` for breakpoints
+    fn watch(&self) -> Option<TriggerMatch> {
+        match self {
+            BreakPointType::WatchWrite => Some(TriggerMatch::Write),
+            BreakPointType::WatchRead => Some(TriggerMatch::Read),
+            BreakPointType::WatchAccess => Some(TriggerMatch::Access),
+            _ => None,
+        }
+    }
+}
+
+/// What a vCont action does to the threads it applies to.  Signals are
+/// ignored, since there's nothing to deliver them to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VContKind {
+    Continue,
+    Step,
+    Stop,
+}
+
+/// One action of a vCont packet, for one thread or, without one, for
+/// every thread that an earlier action didn't name
+#[derive(Clone, Debug, PartialEq)]
+pub struct VContAction {
+    pub kind: VContKind,
+    pub thread: Option<u64>,
+}
+
+/// What a vCont tells `thread` to do: the first action that names it, or
+/// failing that the first that names no thread
+fn vcont_action(actions: &[VContAction], thread: u64) -> Option<VContKind> {
+    actions
+        .iter()
+        .find(|a| a.thread == Some(thread))
+        .or_else(|| actions.iter().find(|a| a.thread.is_none()))
+        .map(|a| a.kind)
+}
+
+/// Parse the actions after `vCont;`, or give None if there's one that
+/// isn't understood, such as a range step
+fn parse_vcont(actions: &str) -> Option<Vec<VContAction>> {
+    actions
+        .split(';')
+        .map(|action| {
+            let (action, thread) = match action.split_once(':') {
+                None => (action, None),
+                Some((action, "-1")) => (action, None),
+                Some((action, thread)) => (action, Some(u64::from_str_radix(thread, 16).ok()?)),
+            };
+            let kind = match action.as_bytes().first()? {
+                b'c' | b'C' => VContKind::Continue,
+                b's' | b'S' => VContKind::Step,
+                b't' => VContKind::Stop,
+                _ => return None,
+            };
+            Some(VContAction { kind, thread })
+        })
+        .collect()
 }
 
 #[derive(Debug, PartialEq)]
@@ -192,9 +406,21 @@ pub enum GdbCommand {
     /// QStartNoAckMode
     StartNoAckMode,
 
+    /// QNonStop:1
+    NonStop(bool),
+
+    /// vStopped
+    NextStop,
+
     /// D
     Disconnect,
 
+    /// !
+    ExtendedMode,
+
+    /// R##
+    Restart,
+
     /// Hg#
     SetCurrentThread(u64),
 
@@ -207,6 +433,15 @@ pub enum GdbCommand {
     /// qfThreadInfo
     GetThreadInfo,
 
+    /// qsThreadInfo
+    GetThreadInfoNext,
+
+    /// T#
+    ThreadAlive(u64),
+
+    /// qThreadExtraInfo,#
+    ThreadExtraInfo(u64),
+
     /// qC
     GetCurrentThreadId,
 
@@ -220,7 +455,10 @@ pub enum GdbCommand {
     GetRegister(u32),
 
     /// P#=#
-    SetRegister(u32, u32),
+    SetRegister(u32, u64),
+
+    /// G###...
+    SetRegisters(Vec<Option<u64>>),
 
     /// qSymbol::
     SymbolsReady,
@@ -238,14 +476,8 @@ pub enum GdbCommand {
     /// vCont?
     VContQuery,
 
-    /// vCont;c
-    VContContinue,
-
-    /// vCont;C04:0;c
-    VContContinueFromSignal(String),
-
-    /// vCont;s:0;c
-    VContStepFromSignal(String),
+    /// vCont;s:2;c
+    VCont(Vec<VContAction>),
 
     /// c
     Continue,
@@ -253,9 +485,19 @@ pub enum GdbCommand {
     /// s
     Step,
 
+    /// bs
+    ReverseStep,
+
+    /// bc
+    ReverseContinue,
+
     /// Ctrl-C
     Interrupt,
 
+    /// Fretcode,errno,C -- the reply to a File-I/O request, with C if the
+    /// user interrupted it
+    FileIoReply(i64, i32, bool),
+
     /// qRcmd,
     MonitorCommand(String),
 
@@ -276,6 +518,9 @@ pub enum GdbCommand {
     /// qOffsets
     GetOffsets,
 
+    /// qCRC:addr,length
+    Crc(u32 /* addr */, u32 /* length */),
+
     /// qXfer:memory-map:read::
     ReadMemoryMap(u32 /* offset */, u32 /* len */),
 
@@ -291,18 +536,103 @@ pub enum GdbCommand {
 
     /// qXfer:threads:read::0,1000
     ReadThreads(u32 /* offset */, u32 /* len */),
+
+    /// vFlashErase:addr,length
+    FlashErase(u32 /* addr */, u32 /* length */),
+
+    /// vFlashWrite:addr:XX...
+    FlashWrite(u32 /* addr */, Vec<u8>),
+
+    /// vFlashDone
+    FlashDone,
 }
 
 impl GdbServer {
     pub fn new(connection: TcpStream) -> Result<GdbServer, GdbServerError> {
         Ok(GdbServer {
+            reader: PacketReader::new(connection.try_clone()?),
             connection,
             no_ack_mode: false,
+            last_sent: Arc::new(Mutex::new(vec![])),
             is_alive: true,
             last_signal: 0,
+            linux: None,
+            tasks: vec![],
+            current_thread: 0,
+            selected_thread: 0,
+            regions: vec![],
+            prefetch: None,
+            footguns: FootgunGuard::new(FootgunPolicy::Off, None, None, false),
+            xlen: Xlen::Rv32,
+            packet_size: DEFAULT_PACKET_SIZE,
+            clock: None,
+            recorder: None,
+            breakpoint_addresses: vec![],
+            stops: Arc::new(Mutex::new(StopQueue::default())),
+            flash: None,
+            monitor: MonitorRegistry::default(),
+            csrs: vec![],
         })
     }
 
+    /// Let GDB send and ask for packets of up to `size` bytes, so that it
+    /// needs fewer of them to move a block of memory.
+    pub fn set_packet_size(&mut self, size: usize) {
+        self.packet_size = size;
+    }
+
+    pub fn set_clock_frequency(&mut self, clock: Option<u64>) {
+        self.clock = clock;
+    }
+
+    /// Record the last `steps` single steps, so that GDB can reverse-step
+    /// and reverse-continue through them
+    pub fn set_recording(&mut self, steps: Option<usize>) {
+        self.recorder = steps.map(Recorder::new);
+    }
+
+    /// Pack registers to the width of the CPU being debugged.
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
+    }
+
+    /// Widen and realign accesses that touch word-only regions, rather than
+    /// letting GDB issue byte accesses that the peripheral can't decode.
+    pub fn set_memory_regions(&mut self, regions: Vec<MemoryRegion>) {
+        self.regions = regions;
+    }
+
+    pub fn set_footguns(&mut self, footguns: FootgunGuard) {
+        self.footguns = footguns;
+    }
+
+    /// Program the flash regions of the memory map with `spiflash` when
+    /// GDB loads into them.  Without it, GDB can't write to flash.
+    pub fn set_flash(&mut self, spiflash: Option<SpiFlash>) {
+        self.flash = spiflash.map(FlashLoader::new);
+    }
+
+    pub fn set_csr_registers(&mut self, csrs: Vec<CsrRegister>) {
+        self.csrs = csrs;
+    }
+
+    /// Add a `monitor` command, which takes the place of any other by
+    /// the same name
+    pub fn register_monitor_command(&mut self, command: Box<dyn MonitorCommand>) {
+        self.monitor.register(command);
+    }
+
+    /// Read ahead of GDB in the ordinary memory of `regions`.
+    pub fn set_prefetch(&mut self, regions: &[MemoryRegion]) {
+        self.prefetch = Some(Prefetcher::new(regions));
+    }
+
+    /// Walk the Linux task list described by `offsets`, and present each
+    /// task to GDB as a thread.
+    pub fn set_linux_offsets(&mut self, offsets: Option<LinuxOffsets>) {
+        self.linux = offsets;
+    }
+
     fn packet_to_command(&self, raw_pkt: &[u8]) -> Result<GdbCommand, GdbServerError> {
         let pkt = String::from_utf8_lossy(raw_pkt).to_string();
         debug!("Raw GDB packet: {}", pkt);
@@ -311,27 +641,43 @@ impl GdbServer {
             Ok(GdbCommand::SupportedQueries(pkt))
         } else if pkt == "D" {
             Ok(GdbCommand::Disconnect)
+        } else if pkt == "!" {
+            Ok(GdbCommand::ExtendedMode)
+        } else if pkt.starts_with('R') {
+            // The argument is ignored, as it always was meant to be
+            Ok(GdbCommand::Restart)
         } else if pkt == "QStartNoAckMode" {
             Ok(GdbCommand::StartNoAckMode)
+        } else if pkt == "QNonStop:0" || pkt == "QNonStop:1" {
+            Ok(GdbCommand::NonStop(pkt.ends_with('1')))
+        } else if pkt == "vStopped" {
+            Ok(GdbCommand::NextStop)
         } else if pkt == "qAttached" {
             Ok(GdbCommand::CheckIsAttached)
         } else if pkt == "qOffsets" {
             Ok(GdbCommand::GetOffsets)
         } else if pkt == "qTStatus" {
             Ok(GdbCommand::TraceStatusQuery)
+        } else if pkt.starts_with("qCRC:") {
+            let pkt = pkt.trim_start_matches("qCRC:");
+            let fields: Vec<&str> = pkt.split(',').collect();
+            if fields.len() != 2 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            Ok(GdbCommand::Crc(parse_u32(fields[0])?, parse_u32(fields[1])?))
         } else if pkt.starts_with("qXfer:memory-map:read::") {
             let pkt = pkt.trim_start_matches("qXfer:memory-map:read::");
-            let offsets: Vec<&str> = pkt.split(',').collect();
-            let offset = parse_u32(offsets[0])?;
-            let len = parse_u32(offsets[1])?;
+            let (offset, len) = pkt.split_once(',').ok_or(GdbServerError::ProtocolError)?;
+            let offset = parse_u32(offset)?;
+            let len = parse_u32(len)?;
             Ok(GdbCommand::ReadMemoryMap(offset, len))
         } else if pkt.starts_with("qXfer:features:read:") {
             let pkt = pkt.trim_start_matches("qXfer:features:read:");
-            let fields: Vec<&str> = pkt.split(':').collect();
-            let offsets: Vec<&str> = fields[1].split(',').collect();
-            let offset = parse_u32(offsets[0])?;
-            let len = parse_u32(offsets[1])?;
-            Ok(GdbCommand::ReadFeature(fields[0].to_string(), offset, len))
+            let (annex, range) = pkt.rsplit_once(':').ok_or(GdbServerError::ProtocolError)?;
+            let (offset, len) = range.split_once(',').ok_or(GdbServerError::ProtocolError)?;
+            let offset = parse_u32(offset)?;
+            let len = parse_u32(len)?;
+            Ok(GdbCommand::ReadFeature(annex.to_string(), offset, len))
         } else if pkt.starts_with("qXfer:threads:read::") {
             let pkt = pkt.trim_start_matches("qXfer:threads:read::");
             let offsets: Vec<&str> = pkt.split(',').collect();
@@ -383,12 +729,29 @@ impl GdbServer {
             let pkt = pkt.trim_start_matches("P").to_string();
             let v: Vec<&str> = pkt.split('=').collect();
             let addr = parse_u32(v[0])?;
-            let value = swab(parse_u32(v[1])?);
+            let value = decode_register(v[1])?;
             Ok(GdbCommand::SetRegister(addr, value))
+        } else if pkt.starts_with("G") {
+            let pkt = pkt.trim_start_matches("G");
+            let mut values = vec![];
+            for chunk in pkt.as_bytes().chunks(self.xlen.bytes() * 2) {
+                let chunk = String::from_utf8_lossy(chunk);
+                // Registers GDB doesn't know are sent as x's
+                if chunk.starts_with('x') {
+                    values.push(None);
+                } else {
+                    values.push(Some(decode_register(&chunk)?));
+                }
+            }
+            Ok(GdbCommand::SetRegisters(values))
         } else if pkt == "c" {
             Ok(GdbCommand::Continue)
         } else if pkt == "s" {
             Ok(GdbCommand::Step)
+        } else if pkt == "bs" {
+            Ok(GdbCommand::ReverseStep)
+        } else if pkt == "bc" {
+            Ok(GdbCommand::ReverseContinue)
         } else if pkt.starts_with("m") {
             let pkt = pkt.trim_start_matches("m").to_string();
             let v: Vec<&str> = pkt.split(',').collect();
@@ -431,7 +794,6 @@ impl GdbServer {
 
             let mut values = vec![];
             if let Some((_delimiter, bin_data)) = bin_data_plus {
-                let bin_data = gdb_unescape(bin_data);
                 for value in bin_data.chunks_exact(4) {
                     values.push(swab(BigEndian::read_u32(&value)));
                 }
@@ -464,18 +826,48 @@ impl GdbServer {
             Ok(GdbCommand::LastSignalPacket)
         } else if pkt == "qfThreadInfo" {
             Ok(GdbCommand::GetThreadInfo)
+        } else if pkt == "qsThreadInfo" {
+            Ok(GdbCommand::GetThreadInfoNext)
+        } else if pkt.starts_with("qThreadExtraInfo,") {
+            Ok(GdbCommand::ThreadExtraInfo(parse_u64(
+                pkt.trim_start_matches("qThreadExtraInfo,"),
+            )?))
+        } else if let Some(id) = pkt
+            .strip_prefix('T')
+            .filter(|id| !id.is_empty() && id.bytes().all(|c| c.is_ascii_hexdigit()))
+        {
+            Ok(GdbCommand::ThreadAlive(parse_u64(id)?))
         } else if pkt == "vCont?" {
             Ok(GdbCommand::VContQuery)
-        } else if pkt == "vCont;c" {
-            Ok(GdbCommand::VContContinue)
-        } else if pkt.starts_with("vCont;C") {
-            //vCont;C04:0;c
-            let pkt = pkt.trim_start_matches("vCont;C").to_string();
-            // let v: Vec<&str> = pkt.split(',').collect();
-            Ok(GdbCommand::VContContinueFromSignal(pkt))
-        } else if pkt.starts_with("vCont;s") {
-            let pkt = pkt.trim_start_matches("vCont;s").to_string();
-            Ok(GdbCommand::VContStepFromSignal(pkt))
+        } else if pkt.starts_with("vCont;") {
+            match parse_vcont(pkt.trim_start_matches("vCont;")) {
+                Some(actions) => Ok(GdbCommand::VCont(actions)),
+                None => {
+                    info!("unsupported vCont actions: {}", pkt);
+                    Ok(GdbCommand::Unknown(pkt))
+                }
+            }
+        } else if let Some(reply) = pkt.strip_prefix('F') {
+            // Anything after a `;` is an attachment, which no call uses
+            let reply = reply.split(';').next().unwrap_or("");
+            let fields: Vec<&str> = reply.split(',').collect();
+            let retcode = parse_i64(fields[0])?;
+            let errno = match fields.get(1) {
+                Some(errno) => parse_i32(errno)?,
+                None => 0,
+            };
+            Ok(GdbCommand::FileIoReply(retcode, errno, fields.get(2) == Some(&"C")))
+        } else if let Some(range) = pkt.strip_prefix("vFlashErase:") {
+            let (addr, len) = range.split_once(',').ok_or(GdbServerError::ProtocolError)?;
+            Ok(GdbCommand::FlashErase(parse_u32(addr)?, parse_u32(len)?))
+        } else if raw_pkt.starts_with(b"vFlashWrite:") {
+            // The data is binary, so only the address is taken as text
+            let rest = &raw_pkt[b"vFlashWrite:".len()..];
+            let colon = rest.iter().position(|&c| c == b':').ok_or(GdbServerError::ProtocolError)?;
+            let addr = parse_u32(&String::from_utf8_lossy(&rest[..colon]))?;
+            Ok(GdbCommand::FlashWrite(addr, rest[colon + 1..].to_vec()))
+        } else if pkt == "vFlashDone" {
+            Ok(GdbCommand::FlashDone)
         } else if pkt == "qSymbol::" {
             Ok(GdbCommand::SymbolsReady)
         } else if pkt == "vMustReplyEmpty" {
@@ -489,6 +881,8 @@ impl GdbServer {
     pub fn get_controller(&self) -> GdbController {
         GdbController {
             connection: self.connection.try_clone().unwrap(),
+            stops: self.stops.clone(),
+            last_sent: self.last_sent.clone(),
         }
     }
 
@@ -499,59 +893,32 @@ impl GdbServer {
     }
 
     fn do_get_command(&mut self) -> Result<GdbCommand, GdbServerError> {
-        let mut buffer = [0; 16384];
-        let mut byte = [0; 1];
-        let mut remote_checksum = [0; 2];
-        let mut buffer_offset = 0;
-
-        // XXX Replace this with a BufReader for performance
         loop {
-            let len = self.connection.read(&mut byte)?;
-            if len == 0 {
-                return Err(GdbServerError::ConnectionClosed);
-            }
-
-            match byte[0] {
-                0x24 /*'$'*/ => {
-                    let mut checksum: u8 = 0;
-                    loop {
-                        let len = self.connection.read(&mut byte)?;
-                        if len == 0 {
-                            return Err(GdbServerError::ConnectionClosed);
-                        }
-                        match byte[0] as char {
-                            '#' => {
-                                // There's got to be a better way to compare the checksum
-                                self.connection.read(&mut remote_checksum)?;
-                                let checksum_str = format!("{:02x}", checksum);
-                                if checksum_str != String::from_utf8_lossy(&remote_checksum) {
-                                    info!(
-                                        "Checksum mismatch: Calculated {:?} vs {}",
-                                        checksum_str,
-                                        String::from_utf8_lossy(&remote_checksum)
-                                    );
-                                    self.gdb_send_nak()?;
-                                } else {
-                                    if !self.no_ack_mode {
-                                        self.gdb_send_ack()?;
-                                    }
-                                }
-                                let (buffer, _remainder) = buffer.split_at(buffer_offset);
-                                // debug!("<  Read packet ${:?}#{:#?}", String::from_utf8_lossy(buffer), String::from_utf8_lossy(&remote_checksum));
-                                return self.packet_to_command(&buffer);
-                            }
-                            other => {
-                                buffer[buffer_offset] = other as u8;
-                                buffer_offset = buffer_offset + 1;
-                                checksum = checksum.wrapping_add(other as u8);
-                            }
-                        }
+            match self.reader.read()? {
+                None => return Err(GdbServerError::ConnectionClosed),
+                Some(Frame::Packet(packet)) => {
+                    if !self.no_ack_mode {
+                        self.gdb_send_ack()?;
+                    }
+                    return self.packet_to_command(packet.data());
+                }
+                // Over TCP this shouldn't happen, and without acks there's
+                // no asking for it again
+                Some(Frame::Corrupt) => {
+                    info!("packet from GDB had a bad checksum");
+                    if !self.no_ack_mode {
+                        self.gdb_send_nak()?;
+                    }
+                }
+                Some(Frame::Nak) if !self.no_ack_mode => {
+                    let last_sent = self.last_sent.lock().unwrap();
+                    if !last_sent.is_empty() {
+                        debug!("GDB asked for the last packet again");
+                        self.connection.write_all(&last_sent)?;
                     }
                 }
-                0x2b /*'+'*/ => {}
-                0x2d /*'-'*/ => {}
-                0x3 => return Ok(GdbCommand::Interrupt),
-                other => error!("Warning: unrecognied byte received: {}", other),
+                Some(Frame::Ack) | Some(Frame::Nak) => (),
+                Some(Frame::Interrupt) => return Ok(GdbCommand::Interrupt),
             }
         }
     }
@@ -559,20 +926,107 @@ impl GdbServer {
     pub fn process(
         &mut self,
         cmd: GdbCommand,
-        cpu: &RiscvCpu,
+        cpu: &RiscvSystem,
         bridge: &Bridge,
     ) -> Result<(), GdbServerError> {
+        // Name the span after the command, leaving out its arguments
+        let _span = trace::span_with("gdb", || {
+            let name = format!("{:?}", cmd);
+            name.split(['(', ' ']).next().unwrap_or("").to_owned()
+        });
+        // Anything but another read may change memory under the cache
+        if let Some(prefetch) = &mut self.prefetch {
+            match cmd {
+                GdbCommand::ReadMemory(_, _) | GdbCommand::GetRegisters | GdbCommand::GetRegister(_) => (),
+                _ => prefetch.invalidate(),
+            }
+        }
         match cmd {
-            GdbCommand::SupportedQueries(_) => self.gdb_send(SUPPORTED_QUERIES)?,
+            GdbCommand::SupportedQueries(_) => {
+                let mut reply = format!("PacketSize={:x};{}", self.packet_size, SUPPORTED_QUERIES);
+                if cpu.has_memory_map() {
+                    reply.push_str(";qXfer:memory-map:read+");
+                }
+                if self.recorder.is_some() {
+                    reply.push_str(";ReverseStep+;ReverseContinue+");
+                }
+                self.gdb_send(reply.as_bytes())?
+            }
             GdbCommand::StartNoAckMode => {
                 self.no_ack_mode = true;
                 self.gdb_send(b"OK")?
             }
-            GdbCommand::SetCurrentThread(_) => self.gdb_send(b"OK")?,
+            // Linux threads share the one CPU, so can't stop by themselves
+            GdbCommand::NonStop(true) if self.linux.is_some() => self.gdb_send(b"E01")?,
+            // A File-I/O request has to be the reply to whatever got the
+            // CPU going, which non-stop mode doesn't have
+            GdbCommand::NonStop(true) if cpu.file_io() => {
+                warn!("GDB asked for non-stop mode, which --gdb-fileio can't work with");
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::NonStop(on) => {
+                cpu.set_non_stop(bridge, on)?;
+                let mut stops = self.stops.lock().unwrap();
+                stops.non_stop = on;
+                stops.pending.clear();
+                drop(stops);
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::NextStop => {
+                let reply = self.stops.lock().unwrap().next();
+                self.gdb_send(reply.as_bytes())?
+            }
+            // Without Linux threads, each thread is a hart and selecting
+            // one sends everything after to it.  Zero means any thread.
+            GdbCommand::SetCurrentThread(thread) if self.linux.is_none() && thread > 0 => {
+                match cpu.select_hart(bridge, thread as u32 - 1) {
+                    Ok(()) => self.gdb_send(b"OK")?,
+                    Err(RiscvCpuError::NoSuchHart(_)) => self.gdb_send(b"E01")?,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            GdbCommand::SetCurrentThread(thread) => {
+                self.selected_thread = thread;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::ContinueThread(thread) if self.linux.is_none() && thread > 0 => {
+                match cpu.select_hart(bridge, thread as u32 - 1) {
+                    Ok(()) => self.gdb_send(b"OK")?,
+                    Err(RiscvCpuError::NoSuchHart(_)) => self.gdb_send(b"E01")?,
+                    Err(e) => return Err(e.into()),
+                }
+            }
             GdbCommand::ContinueThread(_) => self.gdb_send(b"OK")?,
-            GdbCommand::AddBreakpoint(_bptype, address, _size) => {
-                let response = match cpu.add_breakpoint(bridge, address) {
-                    Ok(_) => "OK",
+            // An empty reply tells GDB the CPU can't do that kind at all,
+            // rather than that it failed this time
+            GdbCommand::AddBreakpoint(bptype, _, _)
+                if (bptype.watch().is_some() && !cpu.has_watchpoints())
+                    || (bptype == BreakPointType::BreakHard && cpu.hardware_breakpoints().1 == 0) =>
+            {
+                self.gdb_send(b"")?
+            }
+            GdbCommand::AddBreakpoint(bptype, address, size) => {
+                let is_breakpoint = bptype.watch().is_none();
+                // Memory that can't be patched, such as flash, gets a
+                // hardware breakpoint instead
+                let result = match bptype {
+                    BreakPointType::BreakSoft => match cpu.add_soft_breakpoint(bridge, address) {
+                        Err(RiscvCpuError::BreakpointNotWritable(_)) => {
+                            debug!("couldn't patch {:08x}, using a hardware breakpoint", address);
+                            cpu.add_breakpoint(bridge, address)
+                        }
+                        other => other,
+                    },
+                    BreakPointType::BreakHard => cpu.add_breakpoint(bridge, address),
+                    watch => cpu.add_watchpoint(bridge, address, size, watch.watch().unwrap()),
+                };
+                let response = match result {
+                    Ok(_) => {
+                        if is_breakpoint {
+                            self.breakpoint_addresses.push(address);
+                        }
+                        "OK"
+                    }
                     Err(RiscvCpuError::BreakpointExhausted) => {
                         error!("No available breakpoint found");
                         "E0E"
@@ -588,36 +1042,155 @@ impl GdbServer {
                 self.gdb_send(response.as_bytes())?;
             }
             GdbCommand::TraceStatusQuery => self.gdb_send(b"")?,
-            GdbCommand::RemoveBreakpoint(_bptype, address, _size) => {
-                cpu.remove_breakpoint(bridge, address)?;
+            GdbCommand::RemoveBreakpoint(bptype, address, _size) => {
+                if let Some(kind) = bptype.watch() {
+                    cpu.remove_watchpoint(bridge, address, kind)?;
+                } else if bptype == BreakPointType::BreakSoft
+                    && cpu.has_soft_breakpoint(bridge, address)?
+                {
+                    cpu.remove_soft_breakpoint(bridge, address)?;
+                } else {
+                    cpu.remove_breakpoint(bridge, address)?;
+                }
+                if bptype.watch().is_none() {
+                    if let Some(pos) = self.breakpoint_addresses.iter().position(|a| *a == address) {
+                        self.breakpoint_addresses.remove(pos);
+                    }
+                }
                 self.gdb_send(b"OK")?
             }
+            // Every stopped hart, one after another through `vStopped`
+            GdbCommand::LastSignalPacket if self.non_stop() => {
+                let replies = (0..cpu.harts())
+                    .filter(|hart| cpu.hart_stopped(*hart))
+                    .map(|hart| self.hart_stop_reply(cpu, hart, 0))
+                    .collect();
+                let reply = self.stops.lock().unwrap().restart(replies);
+                self.gdb_send(reply.as_bytes())?
+            }
             GdbCommand::LastSignalPacket => {
-                let sig_str = format!("S{:02x}", self.last_signal);
-                self.gdb_send(if self.is_alive {
-                    sig_str.as_bytes()
-                } else {
-                    b"W00"
-                })?
+                // A halt GDB asked for itself, including the one when it
+                // attached, is reported the way GDB already knows it
+                let reply = match cpu.poll_state(bridge)? {
+                    _ if !self.is_alive => "W00".to_owned(),
+                    CpuState::Halted { cause } if cause != StopCause::HaltRequest => {
+                        self.last_signal = cause.signal();
+                        cause.stop_reply()
+                    }
+                    _ => format!("S{:02x}", self.last_signal),
+                };
+                self.gdb_send(reply.as_bytes())?
+            }
+            GdbCommand::GetThreadInfo if self.linux.is_some() => {
+                self.refresh_threads(cpu, bridge)?;
+                let ids: Vec<String> = self
+                    .thread_ids()
+                    .iter()
+                    .map(|id| format!("{:x}", id))
+                    .collect();
+                self.gdb_send(format!("m{}", ids.join(",")).as_bytes())?
+            }
+            GdbCommand::GetThreadInfo if cpu.harts() > 1 => {
+                let ids: Vec<String> = (1..=cpu.harts()).map(|id| format!("{:x}", id)).collect();
+                self.gdb_send(format!("m{}", ids.join(",")).as_bytes())?
             }
             GdbCommand::GetThreadInfo => self.gdb_send(b"l")?,
+            GdbCommand::GetThreadInfoNext => self.gdb_send(b"l")?,
+            GdbCommand::GetCurrentThreadId if self.linux.is_some() => {
+                self.refresh_threads(cpu, bridge)?;
+                self.gdb_send(format!("QC{:x}", self.current_thread).as_bytes())?
+            }
+            GdbCommand::GetCurrentThreadId if cpu.harts() > 1 => {
+                self.gdb_send(format!("QC{:x}", cpu.hart() + 1).as_bytes())?
+            }
             GdbCommand::GetCurrentThreadId => self.gdb_send(b"QC0")?,
+            GdbCommand::ThreadAlive(thread) => {
+                let alive = match self.linux {
+                    Some(_) => self.thread_ids().contains(&thread),
+                    None => cpu.harts() == 1 || (1..=cpu.harts() as u64).contains(&thread),
+                };
+                if alive {
+                    self.gdb_send(b"OK")?
+                } else {
+                    self.gdb_send(b"E01")?
+                }
+            }
+            GdbCommand::ThreadExtraInfo(thread) => {
+                let info = match self.tasks.iter().find(|t| t.address as u64 == thread) {
+                    Some(task) => format!("pid {}", task.pid),
+                    None => "cpu".to_owned(),
+                };
+                let encoded: Vec<String> = info.bytes().map(|b| format!("{:02x}", b)).collect();
+                self.gdb_send(encoded.join("").as_bytes())?
+            }
             GdbCommand::CheckIsAttached => self.gdb_send(b"1")?,
             GdbCommand::Disconnect => {
+                cpu.clear_soft_breakpoints(bridge)?;
+                // Leaving non-stop mode halts every hart, so that they can
+                // all be let go together
+                if self.non_stop() {
+                    cpu.set_non_stop(bridge, false)?;
+                }
                 cpu.resume(bridge)?;
                 self.gdb_send("OK".as_bytes())?
             }
+            // Extended mode is only wanted for `R`, which GDB won't send
+            // otherwise
+            GdbCommand::ExtendedMode => self.gdb_send(b"OK")?,
+            // There's no reply to a restart.  GDB asks where the CPU is
+            // with `?` afterwards.
+            GdbCommand::Restart => {
+                self.forget_recording();
+                match cpu.reset(bridge, true) {
+                    Ok(_) | Err(RiscvCpuError::ResetVectorMismatch(_, _)) => (),
+                    Err(e) => return Err(e.into()),
+                }
+                self.last_signal = 5;
+            }
+            GdbCommand::GetRegisters if self.selected_task().is_some() => {
+                let task = self.selected_task().unwrap();
+                let offsets = self.linux.clone().unwrap();
+                let mut register_list = String::new();
+                for i in cpu.all_cpu_registers() {
+                    let bytes = cpu.register_bytes(i);
+                    match offsets.saved_register(cpu, bridge, task, i)? {
+                        Some(val) => register_list.push_str(&encode_register(val as u64, bytes)),
+                        None => register_list.push_str(&"xx".repeat(bytes)),
+                    }
+                }
+                self.gdb_send(register_list.as_bytes())?
+            }
+            GdbCommand::GetRegister(reg) if self.selected_task().is_some() => {
+                let task = self.selected_task().unwrap();
+                let offsets = self.linux.clone().unwrap();
+                let bytes = cpu.register_bytes(reg);
+                let response = match offsets.saved_register(cpu, bridge, task, reg) {
+                    Ok(Some(val)) => encode_register(val as u64, bytes),
+                    Ok(None) => "xx".repeat(bytes),
+                    Err(e) => {
+                        error!("Error reading saved register: {}", e);
+                        "E01".to_owned()
+                    }
+                };
+                self.gdb_send(response.as_bytes())?
+            }
+            // Only the running thread's registers can be changed
+            GdbCommand::SetRegister(_, _) | GdbCommand::SetRegisters(_)
+                if self.selected_task().is_some() =>
+            {
+                self.gdb_send(b"E01")?
+            }
             GdbCommand::GetRegisters => {
                 let mut register_list = String::new();
                 for i in cpu.all_cpu_registers() {
-                    register_list
-                        .push_str(format!("{:08x}", swab(cpu.read_register(bridge, i)?)).as_str());
+                    let value = cpu.read_register_wide(bridge, i)?;
+                    register_list.push_str(&encode_register(value, cpu.register_bytes(i)));
                 }
                 self.gdb_send(register_list.as_bytes())?
             }
             GdbCommand::GetRegister(reg) => {
-                let response = match cpu.read_register(bridge, reg) {
-                    Ok(val) => format!("{:08x}", swab(val)),
+                let response = match cpu.read_register_wide(bridge, reg) {
+                    Ok(val) => encode_register(val, cpu.register_bytes(reg)),
                     Err(e) => {
                         error!("Error reading register: {}", e);
                         format!("E01")
@@ -626,15 +1199,79 @@ impl GdbServer {
                 self.gdb_send(response.as_bytes())?
             }
             GdbCommand::SetRegister(reg, val) => {
-                let response = match cpu.write_register(bridge, reg, val) {
+                let response = match cpu.write_register_wide(bridge, reg, val) {
+                    Ok(_) => "OK",
+                    Err(_) => "E01",
+                };
+                self.gdb_send(response.as_bytes())?
+            }
+            GdbCommand::SetRegisters(values) => {
+                let response = match cpu.write_registers(bridge, &values) {
                     Ok(()) => "OK",
                     Err(_) => "E01",
                 };
                 self.gdb_send(response.as_bytes())?
             }
             GdbCommand::SymbolsReady => self.gdb_send(b"OK")?,
+            GdbCommand::Crc(addr, len) => {
+                let response = match self.read_for_crc(cpu, bridge, addr, len) {
+                    Ok(data) => format!("C{:08x}", gdb_crc32(&data)),
+                    Err(e) => {
+                        error!("couldn't read {} bytes at {:08x} for qCRC: {}", len, addr, e);
+                        "E01".to_owned()
+                    }
+                };
+                self.gdb_send(response.as_bytes())?
+            }
+            // Report unmapped virtual addresses back to GDB as EFAULT
+            GdbCommand::ReadMemory(addr, len) | GdbCommand::WriteMemory(addr, len, _)
+                if !cpu.is_mapped(bridge, addr, len)? =>
+            {
+                self.gdb_send(b"E0e")?
+            }
+            GdbCommand::WriteMemory(addr, _, _) if !self.footguns.allow_write_now("GDB", addr) => {
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::ReadMemory(addr, len) if regions::needs_words(&self.regions, addr, len) => {
+                debug!("Reading memory {:08x} as aligned words", addr);
+                let data = regions::read_bytes(addr, len, |a| cpu.read_memory(bridge, a, 4))?;
+                let out_str: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+                self.gdb_send(out_str.as_bytes())?
+            }
+            GdbCommand::WriteMemory(addr, len, values)
+                if regions::needs_words(&self.regions, addr, len) =>
+            {
+                debug!("Writing memory {:08x} as aligned words", addr);
+                // Values hold memory-order bytes, with a trailing partial
+                // word right-aligned the way the M and X parsers leave it.
+                let mut data = vec![];
+                let mut remaining = len as usize;
+                for value in values {
+                    let bytes = value.to_le_bytes();
+                    let count = remaining.min(4);
+                    data.extend_from_slice(&bytes[4 - count..]);
+                    remaining -= count;
+                }
+                regions::write_bytes(
+                    addr,
+                    &data,
+                    |a| cpu.read_memory(bridge, a, 4),
+                    |a, v| cpu.write_memory(bridge, a, 4, v),
+                )?;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::ReadMemory(addr, len)
+                if !cpu.paging_enabled()
+                    && self.prefetch.as_ref().is_some_and(|p| p.covers(addr, len)) =>
+            {
+                debug!("Reading memory {:08x} through the prefetcher", addr);
+                // unwrap() is safe because of the guard above
+                let values = self.prefetch.as_mut().unwrap().read(bridge, addr, len)?;
+                self.gdb_send_u32(values)?
+            }
             GdbCommand::ReadMemory(addr, len) => {
                 debug!("Reading memory {:08x}", addr);
+                bridge.warn_if_lengthy("GDB is reading", len);
                 let mut values = vec![];
 
                 let mut out_str = String::new();
@@ -680,65 +1317,101 @@ impl GdbServer {
                 }
                 self.gdb_send("OK".as_bytes())?
             }
-            GdbCommand::VContQuery => self.gdb_send(b"vCont;c;C;s;S")?,
-            GdbCommand::VContContinue => {
-                if let Some(s) = cpu.resume(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
-                }
-            }
-            GdbCommand::VContContinueFromSignal(_) => {
-                if let Some(s) = cpu.resume(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
-                }
-            }
-            GdbCommand::VContStepFromSignal(_) => {
-                if let Some(s) = cpu.step(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?;
-                }
-                self.last_signal = 5;
-                self.gdb_send(format!("S{:02x}", self.last_signal).as_bytes())?;
+            GdbCommand::VContQuery => self.gdb_send(b"vCont;c;C;s;S;t")?,
+            GdbCommand::VCont(actions) if self.non_stop() => self.vcont_non_stop(cpu, bridge, &actions)?,
+            GdbCommand::VCont(actions) => self.vcont(cpu, bridge, &actions)?,
+            GdbCommand::Step => self.step(cpu, bridge)?,
+            GdbCommand::ReverseStep | GdbCommand::ReverseContinue if self.recorder.is_none() => {
+                self.gdb_send(b"E01")?
             }
+            GdbCommand::ReverseStep => self.reverse(cpu, bridge, false)?,
+            GdbCommand::ReverseContinue => self.reverse(cpu, bridge, true)?,
             GdbCommand::GetOffsets => self.gdb_send(b"Text=0;Data=0;Bss=0")?,
             GdbCommand::Continue => {
+                self.forget_recording();
                 if let Some(s) = cpu.resume(bridge)? {
                     self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
                 }
             }
-            GdbCommand::Step => {
-                if let Some(s) = cpu.step(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
-                }
-            }
             GdbCommand::MonitorCommand(cmd) => {
-                match cmd.as_str() {
-                    "reset" => {
-                        self.print_string("Resetting CPU...\n")?;
-                        cpu.reset(&bridge)?;
-                    }
-                    "about" => {
-                        self.print_string("VexRiscv GDB bridge\n")?;
-                    }
-                    "explain" => {
-                        self.print_string(&cpu.explain(&bridge)?)?;
-                    }
-                    _ => {
-                        self.print_string("Unrecognized monitor command.  Available commands:\n")?;
-                        self.print_string("    about           - Information about the bridge\n")?;
-                        self.print_string("    explain         - Explain what the CPU is doing\n")?;
-                        self.print_string("    reset           - Reset the CPU\n")?;
-                    }
+                let mut ctx = MonitorContext::new(cpu, bridge, &self.csrs, &self.footguns, self.clock, &mut self.recorder);
+                self.monitor.run(&mut ctx, &cmd)?;
+                let output = ctx.into_output();
+                if !output.is_empty() {
+                    self.print_string(&output)?;
                 }
                 self.gdb_send(b"OK")?
             }
             GdbCommand::ReadFeature(filename, offset, len) => {
                 self.gdb_send_file(cpu.get_feature(&filename)?, offset, len)?
             }
-            GdbCommand::ReadMemoryMap(_offset, _len) => {
-                // self.gdb_send_file(cpu.get_memory_map()?, offset, len)?
-                self.gdb_send(b"")?
+            GdbCommand::ReadMemoryMap(offset, len) if cpu.has_memory_map() => {
+                self.gdb_send_file(cpu.get_feature("memory-map.xml")?, offset, len)?
+            }
+            GdbCommand::ReadMemoryMap(_offset, _len) => self.gdb_send(b"")?,
+            GdbCommand::ReadThreads(offset, len) if self.linux.is_some() => {
+                if offset == 0 {
+                    self.refresh_threads(cpu, bridge)?;
+                }
+                let xml = self.threads_xml().into_bytes();
+                self.gdb_send_file(&xml, offset, len)?
             }
             GdbCommand::ReadThreads(offset, len) => {
-                self.gdb_send_file(cpu.get_threads()?, offset, len)?
+                self.gdb_send_file(&cpu.get_threads(bridge)?, offset, len)?
+            }
+            // GDB sends `vCont;t` in non-stop mode, but a Ctrl-C stops
+            // every hart
+            GdbCommand::Interrupt if self.non_stop() => {
+                let harts: Vec<u32> = (0..cpu.harts()).collect();
+                for reply in self.stop_harts(cpu, bridge, &harts, 2)? {
+                    self.report_stop(&reply)?;
+                }
+            }
+            GdbCommand::FlashErase(_, _) | GdbCommand::FlashWrite(_, _) | GdbCommand::FlashDone
+                if self.flash.is_none() =>
+            {
+                error!("GDB tried to program flash, but there's no SPI flash core in csr.csv");
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::FlashErase(addr, len) => match regions::flash_offset(&self.regions, addr, len) {
+                Some(offset) => match self.flash.as_mut().unwrap().erase(bridge, offset, len) {
+                    Ok(()) => self.gdb_send(b"OK")?,
+                    Err(e) => {
+                        error!("couldn't erase flash at {:08x}: {:?}", addr, e);
+                        self.gdb_send(b"E02")?
+                    }
+                },
+                None => {
+                    error!("GDB tried to erase {:08x}, which isn't flash", addr);
+                    self.gdb_send(b"E01")?
+                }
+            },
+            GdbCommand::FlashWrite(addr, data) => {
+                match regions::flash_offset(&self.regions, addr, data.len() as u32) {
+                    Some(offset) => {
+                        self.flash.as_mut().unwrap().write(offset, &data);
+                        self.gdb_send(b"OK")?
+                    }
+                    None => self.gdb_send(b"E.memtype")?,
+                }
+            }
+            GdbCommand::FlashDone => match self.flash.as_mut().unwrap().done(bridge) {
+                Ok(()) => self.gdb_send(b"OK")?,
+                Err(e) => {
+                    error!("couldn't program flash: {:?}", e);
+                    self.gdb_send(b"E02")?
+                }
+            },
+            GdbCommand::FileIoReply(retcode, errno, interrupted) => {
+                if !cpu.finish_file_io(bridge, retcode, errno, interrupted)? {
+                    self.gdb_send(b"E01")?
+                } else if interrupted {
+                    self.last_signal = 2;
+                    let reply = format!("S{:02x}", self.last_signal);
+                    self.report_stop(&reply)?;
+                } else {
+                    self.forget_recording();
+                }
             }
             GdbCommand::Interrupt => {
                 self.last_signal = 2;
@@ -751,12 +1424,107 @@ impl GdbServer {
         Ok(())
     }
 
-    fn gdb_send_ack(&mut self) -> io::Result<usize> {
-        self.connection.write(&['+' as u8])
+    /// Re-read the kernel's task list.  If that fails (e.g. because the
+    /// kernel hasn't booted yet), just present the CPU as the only thread.
+    fn refresh_threads(&mut self, cpu: &RiscvSystem, bridge: &Bridge) -> Result<(), GdbServerError> {
+        let offsets = match &self.linux {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        self.tasks = match offsets.tasks(cpu, bridge) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                debug!("unable to walk the Linux task list: {}", e);
+                vec![]
+            }
+        };
+        self.current_thread = linux::current_thread(cpu, bridge, &self.tasks)?;
+        Ok(())
+    }
+
+    fn thread_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.tasks.iter().map(|t| t.address as u64).collect();
+        if self.current_thread == linux::CPU_THREAD_ID {
+            ids.insert(0, linux::CPU_THREAD_ID);
+        }
+        ids
+    }
+
+    /// If GDB has selected a task that isn't the one that's running, return
+    /// the address of its `task_struct`.
+    fn selected_task(&self) -> Option<u32> {
+        if self.linux.is_none()
+            || self.selected_thread == 0
+            || self.selected_thread == self.current_thread
+            || self.selected_thread == linux::CPU_THREAD_ID
+        {
+            None
+        } else {
+            Some(self.selected_thread as u32)
+        }
+    }
+
+    fn threads_xml(&self) -> String {
+        let mut xml = "<?xml version=\"1.0\"?>\n<threads>\n".to_owned();
+        if self.current_thread == linux::CPU_THREAD_ID {
+            xml.push_str(&format!(
+                "<thread id=\"{:x}\" name=\"cpu\"></thread>\n",
+                linux::CPU_THREAD_ID
+            ));
+        }
+        for task in &self.tasks {
+            let name = task
+                .comm
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('"', "&quot;");
+            xml.push_str(&format!(
+                "<thread id=\"{:x}\" name=\"{}\">pid {}</thread>\n",
+                task.address, name, task.pid
+            ));
+        }
+        xml.push_str("</threads>");
+        xml
+    }
+
+    /// Read memory for `qCRC`.  Physical memory is read in bursts, which
+    /// is what makes `compare-sections` quick, but with paging on each
+    /// word has to be translated, so it goes through the CPU.
+    fn read_for_crc(
+        &self,
+        cpu: &RiscvSystem,
+        bridge: &Bridge,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, RiscvCpuError> {
+        let start = addr & !3;
+        let end = (addr as u64 + len as u64 + 3) & !3;
+        let skip = (addr - start) as usize;
+        let data = if cpu.paging_enabled() {
+            let mut data = vec![];
+            let mut word = start as u64;
+            while word < end {
+                data.extend_from_slice(&cpu.read_memory(bridge, word as u32, 4)?.to_le_bytes());
+                word += 4;
+            }
+            data
+        } else {
+            bridge.burst_read(start, (end - start as u64) as u32)?
+        };
+        // A range that runs off the top of memory comes back short
+        let wanted = skip + len as usize;
+        match data.get(skip..wanted) {
+            Some(data) => Ok(data.to_vec()),
+            None => Err(BridgeError::LengthError(wanted, data.len()).into()),
+        }
+    }
+
+    fn gdb_send_ack(&mut self) -> io::Result<()> {
+        self.connection.write_all(b"+")
     }
 
-    fn gdb_send_nak(&mut self) -> io::Result<usize> {
-        self.connection.write(&['-' as u8])
+    fn gdb_send_nak(&mut self) -> io::Result<()> {
+        self.connection.write_all(b"-")
     }
 
     fn gdb_send_u32(&mut self, vals: Vec<u32>) -> io::Result<()> {
@@ -770,26 +1538,10 @@ impl GdbServer {
     }
 
     fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
-        let mut buffer = [0; 16388];
-        let mut checksum: u8 = 0;
-        buffer[0] = '$' as u8;
-        for i in 0..inp.len() {
-            buffer[i + 1] = inp[i];
-            checksum = checksum.wrapping_add(inp[i]);
-        }
-        let checksum_str = &format!("{:02x}", checksum);
-        let checksum_bytes = checksum_str.as_bytes();
-        buffer[inp.len() + 1] = '#' as u8;
-        buffer[inp.len() + 2] = checksum_bytes[0];
-        buffer[inp.len() + 3] = checksum_bytes[1];
-        let (to_write, _rest) = buffer.split_at(inp.len() + 4);
-        // debug!(
-        //     " > Writing {} bytes: {}",
-        //     to_write.len(),
-        //     String::from_utf8_lossy(&to_write)
-        // );
-        self.connection.write(&to_write)?;
-        Ok(())
+        let _span = trace::span("gdb", "send");
+        let mut last_sent = self.last_sent.lock().unwrap();
+        *last_sent = Packet::new(inp).encode();
+        self.connection.write_all(&last_sent)
     }
 
     pub fn print_string(&mut self, msg: &str) -> io::Result<()> {
@@ -804,25 +1556,199 @@ impl GdbServer {
         self.gdb_send(joined.as_bytes())
     }
 
-    fn gdb_send_file(&mut self, mut data: Vec<u8>, offset: u32, len: u32) -> io::Result<()> {
-        let offset = offset as usize;
-        let len = len as usize;
-        let mut end = offset + len;
-        if offset > data.len() {
-            self.gdb_send(b"l")?;
+    /// Carry out a vCont.  Each hart takes the first action that names
+    /// it, or failing that the first that names no thread, and harts left
+    /// with neither stay halted while the others run.  All-stop means
+    /// everything stops again as soon as a step is done, so a hart that's
+    /// stepped is stepped on its own, and the rest aren't let go for the
+    /// length of one instruction.  Linux threads all share the one CPU,
+    /// so there it's only a question of whether it steps or runs.
+    fn vcont(&mut self, cpu: &RiscvSystem, bridge: &Bridge, actions: &[VContAction]) -> Result<(), GdbServerError> {
+        let action_for = |thread: u64| vcont_action(actions, thread);
+        let plan: Vec<Option<VContKind>> = if self.linux.is_some() {
+            let any = |kind| actions.iter().any(|a| a.kind == kind);
+            if any(VContKind::Step) {
+                vec![Some(VContKind::Step)]
+            } else if any(VContKind::Continue) {
+                vec![Some(VContKind::Continue)]
+            } else {
+                vec![None]
+            }
         } else {
-            if end > data.len() {
-                end = data.len();
+            (1..=cpu.harts() as u64).map(action_for).collect()
+        };
+
+        if let Some(hart) = plan.iter().position(|kind| *kind == Some(VContKind::Step)) {
+            if self.linux.is_none() {
+                cpu.select_hart(bridge, hart as u32)?;
             }
-            let mut trimmed_data: Vec<u8> = data.drain(offset..end).collect();
-            if trimmed_data.len() >= len {
-                // XXX should this be <= or < ?
-                trimmed_data.insert(0, 'm' as u8);
-            } else {
-                trimmed_data.insert(0, 'l' as u8);
+            return self.step(cpu, bridge);
+        }
+        let parked: Vec<u32> = (0..plan.len() as u32)
+            .filter(|hart| plan[*hart as usize] != Some(VContKind::Continue))
+            .collect();
+        if parked.len() == plan.len() {
+            // Nothing to run, so there'd never be a stop to report
+            return Ok(self.gdb_send(b"E01")?);
+        }
+        self.forget_recording();
+        if self.linux.is_none() {
+            cpu.park_harts(&parked);
+        }
+        if let Some(s) = cpu.resume(bridge)? {
+            self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
+        }
+        Ok(())
+    }
+
+    /// Carry out a vCont in non-stop mode, where each hart does just what
+    /// it's told.  GDB gets `OK` straight away and hears about each stop
+    /// afterwards: a step once it's done, and a hart told to stop with
+    /// signal 0.  Harts already doing what they're told are left alone.
+    /// The selected hart stays selected, since GDB is still talking to it.
+    fn vcont_non_stop(&mut self, cpu: &RiscvSystem, bridge: &Bridge, actions: &[VContAction]) -> Result<(), GdbServerError> {
+        let selected = cpu.hart();
+        let plan = |kind| -> Vec<u32> {
+            (0..cpu.harts())
+                .filter(|hart| vcont_action(actions, *hart as u64 + 1) == Some(kind))
+                .collect()
+        };
+        let (to_run, to_step, to_stop) = (plan(VContKind::Continue), plan(VContKind::Step), plan(VContKind::Stop));
+
+        if !to_run.is_empty() || !to_step.is_empty() {
+            self.forget_recording();
+        }
+        for &hart in to_run.iter().filter(|hart| cpu.hart_stopped(**hart)) {
+            cpu.select_hart(bridge, hart)?;
+            if let Some(s) = cpu.resume(bridge)? {
+                info!("hart {} is in a trap: {}", hart, s);
             }
-            self.gdb_send(&trimmed_data)?;
+        }
+        cpu.select_hart(bridge, selected)?;
+        let mut replies = self.stop_harts(cpu, bridge, &to_stop, 0)?;
+        self.gdb_send(b"OK")?;
+
+        for &hart in to_step.iter().filter(|hart| cpu.hart_stopped(**hart)) {
+            cpu.select_hart(bridge, hart)?;
+            let (pc, trap) = cpu.step(bridge)?;
+            if let Some(s) = trap {
+                info!("hart {} is in a trap: {}", hart, s);
+            }
+            replies.push(self.step_reply(cpu, pc, ""));
+        }
+        cpu.select_hart(bridge, selected)?;
+        for reply in replies {
+            self.report_stop(&reply)?;
         }
         Ok(())
     }
+
+    /// Halt each of `harts` that's running, giving back a stop reply with
+    /// `signal` for each one that was
+    fn stop_harts(&mut self, cpu: &RiscvSystem, bridge: &Bridge, harts: &[u32], signal: u8) -> Result<Vec<String>, GdbServerError> {
+        let selected = cpu.hart();
+        let mut replies = vec![];
+        for &hart in harts.iter().filter(|hart| !cpu.hart_stopped(**hart)) {
+            cpu.select_hart(bridge, hart)?;
+            cpu.halt(bridge)?;
+            replies.push(self.hart_stop_reply(cpu, hart, signal));
+        }
+        cpu.select_hart(bridge, selected)?;
+        Ok(replies)
+    }
+
+    fn non_stop(&self) -> bool {
+        self.stops.lock().unwrap().non_stop
+    }
+
+    fn report_stop(&mut self, reply: &str) -> io::Result<()> {
+        let mut stops = self.stops.lock().unwrap();
+        if stops.non_stop {
+            return stops.report(&mut self.connection, reply);
+        }
+        drop(stops);
+        self.gdb_send(reply.as_bytes())
+    }
+
+    fn hart_stop_reply(&self, cpu: &RiscvSystem, hart: u32, signal: u8) -> String {
+        if cpu.harts() > 1 {
+            format!("T{:02x}thread:{:x};", signal, hart + 1)
+        } else {
+            format!("T{:02x}", signal)
+        }
+    }
+
+    /// Step one instruction, and send back a stop reply that includes the
+    /// new PC, so GDB doesn't have to ask for it.
+    fn step(&mut self, cpu: &RiscvSystem, bridge: &Bridge) -> Result<(), GdbServerError> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(cpu, bridge)?;
+        }
+        let (pc, trap) = cpu.step(bridge)?;
+        if let Some(s) = trap {
+            self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?;
+        }
+        self.send_step_reply(cpu, pc, "")
+    }
+
+    /// Go back one recorded step, or with `to_breakpoint`, back until
+    /// reaching a breakpoint.  Running out of record ends it early, which
+    /// GDB is told about as the start of the replay log.
+    fn reverse(&mut self, cpu: &RiscvSystem, bridge: &Bridge, to_breakpoint: bool) -> Result<(), GdbServerError> {
+        // unwrap() is safe because only a recording server gets here
+        let recorder = self.recorder.as_mut().unwrap();
+        loop {
+            match recorder.rewind(cpu, bridge)? {
+                None => {
+                    let pc = cpu.read_register_wide(bridge, PC_REGNUM)?;
+                    return self.send_step_reply(cpu, pc, "replaylog:begin;");
+                }
+                Some(pc) if !to_breakpoint || self.breakpoint_addresses.contains(&(pc as u32)) => {
+                    return self.send_step_reply(cpu, pc, "");
+                }
+                Some(_) => (),
+            }
+        }
+    }
+
+    fn forget_recording(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.clear();
+        }
+    }
+
+    fn send_step_reply(&mut self, cpu: &RiscvSystem, pc: u64, extra: &str) -> Result<(), GdbServerError> {
+        let reply = self.step_reply(cpu, pc, extra);
+        self.gdb_send(reply.as_bytes())?;
+        Ok(())
+    }
+
+    fn step_reply(&mut self, cpu: &RiscvSystem, pc: u64, extra: &str) -> String {
+        self.last_signal = 5;
+        let mut reply = format!(
+            "T{:02x}{:02x}:{};{}",
+            self.last_signal,
+            PC_REGNUM,
+            encode_register(pc, cpu.register_bytes(PC_REGNUM)),
+            extra
+        );
+        if self.linux.is_none() && cpu.harts() > 1 {
+            reply.push_str(&format!("thread:{:x};", cpu.hart() + 1));
+        }
+        reply
+    }
+
+    /// Send the `len` bytes of `data` from `offset`, starting with `m` if
+    /// there's more to come after them or `l` if that's the last of it
+    fn gdb_send_file(&mut self, data: &[u8], offset: u32, len: u32) -> io::Result<()> {
+        let offset = offset as usize;
+        if offset >= data.len() {
+            return self.gdb_send(b"l");
+        }
+        let end = data.len().min(offset.saturating_add(len as usize));
+        let mut reply = Vec::with_capacity(end - offset + 1);
+        reply.push(if end < data.len() { b'm' } else { b'l' });
+        reply.extend_from_slice(&data[offset..end]);
+        self.gdb_send(&reply)
+    }
 }
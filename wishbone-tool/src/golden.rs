@@ -0,0 +1,260 @@
+//! Golden register dumps, for catching gateware regressions: record the
+//! CSRs of a known-good build once, then compare later builds against it.
+//!
+//! A dump is a JSON file with one entry per CSR word:
+//!
+//! ```text
+//!   {
+//!     "registers": [
+//!       { "name": "ctrl_scratch", "address": "0x82000004", "value": "0x12345678" },
+//!       { "name": "timer0_value", "address": "0x82002818", "value": "0x0", "ignore": true },
+//!       { "name": "xadc_temperature", "address": "0x82005000", "value": "0x9c4", "tolerance": 32 },
+//!       { "name": "ctrl_bus_errors", "address": "0x82000008", "value": "0x0", "mask": "0xff" }
+//!     ]
+//!   }
+//! ```
+//!
+//! A recorded dump only has the name, address and value.  `ignore`,
+//! `tolerance` and `mask` are added by hand for registers that are
+//! expected to wander, such as counters and sensors.  Numbers may be
+//! given either as JSON numbers or as strings, which may be hex.
+
+use crate::json::{self, Json};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+#[derive(Debug)]
+pub enum GoldenError {
+    IoError(io::Error),
+
+    /// The file isn't JSON, or isn't laid out like a dump
+    BadDump(String),
+}
+
+impl std::fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use GoldenError::*;
+        match self {
+            IoError(e) => write!(f, "io error: {}", e),
+            BadDump(s) => write!(f, "not a golden dump: {}", s),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for GoldenError {
+    fn from(e: io::Error) -> GoldenError {
+        GoldenError::IoError(e)
+    }
+}
+
+/// One CSR word of a dump
+#[derive(Clone, Debug)]
+pub struct GoldenWord {
+    pub name: String,
+    pub address: u32,
+    pub value: u32,
+
+    /// Skip this word when comparing
+    pub ignore: bool,
+
+    /// How far the value may drift either way and still match
+    pub tolerance: u32,
+
+    /// Only these bits are compared
+    pub mask: u32,
+}
+
+impl GoldenWord {
+    pub fn new(name: &str, address: u32, value: u32) -> GoldenWord {
+        GoldenWord {
+            name: name.to_owned(),
+            address,
+            value,
+            ignore: false,
+            tolerance: 0,
+            mask: 0xffff_ffff,
+        }
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        let golden = self.value & self.mask;
+        let value = value & self.mask;
+        golden.max(value) - golden.min(value) <= self.tolerance
+    }
+}
+
+/// How a fresh snapshot differs from the golden one
+#[derive(Debug)]
+pub enum Difference {
+    /// The value is out of tolerance
+    Changed(GoldenWord, u32 /* observed */),
+
+    /// The register moved to another address.  Its value is still
+    /// compared, going by its position within the register.
+    Moved(GoldenWord, u32 /* new address */),
+
+    /// The register is in the dump but not in the gateware
+    Missing(GoldenWord),
+
+    /// The register is in the gateware but not in the dump
+    Added(GoldenWord),
+}
+
+impl Difference {
+    /// Whether the difference means the gateware no longer matches.  New
+    /// registers are left for the report.
+    pub fn is_regression(&self) -> bool {
+        !matches!(self, Difference::Added(_))
+    }
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Changed(word, observed) => {
+                write!(
+                    f,
+                    "{} @ {:08x}: golden {:08x}, now {:08x}",
+                    word.name, word.address, word.value, observed
+                )?;
+                if word.mask != 0xffff_ffff {
+                    write!(f, " (mask {:08x})", word.mask)?;
+                }
+                if word.tolerance != 0 {
+                    write!(f, " (tolerance {})", word.tolerance)?;
+                }
+                Ok(())
+            }
+            Difference::Moved(word, address) => write!(
+                f,
+                "{} moved from {:08x} to {:08x}",
+                word.name, word.address, address
+            ),
+            Difference::Missing(word) => {
+                write!(f, "{} @ {:08x} is gone", word.name, word.address)
+            }
+            Difference::Added(word) => write!(
+                f,
+                "{} @ {:08x} is new, and is {:08x}",
+                word.name, word.address, word.value
+            ),
+        }
+    }
+}
+
+/// Compare a fresh snapshot against a golden one.  Words are matched up by
+/// register name, and by their order within the register.
+pub fn compare(golden: &[GoldenWord], fresh: &[GoldenWord]) -> Vec<Difference> {
+    let mut fresh_words: HashMap<&str, Vec<&GoldenWord>> = HashMap::new();
+    for word in fresh {
+        fresh_words.entry(&word.name).or_default().push(word);
+    }
+    let mut golden_counts: HashMap<&str, usize> = HashMap::new();
+    let mut differences = vec![];
+    for word in golden {
+        let index = golden_counts.entry(&word.name).or_insert(0);
+        let now = fresh_words
+            .get(word.name.as_str())
+            .and_then(|words| words.get(*index))
+            .copied();
+        *index += 1;
+        let now = match now {
+            Some(now) => now,
+            None => {
+                differences.push(Difference::Missing(word.clone()));
+                continue;
+            }
+        };
+        if now.address != word.address {
+            differences.push(Difference::Moved(word.clone(), now.address));
+        }
+        if !word.ignore && !word.matches(now.value) {
+            differences.push(Difference::Changed(word.clone(), now.value));
+        }
+    }
+    for word in fresh {
+        let golden_words = golden_counts.get(word.name.as_str()).copied().unwrap_or(0);
+        let index = fresh_words[word.name.as_str()]
+            .iter()
+            .position(|w| w.address == word.address)
+            .unwrap_or(0);
+        if index >= golden_words {
+            differences.push(Difference::Added(word.clone()));
+        }
+    }
+    differences
+}
+
+/// Write a dump out, one word per line so that it diffs and edits nicely.
+pub fn write(file_name: &str, words: &[GoldenWord]) -> Result<(), GoldenError> {
+    let mut text = "{\n  \"registers\": [\n".to_owned();
+    for (index, word) in words.iter().enumerate() {
+        text.push_str(&format!(
+            "    {{ \"name\": \"{}\", \"address\": \"0x{:08x}\", \"value\": \"0x{:08x}\"",
+            escape(&word.name),
+            word.address,
+            word.value
+        ));
+        if word.ignore {
+            text.push_str(", \"ignore\": true");
+        }
+        if word.tolerance != 0 {
+            text.push_str(&format!(", \"tolerance\": {}", word.tolerance));
+        }
+        if word.mask != 0xffff_ffff {
+            text.push_str(&format!(", \"mask\": \"0x{:08x}\"", word.mask));
+        }
+        text.push_str(if index + 1 < words.len() { " },\n" } else { " }\n" });
+    }
+    text.push_str("  ]\n}\n");
+    fs::write(file_name, text)?;
+    Ok(())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn read(file_name: &str) -> Result<Vec<GoldenWord>, GoldenError> {
+    let text = fs::read_to_string(file_name)?;
+    let value = json::parse(&text).map_err(GoldenError::BadDump)?;
+
+    let registers = match value.get("registers") {
+        Some(Json::Array(registers)) => registers,
+        _ => {
+            return Err(GoldenError::BadDump(
+                "there's no \"registers\" list".to_owned(),
+            ))
+        }
+    };
+    let mut words = vec![];
+    for (index, register) in registers.iter().enumerate() {
+        let bad = |what: &str| GoldenError::BadDump(format!("register {}: {}", index, what));
+        let name = match register.get("name") {
+            Some(Json::String(name)) => name.clone(),
+            _ => return Err(bad("no name")),
+        };
+        let number = |field: &str| -> Result<Option<u32>, GoldenError> {
+            match register.get(field) {
+                None => Ok(None),
+                Some(value) => value.as_u32().map(Some).ok_or_else(|| {
+                    bad(&format!("{} of {} isn't a 32-bit number", field, name))
+                }),
+            }
+        };
+        let address = number("address")?.ok_or_else(|| bad(&format!("{} has no address", name)))?;
+        let value = number("value")?.ok_or_else(|| bad(&format!("{} has no value", name)))?;
+        let mut word = GoldenWord::new(&name, address, value);
+        word.tolerance = number("tolerance")?.unwrap_or(0);
+        word.mask = number("mask")?.unwrap_or(0xffff_ffff);
+        word.ignore = match register.get("ignore") {
+            None => false,
+            Some(Json::Bool(b)) => *b,
+            Some(_) => return Err(bad(&format!("ignore of {} isn't true or false", name))),
+        };
+        words.push(word);
+    }
+    Ok(words)
+}
@@ -0,0 +1,86 @@
+//! The session journal written with --journal.  Each run appends the
+//! command line that started it, along with when it ran, how each
+//! operation turned out, and anything the operations noted along the way,
+//! such as the values they read.  The journal is a shell script, so the
+//! whole session can be run again with `sh`, or attached to a bug report.
+
+use crate::summary::OperationSummary;
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone)]
+pub struct Journal {
+    filename: String,
+    notes: Arc<Mutex<Vec<String>>>,
+}
+
+/// Quote `arg` so the shell will hand it back unchanged.
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+impl Journal {
+    pub fn new(filename: &str) -> Journal {
+        Journal {
+            filename: filename.to_owned(),
+            notes: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Remember something worth putting in the journal, such as a value
+    /// that was read.
+    pub fn note(&self, text: String) {
+        self.notes.lock().unwrap().push(text);
+    }
+
+    /// Append this run to the journal.
+    pub fn record(&self, args: &[String], operations: &[OperationSummary]) -> io::Result<()> {
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.filename)?;
+        if f.metadata()?.len() == 0 {
+            writeln!(f, "#!/bin/sh")?;
+        }
+        let when = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let results: Vec<String> = operations
+            .iter()
+            .map(|o| {
+                format!(
+                    "{} {} {}ms",
+                    o.name,
+                    if o.succeeded { "ok" } else { "error" },
+                    o.elapsed.as_millis()
+                )
+            })
+            .collect();
+        writeln!(f, "# {} {}", when, results.join(", "))?;
+        for note in self.notes.lock().unwrap().iter() {
+            writeln!(f, "#   {}", note)?;
+        }
+        // Leave out --journal itself, so that running the journal again
+        // doesn't add to it
+        let mut command = vec![];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--journal" {
+                args.next();
+            } else if !arg.starts_with("--journal=") {
+                command.push(quote(arg));
+            }
+        }
+        writeln!(f, "{}", command.join(" "))?;
+        Ok(())
+    }
+}
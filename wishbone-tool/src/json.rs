@@ -0,0 +1,257 @@
+//! Just enough JSON for the files wishbone-tool reads, such as golden
+//! dumps: objects, arrays, strings, numbers, `true`, `false` and `null`.
+
+use crate::config::parse_u32;
+
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        offset: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.offset != parser.text.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+/// A parsed JSON value
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// A number that fits in 32 bits, or a string that parses as one,
+    /// which may be hex
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Json::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n <= u32::MAX as f64 => {
+                Some(*n as u32)
+            }
+            Json::String(s) => parse_u32(s).ok(),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, what: &str) -> String {
+        let line = self.text[..self.offset.min(self.text.len())]
+            .iter()
+            .filter(|b| **b == b'\n')
+            .count();
+        format!("line {}: {}", line + 1, what)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.offset < self.text.len() && self.text[self.offset].is_ascii_whitespace() {
+            self.offset += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.offset).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c as char)));
+        }
+        self.offset += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.offset..].starts_with(word.as_bytes()) {
+            return Err(self.error("unexpected word"));
+        }
+        self.offset += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(c) if c == b'-' || c.is_ascii_digit() => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect(b'{')?;
+        let mut fields = vec![];
+        if self.peek() == Some(b'}') {
+            self.offset += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.offset += 1,
+                Some(b'}') => {
+                    self.offset += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect(b'[')?;
+        let mut items = vec![];
+        if self.peek() == Some(b']') {
+            self.offset += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.offset += 1,
+                Some(b']') => {
+                    self.offset += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut s = vec![];
+        loop {
+            match self.text.get(self.offset) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.offset += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    let escaped = match self.text.get(self.offset + 1) {
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        Some(b'r') => b'\r',
+                        Some(c @ b'"') | Some(c @ b'\\') | Some(c @ b'/') => *c,
+                        _ => return Err(self.error("unsupported escape")),
+                    };
+                    s.push(escaped);
+                    self.offset += 2;
+                }
+                Some(c) => {
+                    s.push(*c);
+                    self.offset += 1;
+                }
+            }
+        }
+        String::from_utf8(s).map_err(|_| self.error("string isn't UTF-8"))
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.offset;
+        while self.offset < self.text.len()
+            && matches!(self.text[self.offset], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        {
+            self.offset += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.offset])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Json::Number)
+            .ok_or_else(|| self.error("bad number"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let json = parse(
+            r#"{
+                "name": "mcustom\t\"x\"\\\/",
+                "csr": "0x7c0",
+                "decimal": 1984,
+                "negative": -1.5e2,
+                "flags": [true, false, null],
+                "empty": {},
+                "nothing": []
+            }"#,
+        )
+        .unwrap();
+        assert!(matches!(json.get("name"), Some(Json::String(s)) if s == "mcustom\t\"x\"\\/"));
+        assert_eq!(json.get("csr").and_then(|csr| csr.as_u32()), Some(0x7c0));
+        assert!(matches!(json.get("decimal"), Some(Json::Number(n)) if *n == 1984.0));
+        assert!(matches!(json.get("negative"), Some(Json::Number(n)) if *n == -150.0));
+        assert!(matches!(json.get("empty"), Some(Json::Object(fields)) if fields.is_empty()));
+        assert!(matches!(json.get("nothing"), Some(Json::Array(items)) if items.is_empty()));
+        match json.get("flags") {
+            Some(Json::Array(items)) => assert!(matches!(
+                items.as_slice(),
+                [Json::Bool(true), Json::Bool(false), Json::Null]
+            )),
+            _ => panic!("flags isn't an array"),
+        }
+        assert!(json.get("missing").is_none());
+    }
+
+    #[test]
+    fn as_u32() {
+        let json = parse(r#"[7, "0x7c0", "1984", -1, 1.5, 4294967296, "x", null]"#).unwrap();
+        let items = match json {
+            Json::Array(items) => items,
+            _ => panic!("not an array"),
+        };
+        let values: Vec<Option<u32>> = items.iter().map(|item| item.as_u32()).collect();
+        assert_eq!(
+            values,
+            vec![Some(7), Some(0x7c0), Some(1984), None, None, None, None, None]
+        );
+    }
+
+    #[test]
+    fn errors_say_where() {
+        let error = |text: &str| match parse(text) {
+            Err(e) => e,
+            Ok(_) => panic!("{} parsed", text),
+        };
+        assert_eq!(error("{\n\"a\": 1,\n\"b\" 2}"), "line 3: expected ':'");
+        assert_eq!(error("[1, 2"), "line 1: expected ',' or ']'");
+        assert_eq!(error("{\"a\": 1 \"b\": 2}"), "line 1: expected ',' or '}'");
+        assert_eq!(error("\"open"), "line 1: unterminated string");
+        assert_eq!(error("\"\\u0041\""), "line 1: unsupported escape");
+        assert_eq!(error("[1] x"), "line 1: trailing characters");
+        assert_eq!(error("tru"), "line 1: unexpected word");
+        assert_eq!(error("-"), "line 1: bad number");
+        assert_eq!(error(""), "line 1: unexpected end of file");
+        assert_eq!(error("\n\n@"), "line 3: unexpected character");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::bridge::Bridge;
+use crate::riscv::{RiscvSystem, RiscvCpuError};
+
+/// Give up walking the task list after this many entries, in case it's
+/// corrupted and loops back on itself somewhere other than `init_task`.
+const MAX_TASKS: usize = 4096;
+
+/// GDB thread ID used for the CPU itself when it isn't running in the
+/// context of a known kernel task (e.g. while in userspace).  Kernel
+/// addresses are never this low, so it can't collide with a task.
+pub const CPU_THREAD_ID: u64 = 1;
+
+/// Where to find things in the running kernel.  These vary from build to
+/// build, so they must be supplied by the user, usually by asking GDB
+/// about `&init_task`, `&((struct task_struct *)0)->tasks`, and so on.
+#[derive(Clone, Debug)]
+pub struct LinuxOffsets {
+    /// Address of `init_task`
+    pub init_task: u32,
+
+    /// Offset of `tasks` within `struct task_struct`
+    pub tasks: u32,
+
+    /// Offset of `pid` within `struct task_struct`
+    pub pid: u32,
+
+    /// Offset of `comm` within `struct task_struct`
+    pub comm: u32,
+
+    /// Offset of `thread` (the saved `struct thread_struct`) within
+    /// `struct task_struct`
+    pub thread: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinuxTask {
+    /// Address of this task's `task_struct`, which is also its GDB thread ID
+    pub address: u32,
+    pub pid: u32,
+    pub comm: String,
+}
+
+impl LinuxOffsets {
+    /// Follow `init_task.tasks` all the way around the list of tasks.
+    pub fn tasks(&self, cpu: &RiscvSystem, bridge: &Bridge) -> Result<Vec<LinuxTask>, RiscvCpuError> {
+        let mut tasks = vec![];
+        let head = self.init_task + self.tasks;
+        let mut task = self.init_task;
+        loop {
+            tasks.push(self.read_task(cpu, bridge, task)?);
+            let next = cpu.read_memory(bridge, task.wrapping_add(self.tasks), 4)?;
+            if next == head || tasks.len() >= MAX_TASKS {
+                break;
+            }
+            // A pointer that can't be a list entry means the list is
+            // corrupted, or the offsets are wrong
+            task = match next.checked_sub(self.tasks) {
+                Some(task) => task,
+                None => break,
+            };
+        }
+        Ok(tasks)
+    }
+
+    fn read_task(
+        &self,
+        cpu: &RiscvSystem,
+        bridge: &Bridge,
+        address: u32,
+    ) -> Result<LinuxTask, RiscvCpuError> {
+        let pid = cpu.read_memory(bridge, address.wrapping_add(self.pid), 4)?;
+        let mut comm = vec![];
+        for offset in (0..16).step_by(4) {
+            let word = cpu.read_memory(bridge, address.wrapping_add(self.comm + offset), 4)?;
+            comm.extend_from_slice(&word.to_le_bytes());
+        }
+        let len = comm.iter().position(|&c| c == 0).unwrap_or(comm.len());
+        Ok(LinuxTask {
+            address,
+            pid,
+            comm: String::from_utf8_lossy(&comm[..len]).to_string(),
+        })
+    }
+
+    /// Read a register that was saved when `task` was switched out.  Only
+    /// `ra`, `sp`, and the callee-saved registers are kept, and a sleeping
+    /// task will resume at `ra`, which is what's reported as its `pc`.
+    /// Any other register is unavailable, and returns `None`.
+    pub fn saved_register(
+        &self,
+        cpu: &RiscvSystem,
+        bridge: &Bridge,
+        task: u32,
+        gdb_idx: u32,
+    ) -> Result<Option<u32>, RiscvCpuError> {
+        // struct thread_struct { ra, sp, s[12], ... }
+        let offset = match gdb_idx {
+            1 | 32 => 0,
+            2 => 4,
+            8..=9 => 8 + (gdb_idx - 8) * 4,
+            18..=27 => 16 + (gdb_idx - 18) * 4,
+            _ => return Ok(None),
+        };
+        Ok(Some(cpu.read_memory(
+            bridge,
+            task + self.thread + offset,
+            4,
+        )?))
+    }
+}
+
+/// Figure out which task the CPU is currently running.  The kernel keeps
+/// the current `task_struct` in `tp`, but only while it is actually
+/// running kernel code.
+pub fn current_thread(
+    cpu: &RiscvSystem,
+    bridge: &Bridge,
+    tasks: &[LinuxTask],
+) -> Result<u64, RiscvCpuError> {
+    let tp = cpu.read_register(bridge, 4)?;
+    if tasks.iter().any(|t| t.address == tp) {
+        Ok(tp as u64)
+    } else {
+        Ok(CPU_THREAD_ID)
+    }
+}
@@ -0,0 +1,85 @@
+//! Just enough of a LiteScope analyzer client to start a capture and read
+//! it back, so captures can be lined up with events seen by `watch`.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::{Config, CsrRegister};
+use crate::ecc;
+
+pub struct LiteScope {
+    trigger_enable: u32,
+    trigger_mem_write: u32,
+    trigger_mem_mask: CsrRegister,
+    trigger_mem_value: CsrRegister,
+    storage_enable: u32,
+    storage_done: u32,
+    storage_length: u32,
+    storage_offset: u32,
+    storage_mem_valid: u32,
+    storage_mem_ready: u32,
+    storage_mem_data: CsrRegister,
+}
+
+impl LiteScope {
+    /// Find the analyzer in csr.csv, if the gateware has one.
+    pub fn new(cfg: &Config) -> Option<LiteScope> {
+        let addr = |name: &str| cfg.register_mapping.get(name).cloned();
+        let reg = |name: &str| cfg.csr_registers.iter().find(|r| r.name == name).cloned();
+        Some(LiteScope {
+            trigger_enable: addr("analyzer_trigger_enable")?,
+            trigger_mem_write: addr("analyzer_trigger_mem_write")?,
+            trigger_mem_mask: reg("analyzer_trigger_mem_mask")?,
+            trigger_mem_value: reg("analyzer_trigger_mem_value")?,
+            storage_enable: addr("analyzer_storage_enable")?,
+            storage_done: addr("analyzer_storage_done")?,
+            storage_length: addr("analyzer_storage_length")?,
+            storage_offset: addr("analyzer_storage_offset")?,
+            storage_mem_valid: addr("analyzer_storage_mem_valid")?,
+            storage_mem_ready: addr("analyzer_storage_mem_ready")?,
+            storage_mem_data: reg("analyzer_storage_mem_data")?,
+        })
+    }
+
+    fn write_wide(bridge: &Bridge, reg: &CsrRegister, value: u32) -> Result<(), BridgeError> {
+        for (word, v) in reg.split(value as u64).iter().enumerate() {
+            bridge.poke(reg.address + word as u32 * 4, *v)?;
+        }
+        Ok(())
+    }
+
+    /// Start a capture of `length` samples, `offset` of them from before
+    /// the trigger.  With `immediate`, the trigger is replaced with one that
+    /// always matches, so the capture happens right away; otherwise the
+    /// trigger that's already been set up is left alone.
+    pub fn arm(
+        &self,
+        bridge: &Bridge,
+        offset: u32,
+        length: u32,
+        immediate: bool,
+    ) -> Result<(), BridgeError> {
+        bridge.poke(self.trigger_enable, 0)?;
+        if immediate {
+            Self::write_wide(bridge, &self.trigger_mem_mask, 0)?;
+            Self::write_wide(bridge, &self.trigger_mem_value, 0)?;
+            bridge.poke(self.trigger_mem_write, 1)?;
+        }
+        bridge.poke(self.storage_offset, offset)?;
+        bridge.poke(self.storage_length, length)?;
+        bridge.poke(self.storage_enable, 1)?;
+        bridge.poke(self.trigger_enable, 1)
+    }
+
+    pub fn done(&self, bridge: &Bridge) -> Result<bool, BridgeError> {
+        Ok(bridge.peek(self.storage_done)? != 0)
+    }
+
+    /// Read out a finished capture, one sample at a time.
+    pub fn upload(&self, bridge: &Bridge) -> Result<Vec<u64>, BridgeError> {
+        let mut samples = vec![];
+        while bridge.peek(self.storage_mem_valid)? != 0 {
+            samples.push(ecc::read_counter(bridge, &self.storage_mem_data)?);
+            bridge.poke(self.storage_mem_ready, 1)?;
+        }
+        Ok(samples)
+    }
+}
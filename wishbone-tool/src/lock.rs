@@ -0,0 +1,165 @@
+//! Advisory locking, so two copies of wishbone-tool don't interleave
+//! transactions on the same device.
+
+use crate::bridge::BridgeKind;
+use crate::config::Config;
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::info;
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another process has the device
+    Busy(String /* holder */),
+
+    /// More than one USB device matches, so it isn't known which to lock
+    Ambiguous(usize /* matching devices */),
+
+    IoError(io::Error),
+}
+
+impl ::std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        match self {
+            LockError::Busy(holder) => write!(f, "device busy ({})", holder),
+            LockError::Ambiguous(count) => write!(
+                f,
+                "{} USB devices match; pick one with --bus and --device",
+                count
+            ),
+            LockError::IoError(e) => write!(f, "couldn't lock the device: {}", e),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for LockError {
+    fn from(e: io::Error) -> LockError {
+        LockError::IoError(e)
+    }
+}
+
+/// Held for as long as this process is using the device.  The lock is
+/// released when the file is closed, so nothing needs cleaning up if the
+/// process dies.
+pub struct DeviceLock {
+    _file: File,
+}
+
+/// A name for the device the configuration points at, which is the same
+/// no matter which process works it out.  USB devices are known by serial
+/// number where they have one.
+fn device_key(cfg: &Config) -> Result<Option<String>, LockError> {
+    let key = match cfg.bridge_kind {
+        BridgeKind::SimBridge => return Ok(None),
+        BridgeKind::UartBridge => match cfg.serial_port.as_ref() {
+            Some(port) => format!("uart-{}", port),
+            None => return Ok(None),
+        },
+        BridgeKind::EthernetBridge => match cfg.ethernet_host.as_ref() {
+            Some(host) => format!("ethernet-{}-{}", host, cfg.ethernet_port),
+            None => return Ok(None),
+        },
+        BridgeKind::SpiBridge => "spi".to_owned(),
+        BridgeKind::UsbBridge => usb_key(cfg)?,
+    };
+    Ok(Some(
+        key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect(),
+    ))
+}
+
+/// The key for the one USB device the --vid, --pid, --bus and --device
+/// selectors match.  If several match, locking any one of them could be
+/// locking the wrong board, so that's an error.
+fn usb_key(cfg: &Config) -> Result<String, LockError> {
+    let fallback = format!(
+        "usb-{:04x}-{:04x}",
+        cfg.usb_vid.unwrap_or(0),
+        cfg.usb_pid.unwrap_or(0)
+    );
+    let usb_ctx = match libusb::Context::new() {
+        Ok(c) => c,
+        Err(_) => return Ok(fallback),
+    };
+    let devices = match usb_ctx.devices() {
+        Ok(d) => d,
+        Err(_) => return Ok(fallback),
+    };
+    let mut matching = vec![];
+    for device in devices.iter() {
+        let desc = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        if cfg.usb_pid.is_some_and(|pid| pid != desc.product_id())
+            || cfg.usb_vid.is_some_and(|vid| vid != desc.vendor_id())
+            || cfg.usb_bus.is_some_and(|bus| bus != device.bus_number())
+            || cfg.usb_device.is_some_and(|dev| dev != device.address())
+        {
+            continue;
+        }
+        matching.push((device, desc));
+    }
+    if matching.len() > 1 {
+        return Err(LockError::Ambiguous(matching.len()));
+    }
+    if let Some((device, desc)) = matching.pop() {
+        let serial = device.open().ok().and_then(|usb| {
+            let langs = usb.read_languages(Duration::from_secs(1)).ok()?;
+            usb.read_serial_number_string(*langs.first()?, &desc, Duration::from_secs(1))
+                .ok()
+        });
+        return Ok(match serial {
+            Some(s) if !s.is_empty() => format!("usb-{}", s),
+            _ => format!("usb-{:03}-{:03}", device.bus_number(), device.address()),
+        });
+    }
+    Ok(fallback)
+}
+
+fn lock_path(key: &str) -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    dir.join(format!("wishbone-tool-{}.lock", key))
+}
+
+/// Take the lock for the configured device.  If someone else has it, either
+/// wait for them to finish, or fail with a description of who they are.
+/// Returns `None` for devices that can't be shared anyway.
+pub fn acquire(cfg: &Config, wait: bool) -> Result<Option<DeviceLock>, LockError> {
+    let key = match device_key(cfg)? {
+        Some(k) => k,
+        None => return Ok(None),
+    };
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path(&key))?;
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) if wait => {
+            info!("waiting for {} to be free", key);
+            file.lock()?;
+        }
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            return Err(LockError::Busy(holder.trim().to_owned()));
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+
+    let servers: Vec<&str> = cfg.server_kind.iter().map(|s| s.name()).collect();
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "pid {}: {}", std::process::id(), servers.join(", "))?;
+    Ok(Some(DeviceLock { _file: file }))
+}
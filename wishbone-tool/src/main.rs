@@ -9,25 +9,73 @@ extern crate rand;
 
 extern crate flexi_logger;
 extern crate log;
-use log::error;
+use log::{error, info, warn};
 
+mod access;
+mod boards;
 mod bridge;
+mod checkpoint;
 mod config;
+mod consolelog;
+mod coverage;
+mod dma;
+mod docs;
+mod dtb;
+mod ecc;
+mod elf;
+mod endurance;
+mod etherbone;
+mod flash;
+mod footgun;
 mod gdb;
+mod golden;
+mod journal;
+mod json;
+mod linux;
+mod litescope;
+mod lock;
+mod monitor;
+mod mqtt;
+mod pac;
+mod phy;
+mod prefetch;
+mod regions;
+mod report;
 mod riscv;
+mod rsp;
 mod server;
+mod sfl;
+mod signature;
+mod sequence;
+mod soc;
+mod spiflash;
+mod stress;
+mod summary;
+mod syslog;
+mod svd;
+mod tap;
+mod trace;
+mod tui;
+mod uf2;
+mod watch;
 mod wishbone;
+mod xmodem;
+mod xover;
 
-use bridge::Bridge;
+use boards::BoardRegistry;
+use bridge::{Bridge, ProbeError};
 
 use clap::{App, Arg, Shell};
-use config::Config;
+use config::{Config, CsrRegister};
 use server::ServerKind;
 
+use std::path::Path;
 use std::process;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-fn list_usb() -> Result<(), libusb::Error> {
+/// List all USB devices.  Those with a `dna` register are tagged with their
+/// ID, and their label if it's in the `boards` registry.
+fn list_usb(boards: &BoardRegistry, dna: Option<&CsrRegister>, pid: Option<u16>) -> Result<(), libusb::Error> {
     let usb_ctx = libusb::Context::new().unwrap();
     let devices = usb_ctx.devices().unwrap();
     println!("devices:");
@@ -58,6 +106,14 @@ fn list_usb() -> Result<(), libusb::Error> {
                     Err(_) => "(unknown manufacturer)".to_owned(),
                 };
                 line.push_str(&format!("{} - {}", product, manufacturer));
+                if let Some(dna) = dna.filter(|_| pid == Some(device_desc.product_id())) {
+                    if let Some(id) = boards::usb_board_id(&usb, dna) {
+                        match boards.label(&id) {
+                            Some(label) => line.push_str(&format!(" - {} (id {})", label, id)),
+                            None => line.push_str(&format!(" - id {}", id)),
+                        }
+                    }
+                }
             } else {
                 line.push_str("(no strings found)");
             }
@@ -85,6 +141,13 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .conflicts_with("address")
                 .required_unless("server-kind")
                 .conflicts_with("server-kind")
+                .required_unless("load-file")
+                .required_unless("dump-file")
+                .required_unless("manifest")
+                .required_unless("pulse")
+                .required_unless("waveform")
+                .required_unless("sequence")
+                .required_unless("calc")
                 .display_order(3)
                 .takes_value(false),
         )
@@ -99,6 +162,13 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .conflicts_with("address")
                 .required_unless("server-kind")
                 .conflicts_with("server-kind")
+                .required_unless("load-file")
+                .required_unless("dump-file")
+                .required_unless("manifest")
+                .required_unless("pulse")
+                .required_unless("waveform")
+                .required_unless("sequence")
+                .required_unless("calc")
                 .display_order(3)
                 .possible_values(&Shell::variants())
                 .takes_value(true)
@@ -183,6 +253,57 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .help("Connect using TCP, for example when using an external wishbone bridge")
                 .display_order(6)
         )
+        .arg(
+            Arg::with_name("ethernet-token")
+                .long("ethernet-token")
+                .value_name("TOKEN")
+                .help("send TOKEN when connecting over --ethernet-tcp to a wishbone server that has --client-tokens")
+                .display_order(6)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("bridge-failover")
+                .long("bridge-failover")
+                .help("Connect over USB, and move over to --ethernet-host if USB stops responding")
+                .display_order(6)
+        )
+        .arg(
+            Arg::with_name("posted-writes")
+                .long("posted-writes")
+                .help("Return from writes straight away and commit them in the background, reporting errors on the next access")
+                .display_order(6)
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .help("With --posted-writes, wait for writes to land before answering each request from a server's client")
+                .requires("posted-writes")
+                .display_order(6)
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("Refuse every write to the board, from every server, so that it can be shared for looking at without any risk to it")
+                .display_order(6)
+        )
+        .arg(
+            Arg::with_name("deglitch")
+                .long("deglitch")
+                .value_name("AREA[,READS[,MASK:VALUE]]")
+                .help("Read each word in AREA (ADDRESS:LENGTH, a memory region, or all) READS times and take the majority, or with MASK:VALUE, only re-read values whose MASK bits don't match VALUE (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(6)
+        )
+        .arg(
+            Arg::with_name("connections")
+                .long("connections")
+                .value_name("N")
+                .help("split dumps across N connections to the device, where the bridge allows it (TCP ethernet)")
+                .takes_value(true)
+                .display_order(6),
+        )
         .arg(
             Arg::with_name("spi-pins")
                 .short("g")
@@ -192,6 +313,55 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(6)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("boards")
+                .long("boards")
+                .help("CSV file of ID,LABEL pairs naming boards by their unique ID (default ~/.config/wishbone-tool/boards.csv)")
+                .display_order(6)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .value_name("LABEL")
+                .help("connect to the USB board with this label in the --boards registry")
+                .display_order(6)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("board-id")
+                .long("board-id")
+                .help("read the board's FPGA DNA or flash UID after connecting, and label the log and --syslog with it")
+                .display_order(6),
+        )
+        .arg(
+            Arg::with_name("wait-for-lock")
+                .long("wait-for-lock")
+                .help("if another wishbone-tool is using the device, wait for it rather than exiting")
+                .display_order(6),
+        )
+        .arg(
+            Arg::with_name("sim")
+                .long("sim")
+                .help("Use a simulated bridge that isn't connected to any hardware")
+                .display_order(6),
+        )
+        .arg(
+            Arg::with_name("no-probe")
+                .long("no-probe")
+                .help("Don't check the scratch register to make sure the gateware is working after connecting")
+                .display_order(6),
+        )
+        .arg(
+            Arg::with_name("sim-peripheral")
+                .long("sim-peripheral")
+                .value_name("KIND@ADDRESS")
+                .help("Attach a simulated uart, timer, or scratch register (implies --sim)")
+                .display_order(6)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("address")
                 .index(1)
@@ -199,6 +369,13 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .conflicts_with("completion")
                 .required_unless("server-kind")
                 .conflicts_with("server-kind")
+                .required_unless("load-file")
+                .required_unless("dump-file")
+                .required_unless("manifest")
+                .required_unless("pulse")
+                .required_unless("waveform")
+                .required_unless("sequence")
+                .required_unless("calc")
                 .required_unless("list")
                 .conflicts_with("list")
                 .display_order(7)
@@ -212,6 +389,14 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(8)
                 .help("value to write"),
         )
+        .arg(
+            Arg::with_name("hart")
+                .long("hart")
+                .value_name("N")
+                .help("read or write through hart N, which also lets the address be a CPU register such as mstatus, or regs for x0-x31 and pc")
+                .display_order(8)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("bind-addr")
                 .short("a")
@@ -233,6 +418,14 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(2)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("client-tokens")
+                .long("client-tokens")
+                .value_name("FILE")
+                .help("only let wishbone clients in that first send a token from FILE and a newline (see --ethernet-token), each with a permission of read-only, read-write (but not reset or the SPI flash), or admin")
+                .display_order(2)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("server-kind")
                 .short("s")
@@ -246,9 +439,51 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .conflicts_with("address")
                 .required_unless("list")
                 .conflicts_with("list")
+                .required_unless("load-file")
+                .required_unless("dump-file")
+                .required_unless("manifest")
+                .required_unless("pulse")
+                .required_unless("waveform")
+                .required_unless("sequence")
+                .required_unless("calc")
                 .help("which server to run (if any)")
                 .display_order(1)
-                .possible_values(&["gdb", "wishbone", "random-test", "load-file", "terminal", "messible"]),
+                .possible_values(&[
+                    "gdb",
+                    "wishbone",
+                    "random-test",
+                    "load-file",
+                    "terminal",
+                    "messible",
+                    "state-save",
+                    "state-restore",
+                    "gdb-bench",
+                    "coverage",
+                    "run",
+                    "serial-boot",
+                    "transfer",
+                    "ecc",
+                    "dma",
+                    "phy-test",
+                    "calc",
+                    "endurance",
+                    "stress",
+                    "watch",
+                    "pulse",
+                    "waveform",
+                    "sequence",
+                    "flash",
+                    "flash-verify-signature",
+                    "otp",
+                    "tap",
+                    "time-sync",
+                    "gen-dtb",
+                    "gen-pac",
+                    "tui",
+                    "gen-docs",
+                    "etherbone-send",
+                    "etherbone-decode",
+                ]),
         )
         .arg(
             Arg::with_name("gdb-port")
@@ -272,125 +507,1157 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(13),
         )
         .arg(
-            Arg::with_name("random-loops")
-                .long("random-loops")
-                .help("number of loops to run when doing a random-test")
-                .display_order(9)
-                .takes_value(true),
+            Arg::with_name("uf2-family")
+                .long("uf2-family")
+                .value_name("ID")
+                .help("only load the blocks of a UF2 --load-name that are for this family")
+                .takes_value(true)
+                .display_order(13),
         )
         .arg(
-            Arg::with_name("random-range")
-                .long("random-range")
-                .help("the size of the random address range (i.e. how many bytes to randomly add to the address)")
-                .display_order(9)
-                .takes_value(true),
+            Arg::with_name("load-file")
+                .long("load-file")
+                .value_name("FILE@ADDRESS")
+                .help("Load a file into memory (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
         )
         .arg(
-            Arg::with_name("messible-address")
-                .long("messible-address")
-                .help("address to use to get messible messages from")
-                .display_order(9)
-                .takes_value(true),
+            Arg::with_name("dump-file")
+                .long("dump-file")
+                .value_name("FILE@ADDRESS:LENGTH")
+                .help("Save a region of memory to a file (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
         )
         .arg(
-            Arg::with_name("csr-csv")
-                .long("csr-csv")
-                .help("csr.csv file containing register mappings")
-                .display_order(9)
-                .takes_value(true),
+            Arg::with_name("sparse")
+                .long("sparse")
+                .help("don't write runs of 0x00 or 0xff when loading, or only write them if the target differs")
+                .possible_values(&["skip", "verify"])
+                .takes_value(true)
+                .display_order(13),
         )
         .arg(
-            Arg::with_name("random-address")
-                .long("random-address")
-                .help("address to write to when doing a random-test")
-                .display_order(10)
-                .takes_value(true),
+            Arg::with_name("resume")
+                .long("resume")
+                .help("continue interrupted loads and dumps from their last checkpoint")
+                .display_order(13),
         )
         .arg(
-            Arg::with_name("debug-offset")
-                .long("debug-offset")
-                .help("address to use for debug bridge")
-                .default_value("0xf00f0000")
-                .display_order(11)
-                .takes_value(true),
+            Arg::with_name("burst-size")
+                .long("burst-size")
+                .help("largest number of bytes to transfer in one burst (default depends on the bridge)")
+                .takes_value(true)
+                .display_order(13),
         )
-}
-
-fn main() {
-    flexi_logger::Logger::with_env_or_str("wishbone_tool=info")
-        .start()
-        .unwrap();
-    let matches = clap_app().get_matches();
-
-    if matches.is_present("list") {
-        if list_usb().is_err() {
-            println!("USB is not properly configured");
-        };
-        return;
-    }
-
-    if let Some(shell_str) = matches.value_of("completion") {
-        use std::io;
-        use std::str::FromStr;
-        let shell = match Shell::from_str(shell_str) {
-            Ok(s) => s,
-            Err(_) => panic!("Unrecognized shell"),
-        };
-        clap_app().gen_completions_to(
-            "wishbone-tool",
-            shell,
-            &mut io::stdout()
-        );
-        return;
-    }
-
-    let cfg = match Config::parse(matches) {
-        Ok(cfg) => cfg,
-        Err(e) => {
-            match e {
-                config::ConfigError::NumberParseError(num, e) => {
-                    error!("unable to parse the number \"{}\": {}", num, e)
-                }
-                config::ConfigError::NoOperationSpecified => panic!("no operation was specified"),
-                config::ConfigError::UnknownServerKind(s) => {
-                    error!("unknown server '{}', see --help", s)
-                }
-                config::ConfigError::SpiParseError(s) => error!("couldn't parse spi pins: {}", s),
-                config::ConfigError::IoError(s) => error!("file error: {}", s),
-                config::ConfigError::InvalidConfig(s) => error!("invalid configuration: {}", s),
-            }
-            process::exit(1);
-        }
-    };
-
-    {
-        let bridge = Bridge::new(&cfg).unwrap();
-        bridge.connect().unwrap();
-        let mut threads = vec![];
-        for server_kind in &cfg.server_kind {
-            use std::thread;
-            let bridge = bridge.clone();
-            let cfg = cfg.clone();
-            let kind = server_kind.clone();
-            let thr_handle = thread::spawn(move || {
-                match kind {
-                    ServerKind::GDB => server::gdb_server(cfg, bridge),
-                    ServerKind::Wishbone => server::wishbone_server(cfg, bridge),
-                    ServerKind::RandomTest => server::random_test(cfg, bridge),
-                    ServerKind::LoadFile => server::load_file(cfg, bridge),
-                    ServerKind::Terminal => server::terminal_client(cfg, bridge),
-                    ServerKind::MemoryAccess => server::memory_access(cfg, bridge),
-                    ServerKind::Messible => server::messible_client(cfg, bridge),
-                }
-            });
-            threads.push(thr_handle);
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .help("CSV file listing load and dump operations to perform")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("state-file")
+                .long("state-file")
+                .help("CSV file to save CSR state to, or restore it from")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("golden")
+                .long("golden")
+                .value_name("dump.json")
+                .help("known-good CSR dump for state-save to compare against, recorded if it doesn't exist yet")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("bench-iterations")
+                .long("bench-iterations")
+                .help("number of times to repeat each gdb-bench measurement")
+                .default_value("10")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("coverage-file")
+                .long("coverage-file")
+                .help("file to write executed addresses to")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("coverage-mode")
+                .long("coverage-mode")
+                .help("record coverage by single-stepping, or by sampling the PC")
+                .default_value("step")
+                .possible_values(&["step", "sample"])
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("coverage-size")
+                .long("coverage-size")
+                .help("number of bytes after --address to record coverage for")
+                .default_value("0x10000")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("coverage-samples")
+                .long("coverage-samples")
+                .help("number of steps or samples to take before stopping")
+                .default_value("100000")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("expect")
+                .long("expect")
+                .help("text the console must print when using \"run\", in order")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .help("report each \"run\" --expect pattern as a test case")
+                .possible_values(&["tap", "junit"])
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("dtb-file")
+                .long("dtb-file")
+                .help("file for gen-dtb to write, as a blob if it ends in .dtb and as source otherwise (default: source on stdout)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("pac-file")
+                .long("pac-file")
+                .help("file for gen-pac to write, as SVD if it ends in .svd and as Rust otherwise (default: Rust on stdout)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("docs-file")
+                .long("docs-file")
+                .help("file for gen-docs to write, as HTML if it ends in .html and as Markdown otherwise (default: Markdown on stdout)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("etherbone-packet")
+                .long("etherbone-packet")
+                .help("file of hex bytes for etherbone-send to send, or etherbone-decode to decode, with # starting a comment")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("report-file")
+                .long("report-file")
+                .help("file to write the --report to, instead of stdout")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .help("how long \"run\" waits for the expected text, or \"dma\" for the engine to finish (e.g. 10s or 500ms)")
+                .default_value("10s")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("ecc-clear")
+                .long("ecc-clear")
+                .help("reset the ECC error counters after reading them")
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("ecc-scrub")
+                .long("ecc-scrub")
+                .help("start an ECC scrub of memory after reading the counters")
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("phy")
+                .long("phy")
+                .value_name("NAME")
+                .help("only run \"phy-test\" on this PHY, rather than all of them")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("prbs")
+                .long("prbs")
+                .help("PRBS pattern for \"phy-test\" to send and check")
+                .possible_values(&["7", "15", "31"])
+                .default_value("7")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("phy-loopback")
+                .long("phy-loopback")
+                .value_name("MODE")
+                .help("value for \"phy-test\" to put in the PHY's loopback register while it runs")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("phy-duration")
+                .long("phy-duration")
+                .help("how long \"phy-test\" counts errors for (e.g. 10s or 2m)")
+                .default_value("10s")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("phy-rate")
+                .long("phy-rate")
+                .value_name("BITS/S")
+                .help("line rate, for \"phy-test\" to work out the bit error rate (e.g. 5G or 1250M)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("endurance-target")
+                .long("endurance-target")
+                .help("whether \"endurance\" cycles RAM, or SPI flash through the spiflash CSRs")
+                .possible_values(&["ram", "flash"])
+                .default_value("ram")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("endurance-region")
+                .long("endurance-region")
+                .value_name("ADDRESS:LENGTH|REGION")
+                .help("what \"endurance\" cycles: an address and length, an offset and length into flash, or a memory region by name")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("endurance-duration")
+                .long("endurance-duration")
+                .help("how long \"endurance\" runs for (e.g. 30m or 48h)")
+                .default_value("1h")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("endurance-interval")
+                .long("endurance-interval")
+                .help("how often \"endurance\" logs its error count and sensors")
+                .default_value("1m")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("endurance-sensor")
+                .long("endurance-sensor")
+                .value_name("REGISTER")
+                .help("a register for \"endurance\" to log, rather than every *_temperature CSR (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("stress-region")
+                .long("stress-region")
+                .value_name("ADDRESS:LENGTH|REGION")
+                .help("RAM that \"stress\" may read and write, as an address and length or a memory region by name (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("stress-seed")
+                .long("stress-seed")
+                .value_name("SEED")
+                .help("seed for \"stress\", to make the same accesses as a run that failed (default: a random one, which is printed)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("stress-ops")
+                .long("stress-ops")
+                .value_name("COUNT")
+                .help("how many accesses \"stress\" makes (default: until one fails)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("svd")
+                .long("svd")
+                .value_name("FILE")
+                .help("SVD file describing the registers and their fields, for \"tui\" (default: the registers in --csr-csv)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("calc")
+                .long("calc")
+                .value_name("[NAME=]EXPRESSION")
+                .help("work out an expression of CSRs and constants, e.g. freq=timer0_value/10 (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("dma-engine")
+                .long("dma-engine")
+                .value_name("NAME")
+                .help("scatter-gather engine for \"dma\" to run, if csr.csv has more than one")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("dma-descriptors")
+                .long("dma-descriptors")
+                .value_name("ADDRESS")
+                .help("target memory for \"dma\" to build its descriptor chain in")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("dma-segment")
+                .long("dma-segment")
+                .value_name("ADDRESS:LENGTH")
+                .help("region of memory for the DMA engine to move (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .value_name("REGISTER")
+                .help("register (name or address) for \"watch\" to poll")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch-ecc")
+                .long("watch-ecc")
+                .help("also watch ECC error counters, and raise an alert when they go up")
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch-interval")
+                .long("watch-interval")
+                .help("how often \"watch\" polls its registers (e.g. 1s or 250ms)")
+                .default_value("1s")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch-threshold")
+                .long("watch-threshold")
+                .value_name("REGISTER>VALUE")
+                .help("raise an alert when a watched register goes above (>) or below (<) a value")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch-alert")
+                .long("watch-alert")
+                .help("shell command to run on an alert, with $WATCH_REGISTER, $WATCH_VALUE, and $WATCH_LIMIT set")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch-ema-alpha")
+                .long("watch-ema-alpha")
+                .help("weight of each new sample in the moving average")
+                .default_value("0.1")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch-count")
+                .long("watch-count")
+                .help("number of times \"watch\" polls before printing statistics and exiting, or 0 for forever")
+                .default_value("0")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("watch-scope")
+                .long("watch-scope")
+                .help("start a LiteScope capture when a threshold is crossed (arm), or report the watched registers when LiteScope triggers (follow)")
+                .possible_values(&["arm", "follow"])
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("mqtt-broker")
+                .long("mqtt-broker")
+                .value_name("HOST[:PORT]")
+                .help("publish every watched value to this MQTT broker")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("mqtt-topic")
+                .long("mqtt-topic")
+                .value_name("TOPIC")
+                .help("topic to publish watched values on, where {register} is replaced with the register name")
+                .default_value("wishbone/{register}")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("mqtt-client-id")
+                .long("mqtt-client-id")
+                .value_name("ID")
+                .help("client ID to give the MQTT broker, which defaults to one based on the process ID")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("scope-offset")
+                .long("scope-offset")
+                .help("number of LiteScope samples to keep from before the trigger")
+                .default_value("16")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("scope-length")
+                .long("scope-length")
+                .help("number of samples in each LiteScope capture")
+                .default_value("128")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("scope-file")
+                .long("scope-file")
+                .help("CSV file to append LiteScope captures to")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("pulse")
+                .long("pulse")
+                .value_name("REGISTER:BIT")
+                .help("set a bit, then clear it again after --duration")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .help("how long --pulse holds its bit set (e.g. 5ms)")
+                .default_value("1ms")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("waveform")
+                .long("waveform")
+                .help("CSV file of TIME,VALUE pairs to write to --waveform-register")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("sequence")
+                .long("sequence")
+                .value_name("FILE")
+                .help("CSV file of steps to run on this board (a) and the --peer (b), with barriers between them")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("peer")
+                .long("peer")
+                .value_name("BRIDGE")
+                .help("second board for --sequence: usb:PID, uart:PORT[:BAUD], ethernet:HOST[:PORT], or sim")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("peer-csr-csv")
+                .long("peer-csr-csv")
+                .value_name("CSR_CSV")
+                .help("csr.csv for the --peer, so its sequence steps can name registers")
+                .requires("peer")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("waveform-register")
+                .long("waveform-register")
+                .value_name("REGISTER")
+                .help("register (name or address) that --waveform updates")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("waveform-loops")
+                .long("waveform-loops")
+                .help("number of times to play --waveform, or 0 to repeat forever")
+                .default_value("1")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("flash-offset")
+                .long("flash-offset")
+                .help("offset into SPI flash to write --load-name to")
+                .default_value("0")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("csr-data-width")
+                .long("csr-data-width")
+                .value_name("BITS")
+                .help("CSR bus width the SoC was built with, if the csr.csv doesn't make it clear (8, 16, or 32)")
+                .takes_value(true)
+                .display_order(6),
+        )
+        .arg(
+            Arg::with_name("csr-ordering")
+                .long("csr-ordering")
+                .value_name("ORDER")
+                .help("which word of a multi-word CSR comes first, if the csr.csv doesn't say")
+                .possible_values(&["big", "little"])
+                .takes_value(true)
+                .display_order(6),
+        )
+        .arg(
+            Arg::with_name("memory-map")
+                .long("memory-map")
+                .help("CSV of name,base,size,policy,type regions, where policy 'word' means only aligned 32-bit accesses are safe, and type is ram, rom or flash for GDB's memory map")
+                .takes_value(true)
+                .display_order(6),
+        )
+        .arg(
+            Arg::with_name("flash-layout")
+                .long("flash-layout")
+                .help("TOML file describing the partitions in SPI flash")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("partition")
+                .long("partition")
+                .help("name of a --flash-layout partition to write to, instead of --flash-offset")
+                .requires("flash-layout")
+                .conflicts_with("flash-offset")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("signature-manifest")
+                .long("signature-manifest")
+                .help("sha256sum-style manifest that images must be listed in before they're flashed")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("signing-key")
+                .long("signing-key")
+                .help("ed25519 public key that must have signed the manifest (MANIFEST.sig)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("otp-base")
+                .long("otp-base")
+                .help("address (or name) of the first word of the OTP/eFuse block")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("otp-words")
+                .long("otp-words")
+                .help("number of 32-bit words in the OTP block")
+                .default_value("16")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("otp-write")
+                .long("otp-write")
+                .value_name("WORD:VALUE")
+                .help("permanently burn VALUE into an OTP word (needs --confirm-otp-write)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("confirm-otp-write")
+                .long("confirm-otp-write")
+                .value_name("WORD:VALUE")
+                .help("the same WORD:VALUE as --otp-write, to confirm it")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("otp-audit-log")
+                .long("otp-audit-log")
+                .help("file to record every OTP write in")
+                .default_value("otp-audit.log")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("time-sync-addr")
+                .long("time-sync-addr")
+                .help("address (or name) of a 64-bit word to write the host's time to, in microseconds since the epoch")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("time-sync-samples")
+                .long("time-sync-samples")
+                .help("number of round trips to time before writing the time")
+                .default_value("16")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("tap-base")
+                .long("tap-base")
+                .help("address (or name) of the firmware's packet rings, for the tap server")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("tap-name")
+                .long("tap-name")
+                .help("host TUN/TAP interface to pass packets through")
+                .default_value("wbtap%d")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("tun")
+                .long("tun")
+                .help("pass IP packets through a TUN interface, rather than Ethernet frames through a TAP")
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("random-loops")
+                .long("random-loops")
+                .help("number of loops to run when doing a random-test")
+                .display_order(9)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("random-range")
+                .long("random-range")
+                .help("the size of the random address range (i.e. how many bytes to randomly add to the address)")
+                .display_order(9)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("messible-address")
+                .long("messible-address")
+                .help("address to use to get messible messages from")
+                .display_order(9)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("csr-csv")
+                .long("csr-csv")
+                .help("csr.csv file containing register mappings")
+                .display_order(9)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("random-address")
+                .long("random-address")
+                .help("address to write to when doing a random-test")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug-offset")
+                .long("debug-offset")
+                .help("address to use for debug bridge")
+                .default_value("0xf00f0000")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reset-vector")
+                .long("reset-vector")
+                .help("address the CPU should be at after a \"reset halt\"")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reset-settle")
+                .long("reset-settle")
+                .help("milliseconds to let the SoC settle after a reset")
+                .default_value("10")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("linux-offsets")
+                .long("linux-offsets")
+                .help("CSV file of Linux task_struct offsets, to show kernel tasks as GDB threads")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("write-combine")
+                .long("write-combine")
+                .value_name("LATENCY")
+                .help("in the terminal, send keystrokes in batches and poll the UART less, waiting at most this long (e.g. 20ms)")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("send-file")
+                .long("send-file")
+                .value_name("FILE")
+                .help("in the terminal, send this file to the firmware when Ctrl-S is pressed")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("receive-file")
+                .long("receive-file")
+                .value_name("FILE")
+                .help("in the terminal, save the file the firmware sends after Ctrl-R is pressed")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("file-protocol")
+                .long("file-protocol")
+                .value_name("PROTOCOL")
+                .help("protocol to use for terminal file transfers")
+                .possible_values(&["xmodem", "ymodem"])
+                .default_value("xmodem")
+                .takes_value(true)
+                .display_order(13),
+        )
+        .arg(
+            Arg::with_name("gdb-prefetch")
+                .long("gdb-prefetch")
+                .help("read ahead of GDB during backtraces and structure reads, in memory regions that aren't io")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("gdb-record")
+                .long("gdb-record")
+                .value_name("STEPS")
+                .help("record the last STEPS single steps, so GDB can reverse-step and reverse-continue through them")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gdb-disasm")
+                .long("gdb-disasm")
+                .help("show the instructions around the pc in GDB each time the CPU stops")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .help("read back everything that's written by a poke, load, or state restore, and report what doesn't match")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("no-cache")
+                .long("no-cache")
+                .help("probe the CPU again rather than reusing what was found last time this SoC was seen")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("gdb-console")
+                .long("gdb-console")
+                .help("show the firmware's console in GDB, with timestamps, alongside breakpoints")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("semihosting")
+                .long("semihosting")
+                .help("carry out the firmware's semihosting calls, printing its output here, rather than stopping on them")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("gdb-fileio")
+                .long("gdb-fileio")
+                .help("have GDB carry out the firmware's semihosting calls on files and the console, so they use GDB's files and console (implies --semihosting)")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("pseudo-register")
+                .long("pseudo-register")
+                .value_name("NAME=EXPRESSION")
+                .help("show GDB a register worked out from others, e.g. uptime_us=mcycle/(clock/1000000) (may be repeated)")
+                .multiple(true)
+                .number_of_values(1)
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("custom-csrs")
+                .long("custom-csrs")
+                .value_name("FILE")
+                .help("JSON file of CSRs outside the spec for GDB to show, and other names for registers")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("clock-frequency")
+                .long("clock-frequency")
+                .value_name("HZ")
+                .help("CPU clock for pseudo-register expressions, if the csr.csv doesn't say")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .help("print a summary of the traffic each operation generated")
+                .possible_values(&["json"])
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("footguns")
+                .long("footguns")
+                .value_name("POLICY")
+                .help("what to do about writes that would upset another server, such as resetting the SoC under a halted GDB session: queue holds them until GDB resumes the CPU")
+                .possible_values(&["off", "warn", "block", "queue"])
+                .default_value("warn")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("go ahead with writes that --footguns would refuse or hold, warning about them instead")
+                .display_order(11),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .value_name("FILE")
+                .help("append each command and what it did to FILE, which can be run again with sh")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-out")
+                .long("trace-out")
+                .value_name("FILE")
+                .help("record where the time goes, and write it to FILE in Chrome trace format as it happens")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("syslog")
+                .long("syslog")
+                .value_name("HOST[:PORT]")
+                .help("also send the log, and what the firmware prints, to this syslog host over UDP, giving an IPv6 address with a port as [ADDR]:PORT")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-dir")
+                .long("log-dir")
+                .value_name("DIR")
+                .help("also capture what the firmware prints to console.log in DIR, setting it aside and gzipping it as it grows")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-max-size")
+                .long("log-max-size")
+                .value_name("SIZE")
+                .help("set --log-dir's console.log aside once it's this big, e.g. 64M (the default)")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-max-age")
+                .long("log-max-age")
+                .value_name("DURATION")
+                .help("set --log-dir's console.log aside once it's this old, e.g. 24h")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("syslog-format")
+                .long("syslog-format")
+                .help("send --syslog lines as RFC 5424 syslog, or as JSON objects")
+                .possible_values(&["syslog", "json"])
+                .default_value("syslog")
+                .display_order(11)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug-backend")
+                .long("debug-backend")
+                .help("debug interface exposed by the CPU: the VexRiscv debug bus, or a RISC-V Debug Module")
+                .default_value("vexriscv")
+                .possible_values(&["vexriscv", "dmi"])
+                .display_order(11)
+                .takes_value(true),
+        )
+}
+
+fn main() {
+    flexi_logger::Logger::with_env_or_str("wishbone_tool=info")
+        .format(syslog::format)
+        .start()
+        .unwrap();
+    let matches = clap_app().get_matches();
+
+    if matches.is_present("list") {
+        let boards = BoardRegistry::load(matches.value_of("boards")).unwrap_or_default();
+        let registers = Config::parse_csr_csv(matches.value_of("csr-csv"))
+            .map(|(_, r)| r)
+            .unwrap_or_default();
+        let pid = matches.value_of("pid").and_then(|p| config::parse_u16(p).ok());
+        if list_usb(&boards, boards::dna_register(&registers), pid).is_err() {
+            println!("USB is not properly configured");
+        };
+        return;
+    }
+
+    if let Some(shell_str) = matches.value_of("completion") {
+        use std::io;
+        use std::str::FromStr;
+        let shell = match Shell::from_str(shell_str) {
+            Ok(s) => s,
+            Err(_) => panic!("Unrecognized shell"),
+        };
+        clap_app().gen_completions_to(
+            "wishbone-tool",
+            shell,
+            &mut io::stdout()
+        );
+        return;
+    }
+
+    let mut cfg = match Config::parse(matches) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            match e {
+                config::ConfigError::NumberParseError(num, e) => {
+                    error!("unable to parse the number \"{}\": {}", num, e)
+                }
+                config::ConfigError::NoOperationSpecified => panic!("no operation was specified"),
+                config::ConfigError::UnknownServerKind(s) => {
+                    error!("unknown server '{}', see --help", s)
+                }
+                config::ConfigError::SpiParseError(s) => error!("couldn't parse spi pins: {}", s),
+                config::ConfigError::IoError(s) => error!("file error: {}", s),
+                config::ConfigError::InvalidConfig(s) => error!("invalid configuration: {}", s),
+            }
+            process::exit(1);
+        }
+    };
+
+    if let Some(file_name) = &cfg.trace_out {
+        if let Err(e) = trace::enable(file_name) {
+            error!("couldn't write trace to {}: {}", file_name, e);
+            process::exit(1);
+        }
+    }
+    if let Some(endpoint) = &cfg.syslog {
+        if let Err(e) = syslog::install(endpoint, cfg.syslog_format) {
+            error!("couldn't send to syslog host {}: {}", endpoint, e);
+            process::exit(1);
+        }
+    }
+    if let Some(dir) = &cfg.log_dir {
+        if let Err(e) = consolelog::install(Path::new(dir), cfg.log_rotation.clone()) {
+            error!("couldn't capture the console to {}: {}", dir, e);
+            process::exit(1);
+        }
+    }
+
+    // Run the generators first, since they don't need a device
+    for kind in cfg.server_kind.iter().filter(|k| !k.needs_bridge()) {
+        let result = match kind {
+            ServerKind::GenDtb => server::gen_dtb(cfg.clone()),
+            ServerKind::GenPac => server::gen_pac(cfg.clone()),
+            ServerKind::GenDocs => server::gen_docs(cfg.clone()),
+            ServerKind::EtherboneSend => server::etherbone_send(cfg.clone()),
+            ServerKind::EtherboneDecode => server::etherbone_decode(cfg.clone()),
+            _ => unreachable!(),
+        };
+        if let Err(e) = result {
+            error!("{} failed: {:?}", kind.name(), e);
+            process::exit(1);
+        }
+    }
+    cfg.server_kind.retain(|k| k.needs_bridge());
+    if cfg.server_kind.is_empty() {
+        return;
+    }
+
+    if let Some(label) = cfg.target.clone() {
+        // unwrap() is safe because the config checks the label exists
+        let id = cfg.boards.id(&label).unwrap().to_owned();
+        match boards::find_usb_board(&cfg, &id) {
+            Some((bus, device)) => {
+                info!("found {} at usb {:03}/{:03}", label, bus, device);
+                cfg.usb_bus = Some(bus);
+                cfg.usb_device = Some(device);
+            }
+            None => {
+                error!("couldn't find a board labelled {} (id {})", label, id);
+                process::exit(1);
+            }
+        }
+    }
+
+    let _lock = match lock::acquire(&cfg, cfg.wait_for_lock) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    {
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        if cfg.probe {
+            // A scratch register that doesn't read back might just be one
+            // the check doesn't understand, so only a bridge that never
+            // answers is fatal.
+            match bridge.health_check(&cfg) {
+                Ok(()) => (),
+                Err(e @ ProbeError::WishboneTimeout(..)) => {
+                    error!("bridge health check failed: {}", e);
+                    process::exit(1);
+                }
+                Err(e) => warn!("bridge health check failed: {}", e),
+            }
+        }
+        if cfg.read_board_id {
+            if let Ok(Some(id)) = boards::board_id(&cfg, &bridge) {
+                syslog::set_board(&id, cfg.boards.label(&id));
+                if !cfg.boards.is_empty() {
+                    info!("board id {} ({})", id, cfg.boards.label(&id).unwrap_or("unregistered"));
+                }
+            }
+        }
+        let start = Instant::now();
+        let mut threads = vec![];
+        for server_kind in &cfg.server_kind {
+            use std::thread;
+            let bridge = bridge.with_new_stats();
+            let stats = bridge.stats().clone();
+            let cfg = cfg.clone();
+            let kind = server_kind.clone();
+            let name = kind.name();
+            let flusher = bridge.clone();
+            let thr_handle = thread::spawn(move || {
+                let op_start = Instant::now();
+                let _span = trace::span("server", name);
+                let result = match kind {
+                    ServerKind::GDB => server::gdb_server(cfg, bridge),
+                    ServerKind::Wishbone => server::wishbone_server(cfg, bridge),
+                    ServerKind::RandomTest => server::random_test(cfg, bridge),
+                    ServerKind::LoadFile => server::load_file(cfg, bridge),
+                    ServerKind::Terminal => server::terminal_client(cfg, bridge),
+                    ServerKind::MemoryAccess => server::memory_access(cfg, bridge),
+                    ServerKind::Messible => server::messible_client(cfg, bridge),
+                    ServerKind::StateSave => server::state_save(cfg, bridge),
+                    ServerKind::StateRestore => server::state_restore(cfg, bridge),
+                    ServerKind::GdbBench => server::gdb_bench(cfg, bridge),
+                    ServerKind::Coverage => server::coverage(cfg, bridge),
+                    ServerKind::Run => server::run_firmware(cfg, bridge),
+                    ServerKind::SerialBoot => server::serial_boot(cfg, bridge),
+                    ServerKind::Transfer => server::transfer(cfg, bridge),
+                    ServerKind::Ecc => server::ecc(cfg, bridge),
+                    ServerKind::Dma => server::dma(cfg, bridge),
+                    ServerKind::PhyTest => server::phy_test(cfg, bridge),
+                    ServerKind::Calc => server::calc(cfg, bridge),
+                    ServerKind::Endurance => server::endurance(cfg, bridge),
+                    ServerKind::Stress => server::stress(cfg, bridge),
+                    ServerKind::Watch => server::watch(cfg, bridge),
+                    ServerKind::Pulse => server::pulse(cfg, bridge),
+                    ServerKind::Waveform => server::waveform(cfg, bridge),
+                    ServerKind::Sequence => server::sequence(cfg, bridge),
+                    ServerKind::Flash => server::flash(cfg, bridge),
+                    ServerKind::FlashVerifySignature => server::flash_verify_signature(cfg, bridge),
+                    ServerKind::Otp => server::otp(cfg, bridge),
+                    ServerKind::Tap => server::tap_server(cfg, bridge),
+                    ServerKind::TimeSync => server::time_sync(cfg, bridge),
+                    ServerKind::Tui => server::tui(cfg, bridge),
+                    ServerKind::GenDtb
+                    | ServerKind::GenPac
+                    | ServerKind::GenDocs
+                    | ServerKind::EtherboneSend
+                    | ServerKind::EtherboneDecode => unreachable!(),
+                };
+                // Posted writes still count towards this server
+                let result = result.and_then(|()| Ok(flusher.flush()?));
+                (result, op_start.elapsed())
+            });
+            threads.push((name, stats, thr_handle));
+        }
+        let mut retcode = 0;
+        let mut operations = vec![];
+        for (name, stats, handle) in threads {
+            let (succeeded, elapsed) = match handle.join() {
+                Ok((Ok(()), elapsed)) => (true, elapsed),
+                Ok((Err(e), elapsed)) => {
+                    error!("server error: {:?}", e);
+                    (false, elapsed)
+                }
+                Err(_) => (false, start.elapsed()),
+            };
+            if !succeeded {
+                retcode = 1;
+            }
+            operations.push(summary::OperationSummary {
+                name: name.to_owned(),
+                elapsed,
+                stats,
+                succeeded,
+            });
+        }
+        if cfg.summary_json {
+            println!("{}", summary::to_json(start.elapsed(), &operations));
+        }
+        if let Some(journal) = &cfg.journal {
+            let args: Vec<String> = std::env::args().collect();
+            if let Err(e) = journal.record(&args, &operations) {
+                error!("couldn't write to the journal: {}", e);
+            }
+        }
+        if let Some(file_name) = &cfg.trace_out {
+            match trace::finish() {
+                Ok(count) => info!("wrote {} spans to {}", count, file_name),
+                Err(e) => error!("couldn't write trace to {}: {}", file_name, e),
+            }
         }
-        for handle in threads {
-            handle.join().ok();
+        if retcode != 0 {
+            process::exit(retcode);
         }
     };
-    // if let Err(e) = retcode {
-    //     error!("server error: {:?}", e);
-    //     process::exit(1);
-    // }
 }
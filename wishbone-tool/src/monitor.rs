@@ -0,0 +1,504 @@
+//! The `monitor` commands that GDB passes on with `qRcmd`.  A command is
+//! picked by the first word of what was typed and given the rest, so a
+//! subsystem with something to offer can register a `MonitorCommand` of
+//! its own rather than the GDB server having to parse it.
+
+use crate::bridge::{Bridge, BridgeKind};
+use crate::config::{parse_u32, parse_u64, CsrMode, CsrRegister};
+use crate::spiflash::SpiFlash;
+use crate::footgun::FootgunGuard;
+use crate::gdb::GdbServerError;
+use crate::riscv::pseudo::{self, PseudoRegister};
+use crate::riscv::record::Recorder;
+use crate::riscv::{pmp, RiscvSystem, RiscvCpuError};
+
+/// How many instructions `monitor disasm` shows if it isn't told
+const DISASM_COUNT: usize = 8;
+
+/// What a command has to work with, and where its output goes
+pub struct MonitorContext<'a> {
+    pub cpu: &'a RiscvSystem,
+    pub bridge: &'a Bridge,
+
+    /// The CSRs from csr.csv
+    pub csrs: &'a [CsrRegister],
+    pub footguns: &'a FootgunGuard,
+
+    /// The CPU clock in Hz, for `clock` in expressions
+    pub clock: Option<u64>,
+    pub recorder: &'a mut Option<Recorder>,
+
+    /// What's been printed so far, for GDB's console
+    output: String,
+}
+
+impl<'a> MonitorContext<'a> {
+    pub fn new(
+        cpu: &'a RiscvSystem,
+        bridge: &'a Bridge,
+        csrs: &'a [CsrRegister],
+        footguns: &'a FootgunGuard,
+        clock: Option<u64>,
+        recorder: &'a mut Option<Recorder>,
+    ) -> MonitorContext<'a> {
+        MonitorContext {
+            cpu,
+            bridge,
+            csrs,
+            footguns,
+            clock,
+            recorder,
+            output: String::new(),
+        }
+    }
+
+    pub fn print(&mut self, text: &str) {
+        self.output.push_str(text);
+    }
+
+    /// Throw away the recorded steps, which stop making sense once the
+    /// CPU has been reset
+    pub fn forget_recording(&mut self) {
+        if let Some(recorder) = self.recorder {
+            recorder.clear();
+        }
+    }
+
+    pub fn into_output(self) -> String {
+        self.output
+    }
+}
+
+pub trait MonitorCommand {
+    /// The first word of the command
+    fn name(&self) -> &str;
+
+    /// What can follow the name, as shown in the help
+    fn usage(&self) -> &str {
+        ""
+    }
+
+    /// What the command does, in a line
+    fn help(&self) -> &str;
+
+    /// Carry out the command, given whatever was typed after its name
+    fn run(&self, ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError>;
+}
+
+type Handler = fn(&mut MonitorContext, &str) -> Result<(), GdbServerError>;
+
+/// One of the commands that are always there
+struct Builtin {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: Handler,
+}
+
+impl MonitorCommand for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn usage(&self) -> &str {
+        self.usage
+    }
+
+    fn help(&self) -> &str {
+        self.help
+    }
+
+    fn run(&self, ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+        (self.run)(ctx, args)
+    }
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin { name: "about", usage: "", help: "Information about the bridge", run: about },
+    Builtin { name: "breakpoints", usage: "", help: "Count the breakpoints in use", run: breakpoints },
+    Builtin {
+        name: "calc",
+        usage: "EXPRESSION",
+        help: "Work out an expression of registers, e.g. calc mcycle/1000",
+        run: calc,
+    },
+    Builtin {
+        name: "bridge",
+        usage: "[switch usb|ethernet|stats]",
+        help: "Show or change how the device is reached, or count the traffic",
+        run: bridge,
+    },
+    Builtin { name: "cause", usage: "", help: "Decode mcause, mtval and mepc", run: cause },
+    Builtin {
+        name: "csr",
+        usage: "read|write CSR [VALUE]",
+        help: "Read or write a CSR from csr.csv, by name or address",
+        run: csr,
+    },
+    Builtin {
+        name: "disasm",
+        usage: "[ADDRESS [COUNT]]",
+        help: "Disassemble from ADDRESS, or around the pc",
+        run: disasm,
+    },
+    Builtin { name: "explain", usage: "", help: "Explain what the CPU is doing", run: explain },
+    Builtin {
+        name: "mmu",
+        usage: "[on|off|ADDRESS]",
+        help: "Show paging, translate ADDRESS, or use physical addresses",
+        run: mmu,
+    },
+    Builtin {
+        name: "pmp",
+        usage: "[ADDRESS]",
+        help: "List the PMP regions, or say which covers ADDRESS",
+        run: pmp,
+    },
+    Builtin {
+        name: "record",
+        usage: "[clear]",
+        help: "Show or throw away the steps recorded for reverse-step",
+        run: record,
+    },
+    Builtin {
+        name: "reset",
+        usage: "[halt|run]",
+        help: "Reset the CPU, or the SoC and halt at the reset vector or start the firmware again",
+        run: reset,
+    },
+];
+
+/// The commands that have been registered, on top of the built-in ones
+#[derive(Default)]
+pub struct MonitorRegistry {
+    commands: Vec<Box<dyn MonitorCommand>>,
+}
+
+impl MonitorRegistry {
+    /// Add `command`, in place of any built-in or earlier command with the
+    /// same name
+    pub fn register(&mut self, command: Box<dyn MonitorCommand>) {
+        self.commands.retain(|c| c.name() != command.name());
+        self.commands.push(command);
+    }
+
+    fn find(&self, name: &str) -> Option<&dyn MonitorCommand> {
+        match self.commands.iter().find(|c| c.name() == name) {
+            Some(command) => Some(command.as_ref()),
+            None => BUILTINS.iter().find(|c| c.name == name).map(|c| c as &dyn MonitorCommand),
+        }
+    }
+
+    /// Carry out `line` as it was typed after `monitor`.  `help`, or
+    /// anything that isn't a command, lists them.
+    pub fn run(&self, ctx: &mut MonitorContext, line: &str) -> Result<(), GdbServerError> {
+        let line = line.trim();
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        if let Some(command) = self.find(name) {
+            return command.run(ctx, args.trim());
+        }
+        if name != "help" {
+            ctx.print("Unrecognized monitor command.  ");
+        }
+        ctx.print("Available commands:\n");
+        ctx.print(&self.listing());
+        Ok(())
+    }
+
+    fn listing(&self) -> String {
+        let mut lines: Vec<(String, &str)> = BUILTINS
+            .iter()
+            .filter(|b| self.commands.iter().all(|c| c.name() != b.name))
+            .map(|b| b as &dyn MonitorCommand)
+            .chain(self.commands.iter().map(|c| c.as_ref()))
+            .map(|c| (format!("{} {}", c.name(), c.usage()).trim_end().to_owned(), c.help()))
+            .collect();
+        lines.push(("help".to_owned(), "List the commands"));
+        lines.sort();
+        let mut text = String::new();
+        for (usage, help) in lines {
+            text.push_str(&format!("    {:<15} - {}\n", usage, help));
+        }
+        text
+    }
+}
+
+fn about(ctx: &mut MonitorContext, _args: &str) -> Result<(), GdbServerError> {
+    ctx.print("VexRiscv GDB bridge\n");
+    Ok(())
+}
+
+fn breakpoints(ctx: &mut MonitorContext, _args: &str) -> Result<(), GdbServerError> {
+    let (in_use, count) = ctx.cpu.hardware_breakpoints();
+    let soft = ctx.cpu.soft_breakpoints();
+    ctx.print(&format!("{} of {} hardware breakpoints in use\n", in_use, count));
+    ctx.print(&format!("{} software breakpoints\n", soft));
+    Ok(())
+}
+
+fn calc(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    let result = PseudoRegister::from_string(&format!("calc={}", args), ctx.clock)
+        .map_err(|e| format!("Bad expression: {}", e))
+        .and_then(|expr| ctx.cpu.evaluate(ctx.bridge, &expr).map_err(|e| e.to_string()));
+    match result {
+        Ok(value) => ctx.print(&format!("{}\n", pseudo::describe(value))),
+        Err(e) => ctx.print(&format!("{}\n", e)),
+    }
+    Ok(())
+}
+
+fn bridge(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    let bridge = ctx.bridge;
+    if args.is_empty() {
+        ctx.print(&format!("Connected over {}\n", bridge.kind_name()));
+    } else if args == "stats" {
+        let stats = bridge.stats();
+        ctx.print(&format!(
+            "{} reads, {} writes, {} retries, {} reads outvoted by --deglitch\n",
+            stats.reads(),
+            stats.writes(),
+            stats.retries(),
+            stats.glitches()
+        ));
+    } else if let Some(name) = args.strip_prefix("switch ") {
+        let name = name.trim();
+        match BridgeKind::from_string(name) {
+            Some(kind @ BridgeKind::UsbBridge) | Some(kind @ BridgeKind::EthernetBridge) => match bridge.switch(kind) {
+                Ok(()) => ctx.print(&format!("Now connected over {}\n", name)),
+                Err(e) => ctx.print(&format!("Unable to switch to {}: {}\n", name, e)),
+            },
+            _ => ctx.print("Only usb and ethernet bridges can be switched to\n"),
+        }
+    } else {
+        ctx.print("bridge: usage is bridge [switch usb|ethernet|stats]\n");
+    }
+    Ok(())
+}
+
+fn cause(ctx: &mut MonitorContext, _args: &str) -> Result<(), GdbServerError> {
+    let text = ctx.cpu.cause(ctx.bridge)?;
+    ctx.print(&text);
+    Ok(())
+}
+
+/// Read or write a CSR, which goes straight over the bridge, as CSRs
+/// aren't behind the MMU
+fn csr(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    const USAGE: &str = "csr: usage is csr read CSR or csr write CSR VALUE\n";
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (write, name, value) = match args.as_slice() {
+        ["read", name] => (false, *name, None),
+        ["write", name, value] => (true, *name, Some(*value)),
+        _ => {
+            ctx.print(USAGE);
+            return Ok(());
+        }
+    };
+    let reg = ctx.csrs.iter().find(|r| r.name == name);
+    let (addr, words) = match (reg, parse_u32(name)) {
+        (Some(reg), _) => (reg.address, reg.words),
+        (None, Ok(addr)) if addr & 3 == 0 => (addr, 1),
+        _ => {
+            ctx.print(&format!("{} isn't a CSR in csr.csv, or a word-aligned address\n", name));
+            return Ok(());
+        }
+    };
+    if !write {
+        let mut data = vec![];
+        for word in 0..words {
+            data.push(ctx.bridge.peek(addr + word * 4)?);
+        }
+        let value = match reg {
+            Some(reg) => reg.assemble(&data),
+            None => data[0] as u64,
+        };
+        ctx.print(&format!("{} at {:08x}: 0x{:x}\n", name, addr, value));
+        return Ok(());
+    }
+
+    let value = match value.map(parse_u64) {
+        Some(Ok(value)) => value,
+        _ => {
+            ctx.print(USAGE);
+            return Ok(());
+        }
+    };
+    if reg.map(|r| r.mode == CsrMode::ReadOnly).unwrap_or(false) {
+        ctx.print(&format!("{} is read-only\n", name));
+        return Ok(());
+    }
+    let values = match reg {
+        Some(reg) => reg.split(value),
+        None => vec![value as u32],
+    };
+    for (word, v) in values.iter().enumerate() {
+        let word_addr = addr + word as u32 * 4;
+        if !ctx.footguns.allow_write_now("GDB", word_addr) {
+            ctx.print(&format!("The write to {:08x} was refused\n", word_addr));
+            return Ok(());
+        }
+        ctx.bridge.poke(word_addr, *v)?;
+    }
+    ctx.print(&format!("{} at {:08x} set to 0x{:x}\n", name, addr, value));
+    Ok(())
+}
+
+fn disasm(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    let mut args = args.split_whitespace();
+    let addr = match args.next().map(|a| u32::from_str_radix(a.trim_start_matches("0x"), 16)) {
+        None => Ok(None),
+        Some(Ok(addr)) => Ok(Some(addr)),
+        Some(Err(_)) => Err("isn't a hex address"),
+    };
+    let count = match args.next().map(|c| c.parse::<usize>()) {
+        None => Ok(DISASM_COUNT),
+        Some(Ok(count)) if count > 0 => Ok(count),
+        Some(_) => Err("needs a count of at least one"),
+    };
+    match (addr, count) {
+        (Ok(addr), Ok(count)) => {
+            let text = ctx.cpu.disassemble(ctx.bridge, addr, count)?;
+            ctx.print(&text);
+        }
+        (Err(e), _) | (_, Err(e)) => ctx.print(&format!("disasm {}: usage is disasm [ADDRESS [COUNT]]\n", e)),
+    }
+    Ok(())
+}
+
+fn explain(ctx: &mut MonitorContext, _args: &str) -> Result<(), GdbServerError> {
+    let text = ctx.cpu.explain(ctx.bridge)?;
+    ctx.print(&text);
+    Ok(())
+}
+
+fn mmu(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    let cpu = ctx.cpu;
+    let text = match args {
+        "off" => {
+            cpu.set_physical_access(true);
+            "GDB's addresses are now physical\n".to_owned()
+        }
+        "on" => {
+            cpu.set_physical_access(false);
+            "GDB's addresses now go through the page tables while paging is on\n".to_owned()
+        }
+        "" => match cpu.current_satp(ctx.bridge)? {
+            None => "Paging is off, so addresses are physical\n".to_owned(),
+            Some(satp) => format!(
+                "Sv32 paging is on, satp 0x{:08x} (root table at 0x{:09x}), and GDB's addresses are {}\n",
+                satp,
+                ((satp & 0x003f_ffff) as u64) << 12,
+                if cpu.paging_enabled() { "virtual" } else { "physical (monitor mmu on to translate)" }
+            ),
+        },
+        arg => match u32::from_str_radix(arg.trim_start_matches("0x"), 16) {
+            Err(_) => format!("{} isn't a hex address\n", arg),
+            Ok(addr) => match cpu.translation(ctx.bridge, addr) {
+                Ok(Some(translation)) => format!("{}\n", translation.describe(addr)),
+                Ok(None) => "Paging is off, so addresses are physical\n".to_owned(),
+                Err(e @ RiscvCpuError::PageFault(_)) => format!("{}\n", e),
+                Err(e) => return Err(e.into()),
+            },
+        },
+    };
+    ctx.print(&text);
+    Ok(())
+}
+
+fn pmp(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    let addr = match args {
+        "" => Ok(None),
+        _ => u64::from_str_radix(args.trim_start_matches("0x"), 16).map(Some),
+    };
+    let regions = match addr {
+        Ok(_) => ctx.cpu.pmp_regions(ctx.bridge),
+        Err(_) => Ok(vec![]),
+    };
+    match (regions, addr) {
+        (_, Err(_)) => ctx.print(&format!("{} isn't a hex address\n", args)),
+        (Err(RiscvCpuError::UnknownRegister(_)), _) => ctx.print("This CPU doesn't seem to have PMP\n"),
+        (Err(e), _) => return Err(e.into()),
+        (Ok(regions), Ok(Some(addr))) => ctx.print(&pmp::explain(&regions, addr)),
+        (Ok(ref regions), Ok(None)) if regions.is_empty() => ctx.print("No PMP entries are on\n"),
+        (Ok(regions), Ok(None)) => {
+            for region in regions {
+                ctx.print(&format!("{}\n", region));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn record(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    if args == "clear" {
+        ctx.forget_recording();
+        ctx.print("Recorded steps thrown away\n");
+        return Ok(());
+    }
+    let text = match ctx.recorder {
+        Some(recorder) => format!("{} of up to {} steps recorded\n", recorder.len(), recorder.limit()),
+        None => "Steps aren't being recorded -- run with --gdb-record\n".to_owned(),
+    };
+    ctx.print(&text);
+    Ok(())
+}
+
+fn reset(ctx: &mut MonitorContext, args: &str) -> Result<(), GdbServerError> {
+    let halt = match args {
+        "" => {
+            ctx.print("Resetting CPU...\n");
+            ctx.cpu.reset_cpu(ctx.bridge)?;
+            return Ok(());
+        }
+        "halt" => true,
+        "run" => false,
+        _ => {
+            ctx.print("reset: usage is reset [halt|run]\n");
+            return Ok(());
+        }
+    };
+    ctx.print("Resetting SoC...\n");
+    ctx.forget_recording();
+    let text = match ctx.cpu.reset(ctx.bridge, halt) {
+        Ok(pc) if halt => format!("CPU halted at 0x{:08x}\n", pc),
+        Ok(pc) => format!(
+            "CPU started again from 0x{:08x}.  GDB still shows it as stopped, so continue to follow it\n",
+            pc
+        ),
+        Err(e @ RiscvCpuError::ResetVectorMismatch(_, _)) if halt => format!("Warning: {}\n", e),
+        Err(e @ RiscvCpuError::ResetVectorMismatch(_, _)) => format!("Warning: {}, so it's been left halted\n", e),
+        Err(e) => return Err(e.into()),
+    };
+    ctx.print(&text);
+    Ok(())
+}
+
+/// `monitor flash`, which says what flash chip is fitted
+pub struct FlashMonitor {
+    spiflash: SpiFlash,
+}
+
+impl FlashMonitor {
+    pub fn new(spiflash: SpiFlash) -> FlashMonitor {
+        FlashMonitor { spiflash }
+    }
+}
+
+impl MonitorCommand for FlashMonitor {
+    fn name(&self) -> &str {
+        "flash"
+    }
+
+    fn help(&self) -> &str {
+        "Show the SPI flash's JEDEC and unique IDs"
+    }
+
+    fn run(&self, ctx: &mut MonitorContext, _args: &str) -> Result<(), GdbServerError> {
+        let ids = self.spiflash.id(ctx.bridge).and_then(|id| Ok((id, self.spiflash.unique_id(ctx.bridge)?)));
+        match ids {
+            Ok((id, unique)) => ctx.print(&format!("JEDEC ID {:02x?}, unique ID {:02x?}\n", id, unique)),
+            Err(e) => ctx.print(&format!("Unable to read the flash: {:?}\n", e)),
+        }
+        Ok(())
+    }
+}
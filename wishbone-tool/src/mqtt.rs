@@ -0,0 +1,164 @@
+//! Just enough MQTT 3.1.1 to publish watched values to a broker.  Every
+//! message goes out at QoS 0, so there's nothing to acknowledge, and a
+//! broker that goes away is reconnected to on the next publish.
+
+use log::{info, warn};
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xc0;
+
+/// How long the broker should wait to hear from us before giving up
+const KEEP_ALIVE: u16 = 60;
+
+/// How long to wait between attempts to reach a broker that's down
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long connecting to the broker, or any one read or write, may take
+/// before it's given up on, so that a broker that's gone quiet doesn't
+/// hold up the watch
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct MqttPublisher {
+    broker: String,
+    client_id: String,
+    stream: Option<TcpStream>,
+    last_sent: Instant,
+    last_attempt: Option<Instant>,
+}
+
+fn encode_length(mut len: usize, out: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_string(s: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    encode_length(body.len(), &mut out);
+    out.extend_from_slice(body);
+    out
+}
+
+impl MqttPublisher {
+    /// Create a publisher for `broker`, given as HOST or HOST:PORT.  The
+    /// connection itself is made on the first publish.
+    pub fn new(broker: &str, client_id: &str) -> MqttPublisher {
+        let broker = if broker.contains(':') {
+            broker.to_owned()
+        } else {
+            format!("{}:1883", broker)
+        };
+        MqttPublisher {
+            broker,
+            client_id: client_id.to_owned(),
+            stream: None,
+            last_sent: Instant::now(),
+            last_attempt: None,
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<TcpStream> {
+        let mut stream = Self::open(&self.broker)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        let mut body = vec![];
+        encode_string(b"MQTT", &mut body);
+        body.push(4); // Protocol level 3.1.1
+        body.push(0x02); // Clean session
+        body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+        encode_string(self.client_id.as_bytes(), &mut body);
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        let mut ack = [0; 4];
+        stream.read_exact(&mut ack)?;
+        if ack[0] != CONNACK || ack[3] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("broker refused the connection (code {})", ack[3]),
+            ));
+        }
+        info!("connected to MQTT broker {}", self.broker);
+        Ok(stream)
+    }
+
+    /// Connect to whichever of the broker's addresses answers first
+    fn open(broker: &str) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in broker.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, IO_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", broker))
+        }))
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            // Don't hold up the poll loop retrying a broker that's down
+            if let Some(last) = self.last_attempt {
+                if last.elapsed() < RECONNECT_DELAY {
+                    return Ok(());
+                }
+            }
+            self.last_attempt = Some(Instant::now());
+            self.stream = Some(self.connect()?);
+        }
+        let result = self.stream.as_mut().unwrap().write_all(data);
+        if result.is_err() {
+            self.stream = None;
+        }
+        self.last_sent = Instant::now();
+        result
+    }
+
+    /// Publish `payload` on `topic`.  Failures are reported but otherwise
+    /// ignored, since a flaky broker shouldn't stop the watch.
+    pub fn publish(&mut self, topic: &str, payload: &str) {
+        let mut body = vec![];
+        encode_string(topic.as_bytes(), &mut body);
+        body.extend_from_slice(payload.as_bytes());
+        if let Err(e) = self.send(&packet(PUBLISH, &body)) {
+            warn!("couldn't publish to MQTT broker {}: {}", self.broker, e);
+        }
+    }
+
+    /// Keep the connection open while nothing's changing.
+    pub fn keep_alive(&mut self) {
+        if self.stream.is_some()
+            && self.last_sent.elapsed() > Duration::from_secs(KEEP_ALIVE as u64 / 2)
+        {
+            if let Err(e) = self.send(&packet(PINGREQ, &[])) {
+                warn!("lost connection to MQTT broker {}: {}", self.broker, e);
+            }
+        }
+    }
+}
+
+/// Fill in a topic template, replacing `{register}` with the register's name.
+pub fn topic(template: &str, register: &str) -> String {
+    template.replace("{register}", &register.to_lowercase())
+}
@@ -0,0 +1,265 @@
+//! Generate register access code for firmware from csr.csv, either as a
+//! Rust module in the spirit of svd2rust, or as an SVD file to feed to
+//! svd2rust itself.  Registers get the same names here that wishbone-tool
+//! accepts on its command line.
+
+use crate::config::{CsrMode, CsrOrdering};
+use crate::soc::SocDescription;
+
+use std::collections::HashMap;
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "crate", "do", "dyn", "else",
+    "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop", "macro",
+    "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static", "struct",
+    "super", "trait", "true", "try", "type", "typeof", "unsafe", "use", "virtual", "where",
+    "while", "yield",
+];
+
+/// Make `name` usable as a Rust identifier
+fn ident(name: &str) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    if KEYWORDS.contains(&ident.as_str()) {
+        ident.insert_str(0, "r#");
+    }
+    ident
+}
+
+const RUST_PRELUDE: &str = r#"#![allow(dead_code)]
+
+/// A CSR that may span several words, in the order given by
+/// `CSR_ORDERING_LITTLE`.  Each word carries `width` bits of the value,
+/// which is the CSR data width the SoC was built with.  Registers wider
+/// than 64 bits can be got at a word at a time.
+pub struct ReadOnly {
+    pub address: usize,
+    pub words: usize,
+    pub width: usize,
+}
+
+pub struct ReadWrite {
+    pub address: usize,
+    pub words: usize,
+    pub width: usize,
+}
+
+fn mask(width: usize) -> u64 {
+    if width >= 32 {
+        0xffff_ffff
+    } else {
+        (1 << width) - 1
+    }
+}
+
+/// How far up the value the word at `word` goes
+fn shift(word: usize, words: usize, width: usize) -> u32 {
+    if CSR_ORDERING_LITTLE {
+        (word * width) as u32
+    } else {
+        ((words - word - 1) * width) as u32
+    }
+}
+
+fn read(address: usize, words: usize, width: usize) -> u64 {
+    let mut value: u64 = 0;
+    for word in 0..words {
+        let w = unsafe { core::ptr::read_volatile((address + word * 4) as *const u32) };
+        value |= (w as u64 & mask(width))
+            .checked_shl(shift(word, words, width))
+            .unwrap_or(0);
+    }
+    value
+}
+
+impl ReadOnly {
+    pub fn read_word(&self, word: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.address + word * 4) as *const u32) }
+    }
+
+    pub fn read(&self) -> u64 {
+        read(self.address, self.words, self.width)
+    }
+}
+
+impl ReadWrite {
+    pub fn read_word(&self, word: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.address + word * 4) as *const u32) }
+    }
+
+    pub fn read(&self) -> u64 {
+        read(self.address, self.words, self.width)
+    }
+
+    pub fn write_word(&self, word: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.address + word * 4) as *mut u32, value) }
+    }
+
+    pub fn write(&self, value: u64) {
+        for word in 0..self.words {
+            let w = value
+                .checked_shr(shift(word, self.words, self.width))
+                .unwrap_or(0)
+                & mask(self.width);
+            self.write_word(word, w as u32);
+        }
+    }
+}
+"#;
+
+/// A Rust module with a submodule for each CSR block, plus the memory
+/// regions, the constants, and a table of every name wishbone-tool knows.
+pub fn to_rust(soc: &SocDescription, names: &HashMap<String, u32>) -> String {
+    let mut out = String::new();
+    out.push_str("//! Register access for this SoC, generated by wishbone-tool from csr.csv.\n");
+    out.push_str("//! Don't edit this by hand -- regenerate it when the gateware changes.\n\n");
+    out.push_str(RUST_PRELUDE);
+    let little = soc
+        .blocks
+        .iter()
+        .flat_map(|b| b.registers.iter())
+        .any(|r| r.ordering == CsrOrdering::Little);
+    out.push_str(&format!(
+        "\n/// Whether the least significant word of a CSR comes first\npub const CSR_ORDERING_LITTLE: bool = {};\n",
+        little
+    ));
+
+    for block in &soc.blocks {
+        out.push_str(&format!("\npub mod {} {{\n", ident(&block.name)));
+        out.push_str("    use super::*;\n\n");
+        out.push_str(&format!(
+            "    pub const BASE: usize = 0x{:08x};\n",
+            block.base
+        ));
+        for reg in &block.registers {
+            let (kind, access) = match reg.mode {
+                CsrMode::ReadOnly => ("ReadOnly", "ro"),
+                CsrMode::ReadWrite => ("ReadWrite", "rw"),
+            };
+            out.push_str(&format!(
+                "\n    /// `{}`, {}, {} word{}\n",
+                reg.name,
+                access,
+                reg.words,
+                if reg.words == 1 { "" } else { "s" }
+            ));
+            out.push_str(&format!(
+                "    pub const {}: {} = {} {{ address: 0x{:08x}, words: {}, width: {} }};\n",
+                ident(&block.short_name(reg).to_uppercase()),
+                kind,
+                kind,
+                reg.address,
+                reg.words,
+                reg.data_width
+            ));
+        }
+        out.push_str("}\n");
+    }
+
+    out.push_str("\npub mod memory {\n");
+    for region in &soc.regions {
+        let name = ident(&region.name.to_uppercase());
+        out.push_str(&format!(
+            "    pub const {}: usize = 0x{:08x};\n    pub const {}_SIZE: usize = 0x{:x};\n",
+            name, region.base, name, region.size
+        ));
+    }
+    out.push_str("}\n");
+
+    out.push_str("\npub mod constants {\n");
+    for (name, value) in &soc.constants {
+        let name = ident(&name.to_uppercase());
+        match crate::config::parse_u32(value) {
+            Ok(n) => out.push_str(&format!("    pub const {}: u32 = {};\n", name, n)),
+            Err(_) => out.push_str(&format!("    pub const {}: &str = {:?};\n", name, value)),
+        }
+    }
+    out.push_str("}\n");
+
+    // The same names wishbone-tool resolves, word by word for wide registers
+    let mut names: Vec<(&String, &u32)> = names.iter().collect();
+    names.sort_by_key(|(name, addr)| (**addr, (*name).clone()));
+    out.push_str("\n/// Every name wishbone-tool accepts for an address\n");
+    out.push_str("pub const NAMES: &[(&str, usize)] = &[\n");
+    for (name, addr) in names {
+        out.push_str(&format!("    ({:?}, 0x{:08x}),\n", name, addr));
+    }
+    out.push_str("];\n");
+    out
+}
+
+/// A CMSIS-SVD description.  Wide registers are split into one register
+/// per word, named the way wishbone-tool names them: the most significant
+/// word has the highest index.
+pub fn to_svd(soc: &SocDescription) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<device schemaVersion=\"1.1\" xmlns:xs=\"http://www.w3.org/2001/XMLSchema-instance\" xs:noNamespaceSchemaLocation=\"CMSIS-SVD.xsd\">\n");
+    out.push_str("  <name>SOC</name>\n");
+    out.push_str("  <addressUnitBits>8</addressUnitBits>\n");
+    out.push_str("  <width>32</width>\n");
+    out.push_str("  <size>32</size>\n");
+    out.push_str("  <access>read-write</access>\n");
+    out.push_str("  <resetValue>0x00000000</resetValue>\n");
+    out.push_str("  <resetMask>0xFFFFFFFF</resetMask>\n");
+    out.push_str("  <peripherals>\n");
+    for block in &soc.blocks {
+        out.push_str("    <peripheral>\n");
+        out.push_str(&format!(
+            "      <name>{}</name>\n",
+            block.name.to_uppercase()
+        ));
+        out.push_str(&format!(
+            "      <baseAddress>0x{:08X}</baseAddress>\n",
+            block.base
+        ));
+        out.push_str(&format!(
+            "      <addressBlock>\n        <offset>0</offset>\n        <size>0x{:x}</size>\n        <usage>registers</usage>\n      </addressBlock>\n",
+            block.size()
+        ));
+        if let Some(irq) = soc.constant(&format!("{}_interrupt", block.name)) {
+            out.push_str(&format!(
+                "      <interrupt>\n        <name>{}</name>\n        <value>{}</value>\n      </interrupt>\n",
+                block.name.to_uppercase(),
+                irq
+            ));
+        }
+        out.push_str("      <registers>\n");
+        for reg in &block.registers {
+            let access = match reg.mode {
+                CsrMode::ReadOnly => "read-only",
+                CsrMode::ReadWrite => "read-write",
+            };
+            for word in 0..reg.words {
+                let name = if reg.words == 1 {
+                    block.short_name(reg).to_uppercase()
+                } else {
+                    format!(
+                        "{}{}",
+                        block.short_name(reg).to_uppercase(),
+                        reg.significance(word)
+                    )
+                };
+                out.push_str("        <register>\n");
+                out.push_str(&format!("          <name>{}</name>\n", name));
+                out.push_str(&format!(
+                    "          <addressOffset>0x{:x}</addressOffset>\n",
+                    reg.address + word * 4 - block.base
+                ));
+                out.push_str("          <size>32</size>\n");
+                out.push_str(&format!("          <access>{}</access>\n", access));
+                out.push_str("        </register>\n");
+            }
+        }
+        out.push_str("      </registers>\n");
+        out.push_str("    </peripheral>\n");
+    }
+    out.push_str("  </peripherals>\n");
+    out.push_str("</device>\n");
+    out
+}
@@ -0,0 +1,179 @@
+//! PRBS tests for SerDes and PHY bring-up: set the pattern generator and
+//! checker going, optionally with the PHY looped back on itself, count the
+//! bit errors over a while, and give the bit error rate.
+//!
+//! A PHY is found by looking for `<name>_tx_prbs_config`,
+//! `<name>_rx_prbs_config` and `<name>_rx_prbs_errors` registers in
+//! csr.csv, as LiteICLink's transceivers have.  The config registers pick
+//! the pattern, with 0 turning it off, and the error counter counts up
+//! for as long as the checker runs.  A `<name>_loopback` register, if
+//! there is one, sets the loopback mode.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::CsrRegister;
+use crate::ecc::read_counter;
+
+use log::debug;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the checker gets to lock onto the pattern before the errors
+/// start counting
+const LOCK_TIME: Duration = Duration::from_millis(100);
+
+/// The PRBS patterns, as written to the config registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrbsPattern {
+    Prbs7,
+    Prbs15,
+    Prbs31,
+}
+
+impl PrbsPattern {
+    pub fn from_string(item: &str) -> Option<PrbsPattern> {
+        match item {
+            "7" | "prbs7" => Some(PrbsPattern::Prbs7),
+            "15" | "prbs15" => Some(PrbsPattern::Prbs15),
+            "31" | "prbs31" => Some(PrbsPattern::Prbs31),
+            _ => None,
+        }
+    }
+
+    fn config(self) -> u32 {
+        match self {
+            PrbsPattern::Prbs7 => 1,
+            PrbsPattern::Prbs15 => 2,
+            PrbsPattern::Prbs31 => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PrbsPattern::Prbs7 => "PRBS7",
+            PrbsPattern::Prbs15 => "PRBS15",
+            PrbsPattern::Prbs31 => "PRBS31",
+        }
+    }
+}
+
+/// What a PRBS test found
+#[derive(Clone, Debug)]
+pub struct PrbsResult {
+    pub errors: u64,
+    pub elapsed: Duration,
+}
+
+impl PrbsResult {
+    /// The bit error rate, given the line rate.  With no errors, this is
+    /// the rate the test could have seen one error at, so the true rate
+    /// is below it.
+    pub fn ber(&self, bits_per_second: u64) -> f64 {
+        let bits = bits_per_second as f64 * self.elapsed.as_secs_f64();
+        if bits == 0.0 {
+            return 0.0;
+        }
+        self.errors.max(1) as f64 / bits
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PrbsPhy {
+    pub name: String,
+    tx_config: u32,
+    rx_config: u32,
+    rx_errors: CsrRegister,
+    loopback: Option<u32>,
+}
+
+impl PrbsPhy {
+    pub fn discover(registers: &[CsrRegister]) -> Vec<PrbsPhy> {
+        let find = |name: &str| registers.iter().find(|r| r.name == name);
+        let mut phys = vec![];
+        for reg in registers {
+            let name = match reg.name.strip_suffix("_rx_prbs_errors") {
+                Some(n) => n,
+                None => continue,
+            };
+            let (tx_config, rx_config) = match (
+                find(&format!("{}_tx_prbs_config", name)),
+                find(&format!("{}_rx_prbs_config", name)),
+            ) {
+                (Some(tx), Some(rx)) => (tx.address, rx.address),
+                _ => continue,
+            };
+            phys.push(PrbsPhy {
+                name: name.to_owned(),
+                tx_config,
+                rx_config,
+                rx_errors: reg.clone(),
+                loopback: find(&format!("{}_loopback", name)).map(|r| r.address),
+            });
+        }
+        phys
+    }
+
+    pub fn has_loopback(&self) -> bool {
+        self.loopback.is_some()
+    }
+
+    /// Run `pattern` for `duration`, with the loopback register set to
+    /// `loopback` if given.  Everything is put back as it was afterwards,
+    /// so the link comes back up the way it was.
+    pub fn run(
+        &self,
+        bridge: &Bridge,
+        pattern: PrbsPattern,
+        loopback: Option<u32>,
+        duration: Duration,
+    ) -> Result<PrbsResult, BridgeError> {
+        let saved_loopback = match (self.loopback, loopback) {
+            (Some(addr), Some(mode)) => {
+                let saved = bridge.peek(addr)?;
+                bridge.poke(addr, mode)?;
+                Some((addr, saved))
+            }
+            _ => None,
+        };
+        let result = self.count_errors(bridge, pattern, duration);
+
+        bridge.poke(self.tx_config, 0)?;
+        bridge.poke(self.rx_config, 0)?;
+        if let Some((addr, saved)) = saved_loopback {
+            bridge.poke(addr, saved)?;
+        }
+        result
+    }
+
+    fn count_errors(
+        &self,
+        bridge: &Bridge,
+        pattern: PrbsPattern,
+        duration: Duration,
+    ) -> Result<PrbsResult, BridgeError> {
+        bridge.poke(self.tx_config, pattern.config())?;
+        bridge.poke(self.rx_config, pattern.config())?;
+        thread::sleep(LOCK_TIME);
+
+        // The counter only stops when the checker does, so the errors are
+        // however far it goes while the test runs
+        let start_errors = read_counter(bridge, &self.rx_errors)?;
+        let start = Instant::now();
+        thread::sleep(duration);
+        let end_errors = read_counter(bridge, &self.rx_errors)?;
+        let elapsed = start.elapsed();
+
+        let width = self.rx_errors.words * self.rx_errors.data_width;
+        let mask = if width >= 64 {
+            u64::MAX
+        } else {
+            (1u64 << width) - 1
+        };
+        let errors = end_errors.wrapping_sub(start_errors) & mask;
+        debug!(
+            "{}: error counter went from {} to {} in {:?}",
+            self.name, start_errors, end_errors, elapsed
+        );
+        Ok(PrbsResult { errors, elapsed })
+    }
+}
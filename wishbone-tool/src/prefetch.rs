@@ -0,0 +1,156 @@
+//! Read-ahead for GDB memory reads.  A backtrace turns into a long run of
+//! small reads that walk down the stack, and printing a structure reads
+//! its fields one after another.  Each of those is a full round trip to
+//! GDB and then to the bridge, so once a run is spotted the next few words
+//! are fetched in the background while GDB is still working out what to
+//! ask for next.
+//!
+//! Only regions that behave like plain memory are read ahead, and the
+//! cache is thrown away as soon as GDB does anything other than read, since
+//! the CPU may then have changed what's there.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::regions::{AccessPolicy, MemoryRegion};
+
+use log::debug;
+
+use std::collections::HashMap;
+use std::thread;
+
+/// How far to read ahead once a pattern is spotted
+const PREFETCH_BYTES: u32 = 128;
+
+/// How close two reads must be to count as part of the same walk
+const NEARBY_BYTES: u64 = 64;
+
+/// Most words to hold before starting again
+const MAX_CACHED_WORDS: usize = 65536;
+
+type PendingRead = (u32, thread::JoinHandle<Result<Vec<u8>, BridgeError>>);
+
+pub struct Prefetcher {
+    regions: Vec<MemoryRegion>,
+    cache: HashMap<u32, u32>,
+    pending: Option<PendingRead>,
+    last: Option<(u32, u32)>,
+    hits: u64,
+}
+
+impl Prefetcher {
+    pub fn new(regions: &[MemoryRegion]) -> Prefetcher {
+        Prefetcher {
+            regions: regions
+                .iter()
+                .filter(|r| r.policy == AccessPolicy::Any)
+                .cloned()
+                .collect(),
+            cache: HashMap::new(),
+            pending: None,
+            last: None,
+            hits: 0,
+        }
+    }
+
+    fn region(&self, addr: u32, len: u32) -> Option<&MemoryRegion> {
+        let end = addr as u64 + len as u64;
+        self.regions
+            .iter()
+            .find(|r| addr >= r.base && end <= r.base as u64 + r.size as u64)
+    }
+
+    /// Whether a read of `len` bytes at `addr` can go through the cache
+    pub fn covers(&self, addr: u32, len: u32) -> bool {
+        addr & 3 == 0 && len & 3 == 0 && len > 0 && self.region(addr, len).is_some()
+    }
+
+    /// Fold in whatever the background read has fetched.
+    fn collect(&mut self) {
+        if let Some((start, handle)) = self.pending.take() {
+            if let Ok(Ok(data)) = handle.join() {
+                for (idx, word) in data.chunks_exact(4).enumerate() {
+                    let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                    self.cache.entry(start + idx as u32 * 4).or_insert(value);
+                }
+            }
+        }
+    }
+
+    /// Read `len` bytes at `addr` as words, from the cache where possible,
+    /// and start reading ahead if this looks like part of a walk.
+    pub fn read(&mut self, bridge: &Bridge, addr: u32, len: u32) -> Result<Vec<u32>, BridgeError> {
+        self.collect();
+        if self.cache.len() > MAX_CACHED_WORDS {
+            self.cache.clear();
+        }
+        let mut values = vec![];
+        for word in (addr..=addr + (len - 4)).step_by(4) {
+            let value = match self.cache.get(&word) {
+                Some(v) => {
+                    self.hits += 1;
+                    *v
+                }
+                None => {
+                    let v = bridge.peek(word)?;
+                    self.cache.insert(word, v);
+                    v
+                }
+            };
+            values.push(value);
+        }
+        self.predict(bridge, addr, len);
+        self.last = Some((addr, len));
+        Ok(values)
+    }
+
+    fn predict(&mut self, bridge: &Bridge, addr: u32, len: u32) {
+        let (last_addr, last_len) = match self.last {
+            Some(l) => l,
+            None => return,
+        };
+        let (addr64, last_end) = (addr as u64, last_addr as u64 + last_len as u64);
+        let (start, end) = if addr < last_addr && (last_addr - addr) as u64 <= NEARBY_BYTES {
+            // Walking down, such as a backtrace
+            (addr64.saturating_sub(PREFETCH_BYTES as u64), addr64)
+        } else if addr64 >= last_end && addr64 - last_end <= NEARBY_BYTES {
+            // Walking up, such as the fields of a structure
+            let end = addr64 + len as u64;
+            (end, end + PREFETCH_BYTES as u64)
+        } else {
+            return;
+        };
+
+        // Keep to the region, and don't fetch what's already here
+        let region = match self.region(addr, len) {
+            Some(r) => r,
+            None => return,
+        };
+        let start = start.max(region.base as u64) as u32;
+        let end = end.min(region.base as u64 + region.size as u64);
+        if end <= start as u64
+            || (start..end as u32)
+                .step_by(4)
+                .all(|w| self.cache.contains_key(&w))
+        {
+            return;
+        }
+        let bridge = bridge.clone();
+        let count = (end - start as u64) as u32;
+        self.pending = Some((
+            start,
+            thread::spawn(move || bridge.burst_read(start, count)),
+        ));
+    }
+
+    /// Forget everything, because memory may have changed.
+    pub fn invalidate(&mut self) {
+        if let Some((_, handle)) = self.pending.take() {
+            handle.join().ok();
+        }
+        if self.hits > 0 {
+            debug!("prefetch: {} reads answered from the cache", self.hits);
+        }
+        self.cache.clear();
+        self.last = None;
+        self.hits = 0;
+    }
+}
@@ -0,0 +1,229 @@
+//! Regions of the address space that need special care when accessed.
+//! Many peripherals, CSRs in particular, only decode full 32-bit aligned
+//! accesses, and return garbage or hang the bus otherwise.
+//!
+//! The regions also make up the memory map that GDB is given, which tells
+//! it where it can `load` to and where it has to use hardware breakpoints.
+
+use crate::spiflash;
+
+use log::debug;
+
+/// The erase block size given to GDB for flash, which is the sector size
+/// that `SpiFlash` erases, so GDB's vFlashErase always lines up with it
+const FLASH_BLOCK_SIZE: u32 = spiflash::SECTOR_SIZE;
+
+const MEMORY_MAP_HEADER: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE memory-map
+          PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN"
+                 "http://sourceware.org/gdb/gdb-memory-map.dtd">
+<memory-map>
+"#;
+
+/// What sort of accesses a region will put up with
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccessPolicy {
+    /// Any size, any alignment
+    Any,
+
+    /// Only aligned 32-bit words
+    WordOnly,
+}
+
+impl AccessPolicy {
+    pub fn from_string(item: &str) -> Option<AccessPolicy> {
+        match item {
+            "any" => Some(AccessPolicy::Any),
+            "word" | "word-only" | "io" => Some(AccessPolicy::WordOnly),
+            _ => None,
+        }
+    }
+}
+
+/// What GDB should take a region to be
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryKind {
+    /// Can be written, and so loaded into and given software breakpoints.
+    /// Peripherals are RAM as far as GDB is concerned.
+    Ram,
+
+    /// Read-only, so breakpoints have to be hardware ones
+    Rom,
+
+    /// Read-only to ordinary writes, like ROM
+    Flash,
+}
+
+impl MemoryKind {
+    pub fn from_string(item: &str) -> Option<MemoryKind> {
+        match item {
+            "ram" => Some(MemoryKind::Ram),
+            "rom" => Some(MemoryKind::Rom),
+            "flash" => Some(MemoryKind::Flash),
+            _ => None,
+        }
+    }
+
+    /// Guess from the names LiteX gives its regions, such as `rom`,
+    /// `sram`, `main_ram` and `spiflash`
+    pub fn from_name(name: &str) -> MemoryKind {
+        if name.contains("flash") {
+            MemoryKind::Flash
+        } else if name == "rom" || name.ends_with("_rom") || name == "bios" {
+            MemoryKind::Rom
+        } else {
+            MemoryKind::Ram
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            MemoryKind::Ram => "ram",
+            MemoryKind::Rom => "rom",
+            MemoryKind::Flash => "flash",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+    pub policy: AccessPolicy,
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    fn overlaps(&self, addr: u32, len: u32) -> bool {
+        let end = addr as u64 + len.max(1) as u64;
+        (addr as u64) < self.base as u64 + self.size as u64 && end > self.base as u64
+    }
+}
+
+/// Describe `regions` as a GDB memory map.  GDB won't have regions that
+/// overlap, so where they do, the first one wins.
+///
+/// Once GDB has a memory map it refuses to touch anything outside it
+/// (`mem inaccessible-by-default`), which would shut it out of the CSRs
+/// and anything else not in the csr map, so the gaps are filled in as
+/// RAM.  Flash is only given as flash when `flash` says that GDB's
+/// vFlash packets can program it, and as ROM otherwise, so that GDB
+/// doesn't try to load into it.
+pub fn memory_map_xml(regions: &[MemoryRegion], flash: bool) -> String {
+    let mut xml = MEMORY_MAP_HEADER.to_owned();
+    let mut mapped: Vec<&MemoryRegion> = vec![];
+    for region in regions {
+        if region.size == 0 {
+            continue;
+        }
+        if let Some(other) = mapped.iter().find(|r| r.overlaps(region.base, region.size)) {
+            debug!("{} overlaps {}, so it's left out of the memory map", region.name, other.name);
+            continue;
+        }
+        mapped.push(region);
+    }
+    mapped.sort_by_key(|r| r.base);
+    let mut next = 0u64;
+    for region in mapped {
+        if (region.base as u64) > next {
+            xml.push_str(&gap_xml(next, region.base as u64 - next));
+        }
+        next = region.base as u64 + region.size as u64;
+        let kind = match region.kind {
+            MemoryKind::Flash if !flash => MemoryKind::Rom,
+            kind => kind,
+        };
+        let start = format!(
+            "    <memory type=\"{}\" start=\"0x{:x}\" length=\"0x{:x}\"",
+            kind.name(),
+            region.base,
+            region.size
+        );
+        if kind == MemoryKind::Flash {
+            xml.push_str(&format!(
+                "{}>\n        <property name=\"blocksize\">0x{:x}</property>\n    </memory>\n",
+                start, FLASH_BLOCK_SIZE
+            ));
+        } else {
+            xml.push_str(&format!("{}/>\n", start));
+        }
+    }
+    if next < 1 << 32 {
+        xml.push_str(&gap_xml(next, (1 << 32) - next));
+    }
+    xml.push_str("</memory-map>\n");
+    xml
+}
+
+/// A stretch of the address space that's in none of the regions
+fn gap_xml(start: u64, len: u64) -> String {
+    format!("    <memory type=\"ram\" start=\"0x{:x}\" length=\"0x{:x}\"/>\n", start, len)
+}
+
+/// Where `len` bytes at `addr` are in the flash, if they're all in one of
+/// the flash regions, which map the flash from its start
+pub fn flash_offset(regions: &[MemoryRegion], addr: u32, len: u32) -> Option<u32> {
+    regions
+        .iter()
+        .find(|r| {
+            r.kind == MemoryKind::Flash
+                && addr >= r.base
+                && (addr - r.base) as u64 + len as u64 <= r.size as u64
+        })
+        .map(|r| addr - r.base)
+}
+
+/// Whether `len` bytes at `addr` need to go through aligned word accesses
+pub fn needs_words(regions: &[MemoryRegion], addr: u32, len: u32) -> bool {
+    (addr & 3 != 0 || len & 3 != 0)
+        && regions
+            .iter()
+            .any(|r| r.policy == AccessPolicy::WordOnly && r.overlaps(addr, len))
+}
+
+/// Read `len` bytes from `addr` using only aligned word reads.
+pub fn read_bytes<E, F: FnMut(u32) -> Result<u32, E>>(
+    addr: u32,
+    len: u32,
+    mut peek: F,
+) -> Result<Vec<u8>, E> {
+    let start = addr & !3;
+    let end = (addr as u64 + len as u64 + 3) & !3;
+    let mut data = vec![];
+    let mut word = start as u64;
+    while word < end {
+        data.extend_from_slice(&peek(word as u32)?.to_le_bytes());
+        word += 4;
+    }
+    let skip = (addr - start) as usize;
+    Ok(data[skip..skip + len as usize].to_vec())
+}
+
+/// Write `data` to `addr` using only aligned word accesses.  Words that
+/// are only partly covered are read first, and the rest of them is
+/// written back unchanged.
+pub fn write_bytes<E, R, W>(addr: u32, data: &[u8], mut peek: R, mut poke: W) -> Result<(), E>
+where
+    R: FnMut(u32) -> Result<u32, E>,
+    W: FnMut(u32, u32) -> Result<(), E>,
+{
+    let end = addr as u64 + data.len() as u64;
+    let mut word = (addr & !3) as u64;
+    while word < end {
+        let mut bytes = [0u8; 4];
+        let covered = (0..4u64).all(|i| word + i >= addr as u64 && word + i < end);
+        if !covered {
+            bytes = peek(word as u32)?.to_le_bytes();
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let pos = word + i as u64;
+            if pos >= addr as u64 && pos < end {
+                *byte = data[(pos - addr as u64) as usize];
+            }
+        }
+        poke(word as u32, u32::from_le_bytes(bytes))?;
+        word += 4;
+    }
+    Ok(())
+}
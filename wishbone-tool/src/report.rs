@@ -0,0 +1,107 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Machine-readable formats for reporting test results
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReportFormat {
+    /// Test Anything Protocol, version 13
+    Tap,
+
+    /// JUnit XML, as understood by most CI systems
+    Junit,
+}
+
+impl ReportFormat {
+    pub fn from_string(item: &str) -> Option<ReportFormat> {
+        match item {
+            "tap" => Some(ReportFormat::Tap),
+            "junit" => Some(ReportFormat::Junit),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of a single test case
+pub struct TestResult {
+    pub name: String,
+    pub elapsed: Duration,
+
+    /// Why the test failed, or `None` if it passed
+    pub failure: Option<String>,
+}
+
+pub fn write_report(
+    format: &ReportFormat,
+    suite: &str,
+    results: &[TestResult],
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    match format {
+        ReportFormat::Tap => write_tap(results, out),
+        ReportFormat::Junit => write_junit(suite, results, out),
+    }
+}
+
+fn write_tap(results: &[TestResult], out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "TAP version 13")?;
+    writeln!(out, "1..{}", results.len())?;
+    for (idx, result) in results.iter().enumerate() {
+        match &result.failure {
+            None => writeln!(out, "ok {} - {}", idx + 1, result.name)?,
+            Some(message) => {
+                writeln!(out, "not ok {} - {}", idx + 1, result.name)?;
+                writeln!(out, "  ---")?;
+                writeln!(out, "  message: {:?}", message)?;
+                writeln!(out, "  ...")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_junit(suite: &str, results: &[TestResult], out: &mut dyn Write) -> io::Result<()> {
+    let failures = results.iter().filter(|r| r.failure.is_some()).count();
+    let total: Duration = results.iter().map(|r| r.elapsed).sum();
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        xml_escape(suite),
+        results.len(),
+        failures,
+        total.as_secs_f64()
+    )?;
+    for result in results {
+        write!(
+            out,
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            xml_escape(&result.name),
+            xml_escape(suite),
+            result.elapsed.as_secs_f64()
+        )?;
+        match &result.failure {
+            None => writeln!(out, "/>")?,
+            Some(message) => {
+                writeln!(out, ">")?;
+                writeln!(out, "    <failure message=\"{}\"/>", xml_escape(message))?;
+                writeln!(out, "  </testcase>")?;
+            }
+        }
+    }
+    writeln!(out, "</testsuite>")
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,79 @@
+//! CSRs that aren't in the privileged spec, such as VexRiscv's own or a
+//! LiteX timer mapped into CSR space, described in a JSON file so that GDB
+//! can get at them by name:
+//!
+//! ```text
+//!   {
+//!     "csrs": [
+//!       { "name": "mmu_ctrl", "number": "0xbc0", "group": "vexriscv" },
+//!       { "name": "timer_value", "number": "0xcc1", "group": "timer" },
+//!       { "name": "cycles", "alias": "mcycle" }
+//!     ]
+//!   }
+//! ```
+//!
+//! A CSR with a `number` is added to the register list, in the CSR group
+//! unless it says otherwise.  One with an `alias` instead gives another
+//! name to a register that's already there, which GDB sees as a read-only
+//! pseudo-register.
+
+use super::pseudo::PseudoRegister;
+use crate::json::{self, Json};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomCsr {
+    pub name: String,
+    pub number: u32,
+
+    /// The register group GDB puts it in, such as for `info registers vexriscv`
+    pub group: Option<String>,
+}
+
+/// Pick the CSRs and aliases out of a file.  The aliases come back as
+/// pseudo-registers.
+pub fn parse(text: &str) -> Result<(Vec<CustomCsr>, Vec<PseudoRegister>), String> {
+    let value = json::parse(text)?;
+    let entries = match value.get("csrs") {
+        Some(Json::Array(entries)) => entries,
+        _ => return Err("there's no \"csrs\" list".to_owned()),
+    };
+    let mut csrs: Vec<CustomCsr> = vec![];
+    let mut aliases = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        let name = match entry.get("name") {
+            Some(Json::String(name)) => name.clone(),
+            _ => return Err(format!("entry {} has no name", index)),
+        };
+        match (entry.get("number"), entry.get("alias")) {
+            (Some(number), None) => {
+                let number = match number.as_u32() {
+                    Some(n) if n < 0x1000 => n,
+                    _ => return Err(format!("{} isn't numbered 0 to 0xfff", name)),
+                };
+                let group = match entry.get("group") {
+                    None => None,
+                    Some(Json::String(group)) => Some(group.clone()),
+                    Some(_) => return Err(format!("the group of {} isn't a string", name)),
+                };
+                if let Some(other) = csrs.iter().find(|c| c.number == number || c.name == name) {
+                    return Err(format!("{} clashes with {}", name, other.name));
+                }
+                csrs.push(CustomCsr {
+                    name,
+                    number,
+                    group,
+                });
+            }
+            (None, Some(Json::String(target))) => {
+                aliases.push(PseudoRegister::from_string(&format!("{}={}", name, target), None)?);
+            }
+            _ => {
+                return Err(format!(
+                    "{} needs either a number or the name of a register to alias",
+                    name
+                ))
+            }
+        }
+    }
+    Ok((csrs, aliases))
+}
@@ -0,0 +1,577 @@
+//! Just enough of a disassembler to show where a halted CPU has got to,
+//! and for `monitor disasm`, without needing objdump for the target.  It
+//! knows RV32IMAC, Zicsr, and the F and D loads and stores, and uses the
+//! usual register names and aliases.  Anything else comes out as a
+//! `.word` or `.half`, so a listing never stops partway.
+
+use super::{instruction_length, RiscvCpuError};
+
+use std::collections::HashMap;
+
+const REGISTERS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const FP_REGISTERS: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// The CSRs worth naming in a listing; the rest are shown by number
+const CSRS: [(u32, &str); 38] = [
+    (0x001, "fflags"),
+    (0x002, "frm"),
+    (0x003, "fcsr"),
+    (0x100, "sstatus"),
+    (0x104, "sie"),
+    (0x105, "stvec"),
+    (0x140, "sscratch"),
+    (0x141, "sepc"),
+    (0x142, "scause"),
+    (0x143, "stval"),
+    (0x144, "sip"),
+    (0x180, "satp"),
+    (0x300, "mstatus"),
+    (0x301, "misa"),
+    (0x302, "medeleg"),
+    (0x303, "mideleg"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0x7a0, "tselect"),
+    (0x7a1, "tdata1"),
+    (0x7a2, "tdata2"),
+    (0x7b0, "dcsr"),
+    (0x7b1, "dpc"),
+    (0xb00, "mcycle"),
+    (0xb02, "minstret"),
+    (0xc00, "cycle"),
+    (0xc01, "time"),
+    (0xc02, "instret"),
+    (0xc80, "cycleh"),
+    (0xc81, "timeh"),
+    (0xc82, "instreth"),
+    (0xf11, "mvendorid"),
+    (0xf14, "mhartid"),
+];
+
+/// One instruction of a listing
+pub struct Line {
+    pub addr: u32,
+    pub insn: u32,
+    pub text: String,
+}
+
+impl Line {
+    /// Show the line as `addr: encoding  instruction`, pointed at if it's
+    /// where the CPU is
+    pub fn format(&self, current: bool) -> String {
+        let encoding = if instruction_length(self.insn) == 2 {
+            format!("{:04x}    ", self.insn)
+        } else {
+            format!("{:08x}", self.insn)
+        };
+        format!(
+            "{} 0x{:08x}:  {}  {}",
+            if current { "=>" } else { "  " },
+            self.addr,
+            encoding,
+            self.text
+        )
+    }
+}
+
+/// Sign-extend the bottom `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    (value << (32 - bits)) as i32 >> (32 - bits)
+}
+
+fn reg(n: u32) -> &'static str {
+    REGISTERS[(n & 0x1f) as usize]
+}
+
+fn freg(n: u32) -> &'static str {
+    FP_REGISTERS[(n & 0x1f) as usize]
+}
+
+fn csr(n: u32) -> String {
+    match CSRS.iter().find(|(number, _)| *number == n) {
+        Some((_, name)) => (*name).to_owned(),
+        None => format!("0x{:03x}", n),
+    }
+}
+
+/// Put the mnemonic in a column of its own
+fn op(mnemonic: &str, operands: &str) -> String {
+    if operands.is_empty() {
+        mnemonic.to_owned()
+    } else {
+        format!("{:<7} {}", mnemonic, operands)
+    }
+}
+
+fn target(pc: u32, offset: i32) -> String {
+    format!("0x{:08x}", pc.wrapping_add(offset as u32))
+}
+
+/// Read the instruction at `pc`, which may be compressed and so only
+/// halfword-aligned.  `read` is given word-aligned addresses.
+pub fn fetch<F>(pc: u32, mut read: F) -> Result<u32, RiscvCpuError>
+where
+    F: FnMut(u32) -> Result<u32, RiscvCpuError>,
+{
+    let low = read(pc & !3)?;
+    if pc & 2 == 0 {
+        return Ok(if low & 3 == 3 { low } else { low & 0xffff });
+    }
+    let half = low >> 16;
+    if half & 3 != 3 {
+        return Ok(half);
+    }
+    let high = read((pc & !3).wrapping_add(4))?;
+    Ok(half | high << 16)
+}
+
+/// Turn `insn`, found at `pc`, into assembly.  Branch and jump targets
+/// are given as addresses rather than offsets.
+pub fn decode(insn: u32, pc: u32) -> String {
+    if insn & 3 != 3 {
+        return decode_compressed(insn & 0xffff, pc)
+            .unwrap_or_else(|| format!(".half   0x{:04x}", insn & 0xffff));
+    }
+    decode_full(insn, pc).unwrap_or_else(|| format!(".word   0x{:08x}", insn))
+}
+
+fn decode_full(insn: u32, pc: u32) -> Option<String> {
+    let rd = (insn >> 7) & 0x1f;
+    let funct3 = (insn >> 12) & 7;
+    let rs1 = (insn >> 15) & 0x1f;
+    let rs2 = (insn >> 20) & 0x1f;
+    let funct7 = insn >> 25;
+    let imm_i = (insn as i32) >> 20;
+    let imm_s = ((insn as i32) >> 25) << 5 | rd as i32;
+    let imm_b = sign_extend(
+        (insn >> 31) << 12 | ((insn >> 7) & 1) << 11 | ((insn >> 25) & 0x3f) << 5 | ((insn >> 8) & 0xf) << 1,
+        13,
+    );
+    let imm_j = sign_extend(
+        (insn >> 31) << 20 | ((insn >> 12) & 0xff) << 12 | ((insn >> 20) & 1) << 11 | ((insn >> 21) & 0x3ff) << 1,
+        21,
+    );
+
+    Some(match insn & 0x7f {
+        0x37 => op("lui", &format!("{}, 0x{:x}", reg(rd), insn >> 12)),
+        0x17 => op("auipc", &format!("{}, 0x{:x}", reg(rd), insn >> 12)),
+        0x6f => match rd {
+            0 => op("j", &target(pc, imm_j)),
+            1 => op("jal", &target(pc, imm_j)),
+            _ => op("jal", &format!("{}, {}", reg(rd), target(pc, imm_j))),
+        },
+        0x67 if funct3 == 0 => match (rd, rs1, imm_i) {
+            (0, 1, 0) => "ret".to_owned(),
+            (0, _, 0) => op("jr", reg(rs1)),
+            (1, _, 0) => op("jalr", reg(rs1)),
+            _ => op("jalr", &format!("{}, {}({})", reg(rd), imm_i, reg(rs1))),
+        },
+        0x63 => {
+            let mnemonic = match funct3 {
+                0 => "beq",
+                1 => "bne",
+                4 => "blt",
+                5 => "bge",
+                6 => "bltu",
+                7 => "bgeu",
+                _ => return None,
+            };
+            if rs2 == 0 && funct3 < 2 {
+                op(&format!("{}z", mnemonic), &format!("{}, {}", reg(rs1), target(pc, imm_b)))
+            } else {
+                op(mnemonic, &format!("{}, {}, {}", reg(rs1), reg(rs2), target(pc, imm_b)))
+            }
+        }
+        0x03 => {
+            let mnemonic = match funct3 {
+                0 => "lb",
+                1 => "lh",
+                2 => "lw",
+                4 => "lbu",
+                5 => "lhu",
+                _ => return None,
+            };
+            op(mnemonic, &format!("{}, {}({})", reg(rd), imm_i, reg(rs1)))
+        }
+        0x23 => {
+            let mnemonic = match funct3 {
+                0 => "sb",
+                1 => "sh",
+                2 => "sw",
+                _ => return None,
+            };
+            op(mnemonic, &format!("{}, {}({})", reg(rs2), imm_s, reg(rs1)))
+        }
+        0x07 => {
+            let mnemonic = match funct3 {
+                2 => "flw",
+                3 => "fld",
+                _ => return None,
+            };
+            op(mnemonic, &format!("{}, {}({})", freg(rd), imm_i, reg(rs1)))
+        }
+        0x27 => {
+            let mnemonic = match funct3 {
+                2 => "fsw",
+                3 => "fsd",
+                _ => return None,
+            };
+            op(mnemonic, &format!("{}, {}({})", freg(rs2), imm_s, reg(rs1)))
+        }
+        0x13 => {
+            let shamt = rs2;
+            match (funct3, funct7) {
+                (0, _) if insn == 0x0000_0013 => "nop".to_owned(),
+                (0, _) if rs1 == 0 => op("li", &format!("{}, {}", reg(rd), imm_i)),
+                (0, _) if imm_i == 0 => op("mv", &format!("{}, {}", reg(rd), reg(rs1))),
+                (0, _) => op("addi", &format!("{}, {}, {}", reg(rd), reg(rs1), imm_i)),
+                (1, 0) => op("slli", &format!("{}, {}, {}", reg(rd), reg(rs1), shamt)),
+                (2, _) => op("slti", &format!("{}, {}, {}", reg(rd), reg(rs1), imm_i)),
+                (3, _) if imm_i == 1 => op("seqz", &format!("{}, {}", reg(rd), reg(rs1))),
+                (3, _) => op("sltiu", &format!("{}, {}, {}", reg(rd), reg(rs1), imm_i)),
+                (4, _) if imm_i == -1 => op("not", &format!("{}, {}", reg(rd), reg(rs1))),
+                (4, _) => op("xori", &format!("{}, {}, {}", reg(rd), reg(rs1), imm_i)),
+                (5, 0) => op("srli", &format!("{}, {}, {}", reg(rd), reg(rs1), shamt)),
+                (5, 0x20) => op("srai", &format!("{}, {}, {}", reg(rd), reg(rs1), shamt)),
+                (6, _) => op("ori", &format!("{}, {}, {}", reg(rd), reg(rs1), imm_i)),
+                (7, _) => op("andi", &format!("{}, {}, {}", reg(rd), reg(rs1), imm_i)),
+                _ => return None,
+            }
+        }
+        0x33 => {
+            let mnemonic = match (funct7, funct3) {
+                (0, 0) => "add",
+                (0x20, 0) => "sub",
+                (0, 1) => "sll",
+                (0, 2) => "slt",
+                (0, 3) => "sltu",
+                (0, 4) => "xor",
+                (0, 5) => "srl",
+                (0x20, 5) => "sra",
+                (0, 6) => "or",
+                (0, 7) => "and",
+                (1, 0) => "mul",
+                (1, 1) => "mulh",
+                (1, 2) => "mulhsu",
+                (1, 3) => "mulhu",
+                (1, 4) => "div",
+                (1, 5) => "divu",
+                (1, 6) => "rem",
+                (1, 7) => "remu",
+                _ => return None,
+            };
+            if mnemonic == "sub" && rs1 == 0 {
+                op("neg", &format!("{}, {}", reg(rd), reg(rs2)))
+            } else {
+                op(mnemonic, &format!("{}, {}, {}", reg(rd), reg(rs1), reg(rs2)))
+            }
+        }
+        0x2f if funct3 == 2 => {
+            let mnemonic = match insn >> 27 {
+                0x00 => "amoadd.w",
+                0x01 => "amoswap.w",
+                0x02 if rs2 == 0 => "lr.w",
+                0x03 => "sc.w",
+                0x04 => "amoxor.w",
+                0x08 => "amoor.w",
+                0x0c => "amoand.w",
+                0x10 => "amomin.w",
+                0x14 => "amomax.w",
+                0x18 => "amominu.w",
+                0x1c => "amomaxu.w",
+                _ => return None,
+            };
+            let ordering = match (insn >> 25) & 3 {
+                0 => "",
+                1 => ".rl",
+                2 => ".aq",
+                _ => ".aqrl",
+            };
+            let mnemonic = format!("{}{}", mnemonic, ordering);
+            if mnemonic.starts_with("lr") {
+                op(&mnemonic, &format!("{}, ({})", reg(rd), reg(rs1)))
+            } else {
+                op(&mnemonic, &format!("{}, {}, ({})", reg(rd), reg(rs2), reg(rs1)))
+            }
+        }
+        0x0f => match funct3 {
+            0 if insn == 0x0ff0_000f => "fence".to_owned(),
+            0 => {
+                let set = |bits: u32| {
+                    let s: String = "iorw"
+                        .chars()
+                        .enumerate()
+                        .filter(|(i, _)| bits & (8 >> i) != 0)
+                        .map(|(_, c)| c)
+                        .collect();
+                    s
+                };
+                op("fence", &format!("{}, {}", set((insn >> 24) & 0xf), set((insn >> 20) & 0xf)))
+            }
+            1 => "fence.i".to_owned(),
+            _ => return None,
+        },
+        0x73 => {
+            let number = insn >> 20;
+            match funct3 {
+                0 => match insn {
+                    0x0000_0073 => "ecall".to_owned(),
+                    0x0010_0073 => "ebreak".to_owned(),
+                    0x1020_0073 => "sret".to_owned(),
+                    0x3020_0073 => "mret".to_owned(),
+                    0x7b20_0073 => "dret".to_owned(),
+                    0x1050_0073 => "wfi".to_owned(),
+                    _ if funct7 == 0x09 && rd == 0 => {
+                        op("sfence.vma", &format!("{}, {}", reg(rs1), reg(rs2)))
+                    }
+                    _ => return None,
+                },
+                2 if rs1 == 0 => op("csrr", &format!("{}, {}", reg(rd), csr(number))),
+                1 if rd == 0 => op("csrw", &format!("{}, {}", csr(number), reg(rs1))),
+                2 if rd == 0 => op("csrs", &format!("{}, {}", csr(number), reg(rs1))),
+                3 if rd == 0 => op("csrc", &format!("{}, {}", csr(number), reg(rs1))),
+                1..=3 => op(
+                    ["csrrw", "csrrs", "csrrc"][funct3 as usize - 1],
+                    &format!("{}, {}, {}", reg(rd), csr(number), reg(rs1)),
+                ),
+                5 if rd == 0 => op("csrwi", &format!("{}, {}", csr(number), rs1)),
+                6 if rd == 0 => op("csrsi", &format!("{}, {}", csr(number), rs1)),
+                7 if rd == 0 => op("csrci", &format!("{}, {}", csr(number), rs1)),
+                5..=7 => op(
+                    ["csrrwi", "csrrsi", "csrrci"][funct3 as usize - 5],
+                    &format!("{}, {}, {}", reg(rd), csr(number), rs1),
+                ),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    })
+}
+
+fn decode_compressed(insn: u32, pc: u32) -> Option<String> {
+    if insn == 0 {
+        // All zeroes is defined to be illegal, and is usually empty memory
+        return None;
+    }
+    let funct3 = (insn >> 13) & 7;
+    let bit12 = (insn >> 12) & 1;
+    let rd = (insn >> 7) & 0x1f;
+    let rs2 = (insn >> 2) & 0x1f;
+    // The three-bit register fields name x8-x15
+    let rdc = 8 + ((insn >> 2) & 7);
+    let rs1c = 8 + ((insn >> 7) & 7);
+    let imm6 = sign_extend(bit12 << 5 | rs2, 6);
+    let word_offset = ((insn >> 10) & 7) << 3 | ((insn >> 6) & 1) << 2 | ((insn >> 5) & 1) << 6;
+    let double_offset = ((insn >> 10) & 7) << 3 | ((insn >> 5) & 3) << 6;
+
+    Some(match (insn & 3, funct3) {
+        (0, 0) => {
+            let imm = ((insn >> 7) & 0x30) | ((insn >> 1) & 0x3c0) | ((insn >> 4) & 4) | ((insn >> 2) & 8);
+            if imm == 0 {
+                return None;
+            }
+            op("c.addi4spn", &format!("{}, sp, {}", reg(rdc), imm))
+        }
+        (0, 1) => op("c.fld", &format!("{}, {}({})", freg(rdc), double_offset, reg(rs1c))),
+        (0, 2) => op("c.lw", &format!("{}, {}({})", reg(rdc), word_offset, reg(rs1c))),
+        (0, 3) => op("c.flw", &format!("{}, {}({})", freg(rdc), word_offset, reg(rs1c))),
+        (0, 5) => op("c.fsd", &format!("{}, {}({})", freg(rdc), double_offset, reg(rs1c))),
+        (0, 6) => op("c.sw", &format!("{}, {}({})", reg(rdc), word_offset, reg(rs1c))),
+        (0, 7) => op("c.fsw", &format!("{}, {}({})", freg(rdc), word_offset, reg(rs1c))),
+
+        (1, 0) if rd == 0 => "c.nop".to_owned(),
+        (1, 0) => op("c.addi", &format!("{}, {}", reg(rd), imm6)),
+        (1, 1) | (1, 5) => {
+            let offset = sign_extend(
+                bit12 << 11
+                    | ((insn >> 11) & 1) << 4
+                    | ((insn >> 9) & 3) << 8
+                    | ((insn >> 8) & 1) << 10
+                    | ((insn >> 7) & 1) << 6
+                    | ((insn >> 6) & 1) << 7
+                    | ((insn >> 3) & 7) << 1
+                    | ((insn >> 2) & 1) << 5,
+                12,
+            );
+            op(if funct3 == 1 { "c.jal" } else { "c.j" }, &target(pc, offset))
+        }
+        (1, 2) => op("c.li", &format!("{}, {}", reg(rd), imm6)),
+        (1, 3) if rd == 2 => {
+            let imm = sign_extend(
+                bit12 << 9
+                    | ((insn >> 6) & 1) << 4
+                    | ((insn >> 5) & 1) << 6
+                    | ((insn >> 3) & 3) << 7
+                    | ((insn >> 2) & 1) << 5,
+                10,
+            );
+            if imm == 0 {
+                return None;
+            }
+            op("c.addi16sp", &format!("sp, {}", imm))
+        }
+        (1, 3) if imm6 != 0 => op("c.lui", &format!("{}, 0x{:x}", reg(rd), imm6 as u32 & 0xfffff)),
+        (1, 4) => {
+            let shamt = bit12 << 5 | rs2;
+            match (insn >> 10) & 3 {
+                0 if bit12 == 0 => op("c.srli", &format!("{}, {}", reg(rs1c), shamt)),
+                1 if bit12 == 0 => op("c.srai", &format!("{}, {}", reg(rs1c), shamt)),
+                2 => op("c.andi", &format!("{}, {}", reg(rs1c), imm6)),
+                3 if bit12 == 0 => {
+                    let mnemonic = ["c.sub", "c.xor", "c.or", "c.and"][((insn >> 5) & 3) as usize];
+                    op(mnemonic, &format!("{}, {}", reg(rs1c), reg(rdc)))
+                }
+                _ => return None,
+            }
+        }
+        (1, 6) | (1, 7) => {
+            let offset = sign_extend(
+                bit12 << 8
+                    | ((insn >> 10) & 3) << 3
+                    | ((insn >> 5) & 3) << 6
+                    | ((insn >> 3) & 3) << 1
+                    | ((insn >> 2) & 1) << 5,
+                9,
+            );
+            op(
+                if funct3 == 6 { "c.beqz" } else { "c.bnez" },
+                &format!("{}, {}", reg(rs1c), target(pc, offset)),
+            )
+        }
+
+        (2, 0) if bit12 == 0 => op("c.slli", &format!("{}, {}", reg(rd), rs2)),
+        (2, 1) => {
+            let offset = bit12 << 5 | ((insn >> 5) & 3) << 3 | ((insn >> 2) & 7) << 6;
+            op("c.fldsp", &format!("{}, {}(sp)", freg(rd), offset))
+        }
+        (2, 2) | (2, 3) if funct3 == 3 || rd != 0 => {
+            let offset = bit12 << 5 | ((insn >> 4) & 7) << 2 | ((insn >> 2) & 3) << 6;
+            if funct3 == 2 {
+                op("c.lwsp", &format!("{}, {}(sp)", reg(rd), offset))
+            } else {
+                op("c.flwsp", &format!("{}, {}(sp)", freg(rd), offset))
+            }
+        }
+        (2, 4) => match (bit12, rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => op("c.jr", reg(rd)),
+            (0, _, _) => op("c.mv", &format!("{}, {}", reg(rd), reg(rs2))),
+            (_, 0, 0) => "c.ebreak".to_owned(),
+            (_, _, 0) => op("c.jalr", reg(rd)),
+            (_, _, _) => op("c.add", &format!("{}, {}", reg(rd), reg(rs2))),
+        },
+        (2, 5) => {
+            let offset = ((insn >> 10) & 7) << 3 | ((insn >> 7) & 7) << 6;
+            op("c.fsdsp", &format!("{}, {}(sp)", freg(rs2), offset))
+        }
+        (2, 6) | (2, 7) => {
+            let offset = ((insn >> 9) & 0xf) << 2 | ((insn >> 7) & 3) << 6;
+            if funct3 == 6 {
+                op("c.swsp", &format!("{}, {}(sp)", reg(rs2), offset))
+            } else {
+                op("c.fswsp", &format!("{}, {}(sp)", freg(rs2), offset))
+            }
+        }
+        _ => return None,
+    })
+}
+
+/// Disassemble `count` instructions from `addr` on, reading memory a word
+/// at a time with `read`
+pub fn listing<F>(addr: u32, count: usize, mut read: F) -> Result<Vec<Line>, RiscvCpuError>
+where
+    F: FnMut(u32) -> Result<u32, RiscvCpuError>,
+{
+    let mut lines = Vec::with_capacity(count);
+    let mut addr = addr & !1;
+    for _ in 0..count {
+        let insn = fetch(addr, &mut read)?;
+        lines.push(Line {
+            addr,
+            insn,
+            text: decode(insn, addr),
+        });
+        addr = addr.wrapping_add(instruction_length(insn));
+    }
+    Ok(lines)
+}
+
+/// Disassemble about `before` instructions leading up to `pc`, the one
+/// at `pc`, and `after` more.  With compressed instructions there's no
+/// telling where the ones before `pc` start, so decoding is begun from a
+/// few places until one lands on `pc`.  If memory before `pc` can't be
+/// read, or nothing lands on it, the listing starts at `pc`.
+pub fn around<F>(
+    pc: u32,
+    before: usize,
+    after: usize,
+    mut read: F,
+) -> Result<Vec<Line>, RiscvCpuError>
+where
+    F: FnMut(u32) -> Result<u32, RiscvCpuError>,
+{
+    let pc = pc & !1;
+    // Each start point reads much the same memory
+    let mut words = HashMap::new();
+    let mut cached = |addr: u32| -> Result<u32, RiscvCpuError> {
+        if let Some(word) = words.get(&addr) {
+            return Ok(*word);
+        }
+        let word = read(addr)?;
+        words.insert(addr, word);
+        Ok(word)
+    };
+
+    let span = (4 * before as u32).min(pc) & !1;
+    let mut leading = vec![];
+    if span > 0 {
+        for start in (0..=span / 2).map(|n| pc - span + 2 * n) {
+            let mut lines = vec![];
+            let mut addr = start;
+            while addr < pc {
+                let insn = match fetch(addr, &mut cached) {
+                    Ok(insn) => insn,
+                    Err(_) => break,
+                };
+                lines.push(Line {
+                    addr,
+                    insn,
+                    text: decode(insn, addr),
+                });
+                addr = addr.wrapping_add(instruction_length(insn));
+            }
+            if addr == pc && !lines.is_empty() {
+                leading = lines;
+                break;
+            }
+        }
+    }
+    let skip = leading.len().saturating_sub(before);
+    let mut lines: Vec<Line> = leading.into_iter().skip(skip).collect();
+    lines.extend(listing(pc, after + 1, &mut cached)?);
+    Ok(lines)
+}
+
+/// A listing as text, with the line at `pc` marked
+pub fn show(lines: &[Line], pc: Option<u32>) -> String {
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line.format(Some(line.addr) == pc));
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,694 @@
+use crate::bridge::Bridge;
+use super::{RiscvCpuError, Xlen};
+
+use log::debug;
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Debug Module registers, as defined by the RISC-V External Debug
+/// Support specification, version 0.13.  Each DMI address is mapped
+/// onto a 32-bit word on the Wishbone bus.
+const DMI_DATA0: u32 = 0x04;
+const DMI_DATA1: u32 = 0x05;
+const DMI_DATA3: u32 = 0x07;
+const DMI_DMCONTROL: u32 = 0x10;
+const DMI_DMSTATUS: u32 = 0x11;
+const DMI_ABSTRACTCS: u32 = 0x16;
+const DMI_COMMAND: u32 = 0x17;
+const DMI_PROGBUF0: u32 = 0x20;
+const DMI_SBCS: u32 = 0x38;
+
+/// Abstract register numbers for the "Access Register" command
+const REGNO_CSR_BASE: u32 = 0x0000;
+const REGNO_GPR_BASE: u32 = 0x1000;
+const REGNO_FPR_BASE: u32 = 0x1020;
+
+/// The `dcsr` and `dpc` CSRs, which are only accessible in debug mode
+pub const CSR_DCSR: u32 = 0x7b0;
+pub const CSR_DPC: u32 = 0x7b1;
+const CSR_MISA: u32 = 0x301;
+
+/// EBREAK, used to terminate the program buffer
+const OPCODE_EBREAK: u32 = 0x0010_0073;
+
+/// How many times to poll the debug module before giving up
+const POLL_COUNT: u32 = 100;
+
+/// Harts past this many aren't looked for
+const MAX_HARTS: u32 = 64;
+
+/// `hartsel` with every bit set, which reads back with only the bits the
+/// Debug Module implements
+const HARTSEL_ALL: u32 = (1 << 20) - 1;
+
+/// Place a hart index in the `hartsello` and `hartselhi` fields of
+/// `dmcontrol`
+fn hartsel_bits(hart: u32) -> u32 {
+    ((hart & 0x3ff) << 16) | (((hart >> 10) & 0x3ff) << 6)
+}
+
+fn hartsel_of(dmcontrol: u32) -> u32 {
+    ((dmcontrol >> 16) & 0x3ff) | (((dmcontrol >> 6) & 0x3ff) << 10)
+}
+
+bitflags! {
+    struct DmControl: u32 {
+        const HALTREQ = 1 << 31;
+        const RESUMEREQ = 1 << 30;
+        const ACKHAVERESET = 1 << 28;
+        const NDMRESET = 1 << 1;
+        const DMACTIVE = 1 << 0;
+    }
+}
+
+bitflags! {
+    struct DmStatus: u32 {
+        const IMPEBREAK = 1 << 22;
+        const ALLRESUMEACK = 1 << 17;
+        const ALLNONEXISTENT = 1 << 15;
+        const ANYNONEXISTENT = 1 << 14;
+        const ALLHALTED = 1 << 9;
+        const AUTHENTICATED = 1 << 7;
+    }
+}
+
+bitflags! {
+    struct AbstractCommand: u32 {
+        const AARSIZE_32 = 2 << 20;
+        const AARSIZE_64 = 3 << 20;
+        const AARSIZE_128 = 4 << 20;
+        const POSTEXEC = 1 << 18;
+        const TRANSFER = 1 << 17;
+        const WRITE = 1 << 16;
+    }
+}
+
+/// The `cause` field of `dcsr`, describing why the hart entered debug mode
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DebugCause {
+    Ebreak,
+    Trigger,
+    HaltRequest,
+    Step,
+    ResetHaltRequest,
+    Unknown(u32),
+}
+
+impl DebugCause {
+    pub fn from_dcsr(dcsr: u32) -> DebugCause {
+        match (dcsr >> 6) & 7 {
+            1 => DebugCause::Ebreak,
+            2 => DebugCause::Trigger,
+            3 => DebugCause::HaltRequest,
+            4 => DebugCause::Step,
+            5 => DebugCause::ResetHaltRequest,
+            x => DebugCause::Unknown(x),
+        }
+    }
+}
+
+/// A Debug Module that is attached to the Wishbone bus.  Everything but
+/// the `hart` functions acts on the selected hart, which is shared with
+/// every copy.
+#[derive(Debug, Clone)]
+pub struct DebugModule {
+    /// The Wishbone address of DMI register 0
+    base: u32,
+
+    /// Number of words in the program buffer
+    progbuf_size: u32,
+
+    /// "true" if the program buffer is followed by an implicit ebreak
+    impebreak: bool,
+
+    /// How wide the hart's registers are, once `detect_xlen()` has looked
+    xlen: Xlen,
+
+    /// The `sbaccess` bits of `sbcs`: bit n set means the system bus can
+    /// be accessed (8 << n) bits at a time.  0 if there's no system bus
+    /// access at all.
+    sbaccess: u32,
+
+    /// Number of harts behind the Debug Module
+    harts: u32,
+
+    /// The hart that `hartsel` picks
+    hartsel: Arc<AtomicU32>,
+
+    /// Harts to be left halted while the rest run, until they're all
+    /// halted again.  A Debug Module can have far more harts than would
+    /// fit in a mask.
+    parked: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl DebugModule {
+    /// Activate the debug module and determine what it supports.
+    pub fn new(bridge: &Bridge, base: u32) -> Result<DebugModule, RiscvCpuError> {
+        let mut dm = DebugModule {
+            base,
+            progbuf_size: 0,
+            impebreak: false,
+            xlen: Xlen::Rv32,
+            sbaccess: 0,
+            harts: 1,
+            hartsel: Arc::new(AtomicU32::new(0)),
+            parked: Arc::new(Mutex::new(HashSet::new())),
+        };
+
+        dm.write(bridge, DMI_DMCONTROL, dm.control(DmControl::DMACTIVE))?;
+        dm.wait_for_active(bridge)?;
+        let raw_status = dm.read(bridge, DMI_DMSTATUS)?;
+        let status = DmStatus::from_bits_truncate(raw_status);
+        let version = raw_status & 0xf;
+        if version != 2 {
+            return Err(RiscvCpuError::DebugModuleNotFound(version));
+        }
+        if !status.contains(DmStatus::AUTHENTICATED) {
+            return Err(RiscvCpuError::DebugModuleNotFound(version));
+        }
+        if status.contains(DmStatus::ALLNONEXISTENT) {
+            return Err(RiscvCpuError::DebugModuleNotFound(version));
+        }
+
+        let abstractcs = dm.read(bridge, DMI_ABSTRACTCS)?;
+        dm.progbuf_size = (abstractcs >> 24) & 0x1f;
+        dm.impebreak = status.contains(DmStatus::IMPEBREAK);
+        let sbcs = dm.read(bridge, DMI_SBCS)?;
+        if sbcs >> 29 != 0 {
+            dm.sbaccess = sbcs & 0x1f;
+        }
+        dm.harts = dm.count_harts(bridge)?;
+        debug!(
+            "debug module at {:08x}: progbuf size {}, impebreak: {}, sbcs {:08x}, {} harts",
+            base, dm.progbuf_size, dm.impebreak, sbcs, dm.harts
+        );
+        Ok(dm)
+    }
+
+    /// Select each hart in turn until one doesn't exist.  Only as many
+    /// `hartsel` bits as there could be harts are kept, which puts a limit
+    /// on the search.
+    fn count_harts(&self, bridge: &Bridge) -> Result<u32, RiscvCpuError> {
+        self.write(
+            bridge,
+            DMI_DMCONTROL,
+            DmControl::DMACTIVE.bits() | hartsel_bits(HARTSEL_ALL),
+        )?;
+        let max = hartsel_of(self.read(bridge, DMI_DMCONTROL)?).min(MAX_HARTS - 1);
+        let mut harts = 1;
+        while harts <= max {
+            self.select_hart(bridge, harts)?;
+            let status = DmStatus::from_bits_truncate(self.read(bridge, DMI_DMSTATUS)?);
+            if status.contains(DmStatus::ANYNONEXISTENT) {
+                break;
+            }
+            harts += 1;
+        }
+        self.select_hart(bridge, 0)?;
+        Ok(harts)
+    }
+
+    /// `dmcontrol` with `flags` set, keeping the selected hart selected
+    fn control(&self, flags: DmControl) -> u32 {
+        flags.bits() | hartsel_bits(self.hart())
+    }
+
+    pub fn harts(&self) -> u32 {
+        self.harts
+    }
+
+    /// The index of the selected hart, which needn't be its `mhartid`
+    pub fn hart(&self) -> u32 {
+        self.hartsel.load(Ordering::Relaxed)
+    }
+
+    pub fn select_hart(&self, bridge: &Bridge, hart: u32) -> Result<(), RiscvCpuError> {
+        self.hartsel.store(hart, Ordering::Relaxed);
+        self.write(bridge, DMI_DMCONTROL, self.control(DmControl::DMACTIVE))
+    }
+
+    /// Run `f` with each hart selected in turn, leaving the one that was
+    /// selected selected again.  It gets its turn last.
+    pub fn for_each_hart<F>(&self, bridge: &Bridge, mut f: F) -> Result<(), RiscvCpuError>
+    where
+        F: FnMut(u32) -> Result<(), RiscvCpuError>,
+    {
+        let selected = self.hart();
+        let result = (0..self.harts)
+            .filter(|hart| *hart != selected)
+            .chain(std::iter::once(selected))
+            .try_for_each(|hart| {
+                self.select_hart(bridge, hart)?;
+                f(hart)
+            });
+        if self.hart() != selected {
+            self.select_hart(bridge, selected)?;
+        }
+        result
+    }
+
+    /// Leave `harts` halted the next time the others are resumed
+    pub fn park(&self, harts: &[u32]) {
+        *self.parked.lock().unwrap() = harts.iter().copied().collect();
+    }
+
+    /// Park or unpark one hart, leaving the others as they are
+    pub fn set_parked(&self, hart: u32, parked: bool) {
+        let mut set = self.parked.lock().unwrap();
+        if parked {
+            set.insert(hart);
+        } else {
+            set.remove(&hart);
+        }
+    }
+
+    pub fn is_parked(&self, hart: u32) -> bool {
+        self.parked.lock().unwrap().contains(&hart)
+    }
+
+    /// The first hart found halted, trying the selected one first.  Other
+    /// harts are only looked at when there are any, so a single hart
+    /// costs one read, as it always did.  Parked harts don't count, since
+    /// they were never let go.
+    pub fn halted_hart(&self, bridge: &Bridge) -> Result<Option<u32>, RiscvCpuError> {
+        let selected = self.hart();
+        if !self.is_parked(selected) && self.is_halted(bridge)? {
+            return Ok(Some(selected));
+        }
+        let mut found = None;
+        for hart in (0..self.harts).filter(|hart| *hart != selected && !self.is_parked(*hart)) {
+            self.select_hart(bridge, hart)?;
+            if self.is_halted(bridge)? {
+                found = Some(hart);
+                break;
+            }
+        }
+        self.select_hart(bridge, selected)?;
+        Ok(found)
+    }
+
+    fn read(&self, bridge: &Bridge, reg: u32) -> Result<u32, RiscvCpuError> {
+        Ok(bridge.peek(self.base + reg * 4)?)
+    }
+
+    fn write(&self, bridge: &Bridge, reg: u32, value: u32) -> Result<(), RiscvCpuError> {
+        Ok(bridge.poke(self.base + reg * 4, value)?)
+    }
+
+    /// How many instructions the program buffer holds, not counting the
+    /// `ebreak` that has to end it
+    pub fn progbuf_capacity(&self) -> u32 {
+        if self.impebreak {
+            self.progbuf_size
+        } else {
+            self.progbuf_size.saturating_sub(1)
+        }
+    }
+
+    /// The widths, in bits, the Debug Module can access the system bus at
+    pub fn system_bus_widths(&self) -> Vec<u32> {
+        (0..5)
+            .filter(|n| self.sbaccess & (1 << n) != 0)
+            .map(|n| 8 << n)
+            .collect()
+    }
+
+    /// Returns `true` if the program buffer can hold at least one instruction
+    pub fn has_progbuf(&self) -> bool {
+        self.progbuf_capacity() > 0
+    }
+
+    pub fn is_halted(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        let status = DmStatus::from_bits_truncate(self.read(bridge, DMI_DMSTATUS)?);
+        Ok(status.contains(DmStatus::ALLHALTED))
+    }
+
+    pub fn halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.write(
+            bridge,
+            DMI_DMCONTROL,
+            self.control(DmControl::DMACTIVE | DmControl::HALTREQ),
+        )?;
+        let result = self.wait_for_status(bridge, DmStatus::ALLHALTED);
+        self.write(bridge, DMI_DMCONTROL, self.control(DmControl::DMACTIVE))?;
+        result
+    }
+
+    /// Halt every hart, so that stopping one stops them all as GDB expects
+    pub fn halt_all(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let result = self.for_each_hart(bridge, |_| {
+            if self.is_halted(bridge)? {
+                return Ok(());
+            }
+            self.halt(bridge)
+        });
+        self.parked.lock().unwrap().clear();
+        result
+    }
+
+    pub fn resume(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.write(
+            bridge,
+            DMI_DMCONTROL,
+            self.control(DmControl::DMACTIVE | DmControl::RESUMEREQ),
+        )?;
+        let result = self.wait_for_status(bridge, DmStatus::ALLRESUMEACK);
+        self.write(bridge, DMI_DMCONTROL, self.control(DmControl::DMACTIVE))?;
+        result
+    }
+
+    /// Let every hart but the parked ones run.  Only the selected one can
+    /// have been stepped, but any that were stepped before another was
+    /// selected would stop again straight away, so they all have
+    /// `dcsr.step` cleared.
+    pub fn resume_all(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.for_each_hart(bridge, |hart| {
+            if self.is_parked(hart) || !self.is_halted(bridge)? {
+                return Ok(());
+            }
+            self.set_step(bridge, false)?;
+            self.resume(bridge)
+        })
+    }
+
+    /// Reset the hart and leave it halted.
+    pub fn reset(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.write(
+            bridge,
+            DMI_DMCONTROL,
+            self.control(DmControl::DMACTIVE | DmControl::HALTREQ | DmControl::NDMRESET),
+        )?;
+        self.write(
+            bridge,
+            DMI_DMCONTROL,
+            self.control(DmControl::DMACTIVE | DmControl::HALTREQ),
+        )?;
+        self.wait_for_status(bridge, DmStatus::ALLHALTED)?;
+        self.write(
+            bridge,
+            DMI_DMCONTROL,
+            self.control(DmControl::DMACTIVE | DmControl::ACKHAVERESET),
+        )?;
+        Ok(())
+    }
+
+    /// Wait for `dmactive` to read back as set.  The debug module may take
+    /// a while to come out of reset, and ignores everything else until it
+    /// has.
+    fn wait_for_active(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        for _ in 0..POLL_COUNT {
+            let control = DmControl::from_bits_truncate(self.read(bridge, DMI_DMCONTROL)?);
+            if control.contains(DmControl::DMACTIVE) {
+                return Ok(());
+            }
+        }
+        Err(RiscvCpuError::DebugModuleInactive)
+    }
+
+    fn wait_for_status(&self, bridge: &Bridge, flag: DmStatus) -> Result<(), RiscvCpuError> {
+        for _ in 0..POLL_COUNT {
+            let status = DmStatus::from_bits_truncate(self.read(bridge, DMI_DMSTATUS)?);
+            if status.contains(flag) {
+                return Ok(());
+            }
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
+    /// Issue an abstract command and wait for it to complete.
+    fn execute_command(&self, bridge: &Bridge, command: u32) -> Result<(), RiscvCpuError> {
+        self.write(bridge, DMI_COMMAND, command)?;
+        for _ in 0..POLL_COUNT {
+            let abstractcs = self.read(bridge, DMI_ABSTRACTCS)?;
+            if abstractcs & (1 << 12) != 0 {
+                continue;
+            }
+            let cmderr = (abstractcs >> 8) & 7;
+            if cmderr != 0 {
+                // Clear the error, which is write-1-to-clear.
+                self.write(bridge, DMI_ABSTRACTCS, 7 << 8)?;
+                return Err(RiscvCpuError::AbstractCommandError(cmderr));
+            }
+            return Ok(());
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
+    /// Translate a RISC-V register number into an abstract `regno`.  GPRs
+    /// are numbered 0 - 31, and CSRs are passed as-is.
+    pub fn gpr_regno(index: u32) -> u32 {
+        REGNO_GPR_BASE + index
+    }
+
+    fn csr_regno(index: u32) -> u32 {
+        REGNO_CSR_BASE + (index & 0xfff)
+    }
+
+    pub fn fpr_regno(index: u32) -> u32 {
+        REGNO_FPR_BASE + index
+    }
+
+    fn aarsize(bits: u32) -> AbstractCommand {
+        match bits {
+            32 => AbstractCommand::AARSIZE_32,
+            64 => AbstractCommand::AARSIZE_64,
+            _ => AbstractCommand::AARSIZE_128,
+        }
+    }
+
+    /// Work out how wide the hart's registers are.  The top two bits of
+    /// `misa` say, but where they are depends on the answer, so try each
+    /// width in turn.  If `misa` reads as zero then fall back on the fact
+    /// that accessing a register with a size wider than it is must fail,
+    /// and find the widest access to `s0` that works.  The hart must be
+    /// halted.  An RV128 hart is found, but only so that it can be
+    /// refused, since only DATA0 and DATA1 are ever moved.
+    pub fn detect_xlen(&mut self, bridge: &Bridge) -> Result<Xlen, RiscvCpuError> {
+        let widths = [Xlen::Rv32, Xlen::Rv64];
+        for xlen in &widths {
+            self.xlen = *xlen;
+            match self.read_register_wide(bridge, Self::csr_regno(CSR_MISA)) {
+                Ok(misa) => {
+                    let mxl = (misa >> (xlen.bits() - 2)) & 3;
+                    if let Some(found) = Xlen::from_mxl(mxl as u32) {
+                        self.xlen = found;
+                        debug!("misa says the hart is {:?}", found);
+                        return Ok(found);
+                    }
+                    if misa == 0 {
+                        break;
+                    }
+                }
+                Err(RiscvCpuError::AbstractCommandError(_)) => (),
+                Err(e) => {
+                    self.xlen = Xlen::Rv32;
+                    return Err(e);
+                }
+            }
+        }
+        // The MXL of an RV128 misa is at the top of DATA3 after a 128-bit
+        // read, and if misa isn't there, its s0 can still be read that wide
+        match self.read_register_sized(bridge, Self::csr_regno(CSR_MISA), 128) {
+            Ok(_) if self.read(bridge, DMI_DATA3)? >> 30 == 3 => {
+                debug!("misa says the hart is {:?}", Xlen::Rv128);
+                self.xlen = Xlen::Rv128;
+                return Ok(Xlen::Rv128);
+            }
+            Ok(_) | Err(RiscvCpuError::AbstractCommandError(_)) => (),
+            Err(e) => {
+                self.xlen = Xlen::Rv32;
+                return Err(e);
+            }
+        }
+        if self.read_register_sized(bridge, Self::gpr_regno(8), 128).is_ok() {
+            self.xlen = Xlen::Rv128;
+            return Ok(Xlen::Rv128);
+        }
+        for xlen in widths.iter().rev() {
+            self.xlen = *xlen;
+            match self.read_register_wide(bridge, Self::gpr_regno(8)) {
+                Ok(_) => {
+                    debug!("s0 reads as {:?}", xlen);
+                    return Ok(*xlen);
+                }
+                Err(RiscvCpuError::AbstractCommandError(_)) => (),
+                Err(e) => {
+                    self.xlen = Xlen::Rv32;
+                    return Err(e);
+                }
+            }
+        }
+        // Nothing else works without reading registers, so stop here
+        // rather than with a confusing error later on
+        self.xlen = Xlen::Rv32;
+        Err(RiscvCpuError::MissingCapability(
+            "the debug module can't read registers with abstract commands",
+        ))
+    }
+
+    pub fn read_register(&self, bridge: &Bridge, regno: u32) -> Result<u32, RiscvCpuError> {
+        Ok(self.read_register_wide(bridge, regno)? as u32)
+    }
+
+    pub fn write_register(
+        &self,
+        bridge: &Bridge,
+        regno: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        self.write_register_wide(bridge, regno, value as u64)
+    }
+
+    /// Read a register at its full width.
+    pub fn read_register_wide(&self, bridge: &Bridge, regno: u32) -> Result<u64, RiscvCpuError> {
+        self.read_register_sized(bridge, regno, self.xlen.bits())
+    }
+
+    pub fn write_register_wide(
+        &self,
+        bridge: &Bridge,
+        regno: u32,
+        value: u64,
+    ) -> Result<(), RiscvCpuError> {
+        self.write_register_sized(bridge, regno, value, self.xlen.bits())
+    }
+
+    /// Read a register that's `bits` wide, which for the FPRs needn't be
+    /// the same as the hart's XLEN.
+    pub fn read_register_sized(
+        &self,
+        bridge: &Bridge,
+        regno: u32,
+        bits: u32,
+    ) -> Result<u64, RiscvCpuError> {
+        self.execute_command(
+            bridge,
+            (Self::aarsize(bits) | AbstractCommand::TRANSFER).bits() | regno,
+        )?;
+        let mut value = self.read(bridge, DMI_DATA0)? as u64;
+        if bits > 32 {
+            value |= (self.read(bridge, DMI_DATA1)? as u64) << 32;
+        }
+        debug!("DMI register {:04x} value: 0x{:08x}", regno, value);
+        Ok(value)
+    }
+
+    pub fn write_register_sized(
+        &self,
+        bridge: &Bridge,
+        regno: u32,
+        value: u64,
+        bits: u32,
+    ) -> Result<(), RiscvCpuError> {
+        debug!("DMI setting register {:04x} -> {:08x}", regno, value);
+        self.write(bridge, DMI_DATA0, value as u32)?;
+        if bits > 32 {
+            self.write(bridge, DMI_DATA1, (value >> 32) as u32)?;
+        }
+        self.execute_command(
+            bridge,
+            (Self::aarsize(bits) | AbstractCommand::TRANSFER | AbstractCommand::WRITE).bits()
+                | regno,
+        )
+    }
+
+    /// Load the given instructions into the program buffer and execute them.
+    pub fn execute_progbuf(&self, bridge: &Bridge, opcodes: &[u32]) -> Result<(), RiscvCpuError> {
+        if opcodes.len() as u32 > self.progbuf_capacity() {
+            return Err(RiscvCpuError::ProgramBufferTooSmall(opcodes.len() as u32));
+        }
+        let mut offset = 0;
+        for opcode in opcodes {
+            self.write(bridge, DMI_PROGBUF0 + offset, *opcode)?;
+            offset += 1;
+        }
+        if !self.impebreak {
+            self.write(bridge, DMI_PROGBUF0 + offset, OPCODE_EBREAK)?;
+        }
+
+        // Issue an "access register" command with "transfer" cleared, which
+        // does nothing other than run the program buffer.
+        self.execute_command(
+            bridge,
+            (Self::aarsize(self.xlen.bits()) | AbstractCommand::POSTEXEC).bits(),
+        )
+    }
+
+    /// Read a CSR.  Many debug modules only support GPRs in the "Access
+    /// Register" command, so if there is a program buffer then run a
+    /// `csrr` instruction from it instead.
+    pub fn read_csr(&self, bridge: &Bridge, csr: u32) -> Result<u32, RiscvCpuError> {
+        Ok(self.read_csr_wide(bridge, csr)? as u32)
+    }
+
+    /// Write a CSR, running a `csrw` from the program buffer if possible.
+    pub fn write_csr(&self, bridge: &Bridge, csr: u32, value: u32) -> Result<(), RiscvCpuError> {
+        self.write_csr_wide(bridge, csr, value as u64)
+    }
+
+    /// Read a CSR at the hart's full register width.
+    pub fn read_csr_wide(&self, bridge: &Bridge, csr: u32) -> Result<u64, RiscvCpuError> {
+        if !self.has_progbuf() {
+            return self.read_register_wide(bridge, Self::csr_regno(csr));
+        }
+
+        // x1 is used as a scratch register, so save it first.
+        let x1 = self.read_register_wide(bridge, Self::gpr_regno(1))?;
+        let result = self
+            .execute_progbuf(bridge, &[Self::csr_opcode(csr, 2, 0, 1)]) // csrr x1, csr
+            .and_then(|_| self.read_register_wide(bridge, Self::gpr_regno(1)));
+        self.write_register_wide(bridge, Self::gpr_regno(1), x1)?;
+        debug!("DMI CSR {:03x} value: 0x{:08x}", csr, result.as_ref().unwrap_or(&0));
+        result
+    }
+
+    pub fn write_csr_wide(&self, bridge: &Bridge, csr: u32, value: u64) -> Result<(), RiscvCpuError> {
+        if !self.has_progbuf() {
+            return self.write_register_wide(bridge, Self::csr_regno(csr), value);
+        }
+
+        let x1 = self.read_register_wide(bridge, Self::gpr_regno(1))?;
+        self.write_register_wide(bridge, Self::gpr_regno(1), value)?;
+        let result = self.execute_progbuf(bridge, &[Self::csr_opcode(csr, 1, 1, 0)]); // csrw csr, x1
+        self.write_register_wide(bridge, Self::gpr_regno(1), x1)?;
+        result
+    }
+
+    /// Encode a Zicsr instruction: `funct3` is 1 for CSRRW and 2 for CSRRS.
+    fn csr_opcode(csr: u32, funct3: u32, rs1: u32, rd: u32) -> u32 {
+        ((csr & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x73
+    }
+
+    /// Set or clear the `step` bit in `dcsr`, so the next resume only
+    /// executes a single instruction.
+    pub fn set_step(&self, bridge: &Bridge, step: bool) -> Result<(), RiscvCpuError> {
+        let dcsr = self.read_csr(bridge, CSR_DCSR)?;
+        let new_dcsr = if step { dcsr | (1 << 2) } else { dcsr & !(1 << 2) };
+        if new_dcsr != dcsr {
+            self.write_csr(bridge, CSR_DCSR, new_dcsr)?;
+        }
+        Ok(())
+    }
+
+    /// Set or clear `ebreakm`, `ebreaks` and `ebreaku` in `dcsr`, which make
+    /// an `ebreak` enter debug mode instead of trapping.  Software
+    /// breakpoints need them, but firmware that uses `ebreak` itself
+    /// expects the trap, so they're only set while there are any.
+    pub fn set_ebreak(&self, bridge: &Bridge, enable: bool) -> Result<(), RiscvCpuError> {
+        let bits = (1 << 15) | (1 << 13) | (1 << 12);
+        let dcsr = self.read_csr(bridge, CSR_DCSR)?;
+        let new_dcsr = if enable { dcsr | bits } else { dcsr & !bits };
+        if new_dcsr != dcsr {
+            self.write_csr(bridge, CSR_DCSR, new_dcsr)?;
+        }
+        Ok(())
+    }
+
+    /// Determine why the hart most recently entered debug mode.
+    pub fn halt_cause(&self, bridge: &Bridge) -> Result<DebugCause, RiscvCpuError> {
+        let dcsr = self.read_csr(bridge, CSR_DCSR)?;
+        Ok(DebugCause::from_dcsr(dcsr))
+    }
+}
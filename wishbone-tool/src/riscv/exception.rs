@@ -123,7 +123,7 @@ impl fmt::Display for RiscvException {
             InstructionPageFault(epc, mtval) => write!(f, "Instruction page fault of 0x{:08x} at 0x{:08x}", mtval, epc),
             LoadPageFault(epc, mtval) => write!(f, "Load page fault of 0x{:08x} at 0x{:08x}", mtval, epc),
             // --reserved--
-            StorePageFault(epc, mtval) => write!(f, "Load page fault of 0x{:08x} at 0x{:08x}", mtval, epc),
+            StorePageFault(epc, mtval) => write!(f, "Store page fault of 0x{:08x} at 0x{:08x}", mtval, epc),
             ReservedFault(code, epc, mtval) => write!(f, "Reserved interrupt 0x{:08x} with cause 0x{:08x} at 0x{:08x}", code, mtval, epc),
         }
     }
@@ -178,3 +178,114 @@ impl RiscvException {
         }
     }
 }
+
+/// Show an address with its halves split, as `0x4000_0000`, so that it's
+/// easier to read at a glance
+fn grouped(addr: u32) -> String {
+    format!("0x{:04x}_{:04x}", addr >> 16, addr & 0xffff)
+}
+
+impl RiscvException {
+    pub fn is_interrupt(&self) -> bool {
+        use RiscvException::*;
+        matches!(
+            *self,
+            UserSoftwareInterrupt(_)
+                | SupervisorSoftwareInterrupt(_)
+                | MachineSoftwareInterrupt(_)
+                | UserTimerInterrupt(_)
+                | SupervisorTimerInterrupt(_)
+                | MachineTimerInterrupt(_)
+                | UserExternalInterrupt(_)
+                | SupervisorExternalInterrupt(_)
+                | MachineExternalInterrupt(_)
+                | ReservedInterrupt(_, _)
+        )
+    }
+
+    /// Whether this is a trap that firmware doesn't take on purpose, that
+    /// is anything but an interrupt, an `ebreak` or an `ecall`
+    pub fn is_fault(&self) -> bool {
+        use RiscvException::*;
+        !self.is_interrupt()
+            && !matches!(
+                *self,
+                NoException | Breakpoint(_) | CallFromUMode(_) | CallFromSMode(_) | CallFromMMode(_)
+            )
+    }
+
+    /// Where the trap was taken
+    pub fn epc(&self) -> Option<u32> {
+        use RiscvException::*;
+        match *self {
+            NoException => None,
+            UserSoftwareInterrupt(epc)
+            | SupervisorSoftwareInterrupt(epc)
+            | MachineSoftwareInterrupt(epc)
+            | UserTimerInterrupt(epc)
+            | SupervisorTimerInterrupt(epc)
+            | MachineTimerInterrupt(epc)
+            | UserExternalInterrupt(epc)
+            | SupervisorExternalInterrupt(epc)
+            | MachineExternalInterrupt(epc)
+            | ReservedInterrupt(_, epc)
+            | InstructionAddressMisaligned(epc, _)
+            | InstructionAccessFault(epc, _)
+            | IllegalInstruction(epc, _)
+            | Breakpoint(epc)
+            | LoadAddressMisaligned(epc, _)
+            | LoadAccessFault(epc, _)
+            | StoreAddressMisaligned(epc, _)
+            | StoreAccessFault(epc, _)
+            | CallFromUMode(epc)
+            | CallFromSMode(epc)
+            | CallFromMMode(epc)
+            | InstructionPageFault(epc, _)
+            | LoadPageFault(epc, _)
+            | StorePageFault(epc, _)
+            | ReservedFault(_, epc, _) => Some(epc),
+        }
+    }
+
+    /// A one-line account of the trap, such as "store access fault @
+    /// 0x4000_0000, from pc 0x2000_1234"
+    pub fn describe(&self) -> String {
+        use RiscvException::*;
+        let what = match *self {
+            NoException => return "no trap".to_owned(),
+            UserSoftwareInterrupt(_) => "user software interrupt".to_owned(),
+            SupervisorSoftwareInterrupt(_) => "supervisor software interrupt".to_owned(),
+            MachineSoftwareInterrupt(_) => "machine software interrupt".to_owned(),
+            UserTimerInterrupt(_) => "user timer interrupt".to_owned(),
+            SupervisorTimerInterrupt(_) => "supervisor timer interrupt".to_owned(),
+            MachineTimerInterrupt(_) => "machine timer interrupt".to_owned(),
+            UserExternalInterrupt(_) => "user external interrupt".to_owned(),
+            SupervisorExternalInterrupt(_) => "supervisor external interrupt".to_owned(),
+            MachineExternalInterrupt(_) => "machine external interrupt".to_owned(),
+            ReservedInterrupt(code, _) => format!("reserved interrupt {}", code),
+            InstructionAddressMisaligned(_, mtval) => {
+                format!("instruction address misaligned @ {}", grouped(mtval))
+            }
+            InstructionAccessFault(_, mtval) => format!("instruction access fault @ {}", grouped(mtval)),
+            // mtval holds the instruction itself, if the CPU fills it in
+            IllegalInstruction(_, 0) => "illegal instruction".to_owned(),
+            IllegalInstruction(_, mtval) => format!("illegal instruction 0x{:08x}", mtval),
+            Breakpoint(_) => "breakpoint".to_owned(),
+            LoadAddressMisaligned(_, mtval) => format!("load address misaligned @ {}", grouped(mtval)),
+            LoadAccessFault(_, mtval) => format!("load access fault @ {}", grouped(mtval)),
+            StoreAddressMisaligned(_, mtval) => format!("store address misaligned @ {}", grouped(mtval)),
+            StoreAccessFault(_, mtval) => format!("store access fault @ {}", grouped(mtval)),
+            CallFromUMode(_) => "ecall from user mode".to_owned(),
+            CallFromSMode(_) => "ecall from supervisor mode".to_owned(),
+            CallFromMMode(_) => "ecall from machine mode".to_owned(),
+            InstructionPageFault(_, mtval) => format!("instruction page fault @ {}", grouped(mtval)),
+            LoadPageFault(_, mtval) => format!("load page fault @ {}", grouped(mtval)),
+            StorePageFault(_, mtval) => format!("store page fault @ {}", grouped(mtval)),
+            ReservedFault(code, _, mtval) => format!("reserved exception {} with mtval 0x{:08x}", code, mtval),
+        };
+        match self.epc() {
+            Some(epc) => format!("{}, from pc {}", what, grouped(epc)),
+            None => what,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Sv32 page-table walks, so that the virtual addresses GDB deals in when
+//! paging is on can be turned into the physical ones the bridge needs.
+//! `satp` gives the root table, each of whose 1024 entries maps 4 MiB,
+//! either directly as a megapage or through a second table of 4 KiB
+//! pages.
+
+use super::RiscvCpuError;
+
+/// The bit in `satp` that turns Sv32 on
+pub const SATP_MODE_SV32: u32 = 1 << 31;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+/// Where a virtual address ended up, and what the leaf entry allows
+#[derive(Clone, Debug, PartialEq)]
+pub struct Translation {
+    /// Sv32 physical addresses are 34 bits wide
+    pub physical: u64,
+    pub megapage: bool,
+    pub pte: u32,
+}
+
+impl Translation {
+    pub fn describe(&self, addr: u32) -> String {
+        let flag = |bit: u32, c: char| if self.pte & bit != 0 { c } else { '-' };
+        format!(
+            "0x{:08x} -> 0x{:09x} ({} {}{}{}{}{}{})",
+            addr,
+            self.physical,
+            if self.megapage { "4 MiB megapage" } else { "4 KiB page" },
+            flag(PTE_R, 'r'),
+            flag(PTE_W, 'w'),
+            flag(PTE_X, 'x'),
+            flag(PTE_U, 'u'),
+            flag(PTE_A, 'a'),
+            flag(PTE_D, 'd')
+        )
+    }
+}
+
+/// Walk the tables `satp` points to for `addr`, reading each entry with
+/// `read`, which is given its physical address.  An entry that isn't
+/// valid or is malformed is a page fault, as it would be for the CPU.
+pub fn walk<F>(satp: u32, addr: u32, mut read: F) -> Result<Translation, RiscvCpuError>
+where
+    F: FnMut(u64) -> Result<u32, RiscvCpuError>,
+{
+    let mut table = ((satp & 0x003f_ffff) as u64) << 12;
+    for level in (0..2).rev() {
+        let vpn = (addr >> (12 + 10 * level)) & 0x3ff;
+        let pte = read(table + (vpn << 2) as u64)?;
+        // W without R is reserved
+        if pte & PTE_V == 0 || (pte & PTE_W != 0 && pte & PTE_R == 0) {
+            return Err(RiscvCpuError::PageFault(addr));
+        }
+        let ppn = (pte >> 10) as u64;
+        if pte & (PTE_R | PTE_X) == 0 {
+            table = ppn << 12;
+            continue;
+        }
+        if level == 1 {
+            // A megapage has to be aligned to its size
+            if ppn & 0x3ff != 0 {
+                return Err(RiscvCpuError::PageFault(addr));
+            }
+            return Ok(Translation {
+                physical: (ppn << 12) | (addr & 0x003f_ffff) as u64,
+                megapage: true,
+                pte,
+            });
+        }
+        return Ok(Translation {
+            physical: (ppn << 12) | (addr & 0xfff) as u64,
+            megapage: false,
+            pte,
+        });
+    }
+    // The second level pointed at yet another table
+    Err(RiscvCpuError::PageFault(addr))
+}
@@ -1,14 +1,39 @@
 use super::bridge::{Bridge, BridgeError};
 use super::gdb::GdbController;
+use super::regions::{self, MemoryRegion};
 
 use log::{debug, info};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell, RefMut};
 use std::collections::HashMap;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+pub mod custom;
+pub mod disasm;
+pub mod dmi;
 pub mod exception;
+pub mod mmu;
+pub mod pmp;
+pub mod probe;
+pub mod pseudo;
+pub mod record;
+pub mod semihosting;
+pub mod softbreak;
+mod target;
+pub mod trigger;
+use dmi::{DebugCause, DebugModule};
+use custom::CustomCsr;
 use exception::RiscvException;
+use pmp::PmpRegion;
+use probe::CpuProbe;
+use pseudo::PseudoRegister;
+use semihosting::{Outcome, Request, Semihosting};
+use softbreak::SoftBreakpoints;
+use target::TargetDescription;
+use trigger::{BreakpointController, TriggerMatch};
 
 bitflags! {
     struct VexRiscvFlags: u32 {
@@ -24,6 +49,42 @@ bitflags! {
     }
 }
 
+/// The `mcause` of an illegal instruction trap
+const ILLEGAL_INSTRUCTION: u32 = 2;
+
+/// The F and D extension bits in `misa`
+const MISA_F: u64 = 1 << 5;
+const MISA_D: u64 = 1 << 3;
+
+/// The C extension bit in `misa`
+const MISA_C: u32 = 1 << 2;
+
+/// The extension letters in `misa`, one bit each from A up to Z
+const MISA_EXTENSIONS: u64 = (1 << 26) - 1;
+
+/// The order extensions go in an ISA string.  S and U are privilege modes
+/// rather than extensions, so they're left out.
+const ISA_ORDER: &str = "iemafdqlcbkjtpvhx";
+
+/// How many instructions either side of the pc to show when the CPU stops
+const HALT_CONTEXT: usize = 2;
+
+/// How long the instruction starting with `halfword` is, in bytes.  Only
+/// 32-bit instructions have both of the bottom bits set.  The longer
+/// encodings aren't used by anything, so they're taken to be 32 bits too.
+pub fn instruction_length(halfword: u32) -> u32 {
+    if halfword & 3 == 3 {
+        4
+    } else {
+        2
+    }
+}
+
+/// The FPU state field in `mstatus`.  The FPU can't be touched while it's
+/// off, so it gets turned on (to "initial") for the duration.
+const MSTATUS_FS: u64 = 3 << 13;
+const MSTATUS_FS_INITIAL: u64 = 1 << 13;
+
 // fn swab(src: u32) -> u32 {
 //     (src << 24) & 0xff000000
 //         | (src << 8) & 0x00ff0000
@@ -31,11 +92,126 @@ bitflags! {
 //         | (src >> 24) & 0x000000ff
 // }
 
-#[derive(Debug, PartialEq)]
-pub enum RiscvCpuState {
+/// Which debug interface the CPU exposes on the Wishbone bus
+#[derive(Debug, PartialEq, Clone)]
+pub enum RiscvBackendKind {
+    /// The VexRiscv native debug bus
+    VexRiscv,
+
+    /// A RISC-V Debug Module (spec 0.13) with its DMI mapped onto Wishbone
+    Dmi,
+}
+
+/// How wide the CPU's registers are
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Xlen {
+    Rv32,
+    Rv64,
+    Rv128,
+}
+
+impl Xlen {
+    /// Decode the `MXL` field of `misa`, where 0 means `misa` isn't there.
+    /// RV128 is recognised, but refused when attaching, since its
+    /// registers don't fit in a u64.
+    pub fn from_mxl(mxl: u32) -> Option<Xlen> {
+        match mxl {
+            1 => Some(Xlen::Rv32),
+            2 => Some(Xlen::Rv64),
+            3 => Some(Xlen::Rv128),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+            Xlen::Rv128 => 128,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// The name GDB knows this architecture by
+    pub fn architecture(self) -> &'static str {
+        match self {
+            Xlen::Rv32 => "riscv:rv32",
+            Xlen::Rv64 => "riscv:rv64",
+            Xlen::Rv128 => "riscv:rv128",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum RiscvBackend {
+    VexRiscv,
+    Dmi(DebugModule),
+}
+
+/// Why the CPU last stopped
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StopCause {
+    /// An `ebreak`, or a trigger set up as a hardware breakpoint
+    Breakpoint,
+
+    /// A watchpoint on the given address
+    Watchpoint(TriggerMatch, u32),
+
+    /// A single step finished
+    Step,
+
+    /// The debugger asked it to halt
+    HaltRequest,
+
+    /// It was reset and held
+    Reset,
+
+    Unknown,
+}
+
+impl StopCause {
+    /// The signal GDB should be told about
+    pub fn signal(self) -> u8 {
+        match self {
+            StopCause::HaltRequest => 2,
+            _ => 5,
+        }
+    }
+
+    /// The `T` stop reply for this cause, naming the watched address for
+    /// a watchpoint so that GDB can show which variable it was.
+    pub fn stop_reply(self) -> String {
+        match self {
+            StopCause::Watchpoint(kind, addr) => match kind.stop_reason() {
+                Some(reason) => format!("T{:02x}{}:{:x};", self.signal(), reason, addr),
+                None => format!("T{:02x}", self.signal()),
+            },
+            _ => format!("T{:02x}", self.signal()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CpuState {
+    /// Nothing has been done to the CPU yet
     Unknown,
-    Halted,
     Running,
+    Halted { cause: StopCause },
+
+    /// Running a single instruction, and about to halt again
+    Stepping,
+
+    /// Being held in reset
+    Resetting,
+}
+
+impl CpuState {
+    pub fn is_halted(self) -> bool {
+        matches!(self, CpuState::Halted { .. })
+    }
 }
 
 #[derive(Debug)]
@@ -52,6 +228,15 @@ pub enum RiscvCpuError {
     /// Couldn't find that breakpoint
     BreakpointNotFound(u32 /* address */),
 
+    /// An `ebreak` written over an instruction didn't stick
+    BreakpointNotWritable(u32 /* address */),
+
+    /// There's no hart with that index
+    NoSuchHart(u32),
+
+    /// A breakpoint was asked for where no instruction can start
+    MisalignedBreakpoint(u32 /* address */),
+
     /// An error occurred with the bridge
     BridgeError(BridgeError),
 
@@ -60,6 +245,36 @@ pub enum RiscvCpuError {
 
     /// CPU didn't complete write
     InstructionTimeout,
+
+    /// No 0.13-compatible debug module was found at the specified address
+    DebugModuleNotFound(u32 /* reported version */),
+
+    /// The debug module never read back as active after being enabled
+    DebugModuleInactive,
+
+    /// The debug module reported an error running an abstract command
+    AbstractCommandError(u32 /* cmderr */),
+
+    /// The debug module's program buffer can't hold the requested program
+    ProgramBufferTooSmall(u32 /* instruction count */),
+
+    /// After a reset, the CPU halted somewhere other than the reset vector
+    ResetVectorMismatch(u32 /* expected */, u32 /* actual */),
+
+    /// The virtual address isn't mapped by the current page tables
+    PageFault(u32 /* virtual address */),
+
+    /// A pseudo-register refers to a register that doesn't exist
+    UnknownRegister(String /* name */),
+
+    /// A custom CSR has the number or name of one that's already there
+    DuplicateRegister(String /* name */, u32 /* number */),
+
+    /// Nothing that works like a VexRiscv debug bus is at the address
+    NoDebugInterface(u32 /* address */),
+
+    /// The debug interface is missing something that's needed
+    MissingCapability(&'static str),
 }
 
 impl ::std::fmt::Display for RiscvCpuError {
@@ -70,9 +285,34 @@ impl ::std::fmt::Display for RiscvCpuError {
             InvalidRegister(r) => write!(f, "invalid register {}", r),
             BreakpointExhausted => write!(f, "ran out of hardware breakpoints"),
             BreakpointNotFound(b) => write!(f, "breakpoint {} not found", b),
+            BreakpointNotWritable(b) => write!(f, "can't patch a breakpoint in at {:08x}", b),
+            NoSuchHart(h) => write!(f, "there's no hart {}", h),
+            MisalignedBreakpoint(b) => write!(f, "no instruction can start at {:08x}", b),
             BridgeError(e) => write!(f, "bridge error: {}", e),
             IoError(e) => write!(f, "io error: {}", e),
             InstructionTimeout => write!(f, "cpu instruction timed out"),
+            DebugModuleNotFound(v) => write!(f, "no debug module found (version {})", v),
+            DebugModuleInactive => write!(f, "the debug module never became active"),
+            AbstractCommandError(e) => write!(f, "abstract command failed with error {}", e),
+            ProgramBufferTooSmall(n) => write!(f, "program buffer too small for {} instructions", n),
+            ResetVectorMismatch(expected, actual) => write!(
+                f,
+                "cpu halted at 0x{:08x} after reset, expected 0x{:08x}",
+                actual, expected
+            ),
+            PageFault(addr) => write!(f, "virtual address 0x{:08x} is not mapped", addr),
+            UnknownRegister(name) => write!(f, "there's no register called {}", name),
+            DuplicateRegister(name, number) => write!(
+                f,
+                "there's already a register called {}, or a CSR numbered {:03x}",
+                name, number
+            ),
+            NoDebugInterface(addr) => write!(
+                f,
+                "no VexRiscv debug bus at 0x{:08x} -- is --debug-offset right?",
+                addr
+            ),
+            MissingCapability(what) => write!(f, "{}", what),
         }
     }
 }
@@ -89,22 +329,8 @@ impl std::convert::From<io::Error> for RiscvCpuError {
     }
 }
 
-// const MEMORY_MAP_XML: &str = r#"<?xml version="1.0"?>
-// <!DOCTYPE memory-map
-//           PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN"
-//                  "http://sourceware.org/gdb/gdb-memory-map.dtd">
-// <memory-map>
-//     <memory type="rom" start="0" length="0x2000"/>
-//     <memory type="ram" start="0x10000000" length="0x20000"/>
-//     <memory type="ram" start="0xe0000000" length="0x10000000"/>
-//     <memory type="flash" start="0x20000000" length="0x200000">
-//         <property name="blocksize">0x1000</property>
-//     </memory>
-// </memory-map>"#;
-
-const THREADS_XML: &str = r#"<?xml version="1.0"?>
-<threads>
-</threads>"#;
+/// The `mhartid` CSR, which numbers each hart the way the software does
+const CSR_MHARTID: u32 = 0xf14;
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
 enum RiscvRegisterType {
@@ -113,22 +339,9 @@ enum RiscvRegisterType {
 
     /// Arch-specific registers
     CSR,
-}
 
-impl RiscvRegisterType {
-    fn feature_name(&self) -> &str {
-        match *self {
-            RiscvRegisterType::General => "org.gnu.gdb.riscv.cpu",
-            RiscvRegisterType::CSR => "org.gnu.gdb.riscv.csr",
-        }
-    }
-
-    fn group(&self) -> &str {
-        match *self {
-            RiscvRegisterType::General => "general",
-            RiscvRegisterType::CSR => "csr",
-        }
-    }
+    /// Floating-point registers f0 to f31
+    Float,
 }
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
@@ -136,6 +349,7 @@ enum RegisterContentsType {
     Int,
     DataPtr,
     CodePtr,
+    Float,
 }
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
@@ -161,6 +375,9 @@ struct RiscvRegister {
 
     /// What kind of data this register contains
     contents: RegisterContentsType,
+
+    /// The group GDB shows it in, if not the usual one for its type
+    group: Option<String>,
 }
 
 impl RiscvRegister {
@@ -178,6 +395,7 @@ impl RiscvRegister {
             present: true,
             save_restore,
             contents,
+            group: None,
         }
     }
 
@@ -190,13 +408,51 @@ impl RiscvRegister {
             present,
             save_restore: true,
             contents: RegisterContentsType::Int,
+            group: None,
         }
     }
 
+    pub fn float(index: u32) -> RiscvRegister {
+        RiscvRegister {
+            register_type: RiscvRegisterType::Float,
+            index,
+            gdb_index: index + Self::float_offset(),
+            name: format!("f{}", index),
+            present: false,
+            save_restore: true,
+            contents: RegisterContentsType::Float,
+            group: None,
+        }
+    }
+
+    /// `fflags`, `frm` and `fcsr` are CSRs, but GDB expects to find them
+    /// alongside the FPRs.
+    fn is_float_csr(&self) -> bool {
+        self.register_type == RiscvRegisterType::CSR && (1..=3).contains(&self.index)
+    }
+
+    /// Which feature GDB expects this register to be described in
+    fn feature(&self) -> RiscvRegisterType {
+        if self.is_float_csr() {
+            RiscvRegisterType::Float
+        } else {
+            self.register_type.clone()
+        }
+    }
+
+    fn float_offset() -> u32 {
+        33
+    }
+
     fn csr_offset() -> u32 {
         65
     }
 
+    /// Pseudo-registers come after the last CSR
+    fn pseudo_offset() -> u32 {
+        Self::csr_offset() + 4096
+    }
+
     pub fn x0() -> RiscvRegister {
         RiscvRegister::general(0, "x0", false, RegisterContentsType::Int)
     }
@@ -213,6 +469,14 @@ impl RiscvRegister {
         RiscvRegister::general(32, "pc", false, RegisterContentsType::CodePtr)
     }
 
+    pub fn a0() -> RiscvRegister {
+        RiscvRegister::general(10, "x10", true, RegisterContentsType::Int)
+    }
+
+    pub fn a1() -> RiscvRegister {
+        RiscvRegister::general(11, "x11", true, RegisterContentsType::Int)
+    }
+
     pub fn satp() -> RiscvRegister {
         RiscvRegister::csr(0x180, "satp", true)
     }
@@ -221,6 +485,10 @@ impl RiscvRegister {
         RiscvRegister::csr(0x300, "mstatus", true)
     }
 
+    pub fn misa() -> RiscvRegister {
+        RiscvRegister::csr(0x301, "misa", true)
+    }
+
     pub fn mtvec() -> RiscvRegister {
         RiscvRegister::csr(0x305, "mtvec", true)
     }
@@ -236,6 +504,72 @@ impl RiscvRegister {
     pub fn mtval() -> RiscvRegister {
         RiscvRegister::csr(0x343, "mtval", true)
     }
+
+    pub fn tselect() -> RiscvRegister {
+        RiscvRegister::csr(0x7a0, "tselect", true)
+    }
+
+    pub fn tdata1() -> RiscvRegister {
+        RiscvRegister::csr(0x7a1, "tdata1", true)
+    }
+
+    pub fn tdata2() -> RiscvRegister {
+        RiscvRegister::csr(0x7a2, "tdata2", true)
+    }
+}
+
+/// What the debug interface turned out to support, for printing when
+/// the GDB server starts
+pub struct DebugCapabilities {
+    /// What's driving the CPU, and where
+    pub interface: String,
+    pub xlen: Xlen,
+
+    /// The ISA string, if `misa` gave one
+    pub isa: Option<String>,
+    pub harts: u32,
+    pub hardware_breakpoints: usize,
+    pub watchpoints: bool,
+
+    /// Instructions the program buffer holds, for a Debug Module
+    pub program_buffer: Option<u32>,
+
+    /// Widths the Debug Module can reach the system bus at, for a Debug
+    /// Module
+    pub system_bus: Option<Vec<u32>>,
+    pub flen: u32,
+    pub mmu: bool,
+}
+
+impl ::std::fmt::Display for DebugCapabilities {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        let yes_no = |b| if b { "yes" } else { "no" };
+        writeln!(f, "{}, {}", self.interface, self.xlen.architecture())?;
+        if let Some(isa) = &self.isa {
+            writeln!(f, "ISA: {}", isa)?;
+        }
+        if self.harts > 1 {
+            writeln!(f, "harts: {}", self.harts)?;
+        }
+        writeln!(f, "hardware breakpoints: {}", self.hardware_breakpoints)?;
+        writeln!(f, "watchpoints: {}", yes_no(self.watchpoints))?;
+        if let Some(words) = self.program_buffer {
+            writeln!(f, "program buffer: {} instructions", words)?;
+        }
+        match &self.system_bus {
+            Some(widths) if !widths.is_empty() => {
+                let widths: Vec<String> = widths.iter().map(|w| w.to_string()).collect();
+                writeln!(f, "system bus access: {}-bit", widths.join("/"))?
+            }
+            Some(_) => writeln!(f, "system bus access: no")?,
+            None => (),
+        }
+        match self.flen {
+            0 => writeln!(f, "FPU: no")?,
+            flen => writeln!(f, "FPU: {}-bit", flen)?,
+        }
+        write!(f, "MMU: {}", if self.mmu { "Sv32" } else { "no" })
+    }
 }
 
 struct RiscvBreakpoint {
@@ -250,24 +584,56 @@ struct RiscvBreakpoint {
     allocated: bool,
 }
 
-pub struct RiscvCpu {
+/// What's been read from one hart since it last ran.  Each hart keeps its
+/// own, so moving between them doesn't throw any of it away.
+#[derive(Default)]
+struct HartState {
+    /// Virtual-to-physical page translations, valid until the hart runs again
+    tlb: HashMap<u32, u32>,
+
+    /// General registers as they were last read from the hart, valid until
+    /// it runs again, so that writing them back unchanged costs nothing
+    register_snapshot: HashMap<u32, u64>,
+}
+
+impl HartState {
+    fn forget(&mut self) {
+        self.tlb.clear();
+        self.register_snapshot.clear();
+    }
+}
+
+/// Every hart behind one debug interface.  Registers, memory, steps and
+/// halts go to the selected hart, which GDB picks with `Hg` and `Hc`.
+pub struct RiscvSystem {
     /// A list of all available registers on this CPU
     gdb_register_map: HashMap<u32, RiscvRegister>,
 
-    /// An XML representation of the register mapping
-    target_xml: String,
+    /// What GDB is told about the registers, feature by feature
+    target: TargetDescription,
+
+    /// The memory map offered to GDB as `memory-map.xml`, if the regions
+    /// of memory are known
+    memory_map_xml: Option<String>,
 
     /// The memory offset of the debug register
     debug_offset: u32,
 
     /// Keep a copy of values that get clobbered during debugging
-    cached_values: Arc<Mutex<HashMap<RiscvRegister, u32>>>,
+    cached_values: Arc<Mutex<HashMap<RiscvRegister, u64>>>,
+
+    /// How wide the registers are
+    xlen: Xlen,
 
     /// All available breakpoints
     breakpoints: RefCell<[RiscvBreakpoint; 2]>,
 
+    /// Instructions that have had an `ebreak` patched over them, by
+    /// physical address
+    soft_breakpoints: RefCell<SoftBreakpoints>,
+
     /// CPU state
-    cpu_state: Arc<Mutex<RiscvCpuState>>,
+    cpu_state: Arc<Mutex<CpuState>>,
 
     /// Our own interface to the CPU
     controller: RiscvCpuController,
@@ -280,6 +646,29 @@ pub struct RiscvCpu {
 
     /// The last exception, if any
     last_exception: Arc<Mutex<Option<RiscvException>>>,
+
+    /// Address of the gateware reset CSR, if known
+    reset_csr: Option<u32>,
+
+    /// Where the CPU is expected to be right after a reset
+    reset_vector: Option<u32>,
+
+    /// How long to let the SoC come out of reset before releasing the CPU
+    reset_settle: Duration,
+
+    /// Set by `monitor mmu off`, so GDB's addresses are taken to be
+    /// physical even while paging is on
+    physical_access: Cell<bool>,
+
+    /// What's known about each hart, by index
+    hart_state: RefCell<Vec<HartState>>,
+
+    /// Keep every register in the snapshot, not just the general ones, so
+    /// that each is only read once per halt
+    cache_all_registers: bool,
+
+    /// Values worked out from other registers, numbered from `pseudo_offset()`
+    pseudo_registers: Vec<PseudoRegister>,
 }
 
 pub struct RiscvCpuController {
@@ -287,10 +676,10 @@ pub struct RiscvCpuController {
     debug_offset: u32,
 
     /// A copy of the CPU's state object
-    cpu_state: Arc<Mutex<RiscvCpuState>>,
+    cpu_state: Arc<Mutex<CpuState>>,
 
     /// Cached values (mostly the program counter)
-    cached_values: Arc<Mutex<HashMap<RiscvRegister, u32>>>,
+    cached_values: Arc<Mutex<HashMap<RiscvRegister, u64>>>,
 
     /// "true" if an MMU exists on this CPU
     has_mmu: bool,
@@ -300,18 +689,228 @@ pub struct RiscvCpuController {
 
     /// The last exception, if any
     last_exception: Arc<Mutex<Option<RiscvException>>>,
+
+    /// The debug interface used to talk to the CPU
+    backend: RiscvBackend,
+
+    /// How wide the FPRs are, or 0 if there's no FPU
+    flen: u32,
+
+    /// The extension bits of `misa`, or 0 if it couldn't say
+    extensions: u32,
+
+    /// Breakpoints and watchpoints made from trigger CSRs, when there's a
+    /// Debug Module
+    triggers: BreakpointController,
+
+    /// Looks after semihosting calls, if they're turned on
+    semihosting: Option<Arc<Mutex<Semihosting>>>,
+
+    /// Show GDB the instructions around the pc whenever the CPU stops
+    disassemble_on_halt: bool,
+
+    /// Halt and resume harts one at a time, for GDB's non-stop mode.
+    /// Parked harts are then the ones GDB knows are stopped.
+    non_stop: Arc<AtomicBool>,
 }
 
-impl RiscvCpu {
-    pub fn new(bridge: &Bridge, offset: u32) -> Result<RiscvCpu, RiscvCpuError> {
+impl RiscvSystem {
+    pub fn new(
+        bridge: &Bridge,
+        offset: u32,
+        backend_kind: RiscvBackendKind,
+    ) -> Result<RiscvSystem, RiscvCpuError> {
+        Self::build(bridge, offset, backend_kind, None)
+    }
+
+    /// Find out how wide the registers are.  VexRiscv is always 32 bits,
+    /// but a Debug Module can be in front of anything.
+    fn probe_xlen(controller: &mut RiscvCpuController, bridge: &Bridge) -> Result<Xlen, RiscvCpuError> {
+        if let RiscvBackend::VexRiscv = controller.backend {
+            controller.check_vexriscv(bridge)?;
+            return Ok(Xlen::Rv32);
+        }
+        let was_running = !controller.is_halted(bridge)?;
+        if was_running {
+            controller.perform_halt(bridge)?;
+        }
+        let xlen = match &mut controller.backend {
+            RiscvBackend::Dmi(dm) => dm.detect_xlen(bridge),
+            RiscvBackend::VexRiscv => Ok(Xlen::Rv32),
+        };
+        if was_running {
+            controller.perform_resume(bridge, false)?;
+        }
+        match xlen? {
+            Xlen::Rv128 => Err(RiscvCpuError::MissingCapability(
+                "this is an RV128 hart, which can't be debugged, as its registers are wider than 64 bits",
+            )),
+            xlen => Ok(xlen),
+        }
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.xlen
+    }
+
+    /// Count the trigger module's breakpoints.  The VexRiscv debug bus has
+    /// breakpoints of its own instead.
+    fn probe_triggers(
+        controller: &mut RiscvCpuController,
+        bridge: &Bridge,
+        xlen: Xlen,
+    ) -> Result<BreakpointController, RiscvCpuError> {
+        if let RiscvBackend::VexRiscv = controller.backend {
+            return Ok(BreakpointController::none());
+        }
+        let was_running = !controller.is_halted(bridge)?;
+        if was_running {
+            controller.perform_halt(bridge)?;
+        }
+        let triggers = BreakpointController::discover(controller, bridge, xlen);
+        if was_running {
+            controller.perform_resume(bridge, false)?;
+        }
+        triggers
+    }
+
+    /// Everything the debug interface was found to support
+    pub fn capabilities(&self) -> DebugCapabilities {
+        let (interface, program_buffer, system_bus) = match &self.controller.backend {
+            RiscvBackend::VexRiscv => (
+                format!("VexRiscv debug bus at {:08x}", self.debug_offset),
+                None,
+                None,
+            ),
+            RiscvBackend::Dmi(dm) => (
+                format!("Debug Module at {:08x}", self.debug_offset),
+                Some(dm.progbuf_capacity()),
+                Some(dm.system_bus_widths()),
+            ),
+        };
+        DebugCapabilities {
+            interface,
+            xlen: self.xlen,
+            isa: self.isa(),
+            harts: self.harts(),
+            hardware_breakpoints: self.hardware_breakpoints().1,
+            watchpoints: self.has_watchpoints(),
+            program_buffer,
+            system_bus,
+            flen: self.controller.flen,
+            mmu: self.has_mmu,
+        }
+    }
+
+    /// How many hardware breakpoints are in use, and how many there are
+    pub fn hardware_breakpoints(&self) -> (usize, usize) {
+        if let RiscvBackend::Dmi(_) = self.controller.backend {
+            let triggers = &self.controller.triggers;
+            return (triggers.in_use(&self.controller), triggers.count());
+        }
+        let bps = self.breakpoints.borrow();
+        (bps.iter().filter(|bp| bp.allocated).count(), bps.len())
+    }
+
+    /// Find out whether this CPU has an MMU: read the "satp" register and
+    /// write the opposite value back in.  If the value changes, then we know
+    /// this register exists.
+    fn probe_mmu(
+        controller: &mut RiscvCpuController,
+        bridge: &Bridge,
+        gdb_register_map: &mut HashMap<u32, RiscvRegister>,
+        mmu_enabled: &Arc<Mutex<bool>>,
+    ) -> Result<(), RiscvCpuError> {
+        let was_running = !controller.is_halted(bridge)?;
+        if was_running {
+            controller.perform_halt(bridge)?;
+        }
+        // A Debug Module will instead refuse to access a CSR that doesn't
+        // exist, which also means there's no MMU.
+        let satp_register = RiscvRegister::satp();
+        match controller.read_register(bridge, &satp_register) {
+            Err(RiscvCpuError::AbstractCommandError(_)) => (),
+            Err(e) => return Err(e),
+            Ok(old_satp) => {
+                match controller.write_register(bridge, &satp_register, !old_satp) {
+                    Err(RiscvCpuError::AbstractCommandError(_)) | Ok(()) => (),
+                    Err(e) => return Err(e),
+                }
+                let new_satp = controller.read_register(bridge, &satp_register)?;
+                if new_satp != old_satp {
+                    controller.write_register(bridge, &satp_register, old_satp)?;
+                    controller.has_mmu = true;
+                    Self::insert_register(gdb_register_map, satp_register);
+                    *mmu_enabled.lock().unwrap() = (old_satp & 0x80000000) == 0x80000000;
+                }
+            }
+        }
+        if was_running {
+            controller.perform_resume(bridge, false)?;
+        }
+        Ok(())
+    }
+
+    /// Like `new()`, but also find out which CSRs the CPU has, for a
+    /// debugger to attach to it.  If `cache_key` is given, take what's known
+    /// about the CPU from the probe cache instead of probing it again, and
+    /// save what's found if it wasn't there.
+    pub fn new_with_cache(
+        bridge: &Bridge,
+        offset: u32,
+        backend_kind: RiscvBackendKind,
+        cache_key: Option<&str>,
+    ) -> Result<RiscvSystem, RiscvCpuError> {
+        let cached = cache_key.and_then(CpuProbe::load);
+        if let Some(probe) = &cached {
+            debug!("using the cached CPU probe: {:?}", probe);
+        }
+        let mut cpu = Self::build(bridge, offset, backend_kind, cached.as_ref())?;
+
+        match cached.as_ref().and_then(|probe| probe.csrs.as_ref()) {
+            Some(csrs) => cpu.mark_csrs_present(csrs),
+            None => cpu.discover_csrs(bridge)?,
+        }
+        match cached.as_ref().and_then(|probe| probe.extensions) {
+            Some(extensions) => cpu.controller.extensions = extensions,
+            None => cpu.probe_extensions(bridge)?,
+        }
+        match cached.as_ref().and_then(|probe| probe.flen) {
+            Some(flen) => cpu.set_flen(flen),
+            None => cpu.probe_fpu(bridge)?,
+        }
+        if let Some(key) = cache_key {
+            let probe = CpuProbe {
+                has_mmu: cpu.has_mmu,
+                csrs: Some(cpu.present_csrs()),
+                flen: Some(cpu.flen()),
+                extensions: Some(cpu.controller.extensions),
+            };
+            if cached.as_ref() != Some(&probe) {
+                probe.save(key);
+            }
+        }
+        Ok(cpu)
+    }
+
+    fn build(
+        bridge: &Bridge,
+        offset: u32,
+        backend_kind: RiscvBackendKind,
+        cached: Option<&CpuProbe>,
+    ) -> Result<RiscvSystem, RiscvCpuError> {
         let mut gdb_register_map = Self::make_registers();
 
-        let cpu_state = Arc::new(Mutex::new(RiscvCpuState::Unknown));
+        let cpu_state = Arc::new(Mutex::new(CpuState::Unknown));
         let debug_offset = offset;
         let cached_values = Arc::new(Mutex::new(HashMap::new()));
         let last_exception = Arc::new(Mutex::new(None));
 
         let mmu_enabled = Arc::new(Mutex::new(false));
+        let backend = match backend_kind {
+            RiscvBackendKind::VexRiscv => RiscvBackend::VexRiscv,
+            RiscvBackendKind::Dmi => RiscvBackend::Dmi(DebugModule::new(bridge, debug_offset)?),
+        };
         let mut controller = RiscvCpuController {
             cpu_state: cpu_state.clone(),
             cached_values: cached_values.clone(),
@@ -319,38 +918,56 @@ impl RiscvCpu {
             has_mmu: false,
             mmu_enabled: mmu_enabled.clone(),
             last_exception: last_exception.clone(),
+            backend,
+            flen: 0,
+            extensions: 0,
+            triggers: BreakpointController::none(),
+            semihosting: None,
+            disassemble_on_halt: false,
+            non_stop: Arc::new(AtomicBool::new(false)),
         };
 
-        // Determine if this CPU has an MMU.
-        // Read the "satp" register and write the opposite value back in.
-        // If the value changes, then we know this register exists.
-        let was_running =
-            (controller.read_status(bridge)? & VexRiscvFlags::HALT) != VexRiscvFlags::HALT;
-        if was_running {
-            controller.perform_halt(bridge)?;
-        }
+        let xlen = Self::probe_xlen(&mut controller, bridge)?;
+        controller.triggers = Self::probe_triggers(&mut controller, bridge, xlen)?;
+
+        // Only Sv32 is understood, so leave the MMU alone on wider CPUs
         let satp_register = RiscvRegister::satp();
-        let old_satp = controller.read_register(bridge, &satp_register)?;
-        controller.write_register(bridge, &satp_register, !old_satp)?;
-        let new_satp = controller.read_register(bridge, &satp_register)?;
-        if new_satp != old_satp {
-            controller.write_register(bridge, &satp_register, old_satp)?;
-            controller.has_mmu = true;
-            Self::insert_register(&mut gdb_register_map, satp_register);
-            *mmu_enabled.lock().unwrap() = (old_satp & 0x80000000) == 0x80000000;
-        }
-        if was_running {
-            controller.perform_resume(bridge, false)?;
+        if xlen != Xlen::Rv32 {
+            debug!("not looking for an MMU on an {:?} CPU", xlen);
+        } else if let Some(probe) = cached {
+            if probe.has_mmu {
+                // The MMU is there, but whether it's on still has to be read
+                let was_running = !controller.is_halted(bridge)?;
+                if was_running {
+                    controller.perform_halt(bridge)?;
+                }
+                let satp = controller.read_register(bridge, &satp_register)?;
+                controller.has_mmu = true;
+                Self::insert_register(&mut gdb_register_map, satp_register.clone());
+                *mmu_enabled.lock().unwrap() = (satp & 0x80000000) == 0x80000000;
+                if was_running {
+                    controller.perform_resume(bridge, false)?;
+                }
+            }
+        } else {
+            Self::probe_mmu(&mut controller, bridge, &mut gdb_register_map, &mmu_enabled)?;
         }
 
-        let target_xml = Self::make_target_xml(&gdb_register_map);
+        let mut target = TargetDescription::new(xlen);
+        target.refresh(&gdb_register_map, &[]);
 
         let has_mmu = controller.has_mmu;
-        let cpu = RiscvCpu {
+        let harts = match &controller.backend {
+            RiscvBackend::VexRiscv => 1,
+            RiscvBackend::Dmi(dm) => dm.harts(),
+        };
+        let cpu = RiscvSystem {
             gdb_register_map,
-            target_xml,
+            target,
+            memory_map_xml: None,
             debug_offset,
             cached_values,
+            xlen,
             breakpoints: RefCell::new([
                 RiscvBreakpoint {
                     address: 0,
@@ -373,16 +990,256 @@ impl RiscvCpu {
                 //     allocated: false,
                 // },
             ]),
+            soft_breakpoints: RefCell::new(SoftBreakpoints::new()),
             controller,
             cpu_state,
             has_mmu,
             mmu_enabled,
             last_exception,
+            reset_csr: None,
+            reset_vector: None,
+            reset_settle: Duration::from_millis(10),
+            physical_access: Cell::new(false),
+            hart_state: RefCell::new((0..harts).map(|_| HartState::default()).collect()),
+            cache_all_registers: false,
+            pseudo_registers: vec![],
         };
 
         Ok(cpu)
     }
 
+    /// Configure how `reset_halt()` resets the SoC.  If `reset_csr` is set,
+    /// the whole SoC is reset and allowed to settle for `settle` before the
+    /// CPU is let out of reset.  If `reset_vector` is set, the PC is checked
+    /// against it once the CPU has halted.
+    pub fn set_reset_control(
+        &mut self,
+        reset_csr: Option<u32>,
+        reset_vector: Option<u32>,
+        settle: Duration,
+    ) {
+        self.reset_csr = reset_csr;
+        self.reset_vector = reset_vector;
+        self.reset_settle = settle;
+    }
+
+    /// Read CSRs and FPU registers only once each time the CPU halts, as
+    /// well as the general registers.  This is for bridges slow enough
+    /// that GDB asking for the same CSR twice is noticeable, and it means
+    /// a CSR that changes on its own while the CPU is halted won't be seen
+    /// to until the CPU runs again.
+    pub fn set_register_caching(&mut self, all: bool) {
+        self.cache_all_registers = all;
+    }
+
+    /// Add pseudo-registers to the register file.  Each one may only be
+    /// worked out from registers this CPU actually has.
+    pub fn set_pseudo_registers(
+        &mut self,
+        registers: Vec<PseudoRegister>,
+    ) -> Result<(), RiscvCpuError> {
+        for pseudo in &registers {
+            for source in pseudo.sources() {
+                if self.register_by_name(source).is_none() {
+                    return Err(RiscvCpuError::UnknownRegister(source.to_owned()));
+                }
+            }
+        }
+        self.pseudo_registers = registers;
+        self.target.invalidate_pseudo();
+        self.target.refresh(&self.gdb_register_map, &self.pseudo_registers);
+        Ok(())
+    }
+
+    /// Add CSRs that aren't in the spec, so GDB can get at them by name.
+    /// They're taken to be there, rather than probed for.  Call this
+    /// before `set_pseudo_registers()`, so that those can use them.
+    pub fn set_custom_csrs(&mut self, csrs: &[CustomCsr]) -> Result<(), RiscvCpuError> {
+        for csr in csrs {
+            let gdb_index = csr.number + RiscvRegister::csr_offset();
+            if self.gdb_register_map.contains_key(&gdb_index)
+                || self.gdb_register_map.values().any(|r| r.name == csr.name)
+            {
+                return Err(RiscvCpuError::DuplicateRegister(csr.name.clone(), csr.number));
+            }
+            let mut reg = RiscvRegister::csr(csr.number, &csr.name, true);
+            reg.group = csr.group.clone();
+            self.target.invalidate(&reg.feature());
+            Self::insert_register(&mut self.gdb_register_map, reg);
+        }
+        self.target.refresh(&self.gdb_register_map, &self.pseudo_registers);
+        Ok(())
+    }
+
+    /// Find out which CSRs this CPU actually has, by reading each one in
+    /// turn and seeing whether the read is refused, and mark those that
+    /// are there as present so GDB gets to see them.
+    pub fn discover_csrs(&mut self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let candidates: Vec<u32> = self
+            .gdb_register_map
+            .values()
+            .filter(|r| r.register_type == RiscvRegisterType::CSR && !r.present)
+            .map(|r| r.index)
+            .collect();
+
+        let found = {
+            let _bridge_mutex = bridge.mutex().lock().unwrap();
+            let was_running = !self.controller.is_halted(bridge)?;
+            if was_running {
+                self.controller.perform_halt(bridge)?;
+            }
+            let found = self.controller.probe_csrs(bridge, &candidates);
+            if was_running {
+                self.controller.perform_resume(bridge, false)?;
+            }
+            found?
+        };
+        debug!("found {} of {} optional CSRs", found.len(), candidates.len());
+        self.mark_csrs_present(&found);
+        Ok(())
+    }
+
+    /// Find out from `misa` which extensions the CPU has.  Without `misa`
+    /// there's no telling, so nothing is assumed either way.
+    pub fn probe_extensions(&mut self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let misa_present = self
+            .gdb_register_map
+            .get(&RiscvRegister::misa().gdb_index)
+            .map(|r| r.present)
+            .unwrap_or(false);
+        if !misa_present {
+            return Ok(());
+        }
+
+        let misa = {
+            let _bridge_mutex = bridge.mutex().lock().unwrap();
+            let was_running = !self.controller.is_halted(bridge)?;
+            if was_running {
+                self.controller.perform_halt(bridge)?;
+            }
+            let misa = self
+                .controller
+                .read_register_wide(bridge, &RiscvRegister::misa());
+            if was_running {
+                self.controller.perform_resume(bridge, false)?;
+            }
+            misa?
+        };
+        self.controller.extensions = (misa & MISA_EXTENSIONS) as u32;
+        if let Some(isa) = self.isa() {
+            debug!("misa {:08x} says the CPU is {}", misa, isa);
+        }
+        Ok(())
+    }
+
+    /// The ISA string that `misa` gives, such as "rv32imac"
+    pub fn isa(&self) -> Option<String> {
+        let extensions = self.controller.extensions;
+        if extensions == 0 {
+            return None;
+        }
+        let letters: String = ISA_ORDER
+            .chars()
+            .filter(|c| extensions & (1 << (*c as u32 - 'a' as u32)) != 0)
+            .collect();
+        Some(format!("rv{}{}", self.xlen.bits(), letters))
+    }
+
+    /// Work out from `misa` whether there's an FPU, and how wide it is.
+    /// Without `misa` there's no telling, so assume there isn't one.
+    pub fn probe_fpu(&mut self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let misa = self.controller.extensions as u64;
+        let flen = if misa & MISA_D != 0 {
+            64
+        } else if misa & MISA_F != 0 {
+            32
+        } else {
+            0
+        };
+
+        // A Debug Module needn't give abstract access to the FPRs even
+        // when there's an FPU
+        if let (RiscvBackend::Dmi(dm), true) = (&self.controller.backend, flen != 0) {
+            let fprs = {
+                let _bridge_mutex = bridge.mutex().lock().unwrap();
+                let was_running = !self.controller.is_halted(bridge)?;
+                if was_running {
+                    self.controller.perform_halt(bridge)?;
+                }
+                let fprs = match dm.read_register_sized(bridge, DebugModule::fpr_regno(0), flen) {
+                    Err(RiscvCpuError::AbstractCommandError(_)) => Ok(false),
+                    other => other.map(|_| true),
+                };
+                if was_running {
+                    self.controller.perform_resume(bridge, false)?;
+                }
+                fprs?
+            };
+            if !fprs {
+                info!("not showing the FPU: the debug module can't read its registers");
+                self.set_flen(0);
+                return Ok(());
+            }
+        }
+        // A double can't be moved into a 32-bit register to read it, and
+        // the VexRiscv debug port has nothing else to read it with.
+        let vexriscv = matches!(self.controller.backend, RiscvBackend::VexRiscv);
+        if flen > self.xlen.bits() && vexriscv {
+            info!("not showing the FPU: its registers are wider than the CPU's");
+            self.set_flen(0);
+        } else {
+            debug!("misa {:08x} says the FPU is {} bits wide", misa, flen);
+            self.set_flen(flen);
+        }
+        Ok(())
+    }
+
+    /// Show GDB `flen`-bit FPRs and the FP CSRs, or hide them if `flen` is 0.
+    fn set_flen(&mut self, flen: u32) {
+        self.controller.flen = flen;
+        for reg in self.gdb_register_map.values_mut() {
+            if reg.register_type == RiscvRegisterType::Float || reg.is_float_csr() {
+                reg.present = flen != 0;
+            }
+        }
+        self.target.set_flen(flen);
+        self.target.refresh(&self.gdb_register_map, &self.pseudo_registers);
+    }
+
+    pub fn flen(&self) -> u32 {
+        self.controller.flen
+    }
+
+    fn mark_csrs_present(&mut self, csrs: &[u32]) {
+        for csr in csrs {
+            if let Some(reg) = self.gdb_register_map.get_mut(&(csr + RiscvRegister::csr_offset())) {
+                if !reg.present {
+                    reg.present = true;
+                    self.target.invalidate(&reg.feature());
+                }
+            }
+        }
+        self.target.refresh(&self.gdb_register_map, &self.pseudo_registers);
+    }
+
+    fn present_csrs(&self) -> Vec<u32> {
+        let mut csrs: Vec<u32> = self
+            .gdb_register_map
+            .values()
+            .filter(|r| r.register_type == RiscvRegisterType::CSR && r.present)
+            .map(|r| r.index)
+            .collect();
+        csrs.sort_unstable();
+        csrs
+    }
+
+    pub fn register_by_name(&self, name: &str) -> Option<u32> {
+        self.gdb_register_map
+            .values()
+            .find(|r| r.present && r.name == name)
+            .map(|r| r.gdb_index)
+    }
+
     fn insert_register(target: &mut HashMap<u32, RiscvRegister>, reg: RiscvRegister) {
         target.insert(reg.gdb_index, reg);
     }
@@ -404,6 +1261,14 @@ impl RiscvCpu {
         // Add the program counter
         registers.insert(32, RiscvRegister::pc());
 
+        // Floating point registers, which are hidden until an FPU turns up
+        for reg_num in 0..32 {
+            Self::insert_register(&mut registers, RiscvRegister::float(reg_num));
+        }
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x001, "fflags", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x002, "frm", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x003, "fcsr", false));
+
         // User trap setup
         Self::insert_register(&mut registers, RiscvRegister::csr(0x000, "ustatus", false));
         Self::insert_register(&mut registers, RiscvRegister::csr(0x004, "uie", false));
@@ -554,67 +1419,173 @@ impl RiscvCpu {
         registers
     }
 
-    fn make_target_xml(registers: &HashMap<u32, RiscvRegister>) -> String {
-        let mut reg_indexes: Vec<u32> = registers.keys().map(|x| *x).collect();
-        reg_indexes.sort();
-        let mut target_xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n".to_string();
+    /// One of the documents GDB reads with `qXfer`, which may be a piece
+    /// of the target description or the memory map
+    pub fn get_feature(&self, name: &str) -> Result<&[u8], RiscvCpuError> {
+        if let Some(data) = self.target.get(name) {
+            return Ok(data);
+        }
+        match (name, &self.memory_map_xml) {
+            ("memory-map.xml", Some(xml)) => Ok(xml.as_bytes()),
+            _ => Err(RiscvCpuError::UnrecognizedFile(name.to_string())),
+        }
+    }
 
-        let mut last_register_type = None;
-        for reg_index in reg_indexes {
-            let reg = registers.get(&reg_index).unwrap();
-            if Some(&reg.register_type) != last_register_type {
-                if last_register_type != None {
-                    target_xml.push_str("</feature>\n");
-                }
-                target_xml.push_str(&format!(
-                    "<feature name=\"{}\">\n",
-                    reg.register_type.feature_name()
-                ));
-                last_register_type = Some(&reg.register_type);
-            }
-            if !reg.present {
-                continue;
-            }
-            let reg_type = match reg.contents {
-                RegisterContentsType::Int => "int",
-                RegisterContentsType::CodePtr => "code_ptr",
-                RegisterContentsType::DataPtr => "data_ptr",
+    /// Give GDB a memory map made from `regions`, so that it only loads
+    /// into RAM, and flash if `flash` says it can be programmed, and uses
+    /// hardware breakpoints in ROM and flash
+    pub fn set_memory_map(&mut self, regions: &[MemoryRegion], flash: bool) {
+        self.memory_map_xml = if regions.is_empty() {
+            None
+        } else {
+            Some(regions::memory_map_xml(regions, flash))
+        };
+    }
+
+    pub fn has_memory_map(&self) -> bool {
+        self.memory_map_xml.is_some()
+    }
+
+    /// Every hart as a GDB thread.  Thread IDs have to be greater than
+    /// zero, so hart `n` is thread `n + 1`.  Each is described by its
+    /// `mhartid`, where that can be read.
+    pub fn get_threads(&self, bridge: &Bridge) -> Result<Vec<u8>, RiscvCpuError> {
+        let mut xml = "<?xml version=\"1.0\"?>\n<threads>\n".to_owned();
+        let mut mhartids = vec![None; self.harts() as usize];
+        if let RiscvBackend::Dmi(dm) = &self.controller.backend {
+            let _bridge_mutex = bridge.mutex().lock().unwrap();
+            if self.controller.is_halted(bridge)? {
+                dm.for_each_hart(bridge, |hart| {
+                    mhartids[hart as usize] = dm.read_csr(bridge, CSR_MHARTID).ok();
+                    Ok(())
+                })?;
+            }
+        }
+        for (hart, mhartid) in mhartids.iter().enumerate() {
+            let extra = match mhartid {
+                Some(id) => format!("mhartid {}", id),
+                None => String::new(),
             };
-            target_xml.push_str(&format!(
-                "<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" type=\"{}\" group=\"{}\"",
-                reg.name,
-                reg.gdb_index,
-                reg_type,
-                reg.register_type.group()
+            xml.push_str(&format!(
+                "<thread id=\"{:x}\" name=\"hart {}\">{}</thread>\n",
+                hart + 1,
+                hart,
+                extra
             ));
-            if !reg.save_restore {
-                target_xml.push_str(" save-restore=\"no\"");
+        }
+        xml.push_str("</threads>");
+        Ok(xml.into_bytes())
+    }
+
+    /// Number of harts that can be debugged.  VexRiscv only ever has one.
+    pub fn harts(&self) -> u32 {
+        match &self.controller.backend {
+            RiscvBackend::VexRiscv => 1,
+            RiscvBackend::Dmi(dm) => dm.harts(),
+        }
+    }
+
+    /// The hart that registers, memory and steps go to
+    pub fn hart(&self) -> u32 {
+        match &self.controller.backend {
+            RiscvBackend::VexRiscv => 0,
+            RiscvBackend::Dmi(dm) => dm.hart(),
+        }
+    }
+
+    /// What's known about the selected hart
+    fn current_hart(&self) -> RefMut<'_, HartState> {
+        let hart = self.hart() as usize;
+        RefMut::map(self.hart_state.borrow_mut(), |harts| &mut harts[hart])
+    }
+
+    /// Forget what was read from the harts that are about to run
+    fn forget_harts<F: Fn(u32) -> bool>(&self, runs: F) {
+        for (hart, state) in self.hart_state.borrow_mut().iter_mut().enumerate() {
+            if runs(hart as u32) {
+                state.forget();
             }
-            target_xml.push_str("/>\n");
         }
-        if last_register_type != None {
-            target_xml.push_str("</feature>\n");
+    }
+
+    /// Whether `hart` is left halted when the others are resumed
+    fn parked(&self, hart: u32) -> bool {
+        match &self.controller.backend {
+            RiscvBackend::VexRiscv => false,
+            RiscvBackend::Dmi(dm) => dm.is_parked(hart),
         }
-        target_xml.push_str("</target>\n");
-        target_xml
     }
 
-    pub fn get_feature(&self, name: &str) -> Result<Vec<u8>, RiscvCpuError> {
-        if name == "target.xml" {
-            let xml = self.target_xml.to_string().into_bytes();
-            Ok(xml)
-        } else {
-            Err(RiscvCpuError::UnrecognizedFile(name.to_string()))
+    /// Leave `harts` halted when the CPU is next resumed, rather than
+    /// having every hart run.  They stay that way until the CPU halts.
+    pub fn park_harts(&self, harts: &[u32]) {
+        if let RiscvBackend::Dmi(dm) = &self.controller.backend {
+            dm.park(harts);
         }
     }
 
-    pub fn get_threads(&self) -> Result<Vec<u8>, RiscvCpuError> {
-        Ok(THREADS_XML.to_string().into_bytes())
+    /// In GDB's non-stop mode each hart is halted and resumed by itself,
+    /// so `halt()`, `resume()` and `step()` only touch the selected one
+    /// and the rest carry on as they were.  With one hart there's nothing
+    /// to tell apart, so it works as it always did.  Either way, every
+    /// hart is halted first.
+    pub fn set_non_stop(&self, bridge: &Bridge, on: bool) -> Result<(), RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.controller.non_stop.store(false, Ordering::Relaxed);
+        *self.cpu_state.lock().unwrap() = CpuState::Halted {
+            cause: StopCause::HaltRequest,
+        };
+        self.controller.perform_halt(bridge)?;
+        if let RiscvBackend::Dmi(dm) = &self.controller.backend {
+            if on && dm.harts() > 1 {
+                self.controller.non_stop.store(true, Ordering::Relaxed);
+                dm.park(&(0..dm.harts()).collect::<Vec<u32>>());
+            }
+        }
+        Ok(())
     }
 
-    // pub fn get_memory_map(&self) -> Result<Vec<u8>, RiscvCpuError> {
-    //     Ok(MEMORY_MAP_XML.to_string().into_bytes())
-    // }
+    /// Whether `hart` is stopped as far as GDB knows.  Outside non-stop
+    /// mode that's all of them or none.
+    pub fn hart_stopped(&self, hart: u32) -> bool {
+        match &self.controller.backend {
+            RiscvBackend::Dmi(dm) if self.controller.non_stop.load(Ordering::Relaxed) => {
+                dm.is_parked(hart)
+            }
+            _ => *self.cpu_state.lock().unwrap() != CpuState::Running,
+        }
+    }
+
+    /// Send registers, memory and steps to another hart.  Registers
+    /// changed on the old one are written back first, and what was read
+    /// from it is kept for when it's selected again, as it can't change
+    /// until it runs.  Breakpoints and watchpoints made from triggers stay
+    /// on the hart they were set on, since each has its own.
+    pub fn select_hart(&self, bridge: &Bridge, hart: u32) -> Result<(), RiscvCpuError> {
+        if hart >= self.harts() {
+            return Err(RiscvCpuError::NoSuchHart(hart));
+        }
+        if hart == self.hart() {
+            return Ok(());
+        }
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        // What's written back is what the old hart will hold
+        for (reg, value) in self.cached_values.lock().unwrap().iter() {
+            if reg.register_type == RiscvRegisterType::General || self.cache_all_registers {
+                self.current_hart().register_snapshot.insert(reg.gdb_index, *value);
+            }
+        }
+        self.controller.write_back_registers(bridge)?;
+        if let RiscvBackend::Dmi(dm) = &self.controller.backend {
+            dm.select_hart(bridge, hart)?;
+        }
+        if self.has_mmu {
+            let satp = self.controller.read_register(bridge, &RiscvRegister::satp())?;
+            *self.mmu_enabled.lock().unwrap() = satp & 0x80000000 == 0x80000000;
+        }
+        debug!("selected hart {}", hart);
+        Ok(())
+    }
 
     /// Print information about why the CPU got into its current state
     pub fn explain(&self, bridge: &Bridge) -> Result<String, RiscvCpuError> {
@@ -629,7 +1600,65 @@ impl RiscvCpu {
         }
     }
 
+    /// Read `mcause`, `mtval` and `mepc` and say what they mean
+    pub fn cause(&self, bridge: &Bridge) -> Result<String, RiscvCpuError> {
+        let mcause = self.controller.read_register(bridge, &RiscvRegister::mcause())?;
+        let mtval = self.controller.read_register(bridge, &RiscvRegister::mtval())?;
+        let mepc = self.controller.read_register(bridge, &RiscvRegister::mepc())?;
+        let exception = RiscvException::from_regs(mcause, mepc, mtval);
+        let state = if exception == RiscvException::NoException {
+            ""
+        } else if self.controller.interrupts_enabled(bridge)? {
+            " (handled already, as interrupts are back on)"
+        } else {
+            " (still being handled)"
+        };
+        Ok(format!(
+            "mcause 0x{:08x}  mtval 0x{:08x}  mepc 0x{:08x}\n{}{}\n",
+            mcause,
+            mtval,
+            mepc,
+            exception.describe(),
+            state
+        ))
+    }
+
+    /// Disassemble `count` instructions from `addr`, or that many around
+    /// the pc if there's no address.  The CPU must be halted.
+    pub fn disassemble(
+        &self,
+        bridge: &Bridge,
+        addr: Option<u32>,
+        count: usize,
+    ) -> Result<String, RiscvCpuError> {
+        let pc = self.read_register_wide(bridge, 32)? as u32;
+        let read = |addr| self.read_memory(bridge, addr, 4);
+        let lines = match addr {
+            Some(addr) => disasm::listing(addr, count, read)?,
+            None => {
+                let before = count / 2;
+                disasm::around(pc, before, count.saturating_sub(before + 1), read)?
+            }
+        };
+        Ok(disasm::show(&lines, Some(pc)))
+    }
+
+    /// Show GDB the instruction the CPU stopped at, and a couple either
+    /// side of it, each time it halts
+    pub fn set_halt_disassembly(&mut self, on: bool) {
+        self.controller.disassemble_on_halt = on;
+    }
+
     pub fn add_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        // A Debug Module has trigger CSRs instead of the VexRiscv debug bus's
+        // breakpoint registers
+        if let RiscvBackend::Dmi(_) = self.controller.backend {
+            let _bridge_mutex = bridge.mutex().lock().unwrap();
+            return self
+                .controller
+                .triggers
+                .add(&self.controller, bridge, addr, 0, TriggerMatch::Execute);
+        }
         let mut bp_index = None;
         let mut bps = self.breakpoints.borrow_mut();
         for (bpidx, bp) in bps.iter().enumerate() {
@@ -652,6 +1681,13 @@ impl RiscvCpu {
     }
 
     pub fn remove_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        if let RiscvBackend::Dmi(_) = self.controller.backend {
+            let _bridge_mutex = bridge.mutex().lock().unwrap();
+            return self
+                .controller
+                .triggers
+                .remove(&self.controller, bridge, addr, TriggerMatch::Execute);
+        }
         let mut bp_index = None;
         let mut bps = self.breakpoints.borrow_mut();
         for (bpidx, bp) in bps.iter().enumerate() {
@@ -672,16 +1708,207 @@ impl RiscvCpu {
         Ok(())
     }
 
+    /// Stop the CPU when it loads or stores, as `kind` says, any of the
+    /// `len` bytes at `addr`.  Only a Debug Module's triggers can do this.
+    pub fn add_watchpoint(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        len: u32,
+        kind: TriggerMatch,
+    ) -> Result<(), RiscvCpuError> {
+        // tselect and tdata are set one after the other, so the poll thread
+        // mustn't get in between
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.controller
+            .triggers
+            .add(&self.controller, bridge, addr, len, kind)
+    }
+
+    pub fn remove_watchpoint(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        kind: TriggerMatch,
+    ) -> Result<(), RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.controller
+            .triggers
+            .remove(&self.controller, bridge, addr, kind)
+    }
+
+    /// Whether there are any triggers to make watchpoints out of
+    pub fn has_watchpoints(&self) -> bool {
+        self.controller.triggers.count() > 0
+    }
+
+    /// Patch an `ebreak` in at `addr`, as GDB's `Z0` asks.  Memory that
+    /// can't be written gives `BreakpointNotWritable`, and it's up to the
+    /// caller to use a hardware breakpoint instead.
+    pub fn add_soft_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let addr = self.translate_address(bridge, addr)?;
+        self.soft_breakpoints
+            .borrow_mut()
+            .insert(&self.controller, bridge, addr)?;
+        self.update_ebreak(bridge)
+    }
+
+    pub fn remove_soft_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let addr = self.translate_address(bridge, addr)?;
+        self.soft_breakpoints
+            .borrow_mut()
+            .remove(&self.controller, bridge, addr)?;
+        self.update_ebreak(bridge)
+    }
+
+    /// Whether `addr` is a software breakpoint rather than a hardware one
+    pub fn has_soft_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<bool, RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let addr = self.translate_address(bridge, addr)?;
+        Ok(self.soft_breakpoints.borrow().contains(addr))
+    }
+
+    pub fn soft_breakpoints(&self) -> usize {
+        self.soft_breakpoints.borrow().len()
+    }
+
+    /// Put back every instruction that has a breakpoint patched over it,
+    /// so that nothing is left behind when the debugger goes away.
+    pub fn clear_soft_breakpoints(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.soft_breakpoints
+            .borrow_mut()
+            .clear(&self.controller, bridge)?;
+        self.update_ebreak(bridge)
+    }
+
+    /// A Debug Module only stops on `ebreak` when `dcsr` says to
+    fn update_ebreak(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        if let RiscvBackend::Dmi(dm) = &self.controller.backend {
+            let enable = !self.soft_breakpoints.borrow().is_empty()
+                || self.controller.semihosting.is_some();
+            dm.set_ebreak(bridge, enable)?;
+        }
+        Ok(())
+    }
+
+    /// Look after semihosting calls from the firmware, rather than
+    /// stopping on them.  Call this before `get_controller()`.
+    pub fn enable_semihosting(&mut self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let word_size = (self.xlen.bits() / 8).min(8);
+        self.controller.semihosting = Some(Arc::new(Mutex::new(Semihosting::new(word_size))));
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.update_ebreak(bridge)
+    }
+
+    /// Have GDB carry out semihosting calls on files and the console with
+    /// File-I/O.  Semihosting has to be enabled first.
+    pub fn set_file_io(&self, on: bool) {
+        if let Some(host) = &self.controller.semihosting {
+            host.lock().unwrap().set_file_io(on);
+        }
+    }
+
+    /// Whether semihosting calls are being handed to GDB with File-I/O
+    pub fn file_io(&self) -> bool {
+        match &self.controller.semihosting {
+            Some(host) => host.lock().unwrap().file_io(),
+            None => false,
+        }
+    }
+
+    /// Give a semihosting call the result of the File-I/O request GDB was
+    /// carrying out for it, and get the CPU going again, unless GDB says
+    /// the user interrupted it.  This returns false, leaving the CPU as
+    /// it is, if there was no request.
+    pub fn finish_file_io(
+        &self,
+        bridge: &Bridge,
+        retcode: i64,
+        errno: i32,
+        interrupted: bool,
+    ) -> Result<bool, RiscvCpuError> {
+        let result = match &self.controller.semihosting {
+            Some(host) => host.lock().unwrap().finish(retcode, errno),
+            None => None,
+        };
+        let result = match result {
+            Some(result) => result,
+            None => return Ok(false),
+        };
+        let pc_index = RiscvRegister::pc().gdb_index;
+        let pc = self.read_register_wide(bridge, pc_index)?;
+        self.write_register_wide(bridge, RiscvRegister::a0().gdb_index, result)?;
+        // Carry on from the `srai` after the `ebreak`
+        self.write_register_wide(bridge, pc_index, pc + 4)?;
+        if interrupted {
+            *self.cpu_state.lock().unwrap() = CpuState::Halted {
+                cause: StopCause::HaltRequest,
+            };
+        } else {
+            self.resume(bridge)?;
+        }
+        Ok(true)
+    }
+
+    /// If the CPU is sitting on a software breakpoint, put the original
+    /// instruction back, step over it and patch the breakpoint in again,
+    /// returning whether that happened.  Without this, resuming would
+    /// just hit the same `ebreak` again.
+    fn step_over_breakpoint(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        if self.soft_breakpoints.borrow().is_empty() {
+            return Ok(false);
+        }
+        let pc = self.read_register_wide(bridge, RiscvRegister::pc().gdb_index)? as u32;
+        let addr = self.translate_address(bridge, pc)?;
+        let breakpoints = self.soft_breakpoints.borrow();
+        if !breakpoints.contains(addr) {
+            return Ok(false);
+        }
+        debug!("stepping over the breakpoint at {:08x}", pc);
+        breakpoints.unpatch(&self.controller, bridge, addr)?;
+        self.single_step(bridge)?;
+        breakpoints.repatch(&self.controller, bridge, addr)?;
+        Ok(true)
+    }
+
+    /// Run exactly one instruction and wait for the CPU to come back into
+    /// debug mode.  On a Debug Module that's `dcsr.step`, and on VexRiscv
+    /// the debug bus's step flag.
+    fn single_step(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.controller.perform_resume(bridge, true)?;
+        for _ in 0..100 {
+            if self.controller.is_halted(bridge)? {
+                return self.controller.perform_halt(bridge);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
     pub fn halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
         let _bridge_mutex = bridge.mutex().lock().unwrap();
         let mut current_status = self.cpu_state.lock().unwrap();
-        *current_status = RiscvCpuState::Halted;
+        *current_status = CpuState::Halted {
+            cause: StopCause::HaltRequest,
+        };
         self.controller.perform_halt(bridge)?;
         debug!("HALT: CPU is now halted");
         Ok(())
     }
 
+    /// Check on the CPU, noticing if it has stopped and why.
+    pub fn poll_state(&self, bridge: &Bridge) -> Result<CpuState, RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.controller.update_state(bridge)
+    }
+
     fn update_breakpoints(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        if let RiscvBackend::Dmi(_) = self.controller.backend {
+            return self.controller.triggers.restore(&self.controller, bridge);
+        }
         for (bpidx, bp) in self.breakpoints.borrow().iter().enumerate() {
             if bp.allocated && bp.enabled {
                 debug!(
@@ -699,37 +1926,112 @@ impl RiscvCpu {
                 bridge.poke(self.debug_offset + 0x40 + (bpidx as u32 * 4), 0)?;
             }
         }
-        Ok(())
+        Ok(())
+    }
+
+    /// Reset the target CPU, restore any breakpoints, and leave it in
+    /// the "halted" state.
+    pub fn reset_cpu(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.perform_reset(bridge)
+    }
+
+    /// Reset the entire SoC through the ctrl CSR, or just the CPU through
+    /// its debug interface if there isn't one, and stop the CPU on the
+    /// very first instruction it would execute.  Unless `halt` is set, it's
+    /// then let go to start the firmware again.  Either way, the PC it
+    /// started from is returned.  If that isn't the reset vector, the CPU
+    /// is left halted so that it can be looked at.
+    pub fn reset(&self, bridge: &Bridge, halt: bool) -> Result<u32, RiscvCpuError> {
+        let pc = self.reset_halt(bridge)?;
+        if !halt {
+            self.resume(bridge)?;
+            debug!("RESET: CPU let go from 0x{:08x}", pc);
+        }
+        Ok(pc)
+    }
+
+    fn reset_halt(&self, bridge: &Bridge) -> Result<u32, RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+
+        if let Some(reset_csr) = self.reset_csr {
+            // Hold the CPU in reset with a halt pending, so it can't run
+            // ahead of the rest of the SoC.
+            if let RiscvBackend::VexRiscv = self.controller.backend {
+                self.controller
+                    .write_status(bridge, VexRiscvFlags::HALT_SET | VexRiscvFlags::RESET_SET)?;
+            }
+            debug!("RESET: pulsing SoC reset CSR at 0x{:08x}", reset_csr);
+            bridge.poke(reset_csr, 1)?;
+            thread::sleep(self.reset_settle);
+        }
+
+        // The SoC reset may also have reset the debug core, so assert the
+        // halt again while bringing the CPU out of reset.
+        self.perform_reset(bridge)?;
+
+        let pc = self.controller.read_register(bridge, &RiscvRegister::pc())?;
+        if let Some(reset_vector) = self.reset_vector {
+            if pc != reset_vector {
+                return Err(RiscvCpuError::ResetVectorMismatch(reset_vector, pc));
+            }
+        }
+        debug!("RESET: CPU halted at 0x{:08x}", pc);
+        Ok(pc)
     }
 
-    /// Reset the target CPU, restore any breakpoints, and leave it in
-    /// the "halted" state.
-    pub fn reset(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
-        let _bridge_mutex = bridge.mutex().lock().unwrap();
+    fn perform_reset(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        *self.cpu_state.lock().unwrap() = CpuState::Resetting;
         // Since we're resetting the CPU, invalidate all cached registers
         self.cached_values.lock().unwrap().drain();
+        self.forget_harts(|_| true);
         self.flush_cache(bridge)?;
         *self.mmu_enabled.lock().unwrap() = false;
         *self.last_exception.lock().unwrap() = None;
 
-        self.controller
-            .write_status(bridge, VexRiscvFlags::HALT_SET)?;
-        self.controller
-            .write_status(bridge, VexRiscvFlags::HALT_SET | VexRiscvFlags::RESET_SET)?;
-        self.controller
-            .write_status(bridge, VexRiscvFlags::RESET_CLEAR)?;
+        match &self.controller.backend {
+            RiscvBackend::VexRiscv => {
+                self.controller
+                    .write_status(bridge, VexRiscvFlags::HALT_SET)?;
+                self.controller
+                    .write_status(bridge, VexRiscvFlags::HALT_SET | VexRiscvFlags::RESET_SET)?;
+                self.controller
+                    .write_status(bridge, VexRiscvFlags::RESET_CLEAR)?;
+            }
+            // The other harts come out of reset running, and are caught
+            // straight after
+            RiscvBackend::Dmi(dm) => {
+                dm.reset(bridge)?;
+                dm.halt_all(bridge)?;
+            }
+        }
 
-        *self.cpu_state.lock().unwrap() = RiscvCpuState::Halted;
+        *self.cpu_state.lock().unwrap() = CpuState::Halted {
+            cause: StopCause::Reset,
+        };
         debug!("RESET: CPU is now halted and reset");
         Ok(())
     }
 
-    /// Restore the CPU state and continue execution.
+    /// Restore the CPU state and continue execution.  There's nothing to
+    /// do if it's already running.
     pub fn resume(&self, bridge: &Bridge) -> Result<Option<String>, RiscvCpuError> {
         let _bridge_mutex = bridge.mutex().lock().unwrap();
-        *self.cpu_state.lock().unwrap() = RiscvCpuState::Running;
+        if !self.hart_stopped(self.hart()) {
+            return Ok(None);
+        }
+        *self.cpu_state.lock().unwrap() = CpuState::Running;
         // Rewrite breakpoints (is this necessary?)
         self.update_breakpoints(bridge)?;
+        self.step_over_breakpoint(bridge)?;
+        // In non-stop mode only the selected hart runs, and otherwise every
+        // one that isn't parked does
+        let selected = self.hart();
+        if self.controller.non_stop.load(Ordering::Relaxed) {
+            self.forget_harts(|hart| hart == selected);
+        } else {
+            self.forget_harts(|hart| !self.parked(hart));
+        }
         self.controller.perform_resume(bridge, false)?;
 
         if let Some(exception) = self.last_exception.lock().unwrap().take() {
@@ -740,17 +2042,30 @@ impl RiscvCpu {
         Ok(None)
     }
 
-    /// Step the CPU forward by one instruction.
-    pub fn step(&self, bridge: &Bridge) -> Result<Option<String>, RiscvCpuError> {
+    /// Step the CPU forward by one instruction, and return the PC it
+    /// stopped at along with the trap it's in, if any.
+    pub fn step(&self, bridge: &Bridge) -> Result<(u64, Option<String>), RiscvCpuError> {
         let _bridge_mutex = bridge.mutex().lock().unwrap();
-        self.controller.perform_resume(bridge, true)?;
+        *self.cpu_state.lock().unwrap() = CpuState::Stepping;
+        let stepped = self.step_over_breakpoint(bridge);
+        // Only the selected hart steps
+        let selected = self.hart();
+        self.forget_harts(|hart| hart == selected);
+        if !stepped? {
+            self.single_step(bridge)?;
+        }
+        *self.cpu_state.lock().unwrap() = CpuState::Halted {
+            cause: StopCause::Step,
+        };
+        let pc = self.read_register_wide(bridge, RiscvRegister::pc().gdb_index)?;
+        debug!("STEP: CPU stopped at {:08x}", pc);
 
         if let Some(exception) = self.last_exception.lock().unwrap().take() {
             if exception != RiscvException::NoException {
-                return Ok(Some(format!("{}", exception)));
+                return Ok((pc, Some(format!("{}", exception))));
             }
         }
-        Ok(None)
+        Ok((pc, None))
     }
 
     /// Convert a GDB `regnum` into a `RiscvRegister`
@@ -769,14 +2084,87 @@ impl RiscvCpu {
     /// The `gdb_idx` is the GDB index, and may include both CPU registers
     /// and CSR-index registers, which are offset by an index.
     pub fn read_register(&self, bridge: &Bridge, gdb_idx: u32) -> Result<u32, RiscvCpuError> {
+        if let Some(pseudo) = gdb_idx
+            .checked_sub(RiscvRegister::pseudo_offset())
+            .and_then(|idx| self.pseudo_registers.get(idx as usize))
+        {
+            return pseudo.value(&mut |name: &str| {
+                // set_pseudo_registers() made sure these all exist
+                let idx = self.register_by_name(name).unwrap();
+                self.read_register(bridge, idx)
+            });
+        }
+        Ok(self.read_register_wide(bridge, gdb_idx)? as u32)
+    }
+
+    /// Work out an expression of this CPU's registers, at their full width
+    pub fn evaluate(&self, bridge: &Bridge, expr: &PseudoRegister) -> Result<u64, RiscvCpuError> {
+        expr.value_wide(&mut |name: &str| match self.register_by_name(name) {
+            Some(idx) => self.read_register_wide(bridge, idx),
+            None => Err(RiscvCpuError::UnknownRegister(name.to_owned())),
+        })
+    }
+
+    /// Read the PMP CSRs and work out which regions they protect.  They
+    /// have to have turned up when the CSRs were looked for.
+    pub fn pmp_regions(&self, bridge: &Bridge) -> Result<Vec<PmpRegion>, RiscvCpuError> {
+        let read = |name: String| match self.register_by_name(&name) {
+            Some(idx) => self.read_register_wide(bridge, idx),
+            None => Err(RiscvCpuError::UnknownRegister(name)),
+        };
+        // Each config register holds a byte per entry.  RV64's are twice
+        // as wide, so only every other one is there.
+        let per_reg = self.xlen.bytes();
+        let mut cfg = [0; pmp::ENTRIES];
+        for (n, entries) in cfg.chunks_mut(per_reg).enumerate() {
+            let value = read(format!("mpmcfg{}", n * per_reg / 4))?;
+            for (i, entry) in entries.iter_mut().enumerate() {
+                *entry = (value >> (i * 8)) as u8;
+            }
+        }
+        let mut addr = [0; pmp::ENTRIES];
+        for (n, value) in addr.iter_mut().enumerate() {
+            *value = read(format!("pmpaddr{}", n))?;
+        }
+        Ok(pmp::decode(&cfg, &addr))
+    }
+
+    /// Read a register at its full width, which for everything but the
+    /// pseudo-registers is `xlen()`.
+    pub fn read_register_wide(&self, bridge: &Bridge, gdb_idx: u32) -> Result<u64, RiscvCpuError> {
+        if gdb_idx >= RiscvRegister::pseudo_offset() {
+            return Ok(self.read_register(bridge, gdb_idx)? as u64);
+        }
         let reg = self.gdb_to_register(gdb_idx)?;
 
         // Give the cached value, if we have it.
-        if let Some(val) = self.get_cached_reg(reg) {
+        if let Some(val) = self.get_cached_reg_wide(reg) {
             return Ok(val);
         }
+        if self.cache_all_registers {
+            if let Some(val) = self.current_hart().register_snapshot.get(&gdb_idx) {
+                return Ok(*val);
+            }
+        }
+
+        let val = self.controller.read_register_wide(bridge, reg)?;
+        if reg.register_type == RiscvRegisterType::General || self.cache_all_registers {
+            self.current_hart().register_snapshot.insert(gdb_idx, val);
+        }
+        Ok(val)
+    }
 
-        self.controller.read_register(bridge, reg)
+    /// How many bytes GDB expects for a register
+    pub fn register_bytes(&self, gdb_idx: u32) -> usize {
+        if gdb_idx >= RiscvRegister::pseudo_offset() {
+            return 4;
+        }
+        match self.gdb_register_map.get(&gdb_idx) {
+            Some(reg) if reg.register_type == RiscvRegisterType::Float => {
+                self.controller.flen as usize / 8
+            }
+            _ => self.xlen.bytes(),
+        }
     }
 
     /// Return a vec containing all valid CPU registers.
@@ -791,10 +2179,42 @@ impl RiscvCpu {
         v
     }
 
+    /// Write every general register at once, as GDB's `G` packet does, in
+    /// the order given by `all_cpu_registers()`.  `None` leaves a register
+    /// alone.
+    pub fn write_registers(
+        &self,
+        bridge: &Bridge,
+        values: &[Option<u64>],
+    ) -> Result<(), RiscvCpuError> {
+        let mut changed = 0;
+        for (gdb_idx, value) in self.all_cpu_registers().into_iter().zip(values) {
+            if let Some(value) = value {
+                if self.write_register_wide(bridge, gdb_idx, *value)? {
+                    changed += 1;
+                }
+            }
+        }
+        debug!("G packet changed {} registers", changed);
+        Ok(())
+    }
+
+    /// Whether a general register is already known to hold `value`, either
+    /// because it's waiting to be written back with it or because that's
+    /// what it read as.
+    fn holds_value(&self, gdb_idx: u32, reg: &RiscvRegister, value: u64) -> bool {
+        let current = self
+            .get_cached_reg_wide(reg)
+            .or_else(|| self.current_hart().register_snapshot.get(&gdb_idx).cloned());
+        current == Some(value)
+    }
+
     /// Write a register on the device.
     ///
     /// For general-purpose registers, simply place the new value in the
-    /// cache, to be updated when we resume the CPU.
+    /// cache, to be updated when we resume the CPU.  A register that
+    /// already holds the value isn't marked dirty, so writing back what
+    /// was read costs nothing.
     ///
     /// For CSRs, initiate the write immediately.
     pub fn write_register(
@@ -803,26 +2223,47 @@ impl RiscvCpu {
         gdb_idx: u32,
         value: u32,
     ) -> Result<(), RiscvCpuError> {
+        self.write_register_wide(bridge, gdb_idx, value as u64)
+            .map(|_| ())
+    }
+
+    /// Write a register at its full width, returning whether it was
+    /// changed.  Only general registers can turn out to be unchanged.
+    pub fn write_register_wide(
+        &self,
+        bridge: &Bridge,
+        gdb_idx: u32,
+        value: u64,
+    ) -> Result<bool, RiscvCpuError> {
         let _bridge_mutex = bridge.mutex().lock().unwrap();
         let reg = self.gdb_to_register(gdb_idx)?;
         if reg.register_type == RiscvRegisterType::General {
+            if self.holds_value(gdb_idx, reg, value) {
+                return Ok(false);
+            }
             self.set_cached_reg(reg, value);
-            Ok(())
+            Ok(true)
         } else if reg.gdb_index == RiscvRegister::satp().gdb_index {
+            self.current_hart().tlb.clear();
             if value & 0x80000000 == 0x80000000 {
                 *self.mmu_enabled.lock().unwrap() = true;
             } else {
                 *self.mmu_enabled.lock().unwrap() = false;
             }
             self.set_cached_reg(reg, value);
-            Ok(())
+            Ok(true)
         } else {
-            self.controller.write_register(bridge, reg, value)
+            // Not every bit of a CSR has to take, so it's read again next
+            // time rather than remembered
+            self.current_hart().register_snapshot.remove(&gdb_idx);
+            self.controller.write_register_wide(bridge, reg, value)?;
+            Ok(true)
         }
     }
 
     pub fn read_memory(&self, bridge: &Bridge, addr: u32, sz: u32) -> Result<u32, RiscvCpuError> {
         let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let addr = self.translate_address(bridge, addr)?;
         self.controller.read_memory(bridge, addr, sz)
     }
 
@@ -834,9 +2275,115 @@ impl RiscvCpu {
         value: u32,
     ) -> Result<(), RiscvCpuError> {
         let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let addr = self.translate_address(bridge, addr)?;
         self.controller.write_memory(bridge, addr, sz, value)
     }
 
+    /// Whether addresses currently go through the page tables
+    pub fn paging_enabled(&self) -> bool {
+        self.mmu_active() && !self.physical_access.get()
+    }
+
+    /// Whether the CPU itself has paging turned on, whatever GDB is using
+    pub fn mmu_active(&self) -> bool {
+        self.has_mmu && *self.mmu_enabled.lock().unwrap()
+    }
+
+    /// Take GDB's addresses as physical, rather than translating them
+    /// through the page tables, or go back to translating them
+    pub fn set_physical_access(&self, physical: bool) {
+        self.physical_access.set(physical);
+    }
+
+    /// Walk the page tables for `addr`, for `monitor mmu`, whether or not
+    /// GDB's own accesses are being translated.  None means paging is off.
+    pub fn translation(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+    ) -> Result<Option<mmu::Translation>, RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        match self.current_satp(bridge)? {
+            Some(satp) => mmu::walk(satp, addr, |a| self.read_physical(bridge, a)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// `satp`, if the MMU is there and Sv32 is on
+    pub fn current_satp(&self, bridge: &Bridge) -> Result<Option<u32>, RiscvCpuError> {
+        if !self.mmu_active() {
+            return Ok(None);
+        }
+        // VexRiscv has its real satp stashed in the cache while halted.
+        let satp = RiscvRegister::satp();
+        let satp_value = match self.get_cached_reg_wide(&satp) {
+            Some(s) => s as u32,
+            None => self.controller.read_register(bridge, &satp)?,
+        };
+        if satp_value & mmu::SATP_MODE_SV32 == 0 {
+            return Ok(None);
+        }
+        Ok(Some(satp_value))
+    }
+
+    /// Read a page table entry, which the 32-bit bridge can only reach in
+    /// the bottom 4 GiB
+    fn read_physical(&self, bridge: &Bridge, addr: u64) -> Result<u32, RiscvCpuError> {
+        if addr > u32::MAX as u64 {
+            debug!("MMU: page table entry at {:09x} is out of the bridge's reach", addr);
+            return Err(RiscvCpuError::PageFault(addr as u32));
+        }
+        Ok(bridge.peek(addr as u32)?)
+    }
+
+    /// Determine whether all `len` bytes at `addr` can be accessed under
+    /// the current page tables, which means every page they touch must be
+    /// mapped.  This is always true when the MMU is off.
+    pub fn is_mapped(&self, bridge: &Bridge, addr: u32, len: u32) -> Result<bool, RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let last = (addr as u64 + len.max(1) as u64 - 1).min(u32::MAX as u64) as u32;
+        let mut page = addr & !0xfff;
+        loop {
+            match self.translate_address(bridge, page.max(addr)) {
+                Ok(_) => (),
+                Err(RiscvCpuError::PageFault(_)) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+            page = match page.checked_add(0x1000) {
+                Some(next) if next <= last => next,
+                _ => return Ok(true),
+            };
+        }
+    }
+
+    /// Turn a virtual address into a physical address by walking the Sv32
+    /// page tables over the bridge.  The bridge itself only ever sees
+    /// physical addresses, so this is needed whenever paging is enabled.
+    fn translate_address(&self, bridge: &Bridge, addr: u32) -> Result<u32, RiscvCpuError> {
+        if self.physical_access.get() {
+            return Ok(addr);
+        }
+        let satp = match self.current_satp(bridge)? {
+            Some(satp) => satp,
+            None => return Ok(addr),
+        };
+
+        let vpage = addr & !0xfff;
+        if let Some(ppage) = self.current_hart().tlb.get(&vpage) {
+            return Ok(ppage | (addr & 0xfff));
+        }
+
+        let translation = mmu::walk(satp, addr, |a| self.read_physical(bridge, a))?;
+        if translation.physical > u32::MAX as u64 {
+            debug!("MMU: {:08x} maps to {:09x}, out of the bridge's reach", addr, translation.physical);
+            return Err(RiscvCpuError::PageFault(addr));
+        }
+        let ppage = translation.physical as u32 & !0xfff;
+        debug!("MMU: virtual page {:08x} -> physical page {:08x}", vpage, ppage);
+        self.current_hart().tlb.insert(vpage, ppage);
+        Ok(ppage | (addr & 0xfff))
+    }
+
     pub fn get_controller(&self) -> RiscvCpuController {
         RiscvCpuController {
             cpu_state: self.cpu_state.clone(),
@@ -845,23 +2392,34 @@ impl RiscvCpu {
             has_mmu: self.has_mmu,
             mmu_enabled: self.mmu_enabled.clone(),
             last_exception: self.last_exception.clone(),
+            backend: self.controller.backend.clone(),
+            flen: self.controller.flen,
+            extensions: self.controller.extensions,
+            triggers: self.controller.triggers.clone(),
+            semihosting: self.controller.semihosting.clone(),
+            disassemble_on_halt: self.controller.disassemble_on_halt,
+            non_stop: self.controller.non_stop.clone(),
         }
     }
 
-    fn get_cached_reg(&self, reg: &RiscvRegister) -> Option<u32> {
-        match self.cached_values.lock().unwrap().get(reg) {
-            Some(x) => Some(*x),
-            None => None,
-        }
+    fn get_cached_reg_wide(&self, reg: &RiscvRegister) -> Option<u64> {
+        self.cached_values.lock().unwrap().get(reg).cloned()
     }
 
-    fn set_cached_reg(&self, reg: &RiscvRegister, value: u32) {
+    fn set_cached_reg(&self, reg: &RiscvRegister, value: u64) {
         self.cached_values.lock().unwrap().insert(reg.clone(), value);
     }
 
     pub fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
         self.controller.flush_cache(bridge)
     }
+
+    /// Write out the registers that have been changed without resuming.
+    /// Otherwise they're only written when the CPU is next let go.
+    pub fn write_back_registers(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.controller.write_back_registers(bridge)
+    }
 }
 
 fn is_running(flags: VexRiscvFlags) -> bool {
@@ -871,6 +2429,12 @@ fn is_running(flags: VexRiscvFlags) -> bool {
 }
 
 impl RiscvCpuController {
+    /// Whether the CPU may be running compressed instructions.  Without
+    /// `misa` there's no telling, so it's taken that it might be.
+    pub fn compressed(&self) -> bool {
+        self.extensions == 0 || self.extensions & MISA_C != 0
+    }
+
     /// Poll the CPU and determine if it's running or not.  If it
     /// transitions between states, handle this transition as appropriate.
     pub fn poll(
@@ -879,46 +2443,224 @@ impl RiscvCpuController {
         gdb_controller: &mut GdbController,
     ) -> Result<bool, RiscvCpuError> {
         let _bridge_mutex = bridge.mutex().lock().unwrap();
-        let flags = self.read_status(bridge)?;
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            if self.non_stop.load(Ordering::Relaxed) {
+                return self.poll_harts(dm, bridge, gdb_controller);
+            }
+        }
+        let was_running = *self.cpu_state.lock().unwrap() == CpuState::Running;
+        let state = self.update_state(bridge)?;
+        if let (true, CpuState::Halted { cause: StopCause::Breakpoint }) = (was_running, state) {
+            if self.semihost(bridge, gdb_controller)? {
+                return Ok(*self.cpu_state.lock().unwrap() == CpuState::Running);
+            }
+        }
+        if let (true, CpuState::Halted { cause }) = (was_running, state) {
+            // Say so if it stopped partway into handling a fault, since
+            // GDB only sees where the handler has got to
+            let fault = match self.last_exception.lock().unwrap().as_ref() {
+                Some(exception) if exception.is_fault() => Some(exception.describe()),
+                _ => None,
+            };
+            if let Some(fault) = fault {
+                info!("CPU halted in a trap: {}", fault);
+                gdb_controller.print_string(&format!("CPU halted in a trap: {}\n", fault))?;
+            }
+            // With paging on the pc is virtual, and these reads aren't
+            if self.disassemble_on_halt && !*self.mmu_enabled.lock().unwrap() {
+                let pc = self.halted_pc(bridge)? as u32;
+                match disasm::around(pc, HALT_CONTEXT, HALT_CONTEXT, |addr| {
+                    self.read_memory(bridge, addr, 4)
+                }) {
+                    Ok(lines) => gdb_controller.print_string(&disasm::show(&lines, Some(pc)))?,
+                    Err(e) => debug!("couldn't disassemble around {:08x}: {:?}", pc, e),
+                }
+            }
+            let mut reply = cause.stop_reply();
+            if let RiscvBackend::Dmi(dm) = &self.backend {
+                if dm.harts() > 1 {
+                    reply.push_str(&format!("thread:{:x};", dm.hart() + 1));
+                }
+            }
+            gdb_controller.report_stop(&reply)?;
+        }
+        Ok(state == CpuState::Running)
+    }
+
+    /// Check on each hart in non-stop mode, and report the ones that have
+    /// stopped since they were let go.  Each is parked, and left halted
+    /// until GDB resumes it.  The selected hart stays selected, since GDB
+    /// is still talking to it.  Semihosting calls aren't carried out here,
+    /// as the registers written for them would go to that hart.
+    fn poll_harts(
+        &self,
+        dm: &DebugModule,
+        bridge: &Bridge,
+        gdb_controller: &mut GdbController,
+    ) -> Result<bool, RiscvCpuError> {
+        let selected = dm.hart();
+        while let Some(hart) = dm.halted_hart(bridge)? {
+            dm.select_hart(bridge, hart)?;
+            let cause = self.stop_cause(bridge);
+            dm.set_parked(hart, true);
+            dm.select_hart(bridge, selected)?;
+            let cause = cause?;
+            debug!("POLL: hart {} is now halted ({:?})", hart, cause);
+            let reply = format!("{}thread:{:x};", cause.stop_reply(), hart + 1);
+            gdb_controller.report_stop(&reply)?;
+        }
+        Ok((0..dm.harts()).any(|hart| !dm.is_parked(hart)))
+    }
+
+    /// If the CPU stopped on a semihosting call, carry it out and get the
+    /// CPU going again, or hand it to GDB as a File-I/O request, returning
+    /// whether that happened.  A call to exit leaves it stopped, to be
+    /// reported as usual.  The bridge must be locked.
+    fn semihost(&self, bridge: &Bridge, gdb_controller: &mut GdbController) -> Result<bool, RiscvCpuError> {
+        let host = match &self.semihosting {
+            Some(host) => host,
+            None => return Ok(false),
+        };
+        let pc_reg = RiscvRegister::pc();
+        let pc = self.halted_pc(bridge)?;
+        if !semihosting::is_call(bridge, pc as u32)? {
+            return Ok(false);
+        }
+        let op = self.read_register_wide(bridge, &RiscvRegister::a0())?;
+        let param = self.read_register_wide(bridge, &RiscvRegister::a1())?;
+        debug!("semihosting call {:#x} at {:08x}", op, pc);
+        let request = host.lock().unwrap().request(bridge, op, param)?;
+        match request {
+            // The CPU stays halted until GDB's reply
+            Request::Gdb(request) => {
+                gdb_controller.report_stop(&request)?;
+                Ok(true)
+            }
+            Request::Done(Outcome::Return(value)) => {
+                self.write_register_wide(bridge, &RiscvRegister::a0(), value)?;
+                // Carry on from the `srai` after the `ebreak`
+                self.cached_values.lock().unwrap().insert(pc_reg, pc + 4);
+                *self.cpu_state.lock().unwrap() = CpuState::Running;
+                self.perform_resume(bridge, false)?;
+                Ok(true)
+            }
+            Request::Done(Outcome::Exit(status)) => {
+                info!("firmware exited with status {}", status);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Where the CPU stopped, which is usually known already from working
+    /// out why.  The bridge must be locked.
+    fn halted_pc(&self, bridge: &Bridge) -> Result<u64, RiscvCpuError> {
+        let pc_reg = RiscvRegister::pc();
+        let cached_pc = self.cached_values.lock().unwrap().get(&pc_reg).copied();
+        match cached_pc {
+            Some(pc) => Ok(pc),
+            None => self.read_register_wide(bridge, &pc_reg),
+        }
+    }
+
+    /// Bring the CPU state up to date with the hardware, working out why
+    /// it stopped if it has.  The bridge must be locked.
+    fn update_state(&self, bridge: &Bridge) -> Result<CpuState, RiscvCpuError> {
+        let running = self.is_running(bridge)?;
         let mut current_status = self.cpu_state.lock().unwrap();
 
-        if !is_running(flags) {
+        if !running {
             // If the status was running, transition to the `halted` state.
-            if *current_status == RiscvCpuState::Running {
-                *current_status = RiscvCpuState::Halted;
-                // gdb_controller.gdb_send(b"T05swbreak:;")?;
-
-                // If we were halted by a breakpoint, save the PC (because it will
-                // be unavailable later).
-                let halt_msg = if flags & VexRiscvFlags::HALTED_BY_BREAK == VexRiscvFlags::HALTED_BY_BREAK {
-                    // The actual opcode doesn't get executed when halted by a break, but
-                    // the pc gets incremented.  Save the target pc so that we can execute it
-                    // when we step/resume.
-                    let pc = self.read_result(bridge)?;
-                    self.cached_values.lock().unwrap().insert(RiscvRegister::pc(), pc);
-                    "05"
-                }
-                else {
-                    "02"
+            if *current_status == CpuState::Running {
+                *current_status = CpuState::Halted {
+                    cause: StopCause::Unknown,
                 };
-
+                let cause = self.stop_cause(bridge)?;
+                *current_status = CpuState::Halted { cause };
                 self.perform_halt(bridge)?;
-                debug!("POLL: CPU is now halted");
-                gdb_controller.gdb_send(format!("T{}", halt_msg).as_bytes())?;
+                debug!("POLL: CPU is now halted ({:?})", cause);
             }
         } else {
             // If we're currently running but we shouldn't be, flush caches and stop.
-            if *current_status == RiscvCpuState::Halted {
+            if current_status.is_halted() {
                 info!("POLL: The debugger thinks the CPU is halted, but CPU is now running!  Halting it and flushing the caches.");
                 self.cached_values.lock().unwrap().drain();
                 self.perform_halt(bridge)?;
             }
         }
-        Ok(*current_status == RiscvCpuState::Running)
+        Ok(*current_status)
+    }
+
+    /// Returns `true` if the CPU is executing code.  With several harts
+    /// it's stopped as soon as any of them is, and that hart is selected
+    /// so that the stop is reported from it.
+    fn is_running(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        match &self.backend {
+            RiscvBackend::VexRiscv => Ok(is_running(self.read_status(bridge)?)),
+            RiscvBackend::Dmi(dm) => match dm.halted_hart(bridge)? {
+                Some(hart) => {
+                    if hart != dm.hart() {
+                        dm.select_hart(bridge, hart)?;
+                    }
+                    Ok(false)
+                }
+                None => Ok(true),
+            },
+        }
+    }
+
+    /// Returns `true` if the CPU is stopped in debug mode
+    fn is_halted(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        match &self.backend {
+            RiscvBackend::VexRiscv => {
+                Ok((self.read_status(bridge)? & VexRiscvFlags::HALT) == VexRiscvFlags::HALT)
+            }
+            RiscvBackend::Dmi(dm) => dm.is_halted(bridge),
+        }
+    }
+
+    /// Work out why a halted CPU stopped.  VexRiscv can only say whether
+    /// it was a breakpoint, but a Debug Module has `dcsr.cause`.
+    fn stop_cause(&self, bridge: &Bridge) -> Result<StopCause, RiscvCpuError> {
+        match &self.backend {
+            RiscvBackend::VexRiscv => {
+                let flags = self.read_status(bridge)?;
+                if flags & VexRiscvFlags::HALTED_BY_BREAK != VexRiscvFlags::HALTED_BY_BREAK {
+                    return Ok(StopCause::HaltRequest);
+                }
+                // If we were halted by a breakpoint, save the PC (because it will
+                // be unavailable later).
+                // The actual opcode doesn't get executed when halted by a break, but
+                // the pc gets incremented.  Save the target pc so that we can execute it
+                // when we step/resume.
+                let pc = self.read_result(bridge)?;
+                self.cached_values.lock().unwrap().insert(RiscvRegister::pc(), pc as u64);
+                Ok(StopCause::Breakpoint)
+            }
+            RiscvBackend::Dmi(dm) => Ok(match dm.halt_cause(bridge)? {
+                DebugCause::Ebreak => StopCause::Breakpoint,
+                DebugCause::Trigger => match self.triggers.watchpoint_hit(self, bridge)? {
+                    Some((kind, addr)) => StopCause::Watchpoint(kind, addr),
+                    None => StopCause::Breakpoint,
+                },
+                DebugCause::HaltRequest => StopCause::HaltRequest,
+                DebugCause::Step => StopCause::Step,
+                DebugCause::ResetHaltRequest => StopCause::Reset,
+                DebugCause::Unknown(_) => StopCause::Unknown,
+            }),
+        }
     }
 
     fn perform_halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
-        self.write_status(bridge, VexRiscvFlags::HALT_SET)?;
+        match &self.backend {
+            RiscvBackend::VexRiscv => self.write_status(bridge, VexRiscvFlags::HALT_SET)?,
+            RiscvBackend::Dmi(dm) if self.non_stop.load(Ordering::Relaxed) => {
+                if !dm.is_halted(bridge)? {
+                    dm.halt(bridge)?;
+                }
+                dm.set_parked(dm.hart(), true);
+            }
+            RiscvBackend::Dmi(dm) => dm.halt_all(bridge)?,
+        }
         self.flush_cache(bridge)?;
 
         let mut last_exception = self.last_exception.lock().unwrap();
@@ -934,10 +2676,14 @@ impl RiscvCpuController {
         // halting the CPU.
         // It will be added to the register cache so that it is restored
         // when the CPU is resumed.
+        // Debug Modules conforming to the specification don't have this
+        // limitation.
         if self.has_mmu {
             let satp = RiscvRegister::satp();
             let satp_value = self.read_register(bridge, &satp)?;
-            if satp_value & 0x80000000 == 0x80000000 {
+            if let RiscvBackend::Dmi(_) = self.backend {
+                *self.mmu_enabled.lock().unwrap() = satp_value & 0x80000000 == 0x80000000;
+            } else if satp_value & 0x80000000 == 0x80000000 {
                 debug!("cpu has an mmu that is enabled -=  disabling it while in debug mode");
                 *self.mmu_enabled.lock().unwrap() = true;
                 self.set_cached_reg(&satp, satp_value);
@@ -950,11 +2696,46 @@ impl RiscvCpuController {
     }
 
     fn perform_resume(&self, bridge: &Bridge, step_only: bool) -> Result<(), RiscvCpuError> {
-        let coll: HashMap<RiscvRegister, u32> = {
+        self.write_back_registers(bridge)?;
+        self.flush_cache(bridge)?;
+
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            // A step only runs the selected hart
+            if step_only {
+                dm.set_step(bridge, true)?;
+                dm.resume(bridge)?;
+            } else if self.non_stop.load(Ordering::Relaxed) {
+                dm.set_step(bridge, false)?;
+                dm.resume(bridge)?;
+                dm.set_parked(dm.hart(), false);
+                debug!("RESUME: hart {} is now running", dm.hart());
+            } else {
+                dm.resume_all(bridge)?;
+                debug!("RESUME: CPU is now running");
+            }
+            return Ok(());
+        }
+
+        if step_only {
+            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP)?;
+        } else {
+            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR)?;
+            debug!("RESUME: CPU is now running");
+        }
+        Ok(())
+    }
+
+    /// Write out the registers that were changed, or clobbered by debug
+    /// operations, while the CPU was halted.
+    fn write_back_registers(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let coll: HashMap<RiscvRegister, u64> = {
             let mut cached_registers = self.cached_values.lock().unwrap();
             let drain = cached_registers.drain();
             drain.collect()
         };
+        if !coll.is_empty() {
+            debug!("writing back {} registers", coll.len());
+        }
 
         // Do two passes through the list.
         // Register 32 (pc), as well as the CSRs all clobber x1/x2, so
@@ -962,29 +2743,16 @@ impl RiscvCpuController {
         for (reg, value) in &coll {
             if reg.gdb_index > 2 {
                 debug!("restoring value of {} to {:08x}", reg.name, value);
-                self.write_register(bridge, reg, *value)?;
+                self.write_register_wide(bridge, reg, *value)?;
             }
         }
 
         for (reg, value) in coll {
             if reg.gdb_index <= 2 {
                 debug!("restoring value of {} to {:08x}", reg.name, value);
-                self.write_register(
-                    bridge,
-                    &reg,
-                    value,
-                )?;
+                self.write_register_wide(bridge, &reg, value)?;
             }
         }
-
-        self.flush_cache(bridge)?;
-
-        if step_only {
-            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP)?;
-        } else {
-            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR)?;
-            debug!("RESUME: CPU is now running");
-        }
         Ok(())
     }
 
@@ -999,6 +2767,54 @@ impl RiscvCpuController {
         }
     }
 
+    /// Return which of `csrs` exist on a halted CPU.  A Debug Module
+    /// refuses to read a CSR that isn't there.  VexRiscv instead takes an
+    /// illegal instruction trap, so each read is followed by a look at
+    /// `mcause`, and the trap registers and PC are put back afterwards.
+    fn probe_csrs(&self, bridge: &Bridge, csrs: &[u32]) -> Result<Vec<u32>, RiscvCpuError> {
+        let mut found = vec![];
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            for &csr in csrs {
+                match dm.read_csr(bridge, csr) {
+                    Ok(_) => found.push(csr),
+                    Err(RiscvCpuError::AbstractCommandError(_)) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(found);
+        }
+
+        let mcause = RiscvRegister::mcause();
+        let mut saved = vec![];
+        for reg in &[RiscvRegister::pc(), RiscvRegister::mstatus(), RiscvRegister::mepc(), mcause.clone()] {
+            saved.push((reg.clone(), self.read_register(bridge, reg)?));
+        }
+        // mtval is optional, and also gets written by the trap, so check
+        // it first and save it if it's there.
+        let mut csrs = csrs.to_vec();
+        let mtval = RiscvRegister::mtval();
+        if let Some(pos) = csrs.iter().position(|&c| c == mtval.index) {
+            csrs.remove(pos);
+            csrs.insert(0, mtval.index);
+        }
+
+        for csr in csrs {
+            self.write_register(bridge, &mcause, 0)?;
+            let value = self.read_register(bridge, &RiscvRegister::csr(csr, "", true))?;
+            if self.read_register(bridge, &mcause)? != ILLEGAL_INSTRUCTION {
+                if csr == mtval.index {
+                    saved.push((mtval.clone(), value));
+                }
+                found.push(csr);
+            }
+        }
+
+        for (reg, value) in saved.iter().rev() {
+            self.write_register(bridge, reg, *value)?;
+        }
+        Ok(found)
+    }
+
     /// Return the current CPU trap, which could be an interrupt or an
     /// exception.
     pub fn get_current_trap(&self, bridge: &Bridge) -> Result<RiscvException, RiscvCpuError> {
@@ -1032,9 +2848,7 @@ impl RiscvCpuController {
         // We clobber $x1 in this function, so read its previous value
         // (if we haven't already).
         // This will get restored when we do a reset.
-        if self.get_cached_reg(&RiscvRegister::x1()).is_none() {
-            self.set_cached_reg(&RiscvRegister::x1(), self.read_register(bridge, &RiscvRegister::x1())?);
-        }
+        self.save_register(bridge, &RiscvRegister::x1())?;
 
         self.write_register(bridge, &RiscvRegister::x1(), addr)?;
         let inst = match sz {
@@ -1064,13 +2878,22 @@ impl RiscvCpuController {
             return Ok(bridge.poke(addr, value)?);
         }
 
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            if !dm.has_progbuf() {
+                // Without a program buffer there's no way to issue a narrow
+                // store, so perform a read-modify-write of the whole word.
+                let shift = 8 * (addr & 3);
+                let mask = if sz == 2 { 0xffff } else { 0xff } << shift;
+                let word = bridge.peek(addr & !3)?;
+                return Ok(bridge.poke(addr & !3, (word & !mask) | ((value << shift) & mask))?);
+            }
+        }
+
         // We clobber $x1 and $x2 in this function, so read their previous
         // values (if we haven't already).
         // This will get restored when we do a reset.
         for reg in &[RiscvRegister::x1(), RiscvRegister::x2()] {
-            if self.get_cached_reg(&reg).is_none() {
-                self.set_cached_reg(&reg, self.read_register(bridge, reg)?);
-            }
+            self.save_register(bridge, reg)?;
         }
 
         self.write_register(bridge, &RiscvRegister::x1(), value)?;
@@ -1096,6 +2919,17 @@ impl RiscvCpuController {
     /// Execute instructions on the CPU.  If reading a CSR, x1 will get clobbered.
     /// This clobbered value will be saved in the register cache.
     fn read_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u32, RiscvCpuError> {
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            // The PC is accessed via `dpc`
+            return match reg.register_type {
+                RiscvRegisterType::General if reg.index == 32 => dm.read_csr(bridge, dmi::CSR_DPC),
+                RiscvRegisterType::General => {
+                    dm.read_register(bridge, DebugModule::gpr_regno(reg.index))
+                }
+                RiscvRegisterType::CSR => dm.read_csr(bridge, reg.index),
+                RiscvRegisterType::Float => Ok(self.read_register_wide(bridge, reg)? as u32),
+            };
+        }
         match reg.register_type {
             RiscvRegisterType::General => {
                 if reg.index == 32 {
@@ -1108,9 +2942,7 @@ impl RiscvCpuController {
                 // We clobber $x1 in this function, so read its previous value
                 // (if we haven't already).
                 // This will get restored when we resume.
-                if self.get_cached_reg(&RiscvRegister::x1()).is_none() {
-                    self.set_cached_reg(&RiscvRegister::x1(), self.read_register(bridge, &RiscvRegister::x1())?);
-                }
+                self.save_register(bridge, &RiscvRegister::x1())?;
 
                 // Perform a CSRRW which does a Read/Write.  If rs1 is $x0, then the write
                 // is ignored and side-effect free.  Set rd to $x1 to make the read
@@ -1125,12 +2957,81 @@ impl RiscvCpuController {
                     | (0x73 << 0), // SYSTEM
                 )
             }
+            RiscvRegisterType::Float => {
+                return self.with_fpu(bridge, || {
+                    // FMV.X.W x0, f?
+                    self.write_instruction(bridge, (0x70 << 25) | (reg.index << 15) | 0x53)?;
+                    self.read_result(bridge)
+                });
+            }
         }?;
         let result = self.read_result(bridge)?;
         debug!("Register x{} value: 0x{:08x}", reg.index, result);
         Ok(result)
     }
 
+    /// Read a register at the CPU's full width.  Only a Debug Module can be
+    /// in front of anything wider than 32 bits.
+    fn read_register_wide(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u64, RiscvCpuError> {
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            return match reg.register_type {
+                RiscvRegisterType::General if reg.index == 32 => {
+                    dm.read_csr_wide(bridge, dmi::CSR_DPC)
+                }
+                RiscvRegisterType::General => {
+                    dm.read_register_wide(bridge, DebugModule::gpr_regno(reg.index))
+                }
+                RiscvRegisterType::CSR => dm.read_csr_wide(bridge, reg.index),
+                RiscvRegisterType::Float => self.with_fpu(bridge, || {
+                    dm.read_register_sized(bridge, DebugModule::fpr_regno(reg.index), self.flen)
+                }),
+            };
+        }
+        Ok(self.read_register(bridge, reg)? as u64)
+    }
+
+    fn write_register_wide(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u64,
+    ) -> Result<(), RiscvCpuError> {
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            debug!("Setting register {:?} -> {:016x}", reg, value);
+            return match reg.register_type {
+                RiscvRegisterType::General if reg.index == 32 => {
+                    dm.write_csr_wide(bridge, dmi::CSR_DPC, value)
+                }
+                RiscvRegisterType::General => {
+                    dm.write_register_wide(bridge, DebugModule::gpr_regno(reg.index), value)
+                }
+                RiscvRegisterType::CSR => dm.write_csr_wide(bridge, reg.index, value),
+                RiscvRegisterType::Float => self.with_fpu(bridge, || {
+                    let regno = DebugModule::fpr_regno(reg.index);
+                    dm.write_register_sized(bridge, regno, value, self.flen)
+                }),
+            };
+        }
+        self.write_register(bridge, reg, value as u32)
+    }
+
+    /// Run `f` with the FPU turned on, since it can't be touched while
+    /// `mstatus.FS` says it's off, and then put `mstatus` back.
+    fn with_fpu<T, F>(&self, bridge: &Bridge, f: F) -> Result<T, RiscvCpuError>
+    where
+        F: FnOnce() -> Result<T, RiscvCpuError>,
+    {
+        let mstatus = RiscvRegister::mstatus();
+        let old = self.read_register_wide(bridge, &mstatus)?;
+        if old & MSTATUS_FS != 0 {
+            return f();
+        }
+        self.write_register_wide(bridge, &mstatus, old | MSTATUS_FS_INITIAL)?;
+        let result = f();
+        self.write_register_wide(bridge, &mstatus, old)?;
+        result
+    }
+
     /// Write a value to a specified register
     ///
     /// Poke instructions into the CPU to update a specified register.  This might
@@ -1143,6 +3044,18 @@ impl RiscvCpuController {
         value: u32,
     ) -> Result<(), RiscvCpuError> {
         debug!("Setting register {:?} -> {:08x}", reg, value);
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            return match reg.register_type {
+                RiscvRegisterType::General if reg.index == 32 => {
+                    dm.write_csr(bridge, dmi::CSR_DPC, value)
+                }
+                RiscvRegisterType::General => {
+                    dm.write_register(bridge, DebugModule::gpr_regno(reg.index), value)
+                }
+                RiscvRegisterType::CSR => dm.write_csr(bridge, reg.index, value),
+                RiscvRegisterType::Float => self.write_register_wide(bridge, reg, value as u64),
+            };
+        }
         match reg.register_type {
             RiscvRegisterType::General => {
                 // Handle PC separately
@@ -1183,9 +3096,7 @@ impl RiscvCpuController {
                 // We clobber $x1 in this function, so read its previous value
                 // (if we haven't already).
                 // This will get restored when we do a reset.
-                if self.get_cached_reg(&RiscvRegister::x1()).is_none() {
-                    self.set_cached_reg(&RiscvRegister::x1(), self.read_register(bridge, &RiscvRegister::x1())?);
-                }
+                self.save_register(bridge, &RiscvRegister::x1())?;
 
                 // Perform a CSRRW which does a Read/Write.  If rd is $x0, then the read
                 // is ignored and side-effect free.  Set rs1 to $x1 to make the write
@@ -1208,25 +3119,43 @@ impl RiscvCpuController {
                     | (0x73 << 0), // SYSTEM
                 )
             }
+            RiscvRegisterType::Float => self.with_fpu(bridge, || {
+                // The value goes via $x1, which gets restored when we resume
+                self.save_register(bridge, &RiscvRegister::x1())?;
+                self.write_register(bridge, &RiscvRegister::x1(), value)?;
+                // FMV.W.X f?, x1
+                self.write_instruction(bridge, (0x78 << 25) | (1 << 15) | (reg.index << 7) | 0x53)
+            }),
         }
     }
 
     fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            // FENCE.I
+            if dm.has_progbuf() {
+                dm.execute_progbuf(bridge, &[4111])?;
+            }
+            return Ok(());
+        }
         for opcode in vec![4111, 19, 19, 19] {
             self.write_instruction(bridge, opcode)?;
         }
         Ok(())
     }
 
-    fn get_cached_reg(&self, reg: &RiscvRegister) -> Option<u32> {
-        match self.cached_values.lock().unwrap().get(reg) {
-            Some(x) => Some(*x),
-            None => None,
-        }
+    fn set_cached_reg(&self, reg: &RiscvRegister, value: u32) {
+        self.cached_values.lock().unwrap().insert(reg.clone(), value as u64);
     }
 
-    fn set_cached_reg(&self, reg: &RiscvRegister, value: u32) {
+    /// Stash the full width of a register that's about to be clobbered, unless
+    /// it's already been stashed.  It gets put back when the CPU resumes.
+    fn save_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<(), RiscvCpuError> {
+        if self.cached_values.lock().unwrap().contains_key(reg) {
+            return Ok(());
+        }
+        let value = self.read_register_wide(bridge, reg)?;
         self.cached_values.lock().unwrap().insert(reg.clone(), value);
+        Ok(())
     }
 
     fn write_instruction(&self, bridge: &Bridge, opcode: u32) -> Result<(), RiscvCpuError> {
@@ -1235,6 +3164,9 @@ impl RiscvCpuController {
         //     opcode,
         //     swab(opcode)
         // );
+        if let RiscvBackend::Dmi(dm) = &self.backend {
+            return dm.execute_progbuf(bridge, &[opcode]);
+        }
         bridge.poke(self.debug_offset + 4, opcode)?;
         for _ in 0..100 {
             if (self.read_status(bridge)? & VexRiscvFlags::PIP_BUSY) != VexRiscvFlags::PIP_BUSY {
@@ -1248,6 +3180,26 @@ impl RiscvCpuController {
         Ok(bridge.peek(self.debug_offset + 4)?)
     }
 
+    /// Make sure there's a VexRiscv debug bus at all, by asking the CPU to
+    /// halt and seeing whether it says it has.  Without this a wrong
+    /// --debug-offset shows up as instruction timeouts much later.
+    fn check_vexriscv(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let flags = self.read_status(bridge)?;
+        if flags.bits == 0xffff_ffff {
+            return Err(RiscvCpuError::NoDebugInterface(self.debug_offset));
+        }
+        if flags.contains(VexRiscvFlags::HALT) {
+            return Ok(());
+        }
+        self.write_status(bridge, VexRiscvFlags::HALT_SET)?;
+        let halted = self.read_status(bridge)?.contains(VexRiscvFlags::HALT);
+        self.write_status(bridge, VexRiscvFlags::HALT_CLEAR)?;
+        if !halted {
+            return Err(RiscvCpuError::NoDebugInterface(self.debug_offset));
+        }
+        Ok(())
+    }
+
     fn write_status(&self, bridge: &Bridge, value: VexRiscvFlags) -> Result<(), RiscvCpuError> {
         debug!("SETTING BRIDGE STATUS: {:08x}", value.bits);
         bridge.poke(self.debug_offset, value.bits)?;
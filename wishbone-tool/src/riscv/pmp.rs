@@ -0,0 +1,158 @@
+//! Physical memory protection, as set up in `mpmcfg0`-`mpmcfg3` and
+//! `pmpaddr0`-`pmpaddr15`.  Each config register holds the byte-wide
+//! configs of four entries on RV32, or eight on RV64, where only the
+//! even-numbered config registers exist.  The configs say what the entry
+//! allows and how its `pmpaddr` is to be read: as the top of a range
+//! starting where the entry before ends (TOR), as a single word (NA4), or
+//! as a naturally aligned power-of-two region whose size is in the low
+//! bits that are set (NAPOT).  The first entry that covers an address
+//! decides the access.
+
+use std::fmt;
+
+pub const ENTRIES: usize = 16;
+
+const CFG_R: u8 = 1 << 0;
+const CFG_W: u8 = 1 << 1;
+const CFG_X: u8 = 1 << 2;
+const CFG_A_SHIFT: u8 = 3;
+const CFG_L: u8 = 1 << 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PmpMode {
+    Tor,
+    Na4,
+    Napot,
+}
+
+impl PmpMode {
+    fn name(self) -> &'static str {
+        match self {
+            PmpMode::Tor => "TOR",
+            PmpMode::Na4 => "NA4",
+            PmpMode::Napot => "NAPOT",
+        }
+    }
+}
+
+/// A PMP entry that's turned on, with the addresses it covers
+#[derive(Clone, Debug, PartialEq)]
+pub struct PmpRegion {
+    pub index: usize,
+    pub mode: PmpMode,
+    pub start: u64,
+
+    /// The first address past the region, which for RV32 may be past 4 GiB
+    pub end: u64,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+
+    /// Whether the entry applies to M-mode too, and can't be changed
+    pub locked: bool,
+}
+
+impl PmpRegion {
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    fn permissions(&self) -> String {
+        format!(
+            "{}{}{}",
+            if self.read { 'r' } else { '-' },
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' }
+        )
+    }
+}
+
+impl fmt::Display for PmpRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pmp{:<2} {:<5} 0x{:09x}-0x{:09x} {}{}",
+            self.index,
+            self.mode.name(),
+            self.start,
+            self.end.saturating_sub(1),
+            self.permissions(),
+            if self.locked { " locked" } else { "" }
+        )
+    }
+}
+
+/// Work out the regions from each entry's config byte, as taken out of
+/// the config registers, and the sixteen address registers.  Entries that
+/// are off, and TOR entries whose top is below their bottom, aren't
+/// returned.
+pub fn decode(cfg: &[u8; ENTRIES], addr: &[u64; ENTRIES]) -> Vec<PmpRegion> {
+    let mut regions = vec![];
+    for index in 0..ENTRIES {
+        let entry_cfg = cfg[index];
+        let pmpaddr = addr[index];
+        let (mode, start, end) = match (entry_cfg >> CFG_A_SHIFT) & 3 {
+            1 => {
+                let bottom = match index {
+                    0 => 0,
+                    _ => addr[index - 1] << 2,
+                };
+                (PmpMode::Tor, bottom, pmpaddr << 2)
+            }
+            2 => (PmpMode::Na4, pmpaddr << 2, (pmpaddr << 2) + 4),
+            3 => {
+                // The ones at the bottom give the size, with each one
+                // doubling it from eight bytes.  No real pmpaddr has more
+                // than 54 bits, so capping them keeps this from overflowing.
+                let ones = pmpaddr.trailing_ones().min(60) as u64;
+                let start = (pmpaddr & !((1 << ones) - 1)) << 2;
+                (PmpMode::Napot, start, start.saturating_add(8 << ones))
+            }
+            _ => continue,
+        };
+        if start >= end {
+            continue;
+        }
+        regions.push(PmpRegion {
+            index,
+            mode,
+            start,
+            end,
+            read: entry_cfg & CFG_R != 0,
+            write: entry_cfg & CFG_W != 0,
+            execute: entry_cfg & CFG_X != 0,
+            locked: entry_cfg & CFG_L != 0,
+        });
+    }
+    regions
+}
+
+/// The entry that decides accesses to `addr`, if any does
+pub fn matching(regions: &[PmpRegion], addr: u64) -> Option<&PmpRegion> {
+    regions.iter().find(|r| r.contains(addr))
+}
+
+/// Say what may be done to `addr`, and which entry says so
+pub fn explain(regions: &[PmpRegion], addr: u64) -> String {
+    match matching(regions, addr) {
+        Some(region) if region.locked => format!(
+            "0x{:08x} is in {}, so every mode may only do {}\n",
+            addr,
+            region,
+            region.permissions()
+        ),
+        Some(region) => format!(
+            "0x{:08x} is in {}, so U- and S-mode may only do {}, and M-mode may do anything\n",
+            addr,
+            region,
+            region.permissions()
+        ),
+        None if regions.is_empty() => {
+            format!("No PMP entries are on, so 0x{:08x} isn't protected\n", addr)
+        }
+        None => format!(
+            "0x{:08x} isn't in any PMP entry, so only M-mode may get at it\n",
+            addr
+        ),
+    }
+}
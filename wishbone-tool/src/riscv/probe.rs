@@ -0,0 +1,103 @@
+//! What was learnt about a CPU the last time we attached to it, kept in
+//! `~/.cache/wishbone-tool` and keyed by the SoC identifier, so attaching
+//! to the same gateware again can skip probing for optional CSRs.
+
+use log::debug;
+
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuProbe {
+    /// Whether `satp` exists, and so whether there's an MMU
+    pub has_mmu: bool,
+
+    /// The CSRs that turned out to be there, if they were looked for
+    pub csrs: Option<Vec<u32>>,
+
+    /// How wide the FPU is, 0 if there isn't one, if it was looked for
+    pub flen: Option<u32>,
+
+    /// The extension bits of `misa`, 0 if it didn't say, if it was read
+    pub extensions: Option<u32>,
+}
+
+/// The file that describes the CPU behind `key`, if there's a home
+/// directory to keep it in.
+fn cache_file(key: &str) -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+
+    // FNV-1a, which is plenty to tell gateware apart
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    Some(
+        base.join("wishbone-tool")
+            .join(format!("{:016x}.probe", hash)),
+    )
+}
+
+impl CpuProbe {
+    pub fn load(key: &str) -> Option<CpuProbe> {
+        let text = fs::read_to_string(cache_file(key)?).ok()?;
+        let mut has_mmu = None;
+        let mut csrs = None;
+        let mut flen = None;
+        let mut extensions = None;
+        for line in text.lines() {
+            match line.split_once('=') {
+                // Don't trust a cache left by different gateware that
+                // happened to collide
+                Some(("key", k)) if k != key => return None,
+                Some(("has_mmu", v)) => has_mmu = Some(v == "true"),
+                Some(("flen", v)) => flen = v.parse().ok(),
+                Some(("extensions", v)) => extensions = u32::from_str_radix(v, 16).ok(),
+                Some(("csrs", v)) => {
+                    csrs = v
+                        .split(',')
+                        .filter(|c| !c.is_empty())
+                        .map(|c| u32::from_str_radix(c, 16).ok())
+                        .collect()
+                }
+                _ => (),
+            }
+        }
+        Some(CpuProbe {
+            has_mmu: has_mmu?,
+            csrs,
+            flen,
+            extensions,
+        })
+    }
+
+    /// Remember this probe.  A cache that can't be written just means
+    /// probing again next time, so failures are only logged.
+    pub fn save(&self, key: &str) {
+        let file = match cache_file(key) {
+            Some(f) => f,
+            None => return,
+        };
+        let mut text = format!("key={}\nhas_mmu={}\n", key, self.has_mmu);
+        if let Some(csrs) = &self.csrs {
+            let csrs: Vec<String> = csrs.iter().map(|c| format!("{:03x}", c)).collect();
+            text.push_str(&format!("csrs={}\n", csrs.join(",")));
+        }
+        if let Some(flen) = self.flen {
+            text.push_str(&format!("flen={}\n", flen));
+        }
+        if let Some(extensions) = self.extensions {
+            text.push_str(&format!("extensions={:07x}\n", extensions));
+        }
+        let result = file
+            .parent()
+            .map(fs::create_dir_all)
+            .unwrap_or(Ok(()))
+            .and_then(|_| fs::write(&file, text));
+        if let Err(e) = result {
+            debug!("couldn't save the CPU probe to {}: {}", file.display(), e);
+        }
+    }
+}
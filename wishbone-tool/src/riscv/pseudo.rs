@@ -0,0 +1,216 @@
+//! Pseudo-registers: values worked out from real registers whenever GDB
+//! reads them, such as `mcycle / (clock / 1000000)` to get microseconds.
+//! They're presented to GDB alongside the real registers, so they appear
+//! in `info registers`, but they can't be written.
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Number(u64),
+    Register(String),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PseudoRegister {
+    pub name: String,
+    expr: Expr,
+}
+
+/// Binary operators, loosest first.  `<` and `>` stand for `<<` and `>>`.
+const PRECEDENCE: [&str; 6] = ["|", "^", "&", "<>", "+-", "*/%"];
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    clock: Option<u64>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_space(&mut self) {
+        while let Some(c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_alphanumeric() && c != '_' {
+                break;
+            }
+            word.push(c);
+            self.chars.next();
+        }
+        word
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        self.skip_space();
+        match self.chars.peek() {
+            Some('(') => {
+                self.chars.next();
+                let expr = self.expr()?;
+                self.skip_space();
+                if self.chars.next() != Some(')') {
+                    return Err("missing ')'".to_owned());
+                }
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let word = self.word();
+                let value = if let Some(hex) = word.strip_prefix("0x") {
+                    u64::from_str_radix(hex, 16).ok()
+                } else if let Some(bin) = word.strip_prefix("0b") {
+                    u64::from_str_radix(bin, 2).ok()
+                } else {
+                    // Decimal numbers may be scaled by k, M or G
+                    let (digits, scale) = match word.char_indices().last() {
+                        Some((idx, 'k')) => (&word[..idx], 1_000),
+                        Some((idx, 'M')) => (&word[..idx], 1_000_000),
+                        Some((idx, 'G')) => (&word[..idx], 1_000_000_000),
+                        _ => (word.as_str(), 1),
+                    };
+                    digits.parse::<u64>().ok().map(|n| n * scale)
+                };
+                value
+                    .map(Expr::Number)
+                    .ok_or_else(|| format!("bad number {}", word))
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => match self.word().as_str() {
+                "clock" => match self.clock {
+                    Some(hz) => Ok(Expr::Number(hz)),
+                    None => Err(
+                        "clock isn't known -- give a --csr-csv with config_clock_frequency, or --clock-frequency"
+                            .to_owned(),
+                    ),
+                },
+                name => Ok(Expr::Register(name.to_owned())),
+            },
+            Some(c) => Err(format!("unexpected '{}'", c)),
+            None => Err("expression ends early".to_owned()),
+        }
+    }
+
+    /// The operators at `level` of `PRECEDENCE` and tighter.  Shifts are
+    /// doubled characters, but are kept as just the first.
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.factor();
+        }
+        let mut expr = self.binary(level + 1)?;
+        loop {
+            self.skip_space();
+            match self.chars.peek() {
+                Some(&op) if PRECEDENCE[level].contains(op) => {
+                    self.chars.next();
+                    if (op == '<' || op == '>') && self.chars.next() != Some(op) {
+                        return Err(format!("'{}' should be '{}{}'", op, op, op));
+                    }
+                    expr = Expr::Binary(Box::new(expr), op, Box::new(self.binary(level + 1)?));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.binary(0)
+    }
+}
+
+impl Expr {
+    fn registers<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => (),
+            Expr::Register(name) => names.push(name),
+            Expr::Binary(left, _, right) => {
+                left.registers(names);
+                right.registers(names);
+            }
+        }
+    }
+
+    fn eval<E>(&self, read: &mut dyn FnMut(&str) -> Result<u64, E>) -> Result<u64, E> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Register(name) => read(name)?,
+            Expr::Binary(left, op, right) => {
+                let left = left.eval(read)?;
+                let right = right.eval(read)?;
+                match op {
+                    '+' => left.wrapping_add(right),
+                    '-' => left.wrapping_sub(right),
+                    '*' => left.wrapping_mul(right),
+                    '/' => left.checked_div(right).unwrap_or(0),
+                    '&' => left & right,
+                    '|' => left | right,
+                    '^' => left ^ right,
+                    '<' => left.checked_shl(right as u32).unwrap_or(0),
+                    '>' => left.checked_shr(right as u32).unwrap_or(0),
+                    _ => left.checked_rem(right).unwrap_or(0),
+                }
+            }
+        })
+    }
+}
+
+impl PseudoRegister {
+    /// Parse `NAME=EXPRESSION`, where the expression may use `+ - * / %`,
+    /// `& | ^ << >>`, parentheses, numbers, register names, and `clock`
+    /// for the CPU clock in Hz.  Numbers may be hex with `0x`, binary with
+    /// `0b`, or decimal with a `k`, `M` or `G` to scale them.  The
+    /// operators bind as tightly as they do in C.  Dividing by zero gives
+    /// zero.
+    pub fn from_string(spec: &str, clock: Option<u64>) -> Result<PseudoRegister, String> {
+        let mut parts = spec.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let text = match parts.next() {
+            Some(e) => e,
+            None => return Err(format!("{} should be NAME=EXPRESSION", spec)),
+        };
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("bad pseudo-register name '{}'", name));
+        }
+        let mut parser = Parser {
+            chars: text.chars().peekable(),
+            clock,
+        };
+        let expr = parser.expr()?;
+        parser.skip_space();
+        if let Some(c) = parser.chars.next() {
+            return Err(format!("unexpected '{}' in {}", c, text));
+        }
+        Ok(PseudoRegister {
+            name: name.to_owned(),
+            expr,
+        })
+    }
+
+    /// Names of the registers this one is worked out from
+    pub fn sources(&self) -> Vec<&str> {
+        let mut names = vec![];
+        self.expr.registers(&mut names);
+        names
+    }
+
+    /// Work out the value, reading registers with `read`
+    pub fn value<E>(&self, read: &mut dyn FnMut(&str) -> Result<u32, E>) -> Result<u32, E> {
+        Ok(self.expr.eval(&mut |name: &str| read(name).map(|v| v as u64))? as u32)
+    }
+
+    /// Work out the value at 64 bits, for registers that are wider than
+    /// the 32 bits GDB sees pseudo-registers as
+    pub fn value_wide<E>(&self, read: &mut dyn FnMut(&str) -> Result<u64, E>) -> Result<u64, E> {
+        self.expr.eval(read)
+    }
+}
+
+/// A value in hex, decimal and binary at once, for `calc`
+pub fn describe(value: u64) -> String {
+    format!("0x{:x} = {} = 0b{:b}", value, value, value)
+}
@@ -0,0 +1,152 @@
+//! A record of the last few single steps, so that GDB can step and
+//! continue backwards through them.  Before each step, the integer
+//! registers and pc are saved, along with whatever memory the instruction
+//! is about to store to, which is found by decoding it.  Going back a step
+//! puts all of that back as it was.
+//!
+//! Only what a step does to the integer registers and memory can be
+//! undone: CSRs, FPRs and anything a device does on its own are left as
+//! they are.  Continuing forwards can't be recorded, so it throws the
+//! record away.
+
+use super::{disasm, RiscvSystem, RiscvCpuError, Xlen};
+use crate::bridge::Bridge;
+
+use std::collections::VecDeque;
+
+/// x0-x31 and pc, by GDB register number
+const REGISTERS: u32 = 33;
+
+struct Snapshot {
+    registers: Vec<u64>,
+
+    /// Each word the step was about to store over, and what it held
+    memory: Vec<(u32, u32)>,
+}
+
+pub struct Recorder {
+    limit: usize,
+    history: VecDeque<Snapshot>,
+}
+
+/// Sign-extend the bottom `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as i64
+}
+
+/// The address and size of whatever `insn` stores to, if it's a store
+fn store_target(insn: u32, registers: &[u64], xlen: Xlen) -> Option<(u64, u32)> {
+    let reg = |n: u32| registers[n as usize];
+    if insn & 3 != 3 {
+        // Compressed: rs1' names x8-x15, and the offsets are unsigned
+        let funct3 = (insn >> 13) & 7;
+        let rs1c = 8 + ((insn >> 7) & 7);
+        let word_offset = ((insn >> 10) & 7) << 3 | ((insn >> 6) & 1) << 2 | ((insn >> 5) & 1) << 6;
+        let double_offset = ((insn >> 10) & 7) << 3 | ((insn >> 5) & 3) << 6;
+        let sp_word_offset = ((insn >> 9) & 0xf) << 2 | ((insn >> 7) & 3) << 6;
+        let sp_double_offset = ((insn >> 10) & 7) << 3 | ((insn >> 7) & 7) << 6;
+        return match (insn & 3, funct3) {
+            // c.fsd
+            (0, 5) => Some((reg(rs1c).wrapping_add(double_offset as u64), 8)),
+            // c.sw
+            (0, 6) => Some((reg(rs1c).wrapping_add(word_offset as u64), 4)),
+            // c.fsw on RV32, c.sd otherwise
+            (0, 7) if xlen == Xlen::Rv32 => Some((reg(rs1c).wrapping_add(word_offset as u64), 4)),
+            (0, 7) => Some((reg(rs1c).wrapping_add(double_offset as u64), 8)),
+            // c.fsdsp
+            (2, 5) => Some((reg(2).wrapping_add(sp_double_offset as u64), 8)),
+            // c.swsp
+            (2, 6) => Some((reg(2).wrapping_add(sp_word_offset as u64), 4)),
+            // c.fswsp on RV32, c.sdsp otherwise
+            (2, 7) if xlen == Xlen::Rv32 => Some((reg(2).wrapping_add(sp_word_offset as u64), 4)),
+            (2, 7) => Some((reg(2).wrapping_add(sp_double_offset as u64), 8)),
+            _ => None,
+        };
+    }
+
+    let funct3 = (insn >> 12) & 7;
+    let rs1 = (insn >> 15) & 0x1f;
+    let store_offset = sign_extend((insn >> 25) << 5 | ((insn >> 7) & 0x1f), 12);
+    match insn & 0x7f {
+        // sb, sh, sw, sd, and fsw, fsd
+        0x23 | 0x27 if funct3 <= 3 => Some((
+            (reg(rs1) as i64).wrapping_add(store_offset) as u64,
+            1 << funct3,
+        )),
+        // The AMOs, which all store to rs1 except for lr, which only loads
+        0x2f if (funct3 == 2 || funct3 == 3) && insn >> 27 != 0b00010 => {
+            Some((reg(rs1), 1 << funct3))
+        }
+        _ => None,
+    }
+}
+
+impl Recorder {
+    /// Keep the last `limit` steps
+    pub fn new(limit: usize) -> Recorder {
+        Recorder {
+            limit,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    /// Save what the step about to be taken will change
+    pub fn record(&mut self, cpu: &RiscvSystem, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let mut registers = Vec::with_capacity(REGISTERS as usize);
+        for idx in 0..REGISTERS {
+            registers.push(cpu.read_register_wide(bridge, idx)?);
+        }
+        let pc = registers[32] as u32;
+        let mut memory = vec![];
+        if let Some((addr, size)) = store_target(
+            disasm::fetch(pc, |addr| cpu.read_memory(bridge, addr, 4))?,
+            &registers,
+            cpu.xlen(),
+        ) {
+            // Whole words are saved, so a misaligned store is covered too
+            let addr = addr as u32;
+            let mut word = addr & !3;
+            while word < addr.wrapping_add(size) {
+                memory.push((word, cpu.read_memory(bridge, word, 4)?));
+                word = word.wrapping_add(4);
+                if word == 0 {
+                    break;
+                }
+            }
+        }
+        if self.history.len() >= self.limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(Snapshot { registers, memory });
+        Ok(())
+    }
+
+    /// Undo the last step, giving where the CPU is now, or None if there's
+    /// nothing left to undo
+    pub fn rewind(&mut self, cpu: &RiscvSystem, bridge: &Bridge) -> Result<Option<u64>, RiscvCpuError> {
+        let snapshot = match self.history.pop_back() {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        for (addr, value) in &snapshot.memory {
+            cpu.write_memory(bridge, *addr, 4, *value)?;
+        }
+        // x0 can't be written
+        for (idx, value) in snapshot.registers.iter().enumerate().skip(1) {
+            cpu.write_register_wide(bridge, idx as u32, *value)?;
+        }
+        Ok(Some(snapshot.registers[32]))
+    }
+}
@@ -0,0 +1,546 @@
+//! Semihosting, where firmware asks the debugger to do things for it such
+//! as printing to the console or reading a file from the host.  RISC-V
+//! borrows ARM's calls, and marks them with an `ebreak` between two
+//! instructions that do nothing:
+//!
+//! ```text
+//!   slli x0, x0, 0x1f
+//!   ebreak
+//!   srai x0, x0, 7
+//! ```
+//!
+//! The call number is in `a0`, and `a1` points to a block of `xlen`-sized
+//! arguments, or is the argument itself for the simplest calls.  The
+//! result goes back in `a0`, and the CPU carries on after the `ebreak`.
+//!
+//! Files are opened relative to the directory wishbone-tool was started in,
+//! and firmware can't get out of it.  The special name `:tt` is the
+//! console.
+//!
+//! With --gdb-fileio, calls on files and the console go to GDB instead as
+//! File-I/O requests, so the files are the ones on GDB's side and output
+//! shows up in GDB.  The CPU stays halted while GDB carries out the call,
+//! reading and writing target memory itself, and carries on once GDB's
+//! `F` reply has been turned into the result.  Handles are then GDB's
+//! file descriptors, of which 0, 1 and 2 are its console.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::regions;
+
+use log::{info, warn};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const SLLI_X0_X0_0X1F: u32 = 0x01f0_1013;
+const EBREAK: u32 = 0x0010_0073;
+const SRAI_X0_X0_7: u32 = 0x4070_5013;
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_READC: u64 = 0x07;
+const SYS_ISERROR: u64 = 0x08;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_REMOVE: u64 = 0x0e;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+/// The reason `SYS_EXIT` gives when the program finished normally
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// The handles that firmware starts out with
+const STDIN: u64 = 0;
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+/// The longest string or buffer that's taken from the target at once
+const MAX_TRANSFER: u64 = 64 * 1024;
+
+/// The `open()` flags File-I/O uses, whatever the host's are
+const GDB_O_RDONLY: u32 = 0x0;
+const GDB_O_WRONLY: u32 = 0x1;
+const GDB_O_RDWR: u32 = 0x2;
+const GDB_O_APPEND: u32 = 0x8;
+const GDB_O_CREAT: u32 = 0x200;
+const GDB_O_TRUNC: u32 = 0x400;
+
+/// The mode files are created with over File-I/O, 0644
+const GDB_CREATE_MODE: u32 = 0o644;
+
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EINVAL: i32 = 22;
+
+/// Whether the three words at `pc - 4` are the semihosting sequence, with
+/// the `ebreak` at `pc`
+pub fn is_call(bridge: &Bridge, pc: u32) -> Result<bool, BridgeError> {
+    if pc < 4 {
+        return Ok(false);
+    }
+    let code = regions::read_bytes(pc - 4, 12, |a| bridge.peek(a))?;
+    let word = |i: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&code[i * 4..i * 4 + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    Ok(word(0) == SLLI_X0_X0_0X1F && word(1) == EBREAK && word(2) == SRAI_X0_X0_7)
+}
+
+/// What the CPU should do once a call has been looked after
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// Put this in `a0` and carry on
+    Return(u64),
+
+    /// The firmware has finished, with this status
+    Exit(u64),
+}
+
+/// A call turned into a File-I/O request, or carried out here if GDB
+/// isn't needed for it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Done(Outcome),
+
+    /// Send this to GDB in place of a stop reply
+    Gdb(String),
+}
+
+/// How GDB's return code for a request becomes the call's result
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    /// The return code as it is
+    Plain,
+
+    /// How many of this many bytes weren't written or read
+    Remaining(u64),
+
+    /// 0, rather than the new offset
+    Seek,
+
+    /// The call's own argument, whatever happened
+    Argument(u64),
+}
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    /// Open files, by handle
+    handles: HashMap<u64, Handle>,
+    next_handle: u64,
+
+    /// The error of the last call that failed, for `SYS_ERRNO`
+    errno: i32,
+
+    /// When `SYS_CLOCK` counts from
+    started: Instant,
+
+    /// How many bytes an argument takes up
+    word_size: u32,
+
+    /// Send calls to GDB with File-I/O
+    file_io: bool,
+
+    /// The request GDB is carrying out
+    pending: Option<Pending>,
+}
+
+impl Semihosting {
+    pub fn new(word_size: u32) -> Semihosting {
+        let mut handles = HashMap::new();
+        handles.insert(STDIN, Handle::Stdin);
+        handles.insert(STDOUT, Handle::Stdout);
+        handles.insert(STDERR, Handle::Stderr);
+        Semihosting {
+            handles,
+            next_handle: STDERR + 1,
+            errno: 0,
+            started: Instant::now(),
+            word_size,
+            file_io: false,
+            pending: None,
+        }
+    }
+
+    pub fn set_file_io(&mut self, on: bool) {
+        self.file_io = on;
+    }
+
+    pub fn file_io(&self) -> bool {
+        self.file_io
+    }
+
+    /// Turn call `op` into a File-I/O request for GDB, or carry it out
+    /// here if File-I/O is off or the call has nothing to do with files.
+    /// A file can't have a length over File-I/O without moving its offset,
+    /// so `SYS_FLEN` fails there.
+    pub fn request(&mut self, bridge: &Bridge, op: u64, param: u64) -> Result<Request, BridgeError> {
+        if !self.file_io {
+            return Ok(Request::Done(self.call(bridge, op, param)?));
+        }
+        let arg = |n: u64| self.argument(bridge, param, n);
+        let (request, pending) = match op {
+            SYS_OPEN => {
+                let (name, mode, len) = (arg(0)?, arg(1)?, arg(2)?);
+                if mode > 11 {
+                    return Ok(self.refuse(EINVAL));
+                }
+                // GDB's console is already open
+                if self.read_string(bridge, name, len)? == ":tt" {
+                    let handle = match mode / 4 {
+                        0 => STDIN,
+                        1 => STDOUT,
+                        _ => STDERR,
+                    };
+                    return Ok(Request::Done(Outcome::Return(handle)));
+                }
+                // The length GDB is given includes the NUL
+                let request = format!("open,{:x}/{:x},{:x},{:x}", name, len + 1, open_flags(mode), GDB_CREATE_MODE);
+                (request, Pending::Plain)
+            }
+            SYS_CLOSE => (format!("close,{:x}", arg(0)?), Pending::Plain),
+            SYS_WRITEC => (format!("write,{:x},{:x},1", STDOUT, param), Pending::Argument(param)),
+            SYS_WRITE0 => {
+                let len = self.read_cstring(bridge, param)?.len();
+                (format!("write,{:x},{:x},{:x}", STDOUT, param, len), Pending::Argument(param))
+            }
+            SYS_WRITE | SYS_READ => {
+                let (handle, buffer, len) = (arg(0)?, arg(1)?, arg(2)?.min(MAX_TRANSFER));
+                let name = if op == SYS_WRITE { "write" } else { "read" };
+                (format!("{},{:x},{:x},{:x}", name, handle, buffer, len), Pending::Remaining(len))
+            }
+            SYS_ISTTY => (format!("isatty,{:x}", arg(0)?), Pending::Plain),
+            SYS_SEEK => (format!("lseek,{:x},{:x},0", arg(0)?, arg(1)?), Pending::Seek),
+            SYS_FLEN => return Ok(self.refuse(EINVAL)),
+            SYS_REMOVE => (format!("unlink,{:x}/{:x}", arg(0)?, arg(1)? + 1), Pending::Plain),
+            _ => return Ok(Request::Done(self.call(bridge, op, param)?)),
+        };
+        self.pending = Some(pending);
+        Ok(Request::Gdb(format!("F{}", request)))
+    }
+
+    /// The result of the request GDB was carrying out, from the return
+    /// code and errno of its `F` reply, or None if there wasn't one
+    pub fn finish(&mut self, retcode: i64, errno: i32) -> Option<u64> {
+        let pending = self.pending.take()?;
+        if retcode < 0 {
+            self.errno = errno;
+        }
+        let result = match (pending, retcode) {
+            (Pending::Argument(param), _) => param,
+            (Pending::Remaining(len), r) if r < 0 => len,
+            (Pending::Remaining(len), r) => len - (r as u64).min(len),
+            (_, r) if r < 0 => u64::MAX,
+            (Pending::Seek, _) => 0,
+            (Pending::Plain, r) => r as u64,
+        };
+        Some(self.result(result))
+    }
+
+    /// Carry out call `op`, where `param` is what was in `a1`
+    pub fn call(&mut self, bridge: &Bridge, op: u64, param: u64) -> Result<Outcome, BridgeError> {
+        let arg = |n: u64| self.argument(bridge, param, n);
+        let result = match op {
+            SYS_OPEN => {
+                let name = self.read_string(bridge, arg(0)?, arg(2)?)?;
+                let mode = arg(1)?;
+                self.open(&name, mode)
+            }
+            SYS_CLOSE => match self.handles.remove(&arg(0)?) {
+                Some(_) => 0,
+                None => self.fail(EBADF),
+            },
+            SYS_WRITEC => {
+                let c = regions::read_bytes(param as u32, 1, |a| bridge.peek(a))?;
+                print_console(&c);
+                return Ok(Outcome::Return(param));
+            }
+            SYS_WRITE0 => {
+                print_console(&self.read_cstring(bridge, param)?);
+                return Ok(Outcome::Return(param));
+            }
+            SYS_WRITE => {
+                let (handle, buffer, len) = (arg(0)?, arg(1)?, arg(2)?.min(MAX_TRANSFER));
+                let data = regions::read_bytes(buffer as u32, len as u32, |a| bridge.peek(a))?;
+                match self.write(handle, &data) {
+                    Ok(written) => len - written as u64,
+                    Err(e) => {
+                        self.fail(e);
+                        len
+                    }
+                }
+            }
+            SYS_READ => {
+                let (handle, buffer, len) = (arg(0)?, arg(1)?, arg(2)?.min(MAX_TRANSFER));
+                match self.read(handle, len as usize) {
+                    Ok(data) => {
+                        regions::write_bytes(
+                            buffer as u32,
+                            &data,
+                            |a| bridge.peek(a),
+                            |a, v| bridge.poke(a, v),
+                        )?;
+                        len - data.len() as u64
+                    }
+                    Err(e) => {
+                        self.fail(e);
+                        len
+                    }
+                }
+            }
+            SYS_READC => match self.read(STDIN, 1) {
+                Ok(c) if !c.is_empty() => c[0] as u64,
+                _ => self.fail(EBADF),
+            },
+            SYS_ISERROR => {
+                let status = arg(0)?;
+                let negative = match self.word_size {
+                    4 => (status as i32) < 0,
+                    _ => (status as i64) < 0,
+                };
+                negative as u64
+            }
+            SYS_ISTTY => match self.handles.get(&arg(0)?) {
+                Some(Handle::File(_)) => 0,
+                Some(_) => 1,
+                None => self.fail(EBADF),
+            },
+            SYS_SEEK => {
+                let (handle, pos) = (arg(0)?, arg(1)?);
+                match self.handles.get_mut(&handle) {
+                    Some(Handle::File(f)) => match f.seek(SeekFrom::Start(pos)) {
+                        Ok(_) => 0,
+                        Err(e) => self.fail(errno(&e)),
+                    },
+                    _ => self.fail(EBADF),
+                }
+            }
+            SYS_FLEN => match self.handles.get(&arg(0)?) {
+                Some(Handle::File(f)) => match f.metadata() {
+                    Ok(m) => m.len(),
+                    Err(e) => self.fail(errno(&e)),
+                },
+                _ => self.fail(EBADF),
+            },
+            SYS_REMOVE => {
+                let name = self.read_string(bridge, arg(0)?, arg(1)?)?;
+                match host_path(&name) {
+                    Some(path) => match std::fs::remove_file(path) {
+                        Ok(()) => 0,
+                        Err(e) => self.fail(errno(&e)),
+                    },
+                    None => self.fail(EACCES),
+                }
+            }
+            SYS_CLOCK => (self.started.elapsed().as_millis() / 10) as u64,
+            SYS_TIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            SYS_ERRNO => self.errno as u64,
+            // On 32-bit targets the reason is the argument, but 64-bit ones
+            // pass a block with the status after it
+            SYS_EXIT if self.word_size == 4 => {
+                return Ok(Outcome::Exit(match param {
+                    ADP_STOPPED_APPLICATION_EXIT => 0,
+                    reason => reason,
+                }))
+            }
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                return Ok(Outcome::Exit(match arg(0)? {
+                    ADP_STOPPED_APPLICATION_EXIT => arg(1)?,
+                    reason => reason,
+                }))
+            }
+            op => {
+                warn!("firmware made semihosting call {:#x}, which isn't supported", op);
+                self.fail(EINVAL)
+            }
+        };
+        Ok(Outcome::Return(self.result(result)))
+    }
+
+    /// Fail a call without asking GDB
+    fn refuse(&mut self, errno: i32) -> Request {
+        let failed = self.fail(errno);
+        Request::Done(Outcome::Return(self.result(failed)))
+    }
+
+    /// Results are as wide as a register
+    fn result(&self, value: u64) -> u64 {
+        match self.word_size {
+            4 => value & 0xffff_ffff,
+            _ => value,
+        }
+    }
+
+    fn argument(&self, bridge: &Bridge, block: u64, n: u64) -> Result<u64, BridgeError> {
+        let addr = block.wrapping_add(n * self.word_size as u64) as u32;
+        let data = regions::read_bytes(addr, self.word_size, |a| bridge.peek(a))?;
+        let mut bytes = [0; 8];
+        bytes[..data.len()].copy_from_slice(&data);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_string(&self, bridge: &Bridge, addr: u64, len: u64) -> Result<String, BridgeError> {
+        let data = regions::read_bytes(addr as u32, len.min(MAX_TRANSFER) as u32, |a| {
+            bridge.peek(a)
+        })?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// The NUL-terminated string at `addr`, without the NUL
+    fn read_cstring(&self, bridge: &Bridge, addr: u64) -> Result<Vec<u8>, BridgeError> {
+        let mut text = vec![];
+        let mut addr = addr as u32;
+        while (text.len() as u64) < MAX_TRANSFER {
+            let word = regions::read_bytes(addr, 4, |a| bridge.peek(a))?;
+            match word.iter().position(|&c| c == 0) {
+                Some(end) => {
+                    text.extend_from_slice(&word[..end]);
+                    break;
+                }
+                None => text.extend_from_slice(&word),
+            }
+            addr = addr.wrapping_add(4);
+        }
+        Ok(text)
+    }
+
+    /// Note the error, and give what calls return when they fail
+    fn fail(&mut self, errno: i32) -> u64 {
+        self.errno = errno;
+        u64::MAX
+    }
+
+    /// Open a file with one of the `fopen()` modes, as numbered from "r"
+    /// through to "a+b"
+    fn open(&mut self, name: &str, mode: u64) -> u64 {
+        if mode > 11 {
+            return self.fail(EINVAL);
+        }
+        let handle = if name == ":tt" {
+            match mode / 4 {
+                0 => Handle::Stdin,
+                1 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let path = match host_path(name) {
+                Some(path) => path,
+                None => {
+                    warn!("firmware tried to open {}, which is outside the current directory", name);
+                    return self.fail(EACCES);
+                }
+            };
+            let mut options = OpenOptions::new();
+            match mode / 4 {
+                0 => options.read(true),
+                1 => options.write(true).create(true).truncate(true),
+                _ => options.append(true).create(true),
+            };
+            if mode & 2 != 0 {
+                options.read(true).write(true);
+            }
+            match options.open(path) {
+                Ok(file) => Handle::File(file),
+                Err(e) => return self.fail(errno(&e)),
+            }
+        };
+        let number = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(number, handle);
+        info!("firmware opened {} as handle {}", name, number);
+        number
+    }
+
+    fn write(&mut self, handle: u64, data: &[u8]) -> Result<usize, i32> {
+        match self.handles.get_mut(&handle) {
+            Some(Handle::Stdout) => {
+                print_console(data);
+                Ok(data.len())
+            }
+            Some(Handle::Stderr) => {
+                let mut stderr = io::stderr();
+                stderr.write_all(data).map_err(|e| errno(&e))?;
+                Ok(data.len())
+            }
+            Some(Handle::File(f)) => f.write(data).map_err(|e| errno(&e)),
+            _ => Err(EBADF),
+        }
+    }
+
+    fn read(&mut self, handle: u64, len: usize) -> Result<Vec<u8>, i32> {
+        let mut data = vec![0; len];
+        let count = match self.handles.get_mut(&handle) {
+            Some(Handle::Stdin) => io::stdin().read(&mut data),
+            Some(Handle::File(f)) => f.read(&mut data),
+            _ => return Err(EBADF),
+        }
+        .map_err(|e| errno(&e))?;
+        data.truncate(count);
+        Ok(data)
+    }
+}
+
+/// The File-I/O flags for one of the `fopen()` modes
+fn open_flags(mode: u64) -> u32 {
+    let access = if mode & 2 != 0 {
+        GDB_O_RDWR
+    } else if mode / 4 == 0 {
+        GDB_O_RDONLY
+    } else {
+        GDB_O_WRONLY
+    };
+    access
+        | match mode / 4 {
+            0 => 0,
+            1 => GDB_O_CREAT | GDB_O_TRUNC,
+            _ => GDB_O_CREAT | GDB_O_APPEND,
+        }
+}
+
+fn print_console(data: &[u8]) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(data);
+    let _ = stdout.flush();
+}
+
+/// Where `name` is on the host, as long as it's under the current directory
+fn host_path(name: &str) -> Option<&Path> {
+    let path = Path::new(name);
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn errno(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        _ => e.raw_os_error().unwrap_or(EINVAL),
+    }
+}
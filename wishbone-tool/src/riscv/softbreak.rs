@@ -0,0 +1,184 @@
+//! Software breakpoints, made by patching an `ebreak` over the instruction
+//! at the breakpoint and putting the original back when it's removed.
+//! Compressed instructions get a `c.ebreak`, so that the patch never
+//! spills over into the next instruction.
+//!
+//! Everything here works on physical addresses, and goes a halfword at a
+//! time, since a 32-bit instruction only has to be 16-bit aligned once
+//! compressed instructions are in use.  A CPU whose `misa` says it has no
+//! C extension only gets `ebreak`s, on word boundaries.
+
+use super::{instruction_length, RiscvCpuController, RiscvCpuError};
+use crate::bridge::Bridge;
+
+use log::debug;
+
+use std::collections::HashMap;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+/// What was at the address before it was patched
+struct Patch {
+    original: u32,
+    compressed: bool,
+}
+
+pub struct SoftBreakpoints {
+    patches: HashMap<u32, Patch>,
+}
+
+impl SoftBreakpoints {
+    pub fn new() -> SoftBreakpoints {
+        SoftBreakpoints {
+            patches: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        self.patches.contains_key(&addr)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    /// Patch an `ebreak` in at `addr`.  The patch is read back, so memory
+    /// that can't be written, such as ROM or flash, gives
+    /// `BreakpointNotWritable` rather than a breakpoint that never fires.
+    pub fn insert(
+        &mut self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        addr: u32,
+    ) -> Result<(), RiscvCpuError> {
+        if self.patches.contains_key(&addr) {
+            return Ok(());
+        }
+        let alignment = if controller.compressed() { 2 } else { 4 };
+        if !addr.is_multiple_of(alignment) {
+            return Err(RiscvCpuError::MisalignedBreakpoint(addr));
+        }
+        let low = controller.read_memory(bridge, addr, 2)?;
+        let compressed = controller.compressed() && instruction_length(low) == 2;
+        let original = if compressed {
+            low
+        } else {
+            low | (controller.read_memory(bridge, addr + 2, 2)? << 16)
+        };
+        let patch = Patch {
+            original,
+            compressed,
+        };
+        write_instruction(controller, bridge, addr, &patch, patch.breakpoint())?;
+        if read_instruction(controller, bridge, addr, &patch)? != patch.breakpoint() {
+            write_instruction(controller, bridge, addr, &patch, original)?;
+            return Err(RiscvCpuError::BreakpointNotWritable(addr));
+        }
+        controller.flush_cache(bridge)?;
+        debug!(
+            "patched {} over {:08x} at {:08x}",
+            if compressed { "c.ebreak" } else { "ebreak" },
+            original,
+            addr
+        );
+        self.patches.insert(addr, patch);
+        Ok(())
+    }
+
+    /// Put back the instruction that was at `addr`.
+    pub fn remove(
+        &mut self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        addr: u32,
+    ) -> Result<(), RiscvCpuError> {
+        self.unpatch(controller, bridge, addr)?;
+        self.patches.remove(&addr);
+        Ok(())
+    }
+
+    /// Put back every original instruction, forgetting the breakpoints.
+    pub fn clear(
+        &mut self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+    ) -> Result<(), RiscvCpuError> {
+        let addrs: Vec<u32> = self.patches.keys().cloned().collect();
+        for addr in addrs {
+            self.remove(controller, bridge, addr)?;
+        }
+        Ok(())
+    }
+
+    /// Put the original instruction back without forgetting the
+    /// breakpoint, so that it can be stepped over and then `repatch`ed.
+    pub fn unpatch(
+        &self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        addr: u32,
+    ) -> Result<(), RiscvCpuError> {
+        let patch = self
+            .patches
+            .get(&addr)
+            .ok_or(RiscvCpuError::BreakpointNotFound(addr))?;
+        write_instruction(controller, bridge, addr, patch, patch.original)?;
+        controller.flush_cache(bridge)
+    }
+
+    pub fn repatch(
+        &self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        addr: u32,
+    ) -> Result<(), RiscvCpuError> {
+        let patch = self
+            .patches
+            .get(&addr)
+            .ok_or(RiscvCpuError::BreakpointNotFound(addr))?;
+        write_instruction(controller, bridge, addr, patch, patch.breakpoint())?;
+        controller.flush_cache(bridge)
+    }
+}
+
+impl Patch {
+    fn breakpoint(&self) -> u32 {
+        if self.compressed {
+            C_EBREAK
+        } else {
+            EBREAK
+        }
+    }
+}
+
+fn read_instruction(
+    controller: &RiscvCpuController,
+    bridge: &Bridge,
+    addr: u32,
+    patch: &Patch,
+) -> Result<u32, RiscvCpuError> {
+    let low = controller.read_memory(bridge, addr, 2)?;
+    if patch.compressed {
+        return Ok(low);
+    }
+    Ok(low | (controller.read_memory(bridge, addr + 2, 2)? << 16))
+}
+
+fn write_instruction(
+    controller: &RiscvCpuController,
+    bridge: &Bridge,
+    addr: u32,
+    patch: &Patch,
+    opcode: u32,
+) -> Result<(), RiscvCpuError> {
+    controller.write_memory(bridge, addr, 2, opcode & 0xffff)?;
+    if !patch.compressed {
+        controller.write_memory(bridge, addr + 2, 2, opcode >> 16)?;
+    }
+    Ok(())
+}
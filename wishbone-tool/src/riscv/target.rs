@@ -0,0 +1,219 @@
+//! The target description GDB reads to find out what registers there are.
+//!
+//! Rather than one big `target.xml`, each feature goes in a document of
+//! its own, which `target.xml` pulls in with `<xi:include>`.  GDB fetches
+//! each one with `qXfer:features:read`, a piece at a time, so a CPU with
+//! a few hundred CSRs doesn't need one enormous transfer.  It also means
+//! that when CSR discovery or the FPU probe changes which registers are
+//! there, only the features that changed are built again.
+
+use super::pseudo::PseudoRegister;
+use super::{RegisterContentsType, RiscvRegister, RiscvRegisterType, Xlen};
+
+use std::collections::HashMap;
+
+/// The features a target description can have, in the order GDB is given them
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Feature {
+    Cpu,
+    Fpu,
+    Csr,
+    Pseudo,
+}
+
+const FEATURES: [Feature; 4] = [Feature::Cpu, Feature::Fpu, Feature::Csr, Feature::Pseudo];
+
+impl Feature {
+    fn of(register_type: &RiscvRegisterType) -> Feature {
+        match register_type {
+            RiscvRegisterType::General => Feature::Cpu,
+            RiscvRegisterType::Float => Feature::Fpu,
+            RiscvRegisterType::CSR => Feature::Csr,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The annex GDB asks for this feature by
+    fn annex(self) -> &'static str {
+        match self {
+            Feature::Cpu => "riscv-cpu.xml",
+            Feature::Fpu => "riscv-fpu.xml",
+            Feature::Csr => "riscv-csr.xml",
+            Feature::Pseudo => "pseudo.xml",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Feature::Cpu => "org.gnu.gdb.riscv.cpu",
+            Feature::Fpu => "org.gnu.gdb.riscv.fpu",
+            Feature::Csr => "org.gnu.gdb.riscv.csr",
+            Feature::Pseudo => "org.wishbone-tool.pseudo",
+        }
+    }
+
+    /// The register group for those that don't name one of their own
+    fn group(self) -> &'static str {
+        match self {
+            Feature::Cpu | Feature::Pseudo => "general",
+            Feature::Fpu => "float",
+            Feature::Csr => "csr",
+        }
+    }
+}
+
+pub struct TargetDescription {
+    xlen: Xlen,
+    flen: u32,
+
+    /// `target.xml` itself, which only says which features there are
+    target_xml: String,
+
+    /// Each feature's document, or None if it has no registers
+    features: [Option<String>; 4],
+
+    /// The features that have to be built again on the next `refresh()`
+    stale: [bool; 4],
+}
+
+impl TargetDescription {
+    /// A description with nothing in it yet, which `refresh()` fills in
+    pub fn new(xlen: Xlen) -> TargetDescription {
+        TargetDescription {
+            xlen,
+            flen: 0,
+            target_xml: String::new(),
+            features: [None, None, None, None],
+            stale: [true; 4],
+        }
+    }
+
+    /// Note that registers of this type have come or gone, or changed
+    pub fn invalidate(&mut self, register_type: &RiscvRegisterType) {
+        self.stale[Feature::of(register_type).index()] = true;
+    }
+
+    pub fn invalidate_pseudo(&mut self) {
+        self.stale[Feature::Pseudo.index()] = true;
+    }
+
+    /// The FPRs are `flen` bits wide, or there aren't any if it's 0
+    pub fn set_flen(&mut self, flen: u32) {
+        if flen != self.flen {
+            self.flen = flen;
+            self.stale[Feature::Fpu.index()] = true;
+        }
+    }
+
+    /// Build whichever features have gone stale since last time, and
+    /// `target.xml` along with them if any have
+    pub fn refresh(
+        &mut self,
+        registers: &HashMap<u32, RiscvRegister>,
+        pseudo_registers: &[PseudoRegister],
+    ) {
+        if !self.stale.iter().any(|s| *s) {
+            return;
+        }
+        for feature in FEATURES.iter() {
+            if self.stale[feature.index()] {
+                self.features[feature.index()] = match feature {
+                    Feature::Pseudo => self.pseudo_xml(pseudo_registers),
+                    _ => self.feature_xml(*feature, registers),
+                };
+                self.stale[feature.index()] = false;
+            }
+        }
+
+        let mut target_xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\" xmlns:xi=\"http://www.w3.org/2001/XInclude\">\n".to_string();
+        target_xml.push_str(&format!("<architecture>{}</architecture>\n", self.xlen.architecture()));
+        for feature in FEATURES.iter() {
+            if self.features[feature.index()].is_some() {
+                target_xml.push_str(&format!("<xi:include href=\"{}\"/>\n", feature.annex()));
+            }
+        }
+        target_xml.push_str("</target>\n");
+        self.target_xml = target_xml;
+    }
+
+    /// The document GDB asked for with `qXfer:features:read`
+    pub fn get(&self, annex: &str) -> Option<&[u8]> {
+        if annex == "target.xml" {
+            return Some(self.target_xml.as_bytes());
+        }
+        FEATURES
+            .iter()
+            .find(|f| f.annex() == annex)
+            .and_then(|f| self.features[f.index()].as_ref())
+            .map(|xml| xml.as_bytes())
+    }
+
+    /// Only registers that are there get listed, so that GDB isn't told
+    /// about an FPU with no registers
+    fn feature_xml(&self, feature: Feature, registers: &HashMap<u32, RiscvRegister>) -> Option<String> {
+        let mut regs: Vec<&RiscvRegister> = registers
+            .values()
+            .filter(|r| r.present && Feature::of(&r.feature()) == feature)
+            .collect();
+        if regs.is_empty() {
+            return None;
+        }
+        regs.sort_by_key(|r| r.gdb_index);
+
+        let mut xml = Self::feature_header(feature);
+        for reg in regs {
+            let reg_type = match reg.contents {
+                RegisterContentsType::Int => "int",
+                RegisterContentsType::CodePtr => "code_ptr",
+                RegisterContentsType::DataPtr => "data_ptr",
+                RegisterContentsType::Float if self.flen == 64 => "ieee_double",
+                RegisterContentsType::Float => "ieee_single",
+            };
+            let bitsize = match reg.register_type {
+                RiscvRegisterType::Float => self.flen,
+                _ => self.xlen.bits(),
+            };
+            xml.push_str(&format!(
+                "<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"{}\" group=\"{}\"",
+                reg.name,
+                bitsize,
+                reg.gdb_index,
+                reg_type,
+                reg.group.as_deref().unwrap_or_else(|| feature.group())
+            ));
+            if !reg.save_restore {
+                xml.push_str(" save-restore=\"no\"");
+            }
+            xml.push_str("/>\n");
+        }
+        xml.push_str("</feature>\n");
+        Some(xml)
+    }
+
+    /// These go in the general group so `info registers` shows them
+    fn pseudo_xml(&self, pseudo_registers: &[PseudoRegister]) -> Option<String> {
+        if pseudo_registers.is_empty() {
+            return None;
+        }
+        let mut xml = Self::feature_header(Feature::Pseudo);
+        for (idx, pseudo) in pseudo_registers.iter().enumerate() {
+            xml.push_str(&format!(
+                "<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" type=\"int\" group=\"general\" save-restore=\"no\"/>\n",
+                pseudo.name,
+                RiscvRegister::pseudo_offset() + idx as u32
+            ));
+        }
+        xml.push_str("</feature>\n");
+        Some(xml)
+    }
+
+    fn feature_header(feature: Feature) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE feature SYSTEM \"gdb-target.dtd\">\n<feature name=\"{}\">\n",
+            feature.name()
+        )
+    }
+}
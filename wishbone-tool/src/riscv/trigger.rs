@@ -0,0 +1,352 @@
+//! Hardware breakpoints and watchpoints for CPUs behind a Debug Module,
+//! made out of the address-matching ("mcontrol") triggers of the trigger
+//! module.  Each trigger is selected with `tselect`, then configured
+//! through `tdata1` and given its address in `tdata2`.
+
+use super::{RiscvBackend, RiscvCpuController, RiscvCpuError, RiscvRegister, Xlen};
+use crate::bridge::Bridge;
+
+use log::debug;
+
+use std::sync::{Arc, Mutex};
+
+/// Triggers past this many aren't looked for
+const MAX_TRIGGERS: u32 = 32;
+
+/// The `type` of a trigger that matches addresses
+const TRIGGER_TYPE_MCONTROL: u64 = 2;
+
+// `mcontrol` fields.  `type` and `dmode` sit at the top of the register,
+// so they depend on XLEN.
+const MCONTROL_HIT: u64 = 1 << 20;
+const MCONTROL_ACTION_DEBUG: u64 = 1 << 12;
+const MCONTROL_MATCH_NAPOT: u64 = 1 << 7;
+const MCONTROL_M: u64 = 1 << 6;
+const MCONTROL_S: u64 = 1 << 4;
+const MCONTROL_U: u64 = 1 << 3;
+const MCONTROL_EXECUTE: u64 = 1 << 2;
+const MCONTROL_STORE: u64 = 1 << 1;
+const MCONTROL_LOAD: u64 = 1 << 0;
+
+/// What a trigger fires on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerMatch {
+    /// Fetching an instruction, which makes a breakpoint
+    Execute,
+
+    /// Storing to memory
+    Write,
+
+    /// Loading from memory
+    Read,
+
+    /// Either loading or storing
+    Access,
+}
+
+impl TriggerMatch {
+    fn bits(self) -> u64 {
+        match self {
+            TriggerMatch::Execute => MCONTROL_EXECUTE,
+            TriggerMatch::Write => MCONTROL_STORE,
+            TriggerMatch::Read => MCONTROL_LOAD,
+            TriggerMatch::Access => MCONTROL_LOAD | MCONTROL_STORE,
+        }
+    }
+
+    /// The stop reason GDB expects when this watchpoint fires
+    pub fn stop_reason(self) -> Option<&'static str> {
+        match self {
+            TriggerMatch::Execute => None,
+            TriggerMatch::Write => Some("watch"),
+            TriggerMatch::Read => Some("rwatch"),
+            TriggerMatch::Access => Some("awatch"),
+        }
+    }
+}
+
+/// What a trigger has been set up to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TriggerUse {
+    addr: u32,
+    len: u32,
+    kind: TriggerMatch,
+}
+
+/// A trigger in use.  Each hart has its own `tselect` and `tdata`, so it's
+/// only set on the hart it was asked for on.
+#[derive(Clone, Copy, Debug)]
+struct Armed {
+    hart: u32,
+    index: u32,
+    trigger: TriggerUse,
+}
+
+struct Triggers {
+    /// The indices of the triggers that can match addresses.  They're
+    /// found on the first hart, and every hart is taken to have the same.
+    indices: Vec<u32>,
+
+    armed: Vec<Armed>,
+}
+
+/// The triggers are shared with every copy of the controller, so that the
+/// poll thread can tell which watchpoint stopped the CPU.
+#[derive(Clone)]
+pub struct BreakpointController {
+    triggers: Arc<Mutex<Triggers>>,
+
+    /// Where `type` sits in `tdata1`
+    xlen: Xlen,
+}
+
+/// The hart that `tselect` and `tdata` go to
+fn selected_hart(controller: &RiscvCpuController) -> u32 {
+    match &controller.backend {
+        RiscvBackend::Dmi(dm) => dm.hart(),
+        RiscvBackend::VexRiscv => 0,
+    }
+}
+
+/// Run `f` with `hart` selected in the Debug Module, then select the hart
+/// that was selected before again
+fn on_hart<T, F>(
+    controller: &RiscvCpuController,
+    bridge: &Bridge,
+    hart: u32,
+    f: F,
+) -> Result<T, RiscvCpuError>
+where
+    F: FnOnce() -> Result<T, RiscvCpuError>,
+{
+    let dm = match &controller.backend {
+        RiscvBackend::Dmi(dm) if dm.hart() != hart => dm,
+        _ => return f(),
+    };
+    let selected = dm.hart();
+    dm.select_hart(bridge, hart)?;
+    let result = f();
+    dm.select_hart(bridge, selected)?;
+    result
+}
+
+impl BreakpointController {
+    /// A controller with no triggers, for CPUs without a trigger module
+    pub fn none() -> BreakpointController {
+        BreakpointController {
+            triggers: Arc::new(Mutex::new(Triggers {
+                indices: vec![],
+                armed: vec![],
+            })),
+            xlen: Xlen::Rv32,
+        }
+    }
+
+    /// Count the triggers by selecting each in turn until `tselect` won't
+    /// take the number, or the trigger's type says there's nothing there.
+    /// The CPU must be halted.
+    pub fn discover(
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        xlen: Xlen,
+    ) -> Result<BreakpointController, RiscvCpuError> {
+        let triggers = BreakpointController {
+            xlen,
+            ..BreakpointController::none()
+        };
+        let tselect = RiscvRegister::tselect();
+        let mut indices = vec![];
+        for index in 0..MAX_TRIGGERS {
+            match controller.write_register_wide(bridge, &tselect, index as u64) {
+                Err(RiscvCpuError::AbstractCommandError(_)) => break,
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            }
+            if controller.read_register_wide(bridge, &tselect)? != index as u64 {
+                break;
+            }
+            let tdata1 = controller.read_register_wide(bridge, &RiscvRegister::tdata1())?;
+            match tdata1 >> triggers.type_shift() {
+                0 => break,
+                TRIGGER_TYPE_MCONTROL => indices.push(index),
+                other => debug!(
+                    "trigger {} is type {}, which can't match addresses",
+                    index, other
+                ),
+            }
+        }
+        debug!("found {} address-matching triggers", indices.len());
+        triggers.triggers.lock().unwrap().indices = indices;
+        Ok(triggers)
+    }
+
+    fn type_shift(&self) -> u32 {
+        self.xlen.bits() - 4
+    }
+
+    /// How many triggers each hart has
+    pub fn count(&self) -> usize {
+        self.triggers.lock().unwrap().indices.len()
+    }
+
+    /// How many triggers are in use on the selected hart
+    pub fn in_use(&self, controller: &RiscvCpuController) -> usize {
+        let hart = selected_hart(controller);
+        self.triggers
+            .lock()
+            .unwrap()
+            .armed
+            .iter()
+            .filter(|a| a.hart == hart)
+            .count()
+    }
+
+    /// Set a trigger on the selected hart to fire on `kind` accesses to
+    /// the `len` bytes at `addr`.  Not every trigger has to support loads
+    /// and stores, so each free one is tried in turn until one takes it.
+    pub fn add(
+        &self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        addr: u32,
+        len: u32,
+        kind: TriggerMatch,
+    ) -> Result<(), RiscvCpuError> {
+        let trigger = TriggerUse { addr, len, kind };
+        let hart = selected_hart(controller);
+        let mut triggers = self.triggers.lock().unwrap();
+        let free: Vec<u32> = triggers
+            .indices
+            .iter()
+            .copied()
+            .filter(|index| !triggers.armed.iter().any(|a| a.hart == hart && a.index == *index))
+            .collect();
+        for index in free {
+            if self.program(controller, bridge, index, Some(trigger))? {
+                triggers.armed.push(Armed { hart, index, trigger });
+                return Ok(());
+            }
+            debug!("trigger {} can't do {:?}", index, kind);
+            self.program(controller, bridge, index, None)?;
+        }
+        Err(RiscvCpuError::BreakpointExhausted)
+    }
+
+    /// Turn off the trigger set on `addr`, going to the hart it was set on.
+    /// One on the selected hart is taken first.
+    pub fn remove(
+        &self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        addr: u32,
+        kind: TriggerMatch,
+    ) -> Result<(), RiscvCpuError> {
+        let hart = selected_hart(controller);
+        let mut triggers = self.triggers.lock().unwrap();
+        let matching = |a: &Armed| a.trigger.addr == addr && a.trigger.kind == kind;
+        let pos = triggers
+            .armed
+            .iter()
+            .position(|a| a.hart == hart && matching(a))
+            .or_else(|| triggers.armed.iter().position(matching))
+            .ok_or(RiscvCpuError::BreakpointNotFound(addr))?;
+        let armed = triggers.armed[pos];
+        on_hart(controller, bridge, armed.hart, || {
+            self.program(controller, bridge, armed.index, None)
+        })?;
+        triggers.armed.remove(pos);
+        Ok(())
+    }
+
+    /// Program every trigger again, each on its own hart, such as after a
+    /// reset has cleared them.
+    pub fn restore(
+        &self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+    ) -> Result<(), RiscvCpuError> {
+        for armed in self.triggers.lock().unwrap().armed.iter() {
+            on_hart(controller, bridge, armed.hart, || {
+                self.program(controller, bridge, armed.index, Some(armed.trigger))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Work out which watchpoint stopped the selected hart, going by the
+    /// `hit` bit, and clear it.  Triggers don't have to implement `hit`,
+    /// so if none of them say and the hart has only one watchpoint, it
+    /// must have been that.
+    pub fn watchpoint_hit(
+        &self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+    ) -> Result<Option<(TriggerMatch, u32)>, RiscvCpuError> {
+        let hart = selected_hart(controller);
+        let watches: Vec<Armed> = self
+            .triggers
+            .lock()
+            .unwrap()
+            .armed
+            .iter()
+            .filter(|a| a.hart == hart && a.trigger.kind != TriggerMatch::Execute)
+            .copied()
+            .collect();
+        let tdata1 = RiscvRegister::tdata1();
+        for watch in &watches {
+            controller.write_register_wide(bridge, &RiscvRegister::tselect(), watch.index as u64)?;
+            let value = controller.read_register_wide(bridge, &tdata1)?;
+            if value & MCONTROL_HIT != 0 {
+                controller.write_register_wide(bridge, &tdata1, value & !MCONTROL_HIT)?;
+                return Ok(Some((watch.trigger.kind, watch.trigger.addr)));
+            }
+        }
+        match watches.as_slice() {
+            [watch] => Ok(Some((watch.trigger.kind, watch.trigger.addr))),
+            _ => Ok(None),
+        }
+    }
+    /// Point a trigger at an address, or turn it off, returning whether
+    /// the trigger took the settings.  It's turned off first either way,
+    /// so that it never matches a half-written address.
+    fn program(
+        &self,
+        controller: &RiscvCpuController,
+        bridge: &Bridge,
+        index: u32,
+        trigger: Option<TriggerUse>,
+    ) -> Result<bool, RiscvCpuError> {
+        let tdata1 = RiscvRegister::tdata1();
+        controller.write_register_wide(bridge, &RiscvRegister::tselect(), index as u64)?;
+        controller.write_register_wide(bridge, &tdata1, 0)?;
+        let trigger = match trigger {
+            Some(t) => t,
+            None => return Ok(true),
+        };
+
+        // A naturally aligned power-of-two range can be matched as a
+        // whole.  Anything else only matches its first byte.
+        let napot = trigger.len > 1
+            && trigger.len.is_power_of_two()
+            && trigger.addr.is_multiple_of(trigger.len);
+        let tdata2 = if napot {
+            trigger.addr | ((trigger.len - 1) >> 1)
+        } else {
+            trigger.addr
+        };
+        controller.write_register_wide(bridge, &RiscvRegister::tdata2(), tdata2 as u64)?;
+        let mcontrol = (TRIGGER_TYPE_MCONTROL << self.type_shift())
+            | (1 << (self.type_shift() - 1)) // dmode: only the debugger may change it
+            | MCONTROL_ACTION_DEBUG
+            | if napot { MCONTROL_MATCH_NAPOT } else { 0 }
+            | MCONTROL_M
+            | MCONTROL_S
+            | MCONTROL_U
+            | trigger.kind.bits();
+        controller.write_register_wide(bridge, &tdata1, mcontrol)?;
+        // `maskmax` is read-only, and anything the trigger can't do reads
+        // back as zero
+        let readback = controller.read_register_wide(bridge, &tdata1)?;
+        Ok(readback & mcontrol == mcontrol)
+    }
+}
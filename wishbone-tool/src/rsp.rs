@@ -0,0 +1,305 @@
+//! The framing of GDB's Remote Serial Protocol.  Each packet goes over
+//! the wire as `$data#cs`, where `cs` is the sum of the bytes between
+//! `$` and `#`, in hex.  Those two, and `}` and `*`, can't appear in the
+//! data as they are: `}` escapes the byte after it, which is sent XORed
+//! with 0x20, and `*` repeats the byte before it, a run-length encoding
+//! GDB understands in replies.  Until no-ack mode is started, each packet
+//! is answered with `+` if it arrived intact, or `-` to have it sent
+//! again.  Notifications, which the stub sends whenever it likes, start
+//! with `%` instead and are never answered.
+//!
+//! Memory reads are mostly runs of the same hex digit, so run-length
+//! encoding them saves a lot of bytes over a slow link.
+
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Runs shorter than this aren't made any shorter by encoding them
+const MIN_RUN: usize = 4;
+
+/// A repeat count is sent as the character 29 places on from it, which
+/// has to be printable
+const MAX_REPEAT: usize = 126 - 29;
+
+/// The data of one packet, with the framing taken off
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    data: Vec<u8>,
+}
+
+/// What came in over the connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Packet(Packet),
+
+    /// A packet whose checksum didn't match, or that couldn't be decoded
+    Corrupt,
+
+    /// `+`
+    Ack,
+
+    /// `-`, asking for the last packet again
+    Nak,
+
+    /// Ctrl-C, sent on its own to stop the target
+    Interrupt,
+}
+
+fn needs_escape(byte: u8) -> bool {
+    matches!(byte, b'$' | b'#' | b'}' | b'*')
+}
+
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+impl Packet {
+    pub fn new(data: &[u8]) -> Packet {
+        Packet { data: data.to_vec() }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Frame the packet for sending, escaping and run-length encoding it
+    pub fn encode(&self) -> Vec<u8> {
+        self.frame(b'$')
+    }
+
+    /// Frame the packet as a notification, such as `Stop:T05`
+    pub fn encode_notification(&self) -> Vec<u8> {
+        self.frame(b'%')
+    }
+
+    fn frame(&self, start: u8) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.data.len() + 4);
+        let mut rest = &self.data[..];
+        while let Some(&byte) = rest.first() {
+            let run = rest.iter().take_while(|b| **b == byte).count();
+            rest = &rest[run..];
+            // An escaped byte can't be repeated, since `*` would only
+            // repeat the second half of the escape
+            if needs_escape(byte) {
+                for _ in 0..run {
+                    body.push(b'}');
+                    body.push(byte ^ 0x20);
+                }
+                continue;
+            }
+            body.push(byte);
+            let mut left = run - 1;
+            while left > 0 {
+                if left < MIN_RUN - 1 {
+                    body.extend(std::iter::repeat_n(byte, left));
+                    break;
+                }
+                // Counts that would come out as `#` or `$` are shortened
+                let mut count = left.min(MAX_REPEAT);
+                if count == 6 || count == 7 {
+                    count = 5;
+                }
+                body.push(b'*');
+                body.push((count + 29) as u8);
+                left -= count;
+            }
+        }
+        let mut frame = Vec::with_capacity(body.len() + 4);
+        frame.push(start);
+        frame.extend_from_slice(&body);
+        frame.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+        frame
+    }
+
+    /// Undo the run-length encoding and escaping of the bytes between `$`
+    /// and `#`.  Runs are expanded first, as GDB does, so a `*` repeats
+    /// the byte before it as it was sent.
+    fn decode(body: &[u8]) -> Option<Packet> {
+        let mut expanded = Vec::with_capacity(body.len());
+        let mut bytes = body.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte != b'*' {
+                expanded.push(byte);
+                continue;
+            }
+            let count = (*bytes.next()? as usize).checked_sub(29)?;
+            let last = *expanded.last()?;
+            expanded.extend(std::iter::repeat_n(last, count));
+        }
+
+        let mut data = Vec::with_capacity(expanded.len());
+        let mut bytes = expanded.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte == b'}' {
+                data.push(bytes.next()? ^ 0x20);
+            } else {
+                data.push(byte);
+            }
+        }
+        Some(Packet { data })
+    }
+}
+
+/// Reads frames from a connection, through a buffer so that a packet
+/// doesn't take a read for every byte
+pub struct PacketReader<R: Read> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(reader: R) -> PacketReader<R> {
+        PacketReader {
+            reader: BufReader::new(reader),
+        }
+    }
+
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let buf = self.reader.fill_buf()?;
+        let byte = match buf.first() {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        self.reader.consume(1);
+        Ok(Some(byte))
+    }
+
+    /// The next frame, or None once the connection has closed.  Stray
+    /// bytes between packets are skipped.
+    pub fn read(&mut self) -> io::Result<Option<Frame>> {
+        loop {
+            let frame = match self.byte()? {
+                None => return Ok(None),
+                Some(b'$') => self.read_packet()?,
+                Some(b'+') => Some(Frame::Ack),
+                Some(b'-') => Some(Frame::Nak),
+                Some(0x03) => Some(Frame::Interrupt),
+                Some(_) => continue,
+            };
+            return Ok(frame);
+        }
+    }
+
+    fn read_packet(&mut self) -> io::Result<Option<Frame>> {
+        let mut body = vec![];
+        self.reader.read_until(b'#', &mut body)?;
+        if body.pop() != Some(b'#') {
+            return Ok(None);
+        }
+        let mut sent = [0u8; 2];
+        for digit in sent.iter_mut() {
+            *digit = match self.byte()? {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+        }
+        let sent = std::str::from_utf8(&sent)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        if sent != Some(checksum(&body)) {
+            return Ok(Some(Frame::Corrupt));
+        }
+        Ok(Some(Packet::decode(&body).map_or(Frame::Corrupt, Frame::Packet)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(bytes: &[u8]) -> Vec<Frame> {
+        let mut reader = PacketReader::new(bytes);
+        let mut frames = vec![];
+        while let Some(frame) = reader.read().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let frame = Packet::new(data).encode();
+        match read_all(&frame).as_slice() {
+            [Frame::Packet(packet)] => packet.data().to_vec(),
+            other => panic!("{:?} came back as {:?}", data, other),
+        }
+    }
+
+    #[test]
+    fn known_frames() {
+        assert_eq!(Packet::new(b"OK").encode(), b"$OK#9a");
+        assert_eq!(Packet::new(b"").encode(), b"$#00");
+        assert_eq!(Packet::new(b"T05").encode_notification(), b"%T05#b9");
+        // The example from GDB's manual: four zeroes are `0* `
+        assert_eq!(Packet::new(b"0000").encode(), b"$0* #7a");
+        assert_eq!(Packet::new(b"000").encode(), b"$000#90");
+        assert_eq!(Packet::new(b"a}b").encode(), b"$a}]b#9d");
+    }
+
+    #[test]
+    fn runs_avoid_the_framing_characters() {
+        // Six and seven repeats would be sent as `#` and `$`
+        assert_eq!(Packet::new(&[b'0'; 7]).encode()[1..6], *b"0*\"0#");
+        assert_eq!(Packet::new(&[b'0'; 8]).encode()[1..7], *b"0*\"00#");
+        for len in 1..300 {
+            let data = vec![b'f'; len];
+            let frame = Packet::new(&data).encode();
+            let body = &frame[1..frame.len() - 3];
+            assert!(!body.contains(&b'#') && !body.contains(&b'$'), "run of {}", len);
+            assert!(body.iter().all(|b| *b <= 126), "run of {}", len);
+            assert_eq!(round_trip(&data), data);
+        }
+    }
+
+    #[test]
+    fn runs_are_split_at_max_repeat() {
+        let data = vec![b'a'; 1 + MAX_REPEAT * 2 + 5];
+        let frame = Packet::new(&data).encode();
+        assert_eq!(frame[1..8], *b"a*~*~*\"");
+        assert_eq!(round_trip(&data), data);
+    }
+
+    #[test]
+    fn escaped_bytes_are_never_repeated() {
+        let data = b"}}}}}}$$$$####****";
+        let frame = Packet::new(data).encode();
+        let body = &frame[1..frame.len() - 3];
+        assert_eq!(body.len(), data.len() * 2);
+        assert!(body.chunks(2).all(|pair| pair[0] == b'}'));
+        assert_eq!(round_trip(data), data);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut every_byte: Vec<u8> = (0..=255).collect();
+        every_byte.extend((0..=255).rev());
+        assert_eq!(round_trip(&every_byte), every_byte);
+        let data = b"m8000,40:00000000000000001111}}}}aaaa$$$*";
+        assert_eq!(round_trip(data), data.to_vec());
+    }
+
+    #[test]
+    fn decoding() {
+        // A run repeats the byte before it as it was sent, so the second
+        // half of an escape
+        assert_eq!(Packet::decode(b"}]* ").unwrap().data(), b"}]]]");
+        assert_eq!(Packet::decode(b"x*\"").unwrap().data(), b"xxxxxx");
+        assert_eq!(Packet::decode(b"x*"), None);
+        assert_eq!(Packet::decode(b"*!"), None);
+        assert_eq!(Packet::decode(b"x*\x10"), None);
+        assert_eq!(Packet::decode(b"ab}"), None);
+    }
+
+    #[test]
+    fn reading_frames() {
+        let frames = read_all(b"+$OK#9a-\x03junk$OK#00$OK#zz%T05#b9$OK");
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Ack,
+                Frame::Packet(Packet::new(b"OK")),
+                Frame::Nak,
+                Frame::Interrupt,
+                Frame::Corrupt,
+                Frame::Corrupt,
+            ]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Sequences of accesses spread across two boards at once, such as poking
+//! a trigger on one and capturing a buffer on the other, kept in step by
+//! barriers.
+//!
+//! Each board gets a thread that works through the steps meant for it,
+//! and every thread has to reach a barrier before any of them carry on
+//! past it.
+
+use crate::bridge::{Bridge, BridgeError};
+
+use log::{debug, info};
+
+use std::fs;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often `wait` polls its register
+const WAIT_POLL: Duration = Duration::from_millis(1);
+
+/// The names that steps use for each board, in the order they're opened
+pub const TARGET_NAMES: [&str; 2] = ["a", "b"];
+
+#[derive(Clone, Debug)]
+pub enum SequenceOp {
+    /// Write a value to an address
+    Poke(u32, u32),
+
+    /// Read an address and print it
+    Peek(u32),
+
+    /// Poll an address until the bits in `mask` equal `value`
+    Wait {
+        addr: u32,
+        mask: u32,
+        value: u32,
+        timeout: Duration,
+    },
+
+    /// Copy `len` bytes of memory out to a file
+    Dump(u32, u32, String),
+
+    /// Do nothing for a while
+    Sleep(Duration),
+
+    /// Make sure every write so far has landed, by reading back an
+    /// address, or the scratch register if there isn't one
+    Fence(Option<u32>),
+
+    /// Wait for every board to get here, once its own writes have landed
+    Barrier,
+}
+
+#[derive(Clone, Debug)]
+pub struct SequenceStep {
+    /// Which board this step runs on, as an index into `TARGET_NAMES`.
+    /// Barriers apply to all of them.
+    pub target: usize,
+    pub op: SequenceOp,
+
+    /// Where the step came from, for error messages
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub enum SequenceError {
+    BridgeError(BridgeError),
+    IoError(io::Error),
+
+    /// A `wait` never saw its value
+    WaitTimeout(
+        usize, /* line */
+        u32,   /* address */
+        u32,   /* last value */
+    ),
+
+    /// Another board's steps failed, so the barrier will never be reached
+    Aborted,
+}
+
+impl std::fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use SequenceError::*;
+        match self {
+            BridgeError(e) => write!(f, "bridge error: {}", e),
+            IoError(e) => write!(f, "io error: {}", e),
+            WaitTimeout(line, addr, value) => write!(
+                f,
+                "line {}: timed out waiting on {:08x}, which was last {:08x}",
+                line, addr, value
+            ),
+            Aborted => write!(f, "another board's sequence failed"),
+        }
+    }
+}
+
+impl std::convert::From<BridgeError> for SequenceError {
+    fn from(e: BridgeError) -> SequenceError {
+        SequenceError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<io::Error> for SequenceError {
+    fn from(e: io::Error) -> SequenceError {
+        SequenceError::IoError(e)
+    }
+}
+
+/// A barrier that can be given up on, so that one board failing doesn't
+/// leave the others waiting forever.
+struct SyncPoint {
+    /// (threads waiting, generation, aborted)
+    state: Mutex<(usize, u64, bool)>,
+    cv: Condvar,
+    parties: usize,
+}
+
+impl SyncPoint {
+    fn new(parties: usize) -> SyncPoint {
+        SyncPoint {
+            state: Mutex::new((0, 0, false)),
+            cv: Condvar::new(),
+            parties,
+        }
+    }
+
+    /// Wait for every thread to arrive, returning `false` if one of them
+    /// gave up instead.
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.1;
+        state.0 += 1;
+        if state.0 == self.parties {
+            state.0 = 0;
+            state.1 += 1;
+            self.cv.notify_all();
+            return !state.2;
+        }
+        while state.1 == generation && !state.2 {
+            state = self.cv.wait(state).unwrap();
+        }
+        !state.2
+    }
+
+    fn abort(&self) {
+        self.state.lock().unwrap().2 = true;
+        self.cv.notify_all();
+    }
+}
+
+/// Run `steps` across `bridges`, one thread per board.
+pub fn run(bridges: Vec<Bridge>, steps: &[SequenceStep]) -> Result<(), SequenceError> {
+    let start = Instant::now();
+    let sync = Arc::new(SyncPoint::new(bridges.len()));
+    let steps = Arc::new(steps.to_vec());
+    let mut threads = vec![];
+    for (target, bridge) in bridges.into_iter().enumerate() {
+        let sync = sync.clone();
+        let steps = steps.clone();
+        threads.push(thread::spawn(move || {
+            let result = run_target(&bridge, target, &steps, &sync, start);
+            if result.is_err() {
+                sync.abort();
+            }
+            result
+        }));
+    }
+
+    // Report the first real failure, rather than the others giving up
+    let mut error = None;
+    for thr in threads {
+        match thr.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(SequenceError::Aborted)) => {
+                error.get_or_insert(SequenceError::Aborted);
+            }
+            Ok(Err(e)) => {
+                if let Some(SequenceError::Aborted) | None = error {
+                    error = Some(e);
+                }
+            }
+            Err(_) => {
+                error.get_or_insert(SequenceError::Aborted);
+            }
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn run_target(
+    bridge: &Bridge,
+    target: usize,
+    steps: &[SequenceStep],
+    sync: &SyncPoint,
+    start: Instant,
+) -> Result<(), SequenceError> {
+    let name = TARGET_NAMES[target];
+    let stamp = || start.elapsed().as_secs_f64() * 1000.0;
+    for step in steps {
+        if let SequenceOp::Barrier = step.op {
+            // Posted writes still on their way would otherwise let the
+            // other board carry on before they've happened
+            bridge.fence(None)?;
+            let arrived = Instant::now();
+            if !sync.wait() {
+                return Err(SequenceError::Aborted);
+            }
+            debug!(
+                "[{:10.3}ms] {}: waited {:?} at the barrier on line {}",
+                stamp(),
+                name,
+                arrived.elapsed(),
+                step.line
+            );
+            continue;
+        }
+        if step.target != target {
+            continue;
+        }
+        match &step.op {
+            SequenceOp::Poke(addr, value) => bridge.poke(*addr, *value)?,
+            SequenceOp::Peek(addr) => {
+                let value = bridge.peek(*addr)?;
+                println!(
+                    "[{:10.3}ms] {}: {:08x} = {:08x}",
+                    stamp(),
+                    name,
+                    addr,
+                    value
+                );
+            }
+            SequenceOp::Wait {
+                addr,
+                mask,
+                value,
+                timeout,
+            } => {
+                let deadline = Instant::now() + *timeout;
+                loop {
+                    let observed = bridge.peek(*addr)?;
+                    if observed & mask == *value {
+                        break;
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(SequenceError::WaitTimeout(step.line, *addr, observed));
+                    }
+                    thread::sleep(WAIT_POLL);
+                }
+            }
+            SequenceOp::Dump(addr, len, file) => {
+                let data = bridge.burst_read(*addr, *len)?;
+                fs::write(file, &data)?;
+                info!(
+                    "[{:10.3}ms] {}: wrote {} bytes from {:08x} to {}",
+                    stamp(),
+                    name,
+                    len,
+                    addr,
+                    file
+                );
+            }
+            SequenceOp::Sleep(time) => thread::sleep(*time),
+            SequenceOp::Fence(readback) => bridge.fence(*readback)?,
+            SequenceOp::Barrier => unreachable!(),
+        }
+    }
+    Ok(())
+}
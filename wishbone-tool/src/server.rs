@@ -1,11 +1,43 @@
 use crate::bridge;
-use crate::config::{Config, ConfigError};
+use crate::checkpoint::{self, Checkpoint, CHECKPOINT_INTERVAL};
+use crate::config::{
+    parse_u32, Config, ConfigError, CsrMode, CsrRegister, SparseMode, Transfer, TransferKind,
+};
+use crate::consolelog;
+use crate::coverage::{CoverageBitmap, CoverageMode};
+use crate::dma::{DmaError, Segment};
+use crate::docs;
+use crate::dtb;
+use crate::ecc::{self, EccController};
+use crate::elf;
+use crate::endurance::{self, EnduranceTarget};
+use crate::stress::Stress;
+use crate::etherbone::{self, EtherboneError, Packet};
+use crate::spiflash::{self, SpiFlash};
+use crate::golden::{self, GoldenError, GoldenWord};
+use crate::monitor::FlashMonitor;
+use crate::mqtt::{self, MqttPublisher};
+use crate::pac;
+use crate::phy::PrbsPhy;
+use crate::signature::{self, SignatureError};
+use crate::report::{self, TestResult};
+use crate::regions::{self, MemoryKind};
+use crate::sequence::{self, SequenceError};
+use crate::sfl;
+use crate::svd;
+use crate::syslog;
+use crate::tap::{self, PacketRings, TapError};
+use crate::tui;
+use crate::uf2;
+use crate::litescope::LiteScope;
+use crate::watch::{ScopeSync, WatchStats};
+use crate::xover::{ConsoleTap, LineStamper, XoverUart};
 use crate::gdb;
 use crate::riscv;
 use crate::wishbone;
 
 extern crate log;
-use log::{error, info};
+use log::{debug, error, info, warn};
 
 extern crate rand;
 use rand::prelude::*;
@@ -14,9 +46,10 @@ use byteorder::{LittleEndian, ReadBytesExt};
 
 use std::fs::File;
 use std::io;
-use std::net::TcpListener;
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::net::{TcpListener, TcpStream};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(PartialEq, Clone)]
 pub enum ServerKind {
@@ -40,6 +73,90 @@ pub enum ServerKind {
 
     /// View the messible
     Messible,
+
+    /// Save all writable CSRs to a file
+    StateSave,
+
+    /// Restore CSRs from a previously-saved file
+    StateRestore,
+
+    /// Measure debug latency at the bridge, CPU, and GDB layers
+    GdbBench,
+
+    /// Record which instructions the CPU executes
+    Coverage,
+
+    /// Load firmware, run it, and check what it prints
+    Run,
+
+    /// Send firmware to the BIOS "serialboot" command over the console
+    SerialBoot,
+
+    /// Copy several files into or out of memory
+    Transfer,
+
+    /// Read, clear, or scrub ECC memory controllers
+    Ecc,
+
+    /// Run a scatter-gather DMA engine over a list of segments
+    Dma,
+
+    /// Run a PRBS test on SerDes PHYs and report the bit error rate
+    PhyTest,
+
+    /// Work out the expressions given with --calc
+    Calc,
+
+    /// Cycle patterns through RAM or flash for hours, counting errors
+    Endurance,
+
+    /// Make a seeded random mix of accesses to RAM, checking what comes back
+    Stress,
+
+    /// Poll registers and report when they change
+    Watch,
+
+    /// Set a bit for a fixed length of time
+    Pulse,
+
+    /// Write a register with values from a table, at the times given
+    Waveform,
+
+    /// Run steps across this board and a --peer, kept in step by barriers
+    Sequence,
+
+    /// Write a file to SPI flash
+    Flash,
+
+    /// Check a file against a signed manifest, without flashing it
+    FlashVerifySignature,
+
+    /// Read, or very carefully write, one-time-programmable fuses
+    Otp,
+
+    /// Pass packets between a host TUN/TAP interface and the firmware
+    Tap,
+
+    /// Set a clock on the target to the host's time
+    TimeSync,
+
+    /// Write out a device tree describing the SoC in the csr.csv
+    GenDtb,
+
+    /// Write out register access code for the SoC in the csr.csv
+    GenPac,
+
+    /// Browse the registers and their fields, and edit them
+    Tui,
+
+    /// Write out documentation of the register map in the csr.csv
+    GenDocs,
+
+    /// Send a hand-made Etherbone packet and decode the reply
+    EtherboneSend,
+
+    /// Decode an Etherbone packet
+    EtherboneDecode,
 }
 
 #[derive(Debug)]
@@ -55,6 +172,48 @@ pub enum ServerError {
         u32, /* observed */
     ),
     TerminalError(terminal::error::ErrorKind),
+    CsvError(csv::Error),
+    StateError(String),
+    ElfError(elf::ElfError),
+    Uf2Error(uf2::Uf2Error),
+
+    /// The console never printed the expected text
+    ExpectTimeout(String /* pattern */),
+
+    /// The BIOS didn't accept a serialboot
+    SerialBootError(String),
+
+    FlashError(spiflash::FlashError),
+    SignatureError(SignatureError),
+
+    /// A fuse didn't read back as what was written to it
+    OtpMismatch(u32 /* address */, u32 /* expected */, u32 /* observed */),
+
+    /// --footguns block turned down a write
+    WriteRefused(u32 /* address */),
+
+    /// --verify found writes that didn't read back as written
+    VerifyFailed(usize /* mismatches */),
+
+    SequenceError(SequenceError),
+    TapError(TapError),
+    EtherboneError(EtherboneError),
+    GoldenError(GoldenError),
+    DmaError(DmaError),
+
+    /// The CSRs no longer match the golden dump
+    GoldenMismatch(usize /* differences */),
+
+    /// A PRBS test saw bit errors
+    PrbsErrors(u64 /* errors */),
+
+    /// An endurance run read back words that were wrong
+    EnduranceErrors(u64 /* words */),
+
+    /// A stress run read back a word that was wrong
+    StressMismatch(u64 /* seed */, u64 /* access */),
+
+    SvdError(svd::SvdError),
 }
 
 impl std::convert::From<io::Error> for ServerError {
@@ -72,6 +231,36 @@ impl std::convert::From<gdb::GdbServerError> for ServerError {
         ServerError::GdbError(e)
     }
 }
+impl std::convert::From<SequenceError> for ServerError {
+    fn from(e: SequenceError) -> ServerError {
+        ServerError::SequenceError(e)
+    }
+}
+impl std::convert::From<EtherboneError> for ServerError {
+    fn from(e: EtherboneError) -> ServerError {
+        ServerError::EtherboneError(e)
+    }
+}
+impl std::convert::From<DmaError> for ServerError {
+    fn from(e: DmaError) -> ServerError {
+        ServerError::DmaError(e)
+    }
+}
+impl std::convert::From<GoldenError> for ServerError {
+    fn from(e: GoldenError) -> ServerError {
+        ServerError::GoldenError(e)
+    }
+}
+impl std::convert::From<svd::SvdError> for ServerError {
+    fn from(e: svd::SvdError) -> ServerError {
+        ServerError::SvdError(e)
+    }
+}
+impl std::convert::From<TapError> for ServerError {
+    fn from(e: TapError) -> ServerError {
+        ServerError::TapError(e)
+    }
+}
 impl std::convert::From<bridge::BridgeError> for ServerError {
     fn from(e: bridge::BridgeError) -> ServerError {
         ServerError::BridgeError(e)
@@ -89,7 +278,107 @@ impl std::convert::From<terminal::error::ErrorKind> for ServerError {
     }
 }
 
+impl std::convert::From<elf::ElfError> for ServerError {
+    fn from(e: elf::ElfError) -> ServerError {
+        ServerError::ElfError(e)
+    }
+}
+
+impl std::convert::From<uf2::Uf2Error> for ServerError {
+    fn from(e: uf2::Uf2Error) -> ServerError {
+        ServerError::Uf2Error(e)
+    }
+}
+
+impl std::convert::From<spiflash::FlashError> for ServerError {
+    fn from(e: spiflash::FlashError) -> ServerError {
+        ServerError::FlashError(e)
+    }
+}
+
+impl std::convert::From<SignatureError> for ServerError {
+    fn from(e: SignatureError) -> ServerError {
+        ServerError::SignatureError(e)
+    }
+}
+
+impl std::convert::From<csv::Error> for ServerError {
+    fn from(e: csv::Error) -> ServerError {
+        ServerError::CsvError(e)
+    }
+}
+
 impl ServerKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerKind::MemoryAccess => "memory-access",
+            ServerKind::Wishbone => "wishbone",
+            ServerKind::GDB => "gdb",
+            ServerKind::RandomTest => "random-test",
+            ServerKind::LoadFile => "load-file",
+            ServerKind::Terminal => "terminal",
+            ServerKind::Messible => "messible",
+            ServerKind::StateSave => "state-save",
+            ServerKind::StateRestore => "state-restore",
+            ServerKind::GdbBench => "gdb-bench",
+            ServerKind::Coverage => "coverage",
+            ServerKind::Run => "run",
+            ServerKind::SerialBoot => "serial-boot",
+            ServerKind::Transfer => "transfer",
+            ServerKind::Ecc => "ecc",
+            ServerKind::Dma => "dma",
+            ServerKind::PhyTest => "phy-test",
+            ServerKind::Calc => "calc",
+            ServerKind::Endurance => "endurance",
+            ServerKind::Stress => "stress",
+            ServerKind::Watch => "watch",
+            ServerKind::Pulse => "pulse",
+            ServerKind::Waveform => "waveform",
+            ServerKind::Sequence => "sequence",
+            ServerKind::Flash => "flash",
+            ServerKind::FlashVerifySignature => "flash-verify-signature",
+            ServerKind::Otp => "otp",
+            ServerKind::Tap => "tap",
+            ServerKind::TimeSync => "time-sync",
+            ServerKind::GenDtb => "gen-dtb",
+            ServerKind::GenPac => "gen-pac",
+            ServerKind::Tui => "tui",
+            ServerKind::GenDocs => "gen-docs",
+            ServerKind::EtherboneSend => "etherbone-send",
+            ServerKind::EtherboneDecode => "etherbone-decode",
+        }
+    }
+
+    /// Whether the server can't do its job without writing to the board,
+    /// so that --read-only can turn it down up front.  Halting the CPU and
+    /// taking characters off the UART are writes too.  Servers that only
+    /// sometimes write are left to have those writes refused.
+    pub fn writes_to_board(&self) -> bool {
+        matches!(
+            self,
+            ServerKind::GDB
+                | ServerKind::RandomTest
+                | ServerKind::LoadFile
+                | ServerKind::Terminal
+                | ServerKind::StateRestore
+                | ServerKind::GdbBench
+                | ServerKind::Coverage
+                | ServerKind::Run
+                | ServerKind::SerialBoot
+                | ServerKind::Dma
+                | ServerKind::PhyTest
+                | ServerKind::Endurance
+                | ServerKind::Stress
+                | ServerKind::Pulse
+                | ServerKind::Waveform
+                | ServerKind::Sequence
+                | ServerKind::Flash
+                | ServerKind::Tap
+                | ServerKind::TimeSync
+                | ServerKind::EtherboneSend
+        )
+    }
+
     pub fn from_string(item: &str) -> Result<ServerKind, ConfigError> {
         match item {
             "gdb" => Ok(ServerKind::GDB),
@@ -99,9 +388,51 @@ impl ServerKind {
             "terminal" => Ok(ServerKind::Terminal),
             "messible" => Ok(ServerKind::Messible),
             "memory-access" => Ok(ServerKind::MemoryAccess),
+            "state-save" => Ok(ServerKind::StateSave),
+            "state-restore" => Ok(ServerKind::StateRestore),
+            "gdb-bench" => Ok(ServerKind::GdbBench),
+            "coverage" => Ok(ServerKind::Coverage),
+            "run" => Ok(ServerKind::Run),
+            "serial-boot" => Ok(ServerKind::SerialBoot),
+            "transfer" => Ok(ServerKind::Transfer),
+            "ecc" => Ok(ServerKind::Ecc),
+            "dma" => Ok(ServerKind::Dma),
+            "phy-test" => Ok(ServerKind::PhyTest),
+            "calc" => Ok(ServerKind::Calc),
+            "endurance" => Ok(ServerKind::Endurance),
+            "stress" => Ok(ServerKind::Stress),
+            "watch" => Ok(ServerKind::Watch),
+            "pulse" => Ok(ServerKind::Pulse),
+            "waveform" => Ok(ServerKind::Waveform),
+            "sequence" => Ok(ServerKind::Sequence),
+            "flash" => Ok(ServerKind::Flash),
+            "flash-verify-signature" => Ok(ServerKind::FlashVerifySignature),
+            "otp" => Ok(ServerKind::Otp),
+            "tap" => Ok(ServerKind::Tap),
+            "time-sync" => Ok(ServerKind::TimeSync),
+            "gen-dtb" => Ok(ServerKind::GenDtb),
+            "gen-pac" => Ok(ServerKind::GenPac),
+            "tui" => Ok(ServerKind::Tui),
+            "gen-docs" => Ok(ServerKind::GenDocs),
+            "etherbone-send" => Ok(ServerKind::EtherboneSend),
+            "etherbone-decode" => Ok(ServerKind::EtherboneDecode),
             unknown => Err(ConfigError::UnknownServerKind(unknown.to_owned())),
         }
     }
+
+    /// Generators work entirely from the configuration, and can run
+    /// without any hardware attached.  The Etherbone tools make their own
+    /// packets rather than going through a bridge.
+    pub fn needs_bridge(&self) -> bool {
+        !matches!(
+            self,
+            ServerKind::GenDtb
+                | ServerKind::GenPac
+                | ServerKind::GenDocs
+                | ServerKind::EtherboneSend
+                | ServerKind::EtherboneDecode
+        )
+    }
 }
 
 /// Poll the Messible at the address specified.
@@ -150,6 +481,36 @@ fn poll_messible(
     }
 }
 
+/// Move whatever is waiting in the console UART over to `tap`.
+fn read_console(uart: &XoverUart, bridge: &bridge::Bridge, tap: &ConsoleTap) {
+    let mut data = vec![];
+    while let Ok(Some(byte)) = uart.read_byte(bridge) {
+        data.push(byte);
+        if data.len() >= 256 {
+            break;
+        }
+    }
+    syslog::console(&data);
+    consolelog::console(&data);
+    tap.push(&data);
+}
+
+/// Send each complete console line to GDB, marked with when it arrived.
+fn forward_console(
+    tap: &ConsoleTap,
+    stamper: &mut LineStamper,
+    gdb_controller: &mut gdb::GdbController,
+) {
+    for (when, data) in tap.take() {
+        for line in stamper.feed(when, &data) {
+            gdb_controller.print_string(&line).ok();
+        }
+    }
+    if let Some(line) = stamper.flush_stale(tap.elapsed(), Duration::from_millis(500)) {
+        gdb_controller.print_string(&line).ok();
+    }
+}
+
 /// Poll the UART at the address specified.
 /// Return `true` if there is still data to be read
 /// after returning.
@@ -157,8 +518,83 @@ fn poll_uart(uart_address: u32, bridge: &bridge::Bridge) -> Result<bool, bridge:
     Ok(bridge.peek(uart_address)? == 0)
 }
 
+/// How often the GDB server checks whether the CPU has stopped
+const GDB_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often it checks over a slow bridge, where each check takes a
+/// noticeable share of the bandwidth
+const GDB_SLOW_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The packet size offered to GDB over a slow bridge
+const GDB_SLOW_PACKET_SIZE: usize = 0xffff;
+
+/// Attach to the CPU for debugging, finding out which CSRs it has and
+/// adding the ones from --custom-csrs, so that every register is there
+/// to be asked for by name.
+fn attach_cpu(cfg: &Config, bridge: &bridge::Bridge) -> Result<riscv::RiscvSystem, ServerError> {
+    // The same gateware always has the same CPU, so what was found out
+    // about it last time can be reused.  Without the identifier there's
+    // nothing to key it on, so the CPU is just probed again.
+    let ident = match cfg.probe_cache {
+        true => bridge.identifier(cfg).unwrap_or_else(|e| {
+            warn!("couldn't read the SoC identifier, so the probe cache won't be used: {}", e);
+            None
+        }),
+        false => None,
+    };
+    let cache_key = match ident {
+        Some(ident) if !ident.is_empty() => Some(format!(
+            "{:?} {:?} {:08x}",
+            ident, cfg.debug_backend, cfg.debug_offset
+        )),
+        _ => None,
+    };
+    let mut cpu = riscv::RiscvSystem::new_with_cache(
+        bridge,
+        cfg.debug_offset,
+        cfg.debug_backend.clone(),
+        cache_key.as_deref(),
+    )?;
+    cpu.set_reset_control(
+        cfg.reset_csr,
+        cfg.reset_vector,
+        Duration::from_millis(cfg.reset_settle as u64),
+    );
+    cpu.set_custom_csrs(&cfg.custom_csrs)?;
+    cpu.set_pseudo_registers(cfg.pseudo_registers.clone())?;
+    Ok(cpu)
+}
+
 pub fn gdb_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
-    let cpu = riscv::RiscvCpu::new(&bridge, cfg.debug_offset)?;
+    let mut cpu = attach_cpu(&cfg, &bridge)?;
+    cpu.set_memory_map(&cfg.memory_regions, spiflash::present(&cfg.register_mapping));
+    if cfg.semihosting {
+        cpu.enable_semihosting(&bridge)?;
+        cpu.set_file_io(cfg.gdb_fileio);
+    }
+    cpu.set_halt_disassembly(cfg.gdb_disasm);
+    for line in cpu.capabilities().to_string().lines() {
+        info!("{}", line);
+    }
+    // Over a bridge that takes milliseconds per access, GDB's chattiness
+    // adds up: let it ask for more at once, keep registers until the CPU
+    // runs, and check on the CPU less often.
+    match bridge.measure_latency() {
+        Ok(latency) if bridge.is_slow() => {
+            warn!(
+                "bridge takes {:?} per access, so registers will be cached and the CPU polled every {:?}",
+                latency, GDB_SLOW_POLL_INTERVAL
+            );
+            cpu.set_register_caching(true);
+        }
+        Ok(latency) => debug!("bridge takes {:?} per access", latency),
+        Err(e) => warn!("couldn't time the bridge: {}", e),
+    }
+    let poll_interval = if bridge.is_slow() {
+        GDB_SLOW_POLL_INTERVAL
+    } else {
+        GDB_POLL_INTERVAL
+    };
     // Enable messible support, but only if we're not also running a messible or wishbone server.
     let messible_address = if cfg.server_kind.contains(&ServerKind::Messible)
         || cfg.server_kind.contains(&ServerKind::Wishbone)
@@ -167,6 +603,15 @@ pub fn gdb_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError
     } else {
         cfg.messible_address
     };
+    // With --gdb-console, the console goes to GDB as well.  If the terminal
+    // is running it reads the UART and hands over what it got, otherwise
+    // the GDB server reads the UART itself.
+    let console = cfg.console_tap.clone();
+    let console_uart = if cfg.gdb_console && !cfg.server_kind.contains(&ServerKind::Terminal) {
+        Some(XoverUart::new(&cfg))
+    } else {
+        None
+    };
     loop {
         let connection = {
             let listener = match TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.gdb_port)) {
@@ -201,18 +646,53 @@ pub fn gdb_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError
         };
 
         let mut gdb = gdb::GdbServer::new(connection).unwrap();
+        gdb.set_linux_offsets(cfg.linux_offsets.clone());
+        gdb.set_memory_regions(cfg.memory_regions.clone());
+        gdb.set_flash(SpiFlash::new(&cfg));
+        gdb.set_csr_registers(cfg.csr_registers.clone());
+        if let Some(spiflash) = SpiFlash::new(&cfg) {
+            gdb.register_monitor_command(Box::new(FlashMonitor::new(spiflash)));
+        }
+        if cfg.gdb_prefetch {
+            gdb.set_prefetch(&cfg.memory_regions);
+        }
+        gdb.set_footguns(cfg.footguns.clone());
+        gdb.set_xlen(cpu.xlen());
+        gdb.set_clock_frequency(cfg.clock_frequency);
+        gdb.set_recording(cfg.gdb_record);
+        if bridge.is_slow() {
+            gdb.set_packet_size(GDB_SLOW_PACKET_SIZE);
+        }
         let cpu_controller = cpu.get_controller();
         let mut gdb_controller = gdb.get_controller();
-        if let Err(e) = cpu.halt(&bridge) {
+        // Every session starts out in all-stop mode, even if the last one
+        // ended in non-stop without detaching.  This halts the CPU.
+        if let Err(e) = cpu.set_non_stop(&bridge, false) {
             error!("couldn't halt CPU: {:?}", e);
             continue;
         }
+        cfg.footguns.set_gdb_halted(true);
 
         let poll_bridge = bridge.clone();
+        let console = console.clone();
+        let console_uart = console_uart.clone();
+        let footguns = cfg.footguns.clone();
         thread::spawn(move || loop {
             let mut had_error = false;
+            let mut stamper = LineStamper::default();
+            let mut running = false;
             loop {
                 let mut do_pause = true;
+                if let Some(tap) = &console {
+                    if let Some(uart) = &console_uart {
+                        read_console(uart, &poll_bridge, tap);
+                    }
+                    // GDB only takes console output while the CPU runs, so
+                    // anything printed while halted waits for a continue.
+                    if running {
+                        forward_console(tap, &mut stamper, &mut gdb_controller);
+                    }
+                }
                 match cpu_controller.poll(&poll_bridge, &mut gdb_controller) {
                     Err(e) => {
                         if !had_error {
@@ -220,8 +700,10 @@ pub fn gdb_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError
                             had_error = true;
                         }
                     }
-                    Ok(running) => {
+                    Ok(is_running) => {
+                        running = is_running;
                         had_error = false;
+                        footguns.set_gdb_halted(!running);
                         // If there's a messible available, poll it.
                         if running {
                             do_pause = !poll_messible(
@@ -234,7 +716,7 @@ pub fn gdb_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError
                 }
 
                 if do_pause {
-                    thread::park_timeout(Duration::from_millis(200));
+                    thread::park_timeout(poll_interval);
                 }
             }
         });
@@ -255,7 +737,18 @@ pub fn gdb_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError
                 }
                 break;
             }
+            if let Err(e) = bridge.barrier() {
+                error!("posted write failed: {}", e);
+            }
+        }
+        // A client that went away without detaching leaves its breakpoints
+        // patched into memory
+        if cpu.soft_breakpoints() > 0 {
+            if let Err(e) = cpu.clear_soft_breakpoints(&bridge) {
+                error!("couldn't remove software breakpoints: {}", e);
+            }
         }
+        cfg.footguns.set_gdb_halted(false);
     }
 }
 
@@ -325,6 +818,10 @@ pub fn wishbone_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), Server
                 println!("Error in Wishbone server: {:?}", e);
                 break;
             }
+            if let Err(e) = bridge.barrier() {
+                println!("Error in Wishbone server: posted write failed: {}", e);
+                break;
+            }
         }
     }
 }
@@ -380,13 +877,69 @@ pub fn random_test(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerErro
     }
 }
 
+fn journal_note(cfg: &Config, text: String) {
+    if let Some(journal) = &cfg.journal {
+        journal.note(text);
+    }
+}
+
 pub fn memory_access(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
-    if let Some(addr) = cfg.memory_address {
+    if let Some(addr) = cfg.memory_register.as_ref().map(|r| r.address).or(cfg.memory_address) {
+        if cfg.memory_value.is_some() && !cfg.footguns.allow_write("memory-access", addr) {
+            return Err(ServerError::WriteRefused(addr));
+        }
+    }
+    if let Some(hart) = cfg.hart {
+        return hart_access(&cfg, &bridge, hart);
+    }
+    if let Some(reg) = &cfg.memory_register {
         if let Some(value) = cfg.memory_value {
+            let mut verifier = WriteVerifier::new(&cfg);
+            for (word, v) in reg.split(value).iter().enumerate() {
+                bridge.poke(reg.address + word as u32 * 4, *v)?;
+                verifier.check(&bridge, reg.address + word as u32 * 4, *v)?;
+            }
+            verifier.report()?;
+        } else {
+            let value = ecc::read_counter(&bridge, reg)?;
+            println!("Value of {} at {:08x}: {:x}", reg.name, reg.address, value);
+            journal_note(&cfg, format!("{} = {:x}", reg.name, value));
+        }
+    } else if let Some(addr) = cfg.memory_address {
+        if regions::needs_words(&cfg.memory_regions, addr, 4) {
+            // The region can't take an unaligned word, so split it across
+            // the two aligned words it straddles.
+            info!("{:08x} is unaligned in a word-only region, realigning", addr);
+            // The config makes sure a value for an address fits in a word
+            if let Some(value) = cfg.memory_value.map(|v| v as u32) {
+                regions::write_bytes(
+                    addr,
+                    &value.to_le_bytes(),
+                    |a| bridge.peek(a),
+                    |a, v| bridge.poke(a, v),
+                )?;
+                if cfg.verify {
+                    let data = regions::read_bytes(addr, 4, |a| bridge.peek(a))?;
+                    let observed = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    let mut verifier = WriteVerifier::new(&cfg);
+                    verifier.compare(addr, value, observed, 0xffff_ffff);
+                    verifier.report()?;
+                }
+            } else {
+                let data = regions::read_bytes(addr, 4, |a| bridge.peek(a))?;
+                let val = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                println!("Value at {:08x}: {:08x}", addr, val);
+                journal_note(&cfg, format!("{:08x} = {:08x}", addr, val));
+            }
+        } else if let Some(value) = cfg.memory_value.map(|v| v as u32) {
             bridge.poke(addr, value)?;
+            let mut verifier = WriteVerifier::new(&cfg);
+            verifier.check(&bridge, addr, value)?;
+            verifier.report()?;
         } else {
             let val = bridge.peek(addr)?;
             println!("Value at {:08x}: {:08x}", addr, val);
+            journal_note(&cfg, format!("{:08x} = {:08x}", addr, val));
         }
     } else {
         println!("No operation and no address specified!");
@@ -397,19 +950,119 @@ pub fn memory_access(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerEr
     Ok(())
 }
 
+/// Read or write as `hart` sees things, through its debug interface.  The
+/// CPU has to be halted for that, so it's stopped for as long as it takes
+/// and then let go again if it was running.  Resuming writes back the
+/// registers that were changed, so a CPU that stays halted has them
+/// written back here instead.
+fn hart_access(cfg: &Config, bridge: &bridge::Bridge, hart: u32) -> Result<(), ServerError> {
+    let cpu = attach_cpu(cfg, bridge)?;
+    let was_running = cpu.poll_state(bridge)? == riscv::CpuState::Running;
+    cpu.halt(bridge)?;
+    let result = cpu
+        .select_hart(bridge, hart)
+        .map_err(ServerError::from)
+        .and_then(|()| hart_access_halted(cfg, bridge, &cpu, hart));
+    if was_running {
+        cpu.resume(bridge)?;
+    } else {
+        cpu.write_back_registers(bridge)?;
+    }
+    result
+}
+
+fn hart_access_halted(
+    cfg: &Config,
+    bridge: &bridge::Bridge,
+    cpu: &riscv::RiscvSystem,
+    hart: u32,
+) -> Result<(), ServerError> {
+    let digits = cpu.xlen().bits() as usize / 4;
+    match cfg.cpu_register.as_deref() {
+        Some("regs") => {
+            for idx in 0..33 {
+                let name = if idx == 32 { "pc".to_owned() } else { format!("x{}", idx) };
+                let value = cpu.read_register_wide(bridge, idx)?;
+                let sep = if idx % 4 == 3 || idx == 32 { "\n" } else { "  " };
+                print!("{:>4} {:0width$x}{}", name, value, sep, width = digits);
+            }
+        }
+        Some(name) => {
+            let idx = cpu
+                .register_by_name(name)
+                .ok_or_else(|| riscv::RiscvCpuError::UnknownRegister(name.to_owned()))?;
+            if let Some(value) = cfg.memory_value {
+                cpu.write_register_wide(bridge, idx, value)?;
+            } else {
+                let value = cpu.read_register_wide(bridge, idx)?;
+                println!("Value of {} on hart {}: {:0width$x}", name, hart, value, width = digits);
+                journal_note(cfg, format!("hart {} {} = {:x}", hart, name, value));
+            }
+        }
+        None => {
+            let (addr, words) = match &cfg.memory_register {
+                Some(reg) => (reg.address, reg.words),
+                None => (cfg.memory_address.unwrap(), 1),
+            };
+            if let Some(value) = cfg.memory_value {
+                let values = match &cfg.memory_register {
+                    Some(reg) => reg.split(value),
+                    None => vec![value as u32],
+                };
+                let mut verifier = WriteVerifier::new(cfg);
+                for (word, v) in values.iter().enumerate() {
+                    let word_addr = addr + word as u32 * 4;
+                    cpu.write_memory(bridge, word_addr, 4, *v)?;
+                    if cfg.verify {
+                        verifier.compare(word_addr, *v, cpu.read_memory(bridge, word_addr, 4)?, 0xffff_ffff);
+                    }
+                }
+                verifier.report()?;
+            } else if let Some(reg) = &cfg.memory_register {
+                let mut data = vec![];
+                for word in 0..words {
+                    data.push(cpu.read_memory(bridge, addr + word * 4, 4)?);
+                }
+                let value = reg.assemble(&data);
+                println!("Value of {} at {:08x} on hart {}: {:x}", reg.name, addr, hart, value);
+                journal_note(cfg, format!("hart {} {} = {:x}", hart, reg.name, value));
+            } else {
+                let value = cpu.read_memory(bridge, addr, 4)?;
+                println!("Value at {:08x} on hart {}: {:08x}", addr, hart, value);
+                journal_note(cfg, format!("hart {} {:08x} = {:08x}", hart, addr, value));
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn load_file(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
     let mut loop_counter: u32 = 0;
     if let Some(file_name) = &cfg.load_name {
+        // A UF2 image says where each part of it goes
+        let data = std::fs::read(file_name)?;
+        if uf2::is_uf2(&data) {
+            if cfg.load_addr.is_some() {
+                return Err(uf2::Uf2Error::AddressGiven.into());
+            }
+            let mut verifier = WriteVerifier::new(&cfg);
+            for segment in uf2::parse(&data, cfg.uf2_family)? {
+                info!("loading {} bytes of {} to 0x{:08x}", segment.data.len(), file_name, segment.addr);
+                load_region(&bridge, segment.addr, &segment.data, &cfg.sparse, &mut verifier)?;
+            }
+            return verifier.report();
+        }
         if let Some(addr) = cfg.load_addr {
             info!("Loading {} values to 0x{:08x}", file_name, addr);
             let mut f = File::open(file_name)?;
             let f_len = f.metadata().unwrap().len() as u32;
+            let mut verifier = WriteVerifier::new(&cfg);
             loop {
                 let value = match f.read_u32::<LittleEndian>() {
                     Ok(x) => x,
                     Err(e) => {
                         error!("Error reading: {}", e);
-                        return Ok(());
+                        return verifier.report();
                     }
                 };
                 if (loop_counter % 1024) == 0 {
@@ -420,7 +1073,9 @@ pub fn load_file(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError>
                         (loop_counter * 100 / f_len)
                     );
                 }
-                bridge.poke(addr + loop_counter, value)?;
+                if load_word(&bridge, addr + loop_counter, value, &cfg.sparse)? {
+                    verifier.check(&bridge, addr + loop_counter, value)?;
+                }
                 loop_counter = loop_counter.wrapping_add(4);
             }
         } else {
@@ -433,6 +1088,10 @@ pub fn load_file(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError>
 }
 
 use terminal::{Action, Event, KeyCode, KeyEvent, KeyModifiers, Retrieved, Terminal, Value};
+
+/// Most keystrokes to hold back at once, which is about what the
+/// crossover UART's FIFO will take
+const WRITE_COMBINE_MAX: usize = 16;
 struct IOInterface {
     term: Terminal<std::io::Stdout>,
 }
@@ -459,8 +1118,25 @@ pub fn terminal_client(cfg: Config, bridge: bridge::Bridge) -> Result<(), Server
         .get("uart_xover_rxempty")
         .unwrap_or(&0xe0001820);
 
+    // With --write-combine, keystrokes are held back for up to the latency
+    // budget and then sent all at once, and the UART is only polled once
+    // per budget while it's quiet.  Otherwise every key goes straight out.
+    let mut keys: Vec<u32> = vec![];
+    let mut keys_since = Instant::now();
+    let mut last_poll = Instant::now();
+    let mut rx_busy = true;
+
     loop {
-        if poll_uart(xover_rxempty, &bridge)? {
+        let poll_due = match cfg.write_combine {
+            None => true,
+            Some(budget) => rx_busy || last_poll.elapsed() >= budget,
+        };
+        if poll_due {
+            last_poll = Instant::now();
+            rx_busy = false;
+        }
+        if poll_due && poll_uart(xover_rxempty, &bridge)? {
+            rx_busy = true;
             let mut char_buffer = vec![];
             while bridge.peek(xover_rxempty)? == 0 {
                 char_buffer.push(bridge.peek(xover_rxtx)? as u8);
@@ -468,6 +1144,11 @@ pub fn terminal_client(cfg: Config, bridge: bridge::Bridge) -> Result<(), Server
             }
             print!("{}", String::from_utf8_lossy(&char_buffer));
             stdout().flush().ok();
+            syslog::console(&char_buffer);
+            consolelog::console(&char_buffer);
+            if let Some(tap) = &cfg.console_tap {
+                tap.push(&char_buffer);
+            }
         }
 
         if let Retrieved::Event(event) = my_terminal
@@ -482,24 +1163,104 @@ pub fn terminal_client(cfg: Config, bridge: bridge::Bridge) -> Result<(), Server
                     code: KeyCode::Enter,
                     ..
                 })) => {
-                    bridge.poke(xover_rxtx, '\r' as u32)?;
-                    bridge.poke(xover_rxtx, '\n' as u32)?;
+                    keys.push('\r' as u32);
+                    keys.push('\n' as u32);
                 }
                 Some(Event::Key(KeyEvent {
                     code: KeyCode::Char('c'),
                     modifiers: KeyModifiers::CONTROL,
                 })) => return Ok(()),
+                Some(Event::Key(KeyEvent {
+                    code: KeyCode::Char(c @ 's'),
+                    modifiers: KeyModifiers::CONTROL,
+                }))
+                | Some(Event::Key(KeyEvent {
+                    code: KeyCode::Char(c @ 'r'),
+                    modifiers: KeyModifiers::CONTROL,
+                })) => {
+                    bridge.poke_all(xover_rxtx, &keys)?;
+                    keys.clear();
+                    terminal_transfer(&cfg, &bridge, c == 's');
+                }
                 Some(Event::Key(KeyEvent {
                     code: KeyCode::Char(e),
                     ..
-                })) => bridge.poke(xover_rxtx, e as u32)?,
+                })) => keys.push(e as u32),
                 Some(_event) => {
                     // println!("{:?}\r", event);
                 }
                 None => (),
             }
         }
+
+        if keys.is_empty() {
+            keys_since = Instant::now();
+            continue;
+        }
+        let flush_due = match cfg.write_combine {
+            None => true,
+            Some(budget) => keys_since.elapsed() >= budget || keys.len() >= WRITE_COMBINE_MAX,
+        };
+        if flush_due {
+            bridge.poke_all(xover_rxtx, &keys)?;
+            keys.clear();
+        }
+    }
+}
+
+/// Run an X/YMODEM transfer from inside the terminal.  Whatever happens
+/// is reported on the console, and the terminal carries on afterwards.
+fn terminal_transfer(cfg: &Config, bridge: &bridge::Bridge, sending: bool) {
+    use crate::xmodem::{self, ModemPort};
+    use std::io::stdout;
+    use std::path::Path;
+
+    let uart = XoverUart::new(cfg);
+    let port = ModemPort::new(&uart, bridge);
+    let result = if sending {
+        let filename = match &cfg.send_file {
+            Some(f) => f,
+            None => {
+                print!("\r\n[no file to send, use --send-file]\r\n");
+                stdout().flush().ok();
+                return;
+            }
+        };
+        print!("\r\n[sending {}]\r\n", filename);
+        stdout().flush().ok();
+        let name = Path::new(filename)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| filename.clone());
+        std::fs::read(filename)
+            .map_err(xmodem::ModemError::from)
+            .and_then(|data| {
+                xmodem::send(&port, &cfg.file_protocol, &name, &data)
+                    .map(|_| format!("sent {} bytes", data.len()))
+            })
+    } else {
+        print!("\r\n[waiting for a file]\r\n");
+        stdout().flush().ok();
+        xmodem::receive(&port, &cfg.file_protocol).and_then(|(name, data)| {
+            // Without --receive-file, use the name the sender gave, but
+            // never let it pick the directory.
+            let filename = match (&cfg.receive_file, name) {
+                (Some(f), _) => f.clone(),
+                (None, Some(n)) if !n.is_empty() => Path::new(&n)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or(n),
+                _ => "received.bin".to_owned(),
+            };
+            std::fs::write(&filename, &data)?;
+            Ok(format!("saved {} bytes to {}", data.len(), filename))
+        })
+    };
+    match result {
+        Ok(msg) => print!("\r\n[{}]\r\n", msg),
+        Err(e) => print!("\r\n[transfer failed: {}]\r\n", e),
     }
+    stdout().flush().ok();
 }
 
 impl IOInterface {
@@ -535,6 +1296,8 @@ pub fn messible_client(cfg: Config, bridge: bridge::Bridge) -> Result<(), Server
         if char_buffer.len() > 0 {
             print!("{}", String::from_utf8_lossy(&char_buffer));
             stdout().flush().ok();
+            syslog::console(&char_buffer);
+            consolelog::console(&char_buffer);
         }
 
         if let Retrieved::Event(event) = my_terminal
@@ -555,3 +1318,1752 @@ pub fn messible_client(cfg: Config, bridge: bridge::Bridge) -> Result<(), Server
         }
     }
 }
+
+/// Registers that trigger an action when written, rather than holding
+/// configuration.  These are never included in a state snapshot.
+fn csr_has_write_side_effects(reg: &CsrRegister) -> bool {
+    reg.name.ends_with("_ev_pending") || reg.name.ends_with("_rxtx") || reg.name == "ctrl_reset"
+}
+
+pub fn state_save(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    if cfg.state_file.is_some() {
+        state_save_csv(&cfg, &bridge)?;
+    }
+    if let Some(file_name) = &cfg.golden_file {
+        state_compare_golden(&cfg, &bridge, file_name)?;
+    }
+    Ok(())
+}
+
+fn state_save_csv(cfg: &Config, bridge: &bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because state_save() checked for a state file
+    let file_name = cfg.state_file.as_ref().unwrap();
+    let mut wtr = csv::Writer::from_path(file_name)?;
+    wtr.write_record(["name", "address", "value"])?;
+
+    let mut count = 0;
+    for reg in &cfg.csr_registers {
+        if reg.mode != CsrMode::ReadWrite || csr_has_write_side_effects(reg) {
+            continue;
+        }
+        for word in 0..reg.words {
+            let addr = reg.address + word * 4;
+            let value = bridge.peek(addr)?;
+            wtr.write_record(&[
+                reg.name.clone(),
+                format!("0x{:08x}", addr),
+                format!("0x{:08x}", value),
+            ])?;
+        }
+        count += 1;
+    }
+    wtr.flush()?;
+    info!("saved {} registers to {}", count, file_name);
+    Ok(())
+}
+
+/// Compare every CSR against a golden dump, or record the dump if there
+/// isn't one yet.  Unlike the state file, read-only registers are included,
+/// as identifiers and status bits are where a gateware change usually shows.
+fn state_compare_golden(
+    cfg: &Config,
+    bridge: &bridge::Bridge,
+    file_name: &str,
+) -> Result<(), ServerError> {
+    let mut fresh = vec![];
+    for reg in &cfg.csr_registers {
+        if csr_has_write_side_effects(reg) {
+            continue;
+        }
+        for word in 0..reg.words {
+            let addr = reg.address + word * 4;
+            fresh.push(GoldenWord::new(&reg.name, addr, bridge.peek(addr)?));
+        }
+    }
+
+    if !std::path::Path::new(file_name).exists() {
+        golden::write(file_name, &fresh)?;
+        info!(
+            "recorded {} CSR words to {} -- later runs will compare against it",
+            fresh.len(),
+            file_name
+        );
+        return Ok(());
+    }
+
+    let golden = golden::read(file_name)?;
+    let differences = golden::compare(&golden, &fresh);
+    for difference in &differences {
+        if difference.is_regression() {
+            error!("{}", difference);
+        } else {
+            info!("{}", difference);
+        }
+    }
+    let regressions = differences.iter().filter(|d| d.is_regression()).count();
+    if regressions > 0 {
+        error!(
+            "{} differences from {} across {} CSR words",
+            regressions,
+            file_name,
+            golden.len()
+        );
+        return Err(ServerError::GoldenMismatch(regressions));
+    }
+    info!("all {} CSR words match {}", golden.len(), file_name);
+    Ok(())
+}
+
+pub fn state_restore(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a state file
+    let file_name = cfg.state_file.as_ref().unwrap();
+    let mut rdr = csv::Reader::from_path(file_name)?;
+    let mut verifier = WriteVerifier::new(&cfg);
+
+    let mut count = 0;
+    for result in rdr.records() {
+        let record = result?;
+        let name = &record[0];
+        let addr = parse_u32(&record[1]).map_err(|_| {
+            ServerError::StateError(format!("invalid address for {}: {}", name, &record[1]))
+        })?;
+        let value = parse_u32(&record[2]).map_err(|_| {
+            ServerError::StateError(format!("invalid value for {}: {}", name, &record[2]))
+        })?;
+
+        // Make sure the snapshot came from the same gateware that we're
+        // restoring it to.
+        let reg = cfg.csr_registers.iter().find(|r| r.name == name);
+        match reg {
+            None => {
+                warn!("{} is not present in the csr map, skipping", name);
+                continue;
+            }
+            Some(reg) => {
+                if addr < reg.address || addr >= reg.address + reg.words * 4 {
+                    warn!(
+                        "{} @ 0x{:08x} is outside of 0x{:08x} in the csr map, skipping",
+                        name, addr, reg.address
+                    );
+                    continue;
+                }
+                if reg.mode != CsrMode::ReadWrite {
+                    warn!("{} is read-only, skipping", name);
+                    continue;
+                }
+            }
+        }
+        bridge.poke(addr, value)?;
+        verifier.check(&bridge, addr, value)?;
+        count += 1;
+    }
+    info!("restored {} CSR words from {}", count, file_name);
+    verifier.report()
+}
+
+/// A minimal GDB client, used to time requests through the GDB server.
+struct GdbBenchClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl GdbBenchClient {
+    fn connect(port: u16) -> io::Result<GdbBenchClient> {
+        let writer = TcpStream::connect(("127.0.0.1", port))?;
+        writer.set_nodelay(true)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(GdbBenchClient { reader, writer })
+    }
+
+    /// Send a packet and wait for its reply.
+    fn request(&mut self, pkt: &str) -> io::Result<Vec<u8>> {
+        let checksum = pkt.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        self.writer
+            .write_all(format!("${}#{:02x}", pkt, checksum).as_bytes())?;
+        loop {
+            let reply = self.read_packet()?;
+            // Console output is sent as "O" packets, and isn't the reply.
+            if reply.first() == Some(&b'O') && reply != b"OK" {
+                continue;
+            }
+            return Ok(reply);
+        }
+    }
+
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut byte = [0; 1];
+        // Skip over any acks until the start of the packet
+        loop {
+            self.reader.read_exact(&mut byte)?;
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut packet = vec![];
+        self.reader.read_until(b'#', &mut packet)?;
+        packet.pop();
+        let mut checksum = [0; 2];
+        self.reader.read_exact(&mut checksum)?;
+        Ok(packet)
+    }
+}
+
+/// Time `iterations` runs of `op`.
+fn bench_op<F>(iterations: u32, mut op: F) -> Result<Vec<Duration>, ServerError>
+where
+    F: FnMut() -> Result<(), ServerError>,
+{
+    let mut samples = vec![];
+    for _ in 0..iterations {
+        let start = Instant::now();
+        op()?;
+        samples.push(start.elapsed());
+    }
+    Ok(samples)
+}
+
+fn bench_mean(samples: &[Duration]) -> Duration {
+    samples.iter().sum::<Duration>() / samples.len() as u32
+}
+
+pub fn gdb_bench(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let iterations = cfg.bench_iterations;
+    let cpu = riscv::RiscvSystem::new(&bridge, cfg.debug_offset, cfg.debug_backend.clone())?;
+    cpu.halt(&bridge)?;
+
+    // Read from wherever the CPU is executing, unless told otherwise,
+    // since that's about the only address that's guaranteed to be memory.
+    let pc = cpu.read_register(&bridge, 32)?;
+    let addr = cfg.memory_address.unwrap_or(pc) & !3;
+    info!(
+        "running each test {} times, reading memory at 0x{:08x}",
+        iterations, addr
+    );
+
+    let mut results = vec![];
+    results.push((
+        "bridge peek",
+        "bridge",
+        bench_op(iterations, || {
+            bridge.peek(addr)?;
+            Ok(())
+        })?,
+    ));
+    results.push((
+        "register read",
+        "cpu",
+        bench_op(iterations, || {
+            cpu.read_register(&bridge, 32)?;
+            Ok(())
+        })?,
+    ));
+    results.push((
+        "single step",
+        "cpu",
+        bench_op(iterations, || {
+            cpu.step(&bridge)?;
+            Ok(())
+        })?,
+    ));
+    results.push((
+        "4 KiB read",
+        "bridge",
+        bench_op(iterations, || {
+            for offset in (0..4096).step_by(4) {
+                bridge.peek(addr + offset)?;
+            }
+            Ok(())
+        })?,
+    ));
+    results.push((
+        "4 KiB read",
+        "cpu",
+        bench_op(iterations, || {
+            for offset in (0..4096).step_by(4) {
+                cpu.read_memory(&bridge, addr + offset, 4)?;
+            }
+            Ok(())
+        })?,
+    ));
+
+    // Run a GDB server on a loopback port, and time requests made through it.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let server_bridge = bridge.clone();
+    let server = thread::spawn(move || -> Result<(), ServerError> {
+        let (connection, _sockaddr) = listener.accept()?;
+        let mut gdb = gdb::GdbServer::new(connection)?;
+        gdb.set_xlen(cpu.xlen());
+        loop {
+            let cmd = match gdb.get_command() {
+                Err(gdb::GdbServerError::ConnectionClosed) => return Ok(()),
+                Err(e) => return Err(e.into()),
+                Ok(o) => o,
+            };
+            gdb.process(cmd, &cpu, &server_bridge)?;
+        }
+    });
+
+    let client_results = GdbBenchClient::connect(port).and_then(|mut client| {
+        let mut time_request = |pkt: &str| -> io::Result<Vec<Duration>> {
+            let mut samples = vec![];
+            for _ in 0..iterations {
+                let start = Instant::now();
+                client.request(pkt)?;
+                samples.push(start.elapsed());
+            }
+            Ok(samples)
+        };
+        Ok(vec![
+            ("register read", "gdb", time_request("p20")?),
+            ("single step", "gdb", time_request("vCont;s:0")?),
+            ("4 KiB read", "gdb", time_request(&format!("m{:x},1000", addr))?),
+        ])
+    });
+
+    // If the GDB server failed, the client just sees a closed connection,
+    // so report the server's error in preference to the client's.
+    server.join().unwrap()?;
+    results.extend(client_results?);
+
+    println!(
+        "{:<16} {:<8} {:>12} {:>12} {:>12}",
+        "operation", "layer", "min (us)", "mean (us)", "max (us)"
+    );
+    for (operation, layer, samples) in &results {
+        println!(
+            "{:<16} {:<8} {:>12} {:>12} {:>12}",
+            operation,
+            layer,
+            samples.iter().min().unwrap().as_micros(),
+            bench_mean(samples).as_micros(),
+            samples.iter().max().unwrap().as_micros()
+        );
+    }
+
+    // Attribute the time for a 4 KiB read to each layer of the stack.
+    let mean_of = |operation: &str, layer: &str| {
+        results
+            .iter()
+            .find(|r| r.0 == operation && r.1 == layer)
+            .map(|r| bench_mean(&r.2).as_secs_f64())
+            .unwrap()
+    };
+    let bridge_time = mean_of("4 KiB read", "bridge");
+    let cpu_time = mean_of("4 KiB read", "cpu").max(bridge_time);
+    let gdb_time = mean_of("4 KiB read", "gdb").max(cpu_time);
+    println!(
+        "4 KiB read time spent: bridge {:.1}%, wishbone-tool {:.1}%, gdb protocol {:.1}%",
+        100.0 * bridge_time / gdb_time,
+        100.0 * (cpu_time - bridge_time) / gdb_time,
+        100.0 * (gdb_time - cpu_time) / gdb_time
+    );
+    info!("benchmark complete, CPU has been left halted");
+    Ok(())
+}
+
+pub fn coverage(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let base = cfg.memory_address.unwrap();
+    let file_name = cfg.coverage_file.unwrap();
+    let cpu = riscv::RiscvSystem::new(&bridge, cfg.debug_offset, cfg.debug_backend.clone())?;
+    let mut bitmap = CoverageBitmap::new(base, cfg.coverage_size);
+
+    info!(
+        "recording coverage of 0x{:08x} - 0x{:08x}",
+        base,
+        base.wrapping_add(cfg.coverage_size)
+    );
+    cpu.halt(&bridge)?;
+    for sample in 0..cfg.coverage_samples {
+        let pc = if cfg.coverage_mode == CoverageMode::Step {
+            cpu.step(&bridge)?.0 as u32
+        } else {
+            // The time it takes to get the halt across the bridge is
+            // what spreads the samples out.
+            cpu.resume(&bridge)?;
+            cpu.halt(&bridge)?;
+            cpu.read_register(&bridge, 32)?
+        };
+        bitmap.record(pc);
+
+        if (sample % 1000) == 0 {
+            info!("sample {}: {} addresses covered", sample, bitmap.covered());
+        }
+    }
+    cpu.resume(&bridge)?;
+
+    bitmap.export(&file_name)?;
+    info!(
+        "wrote {} addresses to {} ({} samples were outside the region)",
+        bitmap.covered(),
+        file_name,
+        bitmap.misses()
+    );
+    Ok(())
+}
+
+/// Read the --load-name file, returning its entry point and the memory
+/// it occupies.  Raw binaries get loaded to, and started from, --load-address.
+/// UF2 images are started from --load-address too, or else from their
+/// lowest address.
+fn firmware_segments(cfg: &Config) -> Result<(u32, Vec<elf::ElfSegment>), ServerError> {
+    let file_name = cfg.load_name.clone().unwrap();
+    let data = std::fs::read(&file_name)?;
+    if elf::is_elf(&data) {
+        let image = elf::ElfImage::parse(&data)?;
+        Ok((image.entry, image.segments))
+    } else if uf2::is_uf2(&data) {
+        let segments = uf2::parse(&data, cfg.uf2_family)?;
+        let entry = cfg.load_addr.unwrap_or(segments[0].addr);
+        Ok((entry, segments))
+    } else if let Some(addr) = cfg.load_addr {
+        Ok((addr, vec![elf::ElfSegment { addr, data }]))
+    } else {
+        Err(ServerError::ElfError(elf::ElfError::NotElf))
+    }
+}
+
+pub fn run_firmware(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let (entry, segments) = firmware_segments(&cfg)?;
+
+    let cpu = riscv::RiscvSystem::new(&bridge, cfg.debug_offset, cfg.debug_backend.clone())?;
+    cpu.halt(&bridge)?;
+    let mut verifier = WriteVerifier::new(&cfg);
+    for segment in &segments {
+        info!(
+            "loading {} bytes to 0x{:08x}",
+            segment.data.len(),
+            segment.addr
+        );
+        load_region(&bridge, segment.addr, &segment.data, &cfg.sparse, &mut verifier)?;
+    }
+    verifier.report()?;
+
+    let uart = XoverUart::new(&cfg);
+
+    // Drain anything left over from before the reset.
+    while uart.read_byte(&bridge)?.is_some() {}
+
+    info!("starting firmware at 0x{:08x}", entry);
+    cpu.reset_cpu(&bridge)?;
+    cpu.write_register(&bridge, 32, entry)?;
+    cpu.resume(&bridge)?;
+
+    // Each pattern must appear after the one before it.
+    let deadline = Instant::now() + cfg.timeout;
+    let mut output: Vec<u8> = vec![];
+    let mut search_from = 0;
+    let mut results = vec![];
+    let mut pattern_start = Instant::now();
+    let mut patterns = cfg.expect.iter().peekable();
+    while let Some(&pattern) = patterns.peek() {
+        let seen = output.len();
+        while let Some(c) = uart.read_byte(&bridge)? {
+            print!("{}", c as char);
+            output.push(c);
+        }
+        syslog::console(&output[seen..]);
+        consolelog::console(&output[seen..]);
+        io::stdout().flush()?;
+
+        if let Some(pos) = output[search_from..]
+            .windows(pattern.len())
+            .position(|w| w == pattern.as_bytes())
+        {
+            info!("matched \"{}\"", pattern);
+            search_from += pos + pattern.len();
+            results.push(TestResult {
+                name: pattern.to_string(),
+                elapsed: pattern_start.elapsed(),
+                failure: None,
+            });
+            pattern_start = Instant::now();
+            patterns.next();
+            continue;
+        }
+
+        if Instant::now() > deadline {
+            error!(
+                "timed out after {:?} waiting for \"{}\"",
+                cfg.timeout, pattern
+            );
+            results.push(TestResult {
+                name: pattern.to_string(),
+                elapsed: pattern_start.elapsed(),
+                failure: Some(format!("timed out after {:?}", cfg.timeout)),
+            });
+            patterns.next();
+            for unreached in patterns {
+                results.push(TestResult {
+                    name: unreached.to_string(),
+                    elapsed: Duration::from_secs(0),
+                    failure: Some("an earlier pattern never matched".to_owned()),
+                });
+            }
+            run_report(&cfg, "run", &results)?;
+            return Err(ServerError::ExpectTimeout(pattern.to_string()));
+        }
+        thread::park_timeout(Duration::from_millis(10));
+    }
+    info!("all patterns matched");
+    run_report(&cfg, "run", &results)
+}
+
+/// Write test results out in the --report format, if one was given.  The
+/// suite is named after the --load-name, if there is one.
+fn run_report(cfg: &Config, suite: &str, results: &[TestResult]) -> Result<(), ServerError> {
+    let format = match &cfg.report_format {
+        Some(f) => f,
+        None => return Ok(()),
+    };
+    let suite = cfg.load_name.as_deref().unwrap_or(suite);
+    match &cfg.report_file {
+        Some(file_name) => {
+            let mut f = File::create(file_name)?;
+            report::write_report(format, suite, results, &mut f)?;
+        }
+        None => {
+            println!();
+            report::write_report(format, suite, results, &mut io::stdout())?;
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the BIOS to acknowledge the last frame, echoing anything else
+/// it prints along the way.
+fn sfl_wait_ack(
+    uart: &XoverUart,
+    bridge: &bridge::Bridge,
+    deadline: Instant,
+) -> Result<u8, ServerError> {
+    loop {
+        if let Some(c) = uart.read_byte(bridge)? {
+            match c {
+                sfl::ACK_SUCCESS | sfl::ACK_CRCERROR => return Ok(c),
+                sfl::ACK_UNKNOWN => {
+                    return Err(ServerError::SerialBootError(
+                        "BIOS didn't recognize the command".to_owned(),
+                    ))
+                }
+                sfl::ACK_ERROR => {
+                    return Err(ServerError::SerialBootError(
+                        "BIOS reported an error".to_owned(),
+                    ))
+                }
+                other => {
+                    print!("{}", other as char);
+                    io::stdout().flush()?;
+                }
+            }
+        } else if Instant::now() > deadline {
+            return Err(ServerError::SerialBootError(
+                "timed out waiting for the BIOS to respond".to_owned(),
+            ));
+        }
+    }
+}
+
+/// Send a frame, resending it if it arrives corrupted.
+fn sfl_send_frame(
+    uart: &XoverUart,
+    bridge: &bridge::Bridge,
+    frame: &[u8],
+    timeout: Duration,
+) -> Result<(), ServerError> {
+    for _ in 0..5 {
+        uart.write(bridge, frame)?;
+        if sfl_wait_ack(uart, bridge, Instant::now() + timeout)? == sfl::ACK_SUCCESS {
+            return Ok(());
+        }
+        warn!("CRC error sending frame, retrying");
+    }
+    uart.write(bridge, &sfl::encode_frame(sfl::CMD_ABORT, &[]))?;
+    Err(ServerError::SerialBootError(
+        "too many CRC errors, giving up".to_owned(),
+    ))
+}
+
+pub fn serial_boot(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let (entry, segments) = firmware_segments(&cfg)?;
+    let uart = XoverUart::new(&cfg);
+
+    // Echo the console until the BIOS asks for an image, which happens
+    // when it boots or when someone types "serialboot".
+    info!("waiting for the BIOS to start serialboot");
+    let deadline = Instant::now() + cfg.timeout;
+    let mut window: Vec<u8> = vec![];
+    while !window.ends_with(sfl::MAGIC_REQUEST) {
+        match uart.read_byte(&bridge)? {
+            Some(c) => {
+                print!("{}", c as char);
+                io::stdout().flush()?;
+                window.push(c);
+                if window.len() > sfl::MAGIC_REQUEST.len() {
+                    window.remove(0);
+                }
+            }
+            None if Instant::now() > deadline => {
+                return Err(ServerError::SerialBootError(
+                    "timed out waiting for serialboot to start".to_owned(),
+                ))
+            }
+            None => thread::park_timeout(Duration::from_millis(10)),
+        }
+    }
+    uart.write(&bridge, sfl::MAGIC_ACK)?;
+
+    let chunk_size = sfl::PAYLOAD_LENGTH - 4;
+    for segment in &segments {
+        info!(
+            "sending {} bytes to 0x{:08x}",
+            segment.data.len(),
+            segment.addr
+        );
+        for (idx, chunk) in segment.data.chunks(chunk_size).enumerate() {
+            let addr = segment.addr + (idx * chunk_size) as u32;
+            sfl_send_frame(&uart, &bridge, &sfl::load_frame(addr, chunk), cfg.timeout)?;
+        }
+    }
+
+    info!("jumping to 0x{:08x}", entry);
+    sfl_send_frame(&uart, &bridge, &sfl::jump_frame(entry), cfg.timeout)?;
+    Ok(())
+}
+
+/// Write one word of an image, unless `sparse` says it can be skipped.
+/// Returns `true` if the word was written.
+fn load_word(
+    bridge: &bridge::Bridge,
+    addr: u32,
+    value: u32,
+    sparse: &SparseMode,
+) -> Result<bool, ServerError> {
+    if value == 0 || value == 0xffff_ffff {
+        match sparse {
+            SparseMode::Off => (),
+            SparseMode::Skip => return Ok(false),
+            SparseMode::Verify => {
+                if bridge.peek(addr)? == value {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+    bridge.poke(addr, value)?;
+    Ok(true)
+}
+
+/// Most mismatches that --verify lists before summing up the rest
+const VERIFY_REPORT_MAX: usize = 16;
+
+/// With --verify, reads back what was written and collects the words that
+/// didn't stick.  Registers that the csr map says can't be read back as
+/// written are left alone.
+struct WriteVerifier<'a> {
+    cfg: &'a Config,
+
+    /// (address, written, read back)
+    mismatches: Vec<(u32, u32, u32)>,
+
+    /// Number of writes that were left unchecked
+    unverifiable: u32,
+}
+
+impl<'a> WriteVerifier<'a> {
+    fn new(cfg: &'a Config) -> WriteVerifier<'a> {
+        WriteVerifier {
+            cfg,
+            mismatches: vec![],
+            unverifiable: 0,
+        }
+    }
+
+    /// The bits of `addr` that should read back as written, or `None` if
+    /// it's a register that doesn't.
+    fn readable_bits(&self, addr: u32) -> Option<u32> {
+        let reg = self
+            .cfg
+            .csr_registers
+            .iter()
+            .find(|r| addr >= r.address && addr < r.address + r.words * 4);
+        match reg {
+            Some(reg) if reg.mode != CsrMode::ReadWrite || csr_has_write_side_effects(reg) => None,
+            Some(reg) => Some(reg.word_mask() as u32),
+            None => Some(0xffff_ffff),
+        }
+    }
+
+    /// Make sure `value` landed at `addr`.
+    fn check(&mut self, bridge: &bridge::Bridge, addr: u32, value: u32) -> Result<(), ServerError> {
+        if !self.cfg.verify {
+            return Ok(());
+        }
+        match self.readable_bits(addr) {
+            Some(mask) => {
+                let observed = bridge.peek(addr)?;
+                self.compare(addr, value, observed, mask);
+            }
+            None => self.unverifiable += 1,
+        }
+        Ok(())
+    }
+
+    /// Make sure `data` landed at `addr`, reading it back in bursts.
+    fn check_region(&mut self, bridge: &bridge::Bridge, addr: u32, data: &[u8]) -> Result<(), ServerError> {
+        if !self.cfg.verify {
+            return Ok(());
+        }
+        let observed = bridge.burst_read(addr, data.len() as u32)?;
+        for (idx, (wrote, read)) in data.chunks(4).zip(observed.chunks(4)).enumerate() {
+            let mut expected = [0; 4];
+            let mut actual = [0; 4];
+            expected[..wrote.len()].copy_from_slice(wrote);
+            actual[..read.len()].copy_from_slice(read);
+            let word_addr = addr + idx as u32 * 4;
+            match self.readable_bits(word_addr) {
+                Some(mask) => self.compare(
+                    word_addr,
+                    u32::from_le_bytes(expected),
+                    u32::from_le_bytes(actual),
+                    mask,
+                ),
+                None => self.unverifiable += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn compare(&mut self, addr: u32, expected: u32, observed: u32, mask: u32) {
+        if (expected ^ observed) & mask != 0 {
+            self.mismatches.push((addr, expected, observed));
+        }
+    }
+
+    /// Report what's been found so far, failing if anything didn't match.
+    fn report(&mut self) -> Result<(), ServerError> {
+        let unverifiable = std::mem::replace(&mut self.unverifiable, 0);
+        if unverifiable > 0 {
+            info!("didn't verify {} writes to registers that can't be read back", unverifiable);
+        }
+        if self.mismatches.is_empty() {
+            return Ok(());
+        }
+        for (addr, expected, observed) in self.mismatches.iter().take(VERIFY_REPORT_MAX) {
+            error!(
+                "verify failed at {:08x}: wrote {:08x}, read back {:08x}",
+                addr, expected, observed
+            );
+        }
+        if self.mismatches.len() > VERIFY_REPORT_MAX {
+            error!("... and {} more mismatches", self.mismatches.len() - VERIFY_REPORT_MAX);
+        }
+        Err(ServerError::VerifyFailed(self.mismatches.len()))
+    }
+}
+
+/// Write `data` to memory starting at `addr`, padding the final word
+/// with zeroes.
+fn load_region(
+    bridge: &bridge::Bridge,
+    addr: u32,
+    data: &[u8],
+    sparse: &SparseMode,
+    verifier: &mut WriteVerifier,
+) -> Result<(), ServerError> {
+    if let SparseMode::Off = sparse {
+        bridge.burst_write(addr, data)?;
+        return verifier.check_region(bridge, addr, data);
+    }
+    let mut skipped = 0;
+    for (idx, word) in data.chunks(4).enumerate() {
+        let mut buf = [0; 4];
+        buf[..word.len()].copy_from_slice(word);
+        let word_addr = addr + (idx as u32 * 4);
+        let value = u32::from_le_bytes(buf);
+        if load_word(bridge, word_addr, value, sparse)? {
+            verifier.check(bridge, word_addr, value)?;
+        } else {
+            skipped += 1;
+        }
+    }
+    if skipped > 0 {
+        info!("skipped writing {} words of fill", skipped);
+    }
+    Ok(())
+}
+
+/// Read `len` bytes of memory starting at `addr`, spread across every
+/// connection that was opened for the transfer.
+fn dump_region(
+    connections: &[bridge::Bridge],
+    addr: u32,
+    len: u32,
+) -> Result<Vec<u8>, ServerError> {
+    Ok(bridge::parallel_read(connections, addr, len)?)
+}
+
+pub fn transfer(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    debug!("transferring in bursts of up to {} bytes", bridge.burst_size());
+    let connections = if cfg.transfers.iter().any(|t| t.kind == TransferKind::Dump) {
+        bridge.connections(&cfg, cfg.connections)?
+    } else {
+        vec![bridge.clone()]
+    };
+    if connections.len() > 1 {
+        info!("dumping over {} connections", connections.len());
+    }
+    for transfer in &cfg.transfers {
+        match transfer.kind {
+            TransferKind::Load => {
+                let data = std::fs::read(&transfer.file)?;
+                info!(
+                    "loading {} ({} bytes) to 0x{:08x}",
+                    transfer.file,
+                    data.len(),
+                    transfer.addr
+                );
+                let len = data.len() as u32;
+                let mut offset = resume_offset(&cfg, transfer, len, &data);
+                let mut crc = checkpoint::crc32(0, &data[..offset as usize]);
+                let mut verifier = WriteVerifier::new(&cfg);
+                while offset < len {
+                    let end = len.min(offset + CHECKPOINT_INTERVAL);
+                    let chunk = &data[offset as usize..end as usize];
+                    load_region(&bridge, transfer.addr + offset, chunk, &cfg.sparse, &mut verifier)?;
+                    verifier.report()?;
+                    crc = checkpoint::crc32(crc, chunk);
+                    offset += chunk.len() as u32;
+                    save_checkpoint(transfer, len, offset, crc)?;
+                }
+                Checkpoint::remove(&transfer.file);
+            }
+            TransferKind::Dump => {
+                info!(
+                    "dumping {} bytes from 0x{:08x} to {}",
+                    transfer.len, transfer.addr, transfer.file
+                );
+                let existing = if cfg.resume {
+                    std::fs::read(&transfer.file).unwrap_or_default()
+                } else {
+                    vec![]
+                };
+                let mut offset = resume_offset(&cfg, transfer, transfer.len, &existing);
+                let mut crc = checkpoint::crc32(0, &existing[..offset as usize]);
+
+                // Throw away anything past the checkpoint, since it may
+                // not have been completely written.
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(offset == 0)
+                    .open(&transfer.file)?;
+                file.set_len(offset as u64)?;
+                file.seek(io::SeekFrom::Start(offset as u64))?;
+                while offset < transfer.len {
+                    let count = (transfer.len - offset).min(CHECKPOINT_INTERVAL);
+                    let data = dump_region(&connections, transfer.addr + offset, count)?;
+                    file.write_all(&data)?;
+                    file.sync_data()?;
+                    crc = checkpoint::crc32(crc, &data);
+                    offset += count;
+                    save_checkpoint(transfer, transfer.len, offset, crc)?;
+                }
+                Checkpoint::remove(&transfer.file);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where to start `transfer` from.  With --resume, that's wherever the
+/// last attempt got to, as long as its checkpoint still matches the file.
+fn resume_offset(cfg: &Config, transfer: &Transfer, len: u32, data: &[u8]) -> u32 {
+    if !cfg.resume {
+        return 0;
+    }
+    match Checkpoint::load(&transfer.file) {
+        Some(ckpt) if ckpt.matches(transfer.addr, len, data) => {
+            info!(
+                "resuming {} at offset {} of {}",
+                transfer.file, ckpt.offset, len
+            );
+            ckpt.offset
+        }
+        Some(_) => {
+            warn!(
+                "checkpoint for {} doesn't match this transfer, starting over",
+                transfer.file
+            );
+            0
+        }
+        None => {
+            info!("no checkpoint for {}, starting from the beginning", transfer.file);
+            0
+        }
+    }
+}
+
+/// Only transfers long enough to be worth resuming leave a checkpoint.
+fn save_checkpoint(transfer: &Transfer, len: u32, offset: u32, crc: u32) -> io::Result<()> {
+    if len <= CHECKPOINT_INTERVAL || offset >= len {
+        return Ok(());
+    }
+    Checkpoint {
+        addr: transfer.addr,
+        len,
+        offset,
+        crc,
+    }
+    .save(&transfer.file)
+}
+
+pub fn ecc(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    for controller in EccController::discover(&cfg.csr_registers) {
+        let counts = controller.read(&bridge)?;
+        println!(
+            "{}: {} correctable, {} uncorrectable",
+            controller.name, counts.correctable, counts.uncorrectable
+        );
+        if cfg.ecc_clear && !controller.clear(&bridge)? {
+            warn!("{} has no way to clear its counters", controller.name);
+        }
+        if cfg.ecc_scrub {
+            if controller.scrub(&bridge)? {
+                info!("started scrubbing {}", controller.name);
+            } else {
+                warn!("{} doesn't support scrubbing", controller.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Build a descriptor chain for the --dma-segment list and have the engine
+/// work through it, reporting how quickly it went.
+pub fn dma(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires an engine and somewhere
+    // to put the descriptors
+    let engine = cfg.dma_engine.as_ref().unwrap();
+    let base = cfg.dma_descriptors.unwrap();
+    let bytes: u64 = cfg.dma_segments.iter().map(|s| s.length as u64).sum();
+    for (index, Segment { address, length }) in cfg.dma_segments.iter().enumerate() {
+        debug!("segment {}: {} bytes at {:08x}", index, length, address);
+    }
+    let elapsed = engine.run(&bridge, base, &cfg.dma_segments, cfg.timeout)?;
+    let secs = elapsed.as_secs_f64();
+    info!(
+        "{} moved {} bytes in {} segments in {:?} ({:.0} bytes/s)",
+        engine.name,
+        bytes,
+        cfg.dma_segments.len(),
+        elapsed,
+        if secs > 0.0 { bytes as f64 / secs } else { 0.0 }
+    );
+    Ok(())
+}
+
+/// Run a PRBS test on each PHY, or just the one given with --phy, and
+/// report the errors and bit error rate.  Each PHY is a test case for
+/// --report.
+pub fn phy_test(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let mut results = vec![];
+    let mut total_errors = 0;
+    for phy in PrbsPhy::discover(&cfg.csr_registers) {
+        if cfg.phy.as_ref().is_some_and(|name| *name != phy.name) {
+            continue;
+        }
+        if cfg.phy_loopback.is_some() && !phy.has_loopback() {
+            warn!("{} has no loopback register, so it's tested as it's wired", phy.name);
+        }
+        info!(
+            "{}: running {} for {:?}",
+            phy.name,
+            cfg.prbs_pattern.name(),
+            cfg.phy_duration
+        );
+        let result = phy.run(&bridge, cfg.prbs_pattern, cfg.phy_loopback, cfg.phy_duration)?;
+        let ber = match cfg.phy_rate {
+            Some(rate) if result.errors == 0 => format!(", BER < {:.1e}", result.ber(rate)),
+            Some(rate) => format!(", BER {:.1e}", result.ber(rate)),
+            None => String::new(),
+        };
+        println!(
+            "{}: {} errors in {:.1}s{}",
+            phy.name,
+            result.errors,
+            result.elapsed.as_secs_f64(),
+            ber
+        );
+        total_errors += result.errors;
+        results.push(TestResult {
+            name: phy.name.clone(),
+            elapsed: result.elapsed,
+            failure: match result.errors {
+                0 => None,
+                n => Some(format!("{} bit errors{}", n, ber)),
+            },
+        });
+    }
+    run_report(&cfg, "phy-test", &results)?;
+    if total_errors > 0 {
+        return Err(ServerError::PrbsErrors(total_errors));
+    }
+    Ok(())
+}
+
+/// Cycle the --endurance-region until --endurance-duration is up, printing
+/// the error count so far and the sensors every --endurance-interval.
+pub fn endurance(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a region
+    let (base, len) = cfg.endurance_region.unwrap();
+    let spiflash = match cfg.endurance_target {
+        // unwrap() is safe because the config requires the SpiFlash CSRs
+        EnduranceTarget::Flash => {
+            // The footguns go by where the CPU sees the flash, if it does
+            let addr = cfg
+                .memory_regions
+                .iter()
+                .find(|r| r.kind == MemoryKind::Flash)
+                .map(|r| r.base.wrapping_add(base))
+                .unwrap_or(base);
+            if !cfg.footguns.allow_write("endurance", addr) {
+                return Err(ServerError::WriteRefused(addr));
+            }
+            let spiflash = SpiFlash::new(&cfg).unwrap();
+            spiflash.check_id(&bridge)?;
+            Some(spiflash)
+        }
+        EnduranceTarget::Ram => {
+            if !cfg.footguns.allow_write("endurance", base) {
+                return Err(ServerError::WriteRefused(base));
+            }
+            None
+        }
+    };
+    info!(
+        "cycling {:?} 0x{:08x} to 0x{:08x} for {:?}",
+        cfg.endurance_target,
+        base,
+        base as u64 + len as u64,
+        cfg.endurance_duration
+    );
+
+    let start = Instant::now();
+    let mut next_log = start + cfg.endurance_interval;
+    let mut cycles = 0;
+    let mut errors = 0;
+    let mut logged = None;
+    while start.elapsed() < cfg.endurance_duration {
+        let wrong =
+            endurance::run_cycle(&bridge, cfg.endurance_target, spiflash.as_ref(), cycles, base, len)?;
+        if wrong > 0 {
+            warn!(
+                "cycle {} ({}): {} words read back wrong",
+                cycles,
+                endurance::pattern_name(cycles),
+                wrong
+            );
+        }
+        errors += wrong;
+        cycles += 1;
+        if Instant::now() >= next_log {
+            endurance_log(&cfg, &bridge, start, cycles, errors)?;
+            logged = Some(cycles);
+            next_log += cfg.endurance_interval;
+        }
+    }
+    if logged != Some(cycles) {
+        endurance_log(&cfg, &bridge, start, cycles, errors)?;
+    }
+
+    let result = TestResult {
+        name: format!("{:?} 0x{:08x}+0x{:x}", cfg.endurance_target, base, len),
+        elapsed: start.elapsed(),
+        failure: match errors {
+            0 => None,
+            n => Some(format!("{} words read back wrong in {} cycles", n, cycles)),
+        },
+    };
+    run_report(&cfg, "endurance", &[result])?;
+    if errors > 0 {
+        return Err(ServerError::EnduranceErrors(errors));
+    }
+    Ok(())
+}
+
+/// How many accesses "stress" makes between saying how it's getting on
+const STRESS_PROGRESS: u64 = 100_000;
+
+/// Make --stress-ops random accesses across the --stress-region areas, or
+/// keep going until something reads back wrong.  A failure says which seed
+/// and how many accesses make it happen again.
+pub fn stress(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    for (base, _) in &cfg.stress_regions {
+        if !cfg.footguns.allow_write("stress", *base) {
+            return Err(ServerError::WriteRefused(*base));
+        }
+    }
+    let seed = cfg.stress_seed.unwrap_or_else(random::<u64>);
+    match cfg.stress_ops {
+        Some(ops) => info!("making {} accesses with --stress-seed {}", ops, seed),
+        None => info!("making accesses with --stress-seed {} until one fails", seed),
+    }
+
+    let mut stress = Stress::new(&cfg.stress_regions, seed);
+    let start = Instant::now();
+    let mut failure = None;
+    while cfg.stress_ops.is_none_or(|ops| stress.count() < ops) {
+        let again = format!("--stress-seed {} --stress-ops {}", seed, stress.count() + 1);
+        let (access, addr, wrong) = match stress.step(&bridge) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("access {} failed: {} (make it again with {})", stress.count(), e, again);
+                return Err(e.into());
+            }
+        };
+        if !wrong.is_empty() {
+            error!("access {} ({:?} at {:08x}) read back wrong:", stress.count(), access, addr);
+            for word in &wrong {
+                error!(
+                    "    {:08x}: {:08x}, but {:08x} was written",
+                    word.addr, word.observed, word.expected
+                );
+            }
+            error!("make it again with {}", again);
+            failure = Some(format!("access {} read back wrong, with {}", stress.count(), again));
+            break;
+        }
+        if stress.count().is_multiple_of(STRESS_PROGRESS) {
+            info!("{} accesses in {:?}, all good", stress.count(), start.elapsed());
+        }
+    }
+
+    let failed = failure.is_some();
+    let result = TestResult {
+        name: format!("seed {}", seed),
+        elapsed: start.elapsed(),
+        failure,
+    };
+    run_report(&cfg, "stress", &[result])?;
+    if failed {
+        return Err(ServerError::StressMismatch(seed, stress.count()));
+    }
+    if !stress.count().is_multiple_of(STRESS_PROGRESS) {
+        info!("{} accesses in {:?}, all good", stress.count(), start.elapsed());
+    }
+    Ok(())
+}
+
+fn endurance_log(
+    cfg: &Config,
+    bridge: &bridge::Bridge,
+    start: Instant,
+    cycles: u64,
+    errors: u64,
+) -> Result<(), ServerError> {
+    let mut line = format!(
+        "{:8}s  {} cycles  {} errors",
+        start.elapsed().as_secs(),
+        cycles,
+        errors
+    );
+    for sensor in &cfg.endurance_sensors {
+        let value = ecc::read_counter(bridge, sensor)?;
+        line.push_str(&format!("  {}={}", sensor.name, value));
+        // The 7-series XADC is a 12-bit reading of a 504K range
+        if sensor.name.contains("xadc") && sensor.name.ends_with("temperature") {
+            line.push_str(&format!(" ({:.1} C)", value as f64 * 503.975 / 4096.0 - 273.15));
+        }
+    }
+    println!("{}", line);
+    journal_note(cfg, line);
+    Ok(())
+}
+
+/// Work out each --calc expression, reading the CSRs it names from the
+/// device, and print the result in hex, decimal and binary.
+pub fn calc(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    for (label, expr) in &cfg.calc {
+        let value = expr.value_wide(&mut |name: &str| {
+            if let Some(value) = cfg.calc_constants.get(name) {
+                return Ok(*value);
+            }
+            // The config made sure that everything else is a CSR
+            let reg = cfg.csr_registers.iter().find(|r| r.name == name).unwrap();
+            ecc::read_counter(&bridge, reg)
+        })?;
+        println!("{} = {}", label, riscv::pseudo::describe(value));
+    }
+    Ok(())
+}
+
+/// Poll the registers given with --watch, printing them whenever they
+/// change.  With --watch-ecc, also watch every ECC error counter, and
+/// raise an alert whenever one goes up.  Each --watch-threshold raises an
+/// alert when its register crosses the limit, and again every time it
+/// crosses back and then over again.  With --mqtt-broker, every sample is
+/// also published to the broker.
+pub fn watch(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let mut targets: Vec<(CsrRegister, bool)> =
+        cfg.watch.iter().map(|reg| (reg.clone(), false)).collect();
+    if cfg.watch_ecc {
+        for controller in EccController::discover(&cfg.csr_registers) {
+            for (_, reg) in controller.counters() {
+                targets.push((reg, true));
+            }
+        }
+    }
+
+    let mut last: Vec<Option<u64>> = vec![None; targets.len()];
+    let mut stats: Vec<WatchStats> = targets.iter().map(|_| WatchStats::default()).collect();
+    let mut tripped = vec![false; cfg.watch_thresholds.len()];
+
+    // The analyzer shares the bridge with everything else, so captures are
+    // started and checked between polls.
+    let scope = if cfg.watch_scope == ScopeSync::Off {
+        None
+    } else {
+        // unwrap() is safe because the config requires an analyzer
+        Some(LiteScope::new(&cfg).unwrap())
+    };
+    let mut capturing = false;
+    let mut captures = 0;
+    if let (Some(scope), ScopeSync::Follow) = (&scope, &cfg.watch_scope) {
+        scope.arm(&bridge, cfg.scope_offset, cfg.scope_length, false)?;
+        capturing = true;
+    }
+
+    let mut mqtt = cfg
+        .mqtt_broker
+        .as_ref()
+        .map(|broker| MqttPublisher::new(broker, &cfg.mqtt_client_id));
+
+    let mut polls = 0;
+    while cfg.watch_count == 0 || polls < cfg.watch_count {
+        let mut trigger = false;
+        for (((reg, alert), last), stats) in targets.iter().zip(last.iter_mut()).zip(stats.iter_mut()) {
+            let value = ecc::read_counter(&bridge, reg)?;
+            stats.record(value, cfg.watch_ema_alpha);
+            if let Some(mqtt) = &mut mqtt {
+                mqtt.publish(&mqtt::topic(&cfg.mqtt_topic, &reg.name), &value.to_string());
+            }
+            for (threshold, tripped) in cfg.watch_thresholds.iter().zip(tripped.iter_mut()) {
+                if threshold.register != reg.name.to_lowercase() {
+                    continue;
+                }
+                let exceeded = threshold.exceeded(value);
+                if exceeded && !*tripped {
+                    threshold.alert(cfg.watch_alert.as_deref(), value);
+                    info!("{}: {}", reg.name, stats.summary());
+                    trigger = true;
+                }
+                *tripped = exceeded;
+            }
+            match *last {
+                None => println!("{}: 0x{:x}", reg.name, value),
+                Some(prev) if *alert && value > prev => warn!(
+                    "ALERT: {} went up by {} (now {})",
+                    reg.name,
+                    value - prev,
+                    value
+                ),
+                Some(prev) if prev != value => {
+                    println!("{}: 0x{:x} -> 0x{:x}", reg.name, prev, value)
+                }
+                Some(_) => (),
+            }
+            *last = Some(value);
+        }
+
+        if let Some(scope) = &scope {
+            if trigger && !capturing && cfg.watch_scope == ScopeSync::Arm {
+                info!("threshold crossed, starting LiteScope capture");
+                scope.arm(&bridge, cfg.scope_offset, cfg.scope_length, true)?;
+                capturing = true;
+            }
+            if capturing && scope.done(&bridge)? {
+                captures += 1;
+                if cfg.watch_scope == ScopeSync::Follow {
+                    warn!("LiteScope triggered (capture {})", captures);
+                    for ((reg, _), last) in targets.iter().zip(last.iter()) {
+                        if let Some(value) = last {
+                            println!("  {}: 0x{:x}", reg.name, value);
+                        }
+                    }
+                }
+                let samples = scope.upload(&bridge)?;
+                if let Some(file_name) = &cfg.scope_file {
+                    let mut f = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(file_name)?;
+                    for (idx, sample) in samples.iter().enumerate() {
+                        writeln!(f, "{},{},0x{:x}", captures, idx, sample)?;
+                    }
+                }
+                info!("captured {} samples", samples.len());
+                capturing = cfg.watch_scope == ScopeSync::Follow;
+                if capturing {
+                    scope.arm(&bridge, cfg.scope_offset, cfg.scope_length, false)?;
+                }
+            }
+        }
+
+        if let Some(mqtt) = &mut mqtt {
+            mqtt.keep_alive();
+        }
+        polls += 1;
+        thread::sleep(cfg.watch_interval);
+    }
+
+    for ((reg, _), stats) in targets.iter().zip(stats.iter()) {
+        println!("{}: {}", reg.name, stats.summary());
+    }
+    Ok(())
+}
+
+/// Set the --pulse bit, hold it for --duration, then clear it again.
+/// Each write takes a round trip over the bridge, so the bit is set for
+/// somewhere between the duration and the duration plus one write.
+pub fn pulse(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires --pulse
+    let (addr, bit) = cfg.pulse.unwrap();
+    let mask = 1 << bit;
+
+    let value = bridge.peek(addr)?;
+    let start = Instant::now();
+    bridge.poke(addr, value | mask)?;
+    let set = Instant::now();
+    let latency = set - start;
+    if latency * 2 > cfg.pulse_duration {
+        warn!(
+            "a write takes {:?} over this bridge, which is too coarse to time a {:?} pulse accurately",
+            latency, cfg.pulse_duration
+        );
+    }
+
+    // Sleep for most of the pulse, then spin for the rest to avoid
+    // oversleeping.
+    let deadline = set + cfg.pulse_duration;
+    let slack = Duration::from_millis(2);
+    if cfg.pulse_duration > slack {
+        thread::sleep(cfg.pulse_duration - slack);
+    }
+    while Instant::now() < deadline {}
+
+    let clear = Instant::now();
+    bridge.poke(addr, value & !mask)?;
+    let done = Instant::now();
+    info!(
+        "pulsed bit {} of 0x{:08x} for between {:?} and {:?}",
+        bit,
+        addr,
+        clear - set,
+        done - start
+    );
+    Ok(())
+}
+
+/// Run the --sequence steps across this board and the --peer, if there
+/// is one.
+pub fn sequence(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let mut bridges = vec![bridge];
+    if let Some(peer_cfg) = cfg.peer_config() {
+        let peer = bridge::Bridge::new(&peer_cfg)?;
+        peer.connect()?;
+        bridges.push(peer);
+    }
+    Ok(sequence::run(bridges, &cfg.sequence)?)
+}
+
+/// Play back the --waveform table into --waveform-register.  Timing is
+/// best-effort: a point that comes due while the bridge is still busy is
+/// written as soon as possible, rather than shifting the rest of the table.
+pub fn waveform(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a register
+    let addr = cfg.waveform_register.unwrap();
+    let mut late = 0;
+    let mut worst = Duration::from_secs(0);
+    let mut loops = 0;
+    while cfg.waveform_loops == 0 || loops < cfg.waveform_loops {
+        let start = Instant::now();
+        for (time, value) in &cfg.waveform {
+            let deadline = start + *time;
+            let now = Instant::now();
+            if now < deadline {
+                thread::sleep(deadline - now);
+            } else if now - deadline > Duration::from_millis(1) {
+                late += 1;
+                worst = worst.max(now - deadline);
+            }
+            bridge.poke(addr, *value)?;
+        }
+        loops += 1;
+    }
+    if late > 0 {
+        warn!(
+            "{} updates were more than 1ms late, by up to {:?}",
+            late, worst
+        );
+    }
+    Ok(())
+}
+
+/// Check the --load-name image against the --signature-manifest, if there
+/// is one.
+fn verify_signature(cfg: &Config, data: &[u8]) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a file name
+    let file_name = cfg.load_name.as_ref().unwrap();
+    let manifest = match &cfg.signature_manifest {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+    if let Err(e) = signature::verify_image(file_name, data, manifest, cfg.signing_key.as_deref()) {
+        error!("refusing {}: {}", file_name, e);
+        return Err(e.into());
+    }
+    if cfg.signing_key.is_some() {
+        info!("{} matches {}, and its signature is good", file_name, manifest);
+    } else {
+        warn!(
+            "{} matches the hash in {}, but with no --signing-key the signature was NOT checked",
+            file_name, manifest
+        );
+    }
+    Ok(())
+}
+
+pub fn flash_verify_signature(cfg: Config, _bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a file name
+    let data = std::fs::read(cfg.load_name.as_ref().unwrap())?;
+    verify_signature(&cfg, &data)
+}
+
+pub fn flash(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires the SpiFlash CSRs
+    let spiflash = SpiFlash::new(&cfg).unwrap();
+    let file_name = cfg.load_name.as_ref().unwrap();
+    let data = std::fs::read(file_name)?;
+    verify_signature(&cfg, &data)?;
+
+    // A UF2 image goes wherever its blocks say, rather than at --flash-offset
+    let pieces = if uf2::is_uf2(&data) {
+        let mut pieces = vec![];
+        for segment in uf2::parse(&data, cfg.uf2_family)? {
+            pieces.push((flash_offset_of(&cfg, segment.addr), segment.data));
+        }
+        pieces
+    } else {
+        vec![(cfg.flash_offset, data)]
+    };
+
+    // Writes must fit in whichever partition they start in.
+    for (offset, data) in &pieces {
+        let partition = match &cfg.flash_partition {
+            Some(p) => Some(p),
+            None => cfg.flash_layout.iter().find(|p| p.contains(*offset)),
+        };
+        if let Some(p) = partition {
+            let room = p.size.saturating_sub(offset.wrapping_sub(p.offset));
+            if !p.contains(*offset) || data.len() as u32 > room {
+                error!(
+                    "{} bytes at 0x{:06x} don't fit in the {} bytes left in the {} partition",
+                    data.len(),
+                    offset,
+                    room,
+                    p.name
+                );
+                return Err(ServerError::FlashError(spiflash::FlashError::Overrun(p.name.clone())));
+            }
+        } else if !cfg.flash_layout.is_empty() {
+            warn!("0x{:06x} isn't in any partition of the flash layout", offset);
+        }
+    }
+
+    let id = spiflash.check_id(&bridge)?;
+    info!("found flash with id {}", signature::to_hex(&id));
+    for (offset, data) in &pieces {
+        info!(
+            "writing {} bytes of {} to flash at 0x{:06x}",
+            data.len(),
+            file_name,
+            offset
+        );
+    }
+    spiflash.write_pieces(&bridge, &pieces)?;
+    info!("flash written and verified");
+    Ok(())
+}
+
+/// Where in flash a UF2 block belongs.  Blocks are addressed the way the
+/// CPU sees flash, so one in a flash region is taken from the region's
+/// base.  Anything else is taken to be an offset into the flash already.
+fn flash_offset_of(cfg: &Config, addr: u32) -> u32 {
+    cfg.memory_regions
+        .iter()
+        .find(|r| {
+            r.kind == MemoryKind::Flash
+                && addr >= r.base
+                && (addr as u64) < r.base as u64 + r.size as u64
+        })
+        .map(|r| addr - r.base)
+        .unwrap_or(addr)
+}
+
+/// Dump the OTP block, then perform the --otp-write, if there is one.
+/// Fuses can't be unburned, so every write is recorded in the audit log,
+/// whether or not it worked.
+pub fn otp(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires an OTP base
+    let base = cfg.otp_base.unwrap();
+    for word in 0..cfg.otp_words {
+        let addr = base + word * 4;
+        println!("{:3} @ {:08x}: {:08x}", word, addr, bridge.peek(addr)?);
+    }
+
+    let (word, value) = match cfg.otp_write {
+        Some(w) => w,
+        None => return Ok(()),
+    };
+    let addr = base + word * 4;
+    let old = bridge.peek(addr)?;
+    if old & !value != 0 {
+        warn!(
+            "word {} already has bits set that aren't in 0x{:08x}, and they can't be cleared",
+            word, value
+        );
+    }
+
+    let result = bridge.poke(addr, value).and_then(|_| bridge.peek(addr));
+    let outcome = match &result {
+        Ok(new) if *new == value => "ok".to_owned(),
+        Ok(new) => format!("mismatch, read back {:08x}", new),
+        Err(e) => format!("error: {}", e),
+    };
+    let mut log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&cfg.otp_audit_log)?;
+    writeln!(
+        log,
+        "{} word={} address={:08x} old={:08x} new={:08x} result={}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        word,
+        addr,
+        old,
+        value,
+        outcome
+    )?;
+
+    let new = result?;
+    if new != value {
+        error!("word {} read back as {:08x}, not {:08x}", word, new, value);
+        return Err(ServerError::OtpMismatch(addr, value, new));
+    }
+    info!("burned word {} to {:08x}, logged to {}", word, value, cfg.otp_audit_log);
+    Ok(())
+}
+
+/// Write the host's time to the target, as microseconds since the epoch,
+/// so that what the firmware logs can be lined up with what the host logs.
+/// The high word goes in first, so firmware that latches the time when the
+/// low word is written gets it as of the moment that write landed.
+pub fn time_sync(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires an address
+    let addr = cfg.time_sync_addr.unwrap();
+
+    // Trust the quickest round trip, since anything slower was held up by
+    // something other than the link itself.
+    let mut round_trips = vec![];
+    for _ in 0..cfg.time_sync_samples {
+        let start = Instant::now();
+        bridge.peek(addr)?;
+        round_trips.push(start.elapsed());
+    }
+    round_trips.sort();
+    let round_trip = round_trips[0];
+
+    // Writing the high word takes a round trip, and the low word gets
+    // there half a round trip after it's sent.
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let stamp = (now + round_trip + round_trip / 2).as_micros() as u64;
+    bridge.poke(addr + 4, (stamp >> 32) as u32)?;
+    bridge.poke(addr, stamp as u32)?;
+    bridge.flush()?;
+
+    info!(
+        "set the time at {:08x} to {}us, with round trips of {:?} to {:?} (median {:?})",
+        addr,
+        stamp,
+        round_trip,
+        round_trips[round_trips.len() - 1],
+        round_trips[round_trips.len() / 2]
+    );
+    Ok(())
+}
+
+/// How many host packets to hold on to while the firmware's rx ring is full
+const TAP_BACKLOG: usize = 64;
+
+/// Give the firmware's packet rings a network link, through a TUN/TAP
+/// interface on the host.  This runs until it's interrupted.
+pub fn tap_server(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a tap base
+    let rings = PacketRings::open(&bridge, cfg.tap_base.unwrap())?;
+    let (mut interface, name) = tap::open_interface(&cfg.tap_name, cfg.tap_tun)?;
+    info!(
+        "passing packets between {} and {} slots at {:08x}, with an mtu of {}",
+        name,
+        rings.slots(),
+        cfg.tap_base.unwrap(),
+        rings.mtu()
+    );
+
+    // Reads from the interface block, so they get their own thread
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut reader = interface.try_clone()?;
+    thread::spawn(move || {
+        let mut buffer = vec![0; 65536];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(len) => {
+                    if tx.send(buffer[..len].to_vec()).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("couldn't read from the interface: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut backlog = std::collections::VecDeque::new();
+    let mut dropped = 0;
+    loop {
+        let mut idle = true;
+        while let Ok(packet) = rx.try_recv() {
+            if packet.len() > rings.mtu() || backlog.len() >= TAP_BACKLOG {
+                dropped += 1;
+                warn!("dropped a {}-byte packet from {} ({} so far)", packet.len(), name, dropped);
+                continue;
+            }
+            backlog.push_back(packet);
+        }
+        while let Some(packet) = backlog.front() {
+            if !rings.send(&bridge, packet)? {
+                break;
+            }
+            debug!("{} -> target: {} bytes", name, packet.len());
+            backlog.pop_front();
+            idle = false;
+        }
+        while let Some(packet) = rings.receive(&bridge)? {
+            debug!("target -> {}: {} bytes", name, packet.len());
+            interface.write_all(&packet)?;
+            idle = false;
+        }
+        if idle {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Describe the SoC as a device tree, as a blob if --dtb-file ends in
+/// .dtb and as source otherwise.
+pub fn gen_dtb(cfg: Config) -> Result<(), ServerError> {
+    let tree = dtb::from_soc(&cfg.soc);
+    match &cfg.dtb_file {
+        Some(file_name) if file_name.ends_with(".dtb") => {
+            std::fs::write(file_name, dtb::to_dtb(&tree))?
+        }
+        Some(file_name) => std::fs::write(file_name, dtb::to_dts(&tree))?,
+        None => print!("{}", dtb::to_dts(&tree)),
+    }
+    Ok(())
+}
+
+/// Browse the registers from the --svd file, or from the csr.csv if
+/// there isn't one.
+pub fn tui(cfg: Config, bridge: bridge::Bridge) -> Result<(), ServerError> {
+    let device = match &cfg.svd_file {
+        Some(file_name) => svd::load(file_name, &cfg.csr_registers)?,
+        None => svd::Device::from_soc(&cfg.soc),
+    };
+    tui::run(&cfg, &bridge, device)
+}
+
+/// Generate register access code, as SVD if --pac-file ends in .svd and as
+/// a Rust module otherwise.
+pub fn gen_pac(cfg: Config) -> Result<(), ServerError> {
+    let code = match &cfg.pac_file {
+        Some(file_name) if file_name.ends_with(".svd") => pac::to_svd(&cfg.soc),
+        _ => pac::to_rust(&cfg.soc, &cfg.register_mapping),
+    };
+    match &cfg.pac_file {
+        Some(file_name) => std::fs::write(file_name, code)?,
+        None => print!("{}", code),
+    }
+    Ok(())
+}
+
+/// Document the register map, as HTML if --docs-file ends in .html and as
+/// Markdown otherwise.
+pub fn gen_docs(cfg: Config) -> Result<(), ServerError> {
+    let doc = match &cfg.docs_file {
+        Some(file_name) if file_name.ends_with(".html") || file_name.ends_with(".htm") => {
+            docs::to_html(&cfg.soc)
+        }
+        _ => docs::to_markdown(&cfg.soc),
+    };
+    match &cfg.docs_file {
+        Some(file_name) => std::fs::write(file_name, doc)?,
+        None => print!("{}", doc),
+    }
+    Ok(())
+}
+
+/// Print what's in an Etherbone packet, or why it isn't one.
+fn show_packet(data: &[u8]) {
+    println!("{}", etherbone::to_hex(data));
+    match Packet::parse(data) {
+        Ok(packet) => print!("{}", packet),
+        Err(e) => println!("{}", e),
+    }
+}
+
+pub fn etherbone_send(cfg: Config) -> Result<(), ServerError> {
+    // unwrap() is safe because the config checks for both of these
+    let data = etherbone::read_hex_file(cfg.etherbone_packet.as_ref().unwrap())?;
+    let host = cfg.ethernet_host.as_ref().unwrap();
+
+    // A packet that doesn't decode may be being sent on purpose, so it
+    // still goes out
+    let wait = Packet::parse(&data).map(|p| p.expects_reply()).unwrap_or(true);
+    println!("sending {} bytes to {}:{}:", data.len(), host, cfg.ethernet_port);
+    show_packet(&data);
+    match etherbone::exchange(host, cfg.ethernet_port, cfg.ethernet_tcp, &data, wait)? {
+        Some(reply) => {
+            println!();
+            println!("received {} bytes:", reply.len());
+            show_packet(&reply);
+        }
+        None if wait => warn!("no reply from {}:{}", host, cfg.ethernet_port),
+        None => (),
+    }
+    Ok(())
+}
+
+pub fn etherbone_decode(cfg: Config) -> Result<(), ServerError> {
+    // unwrap() is safe because the config checks for this
+    let data = etherbone::read_hex_file(cfg.etherbone_packet.as_ref().unwrap())?;
+    show_packet(&data);
+    Ok(())
+}
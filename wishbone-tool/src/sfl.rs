@@ -0,0 +1,62 @@
+//! The LiteX serial flash loader ("sfl") protocol, as spoken by the BIOS
+//! `serialboot` command.
+
+/// Sent by the BIOS when it's ready to receive a serialboot.
+pub const MAGIC_REQUEST: &[u8] = b"sL5DdSMmkekro\n";
+
+/// Sent in response to `MAGIC_REQUEST` to start the transfer.
+pub const MAGIC_ACK: &[u8] = b"z6IHG7cYDID6o\n";
+
+/// Maximum length of a frame payload.  A load command uses four of these
+/// bytes for the address.
+pub const PAYLOAD_LENGTH: usize = 255;
+
+pub const CMD_ABORT: u8 = 0x00;
+pub const CMD_LOAD: u8 = 0x01;
+pub const CMD_JUMP: u8 = 0x02;
+
+pub const ACK_SUCCESS: u8 = b'K';
+pub const ACK_CRCERROR: u8 = b'C';
+pub const ACK_UNKNOWN: u8 = b'U';
+pub const ACK_ERROR: u8 = b'E';
+
+/// CRC-16/XMODEM, as used by the BIOS to check each frame.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Encode a frame: the payload length, a big-endian CRC over the command
+/// and payload, the command, and then the payload itself.
+pub fn encode_frame(cmd: u8, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= PAYLOAD_LENGTH);
+    let mut crc_data = vec![cmd];
+    crc_data.extend_from_slice(payload);
+    let crc = crc16(&crc_data);
+
+    let mut frame = vec![payload.len() as u8, (crc >> 8) as u8, crc as u8];
+    frame.extend_from_slice(&crc_data);
+    frame
+}
+
+/// A frame that writes `data` to memory at `addr`.
+pub fn load_frame(addr: u32, data: &[u8]) -> Vec<u8> {
+    let mut payload = addr.to_be_bytes().to_vec();
+    payload.extend_from_slice(data);
+    encode_frame(CMD_LOAD, &payload)
+}
+
+/// A frame that tells the BIOS to start running code at `addr`.
+pub fn jump_frame(addr: u32) -> Vec<u8> {
+    encode_frame(CMD_JUMP, &addr.to_be_bytes())
+}
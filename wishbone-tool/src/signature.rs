@@ -0,0 +1,245 @@
+//! Checking firmware images against a signed manifest before they're
+//! flashed.  A manifest lists one image per line in `sha256sum` format
+//! ("HASH  NAME"), and is signed with ed25519.  The signature lives next to
+//! it, in a file with ".sig" appended.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum SignatureError {
+    /// Couldn't read the image, manifest, signature, or key
+    IoError(io::Error),
+
+    /// The key or signature was the wrong size or badly encoded
+    BadKey(String),
+
+    /// The manifest isn't signed by the key
+    BadSignature,
+
+    /// The image doesn't appear in the manifest
+    NotInManifest(String),
+
+    /// The image appears in the manifest, but with a different hash
+    HashMismatch(String /* expected */, String /* actual */),
+}
+
+impl ::std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        use SignatureError::*;
+        match self {
+            IoError(e) => write!(f, "io error {}", e),
+            BadKey(s) => write!(f, "invalid key or signature: {}", s),
+            BadSignature => write!(f, "the manifest signature doesn't match the key"),
+            NotInManifest(s) => write!(f, "{} isn't listed in the manifest", s),
+            HashMismatch(expected, actual) => write!(
+                f,
+                "image has a sha256 of {}, but the manifest says it should be {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for SignatureError {
+    fn from(e: io::Error) -> SignatureError {
+        SignatureError::IoError(e)
+    }
+}
+
+/// Make sure `data`, which was read from `image_name`, is listed in the
+/// manifest with a matching hash.  If a public key is given, the manifest
+/// must also carry a valid signature from it.
+pub fn verify_image(
+    image_name: &str,
+    data: &[u8],
+    manifest_name: &str,
+    key_name: Option<&str>,
+) -> Result<(), SignatureError> {
+    let manifest = fs::read(manifest_name)?;
+    if let Some(key_name) = key_name {
+        let key = read_key(key_name, 32)?;
+        let signature = read_key(&format!("{}.sig", manifest_name), 64)?;
+        if !ed25519_verify(&key, &signature, &manifest) {
+            return Err(SignatureError::BadSignature);
+        }
+    }
+
+    let file_name = Path::new(image_name)
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| image_name.to_owned());
+    let actual = to_hex(&sha256(data));
+    for line in String::from_utf8_lossy(&manifest).lines() {
+        let mut fields = line.split_whitespace();
+        let (hash, name) = match (fields.next(), fields.next()) {
+            (Some(h), Some(n)) => (h, n.trim_start_matches('*')),
+            _ => continue,
+        };
+        if name != file_name {
+            continue;
+        }
+        if hash.to_lowercase() != actual {
+            return Err(SignatureError::HashMismatch(hash.to_lowercase(), actual));
+        }
+        return Ok(());
+    }
+    Err(SignatureError::NotInManifest(file_name))
+}
+
+/// Keys and signatures may be stored either raw or as hex.
+fn read_key(file_name: &str, len: usize) -> Result<Vec<u8>, SignatureError> {
+    let data = fs::read(file_name)?;
+    if data.len() == len {
+        return Ok(data);
+    }
+    let text = String::from_utf8_lossy(&data);
+    let text = text.trim();
+    if !text.is_ascii() {
+        return Err(SignatureError::BadKey(format!(
+            "{} is neither raw nor hex",
+            file_name
+        )));
+    }
+    if text.len() != len * 2 {
+        return Err(SignatureError::BadKey(format!(
+            "{} should be {} bytes long",
+            file_name, len
+        )));
+    }
+    let mut key = vec![];
+    for idx in (0..text.len()).step_by(2) {
+        match u8::from_str_radix(&text[idx..idx + 2], 16) {
+            Ok(b) => key.push(b),
+            Err(_) => {
+                return Err(SignatureError::BadKey(format!(
+                    "{} is neither raw nor hex",
+                    file_name
+                )))
+            }
+        }
+    }
+    Ok(key)
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Check an ed25519 signature strictly, so that neither a weak key nor a
+/// second encoding of a good signature gets through.
+pub fn ed25519_verify(public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+    let key = match VerifyingKey::try_from(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    match Signature::from_slice(signature) {
+        Ok(signature) => key.verify_strict(message, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&text[idx..idx + 2], 16).unwrap())
+            .collect()
+    }
+
+    // FIPS 180-2, appendix B
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(
+            to_hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            to_hex(&sha256(&[b'a'; 1_000_000])),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    // RFC 8032, section 7.1, tests 1 to 3
+    const RFC8032: [(&str, &str, &str); 3] = [
+        (
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+             18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn ed25519_known_answers() {
+        for (key, message, signature) in RFC8032.iter() {
+            assert!(ed25519_verify(&from_hex(key), &from_hex(signature), &from_hex(message)));
+        }
+    }
+
+    #[test]
+    fn ed25519_rejects_tampering() {
+        for (key, message, signature) in RFC8032.iter() {
+            let (key, signature) = (from_hex(key), from_hex(signature));
+            let mut message = from_hex(message);
+            message.push(0);
+            assert!(!ed25519_verify(&key, &signature, &message));
+            for idx in [0, 63].iter() {
+                let mut bad = signature.clone();
+                bad[*idx] ^= 1;
+                assert!(!ed25519_verify(&key, &bad, &message[..message.len() - 1]));
+            }
+        }
+    }
+
+    #[test]
+    fn read_key_refuses_text_that_isnt_hex() {
+        let file_name = std::env::temp_dir().join(format!("wishbone-tool-key-{}", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        // The right length in bytes, but with a character that's two of them
+        let mut text = "0".repeat(61);
+        text.push('\u{e9}');
+        fs::write(file_name, &text).unwrap();
+        let result = read_key(file_name, 32);
+        fs::remove_file(file_name).unwrap();
+        assert!(matches!(result, Err(SignatureError::BadKey(_))));
+
+        let hex = "ab".repeat(32);
+        fs::write(file_name, format!("{}\n", hex)).unwrap();
+        let result = read_key(file_name, 32);
+        fs::remove_file(file_name).unwrap();
+        assert_eq!(result.unwrap(), vec![0xab; 32]);
+    }
+}
@@ -0,0 +1,117 @@
+//! Everything a LiteX csr.csv says about an SoC: its CSR blocks and the
+//! registers in them, its memory regions, and the constants it was built
+//! with.  This is what the generators work from.
+
+use crate::config::{csr_data_width, csv_record, parse_u32, ConfigError, CsrMode, CsrOrdering, CsrRegister};
+use crate::regions::{AccessPolicy, MemoryKind, MemoryRegion};
+
+use std::fs::File;
+
+/// One peripheral's worth of CSRs
+#[derive(Clone, Debug)]
+pub struct CsrBlock {
+    pub name: String,
+    pub base: u32,
+    pub registers: Vec<CsrRegister>,
+}
+
+impl CsrBlock {
+    /// Number of bytes from the base to the end of the last register
+    pub fn size(&self) -> u32 {
+        self.registers
+            .iter()
+            .map(|r| r.address + r.words * 4 - self.base)
+            .max()
+            .unwrap_or(4)
+    }
+
+    /// A register's name with the block's name taken off the front
+    pub fn short_name<'a>(&self, reg: &'a CsrRegister) -> &'a str {
+        let prefix = format!("{}_", self.name);
+        reg.name.strip_prefix(&prefix).unwrap_or(&reg.name)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SocDescription {
+    pub blocks: Vec<CsrBlock>,
+    pub regions: Vec<MemoryRegion>,
+    pub constants: Vec<(String, String)>,
+}
+
+impl SocDescription {
+    pub fn load(filename: &str) -> Result<SocDescription, ConfigError> {
+        let mut soc = SocDescription::default();
+        let mut registers = vec![];
+        let file = File::open(filename)?;
+        let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(file);
+        for result in rdr.records() {
+            let r = csv_record(result, "csr csv")?;
+            match &r[0] {
+                "csr_base" if r.len() >= 3 => soc.blocks.push(CsrBlock {
+                    name: r[1].to_lowercase(),
+                    base: parse_u32(&r[2])?,
+                    registers: vec![],
+                }),
+                "csr_register" if r.len() >= 4 => registers.push(CsrRegister {
+                    name: r[1].to_lowercase(),
+                    address: parse_u32(&r[2])?,
+                    words: parse_u32(&r[3])?,
+                    mode: match r.get(4) {
+                        Some("ro") => CsrMode::ReadOnly,
+                        _ => CsrMode::ReadWrite,
+                    },
+                    data_width: 32,
+                    ordering: CsrOrdering::Big,
+                }),
+                "constant" if r.len() >= 3 => {
+                    soc.constants.push((r[1].to_lowercase(), r[2].to_owned()))
+                }
+                "memory_region" if r.len() >= 4 => soc.regions.push(MemoryRegion {
+                    name: r[1].to_lowercase(),
+                    base: parse_u32(&r[2])?,
+                    size: parse_u32(&r[3])?,
+                    policy: match r.get(4) {
+                        Some("io") => AccessPolicy::WordOnly,
+                        _ => AccessPolicy::Any,
+                    },
+                    kind: MemoryKind::from_name(&r[1].to_lowercase()),
+                }),
+                _ => (),
+            }
+        }
+
+        let data_width = csr_data_width(soc.constant("config_csr_data_width"), &registers);
+        let ordering = soc
+            .constant("config_csr_ordering")
+            .and_then(CsrOrdering::from_string)
+            .unwrap_or(CsrOrdering::Big);
+        for reg in &mut registers {
+            reg.data_width = data_width;
+            reg.ordering = ordering.clone();
+        }
+
+        // Each register belongs to the block with the highest base below it
+        soc.blocks.sort_by_key(|b| b.base);
+        for reg in registers {
+            if let Some(block) = soc.blocks.iter_mut().rev().find(|b| b.base <= reg.address) {
+                block.registers.push(reg);
+            }
+        }
+        for block in &mut soc.blocks {
+            block.registers.sort_by_key(|r| r.address);
+        }
+        Ok(soc)
+    }
+
+    pub fn constant(&self, name: &str) -> Option<&str> {
+        self.constants
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn region(&self, name: &str) -> Option<&MemoryRegion> {
+        self.regions.iter().find(|r| r.name == name)
+    }
+}
@@ -0,0 +1,354 @@
+//! Driving SPI NOR flash through LiteX's flash cores: the older SpiFlash
+//! core's bitbang interface, or the master interface that LiteSPI has in
+//! place of it, which shifts a byte at a time.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::Config;
+
+use log::debug;
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Smallest region the flash can erase
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// Largest region the flash can program in one go
+pub const PAGE_SIZE: u32 = 256;
+
+const PIN_MOSI: u32 = 1 << 0;
+const PIN_CLK: u32 = 1 << 1;
+const PIN_CS_N: u32 = 1 << 2;
+
+/// LiteSPI master's phyconfig: eight bits at a time, on one data line
+const PHY_LEN_8: u32 = 8;
+const PHY_WIDTH_1: u32 = 1 << 8;
+const PHY_MASK_1: u32 = 1 << 16;
+
+/// LiteSPI master's status bits
+const STATUS_TX_READY: u32 = 1 << 0;
+const STATUS_RX_READY: u32 = 1 << 1;
+
+/// Longest the LiteSPI master may take to shift one byte.  It's a few
+/// clocks of the PHY, so waiting this long means the CSRs are wrong or the
+/// PHY isn't running.
+const MASTER_TIMEOUT: Duration = Duration::from_millis(100);
+
+const CMD_READ: u8 = 0x03;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_READ_ID: u8 = 0x9f;
+const CMD_READ_UNIQUE_ID: u8 = 0x4b;
+
+/// Write In Progress bit of the status register
+const STATUS_WIP: u8 = 1 << 0;
+
+/// Longest a sector erase or page program may take.  Datasheets give a
+/// few hundred milliseconds at most for a sector, so anything past this
+/// means there's no flash, or it's stuck.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum FlashError {
+    BridgeError(BridgeError),
+
+    /// Reading back what was written gave something else
+    VerifyFailed(u32 /* offset */),
+
+    /// The data is too big for the partition it was written to
+    Overrun(String /* partition */),
+
+    /// An erase that doesn't start and end on a sector boundary
+    Unaligned(u32 /* offset */, u32 /* length */),
+
+    /// The flash, or the core in front of it, stayed busy for too long
+    Timeout(&'static str /* what was waited for */),
+
+    /// The JEDEC ID reads as all zeroes or all ones, so nothing answered
+    NoFlash(Vec<u8> /* id */),
+}
+
+impl std::convert::From<BridgeError> for FlashError {
+    fn from(e: BridgeError) -> FlashError {
+        FlashError::BridgeError(e)
+    }
+}
+
+/// The CSRs of whichever core drives the flash
+enum Transport {
+    /// The SpiFlash core, which toggles the pins one poke at a time
+    Bitbang { bitbang: u32, miso: u32, bitbang_en: u32 },
+
+    /// LiteSPI's master interface, which shifts a whole byte for each
+    /// write to rxtx
+    Master { cs: u32, phyconfig: u32, rxtx: u32, status: u32 },
+}
+
+impl Transport {
+    fn find(register_mapping: &HashMap<String, u32>) -> Option<Transport> {
+        let bitbang = || {
+            Some(Transport::Bitbang {
+                bitbang: *register_mapping.get("spiflash_bitbang")?,
+                miso: *register_mapping.get("spiflash_miso")?,
+                bitbang_en: *register_mapping.get("spiflash_bitbang_en")?,
+            })
+        };
+        let master = || {
+            Some(Transport::Master {
+                cs: *register_mapping.get("spiflash_core_master_cs")?,
+                phyconfig: *register_mapping.get("spiflash_core_master_phyconfig")?,
+                rxtx: *register_mapping.get("spiflash_core_master_rxtx")?,
+                status: *register_mapping.get("spiflash_core_master_status")?,
+            })
+        };
+        bitbang().or_else(master)
+    }
+}
+
+/// Whether csr.csv has either of the cores that `SpiFlash` can drive
+pub fn present(register_mapping: &HashMap<String, u32>) -> bool {
+    Transport::find(register_mapping).is_some()
+}
+
+pub struct SpiFlash {
+    transport: Transport,
+}
+
+impl SpiFlash {
+    /// Find the flash core in csr.csv, if the gateware has one.
+    pub fn new(cfg: &Config) -> Option<SpiFlash> {
+        Some(SpiFlash {
+            transport: Transport::find(&cfg.register_mapping)?,
+        })
+    }
+
+    /// Run one command: send `out`, then clock in `read_len` bytes of reply.
+    fn command(&self, bridge: &Bridge, out: &[u8], read_len: usize) -> Result<Vec<u8>, FlashError> {
+        match self.transport {
+            Transport::Bitbang {
+                bitbang,
+                miso,
+                bitbang_en,
+            } => Self::bitbang_command(bridge, bitbang, miso, bitbang_en, out, read_len),
+            Transport::Master {
+                cs,
+                phyconfig,
+                rxtx,
+                status,
+            } => {
+                bridge.poke(phyconfig, PHY_LEN_8 | PHY_WIDTH_1 | PHY_MASK_1)?;
+                bridge.poke(cs, 1)?;
+                // Chip select goes back down even if the transfer stalls
+                let reply = Self::master_transfer(bridge, rxtx, status, out, read_len);
+                bridge.poke(cs, 0)?;
+                reply
+            }
+        }
+    }
+
+    /// Shift `out` through the LiteSPI master, then `read_len` bytes of
+    /// reply.  Every byte out brings one back, so the reply is what comes
+    /// back while zeroes are sent.
+    fn master_transfer(
+        bridge: &Bridge,
+        rxtx: u32,
+        status: u32,
+        out: &[u8],
+        read_len: usize,
+    ) -> Result<Vec<u8>, FlashError> {
+        let mut reply = Vec::with_capacity(read_len);
+        for (idx, byte) in out.iter().copied().chain(std::iter::repeat_n(0, read_len)).enumerate() {
+            Self::wait_master(bridge, status, STATUS_TX_READY, "LiteSPI to be ready to send")?;
+            bridge.poke(rxtx, byte as u32)?;
+            Self::wait_master(bridge, status, STATUS_RX_READY, "LiteSPI to receive")?;
+            let received = bridge.peek(rxtx)? as u8;
+            if idx >= out.len() {
+                reply.push(received);
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Wait for the LiteSPI master's `status` to show `bit`
+    fn wait_master(bridge: &Bridge, status: u32, bit: u32, what: &'static str) -> Result<(), FlashError> {
+        let deadline = Instant::now() + MASTER_TIMEOUT;
+        while bridge.peek(status)? & bit == 0 {
+            if Instant::now() > deadline {
+                return Err(FlashError::Timeout(what));
+            }
+        }
+        Ok(())
+    }
+
+    fn bitbang_command(
+        bridge: &Bridge,
+        bitbang: u32,
+        miso: u32,
+        bitbang_en: u32,
+        out: &[u8],
+        read_len: usize,
+    ) -> Result<Vec<u8>, FlashError> {
+        bridge.poke(bitbang_en, 1)?;
+        bridge.poke(bitbang, 0)?;
+        for byte in out {
+            for bit in (0..8).rev() {
+                let mosi = if byte & (1 << bit) != 0 { PIN_MOSI } else { 0 };
+                bridge.poke(bitbang, mosi)?;
+                bridge.poke(bitbang, mosi | PIN_CLK)?;
+            }
+        }
+        let mut reply = Vec::with_capacity(read_len);
+        for _ in 0..read_len {
+            let mut byte = 0;
+            for _ in 0..8 {
+                // The flash shifts out on the falling edge, so sample while
+                // the clock is low.
+                bridge.poke(bitbang, 0)?;
+                byte = (byte << 1) | (bridge.peek(miso)? & 1) as u8;
+                bridge.poke(bitbang, PIN_CLK)?;
+            }
+            reply.push(byte);
+        }
+        bridge.poke(bitbang, PIN_CS_N)?;
+        bridge.poke(bitbang_en, 0)?;
+        Ok(reply)
+    }
+
+    fn address_command(cmd: u8, offset: u32) -> Vec<u8> {
+        vec![cmd, (offset >> 16) as u8, (offset >> 8) as u8, offset as u8]
+    }
+
+    /// Read the JEDEC manufacturer and device ID.
+    pub fn id(&self, bridge: &Bridge) -> Result<Vec<u8>, FlashError> {
+        self.command(bridge, &[CMD_READ_ID], 3)
+    }
+
+    /// Read the 64-bit unique ID that's programmed into each chip at the
+    /// factory.  It follows four dummy bytes.
+    pub fn unique_id(&self, bridge: &Bridge) -> Result<Vec<u8>, FlashError> {
+        self.command(bridge, &[CMD_READ_UNIQUE_ID, 0, 0, 0, 0], 8)
+    }
+
+    /// Read the JEDEC ID, and make sure there's a chip there to give it.
+    /// With nothing driving MISO it reads as all zeroes or all ones, and
+    /// erasing then would only end in a timeout or a failed verify.
+    pub fn check_id(&self, bridge: &Bridge) -> Result<Vec<u8>, FlashError> {
+        let id = self.id(bridge)?;
+        if id.iter().all(|b| *b == 0x00) || id.iter().all(|b| *b == 0xff) {
+            return Err(FlashError::NoFlash(id));
+        }
+        Ok(id)
+    }
+
+    pub fn read(&self, bridge: &Bridge, offset: u32, len: u32) -> Result<Vec<u8>, FlashError> {
+        self.command(bridge, &Self::address_command(CMD_READ, offset), len as usize)
+    }
+
+    fn wait_idle(&self, bridge: &Bridge) -> Result<(), FlashError> {
+        let deadline = Instant::now() + BUSY_TIMEOUT;
+        while self.command(bridge, &[CMD_READ_STATUS], 1)?[0] & STATUS_WIP != 0 {
+            if Instant::now() > deadline {
+                return Err(FlashError::Timeout("the flash to finish erasing or programming"));
+            }
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&self, bridge: &Bridge, offset: u32) -> Result<(), FlashError> {
+        self.command(bridge, &[CMD_WRITE_ENABLE], 0)?;
+        self.command(bridge, &Self::address_command(CMD_SECTOR_ERASE, offset), 0)?;
+        self.wait_idle(bridge)
+    }
+
+    fn program_page(&self, bridge: &Bridge, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        let mut out = Self::address_command(CMD_PAGE_PROGRAM, offset);
+        out.extend_from_slice(data);
+        self.command(bridge, &[CMD_WRITE_ENABLE], 0)?;
+        self.command(bridge, &out, 0)?;
+        self.wait_idle(bridge)
+    }
+
+    /// Write several pieces, such as the segments of a UF2 image.  Two
+    /// that share a sector would erase each other if written one at a
+    /// time, so the sectors are put together first, with whatever else is
+    /// in a partly-covered one read from the flash to keep it.  Each
+    /// sector is then erased and programmed once, and everything is read
+    /// back at the end.
+    pub fn write_pieces(&self, bridge: &Bridge, pieces: &[(u32, Vec<u8>)]) -> Result<(), FlashError> {
+        // Each sector's contents, and which of its bytes the pieces cover
+        let mut sectors: BTreeMap<u32, (Vec<u8>, Vec<bool>)> = BTreeMap::new();
+        let blank = || (vec![0xff; SECTOR_SIZE as usize], vec![false; SECTOR_SIZE as usize]);
+        for (offset, data) in pieces {
+            for (idx, byte) in data.iter().enumerate() {
+                let pos = offset + idx as u32;
+                let (contents, covered) = sectors.entry(pos & !(SECTOR_SIZE - 1)).or_insert_with(blank);
+                contents[(pos % SECTOR_SIZE) as usize] = *byte;
+                covered[(pos % SECTOR_SIZE) as usize] = true;
+            }
+        }
+        for (sector, (contents, covered)) in sectors.iter_mut() {
+            if covered.iter().any(|c| !c) {
+                debug!("keeping the rest of the sector at 0x{:06x}", sector);
+                let existing = self.read(bridge, *sector, SECTOR_SIZE)?;
+                for ((byte, old), covered) in contents.iter_mut().zip(existing).zip(covered.iter()) {
+                    if !covered {
+                        *byte = old;
+                    }
+                }
+            }
+            debug!("erasing sector at 0x{:06x}", sector);
+            self.erase_sector(bridge, *sector)?;
+            self.program(bridge, *sector, contents)?;
+        }
+        for (offset, data) in pieces {
+            let readback = self.read(bridge, *offset, data.len() as u32)?;
+            if let Some(idx) = readback.iter().zip(data).position(|(a, b)| a != b) {
+                return Err(FlashError::VerifyFailed(offset + idx as u32));
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase every sector `data` touches and program it page by page,
+    /// without reading it back.  Anything else in the first and last
+    /// sectors is lost.
+    pub fn write_unverified(&self, bridge: &Bridge, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        let end = offset + data.len() as u32;
+        let mut sector = offset & !(SECTOR_SIZE - 1);
+        while sector < end {
+            debug!("erasing sector at 0x{:06x}", sector);
+            self.erase_sector(bridge, sector)?;
+            sector += SECTOR_SIZE;
+        }
+        self.program(bridge, offset, data)
+    }
+
+    /// Erase the sectors from `offset` for `len` bytes, which have to be
+    /// whole sectors
+    pub fn erase(&self, bridge: &Bridge, offset: u32, len: u32) -> Result<(), FlashError> {
+        if !offset.is_multiple_of(SECTOR_SIZE) || !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(FlashError::Unaligned(offset, len));
+        }
+        for sector in (offset..offset + len).step_by(SECTOR_SIZE as usize) {
+            debug!("erasing sector at 0x{:06x}", sector);
+            self.erase_sector(bridge, sector)?;
+        }
+        Ok(())
+    }
+
+    /// Program `data` into flash that's already been erased
+    pub fn program(&self, bridge: &Bridge, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        let end = offset + data.len() as u32;
+        // Pages can't be programmed across a page boundary.
+        let mut pos = offset;
+        while pos < end {
+            let page_end = ((pos & !(PAGE_SIZE - 1)) + PAGE_SIZE).min(end);
+            let chunk = &data[(pos - offset) as usize..(page_end - offset) as usize];
+            self.program_page(bridge, pos, chunk)?;
+            pos = page_end;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Soak testing the bridge, both the gateware and everything on the host
+//! side of it, with a random mix of reads, writes and bursts across the
+//! --stress-region areas.  Every word read back has to be what was last
+//! written to it.
+//!
+//! The mix comes from a seed, and the same seed makes the same accesses in
+//! the same order, so a failure can be made again with --stress-seed.  The
+//! generator is SplitMix64 rather than one from `rand`, whose streams can
+//! change between versions.
+
+use crate::bridge::{Bridge, BridgeError};
+
+use std::collections::HashMap;
+
+/// The longest burst, in words
+const MAX_BURST: u64 = 64;
+
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    BurstRead,
+    BurstWrite,
+}
+
+/// A word that didn't read back as it was written
+#[derive(Debug)]
+pub struct Mismatch {
+    pub addr: u32,
+    pub expected: u32,
+    pub observed: u32,
+}
+
+pub struct Stress {
+    /// Where to make accesses, as base and length
+    regions: Vec<(u32, u32)>,
+    rng: SplitMix64,
+
+    /// What each word was last written with.  Words that haven't been
+    /// written yet can't be checked.
+    written: HashMap<u32, u32>,
+
+    /// How many accesses have been made
+    count: u64,
+}
+
+impl Stress {
+    /// The regions have to be word aligned and a non-zero number of words
+    pub fn new(regions: &[(u32, u32)], seed: u64) -> Stress {
+        Stress {
+            regions: regions.to_vec(),
+            rng: SplitMix64 { state: seed },
+            written: HashMap::new(),
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Make the next access, giving back what it was and any words that
+    /// came back wrong
+    pub fn step(&mut self, bridge: &Bridge) -> Result<(Access, u32, Vec<Mismatch>), BridgeError> {
+        let access = match self.rng.below(100) {
+            0..=34 => Access::Read,
+            35..=69 => Access::Write,
+            70..=84 => Access::BurstRead,
+            _ => Access::BurstWrite,
+        };
+        let (base, len) = self.regions[self.rng.below(self.regions.len() as u64) as usize];
+        let words = len as u64 / 4;
+        let start = self.rng.below(words);
+        let burst = match access {
+            Access::Read | Access::Write => 1,
+            Access::BurstRead | Access::BurstWrite => 1 + self.rng.below(MAX_BURST.min(words - start)),
+        } as u32;
+        let addr = base + start as u32 * 4;
+        self.count += 1;
+
+        let mut wrong = vec![];
+        match access {
+            Access::Write | Access::BurstWrite => {
+                let values: Vec<u32> = (0..burst).map(|_| self.rng.next() as u32).collect();
+                if access == Access::Write {
+                    bridge.poke(addr, values[0])?;
+                } else {
+                    let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                    bridge.burst_write(addr, &data)?;
+                }
+                for (word, value) in values.into_iter().enumerate() {
+                    self.written.insert(addr + word as u32 * 4, value);
+                }
+            }
+            Access::Read | Access::BurstRead => {
+                let observed = if access == Access::Read {
+                    vec![bridge.peek(addr)?]
+                } else {
+                    bridge
+                        .burst_read(addr, burst * 4)?
+                        .chunks(4)
+                        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                        .collect()
+                };
+                for (word, observed) in observed.into_iter().enumerate() {
+                    let word_addr = addr + word as u32 * 4;
+                    if let Some(&expected) = self.written.get(&word_addr) {
+                        if expected != observed {
+                            wrong.push(Mismatch {
+                                addr: word_addr,
+                                expected,
+                                observed,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        Ok((access, addr, wrong))
+    }
+}
@@ -0,0 +1,63 @@
+use crate::bridge::BridgeStats;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What one server did over the course of a run.
+pub struct OperationSummary {
+    pub name: String,
+    pub elapsed: Duration,
+    pub stats: Arc<BridgeStats>,
+    pub succeeded: bool,
+}
+
+impl OperationSummary {
+    fn errors(&self) -> u64 {
+        if self.succeeded {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":\"{}\",\"result\":\"{}\",\"elapsed_ms\":{},\"reads\":{},\"writes\":{},\"bytes_read\":{},\"bytes_written\":{},\"retries\":{},\"glitches\":{},\"errors\":{}}}",
+            self.name,
+            if self.succeeded { "ok" } else { "error" },
+            self.elapsed.as_millis(),
+            self.stats.reads(),
+            self.stats.writes(),
+            self.stats.reads() * 4,
+            self.stats.writes() * 4,
+            self.stats.retries(),
+            self.stats.glitches(),
+            self.errors()
+        )
+    }
+}
+
+/// Render a summary of the whole run, with totals across all operations,
+/// as a single line of JSON.  Every bridge transaction moves one 32-bit word.
+pub fn to_json(elapsed: Duration, operations: &[OperationSummary]) -> String {
+    let reads: u64 = operations.iter().map(|o| o.stats.reads()).sum();
+    let writes: u64 = operations.iter().map(|o| o.stats.writes()).sum();
+    let retries: u64 = operations.iter().map(|o| o.stats.retries()).sum();
+    let glitches: u64 = operations.iter().map(|o| o.stats.glitches()).sum();
+    let errors: u64 = operations.iter().map(|o| o.errors()).sum();
+    let bytes = (reads + writes) * 4;
+    let secs = elapsed.as_secs_f64();
+    let throughput = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+    let ops: Vec<String> = operations.iter().map(|o| o.to_json()).collect();
+    format!(
+        "{{\"elapsed_ms\":{},\"bytes_read\":{},\"bytes_written\":{},\"bytes_per_second\":{:.0},\"retries\":{},\"glitches\":{},\"errors\":{},\"operations\":[{}]}}",
+        elapsed.as_millis(),
+        reads * 4,
+        writes * 4,
+        throughput,
+        retries,
+        glitches,
+        errors,
+        ops.join(",")
+    )
+}
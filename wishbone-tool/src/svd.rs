@@ -0,0 +1,562 @@
+//! Just enough of CMSIS-SVD for the register browser: the peripherals of
+//! a device, their registers, and the bitfields in each, along with any
+//! names given to a field's values.  LiteX writes one of these next to
+//! csr.csv, and unlike csr.csv it knows about fields.  Clusters, `dim`
+//! arrays and the many optional extras SVD allows are skipped over.
+
+use crate::config::{parse_u32, CsrMode, CsrOrdering, CsrRegister};
+use crate::soc::SocDescription;
+
+use std::fs;
+use std::io;
+
+#[derive(Debug)]
+pub enum SvdError {
+    IoError(io::Error),
+
+    /// The file isn't XML, or isn't laid out like an SVD
+    BadSvd(String),
+}
+
+impl std::fmt::Display for SvdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use SvdError::*;
+        match self {
+            IoError(e) => write!(f, "io error: {}", e),
+            BadSvd(s) => write!(f, "not an SVD file: {}", s),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for SvdError {
+    fn from(e: io::Error) -> SvdError {
+        SvdError::IoError(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn readable(self) -> bool {
+        self != Access::WriteOnly
+    }
+
+    pub fn writable(self) -> bool {
+        self != Access::ReadOnly
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub name: String,
+    pub description: Option<String>,
+    pub lsb: u32,
+    pub width: u32,
+    pub access: Access,
+
+    /// Names for some of the values the field can take
+    pub values: Vec<(String, u64)>,
+}
+
+impl Field {
+    pub fn msb(&self) -> u32 {
+        self.lsb + self.width - 1
+    }
+
+    pub fn mask(&self) -> u64 {
+        if self.width >= 64 {
+            !0
+        } else {
+            ((1u64 << self.width) - 1) << self.lsb
+        }
+    }
+
+    pub fn extract(&self, register_value: u64) -> u64 {
+        (register_value & self.mask()) >> self.lsb
+    }
+
+    /// The register value with this field changed to `value`
+    pub fn insert(&self, register_value: u64, value: u64) -> u64 {
+        (register_value & !self.mask()) | ((value << self.lsb) & self.mask())
+    }
+
+    pub fn value_name(&self, value: u64) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(_, v)| *v == value)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Register {
+    pub name: String,
+    pub description: Option<String>,
+
+    /// Where the register is and how its words go together, in the same
+    /// terms as a register from csr.csv
+    pub csr: CsrRegister,
+
+    /// Width of the value in bits
+    pub size: u32,
+    pub access: Access,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Peripheral {
+    pub name: String,
+    pub description: Option<String>,
+    pub base: u32,
+    pub registers: Vec<Register>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Device {
+    pub name: String,
+    pub peripherals: Vec<Peripheral>,
+}
+
+/// Read a device from an SVD file.  Registers that `csrs` also knows
+/// about, by address, take their width and word order from it, since an
+/// SVD has no way to say that a register is split across several CSR
+/// words.
+pub fn load(filename: &str, csrs: &[CsrRegister]) -> Result<Device, SvdError> {
+    let text = fs::read_to_string(filename)?;
+    let root = parse_xml(&text).map_err(SvdError::BadSvd)?;
+    Device::from_xml(&root, csrs)
+}
+
+impl Device {
+    /// A device with the registers from csr.csv, which have no fields,
+    /// for when there's no SVD to hand
+    pub fn from_soc(soc: &SocDescription) -> Device {
+        let peripherals = soc
+            .blocks
+            .iter()
+            .map(|block| Peripheral {
+                name: block.name.clone(),
+                description: None,
+                base: block.base,
+                registers: block
+                    .registers
+                    .iter()
+                    .map(|reg| Register {
+                        name: block.short_name(reg).to_owned(),
+                        description: None,
+                        csr: reg.clone(),
+                        size: (reg.words * reg.data_width).min(64),
+                        access: match reg.mode {
+                            CsrMode::ReadOnly => Access::ReadOnly,
+                            CsrMode::ReadWrite => Access::ReadWrite,
+                        },
+                        fields: vec![],
+                    })
+                    .collect(),
+            })
+            .collect();
+        Device {
+            name: "csr.csv".to_owned(),
+            peripherals,
+        }
+    }
+
+    fn from_xml(root: &Element, csrs: &[CsrRegister]) -> Result<Device, SvdError> {
+        if root.name != "device" {
+            return Err(SvdError::BadSvd(format!("top element is <{}>, not <device>", root.name)));
+        }
+        let default_size = match root.text_of("size") {
+            Some(s) => number(s)? as u32,
+            None => 32,
+        };
+        let mut peripherals: Vec<Peripheral> = vec![];
+        for p in root.path(&["peripherals", "peripheral"]) {
+            let name = p.text_of("name").ok_or_else(|| missing("peripheral", "name"))?;
+            let base = number(p.text_of("baseAddress").ok_or_else(|| missing(name, "baseAddress"))?)? as u32;
+            let mut registers = vec![];
+            for r in p.path(&["registers", "register"]) {
+                registers.push(Self::register(r, base, default_size, csrs)?);
+            }
+            // A copy of another peripheral somewhere else takes its
+            // registers, moved to the new base
+            if let (true, Some(from)) = (registers.is_empty(), p.attribute("derivedFrom")) {
+                if let Some(original) = peripherals.iter().find(|o| o.name == from) {
+                    registers = original
+                        .registers
+                        .iter()
+                        .cloned()
+                        .map(|mut reg| {
+                            reg.csr.address = reg.csr.address - original.base + base;
+                            reg
+                        })
+                        .collect();
+                }
+            }
+            registers.sort_by_key(|r| r.csr.address);
+            peripherals.push(Peripheral {
+                name: name.to_owned(),
+                description: p.text_of("description").map(tidy),
+                base,
+                registers,
+            });
+        }
+        peripherals.sort_by_key(|p| p.base);
+        Ok(Device {
+            name: root.text_of("name").unwrap_or("device").to_owned(),
+            peripherals,
+        })
+    }
+
+    fn register(r: &Element, base: u32, default_size: u32, csrs: &[CsrRegister]) -> Result<Register, SvdError> {
+        let name = r.text_of("name").ok_or_else(|| missing("register", "name"))?;
+        let offset = number(r.text_of("addressOffset").ok_or_else(|| missing(name, "addressOffset"))?)? as u32;
+        let size = match r.text_of("size") {
+            Some(s) => number(s)? as u32,
+            None => default_size,
+        };
+        let access = access(r.text_of("access")).unwrap_or(Access::ReadWrite);
+        let address = base + offset;
+        let csr = match csrs.iter().find(|c| c.address == address) {
+            Some(c) => c.clone(),
+            None => CsrRegister {
+                name: name.to_lowercase(),
+                address,
+                words: size.div_ceil(32).max(1),
+                mode: match access {
+                    Access::ReadOnly => CsrMode::ReadOnly,
+                    _ => CsrMode::ReadWrite,
+                },
+                data_width: 32,
+                ordering: CsrOrdering::Big,
+            },
+        };
+        let mut fields = vec![];
+        for f in r.path(&["fields", "field"]) {
+            fields.push(Self::field(f, name, access)?);
+        }
+        fields.sort_by_key(|f| f.lsb);
+        Ok(Register {
+            name: name.to_owned(),
+            description: r.text_of("description").map(tidy),
+            csr,
+            size: size.min(64),
+            access,
+            fields,
+        })
+    }
+
+    fn field(f: &Element, register: &str, register_access: Access) -> Result<Field, SvdError> {
+        let name = f.text_of("name").ok_or_else(|| missing(register, "field name"))?;
+        // Bit positions can be given in any of three ways
+        let (lsb, width) = if let (Some(offset), Some(width)) = (f.text_of("bitOffset"), f.text_of("bitWidth")) {
+            (number(offset)? as u32, number(width)? as u32)
+        } else if let (Some(lsb), Some(msb)) = (f.text_of("lsb"), f.text_of("msb")) {
+            let (lsb, msb) = (number(lsb)? as u32, number(msb)? as u32);
+            (lsb, msb.saturating_sub(lsb) + 1)
+        } else if let Some(range) = f.text_of("bitRange") {
+            let bad = || SvdError::BadSvd(format!("{}.{} has a bitRange of {}", register, name, range));
+            let (msb, lsb) = range
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .split_once(':')
+                .ok_or_else(bad)?;
+            let (lsb, msb) = (number(lsb)? as u32, number(msb)? as u32);
+            (lsb, msb.saturating_sub(lsb) + 1)
+        } else {
+            return Err(missing(name, "bitOffset"));
+        };
+        if width == 0 || lsb + width > 64 {
+            return Err(SvdError::BadSvd(format!("{}.{} doesn't fit in 64 bits", register, name)));
+        }
+        let mut values = vec![];
+        for v in f.path(&["enumeratedValues", "enumeratedValue"]) {
+            if let (Some(name), Some(value)) = (v.text_of("name"), v.text_of("value")) {
+                // Values with "don't care" bits in them can't be matched
+                if let Ok(value) = number(value) {
+                    values.push((name.to_owned(), value));
+                }
+            }
+        }
+        Ok(Field {
+            name: name.to_owned(),
+            description: f.text_of("description").map(tidy),
+            lsb,
+            width,
+            access: access(f.text_of("access")).unwrap_or(register_access),
+            values,
+        })
+    }
+}
+
+fn missing(what: &str, field: &str) -> SvdError {
+    SvdError::BadSvd(format!("{} has no {}", what, field))
+}
+
+/// SVD numbers may be hex, decimal, or binary written as `#0101`
+fn number(text: &str) -> Result<u64, SvdError> {
+    let text = text.trim();
+    let result = if let Some(bits) = text.strip_prefix('#') {
+        u64::from_str_radix(bits, 2).ok()
+    } else if let Some(bits) = text.strip_prefix("0b") {
+        u64::from_str_radix(bits, 2).ok()
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        parse_u32(text).ok().map(|v| v as u64)
+    };
+    result.ok_or_else(|| SvdError::BadSvd(format!("{} isn't a number", text)))
+}
+
+fn access(text: Option<&str>) -> Option<Access> {
+    match text? {
+        "read-only" => Some(Access::ReadOnly),
+        "write-only" | "writeOnce" => Some(Access::WriteOnly),
+        _ => Some(Access::ReadWrite),
+    }
+}
+
+/// Descriptions are often wrapped across lines and indented in the file
+fn tidy(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// An XML element, with the text directly inside it run together
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn text_of(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|c| c.text.trim())
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The elements at the end of `path`, which is followed from here
+    fn path<'a>(&'a self, path: &[&str]) -> Vec<&'a Element> {
+        let mut found = vec![self];
+        for step in path {
+            found = found
+                .iter()
+                .flat_map(|e| e.children.iter().filter(|c| c.name == *step))
+                .collect();
+        }
+        found
+    }
+}
+
+fn parse_xml(text: &str) -> Result<Element, String> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        offset: 0,
+    };
+    parser.skip_misc()?;
+    parser.element()
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, what: &str) -> String {
+        let line = self.text[..self.offset.min(self.text.len())]
+            .iter()
+            .filter(|c| **c == b'\n')
+            .count();
+        format!("line {}: {}", line + 1, what)
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.text[self.offset.min(self.text.len())..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.offset < self.text.len() && self.text[self.offset].is_ascii_whitespace() {
+            self.offset += 1;
+        }
+    }
+
+    /// Move past `end`, wherever it is
+    fn skip_past(&mut self, end: &str) -> Result<(), String> {
+        match self
+            .rest()
+            .windows(end.len())
+            .position(|w| w == end.as_bytes())
+        {
+            Some(pos) => {
+                self.offset += pos + end.len();
+                Ok(())
+            }
+            None => Err(self.error(&format!("no {} to finish this", end))),
+        }
+    }
+
+    /// The XML declaration, comments and the doctype
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with(b"<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with(b"<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with(b"<!") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let start = self.offset;
+        while self.offset < self.text.len() {
+            let c = self.text[self.offset];
+            if c.is_ascii_whitespace() || c == b'>' || c == b'/' || c == b'=' {
+                break;
+            }
+            self.offset += 1;
+        }
+        if start == self.offset {
+            return Err(self.error("expected a name"));
+        }
+        Ok(String::from_utf8_lossy(&self.text[start..self.offset]).into_owned())
+    }
+
+    fn element(&mut self) -> Result<Element, String> {
+        if !self.rest().starts_with(b"<") {
+            return Err(self.error("expected an element"));
+        }
+        self.offset += 1;
+        let mut element = Element {
+            name: self.name()?,
+            attributes: vec![],
+            children: vec![],
+            text: String::new(),
+        };
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with(b"/>") {
+                self.offset += 2;
+                return Ok(element);
+            }
+            if self.rest().starts_with(b">") {
+                self.offset += 1;
+                break;
+            }
+            let key = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with(b"=") {
+                return Err(self.error("expected '=' after an attribute name"));
+            }
+            self.offset += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().first() {
+                Some(q @ b'"') | Some(q @ b'\'') => *q,
+                _ => return Err(self.error("expected a quoted attribute value")),
+            };
+            self.offset += 1;
+            let start = self.offset;
+            while self.offset < self.text.len() && self.text[self.offset] != quote {
+                self.offset += 1;
+            }
+            let value = decode_entities(&String::from_utf8_lossy(&self.text[start..self.offset]));
+            self.offset += 1;
+            element.attributes.push((key, value));
+        }
+
+        loop {
+            if self.offset >= self.text.len() {
+                return Err(self.error(&format!("<{}> is never closed", element.name)));
+            }
+            if self.rest().starts_with(b"</") {
+                self.offset += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(self.error(&format!("</{}> closes <{}>", name, element.name)));
+                }
+                self.skip_past(">")?;
+                return Ok(element);
+            }
+            if self.rest().starts_with(b"<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with(b"<![CDATA[") {
+                self.offset += 9;
+                let start = self.offset;
+                self.skip_past("]]>")?;
+                element
+                    .text
+                    .push_str(&String::from_utf8_lossy(&self.text[start..self.offset - 3]));
+            } else if self.rest().starts_with(b"<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with(b"<") {
+                element.children.push(self.element()?);
+            } else {
+                let start = self.offset;
+                while self.offset < self.text.len() && self.text[self.offset] != b'<' {
+                    self.offset += 1;
+                }
+                element
+                    .text
+                    .push_str(&decode_entities(&String::from_utf8_lossy(&self.text[start..self.offset])));
+            }
+        }
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_owned();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
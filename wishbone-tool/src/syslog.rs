@@ -0,0 +1,261 @@
+//! Sending the tool's own log, and whatever the firmware prints on its
+//! console, to a central log host over UDP, so that a lab full of boards
+//! can be watched from one place.  Each message is an RFC 5424 syslog
+//! line, or a JSON object for collectors that would rather have that, and
+//! carries the host it came from and, with --board-id, the board's ID.
+//!
+//! The log is caught on its way through the formatter that flexi_logger
+//! calls for each line, so whatever would be shown is also sent.  Nothing
+//! is sent until `install()` has been called.
+
+use flexi_logger::{DeferredNow, Record};
+use log::Level;
+
+use std::fs;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_PORT: u16 = 514;
+
+/// Messages are sent as from the "user-level" facility
+const FACILITY_USER: u8 = 1;
+
+/// Console lines longer than this are sent in pieces
+const MAX_CONSOLE_LINE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyslogFormat {
+    Rfc5424,
+    Json,
+}
+
+impl SyslogFormat {
+    pub fn from_string(item: &str) -> Option<SyslogFormat> {
+        match item {
+            "syslog" | "rfc5424" => Some(SyslogFormat::Rfc5424),
+            "json" => Some(SyslogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+struct Forwarder {
+    socket: UdpSocket,
+    format: SyslogFormat,
+    hostname: String,
+
+    /// The board's unique ID and its label, once they've been read
+    board: Mutex<Option<(String, Option<String>)>>,
+
+    /// Console output that hasn't made it to the end of a line yet
+    console: Mutex<Vec<u8>>,
+}
+
+static FORWARDER: OnceLock<Forwarder> = OnceLock::new();
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+/// Add the default port to `endpoint` if it hasn't got one.  An IPv6
+/// address is full of colons, so one with a port must be given in
+/// brackets, as `[addr]:port`, and a bare one is taken to have no port.
+fn with_port(endpoint: &str) -> String {
+    if endpoint.parse::<Ipv6Addr>().is_ok() {
+        return format!("[{}]:{}", endpoint, DEFAULT_PORT);
+    }
+    match endpoint.rsplit_once(':') {
+        Some((host, _)) if !host.starts_with('[') || host.ends_with(']') => endpoint.to_owned(),
+        _ => format!("{}:{}", endpoint, DEFAULT_PORT),
+    }
+}
+
+/// Start sending to `endpoint`, given as HOST, HOST:PORT or [ADDR]:PORT
+pub fn install(endpoint: &str, format: SyslogFormat) -> io::Result<()> {
+    let endpoint: SocketAddr = with_port(endpoint)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", endpoint)))?;
+    let socket = if endpoint.is_ipv6() {
+        UdpSocket::bind("[::]:0")?
+    } else {
+        UdpSocket::bind("0.0.0.0:0")?
+    };
+    socket.connect(endpoint)?;
+    let forwarder = Forwarder {
+        socket,
+        format,
+        hostname: hostname(),
+        board: Mutex::new(None),
+        console: Mutex::new(vec![]),
+    };
+    if FORWARDER.set(forwarder).is_err() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "syslog is already set up"));
+    }
+    Ok(())
+}
+
+/// Tag everything from here on with the board it's about
+pub fn set_board(id: &str, label: Option<&str>) {
+    if let Some(forwarder) = FORWARDER.get() {
+        *forwarder.board.lock().unwrap() = Some((id.to_owned(), label.map(|l| l.to_owned())));
+    }
+}
+
+/// The formatter given to flexi_logger, which shows each line as usual
+/// and sends it along as well
+pub fn format(w: &mut dyn io::Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    if let Some(forwarder) = FORWARDER.get() {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let message = format!("[{}] {}", record.module_path().unwrap_or("-"), record.args());
+        forwarder.send("tool", severity, &message);
+    }
+    flexi_logger::colored_default_format(w, now, record)
+}
+
+/// Pass along some of what the firmware printed.  Only whole lines are
+/// sent, with the rest held back until the next call finishes them.
+pub fn console(data: &[u8]) {
+    let forwarder = match FORWARDER.get() {
+        Some(f) => f,
+        None => return,
+    };
+    let mut pending = forwarder.console.lock().unwrap();
+    for &byte in data {
+        if byte == b'\n' || pending.len() >= MAX_CONSOLE_LINE {
+            let line = String::from_utf8_lossy(&pending).trim_end_matches('\r').to_owned();
+            if !line.is_empty() {
+                forwarder.send("firmware", 6, &line);
+            }
+            pending.clear();
+        }
+        if byte != b'\n' {
+            pending.push(byte);
+        }
+    }
+}
+
+impl Forwarder {
+    fn send(&self, kind: &str, severity: u8, message: &str) {
+        let board = self.board.lock().unwrap().clone();
+        let packet = match self.format {
+            SyslogFormat::Rfc5424 => {
+                let data = match &board {
+                    None => "-".to_owned(),
+                    Some((id, label)) => format!(
+                        "[board@32473 id=\"{}\"{}]",
+                        sd_escape(id),
+                        label
+                            .as_ref()
+                            .map(|l| format!(" label=\"{}\"", sd_escape(l)))
+                            .unwrap_or_default()
+                    ),
+                };
+                format!(
+                    "<{}>1 {} {} wishbone-tool {} {} {} {}",
+                    FACILITY_USER * 8 + severity,
+                    timestamp(),
+                    self.hostname,
+                    std::process::id(),
+                    kind,
+                    data,
+                    message
+                )
+            }
+            SyslogFormat::Json => {
+                let mut json = format!(
+                    "{{\"time\":\"{}\",\"host\":\"{}\",\"source\":\"{}\",\"severity\":{}",
+                    timestamp(),
+                    json_escape(&self.hostname),
+                    kind,
+                    severity
+                );
+                if let Some((id, label)) = &board {
+                    json.push_str(&format!(",\"board\":\"{}\"", json_escape(id)));
+                    if let Some(label) = label {
+                        json.push_str(&format!(",\"label\":\"{}\"", json_escape(label)));
+                    }
+                }
+                json.push_str(&format!(",\"message\":\"{}\"}}", json_escape(message)));
+                json
+            }
+        };
+        // There's nothing to be done about a log host that isn't there,
+        // and saying so would only log another line to send it
+        self.socket.send(packet.as_bytes()).ok();
+    }
+}
+
+/// Now, in UTC, as RFC 3339 wants it
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_date(secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+/// The year, month and day it is `secs` into 1970, in UTC
+pub fn civil_date(secs: u64) -> (i64, i64, i64) {
+    let days = (secs / 86400) as i64;
+
+    // Days since 1970 to a civil date, after Howard Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Structured data values may not have a bare `"`, `\` or `]` in them
+fn sd_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
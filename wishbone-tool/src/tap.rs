@@ -0,0 +1,202 @@
+//! Pass packets between a TUN/TAP interface on the host and a pair of
+//! packet rings in the target's memory, so that bare-metal firmware gets a
+//! network link over the debug bridge.
+//!
+//! The firmware lays the rings out at the base address as little-endian
+//! words:
+//!
+//! ```text
+//!   +0x00  rx_head    packets the host has put in the rx ring
+//!   +0x04  rx_tail    packets the target has taken out of the rx ring
+//!   +0x08  tx_head    packets the target has put in the tx ring
+//!   +0x0c  tx_tail    packets the host has taken out of the tx ring
+//!   +0x10  slots      number of slots in each ring
+//!   +0x14  slot_size  bytes per slot, including its length word
+//!   +0x20  the rx ring, followed by the tx ring
+//! ```
+//!
+//! Each slot starts with a word giving the length of the packet in it.
+//! The heads and tails count up forever, so a ring is empty when they're
+//! equal and full when they're `slots` apart.  The host only ever writes
+//! `rx_head` and `tx_tail`, and the target only `rx_tail` and `tx_head`.
+
+use crate::bridge::{Bridge, BridgeError};
+
+use std::fs::File;
+use std::io;
+
+const RX_HEAD: u32 = 0x00;
+const RX_TAIL: u32 = 0x04;
+const TX_HEAD: u32 = 0x08;
+const TX_TAIL: u32 = 0x0c;
+const SLOTS: u32 = 0x10;
+const SLOT_SIZE: u32 = 0x14;
+const RINGS: u32 = 0x20;
+
+#[derive(Debug)]
+pub enum TapError {
+    BridgeError(BridgeError),
+    IoError(io::Error),
+
+    /// The rings at the base address don't make sense
+    BadRings(String),
+}
+
+impl std::fmt::Display for TapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TapError::*;
+        match self {
+            BridgeError(e) => write!(f, "bridge error: {}", e),
+            IoError(e) => write!(f, "io error: {}", e),
+            BadRings(s) => write!(f, "packet rings: {}", s),
+        }
+    }
+}
+
+impl std::convert::From<BridgeError> for TapError {
+    fn from(e: BridgeError) -> TapError {
+        TapError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<io::Error> for TapError {
+    fn from(e: io::Error) -> TapError {
+        TapError::IoError(e)
+    }
+}
+
+pub struct PacketRings {
+    base: u32,
+    slots: u32,
+    slot_size: u32,
+}
+
+impl PacketRings {
+    /// Find the rings the firmware has set up at `base`.
+    pub fn open(bridge: &Bridge, base: u32) -> Result<PacketRings, TapError> {
+        let slots = bridge.peek(base + SLOTS)?;
+        let slot_size = bridge.peek(base + SLOT_SIZE)?;
+        if slots == 0 || slot_size <= 4 || slot_size & 3 != 0 {
+            return Err(TapError::BadRings(format!(
+                "{} slots of {} bytes at {:08x} -- has the firmware set them up?",
+                slots, slot_size, base
+            )));
+        }
+        Ok(PacketRings {
+            base,
+            slots,
+            slot_size,
+        })
+    }
+
+    /// The largest packet a slot can hold
+    pub fn mtu(&self) -> usize {
+        self.slot_size as usize - 4
+    }
+
+    pub fn slots(&self) -> u32 {
+        self.slots
+    }
+
+    fn slot_addr(&self, ring: u32, index: u32) -> u32 {
+        self.base + RINGS + (ring * self.slots + index % self.slots) * self.slot_size
+    }
+
+    /// Hand a packet to the target, returning `false` if the rx ring is
+    /// full.  Packets too big for a slot have to be dropped by the caller.
+    pub fn send(&self, bridge: &Bridge, packet: &[u8]) -> Result<bool, TapError> {
+        let head = bridge.peek(self.base + RX_HEAD)?;
+        let tail = bridge.peek(self.base + RX_TAIL)?;
+        if head.wrapping_sub(tail) >= self.slots {
+            return Ok(false);
+        }
+        let slot = self.slot_addr(0, head);
+        let mut data = packet.to_vec();
+        data.resize((packet.len() + 3) & !3, 0);
+        bridge.poke(slot, packet.len() as u32)?;
+        bridge.burst_write(slot + 4, &data)?;
+        // The packet has to be there before the target is told about it
+        bridge.flush()?;
+        bridge.poke(self.base + RX_HEAD, head.wrapping_add(1))?;
+        Ok(true)
+    }
+
+    /// Take the next packet the target has sent, if there is one.
+    pub fn receive(&self, bridge: &Bridge) -> Result<Option<Vec<u8>>, TapError> {
+        let head = bridge.peek(self.base + TX_HEAD)?;
+        let tail = bridge.peek(self.base + TX_TAIL)?;
+        if head == tail {
+            return Ok(None);
+        }
+        let slot = self.slot_addr(1, tail);
+        let len = bridge.peek(slot)?;
+        if len as usize > self.mtu() {
+            return Err(TapError::BadRings(format!(
+                "tx slot {} says it holds {} bytes, but slots are only {} bytes",
+                tail % self.slots,
+                len,
+                self.slot_size
+            )));
+        }
+        let mut packet = bridge.burst_read(slot + 4, (len + 3) & !3)?;
+        packet.truncate(len as usize);
+        bridge.poke(self.base + TX_TAIL, tail.wrapping_add(1))?;
+        Ok(Some(packet))
+    }
+}
+
+/// Open the TUN/TAP interface called `name`, creating it if need be.  A
+/// TAP interface carries Ethernet frames, and a TUN interface bare IP
+/// packets.  Either way each read or write is exactly one packet.
+#[cfg(target_os = "linux")]
+pub fn open_interface(name: &str, tun: bool) -> io::Result<(File, String)> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+    const IFF_TUN: libc::c_short = 0x0001;
+    const IFF_TAP: libc::c_short = 0x0002;
+    const IFF_NO_PI: libc::c_short = 0x1000;
+
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        _pad: [u8; 22],
+    }
+
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("interface name {} is too long", name),
+        ));
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: if tun { IFF_TUN } else { IFF_TAP } | IFF_NO_PI,
+        _pad: [0; 22],
+    };
+    req.name[..name.len()].copy_from_slice(name.as_bytes());
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = req
+        .name
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(req.name.len());
+    let name = String::from_utf8_lossy(&req.name[..len]).to_string();
+    Ok((file, name))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_interface(_name: &str, _tun: bool) -> io::Result<(File, String)> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "TUN/TAP interfaces are only supported on Linux",
+    ))
+}
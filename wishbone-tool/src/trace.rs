@@ -0,0 +1,179 @@
+//! Timing spans for --trace-out.  Each span records when it started and
+//! how long it lasted, and is written out as it ends in Chrome's trace
+//! event format, which chrome://tracing, Perfetto and speedscope can all
+//! show as a flame graph.
+//!
+//! The servers run until they're killed, so nothing is held back for the
+//! end.  The file is the format's JSON array form, whose closing `]` may
+//! be left off, and the writes are flushed every so often, so a trace cut
+//! short by Ctrl-C loses no more than the last moment of it.
+//!
+//! Spans cost almost nothing until tracing is enabled.
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long written spans may sit in the buffer before it's flushed
+const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static START: OnceLock<Instant> = OnceLock::new();
+static OUTPUT: OnceLock<Mutex<TraceFile>> = OnceLock::new();
+static NEXT_THREAD: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    static THREAD_ID: Cell<u32> = const { Cell::new(0) };
+}
+
+struct TraceFile {
+    file: BufWriter<File>,
+    count: usize,
+    flushed: Instant,
+
+    /// Whether a write failed, after which nothing more is written
+    failed: Option<io::Error>,
+}
+
+/// A span that's still open.  It's recorded when it's dropped.
+pub struct Span {
+    name: Cow<'static, str>,
+    category: &'static str,
+    start: Option<Instant>,
+}
+
+/// Start recording spans to `filename`.
+pub fn enable(filename: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(filename)?);
+    writeln!(file, "[")?;
+    file.flush()?;
+    let output = TraceFile {
+        file,
+        count: 0,
+        flushed: Instant::now(),
+        failed: None,
+    };
+    if OUTPUT.set(Mutex::new(output)).is_err() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "tracing is already enabled"));
+    }
+    START.get_or_init(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Open a span, which lasts until the value returned is dropped.
+/// `category` says which layer it's in, such as "bridge" or "gdb".
+pub fn span<N: Into<Cow<'static, str>>>(category: &'static str, name: N) -> Span {
+    if !enabled() {
+        return Span {
+            name: Cow::Borrowed(""),
+            category,
+            start: None,
+        };
+    }
+    Span {
+        name: name.into(),
+        category,
+        start: Some(Instant::now()),
+    }
+}
+
+/// Like `span()`, for names that take some work to put together, which
+/// is only done when tracing is enabled.
+pub fn span_with<F: FnOnce() -> String>(category: &'static str, name: F) -> Span {
+    if !enabled() {
+        return span(category, "");
+    }
+    span(category, name())
+}
+
+fn thread_id() -> u32 {
+    THREAD_ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT_THREAD.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let start = match self.start {
+            Some(s) => s,
+            None => return,
+        };
+        let output = match OUTPUT.get() {
+            Some(o) => o,
+            None => return,
+        };
+        let line = format!(
+            "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3}}}",
+            escape(&self.name),
+            self.category,
+            thread_id(),
+            start.duration_since(*START.get().unwrap()).as_secs_f64() * 1e6,
+            start.elapsed().as_secs_f64() * 1e6,
+        );
+        output.lock().unwrap().add(&line);
+    }
+}
+
+impl TraceFile {
+    fn add(&mut self, line: &str) {
+        if self.failed.is_some() {
+            return;
+        }
+        // Every event but the first follows a comma, so that the file is
+        // only ever missing its `]`
+        let sep = if self.count == 0 { "" } else { ",\n" };
+        let mut result = write!(self.file, "{}{}", sep, line);
+        if result.is_ok() && self.flushed.elapsed() >= FLUSH_INTERVAL {
+            result = self.file.flush();
+            self.flushed = Instant::now();
+        }
+        match result {
+            Ok(()) => self.count += 1,
+            Err(e) => self.failed = Some(e),
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Close off the trace, returning how many spans were written.  Spans
+/// that end after this aren't recorded.
+pub fn finish() -> io::Result<usize> {
+    ENABLED.store(false, Ordering::Relaxed);
+    let output = match OUTPUT.get() {
+        Some(o) => o,
+        None => return Ok(0),
+    };
+    let mut output = output.lock().unwrap();
+    if let Some(e) = output.failed.take() {
+        return Err(e);
+    }
+    writeln!(output.file, "\n]")?;
+    output.file.flush()?;
+    // Nothing more can go after the `]`
+    output.failed = Some(io::Error::other("the trace has been finished"));
+    Ok(output.count)
+}
@@ -0,0 +1,572 @@
+//! An interactive register browser for bring-up.  The peripherals of the
+//! SVD (or of csr.csv, if there's no SVD) are shown as a tree that opens
+//! out into registers and then bitfields, each with its value as it is
+//! now.  Fields and whole registers can be edited in place, which reads
+//! the register, changes just those bits, and writes it back.  Anything
+//! can be put in the watch pane at the bottom, where it's kept up to date
+//! and shown in bold when it changes.
+//!
+//! Only what's on screen, and what's being watched, is read, so that a
+//! large SoC doesn't keep the bridge busy.  Registers whose reads take
+//! something out of a FIFO are never read unless asked for with `r`.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::Config;
+use crate::ecc;
+use crate::server::ServerError;
+use crate::svd::{Device, Field, Register};
+
+use terminal::{Action, Attribute, Clear, Event, KeyCode, KeyEvent, KeyModifiers, Retrieved, Terminal, Value};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Stdout, Write};
+use std::time::{Duration, Instant};
+
+/// How often the values on screen are read again
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// The most lines the watch pane takes up, not counting its title
+const WATCH_ROWS: usize = 6;
+
+const HELP: &str = "arrows move, enter opens or edits, w watches, r reads, q quits";
+
+/// A line of the tree, by index into the device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Peripheral(usize),
+    Register(usize, usize),
+    Field(usize, usize, usize),
+}
+
+impl Node {
+    fn register(self) -> Option<(usize, usize)> {
+        match self {
+            Node::Peripheral(_) => None,
+            Node::Register(p, r) | Node::Field(p, r, _) => Some((p, r)),
+        }
+    }
+}
+
+struct Watch {
+    node: Node,
+    last: Option<u64>,
+
+    /// Whether it was different at the last refresh from the one before
+    changed: bool,
+}
+
+/// Puts the terminal into a state fit for drawing on, and back again
+struct Screen {
+    term: Terminal<Stdout>,
+}
+
+impl Screen {
+    fn new() -> Result<Screen, ServerError> {
+        let term = terminal::stdout();
+        term.act(Action::EnterAlternateScreen)?;
+        term.act(Action::EnableRawMode)?;
+        term.act(Action::HideCursor)?;
+        Ok(Screen { term })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        self.term.act(Action::ShowCursor).ok();
+        self.term.act(Action::DisableRawMode).ok();
+        self.term.act(Action::LeaveAlternateScreen).ok();
+    }
+}
+
+struct Browser<'a> {
+    cfg: &'a Config,
+    bridge: &'a Bridge,
+    device: Device,
+    open: HashSet<Node>,
+
+    /// The last value read from (or, for write-only registers, written
+    /// to) each register
+    values: HashMap<(usize, usize), Result<u64, String>>,
+    cursor: usize,
+    top: usize,
+    watches: Vec<Watch>,
+
+    /// The text typed so far while editing the line at the cursor
+    editing: Option<String>,
+    status: String,
+}
+
+/// A read would take something out of a FIFO, such as a UART's
+fn reads_have_side_effects(reg: &Register) -> bool {
+    reg.csr.name.ends_with("rxtx") || reg.name.to_lowercase().ends_with("rxtx")
+}
+
+fn parse_value(text: &str, field: Option<&Field>) -> Option<u64> {
+    let text = text.trim();
+    if let Some(field) = field {
+        if let Some((_, value)) = field.values.iter().find(|(name, _)| name.eq_ignore_ascii_case(text)) {
+            return Some(*value);
+        }
+    }
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Cut `text` down to `width` columns, or pad it out to them
+fn fit(text: &str, width: usize, pad: bool) -> String {
+    let mut line: String = text.chars().take(width).collect();
+    if pad {
+        let len = line.chars().count();
+        line.extend(std::iter::repeat_n(' ', width - len));
+    }
+    line
+}
+
+pub fn run(cfg: &Config, bridge: &Bridge, device: Device) -> Result<(), ServerError> {
+    let mut browser = Browser {
+        cfg,
+        bridge,
+        device,
+        open: HashSet::new(),
+        values: HashMap::new(),
+        cursor: 0,
+        top: 0,
+        watches: vec![],
+        editing: None,
+        status: HELP.to_owned(),
+    };
+    let screen = Screen::new()?;
+    let mut last_refresh: Option<Instant> = None;
+    let mut redraw = true;
+    loop {
+        let (width, height) = match screen.term.get(Value::TerminalSize)? {
+            Retrieved::TerminalSize(w, h) => (w as usize, h as usize),
+            _ => (80, 24),
+        };
+        let tree_rows = browser.tree_rows(height);
+        browser.scroll(tree_rows);
+        if last_refresh.map(|t| t.elapsed() >= REFRESH).unwrap_or(true) {
+            browser.refresh(tree_rows);
+            last_refresh = Some(Instant::now());
+            redraw = true;
+        }
+        if redraw {
+            browser.draw(&screen.term, width, height)?;
+            redraw = false;
+        }
+        match screen.term.get(Value::Event(Some(Duration::from_millis(50))))? {
+            Retrieved::Event(Some(Event::Key(key))) => {
+                if !browser.key(key) {
+                    return Ok(());
+                }
+                redraw = true;
+            }
+            Retrieved::Event(Some(Event::Resize)) => redraw = true,
+            _ => (),
+        }
+    }
+}
+
+impl<'a> Browser<'a> {
+    /// Every line of the tree as it's opened out now
+    fn rows(&self) -> Vec<Node> {
+        let mut rows = vec![];
+        for (p, peripheral) in self.device.peripherals.iter().enumerate() {
+            rows.push(Node::Peripheral(p));
+            if !self.open.contains(&Node::Peripheral(p)) {
+                continue;
+            }
+            for (r, register) in peripheral.registers.iter().enumerate() {
+                rows.push(Node::Register(p, r));
+                if self.open.contains(&Node::Register(p, r)) {
+                    rows.extend((0..register.fields.len()).map(|f| Node::Field(p, r, f)));
+                }
+            }
+        }
+        rows
+    }
+
+    fn register(&self, p: usize, r: usize) -> &Register {
+        &self.device.peripherals[p].registers[r]
+    }
+
+    fn field(&self, node: Node) -> Option<&Field> {
+        match node {
+            Node::Field(p, r, f) => Some(&self.register(p, r).fields[f]),
+            _ => None,
+        }
+    }
+
+    fn name(&self, node: Node) -> String {
+        let peripheral = |p: usize| &self.device.peripherals[p].name;
+        match node {
+            Node::Peripheral(p) => peripheral(p).clone(),
+            Node::Register(p, r) => format!("{}.{}", peripheral(p), self.register(p, r).name),
+            Node::Field(p, r, f) => format!(
+                "{}.{}.{}",
+                peripheral(p),
+                self.register(p, r).name,
+                self.register(p, r).fields[f].name
+            ),
+        }
+    }
+
+    fn watch_rows(&self) -> usize {
+        match self.watches.len() {
+            0 => 0,
+            n => 1 + n.min(WATCH_ROWS),
+        }
+    }
+
+    /// How many lines of the screen the tree gets, between the title at
+    /// the top and the watch pane and status line at the bottom
+    fn tree_rows(&self, height: usize) -> usize {
+        height.saturating_sub(2 + self.watch_rows()).max(1)
+    }
+
+    /// Keep the cursor on screen
+    fn scroll(&mut self, tree_rows: usize) {
+        let count = self.rows().len();
+        self.cursor = self.cursor.min(count.saturating_sub(1));
+        if self.cursor < self.top {
+            self.top = self.cursor;
+        } else if self.cursor >= self.top + tree_rows {
+            self.top = self.cursor + 1 - tree_rows;
+        }
+    }
+
+    fn read(&mut self, p: usize, r: usize) {
+        let reg = self.register(p, r);
+        let value = ecc::read_counter(self.bridge, &reg.csr).map_err(|e| e.to_string());
+        if let Err(e) = &value {
+            self.status = format!("reading {}: {}", self.name(Node::Register(p, r)), e);
+        }
+        self.values.insert((p, r), value);
+    }
+
+    /// Read each register on screen and in the watch pane once
+    fn refresh(&mut self, tree_rows: usize) {
+        let mut registers: Vec<(usize, usize)> = self
+            .rows()
+            .iter()
+            .skip(self.top)
+            .take(tree_rows)
+            .filter_map(|n| n.register())
+            .collect();
+        registers.extend(self.watches.iter().filter_map(|w| w.node.register()));
+        registers.sort_unstable();
+        registers.dedup();
+        for (p, r) in registers {
+            let reg = self.register(p, r);
+            if reg.access.readable() && !reads_have_side_effects(reg) {
+                self.read(p, r);
+            }
+        }
+
+        for idx in 0..self.watches.len() {
+            let value = self.value(self.watches[idx].node);
+            let watch = &mut self.watches[idx];
+            watch.changed = watch.last.is_some() && value != watch.last;
+            watch.last = value;
+        }
+    }
+
+    /// What the field or register held when it was last read
+    fn value(&self, node: Node) -> Option<u64> {
+        let (p, r) = node.register()?;
+        let value = *self.values.get(&(p, r))?.as_ref().ok()?;
+        Some(match self.field(node) {
+            Some(field) => field.extract(value),
+            None => value,
+        })
+    }
+
+    fn show_value(&self, node: Node) -> String {
+        let (p, r) = match node.register() {
+            Some(pr) => pr,
+            None => return String::new(),
+        };
+        let reg = self.register(p, r);
+        match (self.values.get(&(p, r)), self.value(node)) {
+            (Some(Err(_)), _) => "error".to_owned(),
+            (_, Some(value)) => match self.field(node) {
+                Some(field) => match field.value_name(value) {
+                    Some(name) => format!("0x{:x} ({})", value, name),
+                    None => format!("0x{:x}", value),
+                },
+                None => format!("0x{:0width$x}", value, width = (reg.size as usize).div_ceil(4)),
+            },
+            _ if !reg.access.readable() => "write-only".to_owned(),
+            _ if reads_have_side_effects(reg) => "r to read".to_owned(),
+            _ => "?".to_owned(),
+        }
+    }
+
+    fn describe(&self, node: Node) -> String {
+        match node {
+            Node::Peripheral(p) => {
+                let peripheral = &self.device.peripherals[p];
+                format!(
+                    "{} {:<28} 0x{:08x}  {}",
+                    if self.open.contains(&node) { '-' } else { '+' },
+                    peripheral.name,
+                    peripheral.base,
+                    peripheral.description.as_deref().unwrap_or("")
+                )
+            }
+            Node::Register(p, r) => {
+                let reg = self.register(p, r);
+                let marker = match (reg.fields.is_empty(), self.open.contains(&node)) {
+                    (true, _) => ' ',
+                    (false, true) => '-',
+                    (false, false) => '+',
+                };
+                format!(
+                    "   {} {:<24} 0x{:08x} = {:<20} {}",
+                    marker,
+                    reg.name,
+                    reg.csr.address,
+                    self.show_value(node),
+                    reg.description.as_deref().unwrap_or("")
+                )
+            }
+            Node::Field(_, _, _) => {
+                let field = self.field(node).unwrap();
+                let bits = if field.width == 1 {
+                    format!("[{}]", field.lsb)
+                } else {
+                    format!("[{}:{}]", field.msb(), field.lsb)
+                };
+                format!(
+                    "        {:<22} {:<7} = {:<20} {}",
+                    field.name,
+                    bits,
+                    self.show_value(node),
+                    field.description.as_deref().unwrap_or("")
+                )
+            }
+        }
+    }
+
+    fn draw(&self, term: &Terminal<Stdout>, width: usize, height: usize) -> Result<(), ServerError> {
+        let rows = self.rows();
+        let tree_rows = self.tree_rows(height);
+        let mut out = term.lock_mut()?;
+        let line = |out: &mut terminal::TerminalLock<Stdout>, row: usize, text: &str, highlight: Option<Attribute>| -> Result<(), ServerError> {
+            out.batch(Action::MoveCursorTo(0, row as u16))?;
+            if let Some(attribute) = highlight {
+                out.batch(Action::SetAttribute(attribute))?;
+            }
+            write!(out, "{}", fit(text, width, highlight.is_some()))?;
+            out.batch(Action::SetAttribute(Attribute::Reset))?;
+            out.batch(Action::ClearTerminal(Clear::UntilNewLine))?;
+            Ok(())
+        };
+
+        line(&mut out, 0, &format!(" {} -- {}", self.device.name, HELP), Some(Attribute::Reversed))?;
+        for screen_row in 0..tree_rows {
+            match rows.get(self.top + screen_row) {
+                Some(node) => {
+                    let selected = self.top + screen_row == self.cursor;
+                    line(&mut out, 1 + screen_row, &self.describe(*node), if selected { Some(Attribute::Reversed) } else { None })?
+                }
+                None => line(&mut out, 1 + screen_row, "", None)?,
+            }
+        }
+
+        let mut row = 1 + tree_rows;
+        if !self.watches.is_empty() {
+            line(&mut out, row, " watching", Some(Attribute::Underlined))?;
+            row += 1;
+            for watch in self.watches.iter().take(WATCH_ROWS) {
+                let text = format!("  {:<40} {}", self.name(watch.node), self.show_value(watch.node));
+                line(&mut out, row, &text, if watch.changed { Some(Attribute::Bold) } else { None })?;
+                row += 1;
+            }
+        }
+
+        let status = match (&self.editing, rows.get(self.cursor)) {
+            (Some(input), Some(node)) => format!("new value for {}: {}_", self.name(*node), input),
+            _ => self.status.clone(),
+        };
+        line(&mut out, height.saturating_sub(1), &status, None)?;
+        out.flush_batch()?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Deal with a key, returning false once it's time to quit
+    fn key(&mut self, key: KeyEvent) -> bool {
+        if self.editing.is_some() {
+            self.edit_key(key);
+            return true;
+        }
+        let rows = self.rows();
+        let node = match rows.get(self.cursor) {
+            Some(node) => *node,
+            None => return !matches!(key.code, KeyCode::Char('q') | KeyCode::Esc),
+        };
+        match key.code {
+            KeyCode::Char('c') if key.modifiers == KeyModifiers::CONTROL => return false,
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => self.cursor += 1,
+            KeyCode::PageUp => self.cursor = self.cursor.saturating_sub(10),
+            KeyCode::PageDown => self.cursor += 10,
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = rows.len().saturating_sub(1),
+            KeyCode::Right | KeyCode::Char('l') if self.can_open(node) => {
+                self.open.insert(node);
+            }
+            KeyCode::Left | KeyCode::Char('h') => self.close(node, &rows),
+            KeyCode::Enter if self.can_open(node) => self.toggle_open(node),
+            KeyCode::Enter | KeyCode::Char('e') => self.start_edit(node),
+            KeyCode::Char('w') => self.toggle_watch(node),
+            KeyCode::Char('r') => match node.register() {
+                Some((p, r)) if self.register(p, r).access.readable() => {
+                    self.read(p, r);
+                    self.status = format!("read {}", self.name(Node::Register(p, r)));
+                }
+                Some(_) => self.status = format!("{} can't be read", self.name(node)),
+                None => (),
+            },
+            _ => (),
+        }
+        true
+    }
+
+    fn can_open(&self, node: Node) -> bool {
+        match node {
+            Node::Peripheral(_) => true,
+            Node::Register(p, r) => !self.register(p, r).fields.is_empty(),
+            Node::Field(_, _, _) => false,
+        }
+    }
+
+    fn toggle_open(&mut self, node: Node) {
+        if !self.open.remove(&node) {
+            self.open.insert(node);
+        }
+    }
+
+    /// Close the line at the cursor, or if it's already closed, go up to
+    /// the line it's under
+    fn close(&mut self, node: Node, rows: &[Node]) {
+        if self.open.remove(&node) {
+            return;
+        }
+        let parent = match node {
+            Node::Peripheral(_) => return,
+            Node::Register(p, _) => Node::Peripheral(p),
+            Node::Field(p, r, _) => Node::Register(p, r),
+        };
+        if let Some(pos) = rows.iter().position(|n| *n == parent) {
+            self.cursor = pos;
+        }
+    }
+
+    fn toggle_watch(&mut self, node: Node) {
+        if let Node::Peripheral(_) = node {
+            self.status = "only registers and fields can be watched".to_owned();
+            return;
+        }
+        match self.watches.iter().position(|w| w.node == node) {
+            Some(pos) => {
+                self.watches.remove(pos);
+                self.status = format!("stopped watching {}", self.name(node));
+            }
+            None => {
+                self.watches.push(Watch {
+                    node,
+                    last: None,
+                    changed: false,
+                });
+                self.status = format!("watching {}", self.name(node));
+            }
+        }
+    }
+
+    fn start_edit(&mut self, node: Node) {
+        let writable = match (node, self.field(node)) {
+            (Node::Peripheral(_), _) => return,
+            (_, Some(field)) => field.access.writable(),
+            (Node::Register(p, r), None) | (Node::Field(p, r, _), None) => self.register(p, r).access.writable(),
+        };
+        if writable {
+            self.editing = Some(String::new());
+        } else {
+            self.status = format!("{} is read-only", self.name(node));
+        }
+    }
+
+    fn edit_key(&mut self, key: KeyEvent) {
+        let input = self.editing.as_mut().unwrap();
+        match key.code {
+            KeyCode::Esc => {
+                self.editing = None;
+                self.status = HELP.to_owned();
+            }
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Char('c') if key.modifiers == KeyModifiers::CONTROL => self.editing = None,
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Enter => {
+                let input = self.editing.take().unwrap();
+                if let Some(node) = self.rows().get(self.cursor).copied() {
+                    self.status = match self.write(node, &input) {
+                        Ok(text) => text,
+                        Err(text) => text,
+                    };
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Change the field or register to `input`, leaving the rest of the
+    /// register as it is
+    fn write(&mut self, node: Node, input: &str) -> Result<String, String> {
+        let (p, r) = node.register().unwrap();
+        let field = self.field(node).cloned();
+        let value = parse_value(input, field.as_ref()).ok_or_else(|| format!("{} isn't a value", input.trim()))?;
+        let reg = self.register(p, r).clone();
+        let width = field.as_ref().map(|f| f.width).unwrap_or(reg.size);
+        if width < 64 && value >> width != 0 {
+            return Err(format!("0x{:x} doesn't fit in {} bits", value, width));
+        }
+        // The TUI can't sit waiting for GDB, so a queued write is refused
+        if !self.cfg.footguns.allow_write_now("tui", reg.csr.address) {
+            return Err(format!("--footguns won't let {} be written", self.name(node)));
+        }
+
+        let new = match &field {
+            Some(field) => {
+                // A write-only register is taken to still hold whatever
+                // was last written to it
+                let current = if reg.access.readable() {
+                    ecc::read_counter(self.bridge, &reg.csr).map_err(|e| e.to_string())?
+                } else {
+                    self.value(Node::Register(p, r)).unwrap_or(0)
+                };
+                field.insert(current, value)
+            }
+            None => value,
+        };
+        let result: Result<(), BridgeError> = reg
+            .csr
+            .split(new)
+            .iter()
+            .enumerate()
+            .try_for_each(|(word, v)| self.bridge.poke(reg.csr.address + word as u32 * 4, *v));
+        result.map_err(|e| format!("writing {}: {}", self.name(node), e))?;
+        if reg.access.readable() && !reads_have_side_effects(&reg) {
+            self.read(p, r);
+        } else {
+            self.values.insert((p, r), Ok(new));
+        }
+        Ok(format!("wrote 0x{:x} to {}", new, self.name(Node::Register(p, r))))
+    }
+}
@@ -0,0 +1,151 @@
+//! UF2 images, which many bootloaders for Fomu-class boards use instead of
+//! raw binaries.  A UF2 file is a run of 512-byte blocks, each carrying up
+//! to 476 bytes of payload along with the address it goes at, and usually
+//! the ID of the chip family it was built for.
+
+use crate::elf::ElfSegment;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const BLOCK_SIZE: usize = 512;
+const MAX_PAYLOAD: usize = 476;
+
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// The block is for something other than the main flash, and isn't loaded
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+
+/// The block is part of a file for the bootloader's own filesystem
+const FLAG_FILE_CONTAINER: u32 = 0x0000_1000;
+
+/// The file size field holds a family ID instead
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+#[derive(Debug)]
+pub enum Uf2Error {
+    /// A block doesn't have the magic numbers in the right places
+    BadBlock(usize /* index */),
+
+    /// The file isn't a whole number of blocks long
+    Truncated,
+
+    /// There are blocks for several families, and none was picked
+    MixedFamilies(Vec<u32>),
+
+    /// Nothing in the file is for the family that was asked for
+    NoSuchFamily(u32),
+
+    /// Two blocks go at the same place
+    Overlap(u32 /* address */),
+
+    /// Every block is for something other than memory
+    Empty,
+
+    /// A --load-address was given, but the blocks say where they go
+    AddressGiven,
+}
+
+impl ::std::fmt::Display for Uf2Error {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        use Uf2Error::*;
+        match self {
+            BadBlock(index) => write!(f, "block {} isn't a UF2 block", index),
+            Truncated => write!(f, "UF2 file is truncated"),
+            MixedFamilies(families) => {
+                let ids: Vec<String> = families.iter().map(|id| format!("0x{:08x}", id)).collect();
+                write!(f, "UF2 file has blocks for families {}, so one must be picked", ids.join(", "))
+            }
+            NoSuchFamily(id) => write!(f, "UF2 file has nothing for family 0x{:08x}", id),
+            Overlap(addr) => write!(f, "UF2 file has two blocks at 0x{:08x}", addr),
+            Empty => write!(f, "UF2 file has nothing to load"),
+            AddressGiven => write!(f, "UF2 file says where each block goes, so --load-address can't be given"),
+        }
+    }
+}
+
+pub fn is_uf2(data: &[u8]) -> bool {
+    data.len() >= 8
+        && LittleEndian::read_u32(&data[0..]) == MAGIC_START0
+        && LittleEndian::read_u32(&data[4..]) == MAGIC_START1
+}
+
+struct Block<'a> {
+    addr: u32,
+    family: Option<u32>,
+    payload: &'a [u8],
+}
+
+/// Pull the memory out of a UF2 file, joining up blocks that follow on
+/// from each other.  Only blocks for `family` are kept, along with any
+/// that don't say what they're for.  Without a `family`, the file may
+/// only be for one.  There's always at least one segment.
+pub fn parse(data: &[u8], family: Option<u32>) -> Result<Vec<ElfSegment>, Uf2Error> {
+    if !data.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(Uf2Error::Truncated);
+    }
+    let mut blocks = vec![];
+    for (index, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        if LittleEndian::read_u32(&block[0x000..]) != MAGIC_START0
+            || LittleEndian::read_u32(&block[0x004..]) != MAGIC_START1
+            || LittleEndian::read_u32(&block[0x1fc..]) != MAGIC_END
+        {
+            return Err(Uf2Error::BadBlock(index));
+        }
+        let flags = LittleEndian::read_u32(&block[0x008..]);
+        if flags & (FLAG_NOT_MAIN_FLASH | FLAG_FILE_CONTAINER) != 0 {
+            continue;
+        }
+        let len = LittleEndian::read_u32(&block[0x010..]) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(Uf2Error::BadBlock(index));
+        }
+        blocks.push(Block {
+            addr: LittleEndian::read_u32(&block[0x00c..]),
+            family: if flags & FLAG_FAMILY_ID_PRESENT != 0 {
+                Some(LittleEndian::read_u32(&block[0x01c..]))
+            } else {
+                None
+            },
+            payload: &block[0x020..0x020 + len],
+        });
+    }
+
+    let mut families: Vec<u32> = blocks.iter().filter_map(|b| b.family).collect();
+    families.sort_unstable();
+    families.dedup();
+    match family {
+        Some(id) => {
+            if !families.is_empty() && !families.contains(&id) {
+                return Err(Uf2Error::NoSuchFamily(id));
+            }
+            blocks.retain(|b| b.family.is_none() || b.family == Some(id));
+        }
+        None if families.len() > 1 => return Err(Uf2Error::MixedFamilies(families)),
+        None => (),
+    }
+
+    if blocks.is_empty() {
+        return Err(Uf2Error::Empty);
+    }
+    blocks.sort_by_key(|b| b.addr);
+    let mut segments: Vec<ElfSegment> = vec![];
+    for block in blocks {
+        if let Some(last) = segments.last_mut() {
+            let end = last.addr as u64 + last.data.len() as u64;
+            if (block.addr as u64) < end {
+                return Err(Uf2Error::Overlap(block.addr));
+            }
+            if block.addr as u64 == end {
+                last.data.extend_from_slice(block.payload);
+                continue;
+            }
+        }
+        segments.push(ElfSegment {
+            addr: block.addr,
+            data: block.payload.to_vec(),
+        });
+    }
+    Ok(segments)
+}
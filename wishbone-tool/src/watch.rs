@@ -0,0 +1,134 @@
+//! Statistics and alerting for the `watch` server.
+
+use crate::config::{parse_u32, ConfigError};
+
+use log::{error, warn};
+
+use std::process::Command;
+
+/// How `watch` works together with a LiteScope analyzer
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScopeSync {
+    /// Leave the analyzer alone
+    Off,
+
+    /// Start a capture when a threshold is crossed
+    Arm,
+
+    /// Use the analyzer's own trigger, and note the watched registers
+    /// when it fires
+    Follow,
+}
+
+/// An alert that fires when a watched register goes over (or under) a limit
+#[derive(Clone, Debug)]
+pub struct WatchThreshold {
+    pub register: String,
+
+    /// Fire when the value is above `limit`, rather than below it
+    pub above: bool,
+    pub limit: u64,
+}
+
+impl WatchThreshold {
+    /// Parse a threshold such as `temperature>80` or `vccint<0x3e0`.
+    pub fn from_string(spec: &str) -> Result<WatchThreshold, ConfigError> {
+        let (pos, above) = match (spec.find('>'), spec.find('<')) {
+            (Some(p), None) => (p, true),
+            (None, Some(p)) => (p, false),
+            _ => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} is not a valid threshold -- must be REGISTER>VALUE or REGISTER<VALUE",
+                    spec
+                )))
+            }
+        };
+        Ok(WatchThreshold {
+            register: spec[..pos].trim().to_lowercase(),
+            above,
+            limit: parse_u32(spec[pos + 1..].trim())? as u64,
+        })
+    }
+
+    pub fn exceeded(&self, value: u64) -> bool {
+        if self.above {
+            value > self.limit
+        } else {
+            value < self.limit
+        }
+    }
+
+    /// Run the user's alert command, telling it what happened through the
+    /// environment.
+    pub fn alert(&self, command: Option<&str>, value: u64) {
+        warn!(
+            "ALERT: {} is 0x{:x}, which is {} 0x{:x}",
+            self.register,
+            value,
+            if self.above { "above" } else { "below" },
+            self.limit
+        );
+        let command = match command {
+            Some(c) => c,
+            None => return,
+        };
+        let result = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("WATCH_REGISTER", &self.register)
+            .env("WATCH_VALUE", value.to_string())
+            .env("WATCH_LIMIT", self.limit.to_string())
+            .status();
+        if let Err(e) = result {
+            error!("couldn't run alert command \"{}\": {}", command, e);
+        }
+    }
+}
+
+/// Running statistics for one watched register
+#[derive(Default)]
+pub struct WatchStats {
+    samples: u64,
+    min: u64,
+    max: u64,
+    sum: u128,
+
+    /// Exponential moving average, which follows recent samples
+    ema: f64,
+}
+
+impl WatchStats {
+    /// Add a sample, weighting it by `alpha` in the moving average.
+    pub fn record(&mut self, value: u64, alpha: f64) {
+        if self.samples == 0 {
+            self.min = value;
+            self.max = value;
+            self.ema = value as f64;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+            self.ema += alpha * (value as f64 - self.ema);
+        }
+        self.samples += 1;
+        self.sum += value as u128;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.samples as f64
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} samples, min {}, max {}, mean {:.2}, ema {:.2}",
+            self.samples,
+            self.min,
+            self.max,
+            self.mean(),
+            self.ema
+        )
+    }
+}
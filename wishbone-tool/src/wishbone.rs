@@ -3,10 +3,17 @@ extern crate byteorder;
 use std::io;
 use std::io::{Read, Write, Cursor};
 use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
 
 use super::Config;
+use super::access::{self, AccessList, Permission};
 use super::bridge::{Bridge, BridgeError};
+use super::footgun::FootgunGuard;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, warn};
+
+/// How long a client gets to send its token
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
 
 /* The network protocol looks like this:
 
@@ -42,6 +49,13 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 pub struct WishboneServer {
     listener: TcpListener,
     connection: Option<TcpStream>,
+    footguns: FootgunGuard,
+
+    /// The tokens clients have to give, if there are any
+    access: Option<AccessList>,
+
+    /// What the client that's connected may do
+    permission: Permission,
 }
 
 #[derive(Debug)]
@@ -60,6 +74,11 @@ pub enum WishboneServerError {
 
     /// There was a problem with the device bridge
     BridgeError(BridgeError),
+
+    /// The client wrote somewhere its token doesn't let it, or that
+    /// --footguns turned down.  The protocol can't say a write failed, so
+    /// the connection is closed instead of acknowledging it.
+    WriteRefused(u32 /* address */),
 }
 
 impl std::convert::From<io::Error> for WishboneServerError {
@@ -79,13 +98,51 @@ impl WishboneServer {
         Ok(WishboneServer {
             connection: None,
             listener: TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?,
+            footguns: cfg.footguns.clone(),
+            access: cfg.client_tokens.clone(),
+            permission: Permission::Admin,
         })
     }
 
+    /// Wait for a client, turning away any that don't give a known token
+    /// when there are tokens to give
     pub fn connect(&mut self) -> Result<(), WishboneServerError> {
-        let (connection, _sockaddr) = self.listener.accept()?;
-        self.connection = Some(connection);
-        Ok(())
+        loop {
+            let (mut connection, sockaddr) = self.listener.accept()?;
+            if let Some(access) = &self.access {
+                connection.set_read_timeout(Some(TOKEN_TIMEOUT))?;
+                let permission = access::read_token(&mut connection)
+                    .ok()
+                    .and_then(|token| access.permission(&token));
+                connection.set_read_timeout(None)?;
+                self.permission = match permission {
+                    Some(p) => p,
+                    None => {
+                        warn!("turning away {}, which didn't give a known token", sockaddr);
+                        continue;
+                    }
+                };
+                info!("{} connected with {} access", sockaddr, self.permission.name());
+            }
+            self.connection = Some(connection);
+            return Ok(());
+        }
+    }
+
+    fn permits_write(&self, addr: u32) -> bool {
+        let access = match &self.access {
+            Some(access) => access,
+            None => return true,
+        };
+        if access.allows_write(self.permission, addr) {
+            return true;
+        }
+        warn!(
+            "refusing a write to {:08x} from a client with {} access",
+            addr,
+            self.permission.name()
+        );
+        false
     }
 
     pub fn process(&mut self, bridge: &Bridge) -> Result<(), WishboneServerError> {
@@ -140,6 +197,14 @@ impl WishboneServer {
             while count < wcount {
                 let mut value_vec = Cursor::new(vec![buffer[(4*count+0) as usize], buffer[(4*count+1) as usize], buffer[(4*count+2) as usize], buffer[(4*count+3) as usize]]);
                 let value = value_vec.read_u32::<BigEndian>()?;
+                if !self.permits_write(addr) {
+                    self.connection = None;
+                    return Err(WishboneServerError::WriteRefused(addr));
+                }
+                if !self.footguns.allow_write("the wishbone server", addr) {
+                    self.connection = None;
+                    return Err(WishboneServerError::WriteRefused(addr));
+                }
                 bridge.poke(addr, value)?;
                 count=count+1;
                 addr=addr+4;
@@ -0,0 +1,377 @@
+//! XMODEM and YMODEM, for swapping files with firmware over the console.
+//! Both ends use 16-bit CRCs.  Files are sent in 1k blocks, as XMODEM-1k
+//! and YMODEM do, with a short last block sent as 128 bytes.  A receiver
+//! that keeps refusing 1k blocks gets 128-byte ones from then on, since
+//! plenty of small XMODEM receivers only know those.
+
+use crate::bridge::{Bridge, BridgeError};
+use crate::sfl::crc16;
+use crate::xover::XoverUart;
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+const CRC: u8 = b'C';
+
+/// How many times to try a block before giving up
+const RETRIES: u32 = 10;
+
+/// How many times a 1k block is refused before dropping to 128 bytes
+const FALLBACK_RETRIES: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModemProtocol {
+    Xmodem,
+    Ymodem,
+}
+
+impl ModemProtocol {
+    pub fn from_string(item: &str) -> Option<ModemProtocol> {
+        match item {
+            "xmodem" => Some(ModemProtocol::Xmodem),
+            "ymodem" => Some(ModemProtocol::Ymodem),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ModemError {
+    BridgeError(BridgeError),
+    IoError(io::Error),
+
+    /// The other end sent CAN CAN
+    Cancelled,
+
+    /// The other end stopped responding
+    Timeout,
+
+    /// A block kept failing
+    TooManyRetries(u32 /* block */),
+
+    /// A YMODEM header that didn't make sense
+    BadHeader,
+
+    /// A block that was neither the next one nor a repeat of the last
+    OutOfSequence(u8 /* got */, u8 /* expected */),
+
+    /// The file's name and size don't fit in a YMODEM header
+    NameTooLong,
+}
+
+impl ::std::fmt::Display for ModemError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        match self {
+            ModemError::BridgeError(e) => write!(f, "bridge error: {}", e),
+            ModemError::IoError(e) => write!(f, "file error: {}", e),
+            ModemError::Cancelled => write!(f, "cancelled by the other end"),
+            ModemError::Timeout => write!(f, "the other end stopped responding"),
+            ModemError::TooManyRetries(block) => write!(f, "gave up on block {}", block),
+            ModemError::BadHeader => write!(f, "couldn't understand the file header"),
+            ModemError::OutOfSequence(got, expected) => {
+                write!(f, "got block {} when block {} was next", got, expected)
+            }
+            ModemError::NameTooLong => write!(f, "the file name is too long to send"),
+        }
+    }
+}
+
+impl std::convert::From<BridgeError> for ModemError {
+    fn from(e: BridgeError) -> ModemError {
+        ModemError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<io::Error> for ModemError {
+    fn from(e: io::Error) -> ModemError {
+        ModemError::IoError(e)
+    }
+}
+
+/// The console, as seen by a transfer
+pub struct ModemPort<'a> {
+    uart: &'a XoverUart,
+    bridge: &'a Bridge,
+}
+
+impl<'a> ModemPort<'a> {
+    pub fn new(uart: &'a XoverUart, bridge: &'a Bridge) -> ModemPort<'a> {
+        ModemPort { uart, bridge }
+    }
+
+    fn read(&self, timeout: Duration) -> Result<Option<u8>, ModemError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(byte) = self.uart.read_byte(self.bridge)? {
+                return Ok(Some(byte));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), ModemError> {
+        Ok(self.uart.write(self.bridge, data)?)
+    }
+
+    /// Throw away anything left over from a mangled block
+    fn drain(&self) -> Result<(), ModemError> {
+        while self.read(Duration::from_millis(50))?.is_some() {}
+        Ok(())
+    }
+
+    fn cancel(&self) -> Result<(), ModemError> {
+        self.write(&[CAN, CAN, CAN])
+    }
+
+    /// Having just read a CAN, see whether a second one follows, which is
+    /// what it takes to cancel.  A lone CAN is just line noise.
+    fn cancelled(&self) -> Result<bool, ModemError> {
+        Ok(self.read(Duration::from_secs(1))? == Some(CAN))
+    }
+}
+
+/// Frame `data` as block `number` of `size` bytes, padded out with `pad`
+fn block(number: u8, data: &[u8], size: usize, pad: u8) -> Vec<u8> {
+    let mut payload = data.to_vec();
+    payload.resize(size, pad);
+    let crc = crc16(&payload);
+    let mut frame = vec![if size == 1024 { STX } else { SOH }, number, !number];
+    frame.extend_from_slice(&payload);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame
+}
+
+/// Send one block until it's acknowledged, trying it up to `tries` times.
+/// Returns whether it was.
+fn send_block(port: &ModemPort, frame: &[u8], tries: u32) -> Result<bool, ModemError> {
+    for _ in 0..tries {
+        port.write(frame)?;
+        match port.read(Duration::from_secs(10))? {
+            Some(ACK) => return Ok(true),
+            Some(CAN) if port.cancelled()? => return Err(ModemError::Cancelled),
+            Some(_) => port.drain()?,
+            None => return Err(ModemError::Timeout),
+        }
+    }
+    Ok(false)
+}
+
+/// Send a block that has to get through, cancelling the transfer if it
+/// won't
+fn send_block_or_cancel(port: &ModemPort, number: u32, frame: &[u8]) -> Result<(), ModemError> {
+    if send_block(port, frame, RETRIES)? {
+        return Ok(());
+    }
+    port.cancel()?;
+    Err(ModemError::TooManyRetries(number))
+}
+
+/// Wait for the receiver to ask for CRC blocks
+fn wait_for_receiver(port: &ModemPort) -> Result<(), ModemError> {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while Instant::now() < deadline {
+        match port.read(Duration::from_secs(1))? {
+            Some(CRC) => return Ok(()),
+            Some(CAN) if port.cancelled()? => return Err(ModemError::Cancelled),
+            _ => (),
+        }
+    }
+    Err(ModemError::Timeout)
+}
+
+/// A YMODEM header block: the file's name and its size in decimal, each
+/// ended by a NUL, and padded with NULs.  An empty one ends the batch.
+fn header_block(header: &[u8]) -> Result<Vec<u8>, ModemError> {
+    match header.len() {
+        0..=128 => Ok(block(0, header, 128, 0)),
+        129..=1024 => Ok(block(0, header, 1024, 0)),
+        _ => Err(ModemError::NameTooLong),
+    }
+}
+
+fn send_eot(port: &ModemPort) -> Result<(), ModemError> {
+    for _ in 0..RETRIES {
+        port.write(&[EOT])?;
+        // YMODEM receivers NAK the first EOT, to be sure
+        if let Some(ACK) = port.read(Duration::from_secs(5))? {
+            return Ok(());
+        }
+    }
+    Err(ModemError::Timeout)
+}
+
+/// Send `data` to firmware that's waiting to receive it.  `name` is what
+/// YMODEM tells the receiver the file is called.
+pub fn send(
+    port: &ModemPort,
+    protocol: &ModemProtocol,
+    name: &str,
+    data: &[u8],
+) -> Result<(), ModemError> {
+    // Find out that the name fits before the receiver is told anything
+    let header = match protocol {
+        ModemProtocol::Ymodem => {
+            Some(header_block(format!("{}\0{}\0", name, data.len()).as_bytes())?)
+        }
+        ModemProtocol::Xmodem => None,
+    };
+    wait_for_receiver(port)?;
+    if let Some(header) = header {
+        send_block_or_cancel(port, 0, &header)?;
+        wait_for_receiver(port)?;
+    }
+
+    let mut size = 1024;
+    let mut offset = 0;
+    let mut number: u32 = 1;
+    while offset < data.len() {
+        let remaining = data.len() - offset;
+        let this_size = if remaining <= 128 { 128 } else { size };
+        let chunk = &data[offset..offset + remaining.min(this_size)];
+        let frame = block(number as u8, chunk, this_size, SUB);
+        if this_size == 1024 {
+            if !send_block(port, &frame, FALLBACK_RETRIES)? {
+                // Send the same data again, a 128-byte block at a time
+                size = 128;
+                continue;
+            }
+        } else {
+            send_block_or_cancel(port, number, &frame)?;
+        }
+        offset += chunk.len();
+        number += 1;
+    }
+    send_eot(port)?;
+
+    if *protocol == ModemProtocol::Ymodem {
+        wait_for_receiver(port)?;
+        send_block_or_cancel(port, 0, &header_block(&[])?)?;
+    }
+    Ok(())
+}
+
+/// Receive one block, returning its number and contents, or `None` at the
+/// end of the file.
+fn receive_block(port: &ModemPort, prompt: u8) -> Result<Option<(u8, Vec<u8>)>, ModemError> {
+    for _ in 0..RETRIES {
+        let size = match port.read(Duration::from_secs(3))? {
+            Some(SOH) => 128,
+            Some(STX) => 1024,
+            Some(EOT) => return Ok(None),
+            Some(CAN) if port.cancelled()? => return Err(ModemError::Cancelled),
+            Some(_) => {
+                port.drain()?;
+                port.write(&[NAK])?;
+                continue;
+            }
+            None => {
+                port.write(&[prompt])?;
+                continue;
+            }
+        };
+        let mut frame = vec![];
+        while frame.len() < size + 4 {
+            match port.read(Duration::from_secs(1))? {
+                Some(byte) => frame.push(byte),
+                None => break,
+            }
+        }
+        if frame.len() == size + 4 && frame[0] == !frame[1] {
+            let payload = &frame[2..size + 2];
+            if crc16(payload).to_be_bytes() == frame[size + 2..] {
+                return Ok(Some((frame[0], payload.to_vec())));
+            }
+        }
+        port.drain()?;
+        port.write(&[NAK])?;
+    }
+    port.cancel()?;
+    Err(ModemError::Timeout)
+}
+
+/// Receive a file from firmware that's waiting to send one.  Returns the
+/// name the sender gave it, if it said, along with the contents.
+pub fn receive(
+    port: &ModemPort,
+    protocol: &ModemProtocol,
+) -> Result<(Option<String>, Vec<u8>), ModemError> {
+    let mut name = None;
+    let mut size = None;
+    port.write(&[CRC])?;
+    if *protocol == ModemProtocol::Ymodem {
+        let header = match receive_block(port, CRC)? {
+            Some((0, header)) => header,
+            _ => return Err(ModemError::BadHeader),
+        };
+        let mut fields = header.split(|b| *b == 0);
+        name = fields
+            .next()
+            .map(|n| String::from_utf8_lossy(n).into_owned());
+        size = fields.next().and_then(|s| {
+            // The size may be followed by a modification time and mode
+            let s = String::from_utf8_lossy(s);
+            s.split(' ').next().and_then(|n| n.parse::<usize>().ok())
+        });
+        port.write(&[ACK, CRC])?;
+    }
+
+    let mut data = vec![];
+    let mut expected: u8 = 1;
+    let mut prompt = CRC;
+    loop {
+        match receive_block(port, prompt)? {
+            None => break,
+            Some((number, payload)) => {
+                // A repeat of the last block means our ACK got lost.
+                // Anything else means a block went missing, which can't be
+                // put right.
+                if number == expected {
+                    data.extend_from_slice(&payload);
+                    expected = expected.wrapping_add(1);
+                } else if number != expected.wrapping_sub(1) {
+                    port.cancel()?;
+                    return Err(ModemError::OutOfSequence(number, expected));
+                }
+                port.write(&[ACK])?;
+            }
+        }
+        prompt = NAK;
+    }
+
+    if *protocol == ModemProtocol::Ymodem {
+        port.write(&[NAK])?;
+        match port.read(Duration::from_secs(5))? {
+            Some(EOT) => (),
+            _ => return Err(ModemError::Timeout),
+        }
+        port.write(&[ACK])?;
+        // Turn down any further files in the batch
+        port.write(&[CRC])?;
+        if let Some((0, _)) = receive_block(port, CRC)? {
+            port.write(&[ACK])?;
+        }
+    } else {
+        port.write(&[ACK])?;
+    }
+
+    match size {
+        Some(size) => data.truncate(size),
+        None => {
+            while data.last() == Some(&SUB) {
+                data.pop();
+            }
+        }
+    }
+    Ok((name, data))
+}
@@ -0,0 +1,132 @@
+use crate::bridge::{Bridge, BridgeError};
+use crate::config::Config;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The host side of a LiteX crossover UART, which lets the bridge stand
+/// in for a serial cable to the SoC's console.
+#[derive(Clone)]
+pub struct XoverUart {
+    rxtx: u32,
+    rxempty: u32,
+
+    /// Not all gateware has this, in which case writes aren't paced
+    txfull: Option<u32>,
+}
+
+impl XoverUart {
+    pub fn new(cfg: &Config) -> XoverUart {
+        XoverUart {
+            rxtx: *cfg
+                .register_mapping
+                .get("uart_xover_rxtx")
+                .unwrap_or(&0xe0001818),
+            rxempty: *cfg
+                .register_mapping
+                .get("uart_xover_rxempty")
+                .unwrap_or(&0xe0001820),
+            txfull: cfg.register_mapping.get("uart_xover_txfull").cloned(),
+        }
+    }
+
+    /// Read one character from the console, if there is one waiting.
+    pub fn read_byte(&self, bridge: &Bridge) -> Result<Option<u8>, BridgeError> {
+        if bridge.peek(self.rxempty)? != 0 {
+            return Ok(None);
+        }
+        Ok(Some(bridge.peek(self.rxtx)? as u8))
+    }
+
+    /// Send characters to the console, waiting for room in the FIFO first.
+    pub fn write(&self, bridge: &Bridge, data: &[u8]) -> Result<(), BridgeError> {
+        for byte in data {
+            if let Some(txfull) = self.txfull {
+                while bridge.peek(txfull)? != 0 {}
+            }
+            bridge.poke(self.rxtx, *byte as u32)?;
+        }
+        Ok(())
+    }
+}
+
+/// A copy of the console output, stamped with when each piece of it
+/// arrived, for the GDB server to pass along.  Whoever reads the UART
+/// pushes to it, so the GDB server and the terminal don't fight over the
+/// characters.
+#[derive(Clone)]
+pub struct ConsoleTap {
+    start: Instant,
+    chunks: Arc<Mutex<Vec<ConsoleChunk>>>,
+}
+
+/// Some console output, and when it arrived
+pub type ConsoleChunk = (Duration, Vec<u8>);
+
+impl ConsoleTap {
+    pub fn new() -> ConsoleTap {
+        ConsoleTap {
+            start: Instant::now(),
+            chunks: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn push(&self, data: &[u8]) {
+        if !data.is_empty() {
+            let when = self.start.elapsed();
+            self.chunks.lock().unwrap().push((when, data.to_vec()));
+        }
+    }
+
+    /// Everything that has arrived since the last call
+    pub fn take(&self) -> Vec<ConsoleChunk> {
+        std::mem::take(&mut *self.chunks.lock().unwrap())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Splits console output into lines, each starting with the time its
+/// first character arrived, like the kernel's printk timestamps.
+#[derive(Default)]
+pub struct LineStamper {
+    line: Vec<u8>,
+    started: Option<Duration>,
+}
+
+impl LineStamper {
+    fn finish(&mut self) -> String {
+        let when = self.started.take().unwrap_or_default();
+        let text = String::from_utf8_lossy(&self.line).trim_end().to_owned();
+        self.line.clear();
+        format!("[{:5}.{:06}] {}\n", when.as_secs(), when.subsec_micros(), text)
+    }
+
+    /// Add console output that arrived at `when`, returning any lines it
+    /// completed.
+    pub fn feed(&mut self, when: Duration, data: &[u8]) -> Vec<String> {
+        let mut lines = vec![];
+        for byte in data {
+            if self.started.is_none() {
+                self.started = Some(when);
+            }
+            if *byte == b'\n' {
+                lines.push(self.finish());
+            } else {
+                self.line.push(*byte);
+            }
+        }
+        lines
+    }
+
+    /// Give up waiting for the end of a line that started longer than
+    /// `max_age` ago, so a prompt with no newline still gets shown.
+    pub fn flush_stale(&mut self, now: Duration, max_age: Duration) -> Option<String> {
+        match self.started {
+            Some(started) if now > started + max_age => Some(self.finish()),
+            _ => None,
+        }
+    }
+}